@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{AccountDeletion, ErrorResponse};
+use uchat_proto::jwt::{create_token, Claims};
+
+use crate::{audit, bearer_claims, json_ok, json_status, AuthState};
+
+//
+// ACCOUNT DELETION
+//
+// DELETE /account schedules deletion after a grace window
+// (ACCOUNT_DELETION_GRACE_SECS, default 7 days); logging in again before it
+// ends cancels it. Once due, `purge_due` walks each account through the
+// stages below, auditing each one. A failed stage is retried on the next
+// tick, so a chat-service outage delays a purge but never skips erasure.
+//
+
+const DEFAULT_GRACE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Stage {
+    Scheduled,
+    /// Tokens revoked, passkeys removed.
+    Revoked,
+    /// chat-service erased the user's messages.
+    Erased,
+}
+
+struct Pending {
+    purge_at: DateTime<Utc>,
+    stage: Stage,
+}
+
+pub struct Accounts {
+    grace: Duration,
+    chat_url: String,
+    pending: Mutex<HashMap<String, Pending>>,
+    /// Tokens for these users issued before the given unix time are dead.
+    revoked_before: Mutex<HashMap<String, usize>>,
+}
+
+impl Accounts {
+    /// `ACCOUNT_DELETION_GRACE_SECS` and `CHAT_SERVICE_URL` (erasure target).
+    pub fn from_env() -> Self {
+        let grace = std::env::var("ACCOUNT_DELETION_GRACE_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_GRACE);
+
+        Self {
+            grace,
+            chat_url: std::env::var("CHAT_SERVICE_URL").unwrap_or_else(|_| "http://127.0.0.1:9301".into()),
+            pending: Mutex::new(HashMap::new()),
+            revoked_before: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        self.revoked_before
+            .lock()
+            .unwrap()
+            .get(&claims.sub)
+            .is_some_and(|cutoff| claims.iat < *cutoff)
+    }
+
+    /// Called on every successful login; a scheduled deletion still in its
+    /// grace window is called off.
+    pub fn cancel_deletion(&self, username: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(username).is_some_and(|p| p.stage == Stage::Scheduled) {
+            pending.remove(username);
+            audit::record("account.deletion.cancelled", username, username, "login during grace period");
+        }
+    }
+}
+
+#[utoipa::path(delete, path = "/account", tag = "auth",
+    security(("bearer" = [])),
+    responses((status = 202, body = AccountDeletion), (status = 401, body = ErrorResponse)))]
+pub async fn delete_account(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(claims) = bearer_claims(state, &req) else {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    };
+
+    let accounts = &state.accounts;
+    let purge_at = Utc::now() + accounts.grace;
+    let purge_at = {
+        let mut pending = accounts.pending.lock().unwrap();
+        let entry = pending
+            .entry(claims.sub.clone())
+            .or_insert(Pending { purge_at, stage: Stage::Scheduled });
+        entry.purge_at
+    };
+    audit::record("account.deletion.scheduled", &claims.sub, &claims.sub, format!("purge_at={}", purge_at.to_rfc3339()));
+
+    let body = AccountDeletion { username: claims.sub, purge_at: purge_at.to_rfc3339() };
+    let mut resp = json_ok(serde_json::to_string(&body).unwrap());
+    *resp.status_mut() = StatusCode::ACCEPTED;
+    Ok(resp)
+}
+
+/// Advances due deletions; runs once a minute.
+pub async fn purge_due(state: Arc<AuthState>, http: reqwest::Client) -> anyhow::Result<()> {
+    let due: Vec<(String, Stage)> = state
+        .accounts
+        .pending
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, p)| p.purge_at <= Utc::now())
+        .map(|(user, p)| (user.clone(), p.stage))
+        .collect();
+
+    for (username, stage) in due {
+        advance(&state, &http, &username, stage).await;
+    }
+    Ok(())
+}
+
+async fn advance(state: &AuthState, http: &reqwest::Client, username: &str, mut stage: Stage) {
+    let accounts = &state.accounts;
+    let set_stage = |stage: Stage| {
+        if let Some(p) = accounts.pending.lock().unwrap().get_mut(username) {
+            p.stage = stage;
+        }
+    };
+
+    if stage < Stage::Revoked {
+        let cutoff = Utc::now().timestamp() as usize;
+        accounts.revoked_before.lock().unwrap().insert(username.to_string(), cutoff);
+        state.revocations.revoke_user(username, cutoff);
+        let passkeys = state.passkeys.remove_user(username);
+        state.passwords.remove_user(username);
+        let delegations = state.delegations.revoke_all(username);
+        let sessions = state.sessions.revoke_all(username);
+        let devices = state.devices.remove_owner(username);
+        audit::record(
+            "account.deletion.revoked",
+            "system",
+            username,
+            format!(
+                "tokens revoked, passkeys={} delegations={} sessions={} devices={}",
+                passkeys, delegations, sessions, devices
+            ),
+        );
+        stage = Stage::Revoked;
+        set_stage(stage);
+    }
+
+    if stage < Stage::Erased {
+        // chat-service lets users erase themselves, so a token for the user
+        // is all it takes
+        let token = create_token(&state.secret, username);
+        let sent = http
+            .post(format!("{}/privacy/erase/{}", accounts.chat_url, username))
+            .bearer_auth(token)
+            .json(&serde_json::json!({ "confirm": username }))
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await;
+
+        match sent {
+            Ok(resp) if resp.status().is_success() => {
+                let detail = resp.text().await.unwrap_or_default();
+                audit::record("account.deletion.erased", "system", username, detail);
+                set_stage(Stage::Erased);
+            }
+            other => {
+                let reason = match other {
+                    Ok(resp) => resp.status().to_string(),
+                    Err(e) => e.to_string(),
+                };
+                audit::record("account.deletion.erase_failed", "system", username, reason);
+                return;
+            }
+        }
+    }
+
+    let (left, deleted) = state.groups.purge_user(username);
+    audit::record(
+        "account.deletion.completed",
+        "system",
+        username,
+        format!("groups_left={} groups_deleted={}", left, deleted),
+    );
+    accounts.pending.lock().unwrap().remove(username);
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use serde_json::Value;
+
+    use uchat_proto::jwt::create_token;
+
+    use super::delete_account;
+    use crate::testing::{reply, request};
+    use crate::webauthn::tests::{register, sign_in, Authenticator};
+    use crate::AuthState;
+
+    #[tokio::test]
+    async fn signing_in_during_the_grace_period_keeps_the_account() {
+        let state = AuthState::from_env();
+        let mut auth = Authenticator::new();
+        register(&state, &mut auth, "ann", None).await;
+        let ann = create_token(&state.secret, "ann");
+        let (status, scheduled) = reply(delete_account(&state, "en", request(Some(&ann), Value::Null)).await).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let (_, again) = reply(delete_account(&state, "en", request(Some(&ann), Value::Null)).await).await;
+        assert_eq!(again["purge_at"], scheduled["purge_at"]);
+        assert!(state.accounts.pending.lock().unwrap().contains_key("ann"));
+
+        auth.counter += 1;
+        assert_eq!(sign_in(&state, &auth, Some("ann")).await.0, StatusCode::OK);
+        assert!(state.accounts.pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+/// Audit events kept in memory for the /stats dashboard.
+const RECENT_KEEP: usize = 500;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AuditEvent {
+    pub ts: DateTime<Utc>,
+    pub action: String,
+    pub actor: String,
+    pub target: String,
+    pub detail: String,
+}
+
+static RECENT: Mutex<VecDeque<AuditEvent>> = Mutex::new(VecDeque::new());
+static COUNTS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Writes one `AUDIT` JSON line for log shipping.
+pub fn record(action: &str, actor: &str, target: &str, detail: impl Into<String>) {
+    let event = AuditEvent {
+        ts: Utc::now(),
+        action: action.into(),
+        actor: actor.into(),
+        target: target.into(),
+        detail: detail.into(),
+    };
+    let line = json!({
+        "ts": event.ts.to_rfc3339(),
+        "action": event.action,
+        "actor": event.actor,
+        "target": event.target,
+        "detail": event.detail,
+    });
+    println!("AUDIT {}", line);
+
+    *COUNTS.lock().unwrap().entry(event.action.clone()).or_insert(0) += 1;
+    let mut recent = RECENT.lock().unwrap();
+    recent.push_back(event);
+    while recent.len() > RECENT_KEEP {
+        recent.pop_front();
+    }
+}
+
+/// The newest `limit` events, newest first.
+pub fn recent(limit: usize) -> Vec<AuditEvent> {
+    RECENT.lock().unwrap().iter().rev().take(limit).cloned().collect()
+}
+
+/// Events per action since the process started.
+pub fn counts() -> BTreeMap<String, u64> {
+    COUNTS.lock().unwrap().clone()
+}
@@ -0,0 +1,103 @@
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use uchat_core::i18n::codes;
+
+use crate::json_status;
+
+//
+// REQUEST BODIES
+//
+// Every request body is read here before routing, capped by a per-route
+// limit, so no handler can be made to buffer an arbitrary amount. A body
+// over the limit gets 413, whether the client announced its size in
+// Content-Length or just kept sending.
+//
+// Handlers then decode with `parse`:
+// - malformed JSON is 400;
+// - well-formed JSON with unknown, missing or mistyped fields is 422 (the
+//   request DTOs use `deny_unknown_fields`);
+// - JSON with control characters in any string is also 422, since
+//   usernames and group ids end up in logs and tokens.
+//
+
+/// Fallback for routes without an entry in `limit_for`.
+const DEFAULT_LIMIT: usize = 4 * 1024;
+
+/// Largest body accepted for a route.
+fn limit_for(method: &Method, segments: &[&str]) -> usize {
+    match (method, segments) {
+        (&Method::POST, ["login"] | ["session"] | ["login", "otp", _]) => 1024,
+        (&Method::POST, ["groups"]) | (&Method::POST, ["groups", _, "members"]) => 1024,
+        // attestation objects carry the public key and, optionally, certificates
+        (&Method::POST, ["webauthn", "register", "finish"]) => 64 * 1024,
+        (&Method::POST, ["webauthn", "login", "finish"]) => 16 * 1024,
+        _ => DEFAULT_LIMIT,
+    }
+}
+
+/// Buffers the request body, enforcing the route's limit. On success the
+/// returned request carries the whole body in memory.
+pub async fn buffer(req: Request<Body>, segments: &[&str], locale: &str) -> Result<Request<Body>, Response<Body>> {
+    let limit = limit_for(req.method(), segments);
+    let too_large = || json_status(StatusCode::PAYLOAD_TOO_LARGE, locale, codes::REQUEST_TOO_LARGE);
+
+    let declared = req
+        .headers()
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(too_large());
+    }
+
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let Ok(chunk) = chunk else {
+            return Err(json_status(StatusCode::BAD_REQUEST, locale, codes::REQUEST_INVALID_JSON));
+        };
+        if bytes.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Request::from_parts(parts, Body::from(bytes)))
+}
+
+/// Why a body was refused; turned into an error envelope by `response`.
+pub struct Rejected {
+    status: StatusCode,
+    code: &'static str,
+}
+
+impl Rejected {
+    pub fn response(&self, locale: &str) -> Response<Body> {
+        json_status(self.status, locale, self.code)
+    }
+}
+
+fn has_control_chars(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.chars().any(char::is_control),
+        Value::Array(items) => items.iter().any(has_control_chars),
+        Value::Object(map) => map.iter().any(|(k, v)| k.chars().any(char::is_control) || has_control_chars(v)),
+        _ => false,
+    }
+}
+
+/// Strictly decodes a JSON request body into `T`.
+pub fn parse<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Rejected> {
+    let value: Value = serde_json::from_slice(bytes)
+        .map_err(|_| Rejected { status: StatusCode::BAD_REQUEST, code: codes::REQUEST_INVALID_JSON })?;
+
+    if has_control_chars(&value) {
+        return Err(Rejected { status: StatusCode::UNPROCESSABLE_ENTITY, code: codes::REQUEST_INVALID_CHARACTERS });
+    }
+
+    serde_json::from_value(value)
+        .map_err(|_| Rejected { status: StatusCode::UNPROCESSABLE_ENTITY, code: codes::REQUEST_INVALID_FIELDS })
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::ErrorResponse;
+
+use crate::audit::{self, AuditEvent};
+use crate::hashing::HashingStats;
+use crate::security::bearer_matches;
+use crate::{json_ok, json_status, AuthState};
+
+//
+// OPS DASHBOARD
+//
+// GET /stats summarizes what auth-api has seen since it started, for a
+// small ops UI: requests per route and status, sign-in attempts refused by
+// lockouts and rate limits per address block, login outcomes over recent
+// windows, password hashing load, and the latest audit events.
+//
+//   AUTH_DASHBOARD_TOKEN   bearer token for GET /stats; disabled while unset
+//
+// Everything is in memory and resets on restart, like the security feed.
+//
+
+/// Routes as labelled in the request counts; `:name` matches any segment.
+/// Keep in step with `route_request`. Anything else counts as "other".
+const ROUTES: &[&str] = &[
+    "/login",
+    "/login/otp/start",
+    "/login/otp/verify",
+    "/session",
+    "/account",
+    "/introspect",
+    "/tokens/delegate",
+    "/tokens/delegate/:id",
+    "/devices",
+    "/devices/challenge",
+    "/devices/register",
+    "/webauthn/register/start",
+    "/webauthn/register/finish",
+    "/webauthn/login/start",
+    "/webauthn/login/finish",
+    "/security/events",
+    "/stats",
+    "/policies",
+    "/policies/accept",
+    "/policies/accepted",
+    "/openapi.json",
+    "/groups",
+    "/groups/:id",
+    "/groups/:id/members",
+    "/groups/:id/members/:user",
+];
+
+/// Login windows reported, in minutes.
+const LOGIN_WINDOWS: &[(&str, i64)] = &[("5m", 5), ("1h", 60), ("24h", 24 * 60)];
+
+pub fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').filter(|s| !s.is_empty()).collect();
+    ROUTES
+        .iter()
+        .find(|route| {
+            let parts: Vec<&str> = route.trim_start_matches('/').split('/').collect();
+            parts.len() == segments.len() && parts.iter().zip(&segments).all(|(p, s)| p.starts_with(':') || p == s)
+        })
+        .copied()
+        .unwrap_or("other")
+}
+
+/// (method, route) -> status -> count
+type RequestCounts = BTreeMap<(String, &'static str), BTreeMap<u16, u64>>;
+
+pub struct Dashboard {
+    token: Option<String>,
+    started_at: DateTime<Utc>,
+    requests: Mutex<RequestCounts>,
+}
+
+impl Dashboard {
+    pub fn from_env() -> Self {
+        Self {
+            token: std::env::var("AUTH_DASHBOARD_TOKEN").ok().filter(|t| !t.is_empty()),
+            started_at: Utc::now(),
+            requests: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn count(&self, method: &Method, route: &'static str, status: StatusCode) {
+        let mut requests = self.requests.lock().unwrap();
+        let by_status = requests.entry((method.as_str().to_string(), route)).or_default();
+        *by_status.entry(status.as_u16()).or_insert(0) += 1;
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RouteStats {
+    pub method: String,
+    pub route: String,
+    pub total: u64,
+    /// Responses per HTTP status code.
+    pub by_status: BTreeMap<u16, u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Offender {
+    /// Address block, "203.0.113.0/24" or "2001:db8:0:1::/64".
+    pub bucket: String,
+    pub hits: u64,
+    pub last_hit: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RateLimitStats {
+    /// Sign-in attempts refused by a lockout or rate limit, last 24 hours.
+    pub total: u64,
+    pub top_offenders: Vec<Offender>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LoginWindow {
+    /// "5m", "1h" or "24h".
+    pub window: String,
+    pub succeeded: u64,
+    pub failed: u64,
+    /// failed / (succeeded + failed); 0 without attempts.
+    pub failure_ratio: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AuditStats {
+    /// Events per action since start.
+    pub by_action: BTreeMap<String, u64>,
+    /// Newest first.
+    pub recent: Vec<AuditEvent>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Stats {
+    pub generated_at: DateTime<Utc>,
+    pub started_at: DateTime<Utc>,
+    pub requests: Vec<RouteStats>,
+    pub rate_limited: RateLimitStats,
+    pub logins: Vec<LoginWindow>,
+    /// Password hashing slots and their queue (see hashing.rs).
+    pub hashing: HashingStats,
+    pub audit: AuditStats,
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    #[serde(default = "default_top")]
+    top: usize,
+    #[serde(default = "default_recent")]
+    recent: usize,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+fn default_recent() -> usize {
+    20
+}
+
+#[utoipa::path(get, path = "/stats", tag = "security",
+    params(
+        ("top" = Option<usize>, Query, description = "offending address blocks returned (default 10, max 100)"),
+        ("recent" = Option<usize>, Query, description = "newest audit events returned (default 20, max 500)"),
+    ),
+    security(("bearer" = [])),
+    responses((status = 200, body = Stats), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse)))]
+pub async fn stats(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let dashboard = &state.dashboard;
+    if !bearer_matches(&req, dashboard.token.as_deref()) {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    }
+    let Ok(query) = serde_urlencoded::from_str::<StatsQuery>(req.uri().query().unwrap_or("")) else {
+        return Ok(json_status(StatusCode::BAD_REQUEST, locale, codes::REQUEST_INVALID_FIELDS));
+    };
+
+    let requests = dashboard
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((method, route), by_status)| RouteStats {
+            method: method.clone(),
+            route: route.to_string(),
+            total: by_status.values().sum(),
+            by_status: by_status.clone(),
+        })
+        .collect();
+
+    let (total, top) = state.security.top_offenders(query.top.min(100));
+    let rate_limited = RateLimitStats {
+        total,
+        top_offenders: top.into_iter().map(|(bucket, hits, last_hit)| Offender { bucket, hits, last_hit }).collect(),
+    };
+
+    let logins = LOGIN_WINDOWS
+        .iter()
+        .map(|(window, minutes)| {
+            let (succeeded, failed) = state.security.login_counts(*minutes);
+            let attempts = succeeded + failed;
+            LoginWindow {
+                window: window.to_string(),
+                succeeded,
+                failed,
+                failure_ratio: if attempts == 0 { 0.0 } else { failed as f64 / attempts as f64 },
+            }
+        })
+        .collect();
+
+    let stats = Stats {
+        generated_at: Utc::now(),
+        started_at: dashboard.started_at,
+        requests,
+        rate_limited,
+        logins,
+        hashing: state.hashing.stats(),
+        audit: AuditStats { by_action: audit::counts(), recent: audit::recent(query.recent.min(500)) },
+    };
+    Ok(json_ok(serde_json::to_string(&stats).unwrap()))
+}
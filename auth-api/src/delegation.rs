@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{DelegateRequest, DelegatedToken, ErrorResponse};
+use uchat_proto::jwt::{create_delegated_token, Claims, Scope};
+
+use crate::body;
+use crate::{audit, bearer_claims, json_error, json_ok, json_status, not_found, AuthState};
+
+//
+// DELEGATED BOT TOKENS
+//
+// A user can hand a bot a token that acts for them only in some rooms and
+// only for some actions ("send", "read"). The limits travel in the token's
+// `scope` claim, which gateway and chat-service enforce; each token has its
+// own expiry and id (`jti`) and can be revoked without touching the user's
+// other tokens. Revocation is visible through /introspect, and in the
+// revocation feed for services holding sessions open on the token (see
+// revocations.rs).
+//
+//   AUTH_DELEGATE_MAX_SECS  longest lifetime a caller may ask for
+//                           (default 30 days)
+//
+// Like groups, delegations live in memory.
+//
+
+const DEFAULT_TTL_SECS: u64 = 3600;
+pub const MAX_ROOMS: usize = 32;
+
+struct Delegation {
+    owner: String,
+    scope: Scope,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl Delegation {
+    fn to_dto(&self, id: &str, token: Option<String>) -> DelegatedToken {
+        DelegatedToken {
+            id: id.to_string(),
+            bot: self.scope.bot.clone(),
+            rooms: self.scope.rooms.clone(),
+            actions: self.scope.actions.clone(),
+            created_at: self.created_at.to_rfc3339(),
+            expires_at: self.expires_at.to_rfc3339(),
+            token,
+        }
+    }
+}
+
+pub struct Delegations {
+    max_ttl: u64,
+    active: Mutex<HashMap<String, Delegation>>,
+    /// Revoked ids until the token would have expired anyway.
+    revoked: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl Delegations {
+    pub fn from_env() -> Self {
+        Self {
+            max_ttl: std::env::var("AUTH_DELEGATE_MAX_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30 * 24 * 3600),
+            active: Mutex::new(HashMap::new()),
+            revoked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        claims.jti.as_ref().is_some_and(|id| self.revoked.lock().unwrap().contains_key(id))
+    }
+
+    /// Revokes every delegation `owner` made; returns how many.
+    pub fn revoke_all(&self, owner: &str) -> usize {
+        let mut active = self.active.lock().unwrap();
+        let mut revoked = self.revoked.lock().unwrap();
+        let before = active.len();
+        active.retain(|id, d| {
+            if d.owner != owner {
+                return true;
+            }
+            revoked.insert(id.clone(), d.expires_at);
+            false
+        });
+        before - active.len()
+    }
+
+    /// Longest lifetime a token may be minted with.
+    pub fn max_ttl(&self) -> u64 {
+        self.max_ttl
+    }
+
+    /// Revokes one delegation, whoever made it; when its token would have
+    /// expired, or None if it is not active.
+    pub fn revoke_id(&self, id: &str) -> Option<DateTime<Utc>> {
+        let delegation = self.active.lock().unwrap().remove(id)?;
+        self.revoked.lock().unwrap().insert(id.to_string(), delegation.expires_at);
+        Some(delegation.expires_at)
+    }
+
+    /// Drops expired delegations and revocations.
+    pub fn expire(&self) {
+        let now = Utc::now();
+        self.active.lock().unwrap().retain(|_, d| d.expires_at > now);
+        self.revoked.lock().unwrap().retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+pub fn valid_room(room: &str) -> bool {
+    !room.is_empty() && room.len() <= 64 && !room.chars().any(char::is_control)
+}
+
+/// The caller's own (not delegated) claims, or the status and error code
+/// to refuse with; delegated tokens cannot manage delegations.
+pub fn owner(state: &AuthState, req: &Request<Body>) -> Result<Claims, (StatusCode, &'static str)> {
+    match bearer_claims(state, req) {
+        Some(claims) if claims.is_delegated() => Err((StatusCode::FORBIDDEN, codes::AUTH_FORBIDDEN)),
+        Some(claims) => Ok(claims),
+        None => Err((StatusCode::UNAUTHORIZED, codes::AUTH_INVALID_TOKEN)),
+    }
+}
+
+#[utoipa::path(post, path = "/tokens/delegate", tag = "auth",
+    request_body = DelegateRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = DelegatedToken), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse), (status = 403, body = ErrorResponse)))]
+pub async fn delegate(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let claims = match owner(state, &req) {
+        Ok(claims) => claims,
+        Err((status, code)) => return Ok(json_status(status, locale, code)),
+    };
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let request = match body::parse::<DelegateRequest>(&body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+    let bot = request.bot.trim().to_string();
+    if bot.is_empty()
+        || bot.len() > 64
+        || request.actions.is_empty()
+        || request.rooms.is_empty()
+        || request.rooms.len() > MAX_ROOMS
+        || !request.rooms.iter().all(|r| valid_room(r))
+    {
+        return Ok(json_error(locale, codes::AUTH_DELEGATION_INVALID));
+    }
+    let mut actions = request.actions;
+    actions.sort_by_key(|a| a.as_str());
+    actions.dedup();
+    let ttl = request.expires_in_secs.unwrap_or(DEFAULT_TTL_SECS).clamp(1, state.delegations.max_ttl);
+
+    let dto = mint(state, &claims.sub, Scope { bot, rooms: request.rooms, actions }, ttl);
+    Ok(json_ok(serde_json::to_string(&dto).unwrap()))
+}
+
+/// Mints and records a delegated token for `owner`; the returned DTO
+/// carries the token. Devices get theirs here too (see devices.rs).
+pub fn mint(state: &AuthState, owner: &str, scope: Scope, ttl: u64) -> DelegatedToken {
+    let id = B64.encode(rand::random::<[u8; 16]>());
+    let token = create_delegated_token(
+        &state.secret,
+        owner,
+        state.groups.groups_for(owner),
+        &id,
+        scope.clone(),
+        Duration::seconds(ttl as i64),
+    );
+    let now = Utc::now();
+    let delegation = Delegation {
+        owner: owner.to_string(),
+        scope,
+        created_at: now,
+        expires_at: now + Duration::seconds(ttl as i64),
+    };
+
+    let detail = format!(
+        "bot={} rooms={} actions={} ttl={}s",
+        delegation.scope.bot,
+        delegation.scope.rooms.join(","),
+        delegation.scope.actions.iter().map(|a| a.as_str()).collect::<Vec<_>>().join(","),
+        ttl
+    );
+    audit::record("token.delegate", owner, &id, detail);
+
+    let dto = delegation.to_dto(&id, Some(token));
+    state.delegations.active.lock().unwrap().insert(id, delegation);
+    dto
+}
+
+#[utoipa::path(get, path = "/tokens/delegate", tag = "auth",
+    security(("bearer" = [])),
+    responses((status = 200, body = Vec<DelegatedToken>), (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse)))]
+pub async fn list(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let claims = match owner(state, &req) {
+        Ok(claims) => claims,
+        Err((status, code)) => return Ok(json_status(status, locale, code)),
+    };
+
+    let now = Utc::now();
+    let active = state.delegations.active.lock().unwrap();
+    let mut list: Vec<DelegatedToken> = active
+        .iter()
+        .filter(|(_, d)| d.owner == claims.sub && d.expires_at > now)
+        .map(|(id, d)| d.to_dto(id, None))
+        .collect();
+    list.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    Ok(json_ok(serde_json::to_string(&list).unwrap()))
+}
+
+#[utoipa::path(delete, path = "/tokens/delegate/{id}", tag = "auth",
+    params(("id" = String, Path, description = "token id (the `jti` claim)")),
+    security(("bearer" = [])),
+    responses((status = 200, body = DelegatedToken), (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse), (status = 404)))]
+pub async fn revoke(state: &AuthState, locale: &str, req: Request<Body>, id: &str) -> Result<Response<Body>, hyper::Error> {
+    let claims = match owner(state, &req) {
+        Ok(claims) => claims,
+        Err((status, code)) => return Ok(json_status(status, locale, code)),
+    };
+
+    let mut active = state.delegations.active.lock().unwrap();
+    if active.get(id).is_none_or(|d| d.owner != claims.sub) {
+        return Ok(not_found());
+    }
+    let delegation = active.remove(id).unwrap();
+    state.delegations.revoked.lock().unwrap().insert(id.to_string(), delegation.expires_at);
+    state.revocations.revoke_token(id, delegation.expires_at);
+    audit::record("token.revoke", &claims.sub, id, format!("bot={}", delegation.scope.bot));
+
+    Ok(json_ok(serde_json::to_string(&delegation.to_dto(id, None)).unwrap()))
+}
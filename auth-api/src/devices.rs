@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use chrono::Utc;
+use hyper::{Body, Request, Response, StatusCode};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use utoipa::ToSchema;
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{DelegatedToken, ErrorResponse};
+use uchat_proto::jwt::{Scope, ScopeAction};
+
+use crate::body;
+use crate::delegation::{self, owner, valid_room, MAX_ROOMS};
+use crate::{audit, json_error, json_ok, json_status, AuthState};
+
+//
+// DEVICE REGISTRATION
+//
+// Devices (ESP32 boards and the like) are registered by their owner, who
+// gets back the device's API key: a delegated token (see delegation.rs)
+// for the owner's account, limited to the rooms asked for, that the
+// device then uses on the gateway. It is listed and revoked with the
+// owner's other delegated tokens.
+//
+// A device proves it is genuine by signing a challenge with its secure
+// element or eFuse-derived key:
+//
+//   POST /devices/challenge  -> {"challenge": "<base64url>", "expires_in_secs": 300}
+//   POST /devices/register   {"device_id", "rooms", "manufacturer"?, "attestation"?:
+//                             {"challenge", "signature"}}
+//
+// The signature is ES256 (ECDSA P-256 / SHA-256), DER or raw r||s,
+// over the challenge bytes followed by the device id, and must verify
+// against one of the manufacturer's registered keys; challenges are
+// single-use and bound to the owner who asked for them. A device that
+// fails attestation is not registered.
+//
+// The outcome is recorded on the device as its attestation status and
+// gates what its key may do: attested devices may read and send in their
+// rooms, unattested ones only read.
+//
+//   AUTH_DEVICE_MANUFACTURER_KEYS     "espressif=<base64url SEC1 P-256 key>,..."
+//                                     (a manufacturer may be listed more than once)
+//   AUTH_DEVICE_ATTESTATION_REQUIRED  "1" to refuse devices without attestation
+//
+// GET /devices lists the caller's devices. Like delegations, devices live
+// in memory.
+//
+
+const CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AttestationStatus {
+    /// Signed a challenge with a manufacturer key.
+    Verified,
+    /// Registered without attestation.
+    Unattested,
+}
+
+impl AttestationStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            AttestationStatus::Verified => "verified",
+            AttestationStatus::Unattested => "unattested",
+        }
+    }
+
+    /// What the device's key may do.
+    fn actions(self) -> Vec<ScopeAction> {
+        match self {
+            AttestationStatus::Verified => vec![ScopeAction::Read, ScopeAction::Send],
+            AttestationStatus::Unattested => vec![ScopeAction::Read],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Device {
+    pub device_id: String,
+    pub owner: String,
+    pub manufacturer: Option<String>,
+    pub attestation: AttestationStatus,
+    pub rooms: Vec<String>,
+    pub registered_at: String,
+    /// Id of the device's API key among the owner's delegated tokens.
+    pub key_id: String,
+    /// The API key; only returned on registration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+}
+
+struct Challenge {
+    owner: String,
+    issued: Instant,
+}
+
+pub struct Devices {
+    manufacturer_keys: Vec<(String, VerifyingKey)>,
+    attestation_required: bool,
+    challenges: Mutex<HashMap<Vec<u8>, Challenge>>,
+    registered: Mutex<HashMap<String, Device>>,
+}
+
+impl Devices {
+    pub fn from_env() -> Self {
+        let keys = std::env::var("AUTH_DEVICE_MANUFACTURER_KEYS").unwrap_or_default();
+        let manufacturer_keys = keys
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .filter_map(|entry| {
+                let key = parse_manufacturer_key(entry);
+                if key.is_none() {
+                    println!("auth-api: ignoring malformed manufacturer key {:?}", entry);
+                }
+                key
+            })
+            .collect();
+        Self {
+            manufacturer_keys,
+            attestation_required: std::env::var("AUTH_DEVICE_ATTESTATION_REQUIRED").is_ok_and(|v| v == "1"),
+            challenges: Mutex::new(HashMap::new()),
+            registered: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn new_challenge(&self, owner: &str) -> Vec<u8> {
+        let mut challenge = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        let pending = Challenge { owner: owner.to_string(), issued: Instant::now() };
+        self.challenges.lock().unwrap().insert(challenge.clone(), pending);
+        challenge
+    }
+
+    /// The manufacturer whose key signed the challenge and the device id.
+    fn verify(&self, owner: &str, request: &RegisterDevice, attestation: &Attestation) -> Result<String, &'static str> {
+        let challenge = B64.decode(&attestation.challenge).map_err(|_| "malformed challenge")?;
+        let pending = self.challenges.lock().unwrap().remove(&challenge).ok_or("unknown challenge")?;
+        if pending.owner != owner {
+            return Err("challenge issued to another account");
+        }
+        if pending.issued.elapsed() >= CHALLENGE_TTL {
+            return Err("challenge expired");
+        }
+
+        let signature = B64.decode(&attestation.signature).map_err(|_| "malformed signature")?;
+        let signature = Signature::from_der(&signature)
+            .or_else(|_| Signature::from_slice(&signature))
+            .map_err(|_| "malformed signature")?;
+        let mut signed = challenge;
+        signed.extend_from_slice(request.device_id.as_bytes());
+
+        self.manufacturer_keys
+            .iter()
+            .filter(|(name, _)| request.manufacturer.as_ref().is_none_or(|m| m == name))
+            .find(|(_, key)| key.verify(&signed, &signature).is_ok())
+            .map(|(name, _)| name.clone())
+            .ok_or("no manufacturer key verifies the signature")
+    }
+
+    /// Drops expired challenges.
+    pub fn expire(&self) {
+        self.challenges.lock().unwrap().retain(|_, c| c.issued.elapsed() < CHALLENGE_TTL);
+    }
+
+    /// Forgets the owner's devices (their keys are revoked with the
+    /// account's other delegations); returns how many.
+    pub fn remove_owner(&self, owner: &str) -> usize {
+        let mut registered = self.registered.lock().unwrap();
+        let before = registered.len();
+        registered.retain(|_, d| d.owner != owner);
+        before - registered.len()
+    }
+}
+
+fn parse_manufacturer_key(entry: &str) -> Option<(String, VerifyingKey)> {
+    let (name, key) = entry.split_once('=')?;
+    let key = VerifyingKey::from_sec1_bytes(&B64.decode(key.trim()).ok()?).ok()?;
+    Some((name.trim().to_string(), key))
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Attestation {
+    /// From POST /devices/challenge (base64url).
+    challenge: String,
+    /// ES256 over the challenge bytes and the device id, DER or r||s
+    /// (base64url).
+    signature: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterDevice {
+    device_id: String,
+    /// Rooms the device's key covers.
+    rooms: Vec<String>,
+    /// Only this manufacturer's keys are tried; any otherwise.
+    #[serde(default)]
+    manufacturer: Option<String>,
+    #[serde(default)]
+    attestation: Option<Attestation>,
+}
+
+fn valid_device_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_:.".contains(c))
+}
+
+#[utoipa::path(post, path = "/devices/challenge", tag = "devices",
+    security(("bearer" = [])),
+    responses((status = 200, description = "`challenge` (base64url) and `expires_in_secs`"),
+        (status = 401, body = ErrorResponse), (status = 403, body = ErrorResponse)))]
+pub async fn challenge(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let claims = match owner(state, &req) {
+        Ok(claims) => claims,
+        Err((status, code)) => return Ok(json_status(status, locale, code)),
+    };
+    let challenge = state.devices.new_challenge(&claims.sub);
+    let body = json!({ "challenge": B64.encode(challenge), "expires_in_secs": CHALLENGE_TTL.as_secs() });
+    Ok(json_ok(body.to_string()))
+}
+
+#[utoipa::path(post, path = "/devices/register", tag = "devices",
+    request_body = RegisterDevice,
+    security(("bearer" = [])),
+    responses((status = 200, body = Device), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse), (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse)))]
+pub async fn register(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let claims = match owner(state, &req) {
+        Ok(claims) => claims,
+        Err((status, code)) => return Ok(json_status(status, locale, code)),
+    };
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let request = match body::parse::<RegisterDevice>(&body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+    if !valid_device_id(&request.device_id)
+        || request.rooms.is_empty()
+        || request.rooms.len() > MAX_ROOMS
+        || !request.rooms.iter().all(|r| valid_room(r))
+    {
+        return Ok(json_error(locale, codes::DEVICE_INVALID));
+    }
+
+    let devices = &state.devices;
+    let previous = devices.registered.lock().unwrap().get(&request.device_id).cloned();
+    if previous.as_ref().is_some_and(|d| d.owner != claims.sub) {
+        return Ok(json_status(StatusCode::CONFLICT, locale, codes::DEVICE_EXISTS));
+    }
+
+    let (attestation, manufacturer) = match &request.attestation {
+        Some(attestation) => match devices.verify(&claims.sub, &request, attestation) {
+            Ok(manufacturer) => (AttestationStatus::Verified, Some(manufacturer)),
+            Err(reason) => {
+                println!("auth-api: attestation of device {} failed: {}", request.device_id, reason);
+                audit::record("device.attestation_failed", &claims.sub, &request.device_id, reason);
+                return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::DEVICE_ATTESTATION_FAILED));
+            }
+        },
+        None if devices.attestation_required => {
+            return Ok(json_status(StatusCode::FORBIDDEN, locale, codes::DEVICE_ATTESTATION_REQUIRED));
+        }
+        None => (AttestationStatus::Unattested, request.manufacturer.clone()),
+    };
+
+    // registering again replaces the device's key
+    if let Some(previous) = previous {
+        if let Some(expires_at) = state.delegations.revoke_id(&previous.key_id) {
+            state.revocations.revoke_token(&previous.key_id, expires_at);
+        }
+    }
+    let scope = Scope {
+        bot: format!("device:{}", request.device_id),
+        rooms: request.rooms.clone(),
+        actions: attestation.actions(),
+    };
+    let DelegatedToken { id, token, .. } =
+        delegation::mint(state, &claims.sub, scope, state.delegations.max_ttl());
+    let device = Device {
+        device_id: request.device_id.clone(),
+        owner: claims.sub.clone(),
+        manufacturer,
+        attestation,
+        rooms: request.rooms,
+        registered_at: Utc::now().to_rfc3339(),
+        key_id: id,
+        api_key: None,
+    };
+    let manufacturer = device.manufacturer.as_deref().unwrap_or("-");
+    let detail = format!("attestation={} manufacturer={}", attestation.as_str(), manufacturer);
+    audit::record("device.register", &claims.sub, &device.device_id, detail);
+    devices.registered.lock().unwrap().insert(device.device_id.clone(), device.clone());
+
+    Ok(json_ok(serde_json::to_string(&Device { api_key: token, ..device }).unwrap()))
+}
+
+#[utoipa::path(get, path = "/devices", tag = "devices",
+    security(("bearer" = [])),
+    responses((status = 200, body = Vec<Device>), (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse)))]
+pub async fn list(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let claims = match owner(state, &req) {
+        Ok(claims) => claims,
+        Err((status, code)) => return Ok(json_status(status, locale, code)),
+    };
+    let mut devices: Vec<Device> =
+        state.devices.registered.lock().unwrap().values().filter(|d| d.owner == claims.sub).cloned().collect();
+    devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    Ok(json_ok(serde_json::to_string(&devices).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use hyper::StatusCode;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{DerSignature, Signature, SigningKey};
+    use serde_json::{json, Value};
+
+    use uchat_proto::jwt::{create_token, verify_claims, ScopeAction};
+
+    use super::{challenge, list, register, Devices, B64};
+    use crate::testing::{reply, request};
+    use crate::AuthState;
+
+    /// A state whose one manufacturer key, "acme", is returned with it.
+    fn with_acme_key(required: bool) -> (AuthState, SigningKey) {
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let mut state = AuthState::from_env();
+        state.devices = Devices {
+            manufacturer_keys: vec![("acme".into(), *key.verifying_key())],
+            attestation_required: required,
+            ..Devices::from_env()
+        };
+        (state, key)
+    }
+
+    async fn new_challenge(state: &AuthState, token: &str) -> String {
+        let (_, body) = reply(challenge(state, "en", request(Some(token), Value::Null)).await).await;
+        body["challenge"].as_str().unwrap().to_string()
+    }
+
+    /// `key`'s attestation of `device_id` for `challenge`.
+    fn attest(key: &SigningKey, challenge: &str, device_id: &str) -> Value {
+        let mut signed = B64.decode(challenge).unwrap();
+        signed.extend_from_slice(device_id.as_bytes());
+        let signature: DerSignature = key.sign(&signed);
+        json!({ "challenge": challenge, "signature": B64.encode(signature.as_bytes()) })
+    }
+
+    #[tokio::test]
+    async fn attested_devices_may_send() {
+        let (state, key) = with_acme_key(false);
+        let ann = create_token(&state.secret, "ann");
+        let challenge = new_challenge(&state, &ann).await;
+        let device = json!({ "device_id": "sensor-1", "rooms": ["alerts"],
+            "attestation": attest(&key, &challenge, "sensor-1") });
+        let (status, body) = reply(register(&state, "en", request(Some(&ann), device.clone())).await).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert_eq!((&body["attestation"], &body["manufacturer"]), (&"verified".into(), &"acme".into()));
+        assert!(body["api_key"].is_string());
+
+        // challenges are single use
+        let (status, body) = reply(register(&state, "en", request(Some(&ann), device)).await).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["Error"]["code"], "device.attestation_failed");
+
+        // r||s signatures are taken too
+        let challenge = new_challenge(&state, &ann).await;
+        let mut signed = B64.decode(&challenge).unwrap();
+        signed.extend_from_slice(b"sensor-2");
+        let signature: Signature = key.sign(&signed);
+        let attestation = json!({ "challenge": challenge, "signature": B64.encode(signature.to_bytes()) });
+        let device = json!({ "device_id": "sensor-2", "rooms": ["alerts"], "attestation": attestation });
+        assert_eq!(reply(register(&state, "en", request(Some(&ann), device)).await).await.0, StatusCode::OK);
+        let (_, listed) = reply(list(&state, "en", request(Some(&ann), Value::Null)).await).await;
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_attestations_register_nothing() {
+        let (state, key) = with_acme_key(false);
+        let ann = create_token(&state.secret, "ann");
+        let eve = create_token(&state.secret, "eve");
+        let forger = SigningKey::random(&mut rand::rngs::OsRng);
+        let cases = [
+            // another manufacturer's key
+            (new_challenge(&state, &ann).await, &forger, "sensor-1", None),
+            // a signature over another device id
+            (new_challenge(&state, &ann).await, &key, "sensor-2", None),
+            // a challenge issued to someone else
+            (new_challenge(&state, &eve).await, &key, "sensor-1", None),
+            // only the named manufacturer's keys are tried
+            (new_challenge(&state, &ann).await, &key, "sensor-1", Some("globex")),
+        ];
+        for (challenge, signer, signed_id, manufacturer) in cases {
+            let device = json!({ "device_id": "sensor-1", "rooms": ["alerts"], "manufacturer": manufacturer,
+                "attestation": attest(signer, &challenge, signed_id) });
+            let (status, body) = reply(register(&state, "en", request(Some(&ann), device)).await).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+        }
+        let (_, listed) = reply(list(&state, "en", request(Some(&ann), Value::Null)).await).await;
+        assert_eq!(listed, json!([]));
+    }
+
+    #[tokio::test]
+    async fn unattested_devices_may_only_read_unless_attestation_is_required() {
+        let device = json!({ "device_id": "sensor-1", "rooms": ["alerts"] });
+        let (state, _) = with_acme_key(false);
+        let ann = create_token(&state.secret, "ann");
+        let (status, body) = reply(register(&state, "en", request(Some(&ann), device.clone())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["attestation"], "unattested");
+        let claims = verify_claims(&state.secret, body["api_key"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.scope.unwrap().actions, [ScopeAction::Read]);
+
+        // the id is ann's now
+        let eve = create_token(&state.secret, "eve");
+        let (status, _) = reply(register(&state, "en", request(Some(&eve), device.clone())).await).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (state, _) = with_acme_key(true);
+        let (status, body) = reply(register(&state, "en", request(Some(&ann), device)).await).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["Error"]["code"], "device.attestation_required");
+    }
+}
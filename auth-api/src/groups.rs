@@ -0,0 +1,271 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use hyper::{Body, Request, Response, StatusCode};
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{AddMemberRequest, CreateGroupRequest, ErrorResponse, Group as GroupDto};
+
+use crate::body;
+use crate::{bearer_claims, json_error, json_ok, json_status, not_found, AuthState};
+
+//
+// GROUPS / TEAMS
+//
+// A group id is its slug name; ids end up in JWT `groups` claims and in
+// ROOM_ACL entries, so they need to be stable and human-writable.
+//
+// Reading groups takes a token too; who is in a group is shown only to its
+// owner and its members.
+//
+
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub id: String,
+    pub owner: String,
+    pub members: BTreeSet<String>,
+}
+
+impl Group {
+    /// Whether `username` may see who is in the group.
+    fn visible_to(&self, username: &str) -> bool {
+        self.owner == username || self.members.contains(username)
+    }
+
+    /// The group as `viewer` sees it: members only for the owner and members.
+    fn to_dto(&self, viewer: &str) -> GroupDto {
+        GroupDto {
+            id: self.id.clone(),
+            owner: self.owner.clone(),
+            members: if self.visible_to(viewer) { self.members.iter().cloned().collect() } else { Vec::new() },
+        }
+    }
+
+    fn to_json(&self, viewer: &str) -> String {
+        serde_json::to_string(&self.to_dto(viewer)).unwrap()
+    }
+}
+
+#[derive(Default)]
+pub struct GroupStore {
+    groups: Mutex<HashMap<String, Group>>,
+}
+
+impl GroupStore {
+    /// Removes the user from every group and deletes the groups they own.
+    /// Returns (memberships dropped, groups deleted).
+    pub fn purge_user(&self, username: &str) -> (usize, usize) {
+        let mut groups = self.groups.lock().unwrap();
+        let before = groups.len();
+        groups.retain(|_, g| g.owner != username);
+        let deleted = before - groups.len();
+
+        let left = groups.values_mut().map(|g| g.members.remove(username)).filter(|removed| *removed).count();
+        (left, deleted)
+    }
+
+    pub fn groups_for(&self, username: &str) -> Vec<String> {
+        let groups = self.groups.lock().unwrap();
+        let mut ids: Vec<String> = groups
+            .values()
+            .filter(|g| g.members.contains(username))
+            .map(|g| g.id.clone())
+            .collect();
+        ids.sort();
+        ids
+    }
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+fn caller(state: &AuthState, req: &Request<Body>) -> Option<String> {
+    bearer_claims(state, req).map(|c| c.sub)
+}
+
+fn unauthorized(locale: &str) -> Response<Body> {
+    json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN)
+}
+
+#[utoipa::path(get, path = "/groups", tag = "groups",
+    security(("bearer" = [])),
+    responses((status = 200, body = Vec<GroupDto>), (status = 401, body = ErrorResponse)))]
+pub async fn list_groups(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(caller) = caller(state, &req) else {
+        return Ok(unauthorized(locale));
+    };
+
+    let groups = state.groups.groups.lock().unwrap();
+    let mut list: Vec<GroupDto> = groups.values().map(|g| g.to_dto(&caller)).collect();
+    list.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(json_ok(serde_json::to_string(&list).unwrap()))
+}
+
+#[utoipa::path(get, path = "/groups/{id}", tag = "groups",
+    params(("id" = String, Path, description = "group slug")),
+    security(("bearer" = [])),
+    responses((status = 200, body = GroupDto), (status = 401, body = ErrorResponse), (status = 404)))]
+pub async fn get_group(state: &AuthState, locale: &str, req: Request<Body>, id: &str) -> Result<Response<Body>, hyper::Error> {
+    let Some(caller) = caller(state, &req) else {
+        return Ok(unauthorized(locale));
+    };
+
+    let groups = state.groups.groups.lock().unwrap();
+    Ok(match groups.get(id) {
+        Some(g) => json_ok(g.to_json(&caller)),
+        None => not_found(),
+    })
+}
+
+#[utoipa::path(post, path = "/groups", tag = "groups",
+    request_body = CreateGroupRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = GroupDto), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse), (status = 409, body = ErrorResponse)))]
+pub async fn create_group(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(caller) = caller(state, &req) else {
+        return Ok(unauthorized(locale));
+    };
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let create = match body::parse::<CreateGroupRequest>(&body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+    if !valid_id(&create.id) {
+        return Ok(json_error(locale, codes::GROUP_INVALID_ID));
+    }
+
+    let mut groups = state.groups.groups.lock().unwrap();
+    if groups.contains_key(&create.id) {
+        return Ok(json_status(StatusCode::CONFLICT, locale, codes::GROUP_EXISTS));
+    }
+
+    let group = Group {
+        id: create.id.clone(),
+        owner: caller.clone(),
+        members: BTreeSet::from([caller]),
+    };
+    let json = group.to_json(&group.owner);
+    groups.insert(create.id, group);
+    Ok(json_ok(json))
+}
+
+#[utoipa::path(delete, path = "/groups/{id}", tag = "groups",
+    params(("id" = String, Path, description = "group slug")),
+    security(("bearer" = [])),
+    responses((status = 200), (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse), (status = 404)))]
+pub async fn delete_group(state: &AuthState, locale: &str, req: Request<Body>, id: &str) -> Result<Response<Body>, hyper::Error> {
+    let Some(caller) = caller(state, &req) else {
+        return Ok(unauthorized(locale));
+    };
+
+    let mut groups = state.groups.groups.lock().unwrap();
+    match groups.get(id) {
+        None => Ok(not_found()),
+        Some(g) if g.owner != caller => Ok(json_status(StatusCode::FORBIDDEN, locale, codes::GROUP_NOT_OWNER)),
+        Some(_) => {
+            groups.remove(id);
+            Ok(json_ok("{}".into()))
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/groups/{id}/members", tag = "groups",
+    params(("id" = String, Path, description = "group slug")),
+    request_body = AddMemberRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = GroupDto), (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse), (status = 404)))]
+pub async fn add_member(state: &AuthState, locale: &str, req: Request<Body>, id: &str) -> Result<Response<Body>, hyper::Error> {
+    let Some(caller) = caller(state, &req) else {
+        return Ok(unauthorized(locale));
+    };
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let add = match body::parse::<AddMemberRequest>(&body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    let mut groups = state.groups.groups.lock().unwrap();
+    match groups.get_mut(id) {
+        None => Ok(not_found()),
+        Some(g) if g.owner != caller => Ok(json_status(StatusCode::FORBIDDEN, locale, codes::GROUP_NOT_OWNER)),
+        Some(g) => {
+            g.members.insert(add.username);
+            Ok(json_ok(g.to_json(&caller)))
+        }
+    }
+}
+
+#[utoipa::path(delete, path = "/groups/{id}/members/{username}", tag = "groups",
+    params(("id" = String, Path, description = "group slug"), ("username" = String, Path)),
+    security(("bearer" = [])),
+    responses((status = 200, body = GroupDto), (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse), (status = 404)))]
+pub async fn remove_member(
+    state: &AuthState,
+    locale: &str,
+    req: Request<Body>,
+    id: &str,
+    username: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let Some(caller) = caller(state, &req) else {
+        return Ok(unauthorized(locale));
+    };
+
+    let mut groups = state.groups.groups.lock().unwrap();
+    match groups.get_mut(id) {
+        None => Ok(not_found()),
+        // members may always leave; only the owner removes others
+        Some(g) if g.owner != caller && username != caller => {
+            Ok(json_status(StatusCode::FORBIDDEN, locale, codes::GROUP_NOT_OWNER))
+        }
+        Some(g) => {
+            g.members.remove(username);
+            Ok(json_ok(g.to_json(&caller)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use serde_json::{json, Value};
+
+    use uchat_proto::jwt::create_token;
+
+    use super::{add_member, create_group, get_group, list_groups};
+    use crate::testing::{reply, request};
+    use crate::AuthState;
+
+    #[tokio::test]
+    async fn members_are_shown_to_the_owner_and_members_only() {
+        let state = AuthState::from_env();
+        let (ann, bob, eve) = (
+            create_token(&state.secret, "ann"),
+            create_token(&state.secret, "bob"),
+            create_token(&state.secret, "eve"),
+        );
+        reply(create_group(&state, "en", request(Some(&ann), json!({ "id": "ops" }))).await).await;
+        reply(add_member(&state, "en", request(Some(&ann), json!({ "username": "bob" })), "ops").await).await;
+
+        let (status, _) = reply(list_groups(&state, "en", request(None, Value::Null)).await).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = reply(get_group(&state, "en", request(None, Value::Null), "ops").await).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        for (token, members) in [(&ann, json!(["ann", "bob"])), (&bob, json!(["ann", "bob"])), (&eve, json!([]))] {
+            let (status, group) = reply(get_group(&state, "en", request(Some(token), Value::Null), "ops").await).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!((group["owner"].clone(), group["members"].clone()), (json!("ann"), members.clone()));
+            let (_, list) = reply(list_groups(&state, "en", request(Some(token), Value::Null)).await).await;
+            assert_eq!(list[0]["members"], members);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use utoipa::ToSchema;
+
+use uchat_core::i18n::codes;
+
+use crate::json_status;
+
+//
+// PASSWORD HASHING CONCURRENCY
+//
+// Password checks hash with Argon2, which holds 48 MiB per hash while it
+// runs, so a burst of logins could exhaust memory long before the per-IP
+// request rate limits step in. /login and /session take a hashing slot
+// before their password check, and hold it until they answer:
+//
+//   AUTH_HASH_CONCURRENCY   hashes running at once (default 4)
+//   AUTH_HASH_PER_IP        of those, for one client IP (default 2)
+//   AUTH_HASH_PER_USER      of those, for one username (default 1)
+//   AUTH_HASH_QUEUE_MS      how long a check waits for a free slot
+//                           (default 2000)
+//
+// A check whose IP or username already has its share is refused at once
+// rather than queued, so one client cannot fill the queue either. Refused
+// and timed-out checks answer 503 with Retry-After. GET /stats reports the
+// slots in use, the queue depth and its peak, and the refusals.
+//
+
+pub struct HashingGate {
+    slots: Semaphore,
+    limit: usize,
+    per_ip: usize,
+    per_user: usize,
+    queue_timeout: Duration,
+    /// Checks holding or waiting for a slot, per IP and per username.
+    holders: Mutex<Holders>,
+    queued: AtomicUsize,
+    peak_queued: AtomicUsize,
+    refused: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+#[derive(Default)]
+struct Holders {
+    ips: HashMap<IpAddr, usize>,
+    users: HashMap<String, usize>,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// A hashing slot; released when dropped.
+pub struct HashPermit<'a> {
+    _claim: Claim<'a>,
+    _slot: SemaphorePermit<'a>,
+}
+
+/// One check counted against its IP and username until dropped, whether
+/// it got a slot or its request went away while queued.
+struct Claim<'a> {
+    gate: &'a HashingGate,
+    ip: IpAddr,
+    user: String,
+}
+
+/// Counts a check in the queue until dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl HashingGate {
+    pub fn from_env() -> Self {
+        let limit = env_or("AUTH_HASH_CONCURRENCY", 4usize).max(1);
+        Self {
+            slots: Semaphore::new(limit),
+            limit,
+            per_ip: env_or("AUTH_HASH_PER_IP", 2usize).max(1),
+            per_user: env_or("AUTH_HASH_PER_USER", 1usize).max(1),
+            queue_timeout: Duration::from_millis(env_or("AUTH_HASH_QUEUE_MS", 2000)),
+            holders: Mutex::new(Holders::default()),
+            queued: AtomicUsize::new(0),
+            peak_queued: AtomicUsize::new(0),
+            refused: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+        }
+    }
+
+    /// Waits for a slot to hash `user`'s password for a request from `ip`;
+    /// None when the check is refused or times out.
+    pub async fn acquire(&self, ip: IpAddr, user: &str) -> Option<HashPermit<'_>> {
+        let claim = {
+            let mut holders = self.holders.lock().unwrap();
+            let by_ip = holders.ips.get(&ip).copied().unwrap_or(0);
+            let by_user = holders.users.get(user).copied().unwrap_or(0);
+            if by_ip >= self.per_ip || by_user >= self.per_user {
+                self.refused.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            *holders.ips.entry(ip).or_default() += 1;
+            *holders.users.entry(user.to_string()).or_default() += 1;
+            Claim { gate: self, ip, user: user.to_string() }
+        };
+
+        let queued = Queued::enter(&self.queued);
+        self.peak_queued.fetch_max(self.queued.load(Ordering::Relaxed), Ordering::Relaxed);
+        let slot = tokio::time::timeout(self.queue_timeout, self.slots.acquire()).await;
+        drop(queued);
+
+        match slot {
+            Ok(Ok(slot)) => Some(HashPermit { _claim: claim, _slot: slot }),
+            // the semaphore is never closed; a timeout is the only way here
+            _ => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// 503 for a check that got no slot; worth retrying once the queue has
+    /// had time to move.
+    pub fn busy_response(&self, locale: &str) -> Response<Body> {
+        let mut resp = json_status(StatusCode::SERVICE_UNAVAILABLE, locale, codes::AUTH_BUSY);
+        resp.headers_mut().insert("Retry-After", (self.queue_timeout.as_secs() + 1).into());
+        resp
+    }
+
+    pub fn stats(&self) -> HashingStats {
+        HashingStats {
+            limit: self.limit,
+            in_use: self.limit - self.slots.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+            peak_queued: self.peak_queued.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        let mut holders = self.gate.holders.lock().unwrap();
+        release(&mut holders.ips, &self.ip);
+        release(&mut holders.users, &self.user);
+    }
+}
+
+fn release<K: Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+impl<'a> Queued<'a> {
+    fn enter(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Self(queued)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct HashingStats {
+    /// AUTH_HASH_CONCURRENCY.
+    pub limit: usize,
+    pub in_use: usize,
+    /// Checks waiting for a slot now.
+    pub queued: usize,
+    /// Most checks ever waiting at once.
+    pub peak_queued: usize,
+    /// Refused because their IP or username had its share, since start.
+    pub refused: u64,
+    /// Refused after waiting out AUTH_HASH_QUEUE_MS, since start.
+    pub timed_out: u64,
+}
@@ -1,26 +1,168 @@
+mod account;
+mod audit;
+mod body;
+mod dashboard;
+mod delegation;
+mod devices;
+mod groups;
+mod hashing;
+mod openapi;
+mod otp;
+mod passwords;
+mod policies;
+mod revocations;
+mod secrets;
+mod security;
+mod sessions;
+#[cfg(test)]
+mod testing;
+mod webauthn;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
 use hyper::{Body, Request, Response, Server, Method, StatusCode};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 
-use serde::Deserialize;
-
-use uchat_proto::jwt::create_token;
+use uchat_core::i18n::{self, codes};
+use uchat_core::jobs::{Job, Scheduler};
+use uchat_proto::api::{ErrorResponse, IntrospectRequest, IntrospectResponse, LoginOutcome, LoginRequest};
+use uchat_proto::jwt::{create_token_with_groups, secret_from_env, verify_claims, verify_session_claims, Claims};
 use uchat_proto::events::ServerEvent;
 
-use serde_json;
 use anyhow::Result;
 
-#[derive(Deserialize)]
-struct LoginReq {
-    username: String,
-    password: String,
+pub struct AuthState {
+    pub secret: String,
+    pub groups: groups::GroupStore,
+    pub passkeys: webauthn::Passkeys,
+    pub accounts: account::Accounts,
+    pub jobs: Scheduler,
+    pub security: security::SecurityMonitor,
+    pub policies: policies::PolicyStore,
+    pub dashboard: dashboard::Dashboard,
+    pub delegations: delegation::Delegations,
+    pub devices: devices::Devices,
+    pub sessions: sessions::Sessions,
+    pub otp: otp::OneTimeCodes,
+    pub passwords: passwords::Passwords,
+    pub hashing: hashing::HashingGate,
+    pub revocations: revocations::RevocationLog,
+}
+
+impl AuthState {
+    pub fn from_env() -> Self {
+        Self {
+            secret: secret_from_env(),
+            groups: groups::GroupStore::default(),
+            passkeys: webauthn::Passkeys::new(webauthn::RelyingParty::from_env()),
+            accounts: account::Accounts::from_env(),
+            jobs: Scheduler::default(),
+            security: security::SecurityMonitor::from_env(),
+            policies: policies::PolicyStore::from_env(),
+            dashboard: dashboard::Dashboard::from_env(),
+            delegations: delegation::Delegations::from_env(),
+            devices: devices::Devices::from_env(),
+            sessions: sessions::Sessions::from_env(),
+            otp: otp::OneTimeCodes::from_env(),
+            passwords: passwords::Passwords::from_env(&secrets::SecretProvider),
+            hashing: hashing::HashingGate::from_env(),
+            revocations: revocations::RevocationLog::from_env(),
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let addr = "0.0.0.0:9200".parse().unwrap();
 
-    let make_svc = make_service_fn(|_conn| async {
-        Ok::<_, hyper::Error>(service_fn(handle_request))
+    let state = Arc::new(AuthState::from_env());
+
+    state.jobs.spawn(Job::every("account-purge", Duration::from_secs(60)), {
+        let state = state.clone();
+        let http = reqwest::Client::new();
+        move || account::purge_due(state.clone(), http.clone())
+    });
+    state.jobs.spawn(Job::every("lockout-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.security.expire();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("policy-ticket-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.policies.expire();
+                Ok(())
+            }
+        }
+    });
+
+    state.jobs.spawn(Job::every("delegation-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.delegations.expire();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("device-challenge-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.devices.expire();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("session-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.sessions.expire();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("otp-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.otp.expire();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("revocation-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.revocations.expire();
+                Ok(())
+            }
+        }
+    });
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| handle_request(state.clone(), peer, req)))
+        }
     });
 
     println!("auth-api running on http://{}", addr);
@@ -32,30 +174,176 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    match (req.method(), req.uri().path()) {
-        (&Method::POST, "/login") => handle_login(req).await,
+async fn handle_request(state: Arc<AuthState>, peer: SocketAddr, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let method = req.method().clone();
+    let route = dashboard::route_label(req.uri().path());
+    let resp = route_request(state.clone(), peer, req).await?;
+    state.dashboard.count(&method, route, resp.status());
+    Ok(resp)
+}
+
+async fn route_request(state: Arc<AuthState>, peer: SocketAddr, mut req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let path = req.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').filter(|s| !s.is_empty()).collect();
+
+    let locale = match req.headers().get("Accept-Language").and_then(|v| v.to_str().ok()) {
+        Some(value) => i18n::catalog().negotiate(value),
+        None => i18n::DEFAULT_LOCALE,
+    };
+
+    let ip = state.security.resolve_ip(&req, peer);
+    req.extensions_mut().insert(security::ClientIp(ip));
+
+    // locked-out addresses are refused before their body is even read
+    if let (&Method::POST, ["login"] | ["session"] | ["login", "otp", _] | ["webauthn", "login", _]) =
+        (req.method(), segments.as_slice())
+    {
+        if let Some(remaining) = state.security.locked_out(ip, &path) {
+            return Ok(security::locked_out_response(locale, remaining));
+        }
+        if let Some(wait) = state.security.throttled(ip, &path) {
+            return Ok(security::throttled_response(locale, wait, state.security.attempts_left(ip)));
+        }
+    }
+
+    // sign-in and introspection never act on the session cookie
+    let signs_in = matches!(
+        (req.method(), segments.as_slice()),
+        (&Method::POST, ["login"] | ["session"] | ["introspect"] | ["login", "otp", _] | ["webauthn", "login", _])
+    );
+    if !signs_in && !sessions::csrf_ok(&state, &req) {
+        return Ok(json_status(StatusCode::FORBIDDEN, locale, codes::AUTH_CSRF_FAILED));
+    }
+
+    let req = match body::buffer(req, &segments, locale).await {
+        Ok(req) => req,
+        Err(rejected) => return Ok(rejected),
+    };
+
+    match (req.method(), segments.as_slice()) {
+        (&Method::POST, ["login"]) => handle_login(&state, locale, req).await,
+        (&Method::POST, ["login", "otp", "start"]) => otp::start(&state, locale, req).await,
+        (&Method::POST, ["login", "otp", "verify"]) => otp::verify(&state, locale, req).await,
+        (&Method::POST, ["session"]) => sessions::create(&state, locale, req).await,
+        (&Method::GET, ["session"]) => sessions::current(&state, locale, req).await,
+        (&Method::DELETE, ["session"]) => sessions::end(&state, req).await,
+        (&Method::DELETE, ["account"]) => account::delete_account(&state, locale, req).await,
+        (&Method::POST, ["introspect"]) => handle_introspect(&state, locale, req).await,
+        (&Method::POST, ["tokens", "delegate"]) => delegation::delegate(&state, locale, req).await,
+        (&Method::GET, ["tokens", "delegate"]) => delegation::list(&state, locale, req).await,
+        (&Method::DELETE, ["tokens", "delegate", id]) => delegation::revoke(&state, locale, req, id).await,
+        (&Method::POST, ["devices", "challenge"]) => devices::challenge(&state, locale, req).await,
+        (&Method::POST, ["devices", "register"]) => devices::register(&state, locale, req).await,
+        (&Method::GET, ["devices"]) => devices::list(&state, locale, req).await,
+        (&Method::POST, ["webauthn", "register", "start"]) => webauthn::register_start(&state, locale, req).await,
+        (&Method::POST, ["webauthn", "register", "finish"]) => webauthn::register_finish(&state, locale, req).await,
+        (&Method::POST, ["webauthn", "login", "start"]) => webauthn::login_start(&state, locale, req).await,
+        (&Method::POST, ["webauthn", "login", "finish"]) => webauthn::login_finish(&state, locale, req).await,
+        (&Method::GET, ["security", "events"]) => security::events(&state, locale, req).await,
+        (&Method::GET, ["revocations"]) => revocations::feed(&state, locale, req).await,
+        (&Method::GET, ["stats"]) => dashboard::stats(&state, locale, req).await,
+        (&Method::GET, ["policies"]) => policies::current(&state).await,
+        (&Method::POST, ["policies"]) => policies::publish(&state, locale, req).await,
+        (&Method::POST, ["policies", "accept"]) => policies::accept(&state, locale, req).await,
+        (&Method::GET, ["policies", "accepted"]) => policies::accepted(&state, locale, req).await,
+        (&Method::GET, ["openapi.json"]) => Ok(json_ok(openapi::spec_json())),
+        (&Method::GET, ["groups"]) => groups::list_groups(&state, locale, req).await,
+        (&Method::POST, ["groups"]) => groups::create_group(&state, locale, req).await,
+        (&Method::GET, ["groups", id]) => groups::get_group(&state, locale, req, id).await,
+        (&Method::DELETE, ["groups", id]) => groups::delete_group(&state, locale, req, id).await,
+        (&Method::POST, ["groups", id, "members"]) => groups::add_member(&state, locale, req, id).await,
+        (&Method::DELETE, ["groups", id, "members", user]) => {
+            groups::remove_member(&state, locale, req, id, user).await
+        }
         _ => Ok(not_found()),
     }
 }
 
-async fn handle_login(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+#[utoipa::path(post, path = "/login", tag = "auth",
+    request_body = LoginRequest,
+    responses((status = 200, body = LoginOutcome), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse), (status = 403, body = ErrorResponse),
+        (status = 503, body = ErrorResponse, headers(("Retry-After" = u64, description = "seconds to wait")))))]
+async fn handle_login(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let ip = security::client_ip(&req);
+    let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+    let login: LoginRequest = match body::parse(&whole_body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    if state.passkeys.is_passwordless(&login.username) {
+        state.security.login_failed(ip, Some(&login.username), "password login on a passkey-only account");
+        return Ok(json_status(StatusCode::FORBIDDEN, locale, codes::AUTH_PASSWORD_DISABLED));
+    }
+
+    let Some(_hashing) = state.hashing.acquire(ip, &login.username).await else {
+        return Ok(state.hashing.busy_response(locale));
+    };
+    if !state.passwords.verify(&login.username, &login.password).await {
+        state.security.login_failed(ip, Some(&login.username), "wrong username or password");
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_CREDENTIALS));
+    }
+    state.security.login_succeeded(ip);
+    state.accounts.cancel_deletion(&login.username);
+    Ok(policies::login_reply(state, &login.username))
+}
+
+// POST /introspect — lets other services resolve a token to its claims
+// (including current group membership) without sharing the secret.
+#[utoipa::path(post, path = "/introspect", tag = "auth",
+    request_body = IntrospectRequest,
+    responses((status = 200, body = IntrospectResponse), (status = 400, body = ErrorResponse)))]
+async fn handle_introspect(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     let whole_body = hyper::body::to_bytes(req.into_body()).await?;
-    let login: LoginReq = match serde_json::from_slice(&whole_body) {
+    let body: IntrospectRequest = match body::parse(&whole_body) {
         Ok(v) => v,
-        Err(_) => return Ok(json_error("invalid json")),
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    let claims = verify_claims(&state.secret, &body.token)
+        .or_else(|| verify_session_claims(&state.secret, &body.token))
+        .filter(|c| !is_revoked(state, c));
+    let response = match claims {
+        Some(claims) => IntrospectResponse {
+            active: true,
+            groups: state.groups.groups_for(&claims.sub),
+            sub: Some(claims.sub),
+            exp: Some(claims.exp),
+            scope: claims.scope,
+        },
+        None => IntrospectResponse { active: false, sub: None, exp: None, groups: Vec::new(), scope: None },
     };
 
-    // TODO: password verification — currently accept anything
-    let token = create_token("MY_SECRET_KEY", &login.username);
+    Ok(json_ok(serde_json::to_string(&response).unwrap()))
+}
+
+/// Claims of the caller's Bearer token or, without an Authorization
+/// header, of their session cookie.
+pub fn bearer_claims(state: &AuthState, req: &Request<Body>) -> Option<Claims> {
+    let Some(header) = req.headers().get("Authorization") else {
+        return sessions::cookie_claims(state, req);
+    };
+    let token = header.to_str().ok()?.strip_prefix("Bearer ")?;
+    verify_claims(&state.secret, token).filter(|c| !is_revoked(state, c))
+}
 
-    let response = ServerEvent::LoginOk { token };
-    let json = serde_json::to_string(&response).unwrap();
+/// Revoked with the whole account, as a single delegated token, or with
+/// its ended web session.
+fn is_revoked(state: &AuthState, claims: &Claims) -> bool {
+    state.accounts.is_revoked(claims)
+        || state.delegations.is_revoked(claims)
+        || state.sessions.is_revoked(claims)
+}
 
-    Ok(json_ok(json))
+/// `LoginOk` with a fresh token carrying the user's current groups.
+pub fn token_response(state: &AuthState, username: &str) -> Response<Body> {
+    let groups = state.groups.groups_for(username);
+    let token = create_token_with_groups(&state.secret, username, groups);
+    json_ok(serde_json::to_string(&ServerEvent::LoginOk { token }).unwrap())
 }
 
-fn json_ok(body: String) -> Response<Body> {
+pub fn json_ok(body: String) -> Response<Body> {
     Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
@@ -63,18 +351,26 @@ fn json_ok(body: String) -> Response<Body> {
         .unwrap()
 }
 
-fn json_error(msg: &str) -> Response<Body> {
-    let err = ServerEvent::Error { details: msg.into() };
+pub fn json_error(locale: &str, code: &str) -> Response<Body> {
+    json_status(StatusCode::BAD_REQUEST, locale, code)
+}
+
+/// Error reply carrying a catalog code and its text in the caller's locale.
+pub fn json_status(status: StatusCode, locale: &str, code: &str) -> Response<Body> {
+    let err = ServerEvent::Error {
+        details: i18n::catalog().render(locale, code, &[]),
+        code: Some(code.into()),
+    };
     let json = serde_json::to_string(&err).unwrap();
 
     Response::builder()
-        .status(StatusCode::BAD_REQUEST)
+        .status(status)
         .header("Content-Type", "application/json")
         .body(Body::from(json))
         .unwrap()
 }
 
-fn not_found() -> Response<Body> {
+pub fn not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
         .body(Body::from("not found"))
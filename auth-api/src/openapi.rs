@@ -0,0 +1,135 @@
+use utoipa::openapi::content::ContentBuilder;
+use utoipa::openapi::header::HeaderBuilder;
+use utoipa::openapi::response::ResponseBuilder;
+use utoipa::openapi::schema::{ObjectBuilder, Type};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::Ref;
+use utoipa::{Modify, OpenApi};
+
+use crate::{
+    account, dashboard, delegation, devices, groups, otp, policies, revocations, security, sessions, webauthn,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "auth-api", description = "U-Chat login, passkeys, token introspection and group management"),
+    paths(
+        crate::handle_login,
+        crate::handle_introspect,
+        revocations::feed,
+        otp::start,
+        otp::verify,
+        sessions::create,
+        sessions::current,
+        sessions::end,
+        delegation::delegate,
+        delegation::list,
+        delegation::revoke,
+        devices::challenge,
+        devices::register,
+        devices::list,
+        account::delete_account,
+        groups::list_groups,
+        groups::get_group,
+        groups::create_group,
+        groups::delete_group,
+        groups::add_member,
+        groups::remove_member,
+        webauthn::register_start,
+        webauthn::register_finish,
+        webauthn::login_start,
+        webauthn::login_finish,
+        security::events,
+        dashboard::stats,
+        policies::current,
+        policies::publish,
+        policies::accept,
+        policies::accepted,
+    ),
+    modifiers(&BearerAuth, &BodyErrors, &SignInThrottling),
+    tags((name = "auth"), (name = "webauthn"), (name = "groups"),
+        (name = "devices", description = "Device registration, attestation and API keys"),
+        (name = "policies", description = "Terms of service and privacy policy versions and acceptance"),
+        (name = "security", description = "Brute-force signals for edge firewalls and fail2ban, and the ops dashboard"))
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+    }
+}
+
+/// Every operation with a request body can also fail in `body::buffer` and
+/// `body::parse`; document those replies once here.
+struct BodyErrors;
+
+impl Modify for BodyErrors {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let error = || {
+            let body = ContentBuilder::new().schema(Some(Ref::from_schema_name("ErrorResponse"))).build();
+            ResponseBuilder::new().content("application/json", body)
+        };
+
+        for item in openapi.paths.paths.values_mut() {
+            for op in [&mut item.post, &mut item.put, &mut item.patch].into_iter().flatten() {
+                if op.request_body.is_none() {
+                    continue;
+                }
+                op.responses.responses.insert(
+                    "413".into(),
+                    error().description("Request body over the route's size limit").build().into(),
+                );
+                op.responses.responses.insert(
+                    "422".into(),
+                    error().description("Unknown, missing or invalid fields, or control characters").build().into(),
+                );
+            }
+        }
+    }
+}
+
+/// The sign-in routes are refused with 429 during a lockout or over the
+/// rate limit, before they run (see security.rs).
+struct SignInThrottling;
+
+impl Modify for SignInThrottling {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let header = |kind: Type, description: &str| {
+            HeaderBuilder::new().schema(ObjectBuilder::new().schema_type(kind)).description(Some(description)).build()
+        };
+        let body = ContentBuilder::new().schema(Some(Ref::from_schema_name("ErrorResponse"))).build();
+        let throttled = ResponseBuilder::new()
+            .description("Locked out or rate limited; `throttle` in the body says for how long")
+            .content("application/json", body)
+            .header("Retry-After", header(Type::Integer, "seconds to wait"))
+            .header("X-Login-Attempts-Remaining", header(Type::Integer, "failed sign-ins left before a lockout"))
+            .header("X-Lockout-Until", header(Type::String, "RFC 3339 end of the lockout"))
+            .build();
+
+        let routes = [
+            "/login",
+            "/session",
+            "/login/otp/start",
+            "/login/otp/verify",
+            "/webauthn/login/start",
+            "/webauthn/login/finish",
+        ];
+        for path in routes {
+            if let Some(op) = openapi.paths.paths.get_mut(path).and_then(|item| item.post.as_mut()) {
+                op.responses.responses.insert("429".into(), throttled.clone().into());
+            }
+        }
+    }
+}
+
+// GET /openapi.json
+pub fn spec_json() -> String {
+    ApiDoc::openapi().to_pretty_json().unwrap()
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Response, StatusCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use uchat_core::i18n::codes;
+use uchat_core::ratelimit::{KeyedLimiter, Limit};
+use uchat_proto::api::{ErrorResponse, LoginOutcome, Throttle};
+
+use crate::security;
+use crate::{audit, body, json_status, policies, AuthState};
+
+//
+// ONE-TIME CODES
+//
+// Passwordless sign-in by email or SMS, for people setting up devices
+// where typing a password is a chore:
+//
+//   POST /login/otp/start   {"identifier": "alice@example.com"}  sends a code
+//   POST /login/otp/verify  {"identifier": ..., "code": "123456"} signs in
+//
+// An identifier is an email address or a +E.164 phone number, and is the
+// username signed in as. A code is six digits, single-use, and replaced
+// by the next one requested; only its hash is kept. It is burned after
+// AUTH_OTP_MAX_ATTEMPTS wrong guesses, and wrong guesses count towards
+// the address's lockout like failed logins. Passkey-only accounts cannot
+// use codes.
+//
+//   AUTH_OTP_TTL_SECS        how long a code is valid (default 300)
+//   AUTH_OTP_MAX_ATTEMPTS    wrong guesses before a code is burned (default 5)
+//   AUTH_RATE_LIMIT_OTP      codes sent per identifier (default 5/15m)
+//   AUTH_OTP_WEBHOOK_URL     codes are POSTed here for an email/SMS relay
+//   AUTH_OTP_LINK_URL        optional; the relay also gets a magic link,
+//                            this URL with identifier and code in the query
+//   AUTH_OTP_LOG             "1" to print codes instead (development only)
+//
+// With neither a webhook nor logging, /login/otp/start answers 503.
+//
+
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a code goes, from the shape of the identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Email,
+    Sms,
+}
+
+impl Channel {
+    fn as_str(self) -> &'static str {
+        match self {
+            Channel::Email => "email",
+            Channel::Sms => "sms",
+        }
+    }
+}
+
+/// The identifier as a username, and its channel; None if it is neither
+/// an email address nor a phone number.
+fn normalize(identifier: &str) -> Option<(String, Channel)> {
+    let identifier = identifier.trim();
+    if let Some(digits) = identifier.strip_prefix('+') {
+        let valid = (7..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit());
+        return valid.then(|| (identifier.to_string(), Channel::Sms));
+    }
+    let (local, domain) = identifier.split_once('@')?;
+    let valid = identifier.len() <= 254
+        && !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !identifier.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>'));
+    valid.then(|| (identifier.to_lowercase(), Channel::Email))
+}
+
+/// Delivers codes to people; implementations relay to email or SMS.
+#[async_trait]
+pub trait CodeSender: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn send(&self, channel: Channel, to: &str, code: &str, ttl: Duration) -> Result<(), String>;
+}
+
+/// POSTs `{"channel", "to", "code", "expires_in", "link"}` to a relay.
+pub struct WebhookSender {
+    url: String,
+    link_url: Option<String>,
+    http: reqwest::Client,
+}
+
+#[async_trait]
+impl CodeSender for WebhookSender {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn send(&self, channel: Channel, to: &str, code: &str, ttl: Duration) -> Result<(), String> {
+        let link = self.link_url.as_ref().map(|base| {
+            let query = serde_urlencoded::to_string([("identifier", to), ("code", code)]).unwrap();
+            let sep = if base.contains('?') { '&' } else { '?' };
+            format!("{}{}{}", base, sep, query)
+        });
+        let resp = self
+            .http
+            .post(&self.url)
+            .timeout(SEND_TIMEOUT)
+            .json(&json!({
+                "channel": channel,
+                "to": to,
+                "code": code,
+                "expires_in": ttl.as_secs(),
+                "link": link,
+            }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(resp.status().to_string()),
+        }
+    }
+}
+
+/// Prints codes to stdout, for development without a relay.
+pub struct LogSender;
+
+#[async_trait]
+impl CodeSender for LogSender {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn send(&self, channel: Channel, to: &str, code: &str, ttl: Duration) -> Result<(), String> {
+        println!("auth-api: otp {} for {} via {}, valid {}s", code, to, channel.as_str(), ttl.as_secs());
+        Ok(())
+    }
+}
+
+struct Issued {
+    hash: [u8; 32],
+    issued: Instant,
+    attempts: u32,
+}
+
+pub struct OneTimeCodes {
+    ttl: Duration,
+    max_attempts: u32,
+    sender: Option<Box<dyn CodeSender>>,
+    rate: KeyedLimiter<String>,
+    codes: Mutex<HashMap<String, Issued>>,
+}
+
+fn hash(identifier: &str, code: &str) -> [u8; 32] {
+    Sha256::new().chain_update(identifier).chain_update([0]).chain_update(code).finalize().into()
+}
+
+enum Checked {
+    Valid,
+    Wrong,
+    /// The guess that used up the code's attempts.
+    Burned,
+    Missing,
+}
+
+impl OneTimeCodes {
+    pub fn from_env() -> Self {
+        let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let sender: Option<Box<dyn CodeSender>> = match (env("AUTH_OTP_WEBHOOK_URL"), env("AUTH_OTP_LOG")) {
+            (Some(url), _) => Some(Box::new(WebhookSender {
+                url,
+                link_url: env("AUTH_OTP_LINK_URL"),
+                http: reqwest::Client::new(),
+            })),
+            (None, Some(flag)) if flag == "1" => Some(Box::new(LogSender)),
+            _ => None,
+        };
+        let ttl = env("AUTH_OTP_TTL_SECS").and_then(|v| v.parse().ok()).map_or(DEFAULT_TTL, Duration::from_secs);
+        Self {
+            ttl,
+            max_attempts: env("AUTH_OTP_MAX_ATTEMPTS").and_then(|v| v.parse().ok()).unwrap_or(5).max(1),
+            sender,
+            rate: KeyedLimiter::new(
+                "otp",
+                Limit::from_env("AUTH_RATE_LIMIT_OTP", Limit::new(5, Duration::from_secs(15 * 60))),
+            ),
+            codes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A fresh code for `identifier`, replacing any earlier one.
+    fn issue(&self, identifier: &str) -> String {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let issued = Issued { hash: hash(identifier, &code), issued: Instant::now(), attempts: 0 };
+        self.codes.lock().unwrap().insert(identifier.to_string(), issued);
+        code
+    }
+
+    /// Checks a guess; a valid code is consumed.
+    fn check(&self, identifier: &str, code: &str) -> Checked {
+        let mut codes = self.codes.lock().unwrap();
+        let Some(issued) = codes.get_mut(identifier).filter(|i| i.issued.elapsed() < self.ttl) else {
+            codes.remove(identifier);
+            return Checked::Missing;
+        };
+        // compare in constant time
+        let guess = hash(identifier, code);
+        if guess.iter().zip(&issued.hash).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0 {
+            codes.remove(identifier);
+            return Checked::Valid;
+        }
+        issued.attempts += 1;
+        if issued.attempts >= self.max_attempts {
+            codes.remove(identifier);
+            return Checked::Burned;
+        }
+        Checked::Wrong
+    }
+
+    /// Forgets expired codes and rate limits that have recovered.
+    pub fn expire(&self) {
+        self.rate.retain_recent();
+        self.codes.lock().unwrap().retain(|_, i| i.issued.elapsed() < self.ttl);
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OtpStart {
+    /// An email address or a +E.164 phone number.
+    identifier: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct OtpSent {
+    pub channel: Channel,
+    pub expires_in: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OtpVerify {
+    identifier: String,
+    code: String,
+}
+
+#[utoipa::path(post, path = "/login/otp/start", tag = "auth",
+    request_body = OtpStart,
+    responses((status = 202, body = OtpSent), (status = 400, body = ErrorResponse),
+        (status = 403, body = ErrorResponse), (status = 429, body = ErrorResponse),
+        (status = 503, body = ErrorResponse)))]
+pub async fn start(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let ip = security::client_ip(&req);
+    let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+    let start: OtpStart = match body::parse(&whole_body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+    let Some((identifier, channel)) = normalize(&start.identifier) else {
+        return Ok(json_status(StatusCode::BAD_REQUEST, locale, codes::OTP_INVALID_IDENTIFIER));
+    };
+
+    let otp = &state.otp;
+    let Some(sender) = otp.sender.as_deref() else {
+        return Ok(json_status(StatusCode::SERVICE_UNAVAILABLE, locale, codes::OTP_UNAVAILABLE));
+    };
+    if state.passkeys.is_passwordless(&identifier) {
+        return Ok(json_status(StatusCode::FORBIDDEN, locale, codes::AUTH_PASSWORD_DISABLED));
+    }
+    if let Err(wait) = otp.rate.check(&identifier) {
+        let throttle = Throttle { retry_after_secs: wait.as_secs() + 1, remaining_attempts: None, lockout_until: None };
+        return Ok(security::too_many_requests(locale, codes::OTP_RATE_LIMITED, throttle));
+    }
+
+    let code = otp.issue(&identifier);
+    if let Err(e) = sender.send(channel, &identifier, &code, otp.ttl).await {
+        println!("auth-api: otp delivery via {} to {} failed: {}", sender.name(), identifier, e);
+        otp.codes.lock().unwrap().remove(&identifier);
+        return Ok(json_status(StatusCode::SERVICE_UNAVAILABLE, locale, codes::OTP_UNAVAILABLE));
+    }
+    audit::record("otp.sent", &identifier, &identifier, format!("{} from {}", channel.as_str(), ip));
+
+    let sent = OtpSent { channel, expires_in: otp.ttl.as_secs() };
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&sent).unwrap()))
+        .unwrap())
+}
+
+#[utoipa::path(post, path = "/login/otp/verify", tag = "auth",
+    request_body = OtpVerify,
+    responses((status = 200, body = LoginOutcome), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse)))]
+pub async fn verify(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let ip = security::client_ip(&req);
+    let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+    let verify: OtpVerify = match body::parse(&whole_body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+    let Some((identifier, _)) = normalize(&verify.identifier) else {
+        return Ok(json_status(StatusCode::BAD_REQUEST, locale, codes::OTP_INVALID_IDENTIFIER));
+    };
+
+    let reason = match state.otp.check(&identifier, verify.code.trim()) {
+        Checked::Valid => {
+            audit::record("otp.verified", &identifier, &identifier, ip.to_string());
+            state.security.login_succeeded(ip);
+            state.accounts.cancel_deletion(&identifier);
+            return Ok(policies::login_reply(state, &identifier));
+        }
+        Checked::Wrong => "wrong one-time code",
+        Checked::Burned => {
+            audit::record("otp.burned", &identifier, &identifier, format!("too many wrong codes from {}", ip));
+            "wrong one-time code, code burned"
+        }
+        Checked::Missing => "no valid one-time code",
+    };
+    state.security.login_failed(ip, Some(&identifier), reason);
+    Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::OTP_INVALID_CODE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    use super::{verify, OneTimeCodes};
+    use crate::testing::{reply, request};
+    use crate::AuthState;
+
+    const ANN: &str = "ann@example.com";
+
+    #[tokio::test]
+    async fn codes_are_single_use() {
+        let state = AuthState::from_env();
+        let code = state.otp.issue(ANN);
+        let guess = json!({ "identifier": ANN, "code": code });
+        let (status, body) = reply(verify(&state, "en", request(None, guess.clone())).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["LoginOk"]["token"].is_string(), "{}", body);
+        let (status, body) = reply(verify(&state, "en", request(None, guess)).await).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["Error"]["code"], "otp.invalid_code");
+
+        // a newer code replaces the one before it
+        let old = state.otp.issue(ANN);
+        let new = state.otp.issue(ANN);
+        if old != new {
+            let guess = json!({ "identifier": ANN, "code": old });
+            assert_eq!(reply(verify(&state, "en", request(None, guess)).await).await.0, StatusCode::UNAUTHORIZED);
+        }
+        let guess = json!({ "identifier": ANN, "code": new });
+        assert_eq!(reply(verify(&state, "en", request(None, guess)).await).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn wrong_guesses_burn_the_code_and_count_towards_a_lockout() {
+        let mut state = AuthState::from_env();
+        state.otp = OneTimeCodes { max_attempts: 3, ..OneTimeCodes::from_env() };
+        let code = state.otp.issue(ANN);
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        let ip = IpAddr::from([0, 0, 0, 0]);
+        let left = state.security.attempts_left(ip);
+        for _ in 0..3 {
+            let guess = json!({ "identifier": ANN, "code": wrong });
+            assert_eq!(reply(verify(&state, "en", request(None, guess)).await).await.0, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(state.security.attempts_left(ip), left.saturating_sub(3));
+
+        // burned: the right code no longer works either
+        let guess = json!({ "identifier": ANN, "code": code });
+        assert_eq!(reply(verify(&state, "en", request(None, guess)).await).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
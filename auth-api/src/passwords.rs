@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+
+use crate::secrets::SecretProvider;
+
+//
+// PASSWORDS
+//
+// Passwords are kept as Argon2id PHC strings (48 MiB, 2 passes, 1 lane,
+// which is what the hashing gate in hashing.rs budgets for), keyed with an
+// application pepper that never sits next to the hashes:
+//
+//   AUTH_PASSWORD_PEPPERS  `version:pepper` pairs, comma-separated, read
+//                          through the SecretProvider; the highest
+//                          version peppers new hashes. Unset: no pepper
+//                          (version 0)
+//   AUTH_PASSWORDS_FILE    `username:hash_version:$argon2id$...` lines
+//
+// `hash_version` is the pepper version a hash was made with. A login whose
+// hash has an older version, or other Argon2 parameters, is rehashed with
+// the current ones while it still holds its hashing slot, and the file is
+// rewritten. To rotate, add a higher version and keep the old one until
+// its hashes are upgraded; a hash whose pepper is gone cannot be checked.
+//
+// /login and /session check the password; an unknown username is hashed
+// against a dummy so it answers no faster than a wrong password.
+//
+
+const MEMORY_KIB: u32 = 48 * 1024;
+const PASSES: u32 = 2;
+const LANES: u32 = 1;
+
+#[derive(Clone)]
+struct Stored {
+    hash_version: u32,
+    phc: String,
+}
+
+pub struct Passwords {
+    params: Params,
+    /// Pepper per version; version 0 is no pepper.
+    peppers: BTreeMap<u32, Vec<u8>>,
+    hashes: Mutex<HashMap<String, Stored>>,
+    /// Where `hashes` came from, rewritten after a rehash.
+    file: Option<PathBuf>,
+    /// Stands in for the hash of an unknown user.
+    dummy: OnceLock<String>,
+}
+
+fn argon2(pepper: &[u8], params: Params) -> Argon2<'_> {
+    if pepper.is_empty() {
+        return Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+    }
+    Argon2::new_with_secret(pepper, Algorithm::Argon2id, Version::V0x13, params).unwrap()
+}
+
+/// Hashes `password` into a PHC string; blocks for the whole hash.
+fn hash(pepper: &[u8], params: Params, password: &str) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).unwrap();
+    argon2(pepper, params).hash_password(password.as_bytes(), &salt).unwrap().to_string()
+}
+
+/// Checks `password` with the parameters recorded in `phc`.
+fn matches(pepper: &[u8], phc: &str, password: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(phc) else { return false };
+    argon2(pepper, Params::DEFAULT).verify_password(password.as_bytes(), &parsed).is_ok()
+}
+
+fn same_params(phc: &str, params: &Params) -> bool {
+    PasswordHash::new(phc).ok().and_then(|parsed| Params::try_from(&parsed).ok()).is_some_and(|p| {
+        (p.m_cost(), p.t_cost(), p.p_cost()) == (params.m_cost(), params.t_cost(), params.p_cost())
+    })
+}
+
+fn parse_peppers(spec: &str) -> BTreeMap<u32, Vec<u8>> {
+    let mut peppers = BTreeMap::from([(0, Vec::new())]);
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.split_once(':') {
+            Some((version, pepper)) if !pepper.is_empty() => match version.parse::<u32>() {
+                Ok(version) if version > 0 => {
+                    peppers.insert(version, pepper.as_bytes().to_vec());
+                }
+                _ => println!("auth-api: skipping pepper with bad version {:?}", version),
+            },
+            _ => println!("auth-api: skipping malformed entry in AUTH_PASSWORD_PEPPERS"),
+        }
+    }
+    peppers
+}
+
+fn load(path: &str) -> HashMap<String, Stored> {
+    let mut hashes = HashMap::new();
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            println!("auth-api: cannot read AUTH_PASSWORDS_FILE {}: {}", path, e);
+            return hashes;
+        }
+    };
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let mut fields = line.splitn(3, ':');
+        match (fields.next(), fields.next().and_then(|v| v.parse().ok()), fields.next()) {
+            (Some(user), Some(hash_version), Some(phc)) if PasswordHash::new(phc).is_ok() => {
+                hashes.insert(user.to_string(), Stored { hash_version, phc: phc.to_string() });
+            }
+            _ => println!("auth-api: skipping malformed line in {}", path),
+        }
+    }
+    println!("auth-api: loaded {} password(s) from {}", hashes.len(), path);
+    hashes
+}
+
+impl Passwords {
+    pub fn from_env(secrets: &SecretProvider) -> Self {
+        let peppers = parse_peppers(&secrets.get("AUTH_PASSWORD_PEPPERS").unwrap_or_default());
+        let file = std::env::var("AUTH_PASSWORDS_FILE").ok();
+        let hashes = file.as_deref().map(load).unwrap_or_default();
+        let params = Params::new(MEMORY_KIB, PASSES, LANES, None).unwrap();
+        Self::new(params, peppers, hashes, file.map(PathBuf::from))
+    }
+
+    fn new(
+        params: Params,
+        peppers: BTreeMap<u32, Vec<u8>>,
+        hashes: HashMap<String, Stored>,
+        file: Option<PathBuf>,
+    ) -> Self {
+        let passwords = Self { params, peppers, hashes: Mutex::new(hashes), file, dummy: OnceLock::new() };
+        let stale = passwords.hashes.lock().unwrap().values().filter(|s| passwords.is_stale(s)).count();
+        if stale > 0 {
+            println!("auth-api: {} password hash(es) will be upgraded at their next login", stale);
+        }
+        passwords
+    }
+
+    fn current_version(&self) -> u32 {
+        *self.peppers.keys().next_back().unwrap()
+    }
+
+    fn is_stale(&self, stored: &Stored) -> bool {
+        stored.hash_version != self.current_version() || !same_params(&stored.phc, &self.params)
+    }
+
+    pub fn has_password(&self, username: &str) -> bool {
+        self.hashes.lock().unwrap().contains_key(username)
+    }
+
+    /// Whether `password` is `username`'s, upgrading a stale hash when it
+    /// is. Takes as long for an unknown user; run it holding a hashing
+    /// slot.
+    pub async fn verify(&self, username: &str, password: &str) -> bool {
+        let version = self.current_version();
+        let current = self.peppers[&version].clone();
+        let params = self.params.clone();
+        let password = password.to_string();
+
+        let stored = self.hashes.lock().unwrap().get(username).cloned();
+        let Some(stored) = stored else {
+            match self.dummy.get() {
+                Some(dummy) => {
+                    let dummy = dummy.clone();
+                    let _ = tokio::task::spawn_blocking(move || matches(&current, &dummy, &password)).await;
+                }
+                None => {
+                    let made = tokio::task::spawn_blocking(move || hash(&current, params, "not a password")).await;
+                    if let Ok(dummy) = made {
+                        let _ = self.dummy.set(dummy);
+                    }
+                }
+            }
+            return false;
+        };
+        let Some(pepper) = self.peppers.get(&stored.hash_version).cloned() else {
+            println!("auth-api: no pepper version {} for {}'s password hash", stored.hash_version, username);
+            return false;
+        };
+
+        let stale = self.is_stale(&stored);
+        let phc = stored.phc.clone();
+        let checked = tokio::task::spawn_blocking(move || {
+            if !matches(&pepper, &phc, &password) {
+                return None;
+            }
+            Some(stale.then(|| hash(&current, params, &password)))
+        })
+        .await
+        .ok()
+        .flatten();
+
+        match checked {
+            None => false,
+            Some(None) => true,
+            Some(Some(upgraded)) => {
+                {
+                    let mut hashes = self.hashes.lock().unwrap();
+                    // unless it changed while this login was hashing
+                    if let Some(entry) = hashes.get_mut(username).filter(|e| e.phc == stored.phc) {
+                        *entry = Stored { hash_version: version, phc: upgraded };
+                    }
+                }
+                self.save();
+                true
+            }
+        }
+    }
+
+    pub fn remove_user(&self, username: &str) {
+        if self.hashes.lock().unwrap().remove(username).is_some() {
+            self.save();
+        }
+    }
+
+    /// Rewrites the passwords file, if there is one.
+    fn save(&self) {
+        let Some(path) = &self.file else { return };
+        let text: String = {
+            let hashes = self.hashes.lock().unwrap();
+            let mut lines: Vec<_> =
+                hashes.iter().map(|(user, s)| format!("{}:{}:{}\n", user, s.hash_version, s.phc)).collect();
+            lines.sort();
+            lines.concat()
+        };
+        let tmp = path.with_extension("tmp");
+        if let Err(e) = std::fs::write(&tmp, text).and_then(|_| std::fs::rename(&tmp, path)) {
+            println!("auth-api: cannot rewrite {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::IpAddr;
+
+    use argon2::Params;
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    use super::{hash, Passwords, Stored};
+    use crate::testing::{reply, request};
+    use crate::AuthState;
+
+    /// Cheap parameters, so tests do not hash with 48 MiB.
+    fn params(passes: u32) -> Params {
+        Params::new(64, passes, 1, None).unwrap()
+    }
+
+    /// Unpeppered passwords for `users`, hashed cheaply.
+    pub fn with(users: &[(&str, &str)]) -> Passwords {
+        let hashes = users.iter().map(|(user, password)| (user.to_string(), stored(0, "", 1, password))).collect();
+        Passwords::new(params(1), peppers(&[]), hashes, None)
+    }
+
+    fn peppers(entries: &[(u32, &str)]) -> BTreeMap<u32, Vec<u8>> {
+        let mut peppers = BTreeMap::from([(0, Vec::new())]);
+        peppers.extend(entries.iter().map(|(v, p)| (*v, p.as_bytes().to_vec())));
+        peppers
+    }
+
+    fn stored(version: u32, pepper: &str, passes: u32, password: &str) -> Stored {
+        Stored { hash_version: version, phc: hash(pepper.as_bytes(), params(passes), password) }
+    }
+
+    #[tokio::test]
+    async fn logins_upgrade_hashes_to_the_current_pepper_and_parameters() {
+        let file = std::env::temp_dir().join(format!("auth-passwords-{}", std::process::id()));
+        let hashes = HashMap::from([
+            ("ann".to_string(), stored(1, "old", 1, "hunter22")),
+            ("bob".to_string(), stored(0, "", 1, "swordfish")),
+        ]);
+        let passwords = Passwords::new(params(2), peppers(&[(1, "old"), (2, "new")]), hashes, Some(file.clone()));
+
+        assert!(!passwords.verify("ann", "hunter2").await);
+        assert_eq!(passwords.hashes.lock().unwrap()["ann"].hash_version, 1);
+        assert!(passwords.verify("ann", "hunter22").await);
+        assert!(passwords.verify("bob", "swordfish").await);
+        let upgraded = passwords.hashes.lock().unwrap().clone();
+        for user in ["ann", "bob"] {
+            assert_eq!(upgraded[user].hash_version, 2);
+            assert!(!passwords.is_stale(&upgraded[user]));
+        }
+        let saved = std::fs::read_to_string(&file).unwrap();
+        assert!(saved.starts_with("ann:2:$argon2id$") && saved.contains("\nbob:2:$argon2id$"), "{}", saved);
+
+        // once upgraded, the old pepper can go
+        let rotated = Passwords::new(params(2), peppers(&[(2, "new")]), upgraded, None);
+        assert!(rotated.verify("ann", "hunter22").await);
+        assert!(!rotated.verify("ann", "hunter2").await);
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[tokio::test]
+    async fn a_hash_is_only_checked_with_its_own_pepper() {
+        let hashes = HashMap::from([("ann".to_string(), stored(1, "wrong", 1, "hunter22"))]);
+        let passwords = Passwords::new(params(1), peppers(&[(1, "right")]), hashes, None);
+        assert!(!passwords.verify("ann", "hunter22").await);
+
+        let hashes = HashMap::from([("ann".to_string(), stored(3, "gone", 1, "hunter22"))]);
+        let passwords = Passwords::new(params(1), peppers(&[(4, "new")]), hashes, None);
+        assert!(!passwords.verify("ann", "hunter22").await);
+        assert!(!passwords.verify("nobody", "hunter22").await);
+    }
+
+    #[tokio::test]
+    async fn logins_refuse_wrong_passwords() {
+        let mut state = AuthState::from_env();
+        let hashes = HashMap::from([("ann".to_string(), stored(1, "pepper", 1, "hunter22"))]);
+        state.passwords = Passwords::new(params(1), peppers(&[(1, "pepper")]), hashes, None);
+        let ip = IpAddr::from([0, 0, 0, 0]);
+
+        for (username, password) in [("ann", "hunter2"), ("eve", "hunter22")] {
+            let left = state.security.attempts_left(ip);
+            let login = json!({ "username": username, "password": password });
+            let (status, body) = reply(crate::handle_login(&state, "en", request(None, login)).await).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body["Error"]["code"], "auth.invalid_credentials");
+            assert_eq!(state.security.attempts_left(ip), left - 1);
+        }
+        let login = json!({ "username": "ann", "password": "hunter22" });
+        let (status, body) = reply(crate::handle_login(&state, "en", request(None, login)).await).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["LoginOk"]["token"].is_string());
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{
+    AcceptPoliciesRequest, AcceptedPolicies, ErrorResponse, LoginResponse, PendingPolicies, Policies, PolicyAcceptanceRequired,
+    PolicyAcceptance, PolicyVersion, PublishPolicyRequest,
+};
+
+use crate::body;
+use crate::security;
+use crate::{audit, bearer_claims, json_ok, json_status, token_response, AuthState};
+
+//
+// TERMS OF SERVICE / PRIVACY POLICY ACCEPTANCE
+//
+// Each kind ("tos", "privacy") has a current version; publishing a new one
+// makes every user accept it again. Until they have, a login with good
+// credentials answers `PolicyAcceptanceRequired` with a single-use ticket
+// instead of a token, and POST /policies/accept with that ticket returns
+// the token. Kinds without a published version are not enforced.
+//
+//   POLICY_TOS_VERSION / POLICY_TOS_URL           version in force at start
+//   POLICY_PRIVACY_VERSION / POLICY_PRIVACY_URL
+//   POLICY_PUBLISHERS                             users allowed to publish
+//                                                 ("alice,bob")
+//
+// Like groups and passkeys, versions and acceptances live in memory.
+//
+
+const KINDS: &[&str] = &["tos", "privacy"];
+
+const TICKET_TTL: Duration = Duration::from_secs(600);
+
+struct Acceptance {
+    version: String,
+    accepted_at: DateTime<Utc>,
+    ip: IpAddr,
+}
+
+struct Ticket {
+    username: String,
+    expires: Instant,
+}
+
+pub struct PolicyStore {
+    publishers: HashSet<String>,
+    /// Every published version per kind, oldest first; the last is current.
+    versions: Mutex<BTreeMap<String, Vec<PolicyVersion>>>,
+    /// (username, kind) -> the latest version they accepted.
+    accepted: Mutex<HashMap<(String, String), Acceptance>>,
+    tickets: Mutex<HashMap<String, Ticket>>,
+}
+
+impl PolicyStore {
+    pub fn from_env() -> Self {
+        let mut versions = BTreeMap::new();
+        for kind in KINDS {
+            let prefix = format!("POLICY_{}", kind.to_uppercase());
+            let Ok(version) = std::env::var(format!("{}_VERSION", prefix)) else { continue };
+            let url = std::env::var(format!("{}_URL", prefix)).unwrap_or_default();
+            versions.insert(kind.to_string(), vec![PolicyVersion {
+                kind: kind.to_string(),
+                version,
+                url,
+                published_at: Utc::now().to_rfc3339(),
+            }]);
+        }
+
+        Self {
+            publishers: std::env::var("POLICY_PUBLISHERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|u| u.trim().to_string())
+                .filter(|u| !u.is_empty())
+                .collect(),
+            versions: Mutex::new(versions),
+            accepted: Mutex::new(HashMap::new()),
+            tickets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn current(&self) -> Vec<PolicyVersion> {
+        self.versions.lock().unwrap().values().filter_map(|v| v.last().cloned()).collect()
+    }
+
+    /// Current versions the user has not accepted yet.
+    pub fn pending(&self, username: &str) -> Vec<PolicyVersion> {
+        let accepted = self.accepted.lock().unwrap();
+        self.current()
+            .into_iter()
+            .filter(|p| {
+                accepted
+                    .get(&(username.to_string(), p.kind.clone()))
+                    .is_none_or(|a| a.version != p.version)
+            })
+            .collect()
+    }
+
+    fn issue_ticket(&self, username: &str) -> String {
+        let ticket = B64.encode(rand::random::<[u8; 32]>());
+        self.tickets.lock().unwrap().insert(ticket.clone(), Ticket {
+            username: username.to_string(),
+            expires: Instant::now() + TICKET_TTL,
+        });
+        ticket
+    }
+
+    fn ticket_user(&self, ticket: &str) -> Option<String> {
+        let tickets = self.tickets.lock().unwrap();
+        tickets.get(ticket).filter(|t| t.expires > Instant::now()).map(|t| t.username.clone())
+    }
+
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.tickets.lock().unwrap().retain(|_, t| t.expires > now);
+    }
+}
+
+/// What a login with good credentials gets: the token, or a ticket while
+/// policies are pending.
+pub fn login_reply(state: &AuthState, username: &str) -> Response<Body> {
+    let pending = state.policies.pending(username);
+    if pending.is_empty() {
+        return token_response(state, username);
+    }
+    let ticket = state.policies.issue_ticket(username);
+    let reply = PolicyAcceptanceRequired { required: PendingPolicies { ticket, policies: pending } };
+    json_ok(serde_json::to_string(&reply).unwrap())
+}
+
+#[utoipa::path(get, path = "/policies", tag = "policies",
+    responses((status = 200, body = Policies)))]
+pub async fn current(state: &AuthState) -> Result<Response<Body>, hyper::Error> {
+    let policies = Policies { policies: state.policies.current() };
+    Ok(json_ok(serde_json::to_string(&policies).unwrap()))
+}
+
+#[utoipa::path(post, path = "/policies", tag = "policies",
+    request_body = PublishPolicyRequest,
+    security(("bearer" = [])),
+    responses((status = 201, body = PolicyVersion), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse), (status = 403, body = ErrorResponse),
+        (status = 409, body = ErrorResponse)))]
+pub async fn publish(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(claims) = bearer_claims(state, &req) else {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    };
+    if !state.policies.publishers.contains(&claims.sub) {
+        return Ok(json_status(StatusCode::FORBIDDEN, locale, codes::AUTH_FORBIDDEN));
+    }
+    let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+    let publish: PublishPolicyRequest = match body::parse(&whole_body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+    if !KINDS.contains(&publish.kind.as_str()) {
+        return Ok(json_status(StatusCode::BAD_REQUEST, locale, codes::POLICY_UNKNOWN_KIND));
+    }
+
+    let version = PolicyVersion {
+        kind: publish.kind,
+        version: publish.version,
+        url: publish.url,
+        published_at: Utc::now().to_rfc3339(),
+    };
+    {
+        let mut versions = state.policies.versions.lock().unwrap();
+        let history = versions.entry(version.kind.clone()).or_default();
+        if history.iter().any(|v| v.version == version.version) {
+            return Ok(json_status(StatusCode::CONFLICT, locale, codes::POLICY_VERSION_EXISTS));
+        }
+        history.push(version.clone());
+    }
+    audit::record("policy.published", &claims.sub, &version.kind, &version.version);
+
+    let mut resp = json_ok(serde_json::to_string(&version).unwrap());
+    *resp.status_mut() = StatusCode::CREATED;
+    Ok(resp)
+}
+
+#[utoipa::path(post, path = "/policies/accept", tag = "policies",
+    request_body = AcceptPoliciesRequest,
+    security((), ("bearer" = [])),
+    responses((status = 200, body = LoginResponse), (status = 401, body = ErrorResponse),
+        (status = 409, body = ErrorResponse)))]
+pub async fn accept(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let ip = security::client_ip(&req);
+    let bearer = bearer_claims(state, &req).map(|c| c.sub);
+    let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+    let accept: AcceptPoliciesRequest = match body::parse(&whole_body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    let username = match &accept.ticket {
+        Some(ticket) => state.policies.ticket_user(ticket),
+        None => bearer,
+    };
+    let Some(username) = username else {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    };
+
+    // everything accepted must be in force, and everything pending accepted
+    let current = state.policies.current();
+    let in_force = |kind: &str, version: &str| current.iter().any(|p| p.kind == kind && p.version == version);
+    let all_current = accept.accept.iter().all(|a| in_force(&a.kind, &a.version));
+    let covers_pending = state
+        .policies
+        .pending(&username)
+        .iter()
+        .all(|p| accept.accept.iter().any(|a| a.kind == p.kind && a.version == p.version));
+    if !all_current || !covers_pending {
+        return Ok(json_status(StatusCode::CONFLICT, locale, codes::POLICY_NOT_CURRENT));
+    }
+
+    if let Some(ticket) = &accept.ticket {
+        state.policies.tickets.lock().unwrap().remove(ticket);
+    }
+    let now = Utc::now();
+    {
+        let mut accepted = state.policies.accepted.lock().unwrap();
+        for a in &accept.accept {
+            accepted.insert((username.clone(), a.kind.clone()), Acceptance {
+                version: a.version.clone(),
+                accepted_at: now,
+                ip,
+            });
+            audit::record("policy.accepted", &username, &a.kind, format!("version={} ip={}", a.version, ip));
+        }
+    }
+
+    Ok(token_response(state, &username))
+}
+
+#[utoipa::path(get, path = "/policies/accepted", tag = "policies",
+    security(("bearer" = [])),
+    responses((status = 200, body = AcceptedPolicies), (status = 401, body = ErrorResponse)))]
+pub async fn accepted(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let Some(claims) = bearer_claims(state, &req) else {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    };
+    let accepted = state.policies.accepted.lock().unwrap();
+    let mut policies: Vec<PolicyAcceptance> = accepted
+        .iter()
+        .filter(|((user, _), _)| *user == claims.sub)
+        .map(|((_, kind), a)| PolicyAcceptance {
+            kind: kind.clone(),
+            version: a.version.clone(),
+            accepted_at: a.accepted_at.to_rfc3339(),
+            ip: a.ip.to_string(),
+        })
+        .collect();
+    policies.sort_by(|a, b| a.kind.cmp(&b.kind));
+    Ok(json_ok(serde_json::to_string(&AcceptedPolicies { policies }).unwrap()))
+}
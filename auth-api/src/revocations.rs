@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{ErrorResponse, Revocation, RevocationFeed};
+
+use crate::security::bearer_matches;
+use crate::{json_ok, json_status, AuthState};
+
+//
+// REVOCATION FEED
+//
+// A revoked token is refused here at once, but services that verify tokens
+// by their signature would still take it: gateway WebSockets stay open on
+// it, chat-service keeps serving it. Every revocation is appended to a feed
+// they poll to refuse it as well:
+//
+//   GET /revocations?after=<id>  entries after id, oldest first (bearer
+//                                REVOCATIONS_FEED_TOKEN)
+//
+// An entry revokes either every token of a user issued before a time (the
+// account was revoked, see account.rs) or one token by its jti (a
+// delegated token revoked or replaced, a web session ended). Token entries
+// go once the token has expired anyway; user entries are kept, the newest
+// REVOCATIONS_KEEP at most.
+//
+//   REVOCATIONS_FEED_TOKEN  bearer token for the feed; disabled while unset
+//   REVOCATIONS_KEEP        entries kept (default 10000)
+//
+
+pub struct RevocationLog {
+    feed_token: Option<String>,
+    keep: usize,
+    /// Oldest first, with ids counting up from 1.
+    entries: Mutex<VecDeque<Revocation>>,
+    next_id: Mutex<u64>,
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    #[serde(default)]
+    after: u64,
+}
+
+impl RevocationLog {
+    pub fn from_env() -> Self {
+        Self {
+            feed_token: std::env::var("REVOCATIONS_FEED_TOKEN").ok().filter(|t| !t.is_empty()),
+            keep: std::env::var("REVOCATIONS_KEEP").ok().and_then(|v| v.parse().ok()).unwrap_or(10_000),
+            entries: Mutex::new(VecDeque::new()),
+            next_id: Mutex::new(1),
+        }
+    }
+
+    /// Every token of `sub` issued before `before` (unix seconds).
+    pub fn revoke_user(&self, sub: &str, before: usize) {
+        self.append(|id| Revocation::User { id, sub: sub.to_string(), before });
+    }
+
+    /// The token with id `jti`, which expires at `expires_at`.
+    pub fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) {
+        let exp = expires_at.timestamp().max(0) as usize;
+        self.append(|id| Revocation::Token { id, jti: jti.to_string(), exp });
+    }
+
+    fn append(&self, entry: impl FnOnce(u64) -> Revocation) {
+        let mut next_id = self.next_id.lock().unwrap();
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(entry(*next_id));
+        *next_id += 1;
+        while entries.len() > self.keep {
+            entries.pop_front();
+        }
+    }
+
+    /// Drops token entries for tokens that have expired.
+    pub fn expire(&self) {
+        let now = Utc::now().timestamp().max(0) as usize;
+        self.entries.lock().unwrap().retain(|r| !matches!(r, Revocation::Token { exp, .. } if *exp <= now));
+    }
+}
+
+#[utoipa::path(get, path = "/revocations", tag = "auth",
+    params(("after" = Option<u64>, Query, description = "last entry id seen; entries after it are returned")),
+    security(("bearer" = [])),
+    responses((status = 200, body = RevocationFeed), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse)))]
+pub async fn feed(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let log = &state.revocations;
+    if !bearer_matches(&req, log.feed_token.as_deref()) {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    }
+    let Ok(query) = serde_urlencoded::from_str::<FeedQuery>(req.uri().query().unwrap_or("")) else {
+        return Ok(json_status(StatusCode::BAD_REQUEST, locale, codes::REQUEST_INVALID_FIELDS));
+    };
+
+    let revocations = log.entries.lock().unwrap().iter().filter(|r| r.id() > query.after).cloned().collect();
+    Ok(json_ok(serde_json::to_string(&RevocationFeed { revocations }).unwrap()))
+}
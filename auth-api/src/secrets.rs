@@ -0,0 +1,30 @@
+//
+// SECRETS
+//
+// Secrets other than the shared JWT secret are read through one provider,
+// so each can come from the environment or from a mounted file:
+//
+//   NAME        the secret itself
+//   NAME_FILE   a file holding it (Docker and Kubernetes secrets); wins
+//               over NAME, surrounding whitespace trimmed
+//
+// Unreadable files are logged and treated as unset.
+//
+
+#[derive(Default)]
+pub struct SecretProvider;
+
+impl SecretProvider {
+    pub fn get(&self, name: &str) -> Option<String> {
+        if let Ok(path) = std::env::var(format!("{}_FILE", name)) {
+            return match std::fs::read_to_string(&path) {
+                Ok(text) => Some(text.trim().to_string()),
+                Err(e) => {
+                    println!("auth-api: cannot read {}_FILE {}: {}", name, path, e);
+                    None
+                }
+            };
+        }
+        std::env::var(name).ok()
+    }
+}
@@ -0,0 +1,608 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_core::i18n::{self, codes};
+use uchat_core::ratelimit::{KeyedLimiter, Limit};
+use uchat_proto::api::{ErrorBody, ErrorResponse, Throttle};
+
+use crate::{json_ok, json_status, AuthState};
+
+//
+// SECURITY EVENTS
+//
+// Failed logins, lockouts and requests refused during a lockout, each with
+// the client IP, so edge firewalls and fail2ban can act on them:
+//
+//   AUTH_LOCKOUT_THRESHOLD     failed logins from one IP that trigger a lockout (default 5)
+//   AUTH_LOCKOUT_WINDOW_SECS   window those failures are counted in (default 300)
+//   AUTH_LOCKOUT_SECS          how long the IP is refused login (default 900)
+//   AUTH_RATE_LIMIT_LOGIN      sign-in attempts per IP, failed or not
+//                              (default 30/1m, see uchat_core::ratelimit)
+//   AUTH_TRUST_FORWARDED_FOR   "1" to take the client IP from the last
+//                              X-Forwarded-For hop (only behind a proxy)
+//   SECURITY_FEED_TOKEN        bearer token for GET /security/events; the
+//                              feed is disabled while unset
+//
+// Sign-in attempts refused for a lockout or the rate limit answer 429 with
+// when to try again, so clients can show a countdown:
+//
+//   Retry-After                  seconds to wait
+//   X-Login-Attempts-Remaining   failed sign-ins left before a lockout
+//   X-Lockout-Until              RFC 3339 end of the lockout, when locked out
+//
+// and the same values in the error body's `throttle`.
+//
+// The newest events (SECURITY_EVENTS_KEEP, default 10000) are kept in
+// memory; consumers poll with `since` to pick up where they left off.
+//
+// For the /stats dashboard the monitor also counts refused attempts per
+// address block (/24 for IPv4, /64 for IPv6) and login outcomes per minute,
+// both over the last 24 hours.
+//
+
+const STATS_WINDOW_MINUTES: i64 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    LoginFailed,
+    Lockout,
+    /// A sign-in attempt refused because its IP is locked out or over
+    /// the sign-in rate limit.
+    RateLimited,
+}
+
+impl SecurityEventKind {
+    fn severity(self) -> Severity {
+        match self {
+            SecurityEventKind::LoginFailed => Severity::Info,
+            SecurityEventKind::RateLimited => Severity::Warning,
+            SecurityEventKind::Lockout => Severity::Critical,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SecurityEventKind::LoginFailed => "login_failed",
+            SecurityEventKind::Lockout => "lockout",
+            SecurityEventKind::RateLimited => "rate_limited",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SecurityEvent {
+    pub id: u64,
+    pub ts: DateTime<Utc>,
+    pub kind: SecurityEventKind,
+    pub severity: Severity,
+    #[schema(value_type = String)]
+    pub ip: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub detail: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SecurityEvents {
+    pub events: Vec<SecurityEvent>,
+}
+
+/// The client address, stored in request extensions by `handle_request`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+pub fn client_ip(req: &Request<Body>) -> IpAddr {
+    req.extensions().get::<ClientIp>().map(|c| c.0).unwrap_or(IpAddr::from([0, 0, 0, 0]))
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+struct Failures {
+    /// Failure times inside the window.
+    recent: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+struct Offender {
+    hits: u64,
+    last_hit: DateTime<Utc>,
+}
+
+struct LoginMinute {
+    /// Minutes since the epoch.
+    minute: i64,
+    succeeded: u64,
+    failed: u64,
+}
+
+/// The address block an IP is counted under.
+pub fn ip_bucket(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+    }
+}
+
+pub struct SecurityMonitor {
+    threshold: usize,
+    window: Duration,
+    lockout: Duration,
+    keep: usize,
+    trust_forwarded: bool,
+    feed_token: Option<String>,
+    login_rate: KeyedLimiter<IpAddr>,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+    events: Mutex<VecDeque<SecurityEvent>>,
+    next_id: AtomicU64,
+    offenders: Mutex<HashMap<String, Offender>>,
+    logins: Mutex<VecDeque<LoginMinute>>,
+}
+
+impl SecurityMonitor {
+    pub fn from_env() -> Self {
+        Self {
+            threshold: env_or("AUTH_LOCKOUT_THRESHOLD", 5).max(1),
+            window: Duration::from_secs(env_or("AUTH_LOCKOUT_WINDOW_SECS", 300)),
+            lockout: Duration::from_secs(env_or("AUTH_LOCKOUT_SECS", 900)),
+            keep: env_or("SECURITY_EVENTS_KEEP", 10_000),
+            trust_forwarded: std::env::var("AUTH_TRUST_FORWARDED_FOR").is_ok_and(|v| v == "1"),
+            feed_token: std::env::var("SECURITY_FEED_TOKEN").ok().filter(|t| !t.is_empty()),
+            login_rate: KeyedLimiter::new(
+                "login",
+                Limit::from_env("AUTH_RATE_LIMIT_LOGIN", Limit::new(30, Duration::from_secs(60))),
+            ),
+            failures: Mutex::new(HashMap::new()),
+            events: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(1),
+            offenders: Mutex::new(HashMap::new()),
+            logins: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The address to account a request to: the peer, or the last
+    /// X-Forwarded-For hop when the proxy in front is trusted.
+    pub fn resolve_ip(&self, req: &Request<Body>, peer: SocketAddr) -> IpAddr {
+        if self.trust_forwarded {
+            let forwarded = req
+                .headers()
+                .get("X-Forwarded-For")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit(',').next())
+                .and_then(|hop| hop.trim().parse().ok());
+            if let Some(ip) = forwarded {
+                return ip;
+            }
+        }
+        peer.ip()
+    }
+
+    fn record(&self, kind: SecurityEventKind, ip: IpAddr, username: Option<&str>, detail: impl Into<String>) {
+        let event = SecurityEvent {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            ts: Utc::now(),
+            kind,
+            severity: kind.severity(),
+            ip,
+            username: username.map(str::to_string),
+            detail: detail.into(),
+        };
+        println!(
+            "auth-api: security {} ip={} user={} {}",
+            kind.as_str(),
+            ip,
+            username.unwrap_or("-"),
+            event.detail
+        );
+
+        match kind {
+            SecurityEventKind::RateLimited => {
+                let mut offenders = self.offenders.lock().unwrap();
+                let offender = offenders.entry(ip_bucket(ip)).or_insert(Offender { hits: 0, last_hit: event.ts });
+                offender.hits += 1;
+                offender.last_hit = event.ts;
+            }
+            SecurityEventKind::LoginFailed => self.count_login(false),
+            SecurityEventKind::Lockout => {}
+        }
+
+        let mut events = self.events.lock().unwrap();
+        events.push_back(event);
+        while events.len() > self.keep {
+            events.pop_front();
+        }
+    }
+
+    /// Time left on `ip`'s lockout; a refused attempt is recorded.
+    pub fn locked_out(&self, ip: IpAddr, route: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let remaining = {
+            let failures = self.failures.lock().unwrap();
+            failures.get(&ip)?.locked_until.filter(|until| *until > now).map(|until| until - now)
+        }?;
+        self.record(SecurityEventKind::RateLimited, ip, None, format!("{} refused, locked out", route));
+        Some(remaining)
+    }
+
+    /// Takes a sign-in attempt from `ip`'s rate limit; when it is used up,
+    /// how long until the next attempt is allowed.
+    pub fn throttled(&self, ip: IpAddr, route: &str) -> Option<Duration> {
+        let wait = self.login_rate.check(&ip).err()?;
+        self.record(SecurityEventKind::RateLimited, ip, None, format!("{} refused, rate limited", route));
+        Some(wait)
+    }
+
+    /// Failed logins `ip` may still make before it is locked out.
+    pub fn attempts_left(&self, ip: IpAddr) -> u32 {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        let recent = failures
+            .get(&ip)
+            .map_or(0, |f| f.recent.iter().filter(|t| now.duration_since(**t) <= self.window).count());
+        self.threshold.saturating_sub(recent) as u32
+    }
+
+    /// Counts a failed login from `ip`, locking it out at the threshold.
+    pub fn login_failed(&self, ip: IpAddr, username: Option<&str>, reason: &str) {
+        self.record(SecurityEventKind::LoginFailed, ip, username, reason);
+
+        let now = Instant::now();
+        let locked = {
+            let mut failures = self.failures.lock().unwrap();
+            let entry = failures.entry(ip).or_insert_with(|| Failures { recent: VecDeque::new(), locked_until: None });
+            while entry.recent.front().is_some_and(|t| now.duration_since(*t) > self.window) {
+                entry.recent.pop_front();
+            }
+            entry.recent.push_back(now);
+            if entry.recent.len() >= self.threshold {
+                entry.recent.clear();
+                entry.locked_until = Some(now + self.lockout);
+                true
+            } else {
+                false
+            }
+        };
+        if locked {
+            let detail = format!("{} failed logins within {}s, locked for {}s", self.threshold, self.window.as_secs(), self.lockout.as_secs());
+            self.record(SecurityEventKind::Lockout, ip, username, detail);
+        }
+    }
+
+    pub fn login_succeeded(&self, ip: IpAddr) {
+        self.failures.lock().unwrap().remove(&ip);
+        self.count_login(true);
+    }
+
+    fn count_login(&self, succeeded: bool) {
+        let minute = Utc::now().timestamp() / 60;
+        let mut logins = self.logins.lock().unwrap();
+        if logins.back().is_none_or(|m| m.minute != minute) {
+            logins.push_back(LoginMinute { minute, succeeded: 0, failed: 0 });
+        }
+        let current = logins.back_mut().unwrap();
+        if succeeded {
+            current.succeeded += 1;
+        } else {
+            current.failed += 1;
+        }
+        while logins.front().is_some_and(|m| m.minute <= minute - STATS_WINDOW_MINUTES) {
+            logins.pop_front();
+        }
+    }
+
+    /// (succeeded, failed) logins over the last `minutes`, at most 24 hours.
+    pub fn login_counts(&self, minutes: i64) -> (u64, u64) {
+        let since = Utc::now().timestamp() / 60 - minutes;
+        let logins = self.logins.lock().unwrap();
+        logins
+            .iter()
+            .filter(|m| m.minute > since)
+            .fold((0, 0), |(ok, failed), m| (ok + m.succeeded, failed + m.failed))
+    }
+
+    /// Address blocks with the most refused attempts in the last 24 hours,
+    /// and the total refused.
+    pub fn top_offenders(&self, limit: usize) -> (u64, Vec<(String, u64, DateTime<Utc>)>) {
+        let offenders = self.offenders.lock().unwrap();
+        let total = offenders.values().map(|o| o.hits).sum();
+        let mut top: Vec<_> = offenders.iter().map(|(bucket, o)| (bucket.clone(), o.hits, o.last_hit)).collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)));
+        top.truncate(limit);
+        (total, top)
+    }
+
+    /// Forgets addresses with no recent failures and no active lockout,
+    /// and rate limits that have recovered.
+    pub fn expire(&self) {
+        self.login_rate.retain_recent();
+        let now = Instant::now();
+        self.failures.lock().unwrap().retain(|_, f| {
+            f.locked_until.is_some_and(|until| until > now)
+                || f.recent.back().is_some_and(|t| now.duration_since(*t) <= self.window)
+        });
+        let cutoff = Utc::now() - chrono::Duration::minutes(STATS_WINDOW_MINUTES);
+        self.offenders.lock().unwrap().retain(|_, o| o.last_hit > cutoff);
+    }
+
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        bearer_matches(req, self.feed_token.as_deref())
+    }
+}
+
+/// Whether the request carries `Authorization: Bearer <expected>`; always
+/// false when no token is configured.
+pub fn bearer_matches(req: &Request<Body>, expected: Option<&str>) -> bool {
+    let Some(expected) = expected else { return false };
+    let presented = req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // compare in constant time
+    presented.len() == expected.len()
+        && presented.bytes().zip(expected.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// 429 for a locked-out address.
+pub fn locked_out_response(locale: &str, remaining: Duration) -> Response<Body> {
+    let until = Utc::now() + chrono::Duration::from_std(remaining).unwrap_or_default();
+    let throttle = Throttle {
+        retry_after_secs: remaining.as_secs() + 1,
+        remaining_attempts: Some(0),
+        lockout_until: Some(until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+    };
+    too_many_requests(locale, codes::AUTH_LOCKED_OUT, throttle)
+}
+
+/// 429 for an address over its sign-in rate limit, which has
+/// `attempts_left` failures to go before a lockout.
+pub fn throttled_response(locale: &str, wait: Duration, attempts_left: u32) -> Response<Body> {
+    let throttle =
+        Throttle { retry_after_secs: wait.as_secs() + 1, remaining_attempts: Some(attempts_left), lockout_until: None };
+    too_many_requests(locale, codes::AUTH_RATE_LIMITED, throttle)
+}
+
+/// 429 with `throttle` in the headers and the error body.
+pub fn too_many_requests(locale: &str, code: &str, throttle: Throttle) -> Response<Body> {
+    let mut resp = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Content-Type", "application/json")
+        .header("Retry-After", throttle.retry_after_secs);
+    if let Some(attempts) = throttle.remaining_attempts {
+        resp = resp.header("X-Login-Attempts-Remaining", attempts);
+    }
+    if let Some(until) = &throttle.lockout_until {
+        resp = resp.header("X-Lockout-Until", until.as_str());
+    }
+    let error = ErrorResponse {
+        error: ErrorBody {
+            details: i18n::catalog().render(locale, code, &[]),
+            code: Some(code.into()),
+            throttle: Some(throttle),
+        },
+    };
+    resp.body(Body::from(serde_json::to_string(&error).unwrap())).unwrap()
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    severity: Option<Severity>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+    /// "json" (default) or "text": one line per event for log-based tools.
+    #[serde(default)]
+    format: Option<String>,
+}
+
+fn default_limit() -> usize {
+    500
+}
+
+fn text_line(e: &SecurityEvent) -> String {
+    format!(
+        "{} {} {} ip={} user={} detail={:?}\n",
+        e.ts.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        e.severity.as_str(),
+        e.kind.as_str(),
+        e.ip,
+        e.username.as_deref().unwrap_or("-"),
+        e.detail
+    )
+}
+
+#[utoipa::path(get, path = "/security/events", tag = "security",
+    params(
+        ("since" = Option<String>, Query, description = "RFC 3339 time; only events at or after it"),
+        ("until" = Option<String>, Query, description = "RFC 3339 time; only events before it"),
+        ("severity" = Option<Severity>, Query, description = "minimum severity"),
+        ("limit" = Option<usize>, Query, description = "newest events returned (default 500, max 5000)"),
+        ("format" = Option<String>, Query, description = "`json` (default) or `text`, one event per line"),
+    ),
+    security(("bearer" = [])),
+    responses((status = 200, body = SecurityEvents), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse)))]
+pub async fn events(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let monitor = &state.security;
+    if !monitor.authorized(&req) {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    }
+    let Ok(query) = serde_urlencoded::from_str::<EventsQuery>(req.uri().query().unwrap_or("")) else {
+        return Ok(json_status(StatusCode::BAD_REQUEST, locale, codes::REQUEST_INVALID_FIELDS));
+    };
+
+    let events = monitor.events.lock().unwrap();
+    let mut matching: Vec<SecurityEvent> = events
+        .iter()
+        .rev()
+        .filter(|e| query.since.is_none_or(|since| e.ts >= since))
+        .filter(|e| query.until.is_none_or(|until| e.ts < until))
+        .filter(|e| query.severity.is_none_or(|min| e.severity >= min))
+        .take(query.limit.min(5000))
+        .cloned()
+        .collect();
+    drop(events);
+    matching.reverse();
+
+    if query.format.as_deref() == Some("text") {
+        let body: String = matching.iter().map(text_line).collect();
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Body::from(body))
+            .unwrap());
+    }
+    Ok(json_ok(serde_json::to_string(&SecurityEvents { events: matching }).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+    use hyper::{Body, HeaderMap, Request, StatusCode};
+    use serde_json::Value;
+
+    use uchat_core::ratelimit::{KeyedLimiter, Limit};
+
+    use super::SecurityEventKind::{Lockout, LoginFailed, RateLimited};
+    use super::{SecurityEventKind, SecurityMonitor};
+    use crate::AuthState;
+
+    fn monitor(threshold: usize, window: Duration, lockout: Duration) -> SecurityMonitor {
+        SecurityMonitor { threshold, window, lockout, ..SecurityMonitor::from_env() }
+    }
+
+    fn kinds(monitor: &SecurityMonitor) -> Vec<SecurityEventKind> {
+        monitor.events.lock().unwrap().iter().map(|e| e.kind).collect()
+    }
+
+    /// A password sign-in from `ip` with no password, so it never gets as
+    /// far as hashing.
+    async fn sign_in(state: &Arc<AuthState>, ip: IpAddr) -> (StatusCode, HeaderMap, Value) {
+        let req = Request::post("/login").body(Body::from(r#"{"username": "ann"}"#)).unwrap();
+        let resp = crate::route_request(state.clone(), SocketAddr::new(ip, 4000), req).await.unwrap();
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (parts.status, parts.headers, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn lockouts_start_at_the_threshold_and_expire() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let monitor = monitor(3, Duration::from_secs(60), Duration::from_millis(100));
+        monitor.login_failed(ip, Some("ann"), "wrong password");
+        monitor.login_failed(ip, Some("ann"), "wrong password");
+        assert_eq!(monitor.attempts_left(ip), 1);
+        assert!(monitor.locked_out(ip, "/login").is_none());
+
+        monitor.login_failed(ip, Some("ann"), "wrong password");
+        assert!(monitor.locked_out(ip, "/login").is_some_and(|left| left <= Duration::from_millis(100)));
+        assert!(monitor.locked_out(IpAddr::from([192, 0, 2, 2]), "/login").is_none());
+        assert_eq!(kinds(&monitor), [LoginFailed, LoginFailed, LoginFailed, Lockout, RateLimited]);
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(monitor.locked_out(ip, "/login").is_none());
+        assert_eq!(monitor.attempts_left(ip), 3);
+        monitor.expire();
+        assert!(monitor.failures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_recent_failures_count_towards_a_lockout() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let monitor = monitor(2, Duration::from_millis(50), Duration::from_secs(60));
+        monitor.login_failed(ip, None, "wrong password");
+        std::thread::sleep(Duration::from_millis(80));
+        assert_eq!(monitor.attempts_left(ip), 2);
+        monitor.login_failed(ip, None, "wrong password");
+        assert!(monitor.locked_out(ip, "/login").is_none());
+
+        // signing in forgets earlier failures
+        monitor.login_succeeded(ip);
+        monitor.login_failed(ip, None, "wrong password");
+        assert!(monitor.locked_out(ip, "/login").is_none());
+        monitor.login_failed(ip, None, "wrong password");
+        assert!(monitor.locked_out(ip, "/login").is_some());
+    }
+
+    #[tokio::test]
+    async fn refused_sign_ins_say_when_to_try_again() {
+        let mut state = AuthState::from_env();
+        state.security = SecurityMonitor {
+            login_rate: KeyedLimiter::new("login", Limit::new(2, Duration::from_secs(60))),
+            ..monitor(3, Duration::from_secs(60), Duration::from_secs(600))
+        };
+        let state = Arc::new(state);
+
+        let locked = IpAddr::from([192, 0, 2, 1]);
+        for _ in 0..3 {
+            state.security.login_failed(locked, Some("ann"), "wrong password");
+        }
+        let (status, headers, body) = sign_in(&state, locked).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = headers["retry-after"].to_str().unwrap().parse().unwrap();
+        assert!((599..=601).contains(&retry_after), "{}", retry_after);
+        assert_eq!(headers["x-login-attempts-remaining"], "0");
+        let until = headers["x-lockout-until"].to_str().unwrap();
+        let ahead = DateTime::parse_from_rfc3339(until).unwrap().with_timezone(&Utc) - Utc::now();
+        assert!((598..=600).contains(&ahead.num_seconds()), "{}", until);
+        let throttle = &body["Error"]["throttle"];
+        assert_eq!(body["Error"]["code"], "auth.locked_out");
+        assert_eq!((&throttle["retry_after_secs"], &throttle["lockout_until"]), (&retry_after.into(), &until.into()));
+
+        // over the rate limit, the failures still left before a lockout
+        let busy = IpAddr::from([192, 0, 2, 2]);
+        state.security.login_failed(busy, Some("ann"), "wrong password");
+        for _ in 0..2 {
+            assert_eq!(sign_in(&state, busy).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let (status, headers, body) = sign_in(&state, busy).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = headers["retry-after"].to_str().unwrap().parse().unwrap();
+        assert!((1..=61).contains(&retry_after), "{}", retry_after);
+        assert_eq!(headers["x-login-attempts-remaining"], "2");
+        assert!(!headers.contains_key("x-lockout-until"));
+        assert_eq!(body["Error"]["code"], "auth.rate_limited");
+        let throttle = serde_json::json!({ "retry_after_secs": retry_after, "remaining_attempts": 2 });
+        assert_eq!(body["Error"]["throttle"], throttle);
+    }
+}
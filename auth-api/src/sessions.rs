@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{ErrorResponse, LoginOutcome, LoginRequest, SessionInfo};
+use uchat_proto::jwt::{
+    create_session_token, session_cookie, verify_session_claims, Claims, CSRF_HEADER, SESSION_AUDIENCE, SESSION_COOKIE,
+};
+
+use crate::body;
+use crate::{json_ok, json_status, not_found, policies, security, AuthState};
+
+//
+// COOKIE SESSIONS
+//
+// Optional sign-in for web clients that keeps the token away from page
+// scripts. POST /session checks credentials like /login, records a session
+// server-side and sets an HttpOnly cookie with a JWT for it. That JWT's
+// audience is the web session, so it is refused as a Bearer token
+// everywhere, and it is only honoured while its session record lives;
+// DELETE /session ends it. The gateway takes the cookie on WebSocket
+// upgrades from GATEWAY_ALLOWED_ORIGINS.
+//
+// Browsers attach the cookie to cross-site requests too, so requests it
+// authenticates that change state (any method but GET and HEAD) must
+// carry the session's CSRF token in X-CSRF-Token. GET /session returns
+// the token, e.g. after a page reload.
+//
+//   AUTH_COOKIE_SESSIONS   "1" to enable (default off; /session is 404)
+//   AUTH_SESSION_TTL_SECS  session lifetime (default 43200)
+//   AUTH_COOKIE_SAMESITE   "Strict" (default) or "Lax"
+//   AUTH_COOKIE_DOMAIN     Domain attribute, when the gateway is on another
+//                          host of the same site
+//   AUTH_COOKIE_INSECURE   "1" to leave out the Secure attribute, for
+//                          plain-HTTP development only
+//
+// Like delegations, sessions live in memory.
+//
+
+struct Record {
+    user: String,
+    csrf: String,
+    expires_at: DateTime<Utc>,
+}
+
+pub struct Sessions {
+    enabled: bool,
+    ttl: u64,
+    same_site: &'static str,
+    domain: Option<String>,
+    secure: bool,
+    active: Mutex<HashMap<String, Record>>,
+}
+
+impl Sessions {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: std::env::var("AUTH_COOKIE_SESSIONS").is_ok_and(|v| v == "1"),
+            ttl: std::env::var("AUTH_SESSION_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(12 * 3600),
+            same_site: match std::env::var("AUTH_COOKIE_SAMESITE") {
+                Ok(v) if v.eq_ignore_ascii_case("lax") => "Lax",
+                _ => "Strict",
+            },
+            domain: std::env::var("AUTH_COOKIE_DOMAIN").ok().filter(|d| !d.is_empty()),
+            secure: !std::env::var("AUTH_COOKIE_INSECURE").is_ok_and(|v| v == "1"),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Session tokens whose session has ended; other tokens are not this
+    /// store's business.
+    pub fn is_revoked(&self, claims: &Claims) -> bool {
+        if claims.aud.as_deref() != Some(SESSION_AUDIENCE) {
+            return false;
+        }
+        let active = self.active.lock().unwrap();
+        let record = claims.jti.as_ref().and_then(|sid| active.get(sid));
+        record.is_none_or(|r| r.user != claims.sub || r.expires_at <= Utc::now())
+    }
+
+    /// Ends every session of `user`; returns how many.
+    pub fn revoke_all(&self, user: &str) -> usize {
+        let mut active = self.active.lock().unwrap();
+        let before = active.len();
+        active.retain(|_, r| r.user != user);
+        before - active.len()
+    }
+
+    pub fn expire(&self) {
+        let now = Utc::now();
+        self.active.lock().unwrap().retain(|_, r| r.expires_at > now);
+    }
+
+    fn cookie(&self, value: &str, max_age: u64) -> String {
+        let mut cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite={}; Max-Age={}",
+            SESSION_COOKIE, value, self.same_site, max_age
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if let Some(domain) = &self.domain {
+            cookie.push_str("; Domain=");
+            cookie.push_str(domain);
+        }
+        cookie
+    }
+
+    fn info(&self, sid: &str) -> Option<SessionInfo> {
+        let active = self.active.lock().unwrap();
+        let record = active.get(sid)?;
+        Some(SessionInfo {
+            user: record.user.clone(),
+            csrf_token: record.csrf.clone(),
+            expires_at: record.expires_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Claims from a live session cookie, when the request has one.
+pub fn cookie_claims(state: &AuthState, req: &Request<Body>) -> Option<Claims> {
+    if !state.sessions.enabled {
+        return None;
+    }
+    let header = req.headers().get(hyper::header::COOKIE)?.to_str().ok()?;
+    let claims = verify_session_claims(&state.secret, session_cookie(header)?)?;
+    (!crate::is_revoked(state, &claims)).then_some(claims)
+}
+
+/// For a state-changing request authenticated by the session cookie
+/// alone, whether it carries the session's CSRF token. Requests with an
+/// Authorization header or without a live session pass; they are not
+/// acting on the cookie.
+pub fn csrf_ok(state: &AuthState, req: &Request<Body>) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD) || req.headers().contains_key(hyper::header::AUTHORIZATION) {
+        return true;
+    }
+    let Some(claims) = cookie_claims(state, req) else { return true };
+    let sent = req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok());
+    let active = state.sessions.active.lock().unwrap();
+    let expected = claims.jti.as_ref().and_then(|sid| active.get(sid)).map(|r| r.csrf.as_str());
+    sent.is_some() && sent == expected
+}
+
+fn random_id() -> String {
+    B64.encode(rand::random::<[u8; 32]>())
+}
+
+#[utoipa::path(post, path = "/session", tag = "auth",
+    request_body = LoginRequest,
+    responses((status = 200, body = SessionInfo, description = "Signed in; the session cookie is set"),
+        (status = 200, body = LoginOutcome, description = "Policies must be accepted first"),
+        (status = 400, body = ErrorResponse), (status = 401, body = ErrorResponse),
+        (status = 403, body = ErrorResponse), (status = 404),
+        (status = 503, body = ErrorResponse, headers(("Retry-After" = u64, description = "seconds to wait")))))]
+pub async fn create(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if !state.sessions.enabled {
+        return Ok(not_found());
+    }
+    let ip = security::client_ip(&req);
+    let whole_body = hyper::body::to_bytes(req.into_body()).await?;
+    let login: LoginRequest = match body::parse(&whole_body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    if state.passkeys.is_passwordless(&login.username) {
+        state.security.login_failed(ip, Some(&login.username), "password login on a passkey-only account");
+        return Ok(json_status(StatusCode::FORBIDDEN, locale, codes::AUTH_PASSWORD_DISABLED));
+    }
+
+    let Some(_hashing) = state.hashing.acquire(ip, &login.username).await else {
+        return Ok(state.hashing.busy_response(locale));
+    };
+    if !state.passwords.verify(&login.username, &login.password).await {
+        state.security.login_failed(ip, Some(&login.username), "wrong username or password");
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_CREDENTIALS));
+    }
+    state.security.login_succeeded(ip);
+    state.accounts.cancel_deletion(&login.username);
+    if !state.policies.pending(&login.username).is_empty() {
+        // accept through /policies/accept, then sign in again
+        return Ok(policies::login_reply(state, &login.username));
+    }
+
+    let sid = random_id();
+    let ttl = state.sessions.ttl;
+    let token = create_session_token(
+        &state.secret,
+        &login.username,
+        state.groups.groups_for(&login.username),
+        &sid,
+        Duration::seconds(ttl as i64),
+    );
+    let expires_at = Utc::now() + Duration::seconds(ttl as i64);
+    let record = Record { user: login.username, csrf: random_id(), expires_at };
+    state.sessions.active.lock().unwrap().insert(sid.clone(), record);
+
+    let info = state.sessions.info(&sid).unwrap();
+    let mut resp = json_ok(serde_json::to_string(&info).unwrap());
+    resp.headers_mut().insert(hyper::header::SET_COOKIE, state.sessions.cookie(&token, ttl).parse().unwrap());
+    resp.headers_mut().insert(hyper::header::CACHE_CONTROL, "no-store".parse().unwrap());
+    Ok(resp)
+}
+
+#[utoipa::path(get, path = "/session", tag = "auth",
+    responses((status = 200, body = SessionInfo), (status = 401, body = ErrorResponse), (status = 404)))]
+pub async fn current(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if !state.sessions.enabled {
+        return Ok(not_found());
+    }
+    let info = cookie_claims(state, &req).and_then(|c| state.sessions.info(c.jti.as_deref()?));
+    let Some(info) = info else {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    };
+    let mut resp = json_ok(serde_json::to_string(&info).unwrap());
+    resp.headers_mut().insert(hyper::header::CACHE_CONTROL, "no-store".parse().unwrap());
+    Ok(resp)
+}
+
+#[utoipa::path(delete, path = "/session", tag = "auth",
+    params(("X-CSRF-Token" = String, Header, description = "the session's CSRF token")),
+    responses((status = 204, description = "Signed out; the cookie is cleared"), (status = 403, body = ErrorResponse),
+        (status = 404)))]
+pub async fn end(state: &AuthState, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if !state.sessions.enabled {
+        return Ok(not_found());
+    }
+    if let Some(sid) = cookie_claims(state, &req).and_then(|c| c.jti) {
+        if let Some(record) = state.sessions.active.lock().unwrap().remove(&sid) {
+            state.revocations.revoke_token(&sid, record.expires_at);
+        }
+    }
+    // cleared either way, so a stale cookie goes too
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(hyper::header::SET_COOKIE, state.sessions.cookie("", 0))
+        .body(Body::empty())
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use hyper::StatusCode;
+    use serde_json::json;
+
+    use super::{create, Sessions};
+    use crate::passwords;
+    use crate::testing::{reply, request};
+    use crate::webauthn::tests::Authenticator;
+    use crate::webauthn::{register_finish, register_start};
+    use crate::AuthState;
+
+    #[tokio::test]
+    async fn passkey_only_accounts_cannot_start_a_session_with_a_password() {
+        let mut state = AuthState::from_env();
+        state.sessions = Sessions { enabled: true, ..Sessions::from_env() };
+        let mut auth = Authenticator::new();
+        let start = json!({ "username": "ann", "passwordless": true });
+        let (_, options) = reply(register_start(&state, "en", request(None, start)).await).await;
+        let (status, _) = reply(register_finish(&state, "en", request(None, auth.create(&options))).await).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let ip = IpAddr::from([0, 0, 0, 0]);
+        let left = state.security.attempts_left(ip);
+        let login = json!({ "username": "ann", "password": "hunter22" });
+        let (status, body) = reply(create(&state, "en", request(None, login)).await).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["Error"]["code"], "auth.password_disabled");
+        assert_eq!(state.security.attempts_left(ip), left - 1);
+    }
+
+    #[tokio::test]
+    async fn sessions_need_the_password() {
+        let mut state = AuthState::from_env();
+        state.sessions = Sessions { enabled: true, ..Sessions::from_env() };
+        state.passwords = passwords::tests::with(&[("ann", "hunter22")]);
+
+        let login = json!({ "username": "ann", "password": "hunter2" });
+        let (status, body) = reply(create(&state, "en", request(None, login)).await).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["Error"]["code"], "auth.invalid_credentials");
+
+        let login = json!({ "username": "ann", "password": "hunter22" });
+        let (status, _) = reply(create(&state, "en", request(None, login)).await).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
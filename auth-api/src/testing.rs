@@ -0,0 +1,22 @@
+//! Helpers for calling handlers directly in unit tests.
+
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::Value;
+
+/// A request carrying `body` as JSON, with `token` as its bearer if given.
+pub fn request(token: Option<&str>, body: Value) -> Request<Body> {
+    let body = if body.is_null() { Body::empty() } else { Body::from(body.to_string()) };
+    let mut req = Request::post("/").body(body).unwrap();
+    if let Some(token) = token {
+        req.headers_mut().insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+    }
+    req
+}
+
+/// A handler's status and JSON body (null when it has none).
+pub async fn reply(resp: Result<Response<Body>, hyper::Error>) -> (StatusCode, Value) {
+    let resp = resp.unwrap();
+    let status = resp.status();
+    let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
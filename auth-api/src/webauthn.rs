@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Deserialize;
+use utoipa::ToSchema;
+use webauthn_rs::prelude::{
+    DiscoverableAuthentication, DiscoverableKey, Passkey, PasskeyAuthentication, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, Url, Uuid, Webauthn, WebauthnBuilder, WebauthnError,
+};
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{ErrorResponse, LoginOutcome};
+
+use crate::body;
+use crate::policies;
+use crate::security;
+use crate::{bearer_claims, json_ok, json_status, AuthState};
+
+//
+// WEBAUTHN / PASSKEYS
+//
+// Registration and assertions are verified by webauthn-rs (passkeys: user
+// verification required, "none" attestation, signature counters checked).
+// Credentials are kept in memory alongside the group store. Ceremony state
+// is single-use and looked up by challenge, since the client echoes it back
+// in clientDataJSON.
+//
+// Adding a passkey to an account that exists (it has a password or a
+// passkey) needs that account's own token. Without one, registration only
+// creates a new account; `passwordless` accounts then refuse password
+// login.
+//
+//   WEBAUTHN_RP_ID    relying party id (default localhost)
+//   WEBAUTHN_RP_NAME  shown by authenticators (default U-Chat)
+//   WEBAUTHN_ORIGIN   origin the ceremonies run on, under WEBAUTHN_RP_ID
+//                     (default http://localhost:9200)
+//
+
+const CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn from_env() -> Self {
+        Self {
+            id: std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".into()),
+            name: std::env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| "U-Chat".into()),
+            origin: std::env::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_| "http://localhost:9200".into()),
+        }
+    }
+}
+
+struct Credential {
+    username: String,
+    passkey: Passkey,
+}
+
+struct Account {
+    user_id: Uuid,
+    passwordless: bool,
+}
+
+enum Ceremony {
+    Register {
+        username: String,
+        user_id: Uuid,
+        passwordless: bool,
+        /// Started with the account's token rather than for a new account.
+        by_owner: bool,
+        state: PasskeyRegistration,
+    },
+    Login(PasskeyAuthentication),
+    Discover(DiscoverableAuthentication),
+}
+
+struct Pending {
+    ceremony: Ceremony,
+    issued: Instant,
+}
+
+pub struct Passkeys {
+    webauthn: Webauthn,
+    credentials: Mutex<HashMap<Vec<u8>, Credential>>,
+    accounts: Mutex<HashMap<String, Account>>,
+    challenges: Mutex<HashMap<Vec<u8>, Pending>>,
+}
+
+impl Passkeys {
+    pub fn new(rp: RelyingParty) -> Self {
+        let origin = Url::parse(&rp.origin).expect("WEBAUTHN_ORIGIN must be a URL");
+        let webauthn = WebauthnBuilder::new(&rp.id, &origin)
+            .and_then(|b| b.rp_name(&rp.name).timeout(CHALLENGE_TTL).build())
+            .expect("WEBAUTHN_ORIGIN must be on WEBAUTHN_RP_ID");
+        Self {
+            webauthn,
+            credentials: Mutex::new(HashMap::new()),
+            accounts: Mutex::new(HashMap::new()),
+            challenges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drops the user's account and every passkey; returns how many.
+    pub fn remove_user(&self, username: &str) -> usize {
+        self.accounts.lock().unwrap().remove(username);
+        let mut credentials = self.credentials.lock().unwrap();
+        let before = credentials.len();
+        credentials.retain(|_, c| c.username != username);
+        before - credentials.len()
+    }
+
+    /// Whether password login is disabled for this user.
+    pub fn is_passwordless(&self, username: &str) -> bool {
+        self.accounts.lock().unwrap().get(username).is_some_and(|a| a.passwordless)
+    }
+
+    fn has_credentials(&self, username: &str) -> bool {
+        self.credentials.lock().unwrap().values().any(|c| c.username == username)
+    }
+
+    fn passkeys_of(&self, username: &str) -> Vec<Passkey> {
+        let credentials = self.credentials.lock().unwrap();
+        credentials.values().filter(|c| c.username == username).map(|c| c.passkey.clone()).collect()
+    }
+
+    fn remember(&self, challenge: &[u8], ceremony: Ceremony) {
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, p| p.issued.elapsed() < CHALLENGE_TTL);
+        challenges.insert(challenge.to_vec(), Pending { ceremony, issued: Instant::now() });
+    }
+
+    /// Consumes the ceremony named by clientDataJSON's challenge;
+    /// webauthn-rs checks the rest of clientDataJSON.
+    fn take_challenge(&self, client_data_json: &[u8]) -> Result<Ceremony, &'static str> {
+        let client_data: ClientData =
+            serde_json::from_slice(client_data_json).map_err(|_| "malformed clientDataJSON")?;
+        let challenge = B64.decode(&client_data.challenge).map_err(|_| "malformed challenge")?;
+        let pending = self.challenges.lock().unwrap().remove(&challenge).ok_or("unknown challenge")?;
+        if pending.issued.elapsed() >= CHALLENGE_TTL {
+            return Err("challenge expired");
+        }
+        Ok(pending.ceremony)
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    challenge: String,
+}
+
+/// Why webauthn-rs refused a ceremony, for the log.
+fn refused(e: WebauthnError) -> &'static str {
+    match e {
+        WebauthnError::InvalidRPOrigin | WebauthnError::InvalidRPIDHash => "origin or rp id mismatch",
+        WebauthnError::MismatchedChallenge => "challenge mismatch",
+        WebauthnError::UserNotPresent => "user not present",
+        WebauthnError::UserNotVerified => "user not verified",
+        WebauthnError::CredentialPossibleCompromise => "signature counter did not increase",
+        WebauthnError::CredentialAlteredAlgFromRequest | WebauthnError::CredentialCrossOrigin => {
+            "credential not usable here"
+        }
+        _ => "bad credential or signature",
+    }
+}
+
+fn verification_failed(locale: &str, reason: &str) -> Response<Body> {
+    println!("auth-api: webauthn verification failed: {}", reason);
+    json_status(StatusCode::UNAUTHORIZED, locale, codes::WEBAUTHN_VERIFICATION_FAILED)
+}
+
+//
+// REGISTRATION
+//
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RegisterStart {
+    username: String,
+    /// Disable password login for the account.
+    #[serde(default)]
+    passwordless: bool,
+}
+
+/// `PublicKeyCredential` from `navigator.credentials.create()`, as
+/// `toJSON()` encodes it (binary fields base64url).
+#[derive(Deserialize, ToSchema)]
+#[serde(transparent)]
+#[schema(value_type = Object)]
+pub struct RegisterFinish(RegisterPublicKeyCredential);
+
+#[utoipa::path(post, path = "/webauthn/register/start", tag = "webauthn",
+    request_body = RegisterStart,
+    security((), ("bearer" = [])),
+    responses((status = 200, description = "PublicKeyCredentialCreationOptions under `publicKey`"),
+        (status = 400, body = ErrorResponse), (status = 401, body = ErrorResponse)))]
+pub async fn register_start(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let caller = bearer_claims(state, &req).filter(|c| !c.is_delegated()).map(|c| c.sub);
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let start = match body::parse::<RegisterStart>(&body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    let passkeys = &state.passkeys;
+    let by_owner = caller.as_deref() == Some(start.username.as_str());
+    if account_exists(state, &start.username) && !by_owner {
+        return Ok(json_status(StatusCode::UNAUTHORIZED, locale, codes::AUTH_INVALID_TOKEN));
+    }
+
+    let user_id = passkeys.accounts.lock().unwrap().get(&start.username).map_or_else(Uuid::new_v4, |a| a.user_id);
+    let exclude = passkeys.passkeys_of(&start.username).iter().map(|p| p.cred_id().clone()).collect();
+    let (options, registration) =
+        match passkeys.webauthn.start_passkey_registration(user_id, &start.username, &start.username, Some(exclude)) {
+            Ok(started) => started,
+            Err(e) => return Ok(verification_failed(locale, refused(e))),
+        };
+
+    passkeys.remember(
+        options.public_key.challenge.as_ref(),
+        Ceremony::Register {
+            username: start.username,
+            user_id,
+            passwordless: start.passwordless,
+            by_owner,
+            state: registration,
+        },
+    );
+    Ok(json_ok(serde_json::to_string(&options).unwrap()))
+}
+
+/// Whether `username` has a password or a passkey.
+fn account_exists(state: &AuthState, username: &str) -> bool {
+    state.passwords.has_password(username) || state.passkeys.has_credentials(username)
+}
+
+#[utoipa::path(post, path = "/webauthn/register/finish", tag = "webauthn",
+    request_body = RegisterFinish,
+    responses((status = 200, body = LoginOutcome), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse), (status = 409, body = ErrorResponse)))]
+pub async fn register_finish(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let finish = match body::parse::<RegisterFinish>(&body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    match verify_registration(state, &finish.0) {
+        Ok(username) => Ok(policies::login_reply(state, &username)),
+        Err("credential already registered") => {
+            Ok(json_status(StatusCode::CONFLICT, locale, codes::WEBAUTHN_CREDENTIAL_EXISTS))
+        }
+        Err(reason) => Ok(verification_failed(locale, reason)),
+    }
+}
+
+fn verify_registration(state: &AuthState, credential: &RegisterPublicKeyCredential) -> Result<String, &'static str> {
+    let passkeys = &state.passkeys;
+    let ceremony = passkeys.take_challenge(credential.response.client_data_json.as_ref())?;
+    let Ceremony::Register { username, user_id, passwordless, by_owner, state: registration } = ceremony else {
+        return Err("not a registration challenge");
+    };
+    let passkey = passkeys.webauthn.finish_passkey_registration(credential, &registration).map_err(refused)?;
+
+    // an account created since the unauthenticated start is not ours to extend
+    if !by_owner && account_exists(state, &username) {
+        return Err("account created meanwhile");
+    }
+
+    let cred_id = passkey.cred_id().to_vec();
+    let mut credentials = passkeys.credentials.lock().unwrap();
+    if credentials.contains_key(&cred_id) {
+        return Err("credential already registered");
+    }
+    credentials.insert(cred_id, Credential { username: username.clone(), passkey });
+
+    let mut accounts = passkeys.accounts.lock().unwrap();
+    let account = accounts.entry(username.clone()).or_insert(Account { user_id, passwordless: false });
+    account.passwordless |= passwordless;
+    println!("auth-api: registered passkey for {}", username);
+    Ok(username)
+}
+
+//
+// AUTHENTICATION
+//
+
+#[derive(Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LoginStart {
+    /// Omit to let the authenticator offer discoverable credentials.
+    #[serde(default)]
+    username: Option<String>,
+}
+
+/// `PublicKeyCredential` from `navigator.credentials.get()`, as
+/// `toJSON()` encodes it (binary fields base64url).
+#[derive(Deserialize, ToSchema)]
+#[serde(transparent)]
+#[schema(value_type = Object)]
+pub struct LoginFinish(PublicKeyCredential);
+
+#[utoipa::path(post, path = "/webauthn/login/start", tag = "webauthn",
+    request_body = LoginStart,
+    responses((status = 200, description = "PublicKeyCredentialRequestOptions under `publicKey`"),
+        (status = 400, body = ErrorResponse)))]
+pub async fn login_start(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let start = if body.is_empty() {
+        LoginStart { username: None }
+    } else {
+        match body::parse::<LoginStart>(&body) {
+            Ok(start) => start,
+            Err(rejected) => return Ok(rejected.response(locale)),
+        }
+    };
+
+    // a username without passkeys gets the discoverable ceremony too, so
+    // the answer does not tell whether it has any
+    let passkeys = &state.passkeys;
+    let own = start.username.as_deref().map(|u| passkeys.passkeys_of(u)).unwrap_or_default();
+    let started = if own.is_empty() {
+        passkeys.webauthn.start_discoverable_authentication().map(|(options, s)| (options, Ceremony::Discover(s)))
+    } else {
+        passkeys.webauthn.start_passkey_authentication(&own).map(|(options, s)| (options, Ceremony::Login(s)))
+    };
+    let (options, ceremony) = match started {
+        Ok(started) => started,
+        Err(e) => return Ok(verification_failed(locale, refused(e))),
+    };
+
+    passkeys.remember(options.public_key.challenge.as_ref(), ceremony);
+    Ok(json_ok(serde_json::to_string(&options).unwrap()))
+}
+
+#[utoipa::path(post, path = "/webauthn/login/finish", tag = "webauthn",
+    request_body = LoginFinish,
+    responses((status = 200, body = LoginOutcome), (status = 400, body = ErrorResponse),
+        (status = 401, body = ErrorResponse)))]
+pub async fn login_finish(state: &AuthState, locale: &str, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    let ip = security::client_ip(&req);
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let finish = match body::parse::<LoginFinish>(&body) {
+        Ok(v) => v,
+        Err(rejected) => return Ok(rejected.response(locale)),
+    };
+
+    match verify_assertion(state, &finish.0) {
+        Ok(username) => {
+            state.security.login_succeeded(ip);
+            state.accounts.cancel_deletion(&username);
+            Ok(policies::login_reply(state, &username))
+        }
+        Err(reason) => {
+            state.security.login_failed(ip, None, reason);
+            Ok(verification_failed(locale, reason))
+        }
+    }
+}
+
+fn verify_assertion(state: &AuthState, credential: &PublicKeyCredential) -> Result<String, &'static str> {
+    let passkeys = &state.passkeys;
+    let ceremony = passkeys.take_challenge(credential.response.client_data_json.as_ref())?;
+    let webauthn = &passkeys.webauthn;
+
+    let result = match ceremony {
+        Ceremony::Login(state) => webauthn.finish_passkey_authentication(credential, &state),
+        Ceremony::Discover(state) => {
+            let (user_id, _) = webauthn.identify_discoverable_authentication(credential).map_err(refused)?;
+            let username = passkeys
+                .accounts
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(_, a)| a.user_id == user_id)
+                .map(|(name, _)| name.clone())
+                .ok_or("unknown user handle")?;
+            let own = passkeys.passkeys_of(&username);
+            let keys: Vec<DiscoverableKey> = own.iter().map(DiscoverableKey::from).collect();
+            webauthn.finish_discoverable_authentication(credential, state, &keys)
+        }
+        Ceremony::Register { .. } => return Err("not a login challenge"),
+    }
+    .map_err(refused)?;
+
+    let mut credentials = passkeys.credentials.lock().unwrap();
+    let stored = credentials.get_mut(result.cred_id().as_ref()).ok_or("unknown credential")?;
+    stored.passkey.update_credential(&result);
+    Ok(stored.username.clone())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use base64::Engine;
+    use hyper::StatusCode;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{DerSignature, SigningKey};
+    use rand::RngCore;
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+
+    use uchat_proto::jwt::create_token;
+
+    use super::{login_finish, login_start, register_finish, register_start, B64};
+    use crate::testing::{reply, request};
+    use crate::AuthState;
+
+    const ORIGIN: &str = "http://localhost:9200";
+    const USER_PRESENT: u8 = 0x01;
+    const USER_VERIFIED: u8 = 0x04;
+    const ATTESTED: u8 = 0x40;
+
+    /// A software passkey: ES256, "none" attestation.
+    pub struct Authenticator {
+        key: SigningKey,
+        id: Vec<u8>,
+        user: Vec<u8>,
+        pub counter: u32,
+    }
+
+    impl Authenticator {
+        pub fn new() -> Self {
+            let mut id = vec![0u8; 32];
+            rand::thread_rng().fill_bytes(&mut id);
+            Self { key: SigningKey::random(&mut rand::rngs::OsRng), id, user: Vec::new(), counter: 0 }
+        }
+
+        fn client_data(kind: &str, options: &Value) -> Vec<u8> {
+            let challenge = &options["publicKey"]["challenge"];
+            let data = json!({ "type": kind, "challenge": challenge, "origin": ORIGIN, "crossOrigin": false });
+            data.to_string().into_bytes()
+        }
+
+        fn auth_data(&self, flags: u8, attested: &[u8]) -> Vec<u8> {
+            let mut data = Sha256::digest(b"localhost").to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.counter.to_be_bytes());
+            data.extend_from_slice(attested);
+            data
+        }
+
+        /// Answers `navigator.credentials.create()` for `options`.
+        pub fn create(&mut self, options: &Value) -> Value {
+            self.user = B64.decode(options["publicKey"]["user"]["id"].as_str().unwrap()).unwrap();
+            let point = self.key.verifying_key().to_encoded_point(false);
+            // COSE_Key {1: 2 (EC2), 3: -7 (ES256), -1: 1 (P-256), -2: x, -3: y}
+            let mut cose = vec![0xa5, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01, 0x21, 0x58, 0x20];
+            cose.extend_from_slice(point.x().unwrap());
+            cose.extend_from_slice(&[0x22, 0x58, 0x20]);
+            cose.extend_from_slice(point.y().unwrap());
+
+            // aaguid | credential id length | credential id | key
+            let mut attested = vec![0u8; 16];
+            attested.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+            attested.extend_from_slice(&self.id);
+            attested.extend_from_slice(&cose);
+            let auth_data = self.auth_data(USER_PRESENT | USER_VERIFIED | ATTESTED, &attested);
+
+            // {"fmt": "none", "attStmt": {}, "authData": auth_data}
+            let mut object = vec![0xa3, 0x63];
+            object.extend_from_slice(b"fmt");
+            object.push(0x64);
+            object.extend_from_slice(b"none");
+            object.push(0x67);
+            object.extend_from_slice(b"attStmt");
+            object.extend_from_slice(&[0xa0, 0x68]);
+            object.extend_from_slice(b"authData");
+            object.push(0x59);
+            object.extend_from_slice(&(auth_data.len() as u16).to_be_bytes());
+            object.extend_from_slice(&auth_data);
+
+            json!({
+                "id": B64.encode(&self.id),
+                "rawId": B64.encode(&self.id),
+                "type": "public-key",
+                "response": {
+                    "clientDataJSON": B64.encode(Self::client_data("webauthn.create", options)),
+                    "attestationObject": B64.encode(object),
+                },
+            })
+        }
+
+        /// Answers `navigator.credentials.get()` for `options`, signing
+        /// with the counter as it stands.
+        pub fn get(&self, options: &Value) -> Value {
+            let client_data = Self::client_data("webauthn.get", options);
+            let auth_data = self.auth_data(USER_PRESENT | USER_VERIFIED, &[]);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(&client_data));
+            let signature: DerSignature = self.key.sign(&signed);
+            json!({
+                "id": B64.encode(&self.id),
+                "rawId": B64.encode(&self.id),
+                "type": "public-key",
+                "response": {
+                    "clientDataJSON": B64.encode(&client_data),
+                    "authenticatorData": B64.encode(&auth_data),
+                    "signature": B64.encode(signature.as_bytes()),
+                    "userHandle": B64.encode(&self.user),
+                },
+            })
+        }
+    }
+
+    /// Registers `auth` for `username`; the finish reply, or the start's
+    /// when it was refused.
+    pub async fn register(
+        state: &AuthState,
+        auth: &mut Authenticator,
+        username: &str,
+        token: Option<&str>,
+    ) -> (StatusCode, Value) {
+        let start = json!({ "username": username });
+        let (status, options) = reply(register_start(state, "en", request(token, start)).await).await;
+        if status != StatusCode::OK {
+            return (status, options);
+        }
+        reply(register_finish(state, "en", request(None, auth.create(&options))).await).await
+    }
+
+    /// Signs in with `auth`, naming `username` or not.
+    pub async fn sign_in(state: &AuthState, auth: &Authenticator, username: Option<&str>) -> (StatusCode, Value) {
+        let start = json!({ "username": username });
+        let (_, options) = reply(login_start(state, "en", request(None, start)).await).await;
+        reply(login_finish(state, "en", request(None, auth.get(&options))).await).await
+    }
+
+    #[tokio::test]
+    async fn registering_on_an_existing_account_needs_its_token() {
+        let state = AuthState::from_env();
+        let mut first = Authenticator::new();
+        let (status, reply) = register(&state, &mut first, "ann", None).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+        assert!(reply["LoginOk"]["token"].is_string());
+
+        let mut second = Authenticator::new();
+        let (status, _) = register(&state, &mut second, "ann", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let eve = create_token(&state.secret, "eve");
+        let (status, _) = register(&state, &mut second, "ann", Some(&eve)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let ann = create_token(&state.secret, "ann");
+        let (status, _) = register(&state, &mut second, "ann", Some(&ann)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!state.passkeys.is_passwordless("ann"));
+    }
+
+    #[tokio::test]
+    async fn passkeys_sign_in_with_or_without_a_username() {
+        let state = AuthState::from_env();
+        let mut auth = Authenticator::new();
+        register(&state, &mut auth, "ann", None).await;
+
+        auth.counter = 1;
+        let (status, reply) = sign_in(&state, &auth, Some("ann")).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+        auth.counter = 2;
+        let (status, reply) = sign_in(&state, &auth, None).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+        assert!(reply["LoginOk"]["token"].is_string());
+    }
+
+    #[tokio::test]
+    async fn assertions_need_the_passkey_and_a_rising_counter() {
+        let state = AuthState::from_env();
+        let mut auth = Authenticator::new();
+        register(&state, &mut auth, "ann", None).await;
+        auth.counter = 5;
+        assert_eq!(sign_in(&state, &auth, Some("ann")).await.0, StatusCode::OK);
+
+        // the credential's id signed by another key
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let forged = Authenticator { key, id: auth.id.clone(), user: auth.user.clone(), counter: 6 };
+        for username in [Some("ann"), None] {
+            assert_eq!(sign_in(&state, &forged, username).await.0, StatusCode::UNAUTHORIZED);
+        }
+        // a counter that did not move on: a cloned authenticator or a replay
+        for counter in [5, 4] {
+            auth.counter = counter;
+            assert_eq!(sign_in(&state, &auth, Some("ann")).await.0, StatusCode::UNAUTHORIZED, "{}", counter);
+        }
+        auth.counter = 6;
+        assert_eq!(sign_in(&state, &auth, Some("ann")).await.0, StatusCode::OK);
+    }
+}
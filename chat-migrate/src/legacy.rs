@@ -0,0 +1,158 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde_json::Value;
+
+//
+// LEGACY GATEWAY DUMPS
+//
+// The first gateway broadcast bare strings: whatever a client sent went to
+// everyone as-is, and clients were told it came from "user". Dumps of that
+// traffic come in a few shapes, one message per line:
+//
+//   hello everyone                                   raw broadcast
+//   {"MessageBroadcast":{"from":"user","content":"hi"}}   frame as sent
+//   [2025-11-30T12:00:00Z] alice: hello              log line
+//   2025-11-30 12:00:00 alice: hello                 log line, UTC
+//
+// Sender and time are inferred when the line carries them. The legacy
+// placeholder sender "user" counts as unknown.
+//
+
+/// What the legacy gateway called every sender.
+const PLACEHOLDER_SENDER: &str = "user";
+
+const NAIVE_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+#[derive(Debug, PartialEq)]
+pub struct LegacyMessage {
+    pub from: Option<String>,
+    pub at: Option<DateTime<Utc>>,
+    pub content: String,
+}
+
+/// One dump line as a message; None for blank lines.
+pub fn parse(line: &str) -> Option<LegacyMessage> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    if line.starts_with('{') {
+        if let Some(message) = parse_frame(line) {
+            return Some(message);
+        }
+    }
+
+    let (at, rest) = match split_time(line) {
+        Some((at, rest)) => (Some(at), rest),
+        None => (None, line),
+    };
+    let (from, content) = match split_sender(rest) {
+        Some((from, content)) => (known_sender(from), content),
+        None => (None, rest),
+    };
+    Some(LegacyMessage { from, at, content: content.to_string() })
+}
+
+fn parse_frame(line: &str) -> Option<LegacyMessage> {
+    let value: Value = serde_json::from_str(line).ok()?;
+    let frame = value.get("MessageBroadcast")?;
+    let content = frame.get("content")?.as_str()?.to_string();
+    let from = frame.get("from").and_then(Value::as_str).and_then(known_sender);
+    // some dumps were written with a receive time next to the frame
+    let at = value.get("ts").and_then(Value::as_str).and_then(parse_time);
+    Some(LegacyMessage { from, at, content })
+}
+
+fn known_sender(from: &str) -> Option<String> {
+    let from = from.trim();
+    (!from.is_empty() && from != PLACEHOLDER_SENDER).then(|| from.to_string())
+}
+
+fn parse_time(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(s) {
+        return Some(at.with_timezone(&Utc));
+    }
+    NAIVE_FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .map(|naive| naive.and_utc())
+}
+
+/// A leading timestamp, bracketed or as one or two words.
+fn split_time(line: &str) -> Option<(DateTime<Utc>, &str)> {
+    if let Some(inner) = line.strip_prefix('[') {
+        let (stamp, rest) = inner.split_once(']')?;
+        return parse_time(stamp.trim()).map(|at| (at, rest.trim_start()));
+    }
+    let mut words = line.splitn(3, ' ');
+    let first = words.next()?;
+    let second = words.next();
+    if let Some(second) = second {
+        let two = &line[..first.len() + 1 + second.len()];
+        if let Some(at) = parse_time(two) {
+            return Some((at, line[two.len()..].trim_start()));
+        }
+    }
+    parse_time(first).map(|at| (at, line[first.len()..].trim_start()))
+}
+
+/// "name: text", where name looks like a username.
+fn split_sender(line: &str) -> Option<(&str, &str)> {
+    let (name, content) = line.split_once(": ")?;
+    let plausible = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_alphanumeric() || "_.@-".contains(c));
+    (plausible && !content.trim().is_empty()).then(|| (name, content.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc))
+    }
+
+    #[test]
+    fn lines_without_a_time() {
+        assert_eq!(parse("hello: world and more"), Some(LegacyMessage {
+            from: Some("hello".into()),
+            at: None,
+            content: "world and more".into(),
+        }));
+        assert_eq!(parse("  just text, no sender  "), Some(LegacyMessage {
+            from: None,
+            at: None,
+            content: "just text, no sender".into(),
+        }));
+        assert_eq!(parse("   "), None);
+    }
+
+    #[test]
+    fn frames_drop_the_placeholder_sender() {
+        let frame = parse(r#"{"MessageBroadcast":{"from":"user","content":"hi"}}"#).unwrap();
+        assert_eq!(frame, LegacyMessage { from: None, at: None, content: "hi".into() });
+
+        let frame = parse(r#"{"ts":"2025-11-30T12:00:00Z","MessageBroadcast":{"from":"bob","content":"yo"}}"#).unwrap();
+        assert_eq!(frame.from.as_deref(), Some("bob"));
+        assert_eq!(frame.at, at("2025-11-30T12:00:00Z"));
+
+        // not a frame after all: kept as text
+        assert_eq!(parse("{not json").unwrap().content, "{not json");
+    }
+
+    #[test]
+    fn log_lines_carry_time_and_sender() {
+        let line = parse("[2025-11-30T12:00:00+01:00] alice: see you at 10:30").unwrap();
+        assert_eq!(line.at, at("2025-11-30T11:00:00Z"));
+        assert_eq!(line.from.as_deref(), Some("alice"));
+        assert_eq!(line.content, "see you at 10:30");
+
+        let line = parse("2025-11-30 12:00:01 bob: hi").unwrap();
+        assert_eq!(line.at, at("2025-11-30T12:00:01Z"));
+        assert_eq!(line.from.as_deref(), Some("bob"));
+
+        let line = parse("2025-11-30T12:00:02Z [media message]").unwrap();
+        assert_eq!(line.at, at("2025-11-30T12:00:02Z"));
+        assert_eq!((line.from, line.content.as_str()), (None, "[media message]"));
+    }
+}
@@ -0,0 +1,195 @@
+mod legacy;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use uchat_proto::envelope::Envelope;
+use uchat_proto::events::ServerEvent;
+
+//
+// CHAT-MIGRATE
+//
+// Backfills chat-service history from dumps of the legacy gateway's
+// broadcast stream (formats in legacy.rs):
+//
+//   chat-migrate [--room ROOM] [--dry-run] [--print] DUMP...
+//
+//   --room     room the messages go to (default lobby; the legacy gateway
+//              had no others)
+//   --dry-run  convert and count, write nothing
+//   --print    write each converted message to stdout as a room envelope
+//
+//   CHAT_DB_PATH  chat-service database (default chat.db)
+//
+// Messages without a sender are stored as "legacy". Messages without a
+// time take the previous message's; those before the first timestamped
+// line take its time, and the dump file's modification time stands in
+// when no line has one.
+//
+// Re-runs are safe: each imported line is recorded by file name, line
+// number and a hash of the line, so the same dump (or the same dump with
+// lines appended) only adds what is new. A line that changed since it was
+// imported is reported and left alone.
+//
+// Only the archive is backfilled. The live stream to the gateway (the
+// outbox) is not, so nothing old is broadcast again.
+//
+
+/// Sender for messages whose dump line names none.
+const UNKNOWN_SENDER: &str = "legacy";
+
+struct Options {
+    room: String,
+    dry_run: bool,
+    print: bool,
+    dumps: Vec<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: chat-migrate [--room ROOM] [--dry-run] [--print] DUMP...");
+    std::process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options { room: "lobby".into(), dry_run: false, print: false, dumps: Vec::new() };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--room" => opts.room = args.next().unwrap_or_else(|| usage()),
+            "--dry-run" => opts.dry_run = true,
+            "--print" => opts.print = true,
+            "-h" | "--help" => usage(),
+            flag if flag.starts_with("--") => usage(),
+            _ => opts.dumps.push(arg),
+        }
+    }
+    if opts.dumps.is_empty() || opts.room.is_empty() {
+        usage();
+    }
+    opts
+}
+
+#[derive(Default)]
+struct Counts {
+    imported: usize,
+    already: usize,
+    changed: usize,
+}
+
+fn main() -> Result<()> {
+    let opts = parse_args();
+    let path = std::env::var("CHAT_DB_PATH").unwrap_or_else(|_| "chat.db".into());
+    let mut db = Connection::open(&path).with_context(|| format!("cannot open {}", path))?;
+
+    let has_messages = db
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages'")?
+        .exists([])?;
+    if !has_messages {
+        bail!("{} has no messages table; start chat-service once to create it", path);
+    }
+
+    // envelope seq per room, counted over this run
+    let mut seqs: HashMap<String, u64> = HashMap::new();
+    for dump in &opts.dumps {
+        let counts = migrate(&mut db, &opts, dump, &mut seqs).with_context(|| format!("migrating {}", dump))?;
+        eprintln!(
+            "chat-migrate: {}: {} imported, {} already imported, {} changed since their import (skipped){}",
+            dump,
+            counts.imported,
+            counts.already,
+            counts.changed,
+            if opts.dry_run { " [dry run]" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+fn migrate(db: &mut Connection, opts: &Options, dump: &str, seqs: &mut HashMap<String, u64>) -> Result<Counts> {
+    let bytes = std::fs::read(dump)?;
+    let text = String::from_utf8_lossy(&bytes);
+    let source = Path::new(dump)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| dump.to_string());
+    let messages: Vec<_> = text
+        .lines()
+        .enumerate()
+        .filter_map(|(n, raw)| Some((n, raw, legacy::parse(raw)?)))
+        .collect();
+    let mut last_time: DateTime<Utc> = match messages.iter().find_map(|(_, _, m)| m.at) {
+        Some(first) => first,
+        None => std::fs::metadata(dump)?.modified()?.into(),
+    };
+
+    let tx = db.transaction()?;
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS legacy_imports (
+            source     TEXT NOT NULL,
+            line       INTEGER NOT NULL,
+            digest     TEXT NOT NULL,
+            message_id INTEGER NOT NULL,
+            PRIMARY KEY (source, line)
+        ) WITHOUT ROWID;",
+    )?;
+    let mut counts = Counts::default();
+    for (n, raw, message) in messages {
+        let at = message.at.unwrap_or(last_time);
+        last_time = at;
+        let from = message.from.unwrap_or_else(|| UNKNOWN_SENDER.to_string());
+
+        if opts.print {
+            let seq = seqs.entry(opts.room.clone()).or_insert(0);
+            *seq += 1;
+            let envelope = Envelope {
+                room: opts.room.clone(),
+                seq: *seq,
+                origin: None,
+                sender: Some(from.clone()),
+                ts: Some(at.timestamp_millis()),
+                receipts: false,
+                expires_at: None,
+                encryption: None,
+                event: ServerEvent::MessageBroadcast { from: from.clone(), content: message.content.clone() },
+            };
+            println!("{}", serde_json::to_string(&envelope)?);
+        }
+
+        let line = n as i64 + 1;
+        let digest = format!("{:x}", Sha256::digest(raw.as_bytes()));
+        let recorded: Option<String> = tx
+            .query_row(
+                "SELECT digest FROM legacy_imports WHERE source = ?1 AND line = ?2",
+                params![source, line],
+                |r| r.get(0),
+            )
+            .optional()?;
+        match recorded {
+            Some(d) if d == digest => counts.already += 1,
+            Some(_) => {
+                eprintln!("chat-migrate: {}:{} changed since it was imported, skipping", dump, line);
+                counts.changed += 1;
+            }
+            None => {
+                tx.execute(
+                    "INSERT INTO messages (room, email, message, ts) VALUES (?1, ?2, ?3, ?4)",
+                    params![opts.room, from, message.content, at.to_rfc3339()],
+                )?;
+                tx.execute(
+                    "INSERT INTO legacy_imports (source, line, digest, message_id) VALUES (?1, ?2, ?3, ?4)",
+                    params![source, line, digest, tx.last_insert_rowid()],
+                )?;
+                counts.imported += 1;
+            }
+        }
+    }
+
+    if !opts.dry_run {
+        tx.commit()?;
+    }
+    Ok(counts)
+}
@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Datelike, Days, NaiveDate, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+
+use crate::auth::{api_error, bearer_claims, ApiFailure};
+use crate::leader::Lease;
+use crate::AppState;
+
+//
+// ANALYTICS ROLLUPS
+//
+// Dashboards want trends, not rows. The analytics-rollup job (every
+// CHAT_ANALYTICS_INTERVAL_SECS, holding the `analytics-rollup` lease)
+// summarizes messages into `analytics_rollups`: one value per period (a UTC
+// day, or an ISO week starting Monday), bucket, metric and room, with room
+// '' for all rooms together.
+//
+//   active_users          distinct senders
+//   messages              messages sent
+//   message_bytes         bytes of message bodies sent
+//   median_response_secs  median time from a message to the next one in its
+//                         room by someone else; gaps of an hour or more
+//                         start a new conversation and are not counted
+//
+// Erased messages are left out. Every pass recomputes the current and the
+// previous day and week, so late writes and erasures are picked up; a pass
+// over an empty table backfills CHAT_ANALYTICS_BACKFILL_DAYS first.
+// Attachments are uploaded to the gateway and never reach this store, so
+// their volume is not rolled up here; message_bytes is what this store
+// holds.
+//
+//   CHAT_ANALYTICS_INTERVAL_SECS   between passes (default 3600)
+//   CHAT_ANALYTICS_BACKFILL_DAYS   days rolled up into an empty table
+//                                  (default 90)
+//   CHAT_ANALYTICS_GROUP           claim group that may read the rollups
+//                                  besides admins (default "analysts")
+//
+// The endpoints speak Grafana's JSON datasource protocol, so a data source
+// with its URL set to <chat-service>/analytics and a bearer token header
+// works as is:
+//
+//   GET  /analytics          connection test; when rollups were last computed
+//   POST /analytics/metrics  the metrics and their payload options
+//   POST /analytics/query    {range: {from, to}, targets: [{target,
+//                            payload: {period, room}}]} to one time series
+//                            per target; room "*" gives one per room instead
+//
+//   chat_analytics_rollups_total        passes completed
+//   chat_analytics_rows_written_total
+//
+
+/// Metrics rolled up, with their labels.
+pub const METRICS: [(&str, &str); 4] = [
+    ("active_users", "Active users"),
+    ("messages", "Messages"),
+    ("message_bytes", "Message bytes"),
+    ("median_response_secs", "Median response time (seconds)"),
+];
+
+/// Longest gap still counted as a response.
+const CONVERSATION_GAP_SECS: i64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+    Week,
+}
+
+impl Period {
+    fn as_str(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+        }
+    }
+
+    /// First day of the bucket `day` falls in.
+    fn bucket(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => day,
+            Period::Week => day - Days::new(day.weekday().num_days_from_monday().into()),
+        }
+    }
+
+    fn next(self, bucket: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => bucket + Days::new(1),
+            Period::Week => bucket + Days::new(7),
+        }
+    }
+}
+
+pub struct Analytics {
+    /// `CHAT_ANALYTICS_GROUP`; may read the rollups.
+    pub group: String,
+    pub interval: Duration,
+    backfill_days: u64,
+    lease: Mutex<Lease>,
+    passes: AtomicU64,
+    rows_written: AtomicU64,
+}
+
+impl Analytics {
+    pub fn from_env() -> Self {
+        let env = |name: &str, default: u64| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        let interval = Duration::from_secs(env("CHAT_ANALYTICS_INTERVAL_SECS", 3600).max(1));
+        Self {
+            group: std::env::var("CHAT_ANALYTICS_GROUP").unwrap_or_else(|_| "analysts".into()),
+            interval,
+            backfill_days: env("CHAT_ANALYTICS_BACKFILL_DAYS", 90),
+            lease: Mutex::new(Lease::new("analytics-rollup", interval * 3)),
+            passes: AtomicU64::new(0),
+            rows_written: AtomicU64::new(0),
+        }
+    }
+
+    /// Recomputes every day and week bucket from `since` through `today`;
+    /// returns the rows written.
+    pub fn roll_up(&self, conn: &Connection, since: NaiveDate, today: NaiveDate) -> rusqlite::Result<usize> {
+        let mut rows = 0;
+        for period in [Period::Day, Period::Week] {
+            let mut bucket = period.bucket(since);
+            while bucket <= today {
+                rows += roll_up_bucket(conn, period, bucket)?;
+                bucket = period.next(bucket);
+            }
+        }
+        self.passes.fetch_add(1, Ordering::Relaxed);
+        self.rows_written.fetch_add(rows as u64, Ordering::Relaxed);
+        Ok(rows)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE chat_analytics_rollups_total counter\n");
+        let _ = writeln!(out, "chat_analytics_rollups_total {}", self.passes.load(Ordering::Relaxed));
+        out.push_str("# TYPE chat_analytics_rows_written_total counter\n");
+        let _ = writeln!(out, "chat_analytics_rows_written_total {}", self.rows_written.load(Ordering::Relaxed));
+        out
+    }
+}
+
+#[derive(Default)]
+struct Rollup {
+    senders: HashSet<String>,
+    messages: u64,
+    bytes: u64,
+    gaps: Vec<i64>,
+}
+
+impl Rollup {
+    fn add(&mut self, sender: &str, bytes: i64, gap: Option<i64>) {
+        self.senders.insert(sender.to_string());
+        self.messages += 1;
+        self.bytes += bytes.max(0) as u64;
+        self.gaps.extend(gap);
+    }
+
+    fn values(&mut self) -> Vec<(&'static str, f64)> {
+        let mut values = vec![
+            ("active_users", self.senders.len() as f64),
+            ("messages", self.messages as f64),
+            ("message_bytes", self.bytes as f64),
+        ];
+        if !self.gaps.is_empty() {
+            self.gaps.sort_unstable();
+            let mid = self.gaps.len() / 2;
+            let median = match self.gaps.len() % 2 {
+                1 => self.gaps[mid] as f64,
+                _ => (self.gaps[mid - 1] + self.gaps[mid]) as f64 / 2.0,
+            };
+            values.push(("median_response_secs", median));
+        }
+        values
+    }
+}
+
+fn midnight(day: NaiveDate) -> DateTime<Utc> {
+    day.and_hms_opt(0, 0, 0).unwrap().and_utc()
+}
+
+/// Replaces one bucket's rows; returns how many were written.
+fn roll_up_bucket(conn: &Connection, period: Period, bucket: NaiveDate) -> rusqlite::Result<usize> {
+    let from = midnight(bucket);
+    let to = midnight(period.next(bucket));
+    // the hour before the bucket, so its first replies have something to answer
+    let lookback = from - chrono::Duration::seconds(CONVERSATION_GAP_SECS);
+
+    // timestamps are all RFC 3339 UTC, so they compare as text
+    let mut stmt = conn.prepare(
+        "SELECT m.room, m.email, m.ts, LENGTH(CAST(COALESCE(p.body, m.message) AS BLOB))
+         FROM messages m LEFT JOIN payloads p ON p.digest = m.payload_ref
+         WHERE m.ts >= ?1 AND m.ts < ?2 AND m.erased_at IS NULL
+         ORDER BY m.room, m.ts, m.id",
+    )?;
+    let messages = stmt.query_map(params![lookback.to_rfc3339(), to.to_rfc3339()], |r| {
+        Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, String>(2)?, r.get::<_, i64>(3)?))
+    })?;
+
+    let mut rollups: BTreeMap<String, Rollup> = BTreeMap::new();
+    rollups.insert(String::new(), Rollup::default());
+    let mut last: Option<(String, String, DateTime<Utc>)> = None;
+    for message in messages {
+        let (room, sender, ts, bytes) = message?;
+        let Ok(ts) = DateTime::parse_from_rfc3339(&ts).map(|t| t.with_timezone(&Utc)) else { continue };
+        let gap = match &last {
+            Some((r, s, at)) if *r == room && *s != sender => Some((ts - *at).num_seconds()),
+            _ => None,
+        }
+        .filter(|gap| *gap < CONVERSATION_GAP_SECS);
+        if ts >= from {
+            rollups.entry(room.clone()).or_default().add(&sender, bytes, gap);
+            rollups.get_mut("").unwrap().add(&sender, bytes, gap);
+        }
+        last = Some((room, sender, ts));
+    }
+    drop(stmt);
+
+    let bucket = bucket.to_string();
+    let computed_at = Utc::now().to_rfc3339();
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM analytics_rollups WHERE period = ?1 AND bucket = ?2", params![period.as_str(), bucket])?;
+    let mut rows = 0;
+    for (room, rollup) in &mut rollups {
+        for (metric, value) in rollup.values() {
+            tx.execute(
+                "INSERT INTO analytics_rollups (period, bucket, metric, room, value, computed_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![period.as_str(), bucket, metric, room, value, computed_at],
+            )?;
+            rows += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(rows)
+}
+
+pub async fn rollup_job(state: AppState) -> anyhow::Result<()> {
+    let db = state.db.lock().unwrap();
+    if !state.analytics.lease.lock().unwrap().try_acquire(&db) {
+        return Ok(());
+    }
+    let today = Utc::now().date_naive();
+    let empty = !db.prepare("SELECT 1 FROM analytics_rollups LIMIT 1")?.exists([])?;
+    let since = today - Days::new(if empty { state.analytics.backfill_days } else { 1 });
+    let rows = state.analytics.roll_up(&db, since, today)?;
+    if empty {
+        println!("chat-service: analytics backfilled from {} ({} rows)", since, rows);
+    }
+    Ok(())
+}
+
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), ApiFailure> {
+    let Some(claims) = bearer_claims(state, headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if !state.admins.contains(&claims.sub) && !claims.groups.contains(&state.analytics.group) {
+        return Err(api_error(StatusCode::FORBIDDEN, "only analysts may read analytics"));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AnalyticsStatus {
+    /// When the newest rollup was computed; unset before the first pass.
+    pub computed_at: Option<String>,
+}
+
+#[utoipa::path(get, path = "/analytics", tag = "analytics",
+    security(("bearer" = [])),
+    responses((status = 200, body = AnalyticsStatus), (status = 401, body = ApiError),
+        (status = 403, body = ApiError)))]
+pub async fn status(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<AnalyticsStatus>, ApiFailure> {
+    authorize(&state, &headers)?;
+    let db = state.db.lock().unwrap();
+    let computed_at = db.query_row("SELECT MAX(computed_at) FROM analytics_rollups", [], |r| r.get(0)).unwrap_or(None);
+    Ok(Json(AnalyticsStatus { computed_at }))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MetricInfo {
+    pub label: String,
+    /// What to put in a target's `target`.
+    pub value: String,
+    pub payloads: Vec<PayloadInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PayloadInfo {
+    pub label: String,
+    pub name: String,
+    /// "select"
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub options: Vec<PayloadOption>,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PayloadOption {
+    pub label: String,
+    pub value: String,
+}
+
+fn option(label: &str, value: &str) -> PayloadOption {
+    PayloadOption { label: label.into(), value: value.into() }
+}
+
+#[utoipa::path(post, path = "/analytics/metrics", tag = "analytics",
+    security(("bearer" = [])),
+    responses((status = 200, body = Vec<MetricInfo>), (status = 401, body = ApiError),
+        (status = 403, body = ApiError)))]
+pub async fn metrics(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<Vec<MetricInfo>>, ApiFailure> {
+    authorize(&state, &headers)?;
+    let rooms: Vec<String> = {
+        let db = state.db.lock().unwrap();
+        let mut stmt = db
+            .prepare("SELECT DISTINCT room FROM analytics_rollups WHERE room != '' ORDER BY room")
+            .map_err(|_| api_error(StatusCode::INTERNAL_SERVER_ERROR, "could not list rooms"))?;
+        let rows = stmt.query_map([], |r| r.get(0)).and_then(|rows| rows.collect());
+        rows.map_err(|_| api_error(StatusCode::INTERNAL_SERVER_ERROR, "could not list rooms"))?
+    };
+
+    let periods = PayloadInfo {
+        label: "Period".into(),
+        name: "period".into(),
+        kind: "select".into(),
+        options: vec![option("Day", "day"), option("Week", "week")],
+    };
+    let mut room_options = vec![option("All rooms", ""), option("Each room", "*")];
+    room_options.extend(rooms.iter().map(|r| option(r, r)));
+    let rooms = PayloadInfo { label: "Room".into(), name: "room".into(), kind: "select".into(), options: room_options };
+
+    let metrics = METRICS
+        .iter()
+        .map(|(value, label)| MetricInfo {
+            label: label.to_string(),
+            value: value.to_string(),
+            payloads: vec![periods.clone(), rooms.clone()],
+        })
+        .collect();
+    Ok(Json(metrics))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct QueryRange {
+    /// RFC 3339.
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct TargetPayload {
+    /// Defaults to day.
+    pub period: Option<Period>,
+    /// Unset or "" for all rooms together, "*" for each room.
+    pub room: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct QueryTarget {
+    /// A metric from /analytics/metrics.
+    pub target: String,
+    #[serde(default)]
+    pub payload: TargetPayload,
+    #[serde(default)]
+    pub hide: bool,
+}
+
+/// Grafana sends more (interval, maxDataPoints, refId...); it is ignored.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AnalyticsQuery {
+    pub range: QueryRange,
+    pub targets: Vec<QueryTarget>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Series {
+    /// The metric, followed by the room for per-room series.
+    pub target: String,
+    /// `[value, bucket start in unix milliseconds]`, oldest first.
+    #[schema(value_type = Vec<Vec<f64>>)]
+    pub datapoints: Vec<(f64, i64)>,
+}
+
+#[utoipa::path(post, path = "/analytics/query", tag = "analytics",
+    request_body = AnalyticsQuery,
+    security(("bearer" = [])),
+    responses((status = 200, body = Vec<Series>), (status = 400, body = ApiError),
+        (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn query(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(query): Json<AnalyticsQuery>,
+) -> Result<Json<Vec<Series>>, ApiFailure> {
+    authorize(&state, &headers)?;
+    let parse = |t: &str| DateTime::parse_from_rfc3339(t).map(|t| t.with_timezone(&Utc).date_naive());
+    let (Ok(from), Ok(to)) = (parse(&query.range.from), parse(&query.range.to)) else {
+        return Err(api_error(StatusCode::BAD_REQUEST, "range must be RFC 3339 timestamps"));
+    };
+
+    let db = state.db.lock().unwrap();
+    let mut series = Vec::new();
+    for target in query.targets.iter().filter(|t| !t.hide) {
+        if !METRICS.iter().any(|(m, _)| *m == target.target) {
+            return Err(api_error(StatusCode::BAD_REQUEST, &format!("unknown metric {:?}", target.target)));
+        }
+        let period = target.payload.period.unwrap_or(Period::Day);
+        let room = target.payload.room.as_deref().unwrap_or("");
+        let filter = if room == "*" { "room != ''" } else { "room = ?5" };
+        let sql = format!(
+            "SELECT room, bucket, value FROM analytics_rollups
+             WHERE period = ?1 AND metric = ?2 AND bucket >= ?3 AND bucket <= ?4 AND {}
+             ORDER BY room, bucket",
+            filter
+        );
+        let (first, last) = (period.bucket(from).to_string(), to.to_string());
+        let rows = db.prepare(&sql).and_then(|mut stmt| {
+            let map = |r: &rusqlite::Row| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get::<_, f64>(2)?));
+            if room == "*" {
+                stmt.query_map(params![period.as_str(), target.target, first, last], map)?.collect()
+            } else {
+                stmt.query_map(params![period.as_str(), target.target, first, last, room], map)?.collect()
+            }
+        });
+        let Ok(rows): rusqlite::Result<Vec<_>> = rows else {
+            return Err(api_error(StatusCode::INTERNAL_SERVER_ERROR, "could not read rollups"));
+        };
+
+        let mut by_room: BTreeMap<String, Vec<(f64, i64)>> = BTreeMap::new();
+        for (room, bucket, value) in rows {
+            let Ok(bucket) = bucket.parse::<NaiveDate>() else { continue };
+            by_room.entry(room).or_default().push((value, midnight(bucket).timestamp_millis()));
+        }
+        if room == "*" {
+            series.extend(by_room.into_iter().map(|(room, datapoints)| Series {
+                target: format!("{} {}", target.target, room),
+                datapoints,
+            }));
+        } else {
+            let datapoints = by_room.remove(room).unwrap_or_default();
+            series.push(Series { target: target.target.clone(), datapoints });
+        }
+    }
+    Ok(Json(series))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use super::{rollup_job, METRICS};
+    use crate::testing::{call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn analytics_roll_up_for_grafana() {
+        let state = AppState::new(db::open_path(":memory:").unwrap());
+        let app = router(state.clone());
+        let today = chrono::Utc::now().date_naive();
+        let (first, second) = (today - chrono::Days::new(3), today - chrono::Days::new(2));
+        {
+            let db = state.db.lock().unwrap();
+            let messages = [
+                (first, "10:00:00", "lobby", "ann", "hello"),
+                (first, "10:01:00", "lobby", "bob", "hi"),
+                (first, "10:03:00", "lobby", "ann", "how are you"),
+                (first, "10:05:00", "ops", "carol", "deploying"),
+                (second, "09:00:00", "lobby", "ann", "morning"),
+            ];
+            for (day, time, room, email, message) in messages {
+                db.execute(
+                    "INSERT INTO messages (room, email, message, ts) VALUES (?1, ?2, ?3, ?4)",
+                    rusqlite::params![room, email, message, format!("{}T{}+00:00", day, time)],
+                )
+                .unwrap();
+            }
+            db.execute(
+                "INSERT INTO messages (room, email, message, ts, erased_at) VALUES ('lobby', '[erased]', '', ?1, ?1)",
+                [format!("{}T10:02:00+00:00", first)],
+            )
+            .unwrap();
+        }
+        rollup_job(state.clone()).await.unwrap();
+
+        let analyst = create_token_with_groups(&secret_from_env(), "ana", vec!["analysts".into()]);
+        let ann = create_token(&secret_from_env(), "ann");
+        let (status, _) = call(&app, "GET", "/analytics", "/analytics", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(&app, Some(&ann), "GET", "/analytics", "/analytics", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, health) = call_as(&app, Some(&analyst), "GET", "/analytics", "/analytics", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(health["computed_at"].is_string());
+
+        let (_, metrics) = call_as(&app, Some(&analyst), "POST", "/analytics/metrics", "/analytics/metrics",
+            Some(json!({}))).await;
+        assert_eq!(metrics.as_array().unwrap().len(), METRICS.len());
+        let options = metrics[0]["payloads"][1]["options"].as_array().unwrap();
+        let rooms: Vec<&str> = options.iter().filter_map(|o| o["value"].as_str()).collect();
+        assert_eq!(rooms, ["", "*", "lobby", "ops"]);
+
+        let at = |day: chrono::NaiveDate| json!(day.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp_millis());
+        let range = json!({ "from": format!("{}T00:00:00Z", first), "to": format!("{}T23:59:59Z", today) });
+        let targets = json!([
+            { "target": "messages", "refId": "A" },
+            { "target": "active_users", "payload": { "room": "lobby" } },
+            { "target": "median_response_secs", "payload": { "room": "*" } },
+            { "target": "messages", "payload": { "period": "week" } },
+        ]);
+        let (status, series) = call_as(&app, Some(&analyst), "POST", "/analytics/query", "/analytics/query",
+            Some(json!({ "range": range, "targets": targets, "maxDataPoints": 500 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(series[0]["target"], "messages");
+        let daily = series[0]["datapoints"].as_array().unwrap();
+        assert_eq!(&daily[..2], [json!([4.0, at(first)]), json!([1.0, at(second)])]);
+        assert!(daily[2..].iter().all(|p| p[0] == 0.0));
+        assert_eq!(series[1]["datapoints"][0], json!([2.0, at(first)]));
+        // ann→bob after 60s, bob→ann after 120s; nobody answered carol
+        assert_eq!(series[2]["target"], "median_response_secs lobby");
+        assert_eq!(series[2]["datapoints"], json!([[90.0, at(first)]]));
+        assert_eq!(series.as_array().unwrap().len(), 4);
+        let weekly: f64 = series[3]["datapoints"].as_array().unwrap().iter().map(|p| p[0].as_f64().unwrap()).sum();
+        assert_eq!(weekly, 5.0);
+
+        let (status, _) = call_as(&app, Some(&analyst), "POST", "/analytics/query", "/analytics/query",
+            Some(json!({ "range": range, "targets": [{ "target": "revenue" }] }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,612 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+
+use crate::audit;
+use crate::auth::{api_error, authorize_room, bearer_claims, ApiFailure};
+use crate::leader::Lease;
+use crate::AppState;
+
+//
+// EXTERNAL ARCHIVE
+//
+// Security teams keep some rooms outside the chat store (SIEM, data lake).
+// Admins flag a room with PUT /rooms/{id}/archive (DELETE unflags it);
+// from then on every message sent to it, and every edit, queues an
+// `archive_records` row in the same transaction as the write. The body is
+// the one stored, before masking.
+//
+// A shipper (holding the `archive-shipper` lease) sends queued records in
+// id order, in batches of JSON lines, to one sink:
+//
+//   CHAT_ARCHIVE_URL            POSTs each batch (application/x-ndjson)
+//                               with `Idempotency-Key: <first id>-<last id>`
+//   CHAT_ARCHIVE_S3_ENDPOINT    PUTs each batch as an object to an
+//   CHAT_ARCHIVE_S3_BUCKET      S3-compatible store (path-style, SigV4):
+//   CHAT_ARCHIVE_S3_REGION      <prefix>YYYY/MM/DD/<first id>-<last id>.jsonl
+//   CHAT_ARCHIVE_S3_PREFIX      (region default us-east-1, prefix default
+//   CHAT_ARCHIVE_S3_ACCESS_KEY  "uchat/")
+//   CHAT_ARCHIVE_S3_SECRET_KEY
+//
+//   CHAT_ARCHIVE_BATCH          records per batch (default 500)
+//   CHAT_ARCHIVE_INTERVAL_SECS  how often queued records are shipped (default 60)
+//
+// After a batch is accepted the sink's checkpoint (`archive_checkpoints`)
+// moves to its last id and the shipped rows are deleted, in one
+// transaction. A failed batch is retried from the checkpoint on the next
+// pass, so delivery is at-least-once and consumers dedupe on record id.
+// Without a sink, records stay queued until one is configured.
+//
+// Erasing a user drops their records not shipped yet; what a sink already
+// holds is up to its own retention. GET /archive shows the flagged rooms,
+// the queue and the checkpoint.
+//
+//   chat_archive_records_shipped_total
+//   chat_archive_batches_shipped_total
+//   chat_archive_failures_total
+//
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SHIP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ArchiveRecord {
+    /// Increasing; the same record shipped twice has the same id.
+    pub id: i64,
+    pub room: String,
+    /// "message" or "edit".
+    pub kind: String,
+    pub message_id: i64,
+    pub from: String,
+    pub content: String,
+    pub revision: i64,
+    /// When the message was sent, or edited for an edit.
+    pub ts: String,
+}
+
+pub struct Batch {
+    pub records: Vec<ArchiveRecord>,
+}
+
+impl Batch {
+    fn first(&self) -> i64 {
+        self.records.first().map_or(0, |r| r.id)
+    }
+
+    fn last(&self) -> i64 {
+        self.records.last().map_or(0, |r| r.id)
+    }
+
+    /// One JSON object per line.
+    pub fn jsonl(&self) -> String {
+        let mut out = String::new();
+        for record in &self.records {
+            out.push_str(&serde_json::to_string(record).unwrap());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Names the sink's checkpoint.
+    fn name(&self) -> &'static str;
+
+    async fn ship(&self, batch: &Batch) -> Result<(), String>;
+}
+
+pub struct HttpSink {
+    url: String,
+    http: reqwest::Client,
+}
+
+#[async_trait]
+impl Sink for HttpSink {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn ship(&self, batch: &Batch) -> Result<(), String> {
+        let resp = self
+            .http
+            .post(&self.url)
+            .timeout(SHIP_TIMEOUT)
+            .header("Idempotency-Key", format!("{}-{}", batch.first(), batch.last()))
+            .header("Content-Type", "application/x-ndjson")
+            .body(batch.jsonl())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(resp.status().to_string()),
+        }
+    }
+}
+
+/// Any store speaking S3's PutObject, signed with AWS Signature V4.
+pub struct S3Sink {
+    endpoint: String,
+    bucket: String,
+    region: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    http: reqwest::Client,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl S3Sink {
+    fn key(&self, batch: &Batch, now: DateTime<Utc>) -> String {
+        format!("{}{}/{:012}-{:012}.jsonl", self.prefix, now.format("%Y/%m/%d"), batch.first(), batch.last())
+    }
+
+    /// The Authorization header for a PUT of `body_hash` to `path` on `host`.
+    fn authorization(&self, host: &str, path: &str, body_hash: &str, now: DateTime<Utc>) -> String {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            path, host, body_hash, amz_date, signed_headers, body_hash
+        );
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical.as_bytes())
+        );
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature: String = hmac(&key, &to_sign).iter().map(|b| format!("{:02x}", b)).collect();
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        )
+    }
+}
+
+#[async_trait]
+impl Sink for S3Sink {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn ship(&self, batch: &Batch) -> Result<(), String> {
+        let now = Utc::now();
+        let body = batch.jsonl();
+        let body_hash = format!("{:x}", Sha256::digest(body.as_bytes()));
+        let path = format!("/{}/{}", self.bucket, self.key(batch, now));
+        let host = self.endpoint.split_once("://").map_or(self.endpoint.as_str(), |(_, rest)| rest);
+        let resp = self
+            .http
+            .put(format!("{}{}", self.endpoint, path))
+            .timeout(SHIP_TIMEOUT)
+            .header("Authorization", self.authorization(host, &path, &body_hash, now))
+            .header("x-amz-content-sha256", &body_hash)
+            .header("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string())
+            .header("Content-Type", "application/x-ndjson")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(resp.status().to_string()),
+        }
+    }
+}
+
+/// Bucket and keys are built from these; anything else would need
+/// URI-encoding when signing.
+fn valid_prefix(prefix: &str) -> bool {
+    prefix.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+pub struct Archiver {
+    sink: Option<Box<dyn Sink>>,
+    batch: usize,
+    pub interval: Duration,
+    shipped: AtomicU64,
+    batches: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl Archiver {
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let http = reqwest::Client::new();
+        let sink: Option<Box<dyn Sink>> = if let Some(url) = var("CHAT_ARCHIVE_URL") {
+            Some(Box::new(HttpSink { url, http }))
+        } else if let (Some(endpoint), Some(bucket)) =
+            (var("CHAT_ARCHIVE_S3_ENDPOINT"), var("CHAT_ARCHIVE_S3_BUCKET"))
+        {
+            let prefix = var("CHAT_ARCHIVE_S3_PREFIX").unwrap_or_else(|| "uchat/".into());
+            if valid_prefix(&prefix) && valid_prefix(&bucket) {
+                Some(Box::new(S3Sink {
+                    endpoint: endpoint.trim_end_matches('/').to_string(),
+                    bucket,
+                    region: var("CHAT_ARCHIVE_S3_REGION").unwrap_or_else(|| "us-east-1".into()),
+                    prefix,
+                    access_key: var("CHAT_ARCHIVE_S3_ACCESS_KEY").unwrap_or_default(),
+                    secret_key: var("CHAT_ARCHIVE_S3_SECRET_KEY").unwrap_or_default(),
+                    http,
+                }))
+            } else {
+                println!("chat-service: ignoring archive sink, invalid bucket or prefix {:?}", prefix);
+                None
+            }
+        } else {
+            None
+        };
+        let mut archiver = Self::new(sink);
+        archiver.batch = var("CHAT_ARCHIVE_BATCH").and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(500);
+        archiver.interval =
+            Duration::from_secs(var("CHAT_ARCHIVE_INTERVAL_SECS").and_then(|v| v.parse().ok()).unwrap_or(60).max(1));
+        archiver
+    }
+
+    pub fn new(sink: Option<Box<dyn Sink>>) -> Self {
+        Self {
+            sink,
+            batch: 500,
+            interval: Duration::from_secs(60),
+            shipped: AtomicU64::new(0),
+            batches: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_batch(mut self, batch: usize) -> Self {
+        self.batch = batch.max(1);
+        self
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in [
+            ("chat_archive_records_shipped_total", &self.shipped),
+            ("chat_archive_batches_shipped_total", &self.batches),
+            ("chat_archive_failures_total", &self.failures),
+        ] {
+            let _ = writeln!(out, "# TYPE {} counter\n{} {}", name, name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Queues message `message_id` if its room is archived; `kind` is
+/// "message" or "edit" and `content` the body as stored. Call in the
+/// transaction that wrote it.
+pub fn enqueue(conn: &Connection, kind: &str, message_id: i64, content: &str) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT INTO archive_records (room, kind, message_id, sender, content, revision, ts)
+         SELECT room, ?2, id, email, ?3, revision, COALESCE(edited_at, ts) FROM messages
+         WHERE id = ?1 AND room IN (SELECT room FROM room_archives)",
+        params![message_id, kind, content],
+    )
+}
+
+/// Drops `user`'s records not shipped yet; call in the erasure transaction.
+pub fn erase_user(conn: &Connection, user: &str) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM archive_records WHERE sender = ?1", [user])
+}
+
+fn checkpoint(conn: &Connection, sink: &str) -> i64 {
+    conn.query_row("SELECT last_id FROM archive_checkpoints WHERE sink = ?1", [sink], |r| r.get(0))
+        .optional()
+        .unwrap()
+        .unwrap_or(0)
+}
+
+fn next_batch(conn: &Connection, after: i64, limit: usize) -> Batch {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, room, kind, message_id, sender, content, revision, ts FROM archive_records
+             WHERE id > ?1 ORDER BY id LIMIT ?2",
+        )
+        .unwrap();
+    let records = stmt
+        .query_map(params![after, limit as i64], |r| {
+            Ok(ArchiveRecord {
+                id: r.get(0)?,
+                room: r.get(1)?,
+                kind: r.get(2)?,
+                message_id: r.get(3)?,
+                from: r.get(4)?,
+                content: r.get(5)?,
+                revision: r.get(6)?,
+                ts: r.get(7)?,
+            })
+        })
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    Batch { records }
+}
+
+/// Ships queued records batch by batch until the queue is empty or a batch
+/// fails; returns how many were shipped.
+pub async fn ship_pending(state: &AppState) -> usize {
+    let archiver = &state.archiver;
+    let Some(sink) = &archiver.sink else { return 0 };
+    let mut shipped = 0;
+    loop {
+        let batch = {
+            let db = state.db.lock().unwrap();
+            next_batch(&db, checkpoint(&db, sink.name()), archiver.batch)
+        };
+        if batch.records.is_empty() {
+            break;
+        }
+        if let Err(e) = sink.ship(&batch).await {
+            archiver.failures.fetch_add(1, Ordering::Relaxed);
+            println!("chat-service: archive batch {}-{} failed: {}", batch.first(), batch.last(), e);
+            break;
+        }
+
+        let mut db = state.db.lock().unwrap();
+        let tx = db.transaction().unwrap();
+        tx.execute(
+            "INSERT INTO archive_checkpoints (sink, last_id, shipped_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(sink) DO UPDATE SET last_id = ?2, shipped_at = ?3",
+            params![sink.name(), batch.last(), Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+        tx.execute("DELETE FROM archive_records WHERE id <= ?1", [batch.last()]).unwrap();
+        tx.commit().unwrap();
+
+        let n = batch.records.len();
+        archiver.shipped.fetch_add(n as u64, Ordering::Relaxed);
+        archiver.batches.fetch_add(1, Ordering::Relaxed);
+        shipped += n;
+        if n < archiver.batch {
+            break;
+        }
+    }
+    shipped
+}
+
+pub async fn shipper_loop(state: AppState) {
+    let mut lease = Lease::new("archive-shipper", state.archiver.interval * 3);
+    loop {
+        tokio::time::sleep(state.archiver.interval).await;
+        if lease.try_acquire(&state.db.lock().unwrap()) {
+            ship_pending(&state).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RoomArchive {
+    pub room: String,
+    pub archived: bool,
+    pub flagged_by: Option<String>,
+    pub flagged_at: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ArchiveStatus {
+    /// "http" or "s3"; unset when no sink is configured.
+    pub sink: Option<String>,
+    pub rooms: Vec<RoomArchive>,
+    /// Records waiting to be shipped.
+    pub pending: i64,
+    /// Last record id the sink accepted.
+    pub checkpoint: i64,
+    pub last_shipped_at: Option<String>,
+}
+
+/// `room`'s flag; `archived` is false when there is none.
+pub fn get(conn: &Connection, room: &str) -> RoomArchive {
+    let row = conn
+        .query_row("SELECT flagged_by, flagged_at FROM room_archives WHERE room = ?1", [room], |r| {
+            Ok((r.get(0)?, r.get(1)?))
+        })
+        .optional()
+        .unwrap();
+    match row {
+        Some((flagged_by, flagged_at)) => {
+            RoomArchive { room: room.to_string(), archived: true, flagged_by, flagged_at }
+        }
+        None => RoomArchive { room: room.to_string(), archived: false, flagged_by: None, flagged_at: None },
+    }
+}
+
+/// The caller, if an admin.
+fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<String, ApiFailure> {
+    let Some(claims) = bearer_claims(state, headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if !state.admins.contains(&claims.sub) {
+        return Err(api_error(StatusCode::FORBIDDEN, "only admins may manage the archive"));
+    }
+    Ok(claims.sub)
+}
+
+#[utoipa::path(get, path = "/archive", tag = "privacy",
+    security(("bearer" = [])),
+    responses((status = 200, body = ArchiveStatus), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn status(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<ArchiveStatus>, ApiFailure> {
+    authorize_admin(&state, &headers)?;
+    let sink = state.archiver.sink.as_ref().map(|s| s.name());
+    let db = state.db.lock().unwrap();
+    let rooms: Vec<String> = {
+        let mut stmt = db.prepare("SELECT room FROM room_archives ORDER BY room").unwrap();
+        stmt.query_map([], |r| r.get(0)).unwrap().filter_map(Result::ok).collect()
+    };
+    let (checkpoint, last_shipped_at) = sink
+        .and_then(|sink| {
+            db.query_row("SELECT last_id, shipped_at FROM archive_checkpoints WHERE sink = ?1", [sink], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .optional()
+            .unwrap()
+        })
+        .unwrap_or((0, None));
+    Ok(Json(ArchiveStatus {
+        sink: sink.map(str::to_string),
+        rooms: rooms.iter().map(|room| get(&db, room)).collect(),
+        pending: db.query_row("SELECT COUNT(*) FROM archive_records", [], |r| r.get(0)).unwrap(),
+        checkpoint,
+        last_shipped_at,
+    }))
+}
+
+#[utoipa::path(get, path = "/rooms/{id}/archive", tag = "rooms",
+    params(("id" = String, Path)),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = RoomArchive), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn get_archive(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RoomArchive>, ApiFailure> {
+    authorize_room(&state, &headers, &id)?;
+    Ok(Json(get(&state.db.lock().unwrap(), &id)))
+}
+
+#[utoipa::path(put, path = "/rooms/{id}/archive", tag = "rooms",
+    params(("id" = String, Path)),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomArchive), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn flag(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RoomArchive>, ApiFailure> {
+    let actor = authorize_admin(&state, &headers)?;
+    let db = state.db.lock().unwrap();
+    let flagged = db
+        .execute(
+            "INSERT OR IGNORE INTO room_archives (room, flagged_by, flagged_at) VALUES (?1, ?2, ?3)",
+            params![id, actor, Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+    if flagged > 0 {
+        audit::record(&db, "room.archive", &actor, &id, "");
+    }
+    Ok(Json(get(&db, &id)))
+}
+
+#[utoipa::path(delete, path = "/rooms/{id}/archive", tag = "rooms",
+    params(("id" = String, Path)),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomArchive), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn unflag(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RoomArchive>, ApiFailure> {
+    let actor = authorize_admin(&state, &headers)?;
+    let db = state.db.lock().unwrap();
+    if db.execute("DELETE FROM room_archives WHERE room = ?1", [&id]).unwrap() > 0 {
+        audit::record(&db, "room.unarchive", &actor, &id, "");
+    }
+    Ok(Json(get(&db, &id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use super::{ship_pending, Archiver, Batch, Sink};
+    use crate::testing::{call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn archived_rooms_are_shipped_in_batches() {
+        // records the batches it takes; fails while `down` is set
+        struct Recorder(Arc<Mutex<Vec<Vec<String>>>>, Arc<Mutex<bool>>);
+        #[async_trait::async_trait]
+        impl Sink for Recorder {
+            fn name(&self) -> &'static str {
+                "http"
+            }
+            async fn ship(&self, batch: &Batch) -> Result<(), String> {
+                if *self.1.lock().unwrap() {
+                    return Err("503 Service Unavailable".into());
+                }
+                let records = batch.records.iter().map(|r| format!("{} {}: {}", r.kind, r.room, r.content));
+                self.0.lock().unwrap().push(records.collect());
+                Ok(())
+            }
+        }
+        let (shipped, down) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(true)));
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        state.admins = Arc::new(vec!["root".to_string()]);
+        state.archiver = Arc::new(Archiver::new(Some(Box::new(Recorder(shipped.clone(), down.clone())))).with_batch(2));
+        let app = router(state.clone());
+        let root = create_token(&secret_from_env(), "root");
+        let ann = create_token(&secret_from_env(), "ann");
+
+        let (status, _) = call_as(&app, Some(&ann), "PUT", "/rooms/secops/archive", "/rooms/{id}/archive",
+            None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, flag) = call_as(&app, Some(&root), "PUT", "/rooms/secops/archive", "/rooms/{id}/archive",
+            None).await;
+        assert_eq!((status, &flag["archived"]), (StatusCode::OK, &json!(true)));
+
+        for (room, message) in [("secops", "alert 1"), ("lobby", "lunch?"), ("secops", "alert 2")] {
+            let send = json!({ "email": "ann", "message": message, "room": room });
+            call(&app, "POST", "/send", "/send", Some(send)).await;
+        }
+        let (status, _) = call_as(&app, Some(&ann), "PATCH", "/messages/secops/1", "/messages/{room}/{id}",
+            Some(json!({ "message": "alert 1 (resolved)", "revision": 1 }))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // a failed batch stays queued
+        assert_eq!(ship_pending(&state).await, 0);
+        let (_, status) = call_as(&app, Some(&root), "GET", "/archive", "/archive", None).await;
+        assert_eq!((&status["pending"], &status["checkpoint"]), (&json!(3), &json!(0)));
+
+        *down.lock().unwrap() = false;
+        assert_eq!(ship_pending(&state).await, 3);
+        assert_eq!(*shipped.lock().unwrap(), [
+            vec!["message secops: alert 1", "message secops: alert 2"],
+            vec!["edit secops: alert 1 (resolved)"],
+        ]);
+        let (_, status) = call_as(&app, Some(&root), "GET", "/archive", "/archive", None).await;
+        assert_eq!((&status["pending"], &status["checkpoint"]), (&json!(0), &json!(3)));
+        assert_eq!(status["sink"], "http");
+        assert_eq!(status["rooms"][0]["room"], "secops");
+
+        // unflagged rooms queue nothing more
+        call_as(&app, Some(&root), "DELETE", "/rooms/secops/archive", "/rooms/{id}/archive", None).await;
+        let send = json!({ "email": "ann", "message": "quiet", "room": "secops" });
+        call(&app, "POST", "/send", "/send", Some(send)).await;
+        assert_eq!(ship_pending(&state).await, 0);
+        let (_, flag) = call(&app, "GET", "/rooms/secops/archive", "/rooms/{id}/archive", None).await;
+        assert_eq!(flag["archived"], false);
+    }
+}
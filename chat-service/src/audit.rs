@@ -0,0 +1,331 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{Duration, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+
+use crate::auth::{api_error, bearer_claims, ApiFailure};
+use crate::AppState;
+
+//
+// AUDIT LOG
+//
+// Privacy and admin actions are written to `audit_log` (inside the
+// caller's transaction where there is one) and echoed as an `AUDIT` JSON
+// line for log shipping.
+//
+// Records are kept forever unless a retention rule covers their action.
+// The audit-retention job (hourly) deletes the records past their rule's
+// window, or anonymizes them: the actor is blanked and the record of what
+// was done to which target stays.
+//
+//   CHAT_AUDIT_RETENTION          comma-separated `action=days[:mode]`, mode
+//                                 `anonymize` (default) or `delete`; the
+//                                 action is exact, a `prefix.*` or `*`, and
+//                                 the most specific rule wins, e.g.
+//                                 "privacy.*=2555:anonymize,room.*=365:delete"
+//   CHAT_AUDIT_RETENTION_DRY_RUN  "1" to only count what the job would change
+//
+// Each pass logs a report of the records it changed (or would change) per
+// action and records it as audit.retention. Admins run a pass on demand,
+// dry or not, with POST /audit/retention, which answers the same report.
+//
+//   chat_audit_records_deleted_total
+//   chat_audit_records_anonymized_total
+//
+
+#[derive(Debug, Serialize)]
+pub struct AuditEvent<'a> {
+    pub ts: String,
+    pub action: &'a str,
+    pub actor: &'a str,
+    pub target: &'a str,
+    pub detail: String,
+}
+
+pub fn record(conn: &Connection, action: &str, actor: &str, target: &str, detail: impl Into<String>) {
+    let event = AuditEvent { ts: Utc::now().to_rfc3339(), action, actor, target, detail: detail.into() };
+    println!("AUDIT {}", serde_json::to_string(&event).unwrap());
+
+    conn.execute(
+        "INSERT INTO audit_log (ts, action, actor, target, detail) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![event.ts, event.action, event.actor, event.target, event.detail],
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionMode {
+    /// Blank the actor; keep the rest.
+    Anonymize,
+    Delete,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// An action, `prefix.*` or `*`.
+    pattern: String,
+    days: u32,
+    mode: RetentionMode,
+}
+
+impl Rule {
+    /// How specifically the rule covers `action`; None if it does not.
+    fn matches(&self, action: &str) -> Option<usize> {
+        match self.pattern.strip_suffix('*') {
+            None => (self.pattern == action).then_some(usize::MAX),
+            Some(prefix) => action.starts_with(prefix).then_some(prefix.len()),
+        }
+    }
+}
+
+pub struct Retention {
+    rules: Vec<Rule>,
+    dry_run: bool,
+    deleted: AtomicU64,
+    anonymized: AtomicU64,
+}
+
+impl Retention {
+    pub fn from_env() -> Self {
+        let rules = std::env::var("CHAT_AUDIT_RETENTION").unwrap_or_default();
+        Self::parse(&rules, std::env::var("CHAT_AUDIT_RETENTION_DRY_RUN").is_ok_and(|v| v == "1"))
+    }
+
+    /// Rules as in CHAT_AUDIT_RETENTION; malformed ones are skipped.
+    pub fn parse(rules: &str, dry_run: bool) -> Self {
+        let rules = rules
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .filter_map(|r| {
+                let rule = parse_rule(r);
+                if rule.is_none() {
+                    println!("chat-service: ignoring audit retention rule {:?}", r);
+                }
+                rule
+            })
+            .collect();
+        Self { rules, dry_run, deleted: AtomicU64::new(0), anonymized: AtomicU64::new(0) }
+    }
+
+    fn rule(&self, action: &str) -> Option<&Rule> {
+        self.rules.iter().filter_map(|r| Some((r.matches(action)?, r))).max_by_key(|(s, _)| *s).map(|(_, r)| r)
+    }
+
+    /// One retention pass over `conn`'s audit log; with `dry_run` nothing
+    /// is changed and the report counts what would be.
+    pub fn apply(&self, conn: &Connection, dry_run: bool) -> rusqlite::Result<RetentionReport> {
+        let actions: Vec<String> = {
+            let mut stmt = conn.prepare("SELECT DISTINCT action FROM audit_log ORDER BY action")?;
+            let rows = stmt.query_map([], |r| r.get(0))?;
+            rows.collect::<rusqlite::Result<_>>()?
+        };
+
+        let mut report = RetentionReport { dry_run, actions: Vec::new(), deleted: 0, anonymized: 0 };
+        for action in actions {
+            let Some(rule) = self.rule(&action) else { continue };
+            let cutoff = (Utc::now() - Duration::days(rule.days.into())).to_rfc3339();
+            // anonymized records are not counted again
+            let filter = match rule.mode {
+                RetentionMode::Delete => "action = ?1 AND ts < ?2",
+                RetentionMode::Anonymize => "action = ?1 AND ts < ?2 AND actor != ''",
+            };
+            let records = if dry_run {
+                let sql = format!("SELECT COUNT(*) FROM audit_log WHERE {}", filter);
+                conn.query_row(&sql, params![action, cutoff], |r| r.get::<_, i64>(0))? as u64
+            } else {
+                let sql = match rule.mode {
+                    RetentionMode::Delete => format!("DELETE FROM audit_log WHERE {}", filter),
+                    RetentionMode::Anonymize => format!("UPDATE audit_log SET actor = '' WHERE {}", filter),
+                };
+                conn.execute(&sql, params![action, cutoff])? as u64
+            };
+            if records == 0 {
+                continue;
+            }
+            match rule.mode {
+                RetentionMode::Delete => report.deleted += records,
+                RetentionMode::Anonymize => report.anonymized += records,
+            }
+            report.actions.push(ActionRetention { action, mode: rule.mode, days: rule.days, records });
+        }
+
+        if !dry_run {
+            self.deleted.fetch_add(report.deleted, Ordering::Relaxed);
+            self.anonymized.fetch_add(report.anonymized, Ordering::Relaxed);
+        }
+        Ok(report)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, counter) in [("deleted", &self.deleted), ("anonymized", &self.anonymized)] {
+            let _ = writeln!(out, "# TYPE chat_audit_records_{}_total counter", name);
+            let _ = writeln!(out, "chat_audit_records_{}_total {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+fn parse_rule(rule: &str) -> Option<Rule> {
+    let (pattern, window) = rule.split_once('=')?;
+    let (days, mode) = match window.split_once(':') {
+        Some((days, "anonymize")) => (days, RetentionMode::Anonymize),
+        Some((days, "delete")) => (days, RetentionMode::Delete),
+        Some(_) => return None,
+        None => (window, RetentionMode::Anonymize),
+    };
+    let pattern = pattern.trim();
+    // a `*` anywhere but at the end would never match as written
+    if pattern.is_empty() || pattern.trim_end_matches('*').contains('*') {
+        return None;
+    }
+    Some(Rule { pattern: pattern.to_string(), days: days.trim().parse().ok()?, mode })
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    /// Actions with records changed (or that would be).
+    pub actions: Vec<ActionRetention>,
+    pub deleted: u64,
+    pub anonymized: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ActionRetention {
+    pub action: String,
+    pub mode: RetentionMode,
+    pub days: u32,
+    pub records: u64,
+}
+
+impl RetentionReport {
+    fn summary(&self) -> String {
+        let actions: Vec<String> = self.actions.iter().map(|a| format!("{}={}", a.action, a.records)).collect();
+        let dry = if self.dry_run { "dry run: " } else { "" };
+        format!("{}deleted {}, anonymized {} ({})", dry, self.deleted, self.anonymized, actions.join(" "))
+    }
+}
+
+/// Runs a pass and records its report in the log it just pruned: always
+/// when an admin asked for it, when it found anything otherwise.
+fn run(state: &AppState, admin: Option<&str>, dry_run: bool) -> rusqlite::Result<RetentionReport> {
+    let db = state.db.lock().unwrap();
+    let report = state.audit_retention.apply(&db, dry_run)?;
+    if admin.is_some() || !report.actions.is_empty() {
+        println!("chat-service: audit retention {}", report.summary());
+        record(&db, "audit.retention", admin.unwrap_or("chat-service"), "audit_log", report.summary());
+    }
+    Ok(report)
+}
+
+pub async fn retention_job(state: AppState) -> anyhow::Result<()> {
+    run(&state, None, state.audit_retention.dry_run)?;
+    Ok(())
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RunRetention {
+    /// Defaults to CHAT_AUDIT_RETENTION_DRY_RUN.
+    pub dry_run: Option<bool>,
+}
+
+#[utoipa::path(post, path = "/audit/retention", tag = "privacy",
+    request_body = RunRetention,
+    security(("bearer" = [])),
+    responses((status = 200, body = RetentionReport), (status = 401, body = ApiError),
+        (status = 403, body = ApiError)))]
+pub async fn run_retention(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<RunRetention>,
+) -> Result<Json<RetentionReport>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if !state.admins.contains(&claims.sub) {
+        return Err(api_error(StatusCode::FORBIDDEN, "only admins may run audit retention"));
+    }
+    let dry_run = body.dry_run.unwrap_or(state.audit_retention.dry_run);
+    match run(&state, Some(&claims.sub), dry_run) {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => {
+            println!("chat-service: audit retention failed: {}", e);
+            Err(api_error(StatusCode::INTERNAL_SERVER_ERROR, "audit retention failed"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use super::{record, Retention};
+    use crate::testing::call_as;
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn audit_retention_deletes_or_anonymizes_old_records() {
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        state.admins = Arc::new(vec!["root".to_string()]);
+        state.audit_retention = Arc::new(Retention::parse("room.*=30:delete, *=90, bad", false));
+        let old = (chrono::Utc::now() - chrono::Duration::days(100)).to_rfc3339();
+        {
+            let db = state.db.lock().unwrap();
+            for action in ["room.freeze", "privacy.erase", "privacy.export"] {
+                db.execute("INSERT INTO audit_log (ts, action, actor, target, detail)
+                    VALUES (?1, ?2, 'root', 'ann', '')", [&old, action]).unwrap();
+            }
+            record(&db, "room.freeze", "root", "lobby", "");
+        }
+        let db = state.db.clone();
+        let app = router(state);
+        let root = create_token(&secret_from_env(), "root");
+        let ann = create_token(&secret_from_env(), "ann");
+        let rows = || -> Vec<(String, String)> {
+            let db = db.lock().unwrap();
+            let sql = "SELECT action, actor FROM audit_log WHERE action != 'audit.retention' ORDER BY id";
+            let mut stmt = db.prepare(sql).unwrap();
+            stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().map(Result::unwrap).collect()
+        };
+
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/audit/retention", "/audit/retention",
+            Some(json!({}))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, report) = call_as(&app, Some(&root), "POST", "/audit/retention", "/audit/retention",
+            Some(json!({ "dry_run": true }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((&report["deleted"], &report["anonymized"]), (&json!(1), &json!(2)));
+        assert_eq!(rows().len(), 4);
+
+        let (_, report) = call_as(&app, Some(&root), "POST", "/audit/retention", "/audit/retention",
+            Some(json!({}))).await;
+        let first = json!({ "action": "privacy.erase", "mode": "anonymize", "days": 90, "records": 1 });
+        assert_eq!(report["actions"][0], first);
+        let left = rows();
+        let left: Vec<(&str, &str)> = left.iter().map(|(a, b)| (a.as_str(), b.as_str())).collect();
+        assert_eq!(left, [("privacy.erase", ""), ("privacy.export", ""), ("room.freeze", "root")]);
+
+        // anonymized records are not counted twice
+        let (_, report) = call_as(&app, Some(&root), "POST", "/audit/retention", "/audit/retention",
+            Some(json!({}))).await;
+        assert_eq!(report["actions"], json!([]));
+    }
+}
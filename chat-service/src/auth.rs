@@ -0,0 +1,102 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+
+use uchat_proto::acl::RoomKind;
+use uchat_proto::errors::ApiError;
+use uchat_proto::jwt::{verify_claims, Claims, ScopeAction};
+
+use crate::notify;
+use crate::rooms;
+use crate::AppState;
+
+pub type ApiFailure = (StatusCode, Json<ApiError>);
+
+pub fn api_error(status: StatusCode, message: &str) -> ApiFailure {
+    (status, Json(ApiError { message: message.into() }))
+}
+
+/// Claims of any valid token, delegated ones included. Revoked tokens
+/// are refused (see revocations.rs).
+pub fn token_claims(state: &AppState, headers: &HeaderMap) -> Option<Claims> {
+    let header = headers.get("Authorization")?.to_str().ok()?;
+    let token = header.strip_prefix("Bearer ")?;
+    verify_claims(&state.secret, token).filter(|c| !state.revocations.revokes(c))
+}
+
+/// Claims of a token carrying its owner's full rights. Delegated tokens
+/// only reach the room endpoints, through `authorize_room_for`.
+pub fn bearer_claims(state: &AppState, headers: &HeaderMap) -> Option<Claims> {
+    token_claims(state, headers).filter(|c| !c.is_delegated())
+}
+
+/// Read access to `room`; see `authorize_room_for`.
+pub fn authorize_room(state: &AppState, headers: &HeaderMap, room: &str) -> Result<Option<Claims>, ApiFailure> {
+    authorize_room_for(state, headers, room, ScopeAction::Read)
+}
+
+/// Group-restricted rooms (private rooms, ROOM_ACL) require a token whose
+/// `groups` claim intersects the room's allowed groups, and direct-message
+/// rooms a token of one of their participants. Other rooms pass through. A
+/// delegated token must also cover `action` in `room`.
+pub fn authorize_room_for(
+    state: &AppState,
+    headers: &HeaderMap,
+    room: &str,
+    action: ScopeAction,
+) -> Result<Option<Claims>, ApiFailure> {
+    let claims = token_claims(state, headers);
+    if claims.as_ref().is_some_and(|c| !c.allows(room, action)) {
+        let message = format!("this token may not {} in this room", action.as_str());
+        return Err(api_error(StatusCode::FORBIDDEN, &message));
+    }
+
+    if let Some(participants) = notify::dm_participants(room) {
+        return match claims {
+            Some(c) if participants.contains(&c.sub.as_str()) => Ok(Some(c)),
+            Some(_) => Err(api_error(StatusCode::FORBIDDEN, "only its participants may use a direct-message room")),
+            None => Err(api_error(StatusCode::UNAUTHORIZED, "this room requires a token")),
+        };
+    }
+
+    let room = rooms::info(&state.db.lock().unwrap(), &state.acl, room);
+    if room.kind != RoomKind::Private {
+        return Ok(claims);
+    }
+
+    match claims {
+        Some(c) if c.groups.iter().any(|g| room.groups.contains(g)) => Ok(Some(c)),
+        Some(_) => Err(api_error(StatusCode::FORBIDDEN, "not a member of a group allowed in this room")),
+        None => Err(api_error(StatusCode::UNAUTHORIZED, "this room requires a token")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_delegated_token, secret_from_env, Scope, ScopeAction};
+
+    use crate::testing::{app, call_as};
+
+    #[tokio::test]
+    async fn delegated_tokens_stay_within_their_scope() {
+        let app = app();
+        let scope = Scope { bot: "notifier".into(), rooms: vec!["alerts".into()], actions: vec![ScopeAction::Send] };
+        let bot = create_delegated_token(&secret_from_env(), "ann", Vec::new(), "t1", scope, chrono::Duration::minutes(5));
+
+        let (status, _) = call_as(&app, Some(&bot), "POST", "/send", "/send",
+            Some(json!({ "email": "ann", "message": "disk full", "room": "alerts" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call_as(&app, Some(&bot), "POST", "/send", "/send",
+            Some(json!({ "email": "ann", "message": "hi", "room": "lobby" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call_as(&app, Some(&bot), "GET", "/messages?room=alerts", "/messages", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // account-level endpoints do not take delegated tokens at all
+        let (status, _) = call_as(&app, Some(&bot), "POST", "/rooms", "/rooms", Some(json!({ "id": "bots" }))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,283 @@
+use rusqlite::Connection;
+
+/// Opens the chat database (`CHAT_DB_PATH`, default `chat.db`) and makes
+/// sure the tables exist.
+pub fn open() -> rusqlite::Result<Connection> {
+    let path = std::env::var("CHAT_DB_PATH").unwrap_or_else(|_| "chat.db".into());
+    open_path(&path)
+}
+
+pub fn open_path(path: &str) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS messages (
+            id      INTEGER PRIMARY KEY AUTOINCREMENT,
+            room    TEXT NOT NULL DEFAULT 'lobby',
+            email   TEXT NOT NULL,
+            message TEXT NOT NULL,
+            ts      TEXT NOT NULL,
+            -- set when the author's data was erased; row stays as a tombstone
+            erased_at TEXT,
+            -- body stored by content (see payloads.rs); message is then ''
+            payload_ref TEXT,
+            -- 1 as sent, one more per edit (see edits.rs)
+            revision  INTEGER NOT NULL DEFAULT 1,
+            edited_at TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS payloads (
+            digest     TEXT PRIMARY KEY,
+            body       TEXT NOT NULL,
+            refs       INTEGER NOT NULL,
+            created_at TEXT NOT NULL
+        ) WITHOUT ROWID;
+
+        -- rooms created through POST /rooms; others are implicit (see rooms.rs)
+        CREATE TABLE IF NOT EXISTS rooms (
+            id         TEXT PRIMARY KEY,
+            kind       TEXT NOT NULL,
+            groups     TEXT NOT NULL DEFAULT '[]',
+            created_by TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            -- 1: keep delivery/read receipts (see receipts.rs)
+            compliance INTEGER NOT NULL DEFAULT 0
+        );
+
+        -- named rooms to clone (see templates.rs); include is a JSON array
+        CREATE TABLE IF NOT EXISTS room_templates (
+            name       TEXT PRIMARY KEY,
+            room       TEXT NOT NULL,
+            include    TEXT NOT NULL,
+            created_by TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+
+        -- read-only rooms (see freezes.rs); reason is '' when none was given
+        CREATE TABLE IF NOT EXISTS room_freezes (
+            room      TEXT PRIMARY KEY,
+            reason    TEXT NOT NULL,
+            frozen_by TEXT NOT NULL,
+            frozen_at TEXT NOT NULL
+        );
+
+        -- rooms shipped to the external archive (see archive.rs)
+        CREATE TABLE IF NOT EXISTS room_archives (
+            room       TEXT PRIMARY KEY,
+            flagged_by TEXT NOT NULL,
+            flagged_at TEXT NOT NULL
+        );
+
+        -- queued for the archive sink; deleted once shipped. kind: message, edit
+        CREATE TABLE IF NOT EXISTS archive_records (
+            id         INTEGER PRIMARY KEY AUTOINCREMENT,
+            room       TEXT NOT NULL,
+            kind       TEXT NOT NULL,
+            message_id INTEGER NOT NULL,
+            sender     TEXT NOT NULL,
+            content    TEXT NOT NULL,
+            revision   INTEGER NOT NULL,
+            ts         TEXT NOT NULL
+        );
+
+        -- last record id each sink accepted
+        CREATE TABLE IF NOT EXISTS archive_checkpoints (
+            sink       TEXT PRIMARY KEY,
+            last_id    INTEGER NOT NULL,
+            shipped_at TEXT NOT NULL
+        );
+
+        -- per-room masking switch (see profanity.rs); other rooms use the default
+        CREATE TABLE IF NOT EXISTS profanity_settings (
+            room       TEXT PRIMARY KEY,
+            enabled    INTEGER NOT NULL,
+            updated_by TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+
+        -- unix seconds; one row per message and recipient
+        CREATE TABLE IF NOT EXISTS receipts (
+            message_id   INTEGER NOT NULL,
+            username     TEXT NOT NULL,
+            delivered_at INTEGER NOT NULL,
+            read_at      INTEGER,
+            PRIMARY KEY (message_id, username)
+        ) WITHOUT ROWID;
+
+        -- one row per recipient and message (see notify.rs); created_at is
+        -- unix seconds; status: pending, sent, skipped (recipient online), failed
+        CREATE TABLE IF NOT EXISTS notifications (
+            id         INTEGER PRIMARY KEY AUTOINCREMENT,
+            username   TEXT NOT NULL,
+            kind       TEXT NOT NULL,
+            room       TEXT NOT NULL,
+            message_id INTEGER NOT NULL,
+            sender     TEXT NOT NULL,
+            excerpt    TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            status     TEXT NOT NULL,
+            attempts   INTEGER NOT NULL DEFAULT 0,
+            sent_at    TEXT
+        );
+        CREATE INDEX IF NOT EXISTS notifications_pending ON notifications (status, username);
+
+        CREATE TABLE IF NOT EXISTS notification_prefs (
+            username   TEXT PRIMARY KEY,
+            prefs      TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+
+        -- room '' holds instance-wide emoji (see emoji.rs)
+        CREATE TABLE IF NOT EXISTS custom_emoji (
+            room        TEXT NOT NULL,
+            name        TEXT NOT NULL,
+            storage_key TEXT NOT NULL,
+            url         TEXT NOT NULL,
+            created_by  TEXT NOT NULL,
+            created_at  TEXT NOT NULL,
+            PRIMARY KEY (room, name)
+        );
+
+        CREATE TABLE IF NOT EXISTS reactions (
+            message_id INTEGER NOT NULL,
+            username   TEXT NOT NULL,
+            emoji      TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (message_id, username, emoji)
+        );
+
+        CREATE TABLE IF NOT EXISTS outbox (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            topic        TEXT NOT NULL,
+            payload      TEXT NOT NULL,
+            created_at   TEXT NOT NULL,
+            attempts     INTEGER NOT NULL DEFAULT 0,
+            delivered_at TEXT
+        );
+        CREATE INDEX IF NOT EXISTS outbox_pending ON outbox (id) WHERE delivered_at IS NULL;
+
+        CREATE TABLE IF NOT EXISTS leases (
+            name       TEXT PRIMARY KEY,
+            holder     TEXT NOT NULL,
+            expires_at INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS audit_log (
+            id     INTEGER PRIMARY KEY AUTOINCREMENT,
+            ts     TEXT NOT NULL,
+            action TEXT NOT NULL,
+            actor  TEXT NOT NULL,
+            target TEXT NOT NULL,
+            detail TEXT NOT NULL
+        );
+
+        -- status: pending, done, blocked (policy), failed (gave up)
+        CREATE TABLE IF NOT EXISTS link_previews (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id  INTEGER NOT NULL,
+            url         TEXT NOT NULL,
+            status      TEXT NOT NULL,
+            title       TEXT,
+            description TEXT,
+            image       TEXT,
+            attempts    INTEGER NOT NULL DEFAULT 0,
+            error       TEXT,
+            created_at  TEXT NOT NULL,
+            fetched_at  TEXT,
+            retry_at    TEXT
+        );
+        CREATE INDEX IF NOT EXISTS link_previews_message ON link_previews (message_id);
+
+        -- one stream per device (see telemetry.rs); ts is the device's clock
+        CREATE TABLE IF NOT EXISTS telemetry (
+            device      TEXT NOT NULL,
+            seq         INTEGER NOT NULL,
+            device_ts   TEXT NOT NULL,
+            received_at TEXT NOT NULL,
+            data        TEXT NOT NULL,
+            PRIMARY KEY (device, seq)
+        ) WITHOUT ROWID;
+
+        -- see analytics.rs; bucket is the period's first day, room '' all rooms
+        CREATE TABLE IF NOT EXISTS analytics_rollups (
+            period      TEXT NOT NULL,
+            bucket      TEXT NOT NULL,
+            metric      TEXT NOT NULL,
+            room        TEXT NOT NULL,
+            value       REAL NOT NULL,
+            computed_at TEXT NOT NULL,
+            PRIMARY KEY (period, bucket, metric, room)
+        ) WITHOUT ROWID;
+
+        -- see polls.rs; options is a JSON array, expires_at unix seconds
+        CREATE TABLE IF NOT EXISTS polls (
+            id         TEXT PRIMARY KEY,
+            room       TEXT NOT NULL,
+            question   TEXT NOT NULL,
+            options    TEXT NOT NULL,
+            expires_at INTEGER NOT NULL,
+            closed     INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE INDEX IF NOT EXISTS polls_open ON polls (closed, expires_at);
+
+        CREATE TABLE IF NOT EXISTS poll_votes (
+            poll_id  TEXT NOT NULL,
+            username TEXT NOT NULL,
+            option   INTEGER NOT NULL,
+            PRIMARY KEY (poll_id, username)
+        ) WITHOUT ROWID;
+        CREATE INDEX IF NOT EXISTS poll_votes_user ON poll_votes (username);
+        CREATE INDEX IF NOT EXISTS messages_ts ON messages (ts);",
+    )?;
+
+    // databases created before erasure support lack the column
+    let has_erased_at = conn
+        .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'erased_at'")?
+        .exists([])?;
+    if !has_erased_at {
+        conn.execute("ALTER TABLE messages ADD COLUMN erased_at TEXT", [])?;
+    }
+
+    // ... and before rooms; everything sent so far went to the lobby
+    let has_room = conn
+        .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'room'")?
+        .exists([])?;
+    if !has_room {
+        conn.execute("ALTER TABLE messages ADD COLUMN room TEXT NOT NULL DEFAULT 'lobby'", [])?;
+    }
+
+    // ... and before compliance rooms
+    let has_compliance = conn
+        .prepare("SELECT 1 FROM pragma_table_info('rooms') WHERE name = 'compliance'")?
+        .exists([])?;
+    if !has_compliance {
+        conn.execute("ALTER TABLE rooms ADD COLUMN compliance INTEGER NOT NULL DEFAULT 0", [])?;
+    }
+
+    // ... and before deduplicated payloads
+    let has_payload_ref = conn
+        .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'payload_ref'")?
+        .exists([])?;
+    if !has_payload_ref {
+        conn.execute("ALTER TABLE messages ADD COLUMN payload_ref TEXT", [])?;
+    }
+
+    // ... and before edits
+    let has_revision = conn
+        .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'revision'")?
+        .exists([])?;
+    if !has_revision {
+        conn.execute("ALTER TABLE messages ADD COLUMN revision INTEGER NOT NULL DEFAULT 1", [])?;
+        conn.execute("ALTER TABLE messages ADD COLUMN edited_at TEXT", [])?;
+    }
+
+    // ... and before preview retries backed off
+    let has_retry_at = conn
+        .prepare("SELECT 1 FROM pragma_table_info('link_previews') WHERE name = 'retry_at'")?
+        .exists([])?;
+    if !has_retry_at {
+        conn.execute("ALTER TABLE link_previews ADD COLUMN retry_at TEXT", [])?;
+    }
+
+    Ok(conn)
+}
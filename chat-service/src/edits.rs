@@ -0,0 +1,212 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+use uchat_proto::events::ServerEvent;
+
+use crate::archive;
+use crate::auth::{api_error, authorize_room, bearer_claims};
+use crate::outbox;
+use crate::AppState;
+
+//
+// MESSAGE EDITS
+//
+// Authors replace the text of their own messages:
+//
+//   PATCH /messages/:room/:id   {"message": "...", "revision": 2}
+//
+// Every message has a revision, 1 as sent and one more per edit. The
+// request names the revision the edit was made on; if the message has
+// moved on since (an edit from another device), it is refused with 409
+// and the current version, for the client to reconcile and retry, rather
+// than overwriting the other edit. Accepted edits go out as MessageEdited
+// with the new revision through the outbox. Erased messages stay erased.
+//
+
+#[derive(Deserialize, ToSchema)]
+pub struct EditRequest {
+    pub message: String,
+    /// The revision the edit was made on.
+    pub revision: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessageVersion {
+    pub id: i64,
+    pub room: String,
+    pub message: String,
+    pub revision: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
+}
+
+/// 409 body: what the edit lost to.
+#[derive(Serialize, ToSchema)]
+pub struct EditConflict {
+    pub message: String,
+    pub current: MessageVersion,
+}
+
+struct Stored {
+    author: String,
+    erased: bool,
+    version: MessageVersion,
+}
+
+fn stored(conn: &Connection, room: &str, id: i64) -> Option<Stored> {
+    conn.query_row(
+        "SELECT m.email, m.erased_at IS NOT NULL, COALESCE(p.body, m.message), m.revision, m.edited_at
+         FROM messages m LEFT JOIN payloads p ON p.digest = m.payload_ref
+         WHERE m.id = ?1 AND m.room = ?2",
+        params![id, room],
+        |r| {
+            Ok(Stored {
+                author: r.get(0)?,
+                erased: r.get(1)?,
+                version: MessageVersion {
+                    id,
+                    room: room.to_string(),
+                    message: r.get(2)?,
+                    revision: r.get(3)?,
+                    edited_at: r.get(4)?,
+                },
+            })
+        },
+    )
+    .optional()
+    .unwrap()
+}
+
+#[utoipa::path(patch, path = "/messages/{room}/{id}", tag = "messages",
+    params(("room" = String, Path), ("id" = i64, Path, description = "message id")),
+    request_body = EditRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = MessageVersion), (status = 401, body = ApiError), (status = 403, body = ApiError),
+        (status = 404, body = ApiError), (status = 409, body = EditConflict)))]
+pub async fn edit(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((room, message_id)): Path<(String, i64)>,
+    Json(body): Json<EditRequest>,
+) -> Response {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return api_error(StatusCode::UNAUTHORIZED, "missing or invalid token").into_response();
+    };
+    if let Err(e) = authorize_room(&state, &headers, &room) {
+        return e.into_response();
+    }
+
+    let mut db = state.db.lock().unwrap();
+    let tx = db.transaction().unwrap();
+    let Some(current) = stored(&tx, &room, message_id) else {
+        return api_error(StatusCode::NOT_FOUND, "no such message in this room").into_response();
+    };
+    if current.erased {
+        return api_error(StatusCode::NOT_FOUND, "message was erased").into_response();
+    }
+    if current.author != claims.sub {
+        return api_error(StatusCode::FORBIDDEN, "only the author may edit a message").into_response();
+    }
+
+    // conditional, so an instance sharing the database cannot slip in
+    let now = Utc::now().to_rfc3339();
+    let updated = tx
+        .execute(
+            "UPDATE messages SET revision = revision + 1, edited_at = ?1 WHERE id = ?2 AND revision = ?3",
+            params![now, message_id, body.revision],
+        )
+        .unwrap();
+    if updated == 0 {
+        let conflict = EditConflict {
+            message: format!("message is at revision {}; reapply the edit to it", current.version.revision),
+            current: current.version,
+        };
+        return (StatusCode::CONFLICT, Json(conflict)).into_response();
+    }
+    state.payloads.replace_body(&tx, message_id, &body.message).unwrap();
+    archive::enqueue(&tx, "edit", message_id, &body.message).unwrap();
+
+    let revision = body.revision + 1;
+    // the row keeps the original; only the live event is masked
+    let delivered = state.profanity.for_room(&tx, &room, &body.message).into_owned();
+    outbox::enqueue(&tx, outbox::TOPIC_EDIT, &ServerEvent::MessageEdited {
+        room: room.clone(),
+        message_id,
+        from: claims.sub,
+        content: delivered,
+        revision,
+    })
+    .unwrap();
+    tx.commit().unwrap();
+    state.outbox_notify.notify_one();
+
+    Json(MessageVersion { id: message_id, room, message: body.message, revision, edited_at: Some(now) }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::events::ServerEvent;
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use crate::testing::{call, call_as};
+    use crate::{db, outbox, router, AppState};
+
+    #[tokio::test]
+    async fn concurrent_edits_conflict() {
+        let state = AppState::new(db::open_path(":memory:").unwrap());
+        let mut live = state.tx.subscribe();
+        tokio::spawn(outbox::relay_loop(state.clone()));
+        let app = router(state);
+        let ann = create_token(&secret_from_env(), "ann");
+        let bob = create_token(&secret_from_env(), "bob");
+
+        call(&app, "POST", "/send", "/send", Some(json!({ "email": "ann", "message": "helo" }))).await;
+        let (_, history) = call(&app, "GET", "/messages", "/messages", None).await;
+        assert_eq!(history[0]["revision"], 1);
+
+        let (uri, route) = ("/messages/lobby/1", "/messages/{room}/{id}");
+        let (status, _) = call_as(&app, Some(&bob), "PATCH", uri, route,
+            Some(json!({ "message": "bob was here", "revision": 1 }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, version) = call_as(&app, Some(&ann), "PATCH", uri, route,
+            Some(json!({ "message": "hello", "revision": 1 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(version["revision"], 2);
+
+        // a second device still on revision 1 loses, and learns what won
+        let (status, conflict) = call_as(&app, Some(&ann), "PATCH", uri, route,
+            Some(json!({ "message": "hi", "revision": 1 }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(conflict["current"]["message"], "hello");
+        assert_eq!(conflict["current"]["revision"], 2);
+        let (status, _) = call_as(&app, Some(&ann), "PATCH", "/messages/lobby/9", route,
+            Some(json!({ "message": "hi", "revision": 1 }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, history) = call(&app, "GET", "/messages", "/messages", None).await;
+        assert_eq!(history[0]["message"], "hello");
+        assert!(history[0]["edited_at"].is_string());
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), live.recv()).await.unwrap().unwrap();
+            if let ServerEvent::MessageEdited { message_id, content, revision, .. } = event {
+                assert_eq!((message_id, content.as_str(), revision), (1, "hello", 2));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use uchat_proto::errors::ApiError;
+
+use crate::audit;
+use crate::auth::{api_error, authorize_room, bearer_claims, ApiFailure};
+use crate::rooms;
+use crate::AppState;
+
+//
+// CUSTOM EMOJI
+//
+// Emoji are registered instance-wide by admins, or for one room by its
+// creator (or an admin). A room emoji shadows an instance emoji of the same
+// name in that room. Images go through `Storage`; set MEDIA_BASE_URL so the
+// returned URLs point at whatever serves UPLOAD_DIR (the gateway's /media).
+//
+//   CHAT_EMOJI_MAX_BYTES   largest accepted image (default 262144)
+//
+// Clients fetch GET /emoji?room=... once and revalidate with the ETag.
+// Reactions may use any registered emoji as ":name:" besides Unicode
+// emoji (see reactions.rs).
+//
+
+/// Stored as the `room` of instance-wide emoji.
+const INSTANCE: &str = "";
+
+const IMAGE_TYPES: &[(&str, &str)] = &[("image/png", "png"), ("image/gif", "gif"), ("image/webp", "webp")];
+
+#[derive(Debug, Clone, Serialize, ToSchema, Hash)]
+pub struct CustomEmoji {
+    pub name: String,
+    pub url: String,
+    /// Unset for instance-wide emoji.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EmojiRegistry {
+    pub emoji: Vec<CustomEmoji>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct EmojiQuery {
+    /// Include this room's emoji; without it only instance-wide ones.
+    pub room: Option<String>,
+}
+
+fn valid_name(name: &str) -> bool {
+    (2..=32).contains(&name.len()) && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn row(r: &rusqlite::Row) -> rusqlite::Result<CustomEmoji> {
+    let room: String = r.get(1)?;
+    Ok(CustomEmoji {
+        name: r.get(0)?,
+        room: (room != INSTANCE).then_some(room),
+        url: r.get(2)?,
+        created_by: r.get(3)?,
+        created_at: r.get(4)?,
+    })
+}
+
+/// The emoji usable in `room`: its own, then instance-wide ones it does
+/// not shadow, by name.
+pub fn registry(conn: &Connection, room: Option<&str>) -> Vec<CustomEmoji> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT name, room, url, created_by, created_at FROM custom_emoji e
+             WHERE room = ?1
+                OR (room = '' AND NOT EXISTS (SELECT 1 FROM custom_emoji r WHERE r.room = ?1 AND r.name = e.name))
+             ORDER BY name",
+        )
+        .unwrap();
+    stmt.query_map([room.unwrap_or(INSTANCE)], row).unwrap().filter_map(|r| r.ok()).collect()
+}
+
+/// Gives room `to` the emoji of room `from`. They share the stored
+/// images, which are only deleted with the last emoji using them.
+pub fn copy(conn: &Connection, from: &str, to: &str, actor: &str) {
+    conn.execute(
+        "INSERT INTO custom_emoji (room, name, storage_key, url, created_by, created_at)
+         SELECT ?1, name, storage_key, url, ?2, ?3 FROM custom_emoji WHERE room = ?4",
+        params![to, actor, Utc::now().to_rfc3339(), from],
+    )
+    .unwrap();
+}
+
+/// No emoji uses the image stored at `key` any more.
+fn unreferenced(conn: &Connection, key: &str) -> bool {
+    !conn.prepare("SELECT 1 FROM custom_emoji WHERE storage_key = ?1").unwrap().exists([key]).unwrap()
+}
+
+/// Whether `name` (without colons) is usable in `room`.
+pub fn exists(conn: &Connection, room: &str, name: &str) -> bool {
+    conn.query_row(
+        "SELECT 1 FROM custom_emoji WHERE name = ?1 AND room IN ('', ?2)",
+        params![name, room],
+        |_| Ok(()),
+    )
+    .optional()
+    .unwrap()
+    .is_some()
+}
+
+#[utoipa::path(get, path = "/emoji", tag = "emoji",
+    params(EmojiQuery),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = EmojiRegistry, headers(("ETag" = String))),
+        (status = 304, description = "unchanged since the If-None-Match ETag"),
+        (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn list(State(state): State<AppState>, headers: HeaderMap, Query(q): Query<EmojiQuery>) -> Result<Response, ApiFailure> {
+    if let Some(room) = &q.room {
+        authorize_room(&state, &headers, room)?;
+    }
+    let emoji = registry(&state.db.lock().unwrap(), q.room.as_deref());
+
+    let mut hasher = DefaultHasher::new();
+    emoji.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+    if headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) == Some(etag.as_str()) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok(([(header::ETAG, etag)], Json(EmojiRegistry { emoji })).into_response())
+}
+
+/// The caller, if they may manage emoji of `room` (instance-wide: admins).
+fn authorize_manage(state: &AppState, headers: &HeaderMap, room: &str) -> Result<String, ApiFailure> {
+    let Some(claims) = bearer_claims(state, headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if state.admins.contains(&claims.sub) {
+        return Ok(claims.sub);
+    }
+    if room != INSTANCE {
+        let info = rooms::info(&state.db.lock().unwrap(), &state.acl, room);
+        if info.created_by.as_deref() == Some(claims.sub.as_str()) {
+            return Ok(claims.sub);
+        }
+    }
+    Err(api_error(StatusCode::FORBIDDEN, "only admins and the room's creator may manage its emoji"))
+}
+
+async fn upload(state: AppState, headers: HeaderMap, room: String, name: String, body: Bytes) -> Result<Json<CustomEmoji>, ApiFailure> {
+    let actor = authorize_manage(&state, &headers, &room)?;
+    if !valid_name(&name) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "emoji names are 2-32 of a-z, 0-9 and _"));
+    }
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let Some((_, ext)) = IMAGE_TYPES.iter().find(|(t, _)| *t == content_type) else {
+        return Err(api_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "emoji must be image/png, image/gif or image/webp"));
+    };
+    if body.is_empty() || body.len() > state.emoji_max_bytes {
+        return Err(api_error(StatusCode::PAYLOAD_TOO_LARGE, "emoji image is empty or too large"));
+    }
+
+    let scope = if room == INSTANCE { "_instance" } else { room.as_str() };
+    let key = format!("emoji/{}/{}-{}.{}", scope, name, uuid::Uuid::new_v4().simple(), ext);
+    let url = state.storage.put(&key, body.to_vec()).await.map_err(|e| {
+        println!("chat-service: storing emoji {} failed: {}", key, e);
+        api_error(StatusCode::SERVICE_UNAVAILABLE, "could not store the image")
+    })?;
+
+    let replaced: Option<String> = {
+        let db = state.db.lock().unwrap();
+        let old = db
+            .query_row("SELECT storage_key FROM custom_emoji WHERE room = ?1 AND name = ?2", params![room, name], |r| r.get(0))
+            .optional()
+            .unwrap();
+        db.execute(
+            "INSERT INTO custom_emoji (room, name, storage_key, url, created_by, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (room, name) DO UPDATE SET storage_key = excluded.storage_key, url = excluded.url,
+                 created_by = excluded.created_by, created_at = excluded.created_at",
+            params![room, name, key, url, actor, Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+        audit::record(&db, "emoji.upload", &actor, &format!("{}:{}", scope, name), &key);
+        old
+    };
+    if let Some(old) = replaced.filter(|old| unreferenced(&state.db.lock().unwrap(), old)) {
+        let _ = state.storage.delete(&old).await;
+    }
+
+    let db = state.db.lock().unwrap();
+    let emoji = db
+        .query_row(
+            "SELECT name, room, url, created_by, created_at FROM custom_emoji WHERE room = ?1 AND name = ?2",
+            params![room, name],
+            row,
+        )
+        .unwrap();
+    Ok(Json(emoji))
+}
+
+async fn remove(state: AppState, headers: HeaderMap, room: String, name: String) -> Result<Json<CustomEmoji>, ApiFailure> {
+    let actor = authorize_manage(&state, &headers, &room)?;
+
+    let removed = {
+        let db = state.db.lock().unwrap();
+        let found = db
+            .query_row(
+                "SELECT name, room, url, created_by, created_at, storage_key FROM custom_emoji WHERE room = ?1 AND name = ?2",
+                params![room, name],
+                |r| Ok((row(r)?, r.get::<_, String>(5)?)),
+            )
+            .optional()
+            .unwrap();
+        if found.is_some() {
+            db.execute("DELETE FROM custom_emoji WHERE room = ?1 AND name = ?2", params![room, name]).unwrap();
+            let scope = if room == INSTANCE { "_instance" } else { room.as_str() };
+            audit::record(&db, "emoji.delete", &actor, &format!("{}:{}", scope, name), "");
+        }
+        found.map(|(emoji, key)| (emoji, unreferenced(&db, &key).then_some(key)))
+    };
+    let Some((emoji, key)) = removed else {
+        return Err(api_error(StatusCode::NOT_FOUND, "no such emoji"));
+    };
+    if let Some(key) = key {
+        let _ = state.storage.delete(&key).await;
+    }
+    Ok(Json(emoji))
+}
+
+#[utoipa::path(put, path = "/emoji/{name}", tag = "emoji",
+    params(("name" = String, Path)),
+    request_body(content = Vec<u8>, content_type = "image/png", description = "PNG, GIF or WebP image"),
+    security(("bearer" = [])),
+    responses((status = 200, body = CustomEmoji), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 413, body = ApiError), (status = 415, body = ApiError)))]
+pub async fn put_instance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> Result<Json<CustomEmoji>, ApiFailure> {
+    upload(state, headers, INSTANCE.into(), name, body).await
+}
+
+#[utoipa::path(delete, path = "/emoji/{name}", tag = "emoji",
+    params(("name" = String, Path)),
+    security(("bearer" = [])),
+    responses((status = 200, body = CustomEmoji), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 404, body = ApiError)))]
+pub async fn delete_instance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Json<CustomEmoji>, ApiFailure> {
+    remove(state, headers, INSTANCE.into(), name).await
+}
+
+#[utoipa::path(put, path = "/rooms/{id}/emoji/{name}", tag = "emoji",
+    params(("id" = String, Path), ("name" = String, Path)),
+    request_body(content = Vec<u8>, content_type = "image/png", description = "PNG, GIF or WebP image"),
+    security(("bearer" = [])),
+    responses((status = 200, body = CustomEmoji), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 413, body = ApiError), (status = 415, body = ApiError)))]
+pub async fn put_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((room, name)): Path<(String, String)>,
+    body: Bytes,
+) -> Result<Json<CustomEmoji>, ApiFailure> {
+    upload(state, headers, room, name, body).await
+}
+
+#[utoipa::path(delete, path = "/rooms/{id}/emoji/{name}", tag = "emoji",
+    params(("id" = String, Path), ("name" = String, Path)),
+    security(("bearer" = [])),
+    responses((status = 200, body = CustomEmoji), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 404, body = ApiError)))]
+pub async fn delete_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((room, name)): Path<(String, String)>,
+) -> Result<Json<CustomEmoji>, ApiFailure> {
+    remove(state, headers, room, name).await
+}
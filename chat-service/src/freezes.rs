@@ -0,0 +1,195 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+
+use crate::audit;
+use crate::auth::{api_error, authorize_room, bearer_claims, ApiFailure};
+use crate::AppState;
+
+//
+// ROOM FREEZES
+//
+// A frozen room is read-only: POST /send and /v2/send to it answer 423
+// Locked, for everyone, until it is thawed. History, receipts and
+// reactions to earlier messages keep working. Admins freeze a room with
+// PUT /rooms/{id}/freeze and thaw it with DELETE; the gateway makes the
+// same calls when an admin freezes a room there, so sends over either
+// path are refused.
+//
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RoomFreeze {
+    pub room: String,
+    pub frozen: bool,
+    /// Unset while the room is not frozen.
+    pub reason: Option<String>,
+    pub frozen_by: Option<String>,
+    pub frozen_at: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FreezeRoom {
+    /// Shown to whoever is refused.
+    pub reason: Option<String>,
+}
+
+/// `room`'s freeze; `frozen` is false when there is none.
+pub fn get(conn: &Connection, room: &str) -> RoomFreeze {
+    let row = conn
+        .query_row("SELECT reason, frozen_by, frozen_at FROM room_freezes WHERE room = ?1", [room], |r| {
+            Ok((r.get::<_, String>(0)?, r.get(1)?, r.get(2)?))
+        })
+        .optional()
+        .unwrap();
+    match row {
+        Some((reason, frozen_by, frozen_at)) => RoomFreeze {
+            room: room.to_string(),
+            frozen: true,
+            reason: Some(reason).filter(|r| !r.is_empty()),
+            frozen_by: Some(frozen_by),
+            frozen_at: Some(frozen_at),
+        },
+        None => RoomFreeze { room: room.to_string(), frozen: false, reason: None, frozen_by: None, frozen_at: None },
+    }
+}
+
+/// The 423 for a send to a frozen room; None if `room` is not frozen.
+pub fn refusal(conn: &Connection, room: &str) -> Option<ApiFailure> {
+    let freeze = get(conn, room);
+    if !freeze.frozen {
+        return None;
+    }
+    let message = match freeze.reason {
+        Some(reason) => format!("room {} is frozen: {}", room, reason),
+        None => format!("room {} is frozen", room),
+    };
+    Some(api_error(StatusCode::LOCKED, &message))
+}
+
+/// The caller, if an admin.
+fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<String, ApiFailure> {
+    let Some(claims) = bearer_claims(state, headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if !state.admins.contains(&claims.sub) {
+        return Err(api_error(StatusCode::FORBIDDEN, "only admins may freeze rooms"));
+    }
+    Ok(claims.sub)
+}
+
+#[utoipa::path(get, path = "/rooms/{id}/freeze", tag = "rooms",
+    params(("id" = String, Path)),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = RoomFreeze), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn get_freeze(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RoomFreeze>, ApiFailure> {
+    authorize_room(&state, &headers, &id)?;
+    Ok(Json(get(&state.db.lock().unwrap(), &id)))
+}
+
+#[utoipa::path(put, path = "/rooms/{id}/freeze", tag = "rooms",
+    params(("id" = String, Path)),
+    request_body = FreezeRoom,
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomFreeze), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn freeze(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<FreezeRoom>,
+) -> Result<Json<RoomFreeze>, ApiFailure> {
+    let actor = authorize_admin(&state, &headers)?;
+    let reason = body.reason.unwrap_or_default();
+    let db = state.db.lock().unwrap();
+    db.execute(
+        "INSERT INTO room_freezes (room, reason, frozen_by, frozen_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(room) DO UPDATE SET reason = ?2, frozen_by = ?3, frozen_at = ?4",
+        params![id, reason, actor, Utc::now().to_rfc3339()],
+    )
+    .unwrap();
+    audit::record(&db, "room.freeze", &actor, &id, reason);
+    Ok(Json(get(&db, &id)))
+}
+
+#[utoipa::path(delete, path = "/rooms/{id}/freeze", tag = "rooms",
+    params(("id" = String, Path)),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomFreeze), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn thaw(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RoomFreeze>, ApiFailure> {
+    let actor = authorize_admin(&state, &headers)?;
+    let db = state.db.lock().unwrap();
+    if db.execute("DELETE FROM room_freezes WHERE room = ?1", [&id]).unwrap() > 0 {
+        audit::record(&db, "room.thaw", &actor, &id, "");
+    }
+    Ok(Json(get(&db, &id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use crate::testing::{call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn frozen_rooms_refuse_sends_until_thawed() {
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        state.admins = Arc::new(vec!["root".to_string()]);
+        let app = router(state);
+        let root = create_token(&secret_from_env(), "root");
+        let ann = create_token(&secret_from_env(), "ann");
+        let send = json!({ "message": "hi", "room": "incident" });
+
+        let (status, _) = call_as(&app, Some(&ann), "PUT", "/rooms/incident/freeze", "/rooms/{id}/freeze",
+            Some(json!({}))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, freeze) = call_as(&app, Some(&root), "PUT", "/rooms/incident/freeze", "/rooms/{id}/freeze",
+            Some(json!({ "reason": "incident 42" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((&freeze["frozen"], &freeze["frozen_by"]), (&json!(true), &json!("root")));
+
+        // admins are refused too; nothing is stored
+        for token in [&ann, &root] {
+            let (status, err) =
+                call_as(&app, Some(token), "POST", "/v2/send", "/v2/send", Some(send.clone())).await;
+            assert_eq!(status, StatusCode::LOCKED);
+            assert_eq!(err["message"], "room incident is frozen: incident 42");
+        }
+        let (status, _) = call(&app, "POST", "/send", "/send",
+            Some(json!({ "email": "ann", "message": "hi", "room": "incident" }))).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        let (_, history) = call(&app, "GET", "/rooms/incident/messages", "/rooms/{id}/messages", None).await;
+        assert_eq!(history["messages"], json!([]));
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/v2/send", "/v2/send",
+            Some(json!({ "message": "hi" }))).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (_, freeze) = call_as(&app, Some(&root), "DELETE", "/rooms/incident/freeze", "/rooms/{id}/freeze",
+            None).await;
+        assert_eq!((&freeze["frozen"], &freeze["reason"]), (&json!(false), &Value::Null));
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/v2/send", "/v2/send", Some(send)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, freeze) = call(&app, "GET", "/rooms/incident/freeze", "/rooms/{id}/freeze", None).await;
+        assert_eq!(freeze["frozen"], false);
+    }
+}
@@ -1,54 +1,143 @@
-use axum::{Json, extract::State};
+use std::collections::BTreeMap;
+
+use axum::{Json, extract::{Query, State}, http::{HeaderMap, StatusCode}};
 use serde::{Deserialize, Serialize};
 use chrono::Utc;
+use rusqlite::Connection;
+use utoipa::{IntoParams, ToSchema};
+
+use uchat_proto::errors::ApiError;
+use uchat_proto::events::ServerEvent;
 
+use crate::archive;
+use crate::auth::{api_error, authorize_room, ApiFailure};
+use crate::notify;
+use crate::outbox;
+use crate::profanity;
+use crate::reactions;
+use crate::rooms;
+use crate::unfurl::{self, LinkPreview};
 use crate::AppState;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct IncomingMessage {
     pub email: String,
     pub message: String,
+    #[serde(default = "default_room")]
+    pub room: String,
 }
 
-#[derive(Serialize)]
+fn default_room() -> String {
+    "lobby".into()
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct OutgoingMessage {
+    pub id: i64,
+    pub room: String,
     pub email: String,
     pub message: String,
     pub ts: String,
+    /// Set on tombstones left by a privacy erasure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub erased_at: Option<String>,
+    /// Link previews fetched so far; see MessageUnfurled for live updates.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub previews: Vec<LinkPreview>,
+    /// Reaction count per emoji (see reactions.rs).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, u32>,
+    /// 1 as sent, one more per edit (see edits.rs).
+    pub revision: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
 }
 
-// POST /send
+#[utoipa::path(post, path = "/send", tag = "messages",
+    request_body = IncomingMessage,
+    security((), ("bearer" = [])),
+    responses((status = 200, body = String, example = json!("ok")),
+        (status = 401, body = ApiError), (status = 403, body = ApiError), (status = 423, body = ApiError),
+        (status = 429, body = ApiError, headers(("Retry-After" = u64, description = "seconds to wait")))))]
 pub async fn send_message(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(body): Json<IncomingMessage>,
-) -> Json<&'static str> {
+) -> Result<Json<&'static str>, ApiFailure> {
+    let claims = rooms::authorize_post(&state, &headers, &body.room)?;
+    // with a token, the message goes out under its subject's name
+    if claims.is_some_and(|c| c.sub != body.email) {
+        return Err(api_error(StatusCode::FORBIDDEN, "email does not match the token"));
+    }
+    store_message(&state, &body.room, &body.email, &body.message);
+    Ok(Json("ok"))
+}
+
+/// Stores a message the caller may post and queues its delivery, link
+/// previews and notifications; returns its id and timestamp. Shared by
+/// every API version's send.
+pub fn store_message(state: &AppState, room: &str, sender: &str, message: &str) -> (i64, String) {
     let ts = Utc::now().to_rfc3339();
 
-    let db = state.db.lock().unwrap();
+    let mut db = state.db.lock().unwrap();
+    let tx = db.transaction().unwrap();
+    // the row keeps the original; only the live event is masked
+    let delivered = state.profanity.for_room(&tx, room, message).into_owned();
 
-    db.execute(
-        "INSERT INTO messages (email, message, ts) VALUES (?1, ?2, ?3)",
-        (&body.email, &body.message, &ts),
-    )
+    let message_id = state.payloads.insert_message(&tx, room, sender, message, &ts).unwrap();
+
+    // stream delivery goes through the outbox so it commits with the row
+    outbox::enqueue(&tx, outbox::TOPIC_MESSAGE, &ServerEvent::MessageBroadcast {
+        from: sender.to_string(),
+        content: delivered,
+    })
     .unwrap();
 
-    Json("ok")
+    archive::enqueue(&tx, "message", message_id, message).unwrap();
+    let unfurls = unfurl::enqueue(&tx, &state.unfurl, message_id, message).unwrap();
+    let notifications = notify::enqueue(&tx, &state.notifier, &state.acl, room, sender, message_id, message).unwrap();
+
+    tx.commit().unwrap();
+    state.outbox_notify.notify_one();
+    if unfurls > 0 {
+        state.unfurl_notify.notify_one();
+    }
+    if notifications > 0 {
+        state.notifier.wake.notify_one();
+    }
+
+    (message_id, ts)
 }
 
-// GET /messages
-pub async fn get_messages(
-    State(state): State<AppState>,
-) -> Json<Vec<OutgoingMessage>> {
-    let db = state.db.lock().unwrap();
+/// Messages of `room` in send order, with their fetched link previews and
+/// deduplicated bodies filled in. Order is by timestamp, so history
+/// backfilled by chat-migrate sits before what was sent since.
+pub fn load_messages(db: &Connection, room: &str) -> Vec<OutgoingMessage> {
+    let mut previews = unfurl::fetched(db, room);
+    let mut reactions = reactions::counts(db, room);
 
-    let mut stmt = db.prepare("SELECT email, message, ts FROM messages ORDER BY id ASC").unwrap();
+    let mut stmt = db
+        .prepare(
+            "SELECT m.id, m.room, m.email, COALESCE(p.body, m.message), m.ts, m.erased_at, m.revision, m.edited_at
+             FROM messages m LEFT JOIN payloads p ON p.digest = m.payload_ref
+             WHERE m.room = ?1 ORDER BY m.ts ASC, m.id ASC",
+        )
+        .unwrap();
 
     let rows = stmt
-        .query_map([], |row| {
+        .query_map([room], |row| {
+            let id = row.get(0)?;
             Ok(OutgoingMessage {
-                email: row.get(0)?,
-                message: row.get(1)?,
-                ts: row.get(2)?,
+                id,
+                room: row.get(1)?,
+                email: row.get(2)?,
+                message: row.get(3)?,
+                ts: row.get(4)?,
+                erased_at: row.get(5)?,
+                previews: previews.remove(&id).unwrap_or_default(),
+                reactions: reactions.remove(&id).unwrap_or_default(),
+                revision: row.get(6)?,
+                edited_at: row.get(7)?,
             })
         })
         .unwrap();
@@ -57,6 +146,103 @@ pub async fn get_messages(
     for r in rows {
         out.push(r.unwrap());
     }
+    out
+}
 
-    Json(out)
+#[derive(Deserialize, IntoParams)]
+pub struct MessagesQuery {
+    /// Room to read (default `lobby`).
+    #[serde(default = "default_room")]
+    pub room: String,
+    /// Unmasked content (see profanity.rs); moderators only.
+    #[serde(default)]
+    pub original: bool,
+}
+
+#[utoipa::path(get, path = "/messages", tag = "messages",
+    params(MessagesQuery),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = Vec<OutgoingMessage>),
+        (status = 401, body = ApiError), (status = 403, body = ApiError),
+        (status = 429, body = ApiError, headers(("Retry-After" = u64, description = "seconds to wait")))))]
+pub async fn get_messages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<MessagesQuery>,
+) -> Result<Json<Vec<OutgoingMessage>>, ApiFailure> {
+    Ok(Json(read_messages(&state, &headers, &q.room, q.original)?))
+}
+
+/// `room`'s history as the caller may see it. Shared by every API
+/// version's message listing.
+pub fn read_messages(
+    state: &AppState,
+    headers: &HeaderMap,
+    room: &str,
+    original: bool,
+) -> Result<Vec<OutgoingMessage>, ApiFailure> {
+    let claims = authorize_room(state, headers, room)?;
+    let db = state.db.lock().unwrap();
+    let mut messages = load_messages(&db, room);
+    profanity::deliver(state, &db, claims.as_ref(), room, original, &mut messages)?;
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use crate::testing::{app, call, call_as};
+
+    #[tokio::test]
+    async fn messages_match_schema() {
+        let app = app();
+        let (status, _) = call(&app, "POST", "/send", "/send",
+            Some(json!({ "email": "a@example.com", "message": "hi" }))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, list) = call(&app, "GET", "/messages", "/messages", None).await;
+        assert_eq!(list.as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn message_errors_match_schema() {
+        let app = app();
+        let ann = create_token(&secret_from_env(), "ann");
+        let eve = create_token(&secret_from_env(), "eve");
+        let modr = create_token_with_groups(&secret_from_env(), "mo", vec!["moderators".into()]);
+        call_as(&app, Some(&modr), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "staff", "kind": "private", "groups": ["moderators"] }))).await;
+        call_as(&app, Some(&ann), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "news", "kind": "announcement" }))).await;
+
+        let post = json!({ "email": "ann", "message": "hi", "room": "news" });
+        let (status, _) = call(&app, "POST", "/send", "/send", Some(post)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let post = json!({ "email": "ann", "message": "hi", "room": "lobby" });
+        let (status, body) = call_as(&app, Some(&eve), "POST", "/send", "/send", Some(post)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["message"], "email does not match the token");
+
+        for (uri, route) in [
+            ("/messages?room=staff", "/messages"),
+            ("/rooms/staff/messages", "/rooms/{id}/messages"),
+            ("/rooms/staff", "/rooms/{id}"),
+        ] {
+            let (status, _) = call(&app, "GET", uri, route, None).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", uri);
+            let (status, _) = call_as(&app, Some(&eve), "GET", uri, route, None).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{}", uri);
+            let (status, _) = call_as(&app, Some(&modr), "GET", uri, route, None).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+        }
+
+        let (status, _) = call(&app, "POST", "/rooms", "/rooms", Some(json!({ "id": "x" }))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/rooms", "/rooms", Some(json!({ "id": "Not A Slug" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 }
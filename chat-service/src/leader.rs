@@ -0,0 +1,60 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+
+//
+// BACKGROUND JOB LEASES
+//
+// Several chat-service instances may share one database. Background jobs
+// that must run exactly once (outbox webhooks, sweepers) take a named lease
+// first: a row in `leases` with a holder and an expiry, claimed with an
+// atomic upsert the same way a `SET key NX PX ttl` lock would be. The
+// holder renews on every pass; if it dies the lease lapses after `ttl` and
+// another instance takes over.
+//
+
+/// Stable id for this process (`CHAT_INSTANCE_ID`, else a random uuid).
+pub fn instance_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        std::env::var("CHAT_INSTANCE_ID").unwrap_or_else(|_| uuid::Uuid::new_v4().to_string())
+    })
+}
+
+pub struct Lease {
+    name: &'static str,
+    ttl: Duration,
+    held: bool,
+}
+
+impl Lease {
+    pub fn new(name: &'static str, ttl: Duration) -> Self {
+        Self { name, ttl, held: false }
+    }
+
+    /// Takes the lease if it is free or expired, or renews it if we already
+    /// hold it. Returns whether this instance is now the holder.
+    pub fn try_acquire(&mut self, conn: &Connection) -> bool {
+        let now = Utc::now().timestamp_millis();
+        let expires = now + self.ttl.as_millis() as i64;
+
+        let changed = conn
+            .execute(
+                "INSERT INTO leases (name, holder, expires_at) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(name) DO UPDATE SET holder = ?2, expires_at = ?3
+                 WHERE leases.holder = ?2 OR leases.expires_at < ?4",
+                params![self.name, instance_id(), expires, now],
+            )
+            .unwrap_or(0);
+
+        let held = changed == 1;
+        if held != self.held {
+            let verb = if held { "acquired" } else { "lost" };
+            println!("chat-service: {} lease {} by {}", verb, self.name, instance_id());
+        }
+        self.held = held;
+        held
+    }
+}
@@ -1,52 +1,352 @@
+mod analytics;
+mod archive;
+mod audit;
+mod auth;
+mod db;
+mod edits;
+mod emoji;
+mod freezes;
+mod handlers;
+mod leader;
+mod notify;
+mod openapi;
+mod outbox;
+mod payloads;
+mod polls;
+mod privacy;
+mod profanity;
+mod ratelimit;
+mod reactions;
+mod receipts;
+mod revocations;
+mod rooms;
+mod telemetry;
+mod templates;
+#[cfg(test)]
+mod testing;
+mod unfurl;
+mod v2;
+mod versions;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use tokio::net::TcpListener;
-use tokio::sync::broadcast;
-use tokio_tungstenite::accept_async;
+use tokio::sync::{broadcast, Notify};
+use tokio_tungstenite::{accept_hdr_async, WebSocketStream};
 
 use futures_util::stream::StreamExt;
 use futures_util::SinkExt;
 
-use tungstenite::protocol::Message;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Message};
+
+use axum::{
+    extract::State,
+    middleware,
+    routing::{get, patch, post, put},
+    Router,
+};
 
+use uchat_core::i18n::codes;
+use uchat_core::jobs::{Job, Scheduler};
+use uchat_core::storage::{LocalStorage, Storage};
+use uchat_proto::acl::RoomAcl;
 use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::jwt::secret_from_env;
 
-use serde_json;
 use anyhow::Result;
 
+#[derive(Clone)]
+pub struct AppState {
+    pub tx: broadcast::Sender<ServerEvent>,
+    pub secret: String,
+    pub acl: Arc<RoomAcl>,
+    /// Comma-separated `CHAT_ADMINS`; may act on other users' data.
+    pub admins: Arc<Vec<String>>,
+    /// `CHAT_AUDITOR_GROUP` (default "auditors"); may read receipts.
+    pub auditor_group: Arc<String>,
+    pub db: Arc<Mutex<rusqlite::Connection>>,
+    pub outbox_notify: Arc<Notify>,
+    pub unfurl: Arc<unfurl::UnfurlPolicy>,
+    pub unfurl_notify: Arc<Notify>,
+    pub jobs: Arc<Scheduler>,
+    /// Custom emoji images.
+    pub storage: Arc<dyn Storage>,
+    /// `CHAT_EMOJI_MAX_BYTES`, largest accepted emoji image.
+    pub emoji_max_bytes: usize,
+    /// `CHAT_WS_MAX_MESSAGE_BYTES` (default 65536), largest frame accepted on
+    /// the chat socket; a bigger one is refused and the socket closed (1009).
+    pub ws_max_message_bytes: usize,
+    /// Chat socket frames refused for their size.
+    pub ws_oversized: Arc<AtomicU64>,
+    pub rate_limits: Arc<ratelimit::RateLimits>,
+    pub telemetry: Arc<telemetry::Ingest>,
+    pub profanity: Arc<profanity::Masker>,
+    pub payloads: Arc<payloads::PayloadStore>,
+    pub notifier: Arc<notify::Notifier>,
+    pub versions: Arc<versions::Versions>,
+    pub audit_retention: Arc<audit::Retention>,
+    pub archiver: Arc<archive::Archiver>,
+    pub analytics: Arc<analytics::Analytics>,
+    pub revocations: Arc<revocations::Revocations>,
+}
+
+impl AppState {
+    pub fn new(db: rusqlite::Connection) -> Self {
+        Self {
+            tx: broadcast::channel::<ServerEvent>(1024).0,
+            secret: secret_from_env(),
+            acl: Arc::new(RoomAcl::from_env()),
+            admins: Arc::new(
+                std::env::var("CHAT_ADMINS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            auditor_group: Arc::new(std::env::var("CHAT_AUDITOR_GROUP").unwrap_or_else(|_| "auditors".into())),
+            db: Arc::new(Mutex::new(db)),
+            outbox_notify: Arc::new(Notify::new()),
+            unfurl: Arc::new(unfurl::UnfurlPolicy::from_env()),
+            unfurl_notify: Arc::new(Notify::new()),
+            jobs: Arc::new(Scheduler::default()),
+            storage: Arc::new(LocalStorage::from_env()),
+            emoji_max_bytes: std::env::var("CHAT_EMOJI_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(256 * 1024),
+            ws_max_message_bytes: std::env::var("CHAT_WS_MAX_MESSAGE_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64 * 1024),
+            ws_oversized: Arc::new(AtomicU64::new(0)),
+            rate_limits: Arc::new(ratelimit::RateLimits::from_env()),
+            telemetry: Arc::new(telemetry::Ingest::from_env()),
+            profanity: Arc::new(profanity::Masker::from_env()),
+            payloads: Arc::new(payloads::PayloadStore::from_env()),
+            notifier: Arc::new(notify::Notifier::from_env()),
+            versions: Arc::new(versions::Versions::from_env()),
+            audit_retention: Arc::new(audit::Retention::from_env()),
+            archiver: Arc::new(archive::Archiver::from_env()),
+            analytics: Arc::new(analytics::Analytics::from_env()),
+            revocations: Arc::new(revocations::Revocations::from_env()),
+        }
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    // each version maps its own shapes for these; see versions.rs
+    let send_limit = || middleware::from_fn_with_state(state.clone(), ratelimit::send);
+    let history_limit = || middleware::from_fn_with_state(state.clone(), ratelimit::history);
+    let v1 = Router::new()
+        .route("/send", post(handlers::send_message).route_layer(send_limit()))
+        .route("/messages", get(handlers::get_messages).route_layer(history_limit()))
+        .merge(shared_routes())
+        .layer(middleware::from_fn_with_state(state.clone(), versions::v1));
+    let v2 = Router::new()
+        .route("/send", post(v2::send_message).route_layer(send_limit()))
+        .route("/messages", get(v2::get_messages).route_layer(history_limit()))
+        .merge(shared_routes())
+        .layer(middleware::from_fn_with_state(state.clone(), versions::v2));
+
+    let app = Router::new()
+        .merge(v1.clone())
+        .nest("/v1", v1)
+        .nest("/v2", v2)
+        .route("/openapi.json", get(openapi::spec))
+        .route("/metrics", get(metrics))
+        .with_state(state);
+
+    // interactive docs for local development only
+    #[cfg(debug_assertions)]
+    let app = app.merge(
+        utoipa_swagger_ui::SwaggerUi::new("/docs").url("/docs/openapi.json", openapi::ApiDoc::openapi_doc()),
+    );
+
+    app
+}
+
+/// Routes whose shapes are the same in every API version.
+fn shared_routes() -> Router<AppState> {
+    Router::new()
+        .route("/messages/:room/:id", patch(edits::edit))
+        .route("/messages/:room/:id/receipts", get(receipts::list))
+        .route("/messages/:room/:id/reactions", post(reactions::add))
+        .route("/emoji", get(emoji::list))
+        .route("/emoji/:name", put(emoji::put_instance).delete(emoji::delete_instance))
+        .route("/receipts", post(receipts::record))
+        .route("/rooms", post(rooms::create_room))
+        .route("/rooms/:id", get(rooms::get_room))
+        .route("/rooms/:id/messages", get(rooms::room_history))
+        .route("/rooms/:id/clone", post(rooms::clone_room))
+        .route("/rooms/:id/freeze", get(freezes::get_freeze).put(freezes::freeze).delete(freezes::thaw))
+        .route("/rooms/:id/archive", get(archive::get_archive).put(archive::flag).delete(archive::unflag))
+        .route("/archive", get(archive::status))
+        .route("/room-templates", get(templates::list))
+        .route("/room-templates/:name", put(templates::put).delete(templates::delete))
+        .route("/room-templates/:name/rooms", post(templates::create_room))
+        .route("/rooms/:id/profanity", get(profanity::get_setting).put(profanity::put_setting))
+        .route("/rooms/:id/emoji/:name", put(emoji::put_room).delete(emoji::delete_room))
+        .route("/polls", post(polls::create_poll))
+        .route("/polls/:id", get(polls::get_results))
+        .route("/polls/:id/vote", post(polls::vote))
+        .route("/privacy/erase/:user_id", post(privacy::erase))
+        .route("/privacy/export/:user_id", get(privacy::export))
+        .route("/audit/retention", post(audit::run_retention))
+        .route("/analytics", get(analytics::status))
+        .route("/analytics/metrics", post(analytics::metrics))
+        .route("/analytics/query", post(analytics::query))
+        .route("/notifications/preferences", get(notify::get_prefs).put(notify::put_prefs))
+        .route("/telemetry", post(telemetry::ingest))
+        .route("/telemetry/:device", get(telemetry::stream))
+}
+
+async fn metrics(State(state): State<AppState>) -> String {
+    let mut out = state.rate_limits.render_metrics();
+    out.push_str(&state.telemetry.render_metrics());
+    out.push_str(&state.payloads.render_metrics());
+    out.push_str(&state.notifier.render_metrics());
+    out.push_str(&state.versions.render_metrics());
+    out.push_str(&state.audit_retention.render_metrics());
+    out.push_str(&state.archiver.render_metrics());
+    out.push_str(&state.analytics.render_metrics());
+    out.push_str(&state.revocations.render_metrics());
+    out.push_str(&format!(
+        "# TYPE chat_ws_messages_oversized_total counter\nchat_ws_messages_oversized_total {}\n",
+        state.ws_oversized.load(Ordering::Relaxed)
+    ));
+    out.push_str(&state.jobs.render_metrics("chat"));
+    out
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let listener = TcpListener::bind("0.0.0.0:9300").await.unwrap();
 
-    let (tx, _rx) = broadcast::channel::<String>(1024);
+    let state = AppState::new(db::open()?);
+    let tx = state.tx.clone();
+
+    state.jobs.spawn(Job::every("poll-expiry", Duration::from_secs(1)), {
+        let state = state.clone();
+        move || polls::expire_polls(state.clone())
+    });
+    state.jobs.spawn(Job::every("rate-limit-evict", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.rate_limits.evict();
+                state.telemetry.evict();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("payload-gc", Duration::from_secs(300)), {
+        let state = state.clone();
+        move || payloads::collect_garbage(state.clone())
+    });
+    state.jobs.spawn(Job::every("audit-retention", Duration::from_secs(3600)), {
+        let state = state.clone();
+        move || audit::retention_job(state.clone())
+    });
+    state.jobs.spawn(Job::every("analytics-rollup", state.analytics.interval), {
+        let state = state.clone();
+        move || analytics::rollup_job(state.clone())
+    });
+    state.jobs.spawn(Job::every("revocation-sync", state.revocations.interval), {
+        let state = state.clone();
+        move || revocations::sync_job(state.clone())
+    });
+    state.jobs.spawn(Job::every("outbox-prune", Duration::from_secs(300)), {
+        let state = state.clone();
+        let retention = outbox::retention_from_env();
+        move || outbox::prune_job(state.clone(), retention)
+    });
+    tokio::spawn(outbox::relay_loop(state.clone()));
+    tokio::spawn(unfurl::worker_loop(state.clone()));
+    tokio::spawn(telemetry::writer_loop(state.clone()));
+    tokio::spawn(notify::worker_loop(state.clone()));
+    tokio::spawn(archive::shipper_loop(state.clone()));
+
+    let app = router(state.clone());
+
+    let http_listener = TcpListener::bind("0.0.0.0:9301").await?;
+    println!("chat-service HTTP API on http://0.0.0.0:9301");
+    tokio::spawn(async move {
+        // peer addresses feed the per-IP rate limits
+        axum::serve(http_listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
 
     println!("chat-service running on ws://0.0.0.0:9300/ws");
 
     loop {
         let (stream, _) = listener.accept().await.unwrap();
+        let state = state.clone();
         let tx = tx.clone();
         let mut rx = tx.subscribe();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_chat(stream, tx, &mut rx).await {
+            if let Err(e) = accept_ws(stream, state, tx, &mut rx).await {
                 eprintln!("chat-service error: {:?}", e);
             }
         });
     }
 }
 
-async fn handle_chat(
+/// /telemetry is for devices (see telemetry.rs); any other path is chat.
+// the handshake callback's error type is fixed by tungstenite
+#[allow(clippy::result_large_err)]
+async fn accept_ws(
     stream: tokio::net::TcpStream,
-    tx: broadcast::Sender<String>,
-    rx: &mut broadcast::Receiver<String>,
+    state: AppState,
+    tx: broadcast::Sender<ServerEvent>,
+    rx: &mut broadcast::Receiver<ServerEvent>,
+) -> Result<()> {
+    let mut device = None;
+    let ws = accept_hdr_async(stream, |req: &Request, resp: Response| {
+        if req.uri().path() != "/telemetry" {
+            return Ok(resp);
+        }
+        device = telemetry::ws_device(&state, req.headers());
+        if device.is_some() {
+            return Ok(resp);
+        }
+        let mut refused = ErrorResponse::new(Some("unknown device token".into()));
+        *refused.status_mut() = tungstenite::http::StatusCode::UNAUTHORIZED;
+        Err(refused)
+    })
+    .await?;
+
+    match device {
+        Some(device) => {
+            telemetry::handle_ws(ws, state, device).await;
+            Ok(())
+        }
+        None => handle_chat(ws, state, tx, rx).await,
+    }
+}
+
+async fn handle_chat(
+    ws_stream: WebSocketStream<tokio::net::TcpStream>,
+    state: AppState,
+    tx: broadcast::Sender<ServerEvent>,
+    rx: &mut broadcast::Receiver<ServerEvent>,
 ) -> Result<()> {
-    let ws_stream = accept_async(stream).await?;
     let (ws_write, mut ws_read) = ws_stream.split();
 
     let (msg_tx, mut msg_rx) = tokio::sync::mpsc::unbounded_channel::<Message>();
 
     let mut writer = ws_write;
-    let writer_task = tokio::spawn(async move {
+    let mut writer_task = tokio::spawn(async move {
         while let Some(msg) = msg_rx.recv().await {
-            if writer.send(msg).await.is_err() {
+            let closing = matches!(msg, Message::Close(_));
+            if writer.send(msg).await.is_err() || closing {
                 break;
             }
         }
@@ -55,25 +355,46 @@ async fn handle_chat(
     let msg_tx_clone = msg_tx.clone();
     let mut rx2 = rx.resubscribe();
     let broadcast_task = tokio::spawn(async move {
-        while let Ok(content) = rx2.recv().await {
-            let evt = ServerEvent::MessageBroadcast {
-                from: "chat-service".into(),
-                content,
-            };
+        while let Ok(evt) = rx2.recv().await {
             let _ = msg_tx_clone.send(Message::Text(serde_json::to_string(&evt).unwrap()));
         }
     });
 
     while let Some(msg) = ws_read.next().await {
+        // anything bigger would be broadcast to every socket
+        let size = match &msg {
+            Ok(Message::Text(text)) => text.len(),
+            Ok(Message::Binary(data)) => data.len(),
+            _ => 0,
+        };
+        if size > state.ws_max_message_bytes {
+            state.ws_oversized.fetch_add(1, Ordering::Relaxed);
+            let err = ServerEvent::Error {
+                details: format!("messages are limited to {} bytes", state.ws_max_message_bytes),
+                code: Some(codes::MESSAGE_TOO_LARGE.into()),
+            };
+            let _ = msg_tx.send(Message::Text(serde_json::to_string(&err).unwrap()));
+            let close = CloseFrame { code: CloseCode::Size, reason: "message too big".into() };
+            let _ = msg_tx.send(Message::Close(Some(close)));
+            // let the writer flush the error and the close frame
+            let _ = tokio::time::timeout(Duration::from_secs(1), &mut writer_task).await;
+            break;
+        }
         if let Ok(Message::Text(text)) = msg {
             match serde_json::from_str::<ClientEvent>(&text) {
-                Ok(ClientEvent::SendMessage { content }) => {
-                    let _ = tx.send(content);
+                Ok(ClientEvent::SendMessage { content, .. }) => {
+                    // socket sends go to the lobby
+                    let content = state.profanity.for_room(&state.db.lock().unwrap(), "lobby", &content).into_owned();
+                    let _ = tx.send(ServerEvent::MessageBroadcast {
+                        from: "chat-service".into(),
+                        content,
+                    });
                 }
                 Ok(_) => {}
                 Err(_) => {
                     let err = ServerEvent::Error {
                         details: "Invalid event".into(),
+                        code: None,
                     };
                     let _ = msg_tx.send(Message::Text(serde_json::to_string(&err).unwrap()));
                 }
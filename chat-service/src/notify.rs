@@ -0,0 +1,808 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::{extract::State, http::{HeaderMap, StatusCode}, Json};
+use chrono::{TimeZone, Utc};
+use regex::Regex;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use utoipa::ToSchema;
+
+use uchat_proto::acl::{RoomAcl, RoomKind};
+use uchat_proto::errors::ApiError;
+use uchat_proto::jwt::create_token;
+
+use crate::audit;
+use crate::auth::{api_error, bearer_claims, ApiFailure};
+use crate::leader::Lease;
+use crate::rooms;
+use crate::AppState;
+
+//
+// NOTIFICATIONS FOR OFFLINE USERS
+//
+// A message that mentions someone (`@name` in the body) or is sent in a
+// direct-message room (named "dm:<user>,<user>") queues one
+// `notifications` row per recipient, in the same transaction as the
+// message; the sender is never notified. Only participants read or post in
+// a direct-message room (see auth.rs). A mention is only queued for a user
+// who can read the room: anyone in public and announcement rooms, the
+// participants of a direct-message room. Who belongs to a private room's
+// groups is only known from their tokens, so mentions there are not
+// notified. A background worker (holding the
+// `notifier` lease) collects each user's rows into a digest once the
+// oldest has waited the user's digest window, then asks the gateway's
+// connection census whether the user is connected anywhere:
+//
+// - connected: the rows are marked skipped, the user sees the messages live;
+// - not connected (or the census cannot be reached): the digest goes out on
+//   each of the user's channels, and the rows are marked sent once any
+//   channel took it. After MAX_ATTEMPTS failed passes they are marked failed.
+//
+// Every digest sent or given up on is audit-logged.
+//
+// Channels are pluggable (the `Channel` trait); a channel is available when
+// it is configured:
+//
+//   webhook  POSTs the digest as JSON        CHAT_NOTIFY_WEBHOOK_URL
+//   email    plain SMTP to a relay           CHAT_NOTIFY_SMTP_ADDR (host:port),
+//                                            CHAT_NOTIFY_SMTP_FROM (default uchat@localhost)
+//   apns     through a push relay speaking   CHAT_NOTIFY_PUSH_URL
+//   fcm      gorush's API (APNs needs HTTP/2 and both need provider
+//            credentials, which the relay holds)
+//
+//   CHAT_GATEWAY_URL              gateway HTTP API, for the census
+//                                 (default http://127.0.0.1:7000)
+//   CHAT_NOTIFY_ACCOUNT           subject of the tokens the census is queried
+//                                 with; must be in the gateway's
+//                                 GATEWAY_SERVICE_ACCOUNTS (default chat-service)
+//   CHAT_NOTIFY_DIGEST_SECS       default digest window (default 300; 0 sends
+//                                 at once)
+//   CHAT_NOTIFY_DEFAULT_CHANNELS  channels of users without preferences,
+//                                 addressed by username (default "webhook")
+//
+// Users set their own preferences with GET/PUT /notifications/preferences.
+//
+//   chat_notifications_queued_total         rows queued
+//   chat_notification_digests_sent_total    digests sent
+//   chat_notifications_skipped_total        rows skipped, recipient online
+//   chat_notification_failures_total        channel sends that failed
+//
+
+const MAX_ATTEMPTS: i64 = 5;
+const MAX_MENTIONS: usize = 20;
+const MAX_DIGEST_ITEMS: usize = 50;
+const EXCERPT_CHARS: usize = 140;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Room id prefix of direct-message rooms.
+pub const DM_PREFIX: &str = "dm:";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq)]
+pub struct NotificationPrefs {
+    /// False turns notifications off altogether.
+    #[serde(default = "yes")]
+    pub enabled: bool,
+    #[serde(default = "yes")]
+    pub mentions: bool,
+    /// Direct-message rooms.
+    #[serde(default = "yes")]
+    pub direct: bool,
+    /// Seconds notifications are collected into one digest; unset uses the
+    /// server default.
+    #[serde(default)]
+    pub digest_secs: Option<u64>,
+    /// Channel name to address: an email address for `email`, a device
+    /// token for `apns` and `fcm`; `webhook` passes it on as is.
+    #[serde(default)]
+    pub channels: BTreeMap<String, String>,
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestItem {
+    pub id: i64,
+    /// "mention" or "direct".
+    pub kind: String,
+    pub room: String,
+    pub message_id: i64,
+    pub from: String,
+    pub excerpt: String,
+    pub ts: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Digest {
+    pub user: String,
+    pub items: Vec<DigestItem>,
+}
+
+impl Digest {
+    /// One line, whatever the sender's name holds (it ends up in a mail header).
+    fn title(&self) -> String {
+        match self.items.len() {
+            1 => format!("New message from {}", self.items[0].from.replace(char::is_control, " ")),
+            n => format!("{} new messages", n),
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut out = String::new();
+        for item in &self.items {
+            let verb = if item.kind == "direct" { "wrote to you" } else { "mentioned you" };
+            let from = item.from.replace(char::is_control, " ");
+            let _ = writeln!(out, "{} {} in {}: {}", from, verb, item.room, item.excerpt);
+        }
+        out
+    }
+
+    /// Idempotency key for receivers: the same rows give the same key.
+    fn key(&self) -> String {
+        let ids: Vec<String> = self.items.iter().map(|i| i.id.to_string()).collect();
+        format!("{}:{}", self.user, ids.join(","))
+    }
+}
+
+#[async_trait]
+pub trait Channel: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether `address` is usable on this channel.
+    fn accepts(&self, _address: &str) -> bool {
+        true
+    }
+
+    async fn send(&self, address: &str, digest: &Digest) -> Result<(), String>;
+}
+
+pub struct WebhookChannel {
+    url: String,
+    http: reqwest::Client,
+}
+
+#[async_trait]
+impl Channel for WebhookChannel {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn send(&self, address: &str, digest: &Digest) -> Result<(), String> {
+        let resp = self
+            .http
+            .post(&self.url)
+            .timeout(SEND_TIMEOUT)
+            .header("Idempotency-Key", digest.key())
+            .json(&json!({ "to": address, "digest": digest }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(resp.status().to_string()),
+        }
+    }
+}
+
+/// Plain SMTP to a relay on the local network: no TLS or AUTH, the relay
+/// is trusted to carry the mail on.
+pub struct SmtpChannel {
+    addr: String,
+    from: String,
+}
+
+impl SmtpChannel {
+    async fn session(&self, to: &str, message: &str) -> Result<(), String> {
+        let stream = TcpStream::connect(&self.addr).await.map_err(|e| e.to_string())?;
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+
+        // a reply is its last line, "NNN text"; earlier lines are "NNN-text"
+        async fn reply<R: AsyncBufReadExt + Unpin>(read: &mut R, expect: &[&str]) -> Result<(), String> {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if read.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+                    return Err("relay closed the connection".into());
+                }
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    break;
+                }
+            }
+            match expect.iter().any(|code| line.starts_with(code)) {
+                true => Ok(()),
+                false => Err(format!("relay replied {}", line.trim_end())),
+            }
+        }
+
+        reply(&mut read, &["220"]).await?;
+        for (command, expect) in [
+            ("EHLO uchat".to_string(), &["250"][..]),
+            (format!("MAIL FROM:<{}>", self.from), &["250"]),
+            (format!("RCPT TO:<{}>", to), &["250", "251"]),
+            ("DATA".to_string(), &["354"]),
+        ] {
+            write.write_all(format!("{}\r\n", command).as_bytes()).await.map_err(|e| e.to_string())?;
+            reply(&mut read, expect).await?;
+        }
+        write.write_all(message.as_bytes()).await.map_err(|e| e.to_string())?;
+        write.write_all(b"\r\n.\r\n").await.map_err(|e| e.to_string())?;
+        reply(&mut read, &["250"]).await?;
+        let _ = write.write_all(b"QUIT\r\n").await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Channel for SmtpChannel {
+    fn name(&self) -> &'static str {
+        "email"
+    }
+
+    fn accepts(&self, address: &str) -> bool {
+        address.contains('@') && !address.chars().any(|c| c.is_control() || matches!(c, '<' | '>' | ' '))
+    }
+
+    async fn send(&self, address: &str, digest: &Digest) -> Result<(), String> {
+        let mut message = format!(
+            "From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            self.from,
+            address,
+            digest.title(),
+            Utc::now().to_rfc2822()
+        );
+        // dot-stuffing, so no body line ends the DATA section
+        for line in digest.text().lines() {
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        let message = message.trim_end_matches("\r\n");
+        tokio::time::timeout(SEND_TIMEOUT, self.session(address, message))
+            .await
+            .map_err(|_| "relay timed out".to_string())?
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Platform {
+    Apns,
+    Fcm,
+}
+
+/// APNs and FCM through a push relay with gorush's API.
+pub struct PushChannel {
+    platform: Platform,
+    url: String,
+    http: reqwest::Client,
+}
+
+#[async_trait]
+impl Channel for PushChannel {
+    fn name(&self) -> &'static str {
+        match self.platform {
+            Platform::Apns => "apns",
+            Platform::Fcm => "fcm",
+        }
+    }
+
+    async fn send(&self, address: &str, digest: &Digest) -> Result<(), String> {
+        let platform = match self.platform {
+            Platform::Apns => 1,
+            Platform::Fcm => 2,
+        };
+        let notification = json!({
+            "tokens": [address],
+            "platform": platform,
+            "title": digest.title(),
+            "message": digest.items.last().map(|i| i.excerpt.as_str()).unwrap_or_default(),
+            "badge": digest.items.len(),
+        });
+        let resp = self
+            .http
+            .post(&self.url)
+            .timeout(SEND_TIMEOUT)
+            .json(&json!({ "notifications": [notification] }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(resp.status().to_string()),
+        }
+    }
+}
+
+pub struct Notifier {
+    channels: Vec<Box<dyn Channel>>,
+    default_channels: Vec<String>,
+    digest_secs: u64,
+    gateway_url: String,
+    account: String,
+    http: reqwest::Client,
+    pub wake: Notify,
+    queued: AtomicU64,
+    sent: AtomicU64,
+    skipped: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let http = reqwest::Client::new();
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let mut channels: Vec<Box<dyn Channel>> = Vec::new();
+        if let Some(url) = var("CHAT_NOTIFY_WEBHOOK_URL") {
+            channels.push(Box::new(WebhookChannel { url, http: http.clone() }));
+        }
+        if let Some(addr) = var("CHAT_NOTIFY_SMTP_ADDR") {
+            let from = var("CHAT_NOTIFY_SMTP_FROM").unwrap_or_else(|| "uchat@localhost".into());
+            channels.push(Box::new(SmtpChannel { addr, from }));
+        }
+        if let Some(base) = var("CHAT_NOTIFY_PUSH_URL") {
+            let url = format!("{}/api/push", base.trim_end_matches('/'));
+            for platform in [Platform::Apns, Platform::Fcm] {
+                channels.push(Box::new(PushChannel { platform, url: url.clone(), http: http.clone() }));
+            }
+        }
+        let mut notifier = Self::new(channels);
+        notifier.default_channels = std::env::var("CHAT_NOTIFY_DEFAULT_CHANNELS")
+            .unwrap_or_else(|_| "webhook".into())
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(str::to_string)
+            .collect();
+        notifier.digest_secs = var("CHAT_NOTIFY_DIGEST_SECS").and_then(|v| v.parse().ok()).unwrap_or(300);
+        notifier.gateway_url = var("CHAT_GATEWAY_URL").unwrap_or(notifier.gateway_url);
+        notifier.account = var("CHAT_NOTIFY_ACCOUNT").unwrap_or(notifier.account);
+        notifier
+    }
+
+    pub fn new(channels: Vec<Box<dyn Channel>>) -> Self {
+        Self {
+            channels,
+            default_channels: vec!["webhook".into()],
+            digest_secs: 300,
+            gateway_url: "http://127.0.0.1:7000".into(),
+            account: "chat-service".into(),
+            http: reqwest::Client::new(),
+            wake: Notify::new(),
+            queued: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_census(mut self, gateway_url: &str, digest_secs: u64) -> Self {
+        self.gateway_url = gateway_url.trim_end_matches('/').to_string();
+        self.digest_secs = digest_secs;
+        self
+    }
+
+    fn channel(&self, name: &str) -> Option<&dyn Channel> {
+        self.channels.iter().find(|c| c.name() == name).map(|c| c.as_ref())
+    }
+
+    fn defaults(&self, user: &str) -> NotificationPrefs {
+        NotificationPrefs {
+            enabled: true,
+            mentions: true,
+            direct: true,
+            digest_secs: None,
+            channels: self.default_channels.iter().map(|c| (c.clone(), user.to_string())).collect(),
+        }
+    }
+
+    /// Whether the gateway census has `user` connected; None if it could not
+    /// be asked.
+    async fn online(&self, secret: &str, user: &str) -> Option<bool> {
+        let resp = self
+            .http
+            .get(format!("{}/census/users/{}", self.gateway_url, user))
+            .timeout(Duration::from_secs(5))
+            .bearer_auth(create_token(secret, &self.account))
+            .send()
+            .await;
+        match resp {
+            Ok(resp) if resp.status().is_success() => {
+                resp.json::<serde_json::Value>().await.ok()?.get("online")?.as_bool()
+            }
+            Ok(resp) => {
+                println!("chat-service: census refused lookup of {}: {}", user, resp.status());
+                None
+            }
+            Err(e) => {
+                println!("chat-service: census unreachable: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in [
+            ("chat_notifications_queued_total", &self.queued),
+            ("chat_notification_digests_sent_total", &self.sent),
+            ("chat_notifications_skipped_total", &self.skipped),
+            ("chat_notification_failures_total", &self.failures),
+        ] {
+            let _ = writeln!(out, "# TYPE {} counter\n{} {}", name, name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Users mentioned in `body`, in order, without repeats.
+fn mentions(body: &str) -> Vec<String> {
+    static MENTION: OnceLock<Regex> = OnceLock::new();
+    let re = MENTION.get_or_init(|| Regex::new(r"(?:^|[^\w@])@([\w.-]*\w)").unwrap());
+    let mut out: Vec<String> = Vec::new();
+    for name in re.captures_iter(body).map(|c| c[1].to_string()) {
+        if !out.contains(&name) {
+            out.push(name);
+        }
+        if out.len() == MAX_MENTIONS {
+            break;
+        }
+    }
+    out
+}
+
+/// The participants of a direct-message room, if `room` is one.
+pub fn dm_participants(room: &str) -> Option<Vec<&str>> {
+    let names = room.strip_prefix(DM_PREFIX)?;
+    Some(names.split(',').map(str::trim).filter(|n| !n.is_empty()).collect())
+}
+
+pub fn prefs(notifier: &Notifier, conn: &Connection, user: &str) -> NotificationPrefs {
+    let stored: Option<String> = conn
+        .query_row("SELECT prefs FROM notification_prefs WHERE username = ?1", [user], |r| r.get(0))
+        .optional()
+        .unwrap();
+    stored.and_then(|json| serde_json::from_str(&json).ok()).unwrap_or_else(|| notifier.defaults(user))
+}
+
+/// Queues notifications for a new message; call with the caller's open
+/// transaction. Returns how many were queued.
+pub fn enqueue(
+    conn: &Connection,
+    notifier: &Notifier,
+    acl: &RoomAcl,
+    room: &str,
+    from: &str,
+    message_id: i64,
+    body: &str,
+) -> rusqlite::Result<usize> {
+    let mut recipients: Vec<(String, &str)> = Vec::new();
+    let participants = dm_participants(room);
+    if let Some(participants) = &participants {
+        recipients.extend(participants.iter().map(|p| (p.to_string(), "direct")));
+    }
+    // a DM's readers are its participants, queued above
+    if participants.is_none() && rooms::info(conn, acl, room).kind != RoomKind::Private {
+        for user in mentions(body) {
+            if !recipients.iter().any(|(r, _)| *r == user) {
+                recipients.push((user, "mention"));
+            }
+        }
+    }
+
+    let excerpt: String = body.chars().take(EXCERPT_CHARS).map(|c| if c.is_control() { ' ' } else { c }).collect();
+    let now = Utc::now().timestamp();
+    let mut queued = 0;
+    for (user, kind) in recipients.iter().filter(|(user, _)| user != from) {
+        let prefs = prefs(notifier, conn, user);
+        let wanted = if *kind == "direct" { prefs.direct } else { prefs.mentions };
+        if !prefs.enabled || !wanted {
+            continue;
+        }
+        conn.execute(
+            "INSERT INTO notifications (username, kind, room, message_id, sender, excerpt, created_at, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'pending')",
+            params![user, kind, room, message_id, from, excerpt, now],
+        )?;
+        queued += 1;
+    }
+    notifier.queued.fetch_add(queued as u64, Ordering::Relaxed);
+    Ok(queued)
+}
+
+/// Users with pending rows whose digest window has passed.
+fn due(notifier: &Notifier, conn: &Connection) -> Vec<(String, NotificationPrefs)> {
+    let mut stmt = conn
+        .prepare("SELECT username, MIN(created_at) FROM notifications WHERE status = 'pending' GROUP BY username")
+        .unwrap();
+    let oldest: Vec<(String, i64)> =
+        stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().filter_map(Result::ok).collect();
+    let now = Utc::now().timestamp();
+    oldest
+        .into_iter()
+        .filter_map(|(user, oldest)| {
+            let prefs = prefs(notifier, conn, &user);
+            let window = prefs.digest_secs.unwrap_or(notifier.digest_secs) as i64;
+            (oldest + window <= now).then_some((user, prefs))
+        })
+        .collect()
+}
+
+fn pending_items(conn: &Connection, user: &str) -> Vec<(DigestItem, i64)> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, kind, room, message_id, sender, excerpt, created_at, attempts FROM notifications
+             WHERE username = ?1 AND status = 'pending' ORDER BY id ASC LIMIT ?2",
+        )
+        .unwrap();
+    stmt.query_map(params![user, MAX_DIGEST_ITEMS as i64], |r| {
+        let ts: i64 = r.get(6)?;
+        let item = DigestItem {
+            id: r.get(0)?,
+            kind: r.get(1)?,
+            room: r.get(2)?,
+            message_id: r.get(3)?,
+            from: r.get(4)?,
+            excerpt: r.get(5)?,
+            ts: Utc.timestamp_opt(ts, 0).single().unwrap_or_default().to_rfc3339(),
+        };
+        Ok((item, r.get(7)?))
+    })
+    .unwrap()
+    .filter_map(Result::ok)
+    .collect()
+}
+
+fn mark(conn: &Connection, ids: &[i64], status: &str) {
+    for id in ids {
+        conn.execute(
+            "UPDATE notifications SET status = ?1, attempts = attempts + 1, sent_at = ?2 WHERE id = ?3",
+            params![status, Utc::now().to_rfc3339(), id],
+        )
+        .unwrap();
+    }
+}
+
+/// One pass over the users whose digests are due.
+pub async fn deliver_due(state: &AppState) {
+    let notifier = &state.notifier;
+    let users = due(notifier, &state.db.lock().unwrap());
+    for (user, prefs) in users {
+        let items = pending_items(&state.db.lock().unwrap(), &user);
+        let ids: Vec<i64> = items.iter().map(|(item, _)| item.id).collect();
+        let attempts = items.iter().map(|(_, a)| *a).max().unwrap_or_default();
+
+        if notifier.online(&state.secret, &user).await == Some(true) {
+            mark(&state.db.lock().unwrap(), &ids, "skipped");
+            notifier.skipped.fetch_add(ids.len() as u64, Ordering::Relaxed);
+            continue;
+        }
+
+        let digest = Digest { user: user.clone(), items: items.into_iter().map(|(item, _)| item).collect() };
+        let mut delivered = Vec::new();
+        for (name, address) in &prefs.channels {
+            let Some(channel) = notifier.channel(name).filter(|c| c.accepts(address)) else {
+                println!("chat-service: notification channel {} unavailable for {}", name, user);
+                continue;
+            };
+            match channel.send(address, &digest).await {
+                Ok(()) => delivered.push(name.as_str()),
+                Err(e) => {
+                    notifier.failures.fetch_add(1, Ordering::Relaxed);
+                    println!("chat-service: notifying {} by {} failed: {}", user, name, e);
+                }
+            }
+        }
+
+        let db = state.db.lock().unwrap();
+        if !delivered.is_empty() {
+            mark(&db, &ids, "sent");
+            notifier.sent.fetch_add(1, Ordering::Relaxed);
+            let detail = format!("items={} channels={}", ids.len(), delivered.join(","));
+            audit::record(&db, "notification.sent", "notifier", &user, detail);
+        } else if attempts + 1 >= MAX_ATTEMPTS {
+            mark(&db, &ids, "failed");
+            audit::record(&db, "notification.failed", "notifier", &user, format!("items={}", ids.len()));
+        } else {
+            for id in &ids {
+                db.execute("UPDATE notifications SET attempts = attempts + 1 WHERE id = ?1", [id]).unwrap();
+            }
+        }
+    }
+}
+
+/// Sends due digests, woken by `notifier.wake` or a five-second tick. Only
+/// the instance holding the `notifier` lease sends.
+pub async fn worker_loop(state: AppState) {
+    let mut lease = Lease::new("notifier", Duration::from_secs(60));
+    loop {
+        tokio::select! {
+            _ = state.notifier.wake.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+        }
+        if lease.try_acquire(&state.db.lock().unwrap()) {
+            deliver_due(&state).await;
+        }
+    }
+}
+
+/// Drops `user`'s notifications and preferences, and rows naming them as
+/// sender; call in the erasure transaction.
+pub fn erase_user(conn: &Connection, user: &str) -> rusqlite::Result<usize> {
+    conn.execute("DELETE FROM notification_prefs WHERE username = ?1", [user])?;
+    conn.execute("DELETE FROM notifications WHERE username = ?1 OR sender = ?1", [user])
+}
+
+#[utoipa::path(get, path = "/notifications/preferences", tag = "notifications",
+    security(("bearer" = [])),
+    responses((status = 200, body = NotificationPrefs), (status = 401, body = ApiError)))]
+pub async fn get_prefs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<NotificationPrefs>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    Ok(Json(prefs(&state.notifier, &state.db.lock().unwrap(), &claims.sub)))
+}
+
+#[utoipa::path(put, path = "/notifications/preferences", tag = "notifications",
+    request_body = NotificationPrefs,
+    security(("bearer" = [])),
+    responses((status = 200, body = NotificationPrefs), (status = 400, body = ApiError),
+        (status = 401, body = ApiError)))]
+pub async fn put_prefs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<NotificationPrefs>,
+) -> Result<Json<NotificationPrefs>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    let mut unusable: HashMap<&str, &str> = HashMap::new();
+    for (name, address) in &body.channels {
+        match state.notifier.channel(name) {
+            None => unusable.insert(name, "is not configured on this server"),
+            Some(channel) if !channel.accepts(address) => unusable.insert(name, "cannot use that address"),
+            Some(_) => None,
+        };
+    }
+    if let Some((name, why)) = unusable.into_iter().min() {
+        return Err(api_error(StatusCode::BAD_REQUEST, &format!("channel {} {}", name, why)));
+    }
+
+    let db = state.db.lock().unwrap();
+    db.execute(
+        "INSERT INTO notification_prefs (username, prefs, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT(username) DO UPDATE SET prefs = ?2, updated_at = ?3",
+        params![claims.sub, serde_json::to_string(&body).unwrap(), Utc::now().to_rfc3339()],
+    )
+    .unwrap();
+    let channels: Vec<&str> = body.channels.keys().map(String::as_str).collect();
+    let detail = format!("enabled={} channels={}", body.enabled, channels.join(","));
+    audit::record(&db, "notification.preferences", &claims.sub, &claims.sub, detail);
+    Ok(Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use super::{deliver_due, Channel, Digest, Notifier};
+    use crate::testing::{call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn offline_users_get_notification_digests() {
+        // stands in for the gateway census: carol is connected, nobody else
+        let census = axum::Router::new().route(
+            "/census/users/:user",
+            axum::routing::get(|axum::extract::Path(user): axum::extract::Path<String>| async move {
+                axum::Json(json!({ "user": user, "online": user == "carol", "connections": [] }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let census_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, census).await });
+
+        struct Recorder(Arc<Mutex<Vec<(String, usize)>>>);
+        #[async_trait::async_trait]
+        impl Channel for Recorder {
+            fn name(&self) -> &'static str {
+                "webhook"
+            }
+            async fn send(&self, address: &str, digest: &Digest) -> Result<(), String> {
+                self.0.lock().unwrap().push((address.to_string(), digest.items.len()));
+                Ok(())
+            }
+        }
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        state.notifier = Arc::new(Notifier::new(vec![Box::new(Recorder(sent.clone()))]).with_census(&census_url, 0));
+        let app = router(state.clone());
+        let prefs = "/notifications/preferences";
+        let bob = create_token(&secret_from_env(), "bob");
+        let dave = create_token(&secret_from_env(), "dave");
+
+        let (_, defaults) = call_as(&app, Some(&bob), "GET", prefs, prefs, None).await;
+        assert_eq!(defaults["channels"], json!({ "webhook": "bob" }));
+        let (status, _) = call_as(&app, Some(&bob), "PUT", prefs, prefs, Some(json!({ "channels": { "pager": "1" } }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&app, "PUT", prefs, prefs, Some(json!({}))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, set) =
+            call_as(&app, Some(&bob), "PUT", prefs, prefs, Some(json!({ "channels": { "webhook": "bob-hook" } }))).await;
+        assert_eq!((status, set["enabled"].as_bool()), (StatusCode::OK, Some(true)));
+        call_as(&app, Some(&dave), "PUT", prefs, prefs, Some(json!({ "enabled": false }))).await;
+
+        let ann = create_token_with_groups(&secret_from_env(), "ann", vec!["ops".into()]);
+        call_as(&app, Some(&ann), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "ops", "kind": "private", "groups": ["ops"] }))).await;
+        let sends = [("lobby", "@bob @carol @dave @ann have a look"), ("dm:ann,bob", "and this"), ("ops", "@bob psst")];
+        for (room, message) in sends {
+            let send = json!({ "email": "ann", "message": message, "room": room });
+            let (status, _) = call_as(&app, Some(&ann), "POST", "/send", "/send", Some(send)).await;
+            assert_eq!(status, StatusCode::OK, "{}", room);
+        }
+        deliver_due(&state).await;
+        assert_eq!(*sent.lock().unwrap(), [("bob-hook".to_string(), 2)]);
+
+        let db = state.db.lock().unwrap();
+        let rows: Vec<(String, String, String)> = db
+            .prepare("SELECT username, kind, status FROM notifications ORDER BY id")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let rows: Vec<(&str, &str, &str)> = rows.iter().map(|(u, k, s)| (u.as_str(), k.as_str(), s.as_str())).collect();
+        assert_eq!(rows, [("bob", "mention", "sent"), ("carol", "mention", "skipped"), ("bob", "direct", "sent")]);
+        let audited: i64 = db
+            .query_row("SELECT COUNT(*) FROM audit_log WHERE action = 'notification.sent' AND target = 'bob'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(audited, 1);
+    }
+
+    #[tokio::test]
+    async fn direct_messages_are_for_their_participants_only() {
+        let state = AppState::new(db::open_path(":memory:").unwrap());
+        let app = router(state.clone());
+        let (ann, eve) = (create_token(&secret_from_env(), "ann"), create_token(&secret_from_env(), "eve"));
+        let dm = "/messages?room=dm:ann,bob";
+        let send = |email: &str| json!({ "email": email, "message": "hi bob", "room": "dm:ann,bob" });
+
+        assert_eq!(call_as(&app, Some(&ann), "POST", "/send", "/send", Some(send("ann"))).await.0, StatusCode::OK);
+        let (status, messages) = call_as(&app, Some(&ann), "GET", dm, "/messages", None).await;
+        assert_eq!((status, messages.as_array().map(Vec::len)), (StatusCode::OK, Some(1)));
+
+        // outsiders neither read nor post, with or without a token
+        assert_eq!(call(&app, "GET", dm, "/messages", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call_as(&app, Some(&eve), "GET", dm, "/messages", None).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(call(&app, "POST", "/send", "/send", Some(send("ann"))).await.0, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(&app, Some(&eve), "POST", "/send", "/send", Some(send("ann"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // nor does a participant speak for the other one
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/send", "/send", Some(send("bob"))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let db = state.db.lock().unwrap();
+        let queued: i64 = db.query_row("SELECT COUNT(*) FROM notifications", [], |r| r.get(0)).unwrap();
+        assert_eq!(queued, 1);
+    }
+}
@@ -0,0 +1,84 @@
+use axum::Json;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::{
+    analytics, archive, audit, edits, emoji, freezes, handlers, notify, polls, privacy, profanity, reactions, receipts,
+    rooms, telemetry, templates, v2,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "chat-service", description = "U-Chat message store, rooms, history, receipts, emoji, polls, privacy requests, device telemetry, notifications and analytics"),
+    paths(
+        handlers::send_message,
+        handlers::get_messages,
+        v2::send_message,
+        v2::get_messages,
+        edits::edit,
+        reactions::add,
+        rooms::create_room,
+        rooms::get_room,
+        rooms::room_history,
+        rooms::clone_room,
+        freezes::get_freeze,
+        freezes::freeze,
+        freezes::thaw,
+        archive::get_archive,
+        archive::flag,
+        archive::unflag,
+        archive::status,
+        templates::list,
+        templates::put,
+        templates::delete,
+        templates::create_room,
+        profanity::get_setting,
+        profanity::put_setting,
+        receipts::record,
+        receipts::list,
+        emoji::list,
+        emoji::put_instance,
+        emoji::delete_instance,
+        emoji::put_room,
+        emoji::delete_room,
+        polls::create_poll,
+        polls::vote,
+        polls::get_results,
+        privacy::erase,
+        privacy::export,
+        audit::run_retention,
+        telemetry::ingest,
+        telemetry::stream,
+        notify::get_prefs,
+        notify::put_prefs,
+        analytics::status,
+        analytics::metrics,
+        analytics::query,
+    ),
+    modifiers(&BearerAuth),
+    tags((name = "messages"), (name = "rooms"), (name = "receipts"), (name = "emoji"), (name = "polls"), (name = "privacy"), (name = "telemetry"), (name = "notifications"), (name = "analytics"))
+)]
+pub struct ApiDoc;
+
+impl ApiDoc {
+    pub fn openapi_doc() -> utoipa::openapi::OpenApi {
+        <Self as OpenApi>::openapi()
+    }
+}
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+    }
+}
+
+// GET /openapi.json
+pub async fn spec() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi_doc())
+}
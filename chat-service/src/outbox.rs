@@ -0,0 +1,287 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+
+use uchat_proto::events::ServerEvent;
+
+use crate::leader::Lease;
+use crate::AppState;
+
+//
+// TRANSACTIONAL OUTBOX
+//
+// Writes that must reach more than the primary store (live stream,
+// webhooks) enqueue an outbox row inside the same SQLite transaction as the
+// primary write. The relay below delivers rows at-least-once: anything not
+// yet marked delivered is replayed after a restart, and webhook receivers
+// dedupe on the `Idempotency-Key` header (the outbox id).
+//
+// Every instance streams every row to its own live clients, whoever wrote
+// it, so a client sees messages sent through any instance. Webhooks go out
+// once: only the instance holding the `outbox-relay` lease calls them and
+// marks rows delivered.
+//
+// Delivered rows are pruned once they are older than OUTBOX_RETENTION_SECS
+// (default 3600). Streams read rows by id within a second or two of their
+// insert, so that is far longer than any instance lags; rows not yet
+// delivered are kept however old.
+//
+
+pub const TOPIC_MESSAGE: &str = "message.created";
+pub const TOPIC_UNFURL: &str = "message.unfurled";
+pub const TOPIC_REACTION: &str = "message.reaction";
+pub const TOPIC_EDIT: &str = "message.edited";
+
+const DEFAULT_RETENTION: Duration = Duration::from_secs(3600);
+
+/// How long delivered rows are kept, OUTBOX_RETENTION_SECS if set.
+pub fn retention_from_env() -> Duration {
+    std::env::var("OUTBOX_RETENTION_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETENTION)
+}
+
+/// Enqueue an event; call with the caller's open transaction.
+pub fn enqueue(conn: &Connection, topic: &str, event: &ServerEvent) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO outbox (topic, payload, created_at) VALUES (?1, ?2, ?3)",
+        params![topic, serde_json::to_string(event).unwrap(), Utc::now().to_rfc3339()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+struct Pending {
+    id: i64,
+    topic: String,
+    payload: String,
+}
+
+fn pending(conn: &Connection) -> Vec<Pending> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, topic, payload FROM outbox
+             WHERE delivered_at IS NULL
+             ORDER BY id ASC LIMIT 100",
+        )
+        .unwrap();
+
+    stmt.query_map([], |r| {
+        Ok(Pending { id: r.get(0)?, topic: r.get(1)?, payload: r.get(2)? })
+    })
+    .unwrap()
+    .filter_map(|r| r.ok())
+    .collect()
+}
+
+/// Rows after `after`, delivered or not, for the live stream.
+fn since(conn: &Connection, after: i64) -> Vec<Pending> {
+    let mut stmt = conn
+        .prepare("SELECT id, topic, payload FROM outbox WHERE id > ?1 ORDER BY id ASC LIMIT 100")
+        .unwrap();
+
+    stmt.query_map([after], |r| {
+        Ok(Pending { id: r.get(0)?, topic: r.get(1)?, payload: r.get(2)? })
+    })
+    .unwrap()
+    .filter_map(|r| r.ok())
+    .collect()
+}
+
+/// Pushes rows after `streamed_up_to` to this instance's live stream;
+/// returns the last id pushed.
+fn stream(state: &AppState, mut streamed_up_to: i64) -> i64 {
+    loop {
+        let batch = since(&state.db.lock().unwrap(), streamed_up_to);
+        let Some(last) = batch.last() else { return streamed_up_to };
+        streamed_up_to = last.id;
+        for item in batch {
+            if let Ok(event) = serde_json::from_str::<ServerEvent>(&item.payload) {
+                let _ = state.tx.send(event);
+            }
+        }
+    }
+}
+
+/// Calls the webhook for pending rows in id order and marks them
+/// delivered. A failed webhook leaves the row (and everything after it)
+/// pending so ordering is preserved across retries.
+async fn deliver(state: &AppState, lease: &mut Lease, webhook: Option<&str>, http: &reqwest::Client) {
+    let batch = pending(&state.db.lock().unwrap());
+
+    for item in batch {
+        // webhook calls can be slow; stop if the lease lapsed meanwhile
+        if !lease.try_acquire(&state.db.lock().unwrap()) {
+            break;
+        }
+
+        if let Some(url) = webhook {
+            let sent = http
+                .post(url)
+                .timeout(Duration::from_secs(5))
+                .header("Idempotency-Key", item.id.to_string())
+                .header("X-Outbox-Topic", &item.topic)
+                .header("Content-Type", "application/json")
+                .body(item.payload.clone())
+                .send()
+                .await;
+
+            match sent {
+                Ok(resp) if resp.status().is_success() => {}
+                other => {
+                    let reason = match other {
+                        Ok(resp) => resp.status().to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    println!("chat-service: outbox {} webhook failed: {}", item.id, reason);
+                    state.db.lock().unwrap().execute(
+                        "UPDATE outbox SET attempts = attempts + 1 WHERE id = ?1",
+                        [item.id],
+                    ).unwrap();
+                    break;
+                }
+            }
+        }
+
+        state.db.lock().unwrap().execute(
+            "UPDATE outbox SET delivered_at = ?1 WHERE id = ?2",
+            params![Utc::now().to_rfc3339(), item.id],
+        ).unwrap();
+    }
+}
+
+/// Deletes rows delivered more than `retention` ago; returns how many.
+pub fn prune(conn: &Connection, retention: Duration) -> rusqlite::Result<usize> {
+    let cutoff = Utc::now() - chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+    conn.execute(
+        "DELETE FROM outbox WHERE delivered_at IS NOT NULL AND delivered_at < ?1",
+        [cutoff.to_rfc3339()],
+    )
+}
+
+pub async fn prune_job(state: AppState, retention: Duration) -> anyhow::Result<()> {
+    let deleted = prune(&state.db.lock().unwrap(), retention)?;
+    if deleted > 0 {
+        println!("chat-service: pruned {} delivered outbox rows", deleted);
+    }
+    Ok(())
+}
+
+/// Streams new outbox rows and, holding the `outbox-relay` lease, delivers
+/// them to the webhook; woken by `state.outbox_notify` or a one-second tick.
+pub async fn relay_loop(state: AppState) {
+    let webhook = std::env::var("OUTBOX_WEBHOOK_URL").ok();
+    let http = reqwest::Client::new();
+    let mut lease = Lease::new("outbox-relay", Duration::from_secs(15));
+
+    // start from the oldest row not yet delivered, as before a restart
+    let mut streamed_up_to: i64 = state
+        .db
+        .lock()
+        .unwrap()
+        .query_row(
+            "SELECT COALESCE((SELECT MIN(id) - 1 FROM outbox WHERE delivered_at IS NULL),
+                             (SELECT MAX(id) FROM outbox), 0)",
+            [],
+            |r| r.get(0),
+        )
+        .unwrap();
+
+    loop {
+        tokio::select! {
+            _ = state.outbox_notify.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+        }
+
+        streamed_up_to = stream(&state, streamed_up_to);
+        if lease.try_acquire(&state.db.lock().unwrap()) {
+            deliver(&state, &mut lease, webhook.as_deref(), &http).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use uchat_proto::events::ServerEvent;
+
+    use super::{pending, prune, relay_loop};
+    use crate::testing::call;
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn sends_reach_the_live_stream() {
+        let state = AppState::new(db::open_path(":memory:").unwrap());
+        let mut live = state.tx.subscribe();
+        tokio::spawn(relay_loop(state.clone()));
+        let app = router(state);
+
+        call(&app, "POST", "/send", "/send", Some(json!({ "email": "ann", "message": "hi" }))).await;
+        let event = tokio::time::timeout(Duration::from_secs(5), live.recv()).await.unwrap().unwrap();
+        match event {
+            ServerEvent::MessageBroadcast { from, content } => assert_eq!((from.as_str(), content.as_str()), ("ann", "hi")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn instances_without_the_lease_still_stream() {
+        let state = AppState::new(db::open_path(":memory:").unwrap());
+        let far = chrono::Utc::now().timestamp_millis() + 60_000;
+        state
+            .db
+            .lock()
+            .unwrap()
+            .execute("INSERT INTO leases (name, holder, expires_at) VALUES ('outbox-relay', 'elsewhere', ?1)", [far])
+            .unwrap();
+        let mut live = state.tx.subscribe();
+        tokio::spawn(relay_loop(state.clone()));
+        let app = router(state.clone());
+
+        call(&app, "POST", "/send", "/send", Some(json!({ "email": "ann", "message": "hi" }))).await;
+        let event = tokio::time::timeout(Duration::from_secs(5), live.recv()).await.unwrap().unwrap();
+        assert!(matches!(event, ServerEvent::MessageBroadcast { .. }), "{:?}", event);
+        // delivery is the lease holder's
+        let pending: i64 = state
+            .db
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM outbox WHERE delivered_at IS NULL", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(pending, 1);
+    }
+
+    #[tokio::test]
+    async fn only_rows_delivered_long_ago_are_pruned() {
+        let conn = db::open_path(":memory:").unwrap();
+        let now = chrono::Utc::now();
+        let two_hours_ago = (now - chrono::Duration::hours(2)).to_rfc3339();
+        for delivered_at in [Some(two_hours_ago.clone()), Some(now.to_rfc3339()), None] {
+            conn.execute(
+                "INSERT INTO outbox (topic, payload, created_at, delivered_at) VALUES ('t', '{}', ?1, ?2)",
+                rusqlite::params![two_hours_ago, delivered_at],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(prune(&conn, Duration::from_secs(3600)).unwrap(), 1);
+        let ids: Vec<i64> = pending(&conn).iter().map(|p| p.id).collect();
+        assert_eq!(ids, [3]);
+        let left: i64 = conn.query_row("SELECT COUNT(*) FROM outbox", [], |r| r.get(0)).unwrap();
+        assert_eq!(left, 2);
+
+        let plan: String = conn
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT id FROM outbox WHERE delivered_at IS NULL ORDER BY id ASC LIMIT 100",
+                [],
+                |r| r.get(3),
+            )
+            .unwrap();
+        assert!(plan.contains("outbox_pending"), "{}", plan);
+    }
+}
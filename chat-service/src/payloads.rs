@@ -0,0 +1,198 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+
+use crate::AppState;
+
+//
+// CONTENT-ADDRESSED PAYLOADS
+//
+// Forwarded messages and repeated stickers carry the same body again and
+// again. Bodies of at least CHAT_DEDUPE_MIN_BYTES are stored once in
+// `payloads`, keyed by their SHA-256; the message row keeps the digest in
+// `payload_ref` and an empty `message`. History reads (load_messages,
+// privacy export) join the body back in, so clients never see references.
+//
+//   CHAT_DEDUPE_MIN_BYTES  smallest body stored by reference (default 1024;
+//                          0 stores every body inline)
+//
+// `refs` counts the rows pointing at a payload. Erasure drops the erased
+// rows' references and an edit the edited row's; the payload-gc job
+// deletes payloads nothing points at any more. Payloads live in the chat
+// database rather than the upload Storage, whose objects are public, and
+// so are counted in the same transaction as the rows that use them.
+//
+// The outbox still carries whole bodies: the live stream and webhooks
+// must stand alone.
+//
+//   chat_payload_dedupe_hits_total      bodies found already stored
+//   chat_payload_bytes_saved_total      bytes those hits did not store
+//   chat_payloads_collected_total       payloads deleted by payload-gc
+//
+
+pub struct PayloadStore {
+    min_bytes: usize,
+    hits: AtomicU64,
+    bytes_saved: AtomicU64,
+    collected: AtomicU64,
+}
+
+impl PayloadStore {
+    pub fn from_env() -> Self {
+        Self {
+            min_bytes: std::env::var("CHAT_DEDUPE_MIN_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(1024),
+            hits: AtomicU64::new(0),
+            bytes_saved: AtomicU64::new(0),
+            collected: AtomicU64::new(0),
+        }
+    }
+
+    /// Inserts a message row, by reference when the body is large enough;
+    /// call with the caller's open transaction. Returns the row id.
+    pub fn insert_message(
+        &self,
+        conn: &Connection,
+        room: &str,
+        email: &str,
+        body: &str,
+        ts: &str,
+    ) -> rusqlite::Result<i64> {
+        match self.store(conn, body)? {
+            None => conn.execute(
+                "INSERT INTO messages (room, email, message, ts) VALUES (?1, ?2, ?3, ?4)",
+                params![room, email, body, ts],
+            )?,
+            Some(digest) => conn.execute(
+                "INSERT INTO messages (room, email, message, ts, payload_ref) VALUES (?1, ?2, '', ?3, ?4)",
+                params![room, email, ts, digest],
+            )?,
+        };
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Replaces message `id`'s body, dropping its reference to the old one;
+    /// call with the caller's open transaction.
+    pub fn replace_body(&self, conn: &Connection, id: i64, body: &str) -> rusqlite::Result<()> {
+        conn.execute(
+            "UPDATE payloads SET refs = refs - 1 WHERE digest = (SELECT payload_ref FROM messages WHERE id = ?1)",
+            [id],
+        )?;
+        match self.store(conn, body)? {
+            None => conn.execute("UPDATE messages SET message = ?1, payload_ref = NULL WHERE id = ?2", params![body, id])?,
+            Some(digest) => {
+                conn.execute("UPDATE messages SET message = '', payload_ref = ?1 WHERE id = ?2", params![digest, id])?
+            }
+        };
+        Ok(())
+    }
+
+    /// Takes a reference to `body` in `payloads` if it is large enough to
+    /// be stored there; returns its digest then.
+    fn store(&self, conn: &Connection, body: &str) -> rusqlite::Result<Option<String>> {
+        if self.min_bytes == 0 || body.len() < self.min_bytes {
+            return Ok(None);
+        }
+
+        let digest = format!("{:x}", Sha256::digest(body.as_bytes()));
+        let inserted = conn.execute(
+            "INSERT INTO payloads (digest, body, refs, created_at) VALUES (?1, ?2, 1, ?3)
+             ON CONFLICT (digest) DO NOTHING",
+            params![digest, body, Utc::now().to_rfc3339()],
+        )?;
+        if inserted == 0 {
+            conn.execute("UPDATE payloads SET refs = refs + 1 WHERE digest = ?1", [&digest])?;
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.bytes_saved.fetch_add(body.len() as u64, Ordering::Relaxed);
+        }
+        Ok(Some(digest))
+    }
+
+    /// Deletes payloads no message refers to; returns how many.
+    pub fn collect(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let deleted = conn.execute("DELETE FROM payloads WHERE refs <= 0", [])?;
+        self.collected.fetch_add(deleted as u64, Ordering::Relaxed);
+        Ok(deleted)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in [
+            ("chat_payload_dedupe_hits_total", &self.hits),
+            ("chat_payload_bytes_saved_total", &self.bytes_saved),
+            ("chat_payloads_collected_total", &self.collected),
+        ] {
+            let _ = writeln!(out, "# TYPE {} counter\n{} {}", name, name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Drops the payload references of `email`'s messages; call in the
+/// erasure transaction, before the rows become tombstones.
+pub fn release_author(conn: &Connection, email: &str) -> rusqlite::Result<usize> {
+    conn.execute(
+        "UPDATE payloads SET refs = refs - (
+             SELECT COUNT(*) FROM messages WHERE payload_ref = payloads.digest AND email = ?1)
+         WHERE digest IN (SELECT payload_ref FROM messages WHERE email = ?1)",
+        [email],
+    )?;
+    conn.execute("UPDATE messages SET payload_ref = NULL WHERE payload_ref IS NOT NULL AND email = ?1", [email])
+}
+
+pub async fn collect_garbage(state: AppState) -> anyhow::Result<()> {
+    let deleted = state.payloads.collect(&state.db.lock().unwrap())?;
+    if deleted > 0 {
+        println!("chat-service: collected {} unreferenced payloads", deleted);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use crate::testing::{call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn repeated_payloads_are_stored_once() {
+        let state = AppState::new(db::open_path(":memory:").unwrap());
+        let app = router(state.clone());
+        let ann = create_token(&secret_from_env(), "ann");
+        let sticker = "<3".repeat(1024);
+        for (email, message) in [("ann", &sticker), ("bob", &sticker), ("bob", &"short".to_string())] {
+            call(&app, "POST", "/send", "/send", Some(json!({ "email": email, "message": message }))).await;
+        }
+
+        let (_, list) = call(&app, "GET", "/messages", "/messages", None).await;
+        let bodies: Vec<_> = list.as_array().unwrap().iter().map(|m| m["message"].as_str().unwrap()).collect();
+        assert_eq!(bodies, [sticker.as_str(), &sticker, "short"]);
+        let stored = |state: &AppState| {
+            let db = state.db.lock().unwrap();
+            db.query_row("SELECT COUNT(*), COALESCE(SUM(refs), 0) FROM payloads", [], |r| {
+                Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?))
+            })
+            .unwrap()
+        };
+        assert_eq!(stored(&state), (1, 2));
+
+        // erasure drops ann's reference; bob's copy keeps the payload alive
+        call_as(&app, Some(&ann), "POST", "/privacy/erase/ann", "/privacy/erase/{user_id}",
+            Some(json!({ "confirm": "ann" }))).await;
+        state.payloads.collect(&state.db.lock().unwrap()).unwrap();
+        assert_eq!(stored(&state), (1, 1));
+        let (_, list) = call(&app, "GET", "/messages", "/messages", None).await;
+        assert_eq!((list[0]["message"].as_str(), list[1]["message"].as_str()), (Some(""), Some(sticker.as_str())));
+
+        let bob = create_token(&secret_from_env(), "bob");
+        call_as(&app, Some(&bob), "POST", "/privacy/erase/bob", "/privacy/erase/{user_id}",
+            Some(json!({ "confirm": "bob" }))).await;
+        assert_eq!(state.payloads.collect(&state.db.lock().unwrap()).unwrap(), 1);
+        assert_eq!(stored(&state), (0, 0));
+    }
+}
@@ -0,0 +1,377 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+use uchat_proto::events::ServerEvent;
+use uchat_proto::jwt::ScopeAction;
+
+use crate::auth::{api_error, authorize_room, authorize_room_for, ApiFailure};
+use crate::{rooms, AppState};
+
+//
+// POLLS
+//
+// Polls and their votes live in the `polls` and `poll_votes` tables, so
+// they survive restarts. Voting takes a token: the vote is cast as its
+// subject, once per poll. The poll-expiry job closes polls past their
+// expiry and announces the final tally.
+//
+
+/// Most options a poll may offer.
+pub const MAX_OPTIONS: usize = 20;
+/// Longest a poll may stay open.
+pub const MAX_TTL_SECS: u64 = 30 * 24 * 3600;
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Poll {
+    pub id: String,
+    pub room: String,
+    pub question: String,
+    pub options: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+    pub closed: bool,
+}
+
+impl Poll {
+    fn from_row(r: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let options: String = r.get(3)?;
+        Ok(Self {
+            id: r.get(0)?,
+            room: r.get(1)?,
+            question: r.get(2)?,
+            options: serde_json::from_str(&options).unwrap_or_default(),
+            expires_at: DateTime::from_timestamp(r.get(4)?, 0).unwrap_or_default(),
+            closed: r.get(5)?,
+        })
+    }
+
+    fn updated_event(&self, counts: Vec<u32>) -> ServerEvent {
+        ServerEvent::PollUpdated { poll_id: self.id.clone(), room: self.room.clone(), counts, closed: self.closed }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreatePoll {
+    pub room: String,
+    pub question: String,
+    /// Two to MAX_OPTIONS (20).
+    pub options: Vec<String>,
+    /// Up to MAX_TTL_SECS (30 days).
+    pub expires_in_secs: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct Vote {
+    /// Index into the poll's options.
+    pub option: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PollResults {
+    pub poll: Poll,
+    pub counts: Vec<u32>,
+    pub total_votes: usize,
+}
+
+type ApiResult<T> = Result<Json<T>, ApiFailure>;
+
+fn load(conn: &Connection, id: &str) -> rusqlite::Result<Option<Poll>> {
+    conn.query_row(
+        "SELECT id, room, question, options, expires_at, closed FROM polls WHERE id = ?1",
+        [id],
+        Poll::from_row,
+    )
+    .optional()
+}
+
+/// Votes per option.
+fn counts(conn: &Connection, poll: &Poll) -> rusqlite::Result<Vec<u32>> {
+    let mut counts = vec![0; poll.options.len()];
+    let mut stmt = conn.prepare("SELECT option, COUNT(*) FROM poll_votes WHERE poll_id = ?1 GROUP BY option")?;
+    let rows = stmt.query_map([&poll.id], |r| Ok((r.get::<_, usize>(0)?, r.get::<_, u32>(1)?)))?;
+    for row in rows {
+        let (option, n) = row?;
+        if let Some(count) = counts.get_mut(option) {
+            *count = n;
+        }
+    }
+    Ok(counts)
+}
+
+fn results(conn: &Connection, poll: Poll) -> PollResults {
+    let counts = counts(conn, &poll).unwrap();
+    let total_votes = counts.iter().sum::<u32>() as usize;
+    PollResults { poll, counts, total_votes }
+}
+
+fn not_found() -> ApiFailure {
+    api_error(StatusCode::NOT_FOUND, "poll not found")
+}
+
+#[utoipa::path(post, path = "/polls", tag = "polls",
+    request_body = CreatePoll,
+    security((), ("bearer" = [])),
+    responses((status = 200, body = Poll), (status = 400, body = ApiError),
+        (status = 401, body = ApiError), (status = 403, body = ApiError), (status = 423, body = ApiError),
+        (status = 500, body = ApiError)))]
+pub async fn create_poll(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<CreatePoll>,
+) -> ApiResult<Poll> {
+    rooms::authorize_post(&state, &headers, &body.room)?;
+
+    if body.question.trim().is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "question must not be empty"));
+    }
+    if body.options.len() < 2 {
+        return Err(api_error(StatusCode::BAD_REQUEST, "a poll needs at least two options"));
+    }
+    if body.options.len() > MAX_OPTIONS {
+        return Err(api_error(StatusCode::BAD_REQUEST, &format!("a poll offers at most {} options", MAX_OPTIONS)));
+    }
+    let expires_at = Some(body.expires_in_secs)
+        .filter(|secs| *secs <= MAX_TTL_SECS)
+        .and_then(|secs| Utc::now().checked_add_signed(chrono::Duration::seconds(secs as i64)))
+        .ok_or_else(|| {
+            api_error(StatusCode::BAD_REQUEST, &format!("expires_in_secs must be at most {}", MAX_TTL_SECS))
+        })?;
+
+    let poll = Poll {
+        id: uuid::Uuid::new_v4().to_string(),
+        room: body.room,
+        question: body.question,
+        options: body.options,
+        expires_at,
+        closed: false,
+    };
+
+    state
+        .db
+        .lock()
+        .unwrap()
+        .execute(
+            "INSERT INTO polls (id, room, question, options, expires_at, closed) VALUES (?1, ?2, ?3, ?4, ?5, 0)",
+            params![
+                poll.id,
+                poll.room,
+                poll.question,
+                serde_json::to_string(&poll.options).unwrap(),
+                poll.expires_at.timestamp()
+            ],
+        )
+        .map_err(|_| api_error(StatusCode::INTERNAL_SERVER_ERROR, "could not create the poll"))?;
+    let _ = state.tx.send(poll.updated_event(vec![0; poll.options.len()]));
+
+    Ok(Json(poll))
+}
+
+#[utoipa::path(post, path = "/polls/{id}/vote", tag = "polls",
+    params(("id" = String, Path)),
+    request_body = Vote,
+    security(("bearer" = [])),
+    responses((status = 200, body = PollResults), (status = 400, body = ApiError),
+        (status = 401, body = ApiError), (status = 403, body = ApiError),
+        (status = 404, body = ApiError), (status = 409, body = ApiError)))]
+pub async fn vote(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<Vote>,
+) -> ApiResult<PollResults> {
+    let poll = load(&state.db.lock().unwrap(), &id).unwrap().ok_or_else(not_found)?;
+    let Some(voter) = authorize_room_for(&state, &headers, &poll.room, ScopeAction::Send)? else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "voting requires a token"));
+    };
+
+    if poll.closed || Utc::now() >= poll.expires_at {
+        return Err(api_error(StatusCode::CONFLICT, "poll is closed"));
+    }
+    if body.option >= poll.options.len() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "no such option"));
+    }
+
+    let db = state.db.lock().unwrap();
+    let cast = db
+        .execute(
+            "INSERT OR IGNORE INTO poll_votes (poll_id, username, option) VALUES (?1, ?2, ?3)",
+            params![poll.id, voter.sub, body.option],
+        )
+        .unwrap();
+    if cast == 0 {
+        return Err(api_error(StatusCode::CONFLICT, "already voted"));
+    }
+
+    let results = results(&db, poll);
+    let _ = state.tx.send(results.poll.updated_event(results.counts.clone()));
+    Ok(Json(results))
+}
+
+#[utoipa::path(get, path = "/polls/{id}", tag = "polls",
+    params(("id" = String, Path)),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = PollResults), (status = 404, body = ApiError)))]
+pub async fn get_results(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> ApiResult<PollResults> {
+    let poll = load(&state.db.lock().unwrap(), &id).unwrap().ok_or_else(not_found)?;
+    authorize_room(&state, &headers, &poll.room)?;
+
+    Ok(Json(results(&state.db.lock().unwrap(), poll)))
+}
+
+/// Closes polls once they pass their expiry and announces the final tally.
+pub async fn expire_polls(state: AppState) -> anyhow::Result<()> {
+    let db = state.db.lock().unwrap();
+    let due: Vec<Poll> = db
+        .prepare(
+            "UPDATE polls SET closed = 1 WHERE closed = 0 AND expires_at <= ?1
+             RETURNING id, room, question, options, expires_at, closed",
+        )?
+        .query_map([Utc::now().timestamp()], Poll::from_row)?
+        .collect::<rusqlite::Result<_>>()?;
+    for poll in due {
+        println!("chat-service: poll {} closed", poll.id);
+        let counts = counts(&db, &poll)?;
+        let _ = state.tx.send(poll.updated_event(counts));
+    }
+    Ok(())
+}
+
+/// Unlinks `user` from their votes, which keep counting toward the tally.
+pub fn erase_voter(conn: &Connection, user: &str) -> rusqlite::Result<usize> {
+    conn.execute(
+        "UPDATE poll_votes SET username = 'erased:' || lower(hex(randomblob(16))) WHERE username = ?1",
+        [user],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::events::ServerEvent;
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use super::{expire_polls, MAX_OPTIONS, MAX_TTL_SECS};
+    use crate::testing::{app, call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn polls_match_schema() {
+        let app = app();
+        let ann = create_token(&secret_from_env(), "ann");
+        let (_, poll) = call(&app, "POST", "/polls", "/polls", Some(json!({
+            "room": "lobby", "question": "lunch?", "options": ["yes", "no"], "expires_in_secs": 60
+        }))).await;
+        let id = poll["id"].as_str().unwrap().to_string();
+
+        let vote_uri = format!("/polls/{}/vote", id);
+        let (status, _) = call(&app, "POST", &vote_uri, "/polls/{id}/vote", Some(json!({ "option": 0 }))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, results) = call_as(&app, Some(&ann), "POST", &vote_uri, "/polls/{id}/vote",
+            Some(json!({ "option": 0 }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(results["counts"], json!([1, 0]));
+
+        let (status, _) = call_as(&app, Some(&ann), "POST", &vote_uri, "/polls/{id}/vote",
+            Some(json!({ "option": 1 }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        call(&app, "GET", &format!("/polls/{}", id), "/polls/{id}", None).await;
+    }
+
+    #[tokio::test]
+    async fn polls_bound_their_options_and_lifetime() {
+        let app = app();
+        let options: Vec<String> = (0..=MAX_OPTIONS).map(|i| i.to_string()).collect();
+        let (status, _) = call(&app, "POST", "/polls", "/polls", Some(json!({
+            "room": "lobby", "question": "pick one", "options": options, "expires_in_secs": 60
+        }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        for secs in [MAX_TTL_SECS + 1, u64::MAX] {
+            let (status, _) = call(&app, "POST", "/polls", "/polls", Some(json!({
+                "room": "lobby", "question": "forever?", "options": ["yes", "no"], "expires_in_secs": secs
+            }))).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", secs);
+        }
+    }
+
+    #[tokio::test]
+    async fn polls_survive_a_restart_and_close_on_expiry() {
+        let path = std::env::temp_dir().join(format!("uchat-polls-{}.db", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let ann = create_token(&secret_from_env(), "ann");
+        let id = {
+            let app = router(AppState::new(db::open_path(path).unwrap()));
+            let (_, poll) = call(&app, "POST", "/polls", "/polls", Some(json!({
+                "room": "lobby", "question": "lunch?", "options": ["yes", "no"], "expires_in_secs": 1
+            }))).await;
+            let id = poll["id"].as_str().unwrap().to_string();
+            call_as(&app, Some(&ann), "POST", &format!("/polls/{}/vote", id), "/polls/{id}/vote",
+                Some(json!({ "option": 1 }))).await;
+            id
+        };
+
+        let state = AppState::new(db::open_path(path).unwrap());
+        let mut events = state.tx.subscribe();
+        let app = router(state.clone());
+        let (_, results) = call(&app, "GET", &format!("/polls/{}", id), "/polls/{id}", None).await;
+        assert_eq!((results["counts"].clone(), results["poll"]["closed"].clone()), (json!([0, 1]), json!(false)));
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        expire_polls(state.clone()).await.unwrap();
+        match events.try_recv() {
+            Ok(ServerEvent::PollUpdated { poll_id, counts, closed, .. }) => {
+                assert_eq!((poll_id, counts, closed), (id.clone(), vec![0, 1], true));
+            }
+            other => panic!("expected the final tally, got {:?}", other),
+        }
+        let (_, results) = call(&app, "GET", &format!("/polls/{}", id), "/polls/{id}", None).await;
+        assert_eq!(results["poll"]["closed"], true);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn polls_are_posted_like_messages() {
+        let app = app();
+        let ann = create_token(&secret_from_env(), "ann");
+        let eve = create_token(&secret_from_env(), "eve");
+        let modr = create_token_with_groups(&secret_from_env(), "mo", vec!["moderators".into()]);
+        call_as(&app, Some(&ann), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "news", "kind": "announcement" }))).await;
+        let poll = json!({ "room": "news", "question": "lunch?", "options": ["yes", "no"], "expires_in_secs": 60 });
+
+        let (status, _) = call(&app, "POST", "/polls", "/polls", Some(poll.clone())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(&app, Some(&eve), "POST", "/polls", "/polls", Some(poll.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call_as(&app, Some(&modr), "POST", "/polls", "/polls", Some(poll)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn errors_match_schema() {
+        let app = app();
+        let (status, _) = call(&app, "GET", "/polls/missing", "/polls/{id}", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call(&app, "POST", "/polls", "/polls", Some(json!({
+            "room": "lobby", "question": "?", "options": ["only one"], "expires_in_secs": 60
+        }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,280 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+use uchat_proto::events::ServerEvent;
+
+use crate::archive;
+use crate::audit;
+use crate::auth::{api_error, bearer_claims, ApiFailure};
+use crate::notify;
+use crate::payloads;
+use crate::polls;
+use crate::AppState;
+
+//
+// DATA SUBJECT REQUESTS
+//
+// Erasure keeps each message row as a tombstone (author and content
+// blanked, `erased_at` set) so history keeps its shape, and scrubs the same
+// messages from outbox payloads, the source of the live stream and webhooks.
+// Link previews of those messages, the user's own delivery receipts and
+// reactions, and notifications to or from them are deleted outright. Poll
+// votes keep counting but no longer name the voter. Every request is
+// audit-logged, denied ones included.
+//
+
+/// Author shown on erased messages.
+pub const ERASED_AUTHOR: &str = "[erased]";
+
+#[derive(Deserialize, ToSchema)]
+pub struct EraseRequest {
+    /// Must repeat the user id being erased.
+    pub confirm: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EraseResult {
+    pub user_id: String,
+    pub messages_erased: usize,
+    pub outbox_scrubbed: usize,
+    pub poll_votes_anonymized: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ExportedMessage {
+    pub id: i64,
+    pub message: String,
+    pub ts: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ExportedVote {
+    pub poll_id: String,
+    pub room: String,
+    pub question: String,
+    pub option: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Export {
+    pub user_id: String,
+    pub exported_at: String,
+    pub messages: Vec<ExportedMessage>,
+    pub poll_votes: Vec<ExportedVote>,
+}
+
+/// The caller's name, if they may act on `user_id` (themselves or an admin).
+fn authorize(state: &AppState, headers: &HeaderMap, action: &str, user_id: &str) -> Result<String, ApiFailure> {
+    let Some(claims) = bearer_claims(state, headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if claims.sub != user_id && !state.admins.contains(&claims.sub) {
+        audit::record(&state.db.lock().unwrap(), action, &claims.sub, user_id, "denied");
+        return Err(api_error(StatusCode::FORBIDDEN, "only the user or an admin may do this"));
+    }
+    Ok(claims.sub)
+}
+
+#[utoipa::path(post, path = "/privacy/erase/{user_id}", tag = "privacy",
+    params(("user_id" = String, Path, description = "message author id")),
+    request_body = EraseRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = EraseResult), (status = 400, body = ApiError),
+        (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn erase(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(body): Json<EraseRequest>,
+) -> Result<Json<EraseResult>, ApiFailure> {
+    let actor = authorize(&state, &headers, "privacy.erase", &user_id)?;
+    if body.confirm != user_id {
+        audit::record(&state.db.lock().unwrap(), "privacy.erase", &actor, &user_id, "unconfirmed");
+        return Err(api_error(StatusCode::BAD_REQUEST, "confirm must repeat the user id"));
+    }
+
+    let tombstone = serde_json::to_string(&ServerEvent::MessageBroadcast {
+        from: ERASED_AUTHOR.into(),
+        content: String::new(),
+    })
+    .unwrap();
+
+    let (messages_erased, outbox_scrubbed, poll_votes_anonymized) = {
+        let mut db = state.db.lock().unwrap();
+        let tx = db.transaction().unwrap();
+        audit::record(&tx, "privacy.erase", &actor, &user_id, "started");
+
+        // previews echo the links the user posted
+        tx.execute(
+            "DELETE FROM outbox WHERE delivered_at IS NULL
+             AND json_extract(payload, '$.MessageUnfurled.message_id') IN
+                 (SELECT id FROM messages WHERE email = ?1)",
+            [&user_id],
+        )
+        .unwrap();
+        tx.execute(
+            "DELETE FROM link_previews WHERE message_id IN (SELECT id FROM messages WHERE email = ?1)",
+            [&user_id],
+        )
+        .unwrap();
+        tx.execute("DELETE FROM receipts WHERE username = ?1", [&user_id]).unwrap();
+        tx.execute("DELETE FROM reactions WHERE username = ?1", [&user_id]).unwrap();
+        notify::erase_user(&tx, &user_id).unwrap();
+        // votes keep counting toward the tally but no longer name the voter
+        let poll_votes = polls::erase_voter(&tx, &user_id).unwrap();
+        archive::erase_user(&tx, &user_id).unwrap();
+        tx.execute(
+            "DELETE FROM outbox WHERE delivered_at IS NULL AND json_extract(payload, '$.ReactionAdded.from') = ?1",
+            [&user_id],
+        )
+        .unwrap();
+        tx.execute(
+            "DELETE FROM outbox WHERE delivered_at IS NULL AND json_extract(payload, '$.MessageEdited.from') = ?1",
+            [&user_id],
+        )
+        .unwrap();
+
+        payloads::release_author(&tx, &user_id).unwrap();
+        let messages = tx
+            .execute(
+                "UPDATE messages SET email = ?1, message = '', erased_at = ?2
+                 WHERE email = ?3 AND erased_at IS NULL",
+                params![ERASED_AUTHOR, Utc::now().to_rfc3339(), user_id],
+            )
+            .unwrap();
+        let outbox = tx
+            .execute(
+                "UPDATE outbox SET payload = ?1
+                 WHERE json_extract(payload, '$.MessageBroadcast.from') = ?2",
+                params![tombstone, user_id],
+            )
+            .unwrap();
+
+        audit::record(
+            &tx,
+            "privacy.erase",
+            &actor,
+            &user_id,
+            format!("completed messages={} outbox={}", messages, outbox),
+        );
+        tx.commit().unwrap();
+        (messages, outbox, poll_votes)
+    };
+
+    Ok(Json(EraseResult { user_id, messages_erased, outbox_scrubbed, poll_votes_anonymized }))
+}
+
+#[utoipa::path(get, path = "/privacy/export/{user_id}", tag = "privacy",
+    params(("user_id" = String, Path, description = "message author id")),
+    security(("bearer" = [])),
+    responses((status = 200, body = Export), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn export(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<Json<Export>, ApiFailure> {
+    let actor = authorize(&state, &headers, "privacy.export", &user_id)?;
+
+    let messages: Vec<ExportedMessage> = {
+        let db = state.db.lock().unwrap();
+        let mut stmt = db
+            .prepare(
+                "SELECT m.id, COALESCE(p.body, m.message), m.ts
+                 FROM messages m LEFT JOIN payloads p ON p.digest = m.payload_ref
+                 WHERE m.email = ?1 ORDER BY m.id ASC",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([&user_id], |r| Ok(ExportedMessage { id: r.get(0)?, message: r.get(1)?, ts: r.get(2)? }))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        rows
+    };
+
+    let poll_votes: Vec<ExportedVote> = {
+        let db = state.db.lock().unwrap();
+        let mut stmt = db
+            .prepare(
+                "SELECT p.id, p.room, p.question, json_extract(p.options, '$[' || v.option || ']')
+                 FROM poll_votes v JOIN polls p ON p.id = v.poll_id
+                 WHERE v.username = ?1 ORDER BY p.expires_at ASC",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map([&user_id], |r| {
+                Ok(ExportedVote { poll_id: r.get(0)?, room: r.get(1)?, question: r.get(2)?, option: r.get(3)? })
+            })
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .collect();
+        rows
+    };
+
+    audit::record(
+        &state.db.lock().unwrap(),
+        "privacy.export",
+        &actor,
+        &user_id,
+        format!("messages={} poll_votes={}", messages.len(), poll_votes.len()),
+    );
+
+    Ok(Json(Export { user_id, exported_at: Utc::now().to_rfc3339(), messages, poll_votes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use crate::testing::{app, call, call_as};
+
+    #[tokio::test]
+    async fn privacy_matches_schema() {
+        let app = app();
+        let ann = create_token(&secret_from_env(), "ann");
+        call(&app, "POST", "/send", "/send", Some(json!({ "email": "ann", "message": "secret" }))).await;
+        let (_, poll) = call(&app, "POST", "/polls", "/polls", Some(json!({
+            "room": "lobby", "question": "lunch?", "options": ["yes", "no"], "expires_in_secs": 60
+        }))).await;
+        let poll = poll["id"].as_str().unwrap().to_string();
+        call_as(&app, Some(&ann), "POST", &format!("/polls/{}/vote", poll), "/polls/{id}/vote",
+            Some(json!({ "option": 1 }))).await;
+
+        let (status, export) = call_as(&app, Some(&ann), "GET", "/privacy/export/ann",
+            "/privacy/export/{user_id}", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(export["messages"][0]["message"], "secret");
+        assert_eq!(export["poll_votes"][0]["option"], "no");
+
+        let (status, _) = call_as(&app, Some(&ann), "GET", "/privacy/export/bob",
+            "/privacy/export/{user_id}", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/privacy/erase/ann",
+            "/privacy/erase/{user_id}", Some(json!({ "confirm": "bob" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, result) = call_as(&app, Some(&ann), "POST", "/privacy/erase/ann",
+            "/privacy/erase/{user_id}", Some(json!({ "confirm": "ann" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(result["messages_erased"], 1);
+        assert_eq!(result["poll_votes_anonymized"], 1);
+        let (_, results) = call(&app, "GET", &format!("/polls/{}", poll), "/polls/{id}", None).await;
+        assert_eq!(results["counts"], json!([0, 1]));
+
+        let (_, list) = call(&app, "GET", "/messages", "/messages", None).await;
+        assert_eq!(list[0]["message"], "");
+        assert!(list[0]["erased_at"].is_string());
+    }
+}
@@ -0,0 +1,280 @@
+use std::borrow::Cow;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use regex::{Regex, RegexBuilder};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+use uchat_proto::jwt::Claims;
+
+use crate::audit;
+use crate::auth::{api_error, authorize_room, bearer_claims, ApiFailure};
+use crate::handlers::OutgoingMessage;
+use crate::rooms;
+use crate::AppState;
+
+//
+// PROFANITY MASKING
+//
+// Rooms can have message content masked when it is delivered: in history
+// (/messages, /rooms/{id}/messages) and on the live stream. The stored
+// message keeps the original, which moderators read with `?original=true`.
+//
+//   CHAT_PROFANITY_WORDS    comma-separated words, matched whole and
+//                           case-insensitively
+//   CHAT_PROFANITY_FILE     wordlist, one word per line; "re:" lines are
+//                           regular expressions, "#" lines are comments
+//   CHAT_PROFANITY_DEFAULT  "on" to mask in rooms without their own setting
+//                           (default off)
+//
+// Each match becomes one '*' per character. Admins and a room's creator
+// switch masking for the room with PUT /rooms/{id}/profanity.
+//
+
+/// Group whose members may read unmasked history.
+const MODERATORS: &str = "moderators";
+
+pub struct Masker {
+    patterns: Vec<Regex>,
+    default_on: bool,
+}
+
+impl Masker {
+    pub fn from_env() -> Self {
+        let mut list = std::env::var("CHAT_PROFANITY_WORDS").unwrap_or_default().replace(',', "\n");
+        if let Ok(path) = std::env::var("CHAT_PROFANITY_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(file) => {
+                    list.push('\n');
+                    list.push_str(&file);
+                }
+                Err(e) => println!("chat-service: cannot read CHAT_PROFANITY_FILE {}: {}", path, e),
+            }
+        }
+        Self::parse(&list, std::env::var("CHAT_PROFANITY_DEFAULT").is_ok_and(|v| v == "on"))
+    }
+
+    /// A masker for a wordlist in the CHAT_PROFANITY_FILE format. Invalid
+    /// expressions are skipped with a warning.
+    pub fn parse(list: &str, default_on: bool) -> Self {
+        let mut words = Vec::new();
+        let mut patterns = Vec::new();
+        for line in list.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            match line.strip_prefix("re:") {
+                Some(expr) => match RegexBuilder::new(expr).case_insensitive(true).build() {
+                    Ok(re) => patterns.push(re),
+                    Err(e) => println!("chat-service: ignoring profanity pattern {:?}: {}", expr, e),
+                },
+                None => words.push(regex::escape(line)),
+            }
+        }
+        if !words.is_empty() {
+            let words = RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|")))
+                .case_insensitive(true)
+                .build()
+                .unwrap();
+            patterns.insert(0, words);
+        }
+        Self { patterns, default_on }
+    }
+
+    pub fn mask<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut out = Cow::Borrowed(text);
+        for re in &self.patterns {
+            if let Cow::Owned(masked) = re.replace_all(&out, |c: &regex::Captures| "*".repeat(c[0].chars().count())) {
+                out = Cow::Owned(masked);
+            }
+        }
+        out
+    }
+
+    /// Whether delivery in `room` is masked.
+    pub fn enabled(&self, conn: &Connection, room: &str) -> bool {
+        !self.patterns.is_empty() && setting(conn, room).map_or(self.default_on, |s| s.enabled)
+    }
+
+    /// `text` as delivered in `room`.
+    pub fn for_room<'a>(&self, conn: &Connection, room: &str, text: &'a str) -> Cow<'a, str> {
+        match self.enabled(conn, room) {
+            true => self.mask(text),
+            false => Cow::Borrowed(text),
+        }
+    }
+}
+
+fn is_moderator(state: &AppState, claims: &Claims) -> bool {
+    state.admins.contains(&claims.sub) || claims.groups.iter().any(|g| g == MODERATORS)
+}
+
+/// Prepares history of `room` for delivery: masked if the room is, or the
+/// originals when a moderator asks for them.
+pub fn deliver(
+    state: &AppState,
+    conn: &Connection,
+    claims: Option<&Claims>,
+    room: &str,
+    original: bool,
+    messages: &mut [OutgoingMessage],
+) -> Result<(), ApiFailure> {
+    if original {
+        return match claims {
+            Some(c) if is_moderator(state, c) => Ok(()),
+            Some(_) => Err(api_error(StatusCode::FORBIDDEN, "only moderators may read original messages")),
+            None => Err(api_error(StatusCode::UNAUTHORIZED, "original messages require a token")),
+        };
+    }
+    if state.profanity.enabled(conn, room) {
+        for m in messages {
+            if let Cow::Owned(masked) = state.profanity.mask(&m.message) {
+                m.message = masked;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ProfanitySetting {
+    pub room: String,
+    /// Whether delivered content is masked.
+    pub enabled: bool,
+    /// Unset while the room follows CHAT_PROFANITY_DEFAULT.
+    pub updated_by: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+struct Stored {
+    enabled: bool,
+    updated_by: String,
+    updated_at: String,
+}
+
+fn setting(conn: &Connection, room: &str) -> Option<Stored> {
+    conn.query_row(
+        "SELECT enabled, updated_by, updated_at FROM profanity_settings WHERE room = ?1",
+        [room],
+        |r| Ok(Stored { enabled: r.get(0)?, updated_by: r.get(1)?, updated_at: r.get(2)? }),
+    )
+    .optional()
+    .unwrap()
+}
+
+fn describe(state: &AppState, conn: &Connection, room: &str) -> ProfanitySetting {
+    let stored = setting(conn, room);
+    ProfanitySetting {
+        room: room.to_string(),
+        enabled: state.profanity.enabled(conn, room),
+        updated_by: stored.as_ref().map(|s| s.updated_by.clone()),
+        updated_at: stored.map(|s| s.updated_at),
+    }
+}
+
+#[utoipa::path(get, path = "/rooms/{id}/profanity", tag = "rooms",
+    params(("id" = String, Path)),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = ProfanitySetting), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn get_setting(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<ProfanitySetting>, ApiFailure> {
+    authorize_room(&state, &headers, &id)?;
+    Ok(Json(describe(&state, &state.db.lock().unwrap(), &id)))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateProfanity {
+    pub enabled: bool,
+}
+
+#[utoipa::path(put, path = "/rooms/{id}/profanity", tag = "rooms",
+    params(("id" = String, Path)),
+    request_body = UpdateProfanity,
+    security(("bearer" = [])),
+    responses((status = 200, body = ProfanitySetting), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn put_setting(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<UpdateProfanity>,
+) -> Result<Json<ProfanitySetting>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    let db = state.db.lock().unwrap();
+    let creator = rooms::info(&db, &state.acl, &id).created_by;
+    if !state.admins.contains(&claims.sub) && creator.as_deref() != Some(claims.sub.as_str()) {
+        return Err(api_error(StatusCode::FORBIDDEN, "only admins and the room's creator may change masking"));
+    }
+
+    db.execute(
+        "INSERT INTO profanity_settings (room, enabled, updated_by, updated_at) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(room) DO UPDATE SET enabled = ?2, updated_by = ?3, updated_at = ?4",
+        params![id, body.enabled, claims.sub, Utc::now().to_rfc3339()],
+    )
+    .unwrap();
+    let detail = if body.enabled { "on" } else { "off" };
+    audit::record(&db, "room.profanity", &claims.sub, &id, detail.to_string());
+
+    Ok(Json(describe(&state, &db, &id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::events::ServerEvent;
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use super::Masker;
+    use crate::testing::{call, call_as};
+    use crate::{db, outbox, router, AppState};
+
+    #[tokio::test]
+    async fn profanity_is_masked_on_delivery() {
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        state.profanity = Arc::new(Masker::parse("darn\nre:h[e3]ck", false));
+        let mut live = state.tx.subscribe();
+        tokio::spawn(outbox::relay_loop(state.clone()));
+        let app = router(state);
+        let ann = create_token(&secret_from_env(), "ann");
+        let eve = create_token(&secret_from_env(), "eve");
+        let modr = create_token_with_groups(&secret_from_env(), "mo", vec!["moderators".into()]);
+
+        call_as(&app, Some(&ann), "POST", "/rooms", "/rooms", Some(json!({ "id": "general" }))).await;
+        let (_, setting) = call(&app, "GET", "/rooms/general/profanity", "/rooms/{id}/profanity", None).await;
+        assert_eq!(setting["enabled"], false);
+        let (status, _) = call_as(&app, Some(&eve), "PUT", "/rooms/general/profanity", "/rooms/{id}/profanity",
+            Some(json!({ "enabled": true }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, setting) = call_as(&app, Some(&ann), "PUT", "/rooms/general/profanity", "/rooms/{id}/profanity",
+            Some(json!({ "enabled": true }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(setting["updated_by"], "ann");
+
+        call(&app, "POST", "/send", "/send",
+            Some(json!({ "email": "ann", "message": "Darn it, what the h3ck", "room": "general" }))).await;
+        let event = tokio::time::timeout(Duration::from_secs(5), live.recv()).await.unwrap().unwrap();
+        let ServerEvent::MessageBroadcast { content, .. } = event else { panic!("unexpected {:?}", event) };
+        assert_eq!(content, "**** it, what the ****");
+
+        let (_, history) = call(&app, "GET", "/rooms/general/messages", "/rooms/{id}/messages", None).await;
+        assert_eq!(history["messages"][0]["message"], "**** it, what the ****");
+        let (status, _) = call_as(&app, Some(&eve), "GET", "/messages?room=general&original=true", "/messages", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (_, original) = call_as(&app, Some(&modr), "GET", "/rooms/general/messages?original=true",
+            "/rooms/{id}/messages", None).await;
+        assert_eq!(original["messages"][0]["message"], "Darn it, what the h3ck");
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt::Write as _;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use uchat_core::ratelimit::{KeyedLimiter, Limit};
+use uchat_proto::errors::ApiError;
+
+use crate::auth::token_claims;
+use crate::AppState;
+
+//
+// RATE LIMITS
+//
+// /send and /messages are limited per client IP, and additionally per
+// user when the request carries a valid token, so one account cannot
+// flood from many addresses nor one address with many accounts:
+//
+//   CHAT_RATE_LIMIT_SEND          per user (default 20/10s)
+//   CHAT_RATE_LIMIT_SEND_IP       per IP (default 60/10s)
+//   CHAT_RATE_LIMIT_HISTORY       per user (default 60/1m)
+//   CHAT_RATE_LIMIT_HISTORY_IP    per IP (default 120/1m)
+//   CHAT_TRUST_FORWARDED_FOR      "1" to take the IP from the last
+//                                 X-Forwarded-For hop (behind a proxy)
+//
+// Limits are "<max>/<period>" (see uchat_core::ratelimit). Refusals are
+// 429 with Retry-After, and counted in /metrics.
+//
+
+pub struct RouteLimits {
+    route: &'static str,
+    user: KeyedLimiter<String>,
+    ip: KeyedLimiter<IpAddr>,
+    allowed: AtomicU64,
+    limited_user: AtomicU64,
+    limited_ip: AtomicU64,
+}
+
+impl RouteLimits {
+    pub fn new(route: &'static str, user: Limit, ip: Limit) -> Self {
+        Self {
+            route,
+            user: KeyedLimiter::new(format!("{}.user", route), user),
+            ip: KeyedLimiter::new(format!("{}.ip", route), ip),
+            allowed: AtomicU64::new(0),
+            limited_user: AtomicU64::new(0),
+            limited_ip: AtomicU64::new(0),
+        }
+    }
+}
+
+pub struct RateLimits {
+    trust_forwarded: bool,
+    pub send: RouteLimits,
+    pub history: RouteLimits,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        let limit = |name: &str, max, secs| Limit::from_env(name, Limit::new(max, std::time::Duration::from_secs(secs)));
+        Self {
+            trust_forwarded: std::env::var("CHAT_TRUST_FORWARDED_FOR").is_ok_and(|v| v == "1"),
+            send: RouteLimits::new("send", limit("CHAT_RATE_LIMIT_SEND", 20, 10), limit("CHAT_RATE_LIMIT_SEND_IP", 60, 10)),
+            history: RouteLimits::new(
+                "history",
+                limit("CHAT_RATE_LIMIT_HISTORY", 60, 60),
+                limit("CHAT_RATE_LIMIT_HISTORY_IP", 120, 60),
+            ),
+        }
+    }
+
+    fn client_ip(&self, req: &Request) -> IpAddr {
+        if self.trust_forwarded {
+            let forwarded = req
+                .headers()
+                .get("X-Forwarded-For")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.rsplit(',').next())
+                .and_then(|hop| hop.trim().parse().ok());
+            if let Some(ip) = forwarded {
+                return ip;
+            }
+        }
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|c| c.0.ip())
+            .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+    }
+
+    /// Drops keys that have fully recovered; runs once a minute.
+    pub fn evict(&self) {
+        for route in [&self.send, &self.history] {
+            route.user.retain_recent();
+            route.ip.retain_recent();
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE chat_rate_limit_allowed_total counter\n");
+        for route in [&self.send, &self.history] {
+            let _ = writeln!(
+                out,
+                "chat_rate_limit_allowed_total{{route=\"{}\"}} {}",
+                route.route,
+                route.allowed.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str("# TYPE chat_rate_limited_total counter\n");
+        for route in [&self.send, &self.history] {
+            for (scope, count) in [("user", &route.limited_user), ("ip", &route.limited_ip)] {
+                let _ = writeln!(
+                    out,
+                    "chat_rate_limited_total{{route=\"{}\",scope=\"{}\"}} {}",
+                    route.route,
+                    scope,
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+        out.push_str("# TYPE chat_rate_limit_keys gauge\n");
+        for route in [&self.send, &self.history] {
+            for (scope, keys) in [("user", route.user.info().keys), ("ip", route.ip.info().keys)] {
+                let _ = writeln!(out, "chat_rate_limit_keys{{route=\"{}\",scope=\"{}\"}} {}", route.route, scope, keys);
+            }
+        }
+        out
+    }
+}
+
+fn too_many_requests(wait: std::time::Duration) -> Response {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let body = ApiError { message: "rate limit exceeded, retry later".into() };
+    (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, secs.max(1).to_string())], Json(body)).into_response()
+}
+
+/// The 429 reply, when `route`'s limits refuse the request.
+fn enforce(state: &AppState, route: &RouteLimits, headers: &HeaderMap, ip: IpAddr) -> Option<Response> {
+    if let Err(wait) = route.ip.check(&ip) {
+        route.limited_ip.fetch_add(1, Ordering::Relaxed);
+        return Some(too_many_requests(wait));
+    }
+    if let Some(claims) = token_claims(state, headers) {
+        if let Err(wait) = route.user.check(&claims.sub) {
+            route.limited_user.fetch_add(1, Ordering::Relaxed);
+            return Some(too_many_requests(wait));
+        }
+    }
+    route.allowed.fetch_add(1, Ordering::Relaxed);
+    None
+}
+
+/// Middleware for POST /send.
+pub async fn send(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let ip = state.rate_limits.client_ip(&req);
+    match enforce(&state, &state.rate_limits.send, req.headers(), ip) {
+        None => next.run(req).await,
+        Some(refused) => refused,
+    }
+}
+
+/// Middleware for GET /messages.
+pub async fn history(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let ip = state.rate_limits.client_ip(&req);
+    match enforce(&state, &state.rate_limits.history, req.headers(), ip) {
+        None => next.run(req).await,
+        Some(refused) => refused,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use uchat_core::ratelimit::Limit;
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use super::{RateLimits, RouteLimits};
+    use crate::testing::{call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn rate_limits_match_schema() {
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        let one = Limit::new(1, Duration::from_secs(60));
+        let mut limits = RateLimits::from_env();
+        limits.send = RouteLimits::new("send", one, Limit::new(100, Duration::from_secs(60)));
+        limits.history = RouteLimits::new("history", Limit::new(100, Duration::from_secs(60)), one);
+        state.rate_limits = Arc::new(limits);
+        let app = router(state);
+
+        let token = create_token(&secret_from_env(), "alice");
+        let send = json!({ "email": "alice", "message": "hi" });
+        let (status, _) = call_as(&app, Some(&token), "POST", "/send", "/send", Some(send.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call_as(&app, Some(&token), "POST", "/send", "/send", Some(send)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // another user is limited separately
+        let other = create_token(&secret_from_env(), "bob");
+        let send = json!({ "email": "bob", "message": "hi" });
+        let (status, _) = call_as(&app, Some(&other), "POST", "/send", "/send", Some(send)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        call(&app, "GET", "/messages", "/messages", None).await;
+        let resp = app
+            .clone()
+            .oneshot(Request::builder().uri("/messages").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = resp.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+        assert!((1..=60).contains(&retry_after));
+    }
+}
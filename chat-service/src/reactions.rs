@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+use uchat_proto::events::ServerEvent;
+
+use crate::auth::{api_error, authorize_room, bearer_claims, ApiFailure};
+use crate::emoji;
+use crate::outbox;
+use crate::AppState;
+
+//
+// REACTIONS
+//
+// Anyone who can read a room can react to its messages, once per emoji.
+// An emoji is either Unicode (a single emoji sequence) or ":name:" naming a
+// custom emoji registered for the room or the instance. New reactions go
+// out as ReactionAdded through the outbox.
+//
+
+#[derive(Deserialize, ToSchema)]
+pub struct ReactionRequest {
+    /// A Unicode emoji, or ":name:" for a custom one.
+    pub emoji: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Reactions {
+    /// Reaction count per emoji.
+    pub counts: BTreeMap<String, u32>,
+}
+
+/// A Unicode emoji sequence: a few non-ASCII code points (joiners and
+/// variation selectors included), no text.
+fn unicode_emoji(s: &str) -> bool {
+    let count = s.chars().count();
+    (1..=10).contains(&count) && s.chars().all(|c| !c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace())
+}
+
+/// Whether `emoji` may be used as a reaction in `room`.
+pub fn valid(conn: &Connection, room: &str, emoji: &str) -> bool {
+    match emoji.strip_prefix(':').and_then(|e| e.strip_suffix(':')) {
+        Some(name) => emoji::exists(conn, room, name),
+        None => unicode_emoji(emoji),
+    }
+}
+
+/// Reaction counts per message id.
+pub fn counts(conn: &Connection, room: &str) -> BTreeMap<i64, BTreeMap<String, u32>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT r.message_id, r.emoji, COUNT(*) FROM reactions r JOIN messages m ON m.id = r.message_id
+             WHERE m.room = ?1 GROUP BY r.message_id, r.emoji",
+        )
+        .unwrap();
+    let mut out: BTreeMap<i64, BTreeMap<String, u32>> = BTreeMap::new();
+    let rows = stmt.query_map([room], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?, r.get::<_, u32>(2)?))).unwrap();
+    for (message_id, emoji, count) in rows.filter_map(|r| r.ok()) {
+        out.entry(message_id).or_default().insert(emoji, count);
+    }
+    out
+}
+
+#[utoipa::path(post, path = "/messages/{room}/{id}/reactions", tag = "messages",
+    params(("room" = String, Path), ("id" = i64, Path, description = "message id")),
+    request_body = ReactionRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = Reactions), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 404, body = ApiError)))]
+pub async fn add(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((room, message_id)): Path<(String, i64)>,
+    Json(body): Json<ReactionRequest>,
+) -> Result<Json<Reactions>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    authorize_room(&state, &headers, &room)?;
+
+    let mut db = state.db.lock().unwrap();
+    let exists = db
+        .query_row("SELECT 1 FROM messages WHERE id = ?1 AND room = ?2", params![message_id, room], |_| Ok(()))
+        .optional()
+        .unwrap()
+        .is_some();
+    if !exists {
+        return Err(api_error(StatusCode::NOT_FOUND, "no such message in this room"));
+    }
+    if !valid(&db, &room, &body.emoji) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "not an emoji or a custom emoji registered here"));
+    }
+
+    let tx = db.transaction().unwrap();
+    let added = tx
+        .execute(
+            "INSERT OR IGNORE INTO reactions (message_id, username, emoji, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![message_id, claims.sub, body.emoji, Utc::now().to_rfc3339()],
+        )
+        .unwrap();
+    if added > 0 {
+        outbox::enqueue(&tx, outbox::TOPIC_REACTION, &ServerEvent::ReactionAdded {
+            room: room.clone(),
+            message_id,
+            from: claims.sub,
+            emoji: body.emoji,
+        })
+        .unwrap();
+    }
+    tx.commit().unwrap();
+    if added > 0 {
+        state.outbox_notify.notify_one();
+    }
+
+    let mut stmt = db
+        .prepare("SELECT emoji, COUNT(*) FROM reactions WHERE message_id = ?1 GROUP BY emoji")
+        .unwrap();
+    let counts = stmt.query_map([message_id], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().filter_map(|r| r.ok()).collect();
+    Ok(Json(Reactions { counts }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use crate::testing::{call, call_as};
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn emoji_and_reactions_match_schema() {
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        let dir = std::env::temp_dir().join(format!("uchat-emoji-{}", uuid::Uuid::new_v4()));
+        state.storage = std::sync::Arc::new(uchat_core::storage::LocalStorage::new(&dir, "/media"));
+        let app = router(state);
+        let ann = create_token(&secret_from_env(), "ann");
+        let bob = create_token(&secret_from_env(), "bob");
+
+        call_as(&app, Some(&ann), "POST", "/rooms", "/rooms", Some(json!({ "id": "fun" }))).await;
+        call(&app, "POST", "/send", "/send", Some(json!({ "email": "bob", "message": "hi", "room": "fun" }))).await;
+
+        for (token, status) in [(&bob, StatusCode::FORBIDDEN), (&ann, StatusCode::OK)] {
+            let req = Request::builder()
+                .method("PUT")
+                .uri("/rooms/fun/emoji/parrot")
+                .header("authorization", format!("Bearer {}", token))
+                .header("content-type", "image/png")
+                .body(Body::from(vec![0x89, b'P', b'N', b'G']))
+                .unwrap();
+            assert_eq!(app.clone().oneshot(req).await.unwrap().status(), status);
+        }
+
+        let (_, registry) = call(&app, "GET", "/emoji?room=fun", "/emoji", None).await;
+        assert_eq!(registry["emoji"][0]["name"], "parrot");
+        assert_eq!(registry["emoji"][0]["room"], "fun");
+        let (_, registry) = call(&app, "GET", "/emoji", "/emoji", None).await;
+        assert!(registry["emoji"].as_array().unwrap().is_empty());
+
+        let uri = "/messages/fun/1/reactions";
+        let route = "/messages/{room}/{id}/reactions";
+        let (status, _) = call_as(&app, Some(&bob), "POST", uri, route, Some(json!({ "emoji": ":parrot:" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, reactions) = call_as(&app, Some(&ann), "POST", uri, route, Some(json!({ "emoji": "🎉" }))).await;
+        assert_eq!(reactions["counts"], json!({ ":parrot:": 1, "🎉": 1 }));
+        let (status, _) = call_as(&app, Some(&ann), "POST", uri, route, Some(json!({ "emoji": ":nope:" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call_as(&app, Some(&ann), "POST", uri, route, Some(json!({ "emoji": "lol" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (_, history) = call(&app, "GET", "/rooms/fun/messages", "/rooms/{id}/messages", None).await;
+        assert_eq!(history["messages"][0]["reactions"][":parrot:"], 1);
+
+        let (status, _) = call_as(&app, Some(&ann), "DELETE", "/rooms/fun/emoji/parrot", "/rooms/{id}/emoji/{name}", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,194 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+use uchat_proto::events::ReceiptKind;
+
+use crate::audit;
+use crate::auth::{api_error, authorize_room, bearer_claims, ApiFailure};
+use crate::rooms;
+use crate::AppState;
+
+//
+// DELIVERY RECEIPTS
+//
+// In compliance rooms, who received and who read each message is kept as
+// one row per (message, recipient) with unix-second timestamps. Clients
+// acknowledge messages through the gateway, which relays them here with a
+// token for the user; receipts for other rooms are accepted and dropped.
+//
+// Auditors (CHAT_ADMINS, or members of CHAT_AUDITOR_GROUP, default
+// "auditors") read them per message; every lookup is audit-logged.
+//
+
+#[derive(Deserialize, ToSchema)]
+pub struct ReceiptRequest {
+    pub room: String,
+    pub message_id: i64,
+    pub kind: ReceiptKind,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReceiptAck {
+    /// False when the room does not keep receipts.
+    pub recorded: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Receipt {
+    pub username: String,
+    pub delivered_at: String,
+    pub read_at: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessageReceipts {
+    pub room: String,
+    pub message_id: i64,
+    pub receipts: Vec<Receipt>,
+}
+
+fn message_in_room(conn: &rusqlite::Connection, room: &str, message_id: i64) -> bool {
+    conn.query_row("SELECT 1 FROM messages WHERE id = ?1 AND room = ?2", params![message_id, room], |_| Ok(()))
+        .optional()
+        .unwrap()
+        .is_some()
+}
+
+fn rfc3339(secs: i64) -> String {
+    DateTime::from_timestamp(secs, 0).unwrap_or_default().to_rfc3339()
+}
+
+#[utoipa::path(post, path = "/receipts", tag = "receipts",
+    request_body = ReceiptRequest,
+    security(("bearer" = [])),
+    responses((status = 200, body = ReceiptAck), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 404, body = ApiError)))]
+pub async fn record(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<ReceiptRequest>,
+) -> Result<Json<ReceiptAck>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    authorize_room(&state, &headers, &body.room)?;
+
+    let db = state.db.lock().unwrap();
+    if !message_in_room(&db, &body.room, body.message_id) {
+        return Err(api_error(StatusCode::NOT_FOUND, "no such message in this room"));
+    }
+    if !rooms::info(&db, &state.acl, &body.room).compliance {
+        return Ok(Json(ReceiptAck { recorded: false }));
+    }
+
+    // first delivery and first read win; repeats are no-ops
+    let now = Utc::now().timestamp();
+    let sql = match body.kind {
+        ReceiptKind::Delivered => {
+            "INSERT INTO receipts (message_id, username, delivered_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (message_id, username) DO NOTHING"
+        }
+        ReceiptKind::Read => {
+            "INSERT INTO receipts (message_id, username, delivered_at, read_at) VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT (message_id, username) DO UPDATE SET read_at = COALESCE(read_at, excluded.read_at)"
+        }
+    };
+    db.execute(sql, params![body.message_id, claims.sub, now]).unwrap();
+
+    Ok(Json(ReceiptAck { recorded: true }))
+}
+
+#[utoipa::path(get, path = "/messages/{room}/{id}/receipts", tag = "receipts",
+    params(("room" = String, Path), ("id" = i64, Path, description = "message id")),
+    security(("bearer" = [])),
+    responses((status = 200, body = MessageReceipts), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 404, body = ApiError)))]
+pub async fn list(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((room, message_id)): Path<(String, i64)>,
+) -> Result<Json<MessageReceipts>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    let target = format!("{}/{}", room, message_id);
+
+    let db = state.db.lock().unwrap();
+    let auditor = state.admins.contains(&claims.sub) || claims.groups.contains(&state.auditor_group);
+    if !auditor {
+        audit::record(&db, "receipts.read", &claims.sub, &target, "denied");
+        return Err(api_error(StatusCode::FORBIDDEN, "only auditors may read receipts"));
+    }
+    if !message_in_room(&db, &room, message_id) || !rooms::info(&db, &state.acl, &room).compliance {
+        return Err(api_error(StatusCode::NOT_FOUND, "no receipts are kept for this message"));
+    }
+
+    let mut stmt = db
+        .prepare("SELECT username, delivered_at, read_at FROM receipts WHERE message_id = ?1 ORDER BY delivered_at, username")
+        .unwrap();
+    let receipts: Vec<Receipt> = stmt
+        .query_map([message_id], |r| {
+            Ok(Receipt {
+                username: r.get(0)?,
+                delivered_at: rfc3339(r.get(1)?),
+                read_at: r.get::<_, Option<i64>>(2)?.map(rfc3339),
+            })
+        })
+        .unwrap()
+        .filter_map(|r| r.ok())
+        .collect();
+    drop(stmt);
+
+    audit::record(&db, "receipts.read", &claims.sub, &target, format!("receipts={}", receipts.len()));
+    Ok(Json(MessageReceipts { room, message_id, receipts }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use crate::testing::{app, call, call_as};
+
+    #[tokio::test]
+    async fn receipts_match_schema() {
+        let app = app();
+        let ann = create_token(&secret_from_env(), "ann");
+        let audit = create_token_with_groups(&secret_from_env(), "ivy", vec!["auditors".into()]);
+
+        call_as(&app, Some(&ann), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "trading", "compliance": true }))).await;
+        call(&app, "POST", "/send", "/send", Some(json!({ "email": "bob", "message": "buy", "room": "trading" }))).await;
+        call(&app, "POST", "/send", "/send", Some(json!({ "email": "bob", "message": "hi" }))).await;
+
+        let (status, ack) = call_as(&app, Some(&ann), "POST", "/receipts", "/receipts",
+            Some(json!({ "room": "trading", "message_id": 1, "kind": "read" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ack["recorded"], true);
+        let (_, ack) = call_as(&app, Some(&ann), "POST", "/receipts", "/receipts",
+            Some(json!({ "room": "lobby", "message_id": 2, "kind": "delivered" }))).await;
+        assert_eq!(ack["recorded"], false);
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/receipts", "/receipts",
+            Some(json!({ "room": "trading", "message_id": 2, "kind": "delivered" }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = call_as(&app, Some(&ann), "GET", "/messages/trading/1/receipts",
+            "/messages/{room}/{id}/receipts", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, list) = call_as(&app, Some(&audit), "GET", "/messages/trading/1/receipts",
+            "/messages/{room}/{id}/receipts", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list["receipts"][0]["username"], "ann");
+        assert!(list["receipts"][0]["read_at"].is_string());
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use uchat_proto::api::{Revocation, RevocationFeed};
+use uchat_proto::jwt::{Claims, RevocationList};
+
+use crate::AppState;
+
+//
+// TOKEN REVOCATION
+//
+// chat-service verifies tokens by their signature, so a token revoked in
+// auth-api (a delegated token, or every token of a revoked account) would
+// keep working here until it expired. Like the gateway, chat-service
+// follows auth-api's revocation feed (GET /revocations) and refuses a
+// token on it wherever one is checked (see auth.rs): 401, as if there were
+// no token.
+//
+// The revocation-sync job polls the feed; a token is refused from the
+// first poll after its revocation.
+//
+//   CHAT_REVOCATIONS_URL            auth-api's feed, e.g.
+//                                   http://auth-api:9200/revocations;
+//                                   unset: revocations are not followed
+//   CHAT_REVOCATIONS_TOKEN          auth-api's REVOCATIONS_FEED_TOKEN
+//   CHAT_REVOCATIONS_INTERVAL_SECS  how often to poll (default 15)
+//
+//   chat_revocations{kind="user|token"}  entries held
+//   chat_revocation_sync_failures_total
+//
+
+const DEFAULT_INTERVAL_SECS: u64 = 15;
+const FEED_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Feed {
+    url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+pub struct Revocations {
+    feed: Option<Feed>,
+    pub interval: Duration,
+    list: Mutex<RevocationList>,
+    failures: AtomicU64,
+}
+
+impl Revocations {
+    pub fn from_env() -> Self {
+        let url = std::env::var("CHAT_REVOCATIONS_URL").ok().filter(|u| !u.trim().is_empty());
+        let token = std::env::var("CHAT_REVOCATIONS_TOKEN").ok().filter(|t| !t.is_empty());
+        let secs = std::env::var("CHAT_REVOCATIONS_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_INTERVAL_SECS);
+        if let Some(url) = &url {
+            println!("chat-service: following token revocations from {}", url);
+        }
+        Self {
+            feed: url.map(|url| Feed { url, token, http: reqwest::Client::new() }),
+            interval: Duration::from_secs(secs.max(1)),
+            list: Mutex::new(RevocationList::default()),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn revokes(&self, claims: &Claims) -> bool {
+        self.list.lock().unwrap().revokes(claims)
+    }
+
+    /// Adds entries, e.g. from the feed.
+    pub fn apply(&self, revocations: &[Revocation]) {
+        let mut list = self.list.lock().unwrap();
+        for revocation in revocations {
+            list.apply(revocation);
+        }
+    }
+
+    /// Reads the feed past the last entry applied.
+    async fn poll(&self) -> anyhow::Result<Vec<Revocation>> {
+        let Some(feed) = &self.feed else { return Ok(Vec::new()) };
+        let after = self.list.lock().unwrap().cursor;
+        let mut req = feed.http.get(&feed.url).query(&[("after", after)]).timeout(FEED_TIMEOUT);
+        if let Some(token) = &feed.token {
+            req = req.bearer_auth(token);
+        }
+        let page: RevocationFeed = req.send().await?.error_for_status()?.json().await?;
+        Ok(page.revocations)
+    }
+
+    /// Polls the feed, then forgets revoked tokens that have expired anyway.
+    pub async fn sync(&self) {
+        match self.poll().await {
+            Ok(fresh) => self.apply(&fresh),
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                println!("chat-service: cannot read token revocations: {}", e);
+            }
+        }
+        let now = Utc::now().timestamp().max(0) as usize;
+        self.list.lock().unwrap().forget_expired(now);
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let (users, tokens) = self.list.lock().unwrap().counts();
+        let mut out = String::from("# TYPE chat_revocations gauge\n");
+        let _ = writeln!(out, "chat_revocations{{kind=\"user\"}} {}", users);
+        let _ = writeln!(out, "chat_revocations{{kind=\"token\"}} {}", tokens);
+        out.push_str("# TYPE chat_revocation_sync_failures_total counter\n");
+        let _ = writeln!(out, "chat_revocation_sync_failures_total {}", self.failures.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// The revocation-sync job.
+pub async fn sync_job(state: AppState) -> anyhow::Result<()> {
+    state.revocations.sync().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::api::Revocation;
+    use uchat_proto::jwt::{
+        create_delegated_token, create_token, create_token_with_groups, secret_from_env, Scope, ScopeAction,
+    };
+
+    use crate::testing::call_as;
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn revoked_tokens_are_refused() {
+        let state = AppState::new(db::open_path(":memory:").unwrap());
+        let app = router(state.clone());
+        let scope = Scope { bot: "notifier".into(), rooms: vec!["alerts".into()], actions: vec![ScopeAction::Send] };
+        let ops = vec!["ops".to_string()];
+        let ttl = chrono::Duration::minutes(5);
+        let bot = create_delegated_token(&secret_from_env(), "ann", ops.clone(), "t1", scope, ttl);
+        let ann = create_token_with_groups(&secret_from_env(), "ann", ops);
+        let eve = create_token(&secret_from_env(), "eve");
+        call_as(&app, Some(&ann), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "alerts", "kind": "private", "groups": ["ops"] }))).await;
+        let post = json!({ "email": "ann", "message": "disk full", "room": "alerts" });
+        let (status, _) = call_as(&app, Some(&bot), "POST", "/send", "/send", Some(post.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let exp = (chrono::Utc::now() + chrono::Duration::minutes(5)).timestamp() as usize;
+        let before = chrono::Utc::now().timestamp() as usize + 1;
+        state.revocations.apply(&[
+            Revocation::Token { id: 1, jti: "t1".into(), exp },
+            Revocation::User { id: 2, sub: "eve".into(), before },
+        ]);
+        let (status, _) = call_as(&app, Some(&bot), "POST", "/send", "/send", Some(post)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call_as(&app, Some(&eve), "POST", "/rooms", "/rooms", Some(json!({ "id": "eves" }))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,388 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use uchat_proto::acl::{RoomAcl, RoomKind, ANNOUNCER_GROUPS};
+use uchat_proto::errors::ApiError;
+use uchat_proto::jwt::{Claims, ScopeAction};
+
+use crate::audit;
+use crate::emoji;
+use crate::freezes;
+use crate::auth::{api_error, authorize_room, authorize_room_for, bearer_claims, ApiFailure};
+use crate::handlers::{load_messages, OutgoingMessage};
+use crate::profanity;
+use crate::AppState;
+
+//
+// ROOMS
+//
+// Rooms are created with a kind that decides who may post:
+// - public: anyone;
+// - private: members of the room's groups, who are also the only readers;
+// - announcement: everyone reads, only moderators and bots post.
+//
+// Any kind can be created as a compliance room, which records who received
+// and read each message (see receipts.rs).
+//
+// Rooms that were never created (the lobby, rooms named in ROOM_ACL) keep
+// working as before: they are public, or private when ROOM_ACL lists them.
+// Direct-message rooms ("dm:<user>,<user>", see notify.rs) are never
+// created either; only their participants read or post in them.
+//
+// POST /rooms/{id}/clone creates a room with another's kind and, unless
+// `include` picks fewer, its groups, compliance flag, masking setting and
+// emoji; not its messages. Room templates (templates.rs) name rooms to
+// clone this way.
+//
+// Nobody posts in a frozen room (see freezes.rs), whatever its kind.
+//
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RoomInfo {
+    pub id: String,
+    pub kind: RoomKind,
+    /// Groups allowed in a private room.
+    pub groups: Vec<String>,
+    /// Unset for rooms that were never explicitly created.
+    pub created_by: Option<String>,
+    pub created_at: Option<String>,
+    /// Delivery and read receipts are kept for this room.
+    pub compliance: bool,
+}
+
+/// The room `id`, or its implicit definition if it was never created.
+pub fn info(conn: &Connection, acl: &RoomAcl, id: &str) -> RoomInfo {
+    let row = conn
+        .query_row(
+            "SELECT kind, groups, created_by, created_at, compliance FROM rooms WHERE id = ?1",
+            [id],
+            |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+        )
+        .optional()
+        .unwrap();
+
+    match row {
+        Some((kind, groups, created_by, created_at, compliance)) => RoomInfo {
+            id: id.to_string(),
+            kind: RoomKind::parse(&kind).unwrap_or_default(),
+            groups: serde_json::from_str(&groups).unwrap_or_default(),
+            created_by: Some(created_by),
+            created_at: Some(created_at),
+            compliance,
+        },
+        None => {
+            let groups = acl.groups(id).map(<[String]>::to_vec);
+            RoomInfo {
+                id: id.to_string(),
+                kind: if groups.is_some() { RoomKind::Private } else { RoomKind::Public },
+                groups: groups.unwrap_or_default(),
+                created_by: None,
+                created_at: None,
+                compliance: false,
+            }
+        }
+    }
+}
+
+/// Whether `claims` may post in an announcement room.
+fn is_announcer(state: &AppState, claims: &Claims) -> bool {
+    state.admins.contains(&claims.sub) || claims.groups.iter().any(|g| ANNOUNCER_GROUPS.contains(&g.as_str()))
+}
+
+/// Room access (see `authorize_room_for`) plus the room kind's posting
+/// rule; nobody posts in a frozen room.
+pub fn authorize_post(state: &AppState, headers: &HeaderMap, room: &str) -> Result<Option<Claims>, ApiFailure> {
+    let claims = authorize_room_for(state, headers, room, ScopeAction::Send)?;
+
+    let kind = {
+        let db = state.db.lock().unwrap();
+        if let Some(refusal) = freezes::refusal(&db, room) {
+            return Err(refusal);
+        }
+        info(&db, &state.acl, room).kind
+    };
+    if kind != RoomKind::Announcement {
+        return Ok(claims);
+    }
+    match claims {
+        Some(c) if is_announcer(state, &c) => Ok(Some(c)),
+        Some(_) => Err(api_error(StatusCode::FORBIDDEN, "only moderators and bots may post in announcement rooms")),
+        None => Err(api_error(StatusCode::UNAUTHORIZED, "announcement rooms require a token")),
+    }
+}
+
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateRoom {
+    pub id: String,
+    #[serde(default)]
+    pub kind: RoomKind,
+    /// Required for private rooms; ignored otherwise.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Keep delivery and read receipts for auditors.
+    #[serde(default)]
+    pub compliance: bool,
+}
+
+#[utoipa::path(post, path = "/rooms", tag = "rooms",
+    request_body = CreateRoom,
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomInfo), (status = 400, body = ApiError),
+        (status = 401, body = ApiError), (status = 409, body = ApiError)))]
+pub async fn create_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<CreateRoom>,
+) -> Result<Json<RoomInfo>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if !valid_id(&body.id) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "room id must be a lowercase slug"));
+    }
+    let groups = match body.kind {
+        RoomKind::Private if body.groups.is_empty() => {
+            return Err(api_error(StatusCode::BAD_REQUEST, "a private room needs at least one group"));
+        }
+        RoomKind::Private => body.groups,
+        _ => Vec::new(),
+    };
+
+    let db = state.db.lock().unwrap();
+    let inserted = db
+        .execute(
+            "INSERT INTO rooms (id, kind, groups, created_by, created_at, compliance)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(id) DO NOTHING",
+            params![
+                body.id,
+                body.kind.as_str(),
+                serde_json::to_string(&groups).unwrap(),
+                claims.sub,
+                Utc::now().to_rfc3339(),
+                body.compliance
+            ],
+        )
+        .unwrap();
+    if inserted == 0 {
+        return Err(api_error(StatusCode::CONFLICT, "room already exists"));
+    }
+    let detail = match body.compliance {
+        true => format!("{} compliance", body.kind.as_str()),
+        false => body.kind.as_str().to_string(),
+    };
+    audit::record(&db, "room.create", &claims.sub, &body.id, detail);
+
+    Ok(Json(info(&db, &state.acl, &body.id)))
+}
+
+#[utoipa::path(get, path = "/rooms/{id}", tag = "rooms",
+    params(("id" = String, Path)),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = RoomInfo), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn get_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<RoomInfo>, ApiFailure> {
+    authorize_room(&state, &headers, &id)?;
+    Ok(Json(info(&state.db.lock().unwrap(), &state.acl, &id)))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RoomHistory {
+    pub room: RoomInfo,
+    pub messages: Vec<OutgoingMessage>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct HistoryQuery {
+    /// Unmasked content (see profanity.rs); moderators only.
+    #[serde(default)]
+    pub original: bool,
+}
+
+#[utoipa::path(get, path = "/rooms/{id}/messages", tag = "rooms",
+    params(("id" = String, Path), HistoryQuery),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = RoomHistory), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn room_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(q): Query<HistoryQuery>,
+) -> Result<Json<RoomHistory>, ApiFailure> {
+    let claims = authorize_room(&state, &headers, &id)?;
+
+    let db = state.db.lock().unwrap();
+    let mut messages = load_messages(&db, &id);
+    profanity::deliver(&state, &db, claims.as_ref(), &id, q.original, &mut messages)?;
+    Ok(Json(RoomHistory { room: info(&db, &state.acl, &id), messages }))
+}
+
+/// What a clone copies besides the room's kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ClonePart {
+    /// The groups of a private room, its members.
+    Groups,
+    /// Keeping delivery and read receipts.
+    Compliance,
+    /// The room's own masking setting.
+    Profanity,
+    /// The room's custom emoji.
+    Emoji,
+}
+
+impl ClonePart {
+    pub const ALL: [ClonePart; 4] = [ClonePart::Groups, ClonePart::Compliance, ClonePart::Profanity, ClonePart::Emoji];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ClonePart::Groups => "groups",
+            ClonePart::Compliance => "compliance",
+            ClonePart::Profanity => "profanity",
+            ClonePart::Emoji => "emoji",
+        }
+    }
+}
+
+/// Creates room `id` as a copy of `source`: its kind, and the parts in
+/// `include`. Messages are not copied.
+pub fn copy_room(
+    db: &mut Connection,
+    acl: &RoomAcl,
+    source: &str,
+    id: &str,
+    include: &[ClonePart],
+    actor: &str,
+) -> Result<RoomInfo, ApiFailure> {
+    if !valid_id(id) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "room id must be a lowercase slug"));
+    }
+    let from = info(db, acl, source);
+    let groups = if include.contains(&ClonePart::Groups) { from.groups } else { Vec::new() };
+    if from.kind == RoomKind::Private && groups.is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "a private room needs groups; include `groups` to copy them"));
+    }
+    let compliance = from.compliance && include.contains(&ClonePart::Compliance);
+    let now = Utc::now().to_rfc3339();
+
+    let tx = db.transaction().unwrap();
+    let inserted = tx
+        .execute(
+            "INSERT INTO rooms (id, kind, groups, created_by, created_at, compliance)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(id) DO NOTHING",
+            params![id, from.kind.as_str(), serde_json::to_string(&groups).unwrap(), actor, now, compliance],
+        )
+        .unwrap();
+    if inserted == 0 {
+        return Err(api_error(StatusCode::CONFLICT, "room already exists"));
+    }
+    if include.contains(&ClonePart::Profanity) {
+        tx.execute(
+            "INSERT INTO profanity_settings (room, enabled, updated_by, updated_at)
+             SELECT ?1, enabled, ?2, ?3 FROM profanity_settings WHERE room = ?4",
+            params![id, actor, now, source],
+        )
+        .unwrap();
+    }
+    if include.contains(&ClonePart::Emoji) {
+        emoji::copy(&tx, source, id, actor);
+    }
+    let parts: Vec<&str> = include.iter().map(|p| p.as_str()).collect();
+    audit::record(&tx, "room.clone", actor, id, format!("from {}: {}", source, parts.join(", ")));
+    tx.commit().unwrap();
+
+    Ok(info(db, acl, id))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CloneRoom {
+    /// Id of the new room.
+    pub id: String,
+    /// What to copy besides the kind; everything when absent.
+    pub include: Option<Vec<ClonePart>>,
+}
+
+#[utoipa::path(post, path = "/rooms/{id}/clone", tag = "rooms",
+    params(("id" = String, Path, description = "Room to copy")),
+    request_body = CloneRoom,
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomInfo), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 403, body = ApiError), (status = 409, body = ApiError)))]
+pub async fn clone_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(source): Path<String>,
+    Json(body): Json<CloneRoom>,
+) -> Result<Json<RoomInfo>, ApiFailure> {
+    // copying a room reveals its settings, so it takes being able to read it
+    let Some(claims) = authorize_room(&state, &headers, &source)? else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    let include = body.include.unwrap_or_else(|| ClonePart::ALL.to_vec());
+    let mut db = state.db.lock().unwrap();
+    copy_room(&mut db, &state.acl, &source, &body.id, &include, &claims.sub).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use crate::testing::{app, call, call_as};
+
+    #[tokio::test]
+    async fn rooms_match_schema() {
+        let app = app();
+        let ann = create_token(&secret_from_env(), "ann");
+        let modr = create_token_with_groups(&secret_from_env(), "mo", vec!["moderators".into()]);
+
+        let (status, room) = call_as(&app, Some(&ann), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "news", "kind": "announcement" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(room["kind"], "announcement");
+
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "news" }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/send", "/send",
+            Some(json!({ "email": "ann", "message": "hi", "room": "news" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call_as(&app, Some(&modr), "POST", "/send", "/send",
+            Some(json!({ "email": "mo", "message": "release today", "room": "news" }))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, history) = call(&app, "GET", "/rooms/news/messages", "/rooms/{id}/messages", None).await;
+        assert_eq!(history["room"]["kind"], "announcement");
+        assert_eq!(history["messages"][0]["message"], "release today");
+
+        call_as(&app, Some(&modr), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "staff", "kind": "private", "groups": ["moderators"] }))).await;
+        let (status, _) = call_as(&app, Some(&ann), "GET", "/rooms/staff", "/rooms/{id}", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call_as(&app, Some(&modr), "GET", "/messages?room=staff", "/messages", None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, lobby) = call(&app, "GET", "/rooms/lobby", "/rooms/{id}", None).await;
+        assert_eq!(lobby["kind"], "public");
+    }
+}
@@ -0,0 +1,473 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{SinkExt, StreamExt};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tungstenite::protocol::Message;
+use utoipa::{IntoParams, ToSchema};
+
+use uchat_core::ratelimit::{KeyedLimiter, Limit};
+use uchat_proto::errors::ApiError;
+
+use crate::auth::{api_error, bearer_claims, ApiFailure};
+use crate::AppState;
+
+//
+// TELEMETRY INGESTION
+//
+// Sensors send batches of readings instead of one POST /send each, over
+// HTTP (POST /telemetry, one batch per request) or a WebSocket on the chat
+// port (ws://host:9300/telemetry, one batch per text frame). Devices
+// authenticate with their own token, not a user JWT:
+//
+//   CHAT_DEVICE_TOKENS        "thermo-1=token1,thermo-2=token2"
+//   CHAT_TELEMETRY_RATE       batches per device (default 20/1s)
+//   CHAT_TELEMETRY_MAX_BATCH  most readings per batch (default 1000)
+//   CHAT_TELEMETRY_QUEUE      batches waiting to be written (default 256)
+//
+// Each device has its own stream: readings get consecutive `seq` numbers
+// per device, in arrival order, next to the device's own timestamp.
+// Accepted batches queue for a single writer that commits several at a
+// time. When the queue is full, HTTP answers 503 with Retry-After. The
+// WebSocket sends `slow_down` and stops reading frames until there is
+// room, so the device's socket buffer fills and TCP backpressure reaches
+// the sender. Every ack carries `credit`, the room left in the queue, so
+// well-behaved devices can pace themselves before that happens.
+//
+
+/// How far ahead of our clock a device timestamp may be.
+const MAX_CLOCK_SKEW: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Batches committed in one transaction at most.
+const WRITE_BATCH: usize = 32;
+
+#[derive(Deserialize, ToSchema)]
+pub struct TelemetryReading {
+    /// Device clock when the reading was taken.
+    pub ts: DateTime<Utc>,
+    /// Sensor values, stored as sent.
+    #[schema(value_type = Object)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TelemetryBatch {
+    pub readings: Vec<TelemetryReading>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TelemetryAck {
+    pub accepted: usize,
+    pub first_seq: u64,
+    pub last_seq: u64,
+    /// Batches the ingest queue can still take.
+    pub credit: usize,
+}
+
+/// WebSocket replies, one per frame (plus `slow_down` notices).
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsReply {
+    Ack(TelemetryAck),
+    SlowDown { retry_after_ms: u64 },
+    Error { message: String },
+}
+
+struct QueuedBatch {
+    device: String,
+    readings: Vec<TelemetryReading>,
+    /// First and last seq written, or why the write failed.
+    done: oneshot::Sender<Result<(u64, u64), String>>,
+}
+
+pub struct Ingest {
+    /// (device, token)
+    devices: Vec<(String, String)>,
+    rate: KeyedLimiter<String>,
+    pub max_batch: usize,
+    queue: mpsc::Sender<QueuedBatch>,
+    receiver: Mutex<Option<mpsc::Receiver<QueuedBatch>>>,
+    readings: AtomicU64,
+    batches: AtomicU64,
+    throttled_rate: AtomicU64,
+    throttled_queue: AtomicU64,
+}
+
+impl Ingest {
+    pub fn new(devices: Vec<(String, String)>, rate: Limit, max_batch: usize, queue: usize) -> Self {
+        let (tx, rx) = mpsc::channel(queue.max(1));
+        Self {
+            devices,
+            rate: KeyedLimiter::new("telemetry.device", rate),
+            max_batch,
+            queue: tx,
+            receiver: Mutex::new(Some(rx)),
+            readings: AtomicU64::new(0),
+            batches: AtomicU64::new(0),
+            throttled_rate: AtomicU64::new(0),
+            throttled_queue: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let mut devices = Vec::new();
+        for entry in std::env::var("CHAT_DEVICE_TOKENS").unwrap_or_default().split(',') {
+            match entry.split_once('=') {
+                Some((device, token)) if !device.trim().is_empty() && !token.trim().is_empty() => {
+                    devices.push((device.trim().to_string(), token.trim().to_string()));
+                }
+                _ if entry.trim().is_empty() => {}
+                _ => println!("chat-service: ignoring malformed device token entry for {:?}", entry.split('=').next()),
+            }
+        }
+        let env = |name: &str, default: usize| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        Self::new(
+            devices,
+            Limit::from_env("CHAT_TELEMETRY_RATE", Limit::new(20, Duration::from_secs(1))),
+            env("CHAT_TELEMETRY_MAX_BATCH", 1000),
+            env("CHAT_TELEMETRY_QUEUE", 256),
+        )
+    }
+
+    /// The device a token belongs to. Every entry is compared in full so
+    /// timing does not tell how close a guess was.
+    fn device(&self, token: &str) -> Option<String> {
+        let mut found = None;
+        for (device, expected) in &self.devices {
+            let same = expected.len() == token.len()
+                && expected.bytes().zip(token.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0;
+            if same {
+                found = Some(device.clone());
+            }
+        }
+        found
+    }
+
+    pub fn credit(&self) -> usize {
+        self.queue.capacity()
+    }
+
+    pub fn evict(&self) {
+        self.rate.retain_recent();
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE chat_telemetry_readings_total counter\n");
+        let _ = writeln!(out, "chat_telemetry_readings_total {}", self.readings.load(Ordering::Relaxed));
+        out.push_str("# TYPE chat_telemetry_batches_total counter\n");
+        let _ = writeln!(out, "chat_telemetry_batches_total {}", self.batches.load(Ordering::Relaxed));
+        out.push_str("# TYPE chat_telemetry_throttled_total counter\n");
+        for (reason, count) in [("rate", &self.throttled_rate), ("queue", &self.throttled_queue)] {
+            let _ = writeln!(out, "chat_telemetry_throttled_total{{reason=\"{}\"}} {}", reason, count.load(Ordering::Relaxed));
+        }
+        out.push_str("# TYPE chat_telemetry_queue_depth gauge\n");
+        let _ = writeln!(out, "chat_telemetry_queue_depth {}", self.queue.max_capacity() - self.queue.capacity());
+        out
+    }
+}
+
+/// Why a batch was refused before queueing.
+enum Refused {
+    Invalid(String),
+    RateLimited(Duration),
+}
+
+fn check_batch(ingest: &Ingest, device: &str, batch: &TelemetryBatch) -> Result<(), Refused> {
+    if batch.readings.is_empty() {
+        return Err(Refused::Invalid("batch has no readings".into()));
+    }
+    if batch.readings.len() > ingest.max_batch {
+        return Err(Refused::Invalid(format!("batch has more than {} readings", ingest.max_batch)));
+    }
+    let latest = Utc::now() + MAX_CLOCK_SKEW;
+    if batch.readings.iter().any(|r| r.ts > latest) {
+        return Err(Refused::Invalid("reading timestamp is in the future".into()));
+    }
+    if let Err(wait) = ingest.rate.check(&device.to_string()) {
+        ingest.throttled_rate.fetch_add(1, Ordering::Relaxed);
+        return Err(Refused::RateLimited(wait));
+    }
+    Ok(())
+}
+
+async fn wait_written(
+    rx: oneshot::Receiver<Result<(u64, u64), String>>,
+    accepted: usize,
+    ingest: &Ingest,
+) -> Result<TelemetryAck, String> {
+    match rx.await {
+        Ok(Ok((first_seq, last_seq))) => Ok(TelemetryAck { accepted, first_seq, last_seq, credit: ingest.credit() }),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err("telemetry writer stopped".into()),
+    }
+}
+
+fn retry_after(status: StatusCode, wait: Duration, message: &str) -> Response {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let body = ApiError { message: message.into() };
+    (status, [(header::RETRY_AFTER, secs.max(1).to_string())], Json(body)).into_response()
+}
+
+#[utoipa::path(post, path = "/telemetry", tag = "telemetry",
+    request_body = TelemetryBatch,
+    security(("bearer" = [])),
+    responses((status = 202, body = TelemetryAck), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 429, body = ApiError, headers(("Retry-After" = u64, description = "seconds to wait"))),
+        (status = 503, body = ApiError, headers(("Retry-After" = u64, description = "seconds to wait")))))]
+pub async fn ingest(State(state): State<AppState>, headers: HeaderMap, Json(batch): Json<TelemetryBatch>) -> Response {
+    let ingest = &state.telemetry;
+    let token = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer "));
+    let Some(device) = token.and_then(|t| ingest.device(t)) else {
+        return api_error(StatusCode::UNAUTHORIZED, "unknown device token").into_response();
+    };
+
+    match check_batch(ingest, &device, &batch) {
+        Ok(()) => {}
+        Err(Refused::Invalid(message)) => return api_error(StatusCode::BAD_REQUEST, &message).into_response(),
+        Err(Refused::RateLimited(wait)) => {
+            return retry_after(StatusCode::TOO_MANY_REQUESTS, wait, "device rate limit exceeded, retry later");
+        }
+    }
+
+    let accepted = batch.readings.len();
+    let (done, rx) = oneshot::channel();
+    if ingest.queue.try_send(QueuedBatch { device, readings: batch.readings, done }).is_err() {
+        ingest.throttled_queue.fetch_add(1, Ordering::Relaxed);
+        return retry_after(StatusCode::SERVICE_UNAVAILABLE, Duration::from_secs(1), "ingest queue full, retry later");
+    }
+    match wait_written(rx, accepted, ingest).await {
+        Ok(ack) => (StatusCode::ACCEPTED, Json(ack)).into_response(),
+        Err(message) => api_error(StatusCode::SERVICE_UNAVAILABLE, &message).into_response(),
+    }
+}
+
+/// ws://host:9300/telemetry, after the handshake checked the device token.
+pub async fn handle_ws<S>(ws: tokio_tungstenite::WebSocketStream<S>, state: AppState, device: String)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let ingest = &state.telemetry;
+    let (mut write, mut read) = ws.split();
+    let reply = |r: WsReply| Message::Text(serde_json::to_string(&r).unwrap());
+
+    // one frame at a time: while a batch waits for queue room nothing
+    // else is read, which is the backpressure
+    while let Some(Ok(msg)) = read.next().await {
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let batch: TelemetryBatch = match serde_json::from_str(&text) {
+            Ok(b) => b,
+            Err(e) => {
+                let _ = write.send(reply(WsReply::Error { message: format!("invalid batch: {}", e) })).await;
+                continue;
+            }
+        };
+        let refused = match check_batch(ingest, &device, &batch) {
+            Ok(()) => None,
+            Err(Refused::Invalid(message)) => Some(WsReply::Error { message }),
+            Err(Refused::RateLimited(wait)) => Some(WsReply::SlowDown { retry_after_ms: wait.as_millis() as u64 }),
+        };
+        if let Some(r) = refused {
+            let _ = write.send(reply(r)).await;
+            continue;
+        }
+
+        let accepted = batch.readings.len();
+        let (done, rx) = oneshot::channel();
+        let queued_batch = QueuedBatch { device: device.clone(), readings: batch.readings, done };
+        let queued = match ingest.queue.try_send(queued_batch) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(queued_batch)) => {
+                ingest.throttled_queue.fetch_add(1, Ordering::Relaxed);
+                let _ = write.send(reply(WsReply::SlowDown { retry_after_ms: 1000 })).await;
+                ingest.queue.send(queued_batch).await.is_ok()
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        };
+        let r = match queued {
+            true => match wait_written(rx, accepted, ingest).await {
+                Ok(ack) => WsReply::Ack(ack),
+                Err(message) => WsReply::Error { message },
+            },
+            false => WsReply::Error { message: "telemetry writer stopped".into() },
+        };
+        if write.send(reply(r)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Checks the device token on the WebSocket upgrade request.
+pub fn ws_device(state: &AppState, headers: &tungstenite::http::HeaderMap) -> Option<String> {
+    let token = headers.get("authorization")?.to_str().ok()?.strip_prefix("Bearer ")?;
+    state.telemetry.device(token)
+}
+
+fn write(conn: &mut Connection, batches: &[QueuedBatch]) -> rusqlite::Result<Vec<(u64, u64)>> {
+    let received_at = Utc::now().to_rfc3339();
+    let tx = conn.transaction()?;
+    let mut seqs = Vec::with_capacity(batches.len());
+    for w in batches {
+        let last: i64 =
+            tx.query_row("SELECT COALESCE(MAX(seq), 0) FROM telemetry WHERE device = ?1", [&w.device], |r| r.get(0))?;
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO telemetry (device, seq, device_ts, received_at, data) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for (i, reading) in w.readings.iter().enumerate() {
+            stmt.execute(params![w.device, last + 1 + i as i64, reading.ts.to_rfc3339(), received_at, reading.data.to_string()])?;
+        }
+        seqs.push((last as u64 + 1, last as u64 + w.readings.len() as u64));
+    }
+    tx.commit()?;
+    Ok(seqs)
+}
+
+/// Commits queued batches, up to WRITE_BATCH per transaction.
+pub async fn writer_loop(state: AppState) {
+    let Some(mut rx) = state.telemetry.receiver.lock().unwrap().take() else { return };
+    let mut batches = Vec::with_capacity(WRITE_BATCH);
+
+    while rx.recv_many(&mut batches, WRITE_BATCH).await > 0 {
+        let written = write(&mut state.db.lock().unwrap(), &batches);
+        if let Err(e) = &written {
+            println!("chat-service: telemetry write of {} batch(es) failed: {}", batches.len(), e);
+        }
+        for (i, b) in batches.drain(..).enumerate() {
+            let result = match &written {
+                Ok(seqs) => {
+                    state.telemetry.batches.fetch_add(1, Ordering::Relaxed);
+                    state.telemetry.readings.fetch_add(b.readings.len() as u64, Ordering::Relaxed);
+                    Ok(seqs[i])
+                }
+                Err(_) => Err("telemetry write failed".to_string()),
+            };
+            let _ = b.done.send(result);
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct StreamQuery {
+    /// Readings after this seq (default 0).
+    #[serde(default)]
+    pub after: u64,
+    /// At most this many (default 100, max 1000).
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StoredReading {
+    pub seq: u64,
+    pub ts: String,
+    pub received_at: String,
+    #[schema(value_type = Object)]
+    pub data: serde_json::Value,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TelemetryStream {
+    pub device: String,
+    pub readings: Vec<StoredReading>,
+}
+
+#[utoipa::path(get, path = "/telemetry/{device}", tag = "telemetry",
+    params(("device" = String, Path), StreamQuery),
+    security(("bearer" = [])),
+    responses((status = 200, body = TelemetryStream), (status = 401, body = ApiError), (status = 403, body = ApiError)))]
+pub async fn stream(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(device): Path<String>,
+    Query(q): Query<StreamQuery>,
+) -> Result<Json<TelemetryStream>, ApiFailure> {
+    let claims = bearer_claims(&state, &headers).ok_or_else(|| api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"))?;
+    if !state.admins.contains(&claims.sub) {
+        return Err(api_error(StatusCode::FORBIDDEN, "admins only"));
+    }
+
+    let db = state.db.lock().unwrap();
+    let mut stmt = db
+        .prepare(
+            "SELECT seq, device_ts, received_at, data FROM telemetry
+             WHERE device = ?1 AND seq > ?2 ORDER BY seq LIMIT ?3",
+        )
+        .unwrap();
+    let readings = stmt
+        .query_map(params![device, q.after as i64, q.limit.unwrap_or(100).min(1000)], |r| {
+            Ok(StoredReading {
+                seq: r.get::<_, i64>(0)? as u64,
+                ts: r.get(1)?,
+                received_at: r.get(2)?,
+                data: serde_json::from_str(&r.get::<_, String>(3)?).unwrap_or_default(),
+            })
+        })
+        .unwrap()
+        .filter_map(Result::ok)
+        .collect();
+    Ok(Json(TelemetryStream { device, readings }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_core::ratelimit::Limit;
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use super::{writer_loop, Ingest};
+    use crate::testing::call_as;
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn telemetry_matches_schema() {
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        let devices = vec![("thermo-1".to_string(), "device-secret".to_string())];
+        state.telemetry = Arc::new(Ingest::new(devices, Limit::new(2, Duration::from_secs(60)), 3, 8));
+        state.admins = Arc::new(vec!["root".to_string()]);
+        tokio::spawn(writer_loop(state.clone()));
+        let app = router(state);
+
+        let reading = |t: f64| json!({ "ts": "2024-05-01T12:00:00Z", "data": { "temp": t } });
+        let batch = json!({ "readings": [reading(20.5), reading(20.7)] });
+        let (status, _) = call_as(&app, Some("wrong"), "POST", "/telemetry", "/telemetry", Some(batch.clone())).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, ack) = call_as(&app, Some("device-secret"), "POST", "/telemetry", "/telemetry", Some(batch.clone())).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!((ack["accepted"].as_u64(), ack["first_seq"].as_u64(), ack["last_seq"].as_u64()), (Some(2), Some(1), Some(2)));
+
+        let too_big = json!({ "readings": [reading(1.0), reading(2.0), reading(3.0), reading(4.0)] });
+        let (status, _) = call_as(&app, Some("device-secret"), "POST", "/telemetry", "/telemetry", Some(too_big)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        call_as(&app, Some("device-secret"), "POST", "/telemetry", "/telemetry", Some(batch.clone())).await;
+        let (status, _) = call_as(&app, Some("device-secret"), "POST", "/telemetry", "/telemetry", Some(batch)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let root = create_token(&secret_from_env(), "root");
+        let bob = create_token(&secret_from_env(), "bob");
+        let (status, _) = call_as(&app, Some(&bob), "GET", "/telemetry/thermo-1", "/telemetry/{device}", None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, stream) = call_as(&app, Some(&root), "GET", "/telemetry/thermo-1?after=2", "/telemetry/{device}", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stream["readings"].as_array().unwrap().len(), 2);
+        assert_eq!(stream["readings"][0]["seq"], 3);
+        assert_eq!(stream["readings"][0]["data"]["temp"], 20.5);
+    }
+}
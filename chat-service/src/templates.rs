@@ -0,0 +1,242 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use uchat_proto::errors::ApiError;
+
+use crate::audit;
+use crate::auth::{api_error, bearer_claims, ApiFailure};
+use crate::rooms::{self, ClonePart, RoomInfo};
+use crate::AppState;
+
+//
+// ROOM TEMPLATES
+//
+// Recurring rooms (an incident's war room, a release channel) are set up
+// the same way every time. Admins name a room as a template with PUT
+// /room-templates/{name}, along with the parts of it to copy (see
+// `ClonePart`); anyone with a token then creates a room from it with
+// POST /room-templates/{name}/rooms, which is POST /rooms/{id}/clone of
+// the template's room without needing access to it. Changing the
+// template room's settings changes what later rooms start with.
+//
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct RoomTemplate {
+    pub name: String,
+    /// The room copied.
+    pub room: String,
+    pub include: Vec<ClonePart>,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RoomTemplates {
+    pub templates: Vec<RoomTemplate>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct PutTemplate {
+    pub room: String,
+    /// What to copy besides the kind; everything when absent.
+    pub include: Option<Vec<ClonePart>>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct FromTemplate {
+    /// Id of the new room.
+    pub id: String,
+}
+
+fn row(r: &rusqlite::Row) -> rusqlite::Result<RoomTemplate> {
+    Ok(RoomTemplate {
+        name: r.get(0)?,
+        room: r.get(1)?,
+        include: serde_json::from_str(&r.get::<_, String>(2)?).unwrap_or_default(),
+        created_by: r.get(3)?,
+        created_at: r.get(4)?,
+    })
+}
+
+fn get(conn: &Connection, name: &str) -> Option<RoomTemplate> {
+    conn.query_row(
+        "SELECT name, room, include, created_by, created_at FROM room_templates WHERE name = ?1",
+        [name],
+        row,
+    )
+    .optional()
+    .unwrap()
+}
+
+/// The caller, if an admin.
+fn authorize_admin(state: &AppState, headers: &HeaderMap) -> Result<String, ApiFailure> {
+    let Some(claims) = bearer_claims(state, headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if !state.admins.contains(&claims.sub) {
+        return Err(api_error(StatusCode::FORBIDDEN, "only admins may manage room templates"));
+    }
+    Ok(claims.sub)
+}
+
+#[utoipa::path(get, path = "/room-templates", tag = "rooms",
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomTemplates), (status = 401, body = ApiError)))]
+pub async fn list(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<RoomTemplates>, ApiFailure> {
+    if bearer_claims(&state, &headers).is_none() {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    }
+    let db = state.db.lock().unwrap();
+    let mut stmt =
+        db.prepare("SELECT name, room, include, created_by, created_at FROM room_templates ORDER BY name").unwrap();
+    let templates = stmt.query_map([], row).unwrap().filter_map(|r| r.ok()).collect();
+    Ok(Json(RoomTemplates { templates }))
+}
+
+#[utoipa::path(put, path = "/room-templates/{name}", tag = "rooms",
+    params(("name" = String, Path)),
+    request_body = PutTemplate,
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomTemplate), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 403, body = ApiError)))]
+pub async fn put(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(body): Json<PutTemplate>,
+) -> Result<Json<RoomTemplate>, ApiFailure> {
+    let actor = authorize_admin(&state, &headers)?;
+    if !rooms::valid_id(&name) || !rooms::valid_id(&body.room) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "template names and room ids are lowercase slugs"));
+    }
+    let include = body.include.unwrap_or_else(|| ClonePart::ALL.to_vec());
+
+    let db = state.db.lock().unwrap();
+    db.execute(
+        "INSERT INTO room_templates (name, room, include, created_by, created_at) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(name) DO UPDATE SET room = ?2, include = ?3, created_by = ?4, created_at = ?5",
+        params![name, body.room, serde_json::to_string(&include).unwrap(), actor, Utc::now().to_rfc3339()],
+    )
+    .unwrap();
+    audit::record(&db, "room_template.put", &actor, &name, body.room);
+    Ok(Json(get(&db, &name).unwrap()))
+}
+
+#[utoipa::path(delete, path = "/room-templates/{name}", tag = "rooms",
+    params(("name" = String, Path)),
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomTemplate), (status = 401, body = ApiError), (status = 403, body = ApiError),
+        (status = 404, body = ApiError)))]
+pub async fn delete(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<Json<RoomTemplate>, ApiFailure> {
+    let actor = authorize_admin(&state, &headers)?;
+    let db = state.db.lock().unwrap();
+    let Some(template) = get(&db, &name) else {
+        return Err(api_error(StatusCode::NOT_FOUND, "no such template"));
+    };
+    db.execute("DELETE FROM room_templates WHERE name = ?1", [&name]).unwrap();
+    audit::record(&db, "room_template.delete", &actor, &name, "");
+    Ok(Json(template))
+}
+
+#[utoipa::path(post, path = "/room-templates/{name}/rooms", tag = "rooms",
+    params(("name" = String, Path)),
+    request_body = FromTemplate,
+    security(("bearer" = [])),
+    responses((status = 200, body = RoomInfo), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 404, body = ApiError), (status = 409, body = ApiError)))]
+pub async fn create_room(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(body): Json<FromTemplate>,
+) -> Result<Json<RoomInfo>, ApiFailure> {
+    let Some(claims) = bearer_claims(&state, &headers) else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    let mut db = state.db.lock().unwrap();
+    let Some(template) = get(&db, &name) else {
+        return Err(api_error(StatusCode::NOT_FOUND, "no such template"));
+    };
+    rooms::copy_room(&mut db, &state.acl, &template.room, &body.id, &template.include, &claims.sub).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use uchat_proto::jwt::{create_token, create_token_with_groups, secret_from_env};
+
+    use crate::testing::call_as;
+    use crate::{db, router, AppState};
+
+    #[tokio::test]
+    async fn rooms_clone_and_templates_match_schema() {
+        let mut state = AppState::new(db::open_path(":memory:").unwrap());
+        state.admins = Arc::new(vec!["root".to_string()]);
+        let app = router(state);
+        let root = create_token(&secret_from_env(), "root");
+        let ann = create_token(&secret_from_env(), "ann");
+        let sre = create_token_with_groups(&secret_from_env(), "sam", vec!["sre".into()]);
+
+        call_as(&app, Some(&sre), "POST", "/rooms", "/rooms",
+            Some(json!({ "id": "incident", "kind": "private", "groups": ["sre"], "compliance": true }))).await;
+        call_as(&app, Some(&sre), "PUT", "/rooms/incident/profanity", "/rooms/{id}/profanity",
+            Some(json!({ "enabled": true }))).await;
+
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/rooms/incident/clone", "/rooms/{id}/clone",
+            Some(json!({ "id": "copy" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, room) = call_as(&app, Some(&sre), "POST", "/rooms/incident/clone", "/rooms/{id}/clone",
+            Some(json!({ "id": "incident-2" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((&room["kind"], &room["groups"]), (&json!("private"), &json!(["sre"])));
+        assert_eq!(room["compliance"], true);
+        let (_, masking) =
+            call_as(&app, Some(&sre), "GET", "/rooms/incident-2/profanity", "/rooms/{id}/profanity", None).await;
+        assert_eq!(masking["updated_by"], "sam");
+        let (status, _) = call_as(&app, Some(&sre), "POST", "/rooms/incident/clone", "/rooms/{id}/clone",
+            Some(json!({ "id": "incident-3", "include": ["compliance"] }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call_as(&app, Some(&sre), "POST", "/rooms/incident/clone", "/rooms/{id}/clone",
+            Some(json!({ "id": "incident-2" }))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let template = json!({ "room": "incident", "include": ["groups"] });
+        let (status, _) = call_as(&app, Some(&ann), "PUT", "/room-templates/war-room", "/room-templates/{name}",
+            Some(template.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = call_as(&app, Some(&root), "PUT", "/room-templates/war-room", "/room-templates/{name}",
+            Some(template)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, list) = call_as(&app, Some(&ann), "GET", "/room-templates", "/room-templates", None).await;
+        assert_eq!(list["templates"][0]["include"], json!(["groups"]));
+
+        // anyone may start a room from a template, even one they cannot read
+        let (status, room) = call_as(&app, Some(&ann), "POST", "/room-templates/war-room/rooms",
+            "/room-templates/{name}/rooms", Some(json!({ "id": "war-room-1" }))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!((&room["groups"], &room["compliance"]), (&json!(["sre"]), &json!(false)));
+        assert_eq!(room["created_by"], "ann");
+
+        let (status, _) = call_as(&app, Some(&root), "DELETE", "/room-templates/war-room", "/room-templates/{name}",
+            None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call_as(&app, Some(&ann), "POST", "/room-templates/war-room/rooms",
+            "/room-templates/{name}/rooms", Some(json!({ "id": "war-room-2" }))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
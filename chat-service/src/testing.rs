@@ -0,0 +1,126 @@
+//! Helpers for the contract tests, which sit next to each feature: they
+//! drive the real router and check every response body against the schema
+//! published for that route and status (see openapi.rs).
+
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use serde_json::Value;
+use tower::ServiceExt;
+
+use crate::openapi::ApiDoc;
+use crate::{db, router, AppState};
+
+fn spec() -> Value {
+    serde_json::to_value(ApiDoc::openapi_doc()).unwrap()
+}
+
+fn resolve<'a>(spec: &'a Value, schema: &'a Value) -> &'a Value {
+    match schema.get("$ref").and_then(Value::as_str) {
+        Some(r) => {
+            let name = r.trim_start_matches("#/components/schemas/");
+            &spec["components"]["schemas"][name]
+        }
+        None => schema,
+    }
+}
+
+fn type_matches(ty: &str, value: &Value) -> bool {
+    match ty {
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn validate(spec: &Value, schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let schema = resolve(spec, schema);
+
+    if let Some(options) = schema.get("oneOf").and_then(Value::as_array) {
+        return match options.iter().any(|s| validate(spec, s, value, path).is_ok()) {
+            true => Ok(()),
+            false => Err(format!("{}: matches no oneOf branch", path)),
+        };
+    }
+
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(t) => vec![t.as_str()],
+        Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    if !types.is_empty() && !types.iter().any(|t| type_matches(t, value)) {
+        return Err(format!("{}: expected {:?}, got {}", path, types, value));
+    }
+
+    if let (Some(props), Some(obj)) = (schema.get("properties").and_then(Value::as_object), value.as_object()) {
+        for required in schema["required"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+            if !obj.contains_key(required) {
+                return Err(format!("{}: missing required field {}", path, required));
+            }
+        }
+        for (key, field) in obj {
+            match props.get(key) {
+                Some(s) => validate(spec, s, field, &format!("{}.{}", path, key))?,
+                None => return Err(format!("{}: undocumented field {}", path, key)),
+            }
+        }
+    }
+
+    if let (Some(items), Some(arr)) = (schema.get("items"), value.as_array()) {
+        for (i, item) in arr.iter().enumerate() {
+            validate(spec, items, item, &format!("{}[{}]", path, i))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Calls the router and validates the reply against the documented
+/// schema for (`route`, method, status).
+pub async fn call(app: &axum::Router, method: &str, uri: &str, route: &str, body: Option<Value>) -> (StatusCode, Value) {
+    call_as(app, None, method, uri, route, body).await
+}
+
+pub async fn call_as(
+    app: &axum::Router,
+    token: Option<&str>,
+    method: &str,
+    uri: &str,
+    route: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let mut req = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        req = req.header("authorization", format!("Bearer {}", token));
+    }
+    let req = match body {
+        Some(b) => req.header("content-type", "application/json").body(Body::from(b.to_string())),
+        None => req.body(Body::empty()),
+    }
+    .unwrap();
+
+    let resp = app.clone().oneshot(req).await.unwrap();
+    let status = resp.status();
+    let bytes = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let value: Value = serde_json::from_slice(&bytes).unwrap();
+
+    let spec = spec();
+    let op = &spec["paths"][route][method.to_lowercase()];
+    assert!(op.is_object(), "{} {} is not documented", method, route);
+    let response = &op["responses"][status.as_str()];
+    assert!(response.is_object(), "{} {} does not document status {}", method, route, status);
+    let schema = &response["content"]["application/json"]["schema"];
+
+    if let Err(e) = validate(&spec, schema, &value, "$") {
+        panic!("{} {} ({}): {}\nbody: {}", method, route, status, e, value);
+    }
+    (status, value)
+}
+
+pub fn app() -> axum::Router {
+    router(AppState::new(db::open_path(":memory:").unwrap()))
+}
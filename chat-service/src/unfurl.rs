@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use chrono::Utc;
+use reqwest::Url;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use uchat_proto::events::ServerEvent;
+
+use crate::leader::Lease;
+use crate::outbox;
+use crate::AppState;
+
+//
+// LINK PREVIEWS
+//
+// Sending a message that contains URLs queues one `link_previews` row per
+// URL in the same transaction as the message. A background worker fetches
+// each page and reads its title, description and image from the
+// OpenGraph/HTML meta tags. The result is stored on the row and published
+// through the outbox as a MessageUnfurled event, so live clients can
+// attach it to a message they already show.
+//
+// Fetches are treated as hostile:
+// - only http(s) on the default ports is fetched;
+// - every hop must resolve to public addresses only, and the connection is
+//   pinned to the address that was checked, so a DNS answer cannot change
+//   between the check and the connect;
+// - redirects are followed by hand and re-checked at each hop;
+// - responses must be HTML and are capped in size and time.
+//
+// A failed fetch is retried after RETRY_BACKOFF, doubling with each
+// attempt, and given up after MAX_ATTEMPTS.
+//
+
+const MAX_URLS_PER_MESSAGE: usize = 3;
+const MAX_REDIRECTS: usize = 3;
+const MAX_ATTEMPTS: i64 = 3;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Domain policy for previews (`UNFURL_ALLOW_DOMAINS`,
+/// `UNFURL_DENY_DOMAINS`); entries match the domain and its subdomains.
+pub struct UnfurlPolicy {
+    pub enabled: bool,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    max_bytes: usize,
+}
+
+fn domain_list(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(|d| d.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+impl UnfurlPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: std::env::var("UNFURL_ENABLED").map(|v| v != "0" && v != "false").unwrap_or(true),
+            allow: domain_list("UNFURL_ALLOW_DOMAINS"),
+            deny: domain_list("UNFURL_DENY_DOMAINS"),
+            max_bytes: std::env::var("UNFURL_MAX_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(512 * 1024),
+        }
+    }
+
+    /// Whether `url` may be fetched at all: scheme, port and domain lists.
+    /// Address checks happen at fetch time.
+    fn permits(&self, url: &Url) -> bool {
+        if !matches!(url.scheme(), "http" | "https") || url.port().is_some() {
+            return false;
+        }
+        let Some(host) = url.host_str().map(str::to_ascii_lowercase) else { return false };
+
+        let matches = |d: &String| host == *d || host.ends_with(&format!(".{}", d));
+        if self.deny.iter().any(matches) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(matches)
+    }
+}
+
+/// A fetched preview, as stored and as returned with `/messages`.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Absolute URL of the page's preview image.
+    pub image: Option<String>,
+}
+
+/// http(s) URLs in a message, in order, deduplicated.
+pub fn extract_urls(text: &str) -> Vec<Url> {
+    let mut urls: Vec<Url> = Vec::new();
+    for word in text.split_whitespace() {
+        let word = word.trim_start_matches(['(', '<', '"', '\'']);
+        if !(word.starts_with("http://") || word.starts_with("https://")) {
+            continue;
+        }
+        let word = word.trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>', '"', '\'']);
+        if let Ok(url) = Url::parse(word) {
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        if urls.len() == MAX_URLS_PER_MESSAGE {
+            break;
+        }
+    }
+    urls
+}
+
+/// Queues previews for the URLs in `text`; call with the transaction that
+/// inserted message `message_id`. Returns how many were queued.
+pub fn enqueue(conn: &Connection, policy: &UnfurlPolicy, message_id: i64, text: &str) -> rusqlite::Result<usize> {
+    if !policy.enabled {
+        return Ok(0);
+    }
+
+    let mut queued = 0;
+    for url in extract_urls(text).into_iter().filter(|u| policy.permits(u)) {
+        conn.execute(
+            "INSERT INTO link_previews (message_id, url, status, created_at) VALUES (?1, ?2, 'pending', ?3)",
+            params![message_id, url.as_str(), Utc::now().to_rfc3339()],
+        )?;
+        queued += 1;
+    }
+    Ok(queued)
+}
+
+/// Fetched previews of `room`'s messages, keyed by message id.
+pub fn fetched(conn: &Connection, room: &str) -> HashMap<i64, Vec<LinkPreview>> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT l.message_id, l.url, l.title, l.description, l.image
+             FROM link_previews l JOIN messages m ON m.id = l.message_id
+             WHERE m.room = ?1 AND l.status = 'done' ORDER BY l.id ASC",
+        )
+        .unwrap();
+
+    let mut out: HashMap<i64, Vec<LinkPreview>> = HashMap::new();
+    let rows = stmt
+        .query_map([room], |r| {
+            Ok((r.get::<_, i64>(0)?, LinkPreview {
+                url: r.get(1)?,
+                title: r.get(2)?,
+                description: r.get(3)?,
+                image: r.get(4)?,
+            }))
+        })
+        .unwrap();
+    for (message_id, preview) in rows.filter_map(|r| r.ok()) {
+        out.entry(message_id).or_default().push(preview);
+    }
+    out
+}
+
+#[derive(Debug)]
+enum FetchError {
+    /// Policy said no; never retried.
+    Blocked(String),
+    /// Network trouble or a bad response; retried up to `MAX_ATTEMPTS`.
+    Failed(String),
+}
+
+/// Addresses a preview fetch may connect to.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            !(v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || v4.is_documentation()
+                || v4.is_unspecified()
+                || v4.is_multicast()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)) // carrier-grade NAT
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (b == 18 || b == 19)) // benchmarking
+                || a >= 240)
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(v4));
+            }
+            let [first, second, ..] = v6.segments();
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80 // link local
+                || (first == 0x64 && second == 0xff9b) // NAT64, well-known and local-use
+                || first == 0x2002) // 6to4
+        }
+    }
+}
+
+/// Resolves `url`'s host and picks an address, refusing hosts that resolve
+/// to anything non-public.
+async fn resolve_public(url: &Url) -> Result<SocketAddr, FetchError> {
+    let host = url.host_str().ok_or_else(|| FetchError::Blocked("no host".into()))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| FetchError::Failed(format!("resolve {}: {}", host, e)))?
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(FetchError::Failed(format!("resolve {}: no addresses", host)));
+    }
+    if let Some(bad) = addrs.iter().find(|a| !is_public(a.ip())) {
+        return Err(FetchError::Blocked(format!("{} resolves to {}", host, bad.ip())));
+    }
+    Ok(addrs[0])
+}
+
+async fn fetch(policy: &UnfurlPolicy, start: &Url) -> Result<LinkPreview, FetchError> {
+    let mut url = start.clone();
+
+    for _ in 0..=MAX_REDIRECTS {
+        if !policy.permits(&url) {
+            return Err(FetchError::Blocked(format!("{} not allowed", url)));
+        }
+        let addr = resolve_public(&url).await?;
+
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .timeout(FETCH_TIMEOUT)
+            .resolve(url.host_str().unwrap(), addr)
+            .user_agent("uchat-unfurl/0.1")
+            .build()
+            .unwrap();
+
+        let mut resp = client
+            .get(url.clone())
+            .header("Accept", "text/html")
+            .send()
+            .await
+            .map_err(|e| FetchError::Failed(e.to_string()))?;
+
+        if resp.status().is_redirection() {
+            let location = resp
+                .headers()
+                .get("location")
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| FetchError::Failed("redirect without location".into()))?;
+            url = url.join(location).map_err(|e| FetchError::Failed(e.to_string()))?;
+            continue;
+        }
+        if !resp.status().is_success() {
+            return Err(FetchError::Failed(resp.status().to_string()));
+        }
+
+        let html = resp
+            .headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|ct| ct.starts_with("text/html") || ct.starts_with("application/xhtml"));
+        if !html {
+            return Err(FetchError::Blocked("not an html page".into()));
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(|e| FetchError::Failed(e.to_string()))? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= policy.max_bytes {
+                body.truncate(policy.max_bytes);
+                break;
+            }
+        }
+
+        return Ok(parse_meta(&url, &String::from_utf8_lossy(&body)));
+    }
+
+    Err(FetchError::Failed("too many redirects".into()))
+}
+
+//
+// HTML META PARSING
+//
+// Deliberately small: previews only need a handful of tags from <head>, so
+// this scans tags instead of building a DOM.
+//
+
+fn decode_entities(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn clean(s: &str, max_chars: usize) -> Option<String> {
+    let text = decode_entities(s).split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        return None;
+    }
+    Some(text.chars().take(max_chars).collect())
+}
+
+/// Attribute values of one tag body (`meta property="og:title" ...`).
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    let mut rest = tag;
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq].split_whitespace().last().unwrap_or("").to_ascii_lowercase();
+        let after = rest[eq + 1..].trim_start();
+        let (value, remaining) = match after.chars().next() {
+            Some(q @ ('"' | '\'')) => match after[1..].find(q) {
+                Some(end) => (&after[1..end + 1], &after[end + 2..]),
+                None => (&after[1..], ""),
+            },
+            _ => {
+                let end = after.find(char::is_whitespace).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        out.push((name, value.to_string()));
+        rest = remaining;
+    }
+    out
+}
+
+fn parse_meta(page: &Url, html: &str) -> LinkPreview {
+    let lower = html.to_ascii_lowercase();
+    let head_end = lower.find("</head>").unwrap_or(lower.len());
+
+    let mut meta = HashMap::new();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..head_end].find("<meta") {
+        let start = pos + start;
+        let Some(end) = lower[start..].find('>') else { break };
+        let attrs = attributes(&html[start + 5..start + end]);
+        let key = attrs.iter().find(|(n, _)| n == "property" || n == "name").map(|(_, v)| v.to_ascii_lowercase());
+        let content = attrs.iter().find(|(n, _)| n == "content").map(|(_, v)| v.clone());
+        if let (Some(key), Some(content)) = (key, content) {
+            meta.entry(key).or_insert(content);
+        }
+        pos = start + end;
+    }
+
+    let title_tag = lower[..head_end].find("<title").and_then(|start| {
+        let open_end = start + lower[start..].find('>')? + 1;
+        let close = open_end + lower[open_end..].find("</title")?;
+        Some(html[open_end..close].to_string())
+    });
+
+    let pick = |keys: &[&str]| keys.iter().find_map(|k| meta.get(*k).cloned());
+    let image = pick(&["og:image", "og:image:url", "twitter:image"])
+        .and_then(|src| page.join(decode_entities(&src).trim()).ok())
+        .filter(|u| matches!(u.scheme(), "http" | "https"))
+        .map(String::from);
+
+    LinkPreview {
+        url: page.to_string(),
+        title: pick(&["og:title", "twitter:title"]).or(title_tag).and_then(|t| clean(&t, 200)),
+        description: pick(&["og:description", "twitter:description", "description"]).and_then(|d| clean(&d, 500)),
+        image,
+    }
+}
+
+//
+// WORKER
+//
+
+struct Job {
+    id: i64,
+    message_id: i64,
+    url: String,
+    attempts: i64,
+}
+
+/// Jobs due now; failed ones wait out their backoff.
+fn pending(conn: &Connection) -> Vec<Job> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, message_id, url, attempts FROM link_previews
+             WHERE status = 'pending' AND (retry_at IS NULL OR retry_at <= ?1) ORDER BY id ASC LIMIT 10",
+        )
+        .unwrap();
+
+    stmt.query_map([Utc::now().to_rfc3339()], |r| {
+        Ok(Job { id: r.get(0)?, message_id: r.get(1)?, url: r.get(2)?, attempts: r.get(3)? })
+    })
+        .unwrap()
+        .filter_map(|r| r.ok())
+        .collect()
+}
+
+/// When a job that failed after `attempts` earlier tries is next due.
+fn retry_at(attempts: i64) -> chrono::DateTime<Utc> {
+    let backoff = RETRY_BACKOFF * 2u32.pow(attempts.clamp(0, 10) as u32);
+    Utc::now() + chrono::Duration::from_std(backoff).unwrap()
+}
+
+/// Works the preview queue, woken by `state.unfurl_notify` or a two-second
+/// tick. Only the instance holding the `unfurl-worker` lease fetches.
+pub async fn worker_loop(state: AppState) {
+    let mut lease = Lease::new("unfurl-worker", Duration::from_secs(30));
+
+    loop {
+        tokio::select! {
+            _ = state.unfurl_notify.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(2)) => {}
+        }
+
+        if !lease.try_acquire(&state.db.lock().unwrap()) {
+            continue;
+        }
+
+        let jobs = pending(&state.db.lock().unwrap());
+        for job in jobs {
+            let Ok(url) = Url::parse(&job.url) else { continue };
+            let result = fetch(&state.unfurl, &url).await;
+
+            let mut db = state.db.lock().unwrap();
+            match result {
+                Ok(preview) => {
+                    let tx = db.transaction().unwrap();
+                    tx.execute(
+                        "UPDATE link_previews SET status = 'done', title = ?1, description = ?2, image = ?3,
+                         fetched_at = ?4, attempts = attempts + 1 WHERE id = ?5",
+                        params![preview.title, preview.description, preview.image, Utc::now().to_rfc3339(), job.id],
+                    )
+                    .unwrap();
+                    outbox::enqueue(&tx, outbox::TOPIC_UNFURL, &ServerEvent::MessageUnfurled {
+                        message_id: job.message_id,
+                        url: job.url.clone(),
+                        title: preview.title,
+                        description: preview.description,
+                        image: preview.image,
+                    })
+                    .unwrap();
+                    tx.commit().unwrap();
+                    state.outbox_notify.notify_one();
+                }
+                Err(FetchError::Blocked(reason)) => {
+                    println!("chat-service: unfurl {} blocked: {}", job.url, reason);
+                    db.execute(
+                        "UPDATE link_previews SET status = 'blocked', error = ?1 WHERE id = ?2",
+                        params![reason, job.id],
+                    )
+                    .unwrap();
+                }
+                Err(FetchError::Failed(reason)) => {
+                    let status = if job.attempts + 1 >= MAX_ATTEMPTS { "failed" } else { "pending" };
+                    println!("chat-service: unfurl {} failed: {}", job.url, reason);
+                    db.execute(
+                        "UPDATE link_previews SET status = ?1, error = ?2, attempts = attempts + 1, retry_at = ?3
+                         WHERE id = ?4",
+                        params![status, reason, retry_at(job.attempts).to_rfc3339(), job.id],
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use reqwest::Url;
+    use rusqlite::params;
+
+    use super::{is_public, pending, retry_at, UnfurlPolicy};
+    use crate::db;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn only_public_addresses_are_fetched() {
+        for public in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946", "::ffff:93.184.216.34"] {
+            assert!(is_public(ip(public)), "{}", public);
+        }
+        for internal in [
+            "127.0.0.1",
+            "::1",
+            "169.254.169.254",
+            "fe80::1",
+            "10.0.0.1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "64:ff9b::7f00:1",
+            "64:ff9b::5db8:d822",
+            "64:ff9b:1::a00:1",
+            "2002:7f00:1::1",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(!is_public(ip(internal)), "{}", internal);
+        }
+    }
+
+    #[test]
+    fn the_policy_takes_http_on_default_ports_from_allowed_domains() {
+        let policy = UnfurlPolicy {
+            enabled: true,
+            allow: Vec::new(),
+            deny: vec!["internal.example".into()],
+            max_bytes: 1024,
+        };
+        let permits = |url: &str| policy.permits(&Url::parse(url).unwrap());
+        assert!(permits("https://example.com/page"));
+        assert!(!permits("ftp://example.com/file"));
+        assert!(!permits("http://example.com:8080/"));
+        assert!(!permits("https://internal.example/"));
+        assert!(!permits("https://wiki.internal.example/"));
+
+        let allowlist = UnfurlPolicy { allow: vec!["example.com".into()], ..policy };
+        assert!(allowlist.permits(&Url::parse("https://www.example.com/").unwrap()));
+        assert!(!allowlist.permits(&Url::parse("https://example.org/").unwrap()));
+    }
+
+    #[test]
+    fn failed_fetches_back_off() {
+        let conn = db::open_path(":memory:").unwrap();
+        let now = chrono::Utc::now().to_rfc3339();
+        for retry in [None, Some(retry_at(0).to_rfc3339())] {
+            conn.execute(
+                "INSERT INTO link_previews (message_id, url, status, created_at, retry_at)
+                 VALUES (1, 'https://example.com/', 'pending', ?1, ?2)",
+                params![now, retry],
+            )
+            .unwrap();
+        }
+        let due: Vec<i64> = pending(&conn).iter().map(|j| j.id).collect();
+        assert_eq!(due, [1]);
+        assert!(retry_at(2) - retry_at(1) > chrono::Duration::seconds(50));
+    }
+}
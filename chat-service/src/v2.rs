@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use uchat_proto::errors::ApiError;
+
+use crate::auth::{api_error, ApiFailure};
+use crate::handlers::{self, OutgoingMessage};
+use crate::rooms;
+use crate::unfurl::LinkPreview;
+use crate::AppState;
+
+//
+// API v2 SHAPES
+//
+// What v2 changes against v1 (see versions.rs), mapped onto the shared
+// handler cores:
+//
+//   POST /v2/send      needs a token and sends as its subject; the body has
+//                      no `email`. Answers 201 with the stored message's id.
+//   GET /v2/messages   pages through history with `limit` (default 50, at
+//                      most 200) and a `before` cursor, newest page first.
+//                      Messages name their `sender` rather than `email`.
+//
+
+const DEFAULT_PAGE: usize = 50;
+const MAX_PAGE: usize = 200;
+
+fn default_room() -> String {
+    "lobby".into()
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SendMessageV2 {
+    pub message: String,
+    #[serde(default = "default_room")]
+    pub room: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SentMessageV2 {
+    pub id: i64,
+    pub room: String,
+    pub sender: String,
+    pub ts: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessageV2 {
+    pub id: i64,
+    pub room: String,
+    pub sender: String,
+    pub message: String,
+    pub ts: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub erased_at: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub previews: Vec<LinkPreview>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, u32>,
+    pub revision: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
+}
+
+impl From<OutgoingMessage> for MessageV2 {
+    fn from(m: OutgoingMessage) -> Self {
+        Self {
+            id: m.id,
+            room: m.room,
+            sender: m.email,
+            message: m.message,
+            ts: m.ts,
+            erased_at: m.erased_at,
+            previews: m.previews,
+            reactions: m.reactions,
+            revision: m.revision,
+            edited_at: m.edited_at,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessagePageV2 {
+    /// Oldest first.
+    pub messages: Vec<MessageV2>,
+    /// Pass as `before` for the previous page; absent on the first page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_before: Option<i64>,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct MessagesQueryV2 {
+    /// Room to read (default `lobby`).
+    #[serde(default = "default_room")]
+    pub room: String,
+    /// Unmasked content (see profanity.rs); moderators only.
+    #[serde(default)]
+    pub original: bool,
+    /// Only messages older than this one (a `next_before`).
+    pub before: Option<i64>,
+    /// Page size, at most 200.
+    pub limit: Option<usize>,
+}
+
+#[utoipa::path(post, path = "/v2/send", tag = "messages",
+    request_body = SendMessageV2,
+    security(("bearer" = [])),
+    responses((status = 201, body = SentMessageV2),
+        (status = 401, body = ApiError), (status = 403, body = ApiError), (status = 423, body = ApiError),
+        (status = 429, body = ApiError, headers(("Retry-After" = u64, description = "seconds to wait")))))]
+pub async fn send_message(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<SendMessageV2>,
+) -> Result<(StatusCode, Json<SentMessageV2>), ApiFailure> {
+    let Some(claims) = rooms::authorize_post(&state, &headers, &body.room)? else {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "sending requires a token"));
+    };
+    let (id, ts) = handlers::store_message(&state, &body.room, &claims.sub, &body.message);
+    Ok((StatusCode::CREATED, Json(SentMessageV2 { id, room: body.room, sender: claims.sub, ts })))
+}
+
+#[utoipa::path(get, path = "/v2/messages", tag = "messages",
+    params(MessagesQueryV2),
+    security((), ("bearer" = [])),
+    responses((status = 200, body = MessagePageV2),
+        (status = 400, body = ApiError), (status = 401, body = ApiError), (status = 403, body = ApiError),
+        (status = 429, body = ApiError, headers(("Retry-After" = u64, description = "seconds to wait")))))]
+pub async fn get_messages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(q): Query<MessagesQueryV2>,
+) -> Result<Json<MessagePageV2>, ApiFailure> {
+    let mut messages = handlers::read_messages(&state, &headers, &q.room, q.original)?;
+
+    // history is in send order, not id order, so the cursor is a position
+    if let Some(before) = q.before {
+        let Some(end) = messages.iter().position(|m| m.id == before) else {
+            return Err(api_error(StatusCode::BAD_REQUEST, "unknown `before` cursor"));
+        };
+        messages.truncate(end);
+    }
+    let limit = q.limit.unwrap_or(DEFAULT_PAGE).clamp(1, MAX_PAGE);
+    let start = messages.len().saturating_sub(limit);
+    let page: Vec<OutgoingMessage> = messages.drain(start..).collect();
+    let next_before = (start > 0).then(|| page[0].id);
+
+    Ok(Json(MessagePageV2 { messages: page.into_iter().map(MessageV2::from).collect(), next_before }))
+}
@@ -0,0 +1,212 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use axum::{
+    extract::{OriginalUri, Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+
+use crate::AppState;
+
+//
+// API VERSIONS
+//
+// The HTTP API is served under /v1 and /v2. Both are built from the same
+// handler cores (handlers::store_message, handlers::read_messages, ...);
+// a version only differs in the request and response shapes it maps to
+// and from them (v2's are in v2.rs). Routes whose shape has not changed
+// are served unchanged under both prefixes. The unprefixed paths are v1,
+// kept for clients that predate versioning.
+//
+// v1 is deprecated. Its responses carry:
+//
+//   Deprecation: @<unix time>       RFC 9745, from CHAT_API_V1_DEPRECATED_AT
+//                                   (RFC 3339, default 2026-10-16T00:00:00Z)
+//   Sunset: <HTTP date>             RFC 8594, when CHAT_API_V1_SUNSET
+//                                   (RFC 3339) is set
+//   Link: </v2/...>; rel="successor-version"
+//
+// /metrics counts requests per version, and when each was last used, so
+// v1 can be removed once nothing calls it:
+//
+//   chat_api_requests_total{version}
+//   chat_api_unprefixed_requests_total       v1 calls without the /v1 prefix
+//   chat_api_last_request_timestamp_seconds{version}
+//
+
+const DEFAULT_V1_DEPRECATED_AT: &str = "2026-10-16T00:00:00Z";
+
+#[derive(Clone, Copy)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    const ALL: [ApiVersion; 2] = [ApiVersion::V1, ApiVersion::V2];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "v1",
+            ApiVersion::V2 => "v2",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    requests: AtomicU64,
+    last_seen: AtomicI64,
+}
+
+pub struct Versions {
+    deprecation: HeaderValue,
+    sunset: Option<HeaderValue>,
+    usage: [Usage; 2],
+    unprefixed: AtomicU64,
+}
+
+fn env_time(name: &str) -> Option<DateTime<Utc>> {
+    let value = std::env::var(name).ok()?;
+    match DateTime::parse_from_rfc3339(&value) {
+        Ok(time) => Some(time.with_timezone(&Utc)),
+        Err(e) => {
+            eprintln!("chat-service: ignoring {}={:?}: {}", name, value, e);
+            None
+        }
+    }
+}
+
+impl Versions {
+    pub fn from_env() -> Self {
+        let deprecated_at = env_time("CHAT_API_V1_DEPRECATED_AT")
+            .unwrap_or_else(|| DEFAULT_V1_DEPRECATED_AT.parse().unwrap());
+        let sunset = env_time("CHAT_API_V1_SUNSET")
+            .map(|t| HeaderValue::from_str(&t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()).unwrap());
+        Self {
+            deprecation: HeaderValue::from_str(&format!("@{}", deprecated_at.timestamp())).unwrap(),
+            sunset,
+            usage: Default::default(),
+            unprefixed: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, version: ApiVersion) {
+        let usage = &self.usage[version as usize];
+        usage.requests.fetch_add(1, Ordering::Relaxed);
+        usage.last_seen.store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE chat_api_requests_total counter\n");
+        for version in ApiVersion::ALL {
+            let requests = self.usage[version as usize].requests.load(Ordering::Relaxed);
+            let _ = writeln!(out, "chat_api_requests_total{{version=\"{}\"}} {}", version.as_str(), requests);
+        }
+        out.push_str("# TYPE chat_api_unprefixed_requests_total counter\n");
+        let _ = writeln!(out, "chat_api_unprefixed_requests_total {}", self.unprefixed.load(Ordering::Relaxed));
+        out.push_str("# TYPE chat_api_last_request_timestamp_seconds gauge\n");
+        for version in ApiVersion::ALL {
+            let last_seen = self.usage[version as usize].last_seen.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "chat_api_last_request_timestamp_seconds{{version=\"{}\"}} {}",
+                version.as_str(),
+                last_seen
+            );
+        }
+        out
+    }
+}
+
+/// Middleware for the v1 routes, prefixed or not.
+pub async fn v1(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    state.versions.record(ApiVersion::V1);
+    let path = req.extensions().get::<OriginalUri>().map_or(req.uri().path(), |uri| uri.path()).to_string();
+    let successor = match path.strip_prefix("/v1") {
+        Some(rest) => format!("/v2{}", rest),
+        None => {
+            state.versions.unprefixed.fetch_add(1, Ordering::Relaxed);
+            format!("/v2{}", path)
+        }
+    };
+
+    let mut resp = next.run(req).await;
+    let headers = resp.headers_mut();
+    headers.insert("deprecation", state.versions.deprecation.clone());
+    if let Some(sunset) = &state.versions.sunset {
+        headers.insert("sunset", sunset.clone());
+    }
+    if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor)) {
+        headers.insert(header::LINK, link);
+    }
+    resp
+}
+
+/// Middleware for the v2 routes.
+pub async fn v2(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    state.versions.record(ApiVersion::V2);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Request, StatusCode};
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use uchat_proto::jwt::{create_token, secret_from_env};
+
+    use crate::testing::{app, call, call_as};
+
+    #[tokio::test]
+    async fn api_versions_map_their_own_shapes() {
+        let app = app();
+        let ann = create_token(&secret_from_env(), "ann");
+
+        // v2 sends as the token's subject, and only with one
+        let (status, _) = call(&app, "POST", "/v2/send", "/v2/send", Some(json!({ "message": "hi" }))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        for text in ["one", "two", "three"] {
+            let (status, sent) = call_as(&app, Some(&ann), "POST", "/v2/send", "/v2/send",
+                Some(json!({ "message": text }))).await;
+            assert_eq!(status, StatusCode::CREATED);
+            assert_eq!(sent["sender"], "ann");
+        }
+
+        // v1 sees the same rows in its own shape, prefixed or not
+        let (_, list) = call(&app, "GET", "/v1/messages", "/messages", None).await;
+        assert_eq!(list.as_array().unwrap().len(), 3);
+        assert_eq!(list[0]["email"], "ann");
+
+        let (_, page) = call(&app, "GET", "/v2/messages?limit=2", "/v2/messages", None).await;
+        assert_eq!(page["messages"][0]["message"], "two");
+        assert_eq!(page["messages"][1]["sender"], "ann");
+        let before = page["next_before"].as_i64().unwrap();
+        let uri = format!("/v2/messages?limit=2&before={}", before);
+        let (_, page) = call(&app, "GET", &uri, "/v2/messages", None).await;
+        assert_eq!(page["messages"].as_array().unwrap().len(), 1);
+        assert!(page.get("next_before").is_none());
+        let (status, _) = call(&app, "GET", "/v2/messages?before=999", "/v2/messages", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // only v1 is deprecated
+        for (uri, deprecated) in [("/messages", true), ("/v1/rooms/lobby", true), ("/v2/rooms/lobby", false)] {
+            let resp = app.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().contains_key("deprecation"), deprecated, "{}", uri);
+            if deprecated {
+                assert!(resp.headers()["link"].to_str().unwrap().starts_with("</v2/"));
+            }
+        }
+
+        let resp = app.clone().oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+        let metrics = String::from_utf8(to_bytes(resp.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+        assert!(metrics.contains("chat_api_requests_total{version=\"v2\"} 8"), "{}", metrics);
+        assert!(metrics.contains("chat_api_unprefixed_requests_total 1"), "{}", metrics);
+    }
+}
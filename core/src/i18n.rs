@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+//
+// MESSAGE CATALOG
+//
+// User-facing errors and system messages are looked up by a stable code
+// (`auth.invalid_token`) rather than written inline, so clients can match
+// on the code and services can answer in the caller's language. Templates
+// use `{name}` placeholders filled from the params passed to `render`.
+//
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Stable message codes. These go on the wire; never rename one.
+pub mod codes {
+    pub const PROTOCOL_INVALID_EVENT: &str = "protocol.invalid_event";
+    pub const PROTOCOL_UNSUPPORTED_EVENT: &str = "protocol.unsupported_event";
+    pub const PROTOCOL_RATE_LIMITED: &str = "protocol.rate_limited";
+    pub const PROTOCOL_HANDLER_FAILED: &str = "protocol.handler_failed";
+    pub const PROTOCOL_UNKNOWN_LOCALE: &str = "protocol.unknown_locale";
+    pub const PROTOCOL_HELLO_REQUIRED: &str = "protocol.hello_required";
+    pub const PROTOCOL_HELLO_TIMEOUT: &str = "protocol.hello_timeout";
+    pub const PROTOCOL_WRONG_STATE: &str = "protocol.wrong_state";
+    pub const PROTOCOL_RESUME_INVALID: &str = "protocol.resume_invalid";
+    pub const PROTOCOL_SIGNED_REJECTED: &str = "protocol.signed_rejected";
+    pub const PROTOCOL_TOO_SLOW: &str = "protocol.too_slow";
+    pub const POLL_SESSION_UNKNOWN: &str = "poll.session_unknown";
+
+    pub const REQUEST_INVALID_JSON: &str = "request.invalid_json";
+    pub const REQUEST_NOT_FOUND: &str = "request.not_found";
+    pub const REQUEST_TOO_LARGE: &str = "request.too_large";
+    pub const REQUEST_INVALID_FIELDS: &str = "request.invalid_fields";
+    pub const REQUEST_INVALID_CHARACTERS: &str = "request.invalid_characters";
+    pub const AUTH_INVALID_TOKEN: &str = "auth.invalid_token";
+    pub const AUTH_PASSWORD_DISABLED: &str = "auth.password_disabled";
+    pub const AUTH_LOCKED_OUT: &str = "auth.locked_out";
+    pub const AUTH_RATE_LIMITED: &str = "auth.rate_limited";
+    pub const AUTH_FORBIDDEN: &str = "auth.forbidden";
+    pub const AUTH_DELEGATION_INVALID: &str = "auth.delegation_invalid";
+    pub const AUTH_SCOPE_DENIED: &str = "auth.scope_denied";
+    pub const AUTH_POLICY_DENIED: &str = "auth.policy_denied";
+    pub const AUTH_CSRF_FAILED: &str = "auth.csrf_failed";
+    pub const AUTH_BUSY: &str = "auth.busy";
+    pub const AUTH_TOKEN_REVOKED: &str = "auth.token_revoked";
+    pub const AUTH_LOGIN_UNSUPPORTED: &str = "auth.login_unsupported";
+    pub const AUTH_INVALID_CREDENTIALS: &str = "auth.invalid_credentials";
+    pub const AUTH_ORIGIN_REFUSED: &str = "auth.origin_refused";
+    pub const AUTH_COOKIE_ORIGIN_REFUSED: &str = "auth.cookie_origin_refused";
+
+    pub const POLICY_UNKNOWN_KIND: &str = "policy.unknown_kind";
+    pub const POLICY_VERSION_EXISTS: &str = "policy.version_exists";
+    pub const POLICY_NOT_CURRENT: &str = "policy.not_current";
+
+    pub const OTP_INVALID_IDENTIFIER: &str = "otp.invalid_identifier";
+    pub const OTP_INVALID_CODE: &str = "otp.invalid_code";
+    pub const OTP_RATE_LIMITED: &str = "otp.rate_limited";
+    pub const OTP_UNAVAILABLE: &str = "otp.unavailable";
+
+    pub const WEBAUTHN_VERIFICATION_FAILED: &str = "webauthn.verification_failed";
+    pub const WEBAUTHN_CREDENTIAL_EXISTS: &str = "webauthn.credential_exists";
+
+    pub const DEVICE_INVALID: &str = "device.invalid";
+    pub const DEVICE_EXISTS: &str = "device.exists";
+    pub const DEVICE_ATTESTATION_FAILED: &str = "device.attestation_failed";
+    pub const DEVICE_ATTESTATION_REQUIRED: &str = "device.attestation_required";
+
+    pub const ROOM_POST_FORBIDDEN: &str = "room.post_forbidden";
+    pub const ROOM_INVALID_NAME: &str = "room.invalid_name";
+    pub const ROOM_NOT_JOINED: &str = "room.not_joined";
+    pub const ROOM_LIMIT_REACHED: &str = "room.limit_reached";
+    pub const ROOM_JOIN_FORBIDDEN: &str = "room.join_forbidden";
+    pub const ROOM_FROZEN: &str = "room.frozen";
+    pub const ROOM_RATE_LIMITED: &str = "room.rate_limited";
+
+    pub const MESSAGE_CLIENT_ID_INVALID: &str = "message.client_id_invalid";
+    pub const MESSAGE_TOO_LARGE: &str = "message.too_large";
+    pub const MESSAGE_TTL_INVALID: &str = "message.ttl_invalid";
+    pub const MESSAGE_E2EE_REQUIRED: &str = "message.e2ee_required";
+
+    pub const COMMAND_UNKNOWN: &str = "command.unknown";
+    pub const COMMAND_FORBIDDEN: &str = "command.forbidden";
+    pub const COMMAND_RATE_LIMITED: &str = "command.rate_limited";
+    pub const COMMAND_UNAVAILABLE: &str = "command.unavailable";
+    pub const COMMAND_INVALID_REPLY: &str = "command.invalid_reply";
+
+    pub const UPLOAD_REJECTED: &str = "upload.rejected";
+    pub const UPLOAD_SCAN_UNAVAILABLE: &str = "upload.scan_unavailable";
+
+    pub const GROUP_INVALID_ID: &str = "group.invalid_id";
+    pub const GROUP_EXISTS: &str = "group.exists";
+    pub const GROUP_NOT_OWNER: &str = "group.not_owner";
+}
+
+use codes::*;
+
+const EN: &[(&str, &str)] = &[
+    (PROTOCOL_INVALID_EVENT, "Invalid event: {reason}"),
+    (PROTOCOL_UNSUPPORTED_EVENT, "unsupported event {event}"),
+    (PROTOCOL_RATE_LIMITED, "rate limited: {event}"),
+    (PROTOCOL_HANDLER_FAILED, "{event} failed"),
+    (PROTOCOL_UNKNOWN_LOCALE, "unsupported locale {locale}"),
+    (PROTOCOL_HELLO_REQUIRED, "send Hello before {event}"),
+    (PROTOCOL_HELLO_TIMEOUT, "no Hello within {secs}s, closing"),
+    (PROTOCOL_WRONG_STATE, "{event} not allowed while {state}"),
+    (PROTOCOL_RESUME_INVALID, "resume token is invalid or expired, starting a new session"),
+    (PROTOCOL_SIGNED_REJECTED, "signed message rejected: {reason}"),
+    (PROTOCOL_TOO_SLOW, "too slow: {missed} events were dropped, closing; reconnect and resume"),
+    (POLL_SESSION_UNKNOWN, "unknown or expired poll session"),
+    (REQUEST_INVALID_JSON, "invalid json"),
+    (REQUEST_NOT_FOUND, "not found"),
+    (REQUEST_TOO_LARGE, "request body too large"),
+    (REQUEST_INVALID_FIELDS, "unknown, missing or invalid fields"),
+    (REQUEST_INVALID_CHARACTERS, "request contains control characters"),
+    (AUTH_INVALID_TOKEN, "missing or invalid token"),
+    (AUTH_PASSWORD_DISABLED, "this account signs in with a passkey"),
+    (AUTH_LOCKED_OUT, "too many failed sign-in attempts from this address, try again later"),
+    (AUTH_RATE_LIMITED, "too many sign-in attempts from this address, slow down"),
+    (AUTH_FORBIDDEN, "not allowed for this account"),
+    (AUTH_DELEGATION_INVALID, "a delegated token needs a bot name, up to 32 rooms and at least one action"),
+    (AUTH_SCOPE_DENIED, "this token may not {action} in {room}"),
+    (AUTH_POLICY_DENIED, "{action} in {room} is not allowed by this server's policy"),
+    (AUTH_CSRF_FAILED, "missing or wrong CSRF token for this session"),
+    (AUTH_BUSY, "sign-in is busy, try again in a moment"),
+    (AUTH_TOKEN_REVOKED, "you were signed out, sign in again"),
+    (AUTH_LOGIN_UNSUPPORTED, "sign in with auth-api and connect with its token"),
+    (AUTH_INVALID_CREDENTIALS, "wrong username or password"),
+    (AUTH_ORIGIN_REFUSED, "origin not allowed"),
+    (AUTH_COOKIE_ORIGIN_REFUSED, "session cookies are not accepted from this origin"),
+    (POLICY_UNKNOWN_KIND, "policy kind must be tos or privacy"),
+    (POLICY_VERSION_EXISTS, "this policy version was already published"),
+    (POLICY_NOT_CURRENT, "accept the current version of every pending policy"),
+    (OTP_INVALID_IDENTIFIER, "sign-in codes go to an email address or a phone number in +E.164 form"),
+    (OTP_INVALID_CODE, "the code is wrong, expired or already used"),
+    (OTP_RATE_LIMITED, "too many codes requested for this address, try again later"),
+    (OTP_UNAVAILABLE, "sign-in codes cannot be sent right now"),
+    (WEBAUTHN_VERIFICATION_FAILED, "passkey verification failed"),
+    (WEBAUTHN_CREDENTIAL_EXISTS, "passkey already registered"),
+    (DEVICE_INVALID, "invalid device id or rooms"),
+    (DEVICE_EXISTS, "device is registered to another account"),
+    (DEVICE_ATTESTATION_FAILED, "device attestation failed"),
+    (DEVICE_ATTESTATION_REQUIRED, "devices must be attested to register"),
+    (ROOM_POST_FORBIDDEN, "only moderators and bots may post in {room}"),
+    (ROOM_INVALID_NAME, "not a room name: {room}"),
+    (ROOM_NOT_JOINED, "not in room {room}; join it first"),
+    (ROOM_LIMIT_REACHED, "a connection may be in at most {max} rooms"),
+    (ROOM_JOIN_FORBIDDEN, "{room} is only open to members of its groups"),
+    (ROOM_FROZEN, "{room} is frozen; nobody can post in it for now"),
+    (ROOM_RATE_LIMITED, "{room} is too busy right now, try again in a moment"),
+    (MESSAGE_CLIENT_ID_INVALID, "client_msg_id must be 1 to {max} characters"),
+    (MESSAGE_TOO_LARGE, "messages are limited to {max} bytes"),
+    (MESSAGE_TTL_INVALID, "expires_in_secs must be 1 to {max}"),
+    (MESSAGE_E2EE_REQUIRED, "{room} only accepts end-to-end encrypted messages"),
+    (COMMAND_UNKNOWN, "unknown command /{command}"),
+    (COMMAND_FORBIDDEN, "/{command} requires admin rights"),
+    (COMMAND_RATE_LIMITED, "/{command} is rate limited, try again in {secs}s"),
+    (COMMAND_UNAVAILABLE, "/{command} is unavailable"),
+    (COMMAND_INVALID_REPLY, "/{command} returned an invalid reply"),
+    (UPLOAD_REJECTED, "{file} was rejected by the malware scanner ({signature})"),
+    (UPLOAD_SCAN_UNAVAILABLE, "uploads cannot be scanned right now, try again later"),
+    (GROUP_INVALID_ID, "group id must be a lowercase slug"),
+    (GROUP_EXISTS, "group already exists"),
+    (GROUP_NOT_OWNER, "not the group owner"),
+];
+
+const ES: &[(&str, &str)] = &[
+    (PROTOCOL_INVALID_EVENT, "Evento no válido: {reason}"),
+    (PROTOCOL_UNSUPPORTED_EVENT, "evento no admitido: {event}"),
+    (PROTOCOL_RATE_LIMITED, "límite de frecuencia alcanzado: {event}"),
+    (PROTOCOL_HANDLER_FAILED, "falló {event}"),
+    (PROTOCOL_UNKNOWN_LOCALE, "idioma no admitido: {locale}"),
+    (PROTOCOL_HELLO_REQUIRED, "envía Hello antes de {event}"),
+    (PROTOCOL_HELLO_TIMEOUT, "no se recibió Hello en {secs}s, cerrando"),
+    (PROTOCOL_WRONG_STATE, "{event} no está permitido en el estado {state}"),
+    (PROTOCOL_RESUME_INVALID, "el token de reanudación no es válido o caducó, se inicia una sesión nueva"),
+    (PROTOCOL_SIGNED_REJECTED, "mensaje firmado rechazado: {reason}"),
+    (PROTOCOL_TOO_SLOW, "demasiado lento: se descartaron {missed} eventos, cerrando; reconecta y reanuda"),
+    (POLL_SESSION_UNKNOWN, "sesión de sondeo desconocida o caducada"),
+    (REQUEST_INVALID_JSON, "JSON no válido"),
+    (REQUEST_NOT_FOUND, "no encontrado"),
+    (REQUEST_TOO_LARGE, "cuerpo de la solicitud demasiado grande"),
+    (REQUEST_INVALID_FIELDS, "campos desconocidos, ausentes o no válidos"),
+    (REQUEST_INVALID_CHARACTERS, "la solicitud contiene caracteres de control"),
+    (AUTH_INVALID_TOKEN, "token ausente o no válido"),
+    (AUTH_PASSWORD_DISABLED, "esta cuenta inicia sesión con una llave de acceso"),
+    (AUTH_LOCKED_OUT, "demasiados intentos fallidos desde esta dirección, inténtalo más tarde"),
+    (AUTH_RATE_LIMITED, "demasiados intentos de inicio de sesión desde esta dirección, ve más despacio"),
+    (AUTH_FORBIDDEN, "no permitido para esta cuenta"),
+    (AUTH_DELEGATION_INVALID, "un token delegado necesita un nombre de bot, hasta 32 salas y al menos una acción"),
+    (AUTH_SCOPE_DENIED, "este token no permite {action} en {room}"),
+    (AUTH_POLICY_DENIED, "la política de este servidor no permite {action} en {room}"),
+    (AUTH_CSRF_FAILED, "token CSRF ausente o incorrecto para esta sesión"),
+    (AUTH_BUSY, "el inicio de sesión está ocupado, inténtalo de nuevo en un momento"),
+    (AUTH_TOKEN_REVOKED, "se cerró tu sesión, vuelve a iniciarla"),
+    (AUTH_LOGIN_UNSUPPORTED, "inicia sesión con auth-api y conéctate con su token"),
+    (AUTH_INVALID_CREDENTIALS, "usuario o contraseña incorrectos"),
+    (AUTH_ORIGIN_REFUSED, "origen no permitido"),
+    (AUTH_COOKIE_ORIGIN_REFUSED, "no se aceptan cookies de sesión desde este origen"),
+    (POLICY_UNKNOWN_KIND, "el tipo de política debe ser tos o privacy"),
+    (POLICY_VERSION_EXISTS, "esta versión de la política ya se publicó"),
+    (POLICY_NOT_CURRENT, "acepta la versión vigente de cada política pendiente"),
+    (OTP_INVALID_IDENTIFIER, "los códigos se envían a un correo electrónico o a un teléfono en formato +E.164"),
+    (OTP_INVALID_CODE, "el código es incorrecto, caducó o ya se usó"),
+    (OTP_RATE_LIMITED, "demasiados códigos solicitados para esta dirección, inténtalo más tarde"),
+    (OTP_UNAVAILABLE, "ahora mismo no se pueden enviar códigos de acceso"),
+    (WEBAUTHN_VERIFICATION_FAILED, "falló la verificación de la llave de acceso"),
+    (WEBAUTHN_CREDENTIAL_EXISTS, "la llave de acceso ya está registrada"),
+    (DEVICE_INVALID, "id de dispositivo o salas no válidos"),
+    (DEVICE_EXISTS, "el dispositivo está registrado en otra cuenta"),
+    (DEVICE_ATTESTATION_FAILED, "falló la atestación del dispositivo"),
+    (DEVICE_ATTESTATION_REQUIRED, "los dispositivos deben estar atestados para registrarse"),
+    (ROOM_POST_FORBIDDEN, "solo moderadores y bots pueden publicar en {room}"),
+    (ROOM_INVALID_NAME, "nombre de sala no válido: {room}"),
+    (ROOM_NOT_JOINED, "no estás en la sala {room}; únete primero"),
+    (ROOM_LIMIT_REACHED, "una conexión puede estar en {max} salas como máximo"),
+    (ROOM_JOIN_FORBIDDEN, "{room} solo está abierta a los miembros de sus grupos"),
+    (ROOM_FROZEN, "{room} está congelada; por ahora nadie puede publicar en ella"),
+    (ROOM_RATE_LIMITED, "{room} está demasiado activa ahora mismo, inténtalo de nuevo en un momento"),
+    (MESSAGE_CLIENT_ID_INVALID, "client_msg_id debe tener entre 1 y {max} caracteres"),
+    (MESSAGE_TOO_LARGE, "los mensajes están limitados a {max} bytes"),
+    (MESSAGE_TTL_INVALID, "expires_in_secs debe estar entre 1 y {max}"),
+    (MESSAGE_E2EE_REQUIRED, "{room} solo acepta mensajes cifrados de extremo a extremo"),
+    (COMMAND_UNKNOWN, "comando desconocido /{command}"),
+    (COMMAND_FORBIDDEN, "/{command} requiere permisos de administrador"),
+    (COMMAND_RATE_LIMITED, "/{command} alcanzó su límite de frecuencia, inténtalo de nuevo en {secs}s"),
+    (COMMAND_UNAVAILABLE, "/{command} no está disponible"),
+    (COMMAND_INVALID_REPLY, "/{command} devolvió una respuesta no válida"),
+    (UPLOAD_REJECTED, "el analizador de malware rechazó {file} ({signature})"),
+    (UPLOAD_SCAN_UNAVAILABLE, "ahora no se pueden analizar las subidas, inténtalo más tarde"),
+    (GROUP_INVALID_ID, "el id del grupo debe ser un slug en minúsculas"),
+    (GROUP_EXISTS, "el grupo ya existe"),
+    (GROUP_NOT_OWNER, "no eres el propietario del grupo"),
+];
+
+const DE: &[(&str, &str)] = &[
+    (PROTOCOL_INVALID_EVENT, "Ungültiges Ereignis: {reason}"),
+    (PROTOCOL_UNSUPPORTED_EVENT, "nicht unterstütztes Ereignis {event}"),
+    (PROTOCOL_RATE_LIMITED, "Ratenlimit erreicht: {event}"),
+    (PROTOCOL_HANDLER_FAILED, "{event} fehlgeschlagen"),
+    (PROTOCOL_UNKNOWN_LOCALE, "nicht unterstützte Sprache {locale}"),
+    (PROTOCOL_HELLO_REQUIRED, "vor {event} zuerst Hello senden"),
+    (PROTOCOL_HELLO_TIMEOUT, "kein Hello innerhalb von {secs}s, Verbindung wird geschlossen"),
+    (PROTOCOL_WRONG_STATE, "{event} im Zustand {state} nicht erlaubt"),
+    (PROTOCOL_RESUME_INVALID, "Resume-Token ungültig oder abgelaufen, neue Sitzung wird gestartet"),
+    (PROTOCOL_SIGNED_REJECTED, "signierte Nachricht abgelehnt: {reason}"),
+    (PROTOCOL_TOO_SLOW, "zu langsam: {missed} Ereignisse verworfen, Verbindung wird getrennt; neu verbinden"),
+    (POLL_SESSION_UNKNOWN, "unbekannte oder abgelaufene Poll-Sitzung"),
+    (REQUEST_INVALID_JSON, "ungültiges JSON"),
+    (REQUEST_NOT_FOUND, "nicht gefunden"),
+    (REQUEST_TOO_LARGE, "Anfrage zu groß"),
+    (REQUEST_INVALID_FIELDS, "unbekannte, fehlende oder ungültige Felder"),
+    (REQUEST_INVALID_CHARACTERS, "Anfrage enthält Steuerzeichen"),
+    (AUTH_INVALID_TOKEN, "fehlendes oder ungültiges Token"),
+    (AUTH_PASSWORD_DISABLED, "dieses Konto meldet sich mit einem Passkey an"),
+    (AUTH_LOCKED_OUT, "zu viele fehlgeschlagene Anmeldeversuche von dieser Adresse, später erneut versuchen"),
+    (AUTH_RATE_LIMITED, "zu viele Anmeldeversuche von dieser Adresse, bitte langsamer"),
+    (AUTH_FORBIDDEN, "für dieses Konto nicht erlaubt"),
+    (AUTH_DELEGATION_INVALID, "ein delegiertes Token braucht einen Bot-Namen, bis zu 32 Räume und mindestens eine Aktion"),
+    (AUTH_SCOPE_DENIED, "dieses Token erlaubt {action} in {room} nicht"),
+    (AUTH_POLICY_DENIED, "die Richtlinie dieses Servers erlaubt {action} in {room} nicht"),
+    (AUTH_CSRF_FAILED, "CSRF-Token für diese Sitzung fehlt oder ist falsch"),
+    (AUTH_BUSY, "die Anmeldung ist ausgelastet, bitte gleich noch einmal versuchen"),
+    (AUTH_TOKEN_REVOKED, "du wurdest abgemeldet, bitte melde dich erneut an"),
+    (AUTH_LOGIN_UNSUPPORTED, "melde dich bei auth-api an und verbinde dich mit dessen Token"),
+    (AUTH_INVALID_CREDENTIALS, "falscher Benutzername oder falsches Passwort"),
+    (AUTH_ORIGIN_REFUSED, "Herkunft nicht erlaubt"),
+    (AUTH_COOKIE_ORIGIN_REFUSED, "Sitzungscookies werden von dieser Herkunft nicht angenommen"),
+    (POLICY_UNKNOWN_KIND, "Richtlinienart muss tos oder privacy sein"),
+    (POLICY_VERSION_EXISTS, "diese Richtlinienversion wurde bereits veröffentlicht"),
+    (POLICY_NOT_CURRENT, "die aktuelle Version jeder ausstehenden Richtlinie akzeptieren"),
+    (OTP_INVALID_IDENTIFIER, "Anmeldecodes gehen an eine E-Mail-Adresse oder eine Telefonnummer im Format +E.164"),
+    (OTP_INVALID_CODE, "der Code ist falsch, abgelaufen oder bereits verwendet"),
+    (OTP_RATE_LIMITED, "zu viele Codes für diese Adresse angefordert, später erneut versuchen"),
+    (OTP_UNAVAILABLE, "Anmeldecodes können gerade nicht gesendet werden"),
+    (WEBAUTHN_VERIFICATION_FAILED, "Passkey-Prüfung fehlgeschlagen"),
+    (WEBAUTHN_CREDENTIAL_EXISTS, "Passkey bereits registriert"),
+    (DEVICE_INVALID, "ungültige Geräte-ID oder Räume"),
+    (DEVICE_EXISTS, "das Gerät ist bei einem anderen Konto registriert"),
+    (DEVICE_ATTESTATION_FAILED, "Geräteattestierung fehlgeschlagen"),
+    (DEVICE_ATTESTATION_REQUIRED, "Geräte müssen zur Registrierung attestiert sein"),
+    (ROOM_POST_FORBIDDEN, "nur Moderatoren und Bots dürfen in {room} schreiben"),
+    (ROOM_INVALID_NAME, "kein gültiger Raumname: {room}"),
+    (ROOM_NOT_JOINED, "nicht im Raum {room}; zuerst beitreten"),
+    (ROOM_LIMIT_REACHED, "eine Verbindung kann höchstens {max} Räumen beitreten"),
+    (ROOM_JOIN_FORBIDDEN, "{room} ist nur für Mitglieder seiner Gruppen offen"),
+    (ROOM_FROZEN, "{room} ist eingefroren; vorerst kann niemand darin schreiben"),
+    (ROOM_RATE_LIMITED, "in {room} ist gerade zu viel los, versuche es gleich noch einmal"),
+    (MESSAGE_CLIENT_ID_INVALID, "client_msg_id muss 1 bis {max} Zeichen lang sein"),
+    (MESSAGE_TOO_LARGE, "Nachrichten sind auf {max} Bytes begrenzt"),
+    (MESSAGE_TTL_INVALID, "expires_in_secs muss zwischen 1 und {max} liegen"),
+    (MESSAGE_E2EE_REQUIRED, "{room} nimmt nur Ende-zu-Ende-verschlüsselte Nachrichten an"),
+    (COMMAND_UNKNOWN, "unbekannter Befehl /{command}"),
+    (COMMAND_FORBIDDEN, "/{command} erfordert Administratorrechte"),
+    (COMMAND_RATE_LIMITED, "/{command} ist begrenzt, versuche es in {secs}s erneut"),
+    (COMMAND_UNAVAILABLE, "/{command} ist nicht verfügbar"),
+    (COMMAND_INVALID_REPLY, "/{command} hat eine ungültige Antwort geliefert"),
+    (UPLOAD_REJECTED, "{file} wurde vom Malware-Scanner abgelehnt ({signature})"),
+    (UPLOAD_SCAN_UNAVAILABLE, "Uploads können gerade nicht geprüft werden, bitte später erneut versuchen"),
+    (GROUP_INVALID_ID, "Gruppen-ID muss ein kleingeschriebener Slug sein"),
+    (GROUP_EXISTS, "Gruppe existiert bereits"),
+    (GROUP_NOT_OWNER, "nicht der Gruppeneigentümer"),
+];
+
+pub struct Catalog {
+    locales: HashMap<&'static str, HashMap<&'static str, &'static str>>,
+}
+
+impl Catalog {
+    pub fn builtin() -> Self {
+        let mut catalog = Self { locales: HashMap::new() };
+        catalog.add_locale("en", EN);
+        catalog.add_locale("es", ES);
+        catalog.add_locale("de", DE);
+        catalog
+    }
+
+    pub fn add_locale(&mut self, locale: &'static str, templates: &[(&'static str, &'static str)]) {
+        self.locales
+            .entry(locale)
+            .or_default()
+            .extend(templates.iter().copied());
+    }
+
+    /// The supported locale matching `tag` ("es-MX" -> "es"), if any.
+    pub fn resolve(&self, tag: &str) -> Option<&'static str> {
+        let primary = tag.trim().split(['-', '_']).next()?.to_ascii_lowercase();
+        self.locales.keys().find(|l| **l == primary).copied()
+    }
+
+    /// Picks the best supported locale from an `Accept-Language` value,
+    /// honouring q-weights; falls back to `DEFAULT_LOCALE`.
+    pub fn negotiate(&self, accept_language: &str) -> &'static str {
+        let mut best: Option<(f32, &'static str)> = None;
+
+        for part in accept_language.split(',') {
+            let mut pieces = part.split(';');
+            let tag = pieces.next().unwrap_or("");
+            let q = pieces
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if let Some(locale) = self.resolve(tag)
+                && q > 0.0
+                && best.is_none_or(|(bq, _)| q > bq)
+            {
+                best = Some((q, locale));
+            }
+        }
+
+        best.map(|(_, l)| l).unwrap_or(DEFAULT_LOCALE)
+    }
+
+    /// Renders `code` in `locale`, falling back to the default locale and
+    /// then to the bare code.
+    pub fn render(&self, locale: &str, code: &str, params: &[(&str, &str)]) -> String {
+        let template = self
+            .locales
+            .get(locale)
+            .and_then(|t| t.get(code))
+            .or_else(|| self.locales.get(DEFAULT_LOCALE).and_then(|t| t.get(code)))
+            .copied()
+            .unwrap_or(code);
+
+        let mut out = template.to_string();
+        for (name, value) in params {
+            out = out.replace(&format!("{{{}}}", name), value);
+        }
+        out
+    }
+}
+
+/// Process-wide catalog with the built-in locales.
+pub fn catalog() -> &'static Catalog {
+    static CATALOG: OnceLock<Catalog> = OnceLock::new();
+    CATALOG.get_or_init(Catalog::builtin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_and_renders() {
+        let catalog = Catalog::builtin();
+        assert_eq!(catalog.negotiate("fr-CH, es-MX;q=0.8, de;q=0.9"), "de");
+        assert_eq!(catalog.negotiate("fr"), DEFAULT_LOCALE);
+        assert_eq!(
+            catalog.render("es", PROTOCOL_RATE_LIMITED, &[("event", "login")]),
+            "límite de frecuencia alcanzado: login"
+        );
+        assert_eq!(catalog.render("xx", "no.such.code", &[]), "no.such.code");
+    }
+}
@@ -0,0 +1,340 @@
+use std::fmt::Write as _;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, DurationRound, Timelike, Utc};
+use rand::Rng;
+
+//
+// BACKGROUND JOBS
+//
+// Periodic work (expiry sweeps, purges, retention) registers here instead
+// of hand-rolling a `tokio::time::interval` loop. Each job:
+// - runs on a fixed interval or a cron expression (UTC);
+// - may add random jitter, so replicas don't all fire on the same second;
+// - never overlaps itself: a run that overshoots its next fire time makes
+//   the scheduler skip that tick (counted as `skipped`) instead of queueing;
+// - runs in its own task, so a panic is counted as a failure and the job
+//   fires again on its next tick rather than dying silently.
+//
+// `render_metrics` exposes runs, failures, skips and last duration per job
+// in Prometheus text format.
+//
+
+/// When a job fires.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Every `Duration`, starting one period after the job is spawned.
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    pub fn every(period: Duration) -> Self {
+        Schedule::Every(period)
+    }
+
+    /// A five-field cron expression, see [`Cron::parse`].
+    pub fn cron(expr: &str) -> Result<Self, CronError> {
+        Cron::parse(expr).map(Schedule::Cron)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CronError {
+    FieldCount(usize),
+    Field { field: &'static str, value: String },
+}
+
+// thiserror's derive paths through `::core`, which this crate shadows
+impl std::fmt::Display for CronError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CronError::FieldCount(n) => {
+                write!(f, "expected 5 fields (minute hour day-of-month month day-of-week), got {}", n)
+            }
+            CronError::Field { field, value } => write!(f, "invalid {} field: {:?}", field, value),
+        }
+    }
+}
+
+impl std::error::Error for CronError {}
+
+/// A parsed `minute hour day-of-month month day-of-week` expression.
+///
+/// Each field takes `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`,
+/// or a comma-separated list of those. Day-of-week runs 0-6 from Sunday
+/// (7 is also Sunday). As in cron, when both day fields are restricted a
+/// day matching either one fires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &'static str, value: &str, min: u32, max: u32) -> Result<u64, CronError> {
+    let invalid = || CronError::Field { field, value: value.to_string() };
+    let mut bits = 0u64;
+
+    for part in value.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (lo, hi) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((lo, hi)) => (lo.parse().map_err(|_| invalid())?, hi.parse().map_err(|_| invalid())?),
+                // `5/15` means from 5 to the end, every 15
+                None if part.contains('/') => (range.parse().map_err(|_| invalid())?, max),
+                None => {
+                    let n = range.parse().map_err(|_| invalid())?;
+                    (n, n)
+                }
+            },
+        };
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return Err(invalid());
+        }
+        for n in (lo..=hi).step_by(step as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Self, CronError> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(CronError::FieldCount(fields.len()));
+        };
+
+        let mut weekdays = parse_field("day-of-week", weekday, 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Cron {
+            minutes: parse_field("minute", minute, 0, 59)?,
+            hours: parse_field("hour", hour, 0, 23)?,
+            days: parse_field("day-of-month", day, 1, 31)?,
+            months: parse_field("month", month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    fn day_matches(&self, t: DateTime<Utc>) -> bool {
+        let day = self.days & (1 << t.day()) != 0;
+        let weekday = self.weekdays & (1 << t.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `after`, or `None` if the
+    /// expression never matches (e.g. `0 0 31 2 *`).
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut t = after.duration_trunc(ChronoDuration::minutes(1)).ok()? + ChronoDuration::minutes(1);
+        // five years covers every valid month/day/weekday combination
+        let limit = t + ChronoDuration::days(5 * 366);
+
+        while t < limit {
+            if self.months & (1 << t.month()) == 0 {
+                let (year, month) = if t.month() == 12 { (t.year() + 1, 1) } else { (t.year(), t.month() + 1) };
+                t = t.with_day(1)?.with_hour(0)?.with_minute(0)?.with_month(month)?.with_year(year)?;
+            } else if !self.day_matches(t) {
+                t = (t + ChronoDuration::days(1)).with_hour(0)?.with_minute(0)?;
+            } else if self.hours & (1 << t.hour()) == 0 {
+                t = (t + ChronoDuration::hours(1)).with_minute(0)?;
+            } else if self.minutes & (1 << t.minute()) == 0 {
+                t += ChronoDuration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+/// A job's name, schedule and jitter; handed to [`Scheduler::spawn`].
+#[derive(Debug, Clone)]
+pub struct Job {
+    name: &'static str,
+    schedule: Schedule,
+    jitter: Duration,
+}
+
+impl Job {
+    pub fn new(name: &'static str, schedule: Schedule) -> Self {
+        Job { name, schedule, jitter: Duration::ZERO }
+    }
+
+    pub fn every(name: &'static str, period: Duration) -> Self {
+        Job::new(name, Schedule::every(period))
+    }
+
+    /// Delays each run by a random amount below `jitter`.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+}
+
+#[derive(Default)]
+struct JobStats {
+    runs: AtomicU64,
+    failures: AtomicU64,
+    skipped: AtomicU64,
+    running: AtomicBool,
+    last_duration_ms: AtomicU64,
+}
+
+/// Metric name suffix, Prometheus type, and how to read it from a job.
+type Metric = (&'static str, &'static str, fn(&JobStats) -> u64);
+
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Mutex<Vec<(&'static str, Arc<JobStats>)>>,
+}
+
+impl Scheduler {
+    /// Starts `job` on the current tokio runtime; `run` builds one run's future.
+    pub fn spawn<F, Fut>(&self, job: Job, run: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let stats = Arc::new(JobStats::default());
+        self.jobs.lock().unwrap().push((job.name, stats.clone()));
+        tokio::spawn(drive(job, stats, run));
+    }
+
+    /// Prometheus counters and gauges for every job, named `{prefix}_job_*`.
+    pub fn render_metrics(&self, prefix: &str) -> String {
+        let jobs = self.jobs.lock().unwrap();
+        let mut out = String::new();
+
+        let metrics: [Metric; 5] = [
+            ("runs_total", "counter", |s| s.runs.load(Ordering::Relaxed)),
+            ("failures_total", "counter", |s| s.failures.load(Ordering::Relaxed)),
+            ("skipped_total", "counter", |s| s.skipped.load(Ordering::Relaxed)),
+            ("running", "gauge", |s| s.running.load(Ordering::Relaxed) as u64),
+            ("last_duration_ms", "gauge", |s| s.last_duration_ms.load(Ordering::Relaxed)),
+        ];
+        for (metric, kind, value) in metrics {
+            let _ = writeln!(out, "# TYPE {}_job_{} {}", prefix, metric, kind);
+            for (name, stats) in jobs.iter() {
+                let _ = writeln!(out, "{}_job_{}{{job=\"{}\"}} {}", prefix, metric, name, value(stats));
+            }
+        }
+        out
+    }
+}
+
+/// Time until the job's next fire after `now`, or `None` if it never fires again.
+fn next_delay(schedule: &Schedule, now: DateTime<Utc>) -> Option<Duration> {
+    match schedule {
+        Schedule::Every(period) => Some(*period),
+        Schedule::Cron(cron) => cron.next_after(now).map(|t| (t - now).to_std().unwrap_or_default()),
+    }
+}
+
+async fn drive<F, Fut>(job: Job, stats: Arc<JobStats>, run: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let Some(first) = next_delay(&job.schedule, Utc::now()) else {
+        eprintln!("jobs: {} never fires, not scheduled", job.name);
+        return;
+    };
+    let mut due = Instant::now() + first;
+
+    loop {
+        let jitter = match job.jitter {
+            Duration::ZERO => Duration::ZERO,
+            max => rand::thread_rng().gen_range(Duration::ZERO..max),
+        };
+        tokio::time::sleep_until((due + jitter).into()).await;
+
+        stats.running.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        let outcome = tokio::spawn(run()).await;
+        stats.last_duration_ms.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        stats.running.store(false, Ordering::Relaxed);
+        stats.runs.fetch_add(1, Ordering::Relaxed);
+
+        match outcome {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                eprintln!("jobs: {} failed: {:#}", job.name, e);
+            }
+            Err(e) => {
+                stats.failures.fetch_add(1, Ordering::Relaxed);
+                eprintln!("jobs: {} panicked: {}", job.name, e);
+            }
+        }
+
+        // fire times that passed while the job was running are skipped
+        let now = Instant::now();
+        match &job.schedule {
+            Schedule::Every(period) => {
+                due += *period;
+                while due <= now {
+                    stats.skipped.fetch_add(1, Ordering::Relaxed);
+                    due += *period;
+                }
+            }
+            Schedule::Cron(cron) => {
+                let wall = Utc::now();
+                let scheduled = wall - ChronoDuration::from_std(started.elapsed()).unwrap_or_default();
+                let mut missed = cron.next_after(scheduled);
+                while let Some(t) = missed.filter(|t| *t <= wall) {
+                    stats.skipped.fetch_add(1, Ordering::Relaxed);
+                    missed = cron.next_after(t);
+                }
+                let Some(delay) = next_delay(&job.schedule, wall) else { return };
+                due = now + delay;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn cron_next_after() {
+        let every_15 = Cron::parse("*/15 * * * *").unwrap();
+        assert_eq!(every_15.next_after(at(2026, 1, 1, 10, 7)), Some(at(2026, 1, 1, 10, 15)));
+
+        let nightly = Cron::parse("30 3 * * *").unwrap();
+        assert_eq!(nightly.next_after(at(2026, 1, 1, 3, 30)), Some(at(2026, 1, 2, 3, 30)));
+
+        // 2026-01-04 is a Sunday
+        let sundays = Cron::parse("0 0 * * 7").unwrap();
+        assert_eq!(sundays.next_after(at(2026, 1, 1, 0, 0)), Some(at(2026, 1, 4, 0, 0)));
+
+        assert_eq!(Cron::parse("0 0 31 2 *").unwrap().next_after(at(2026, 1, 1, 0, 0)), None);
+        assert_eq!(Cron::parse("* * *"), Err(CronError::FieldCount(3)));
+        assert!(Cron::parse("60 * * * *").is_err());
+    }
+}
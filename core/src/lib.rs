@@ -1,3 +1,8 @@
+pub mod i18n;
+pub mod jobs;
+pub mod ratelimit;
+pub mod storage;
+
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
 }
@@ -0,0 +1,172 @@
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use governor::clock::{Clock, DefaultClock};
+use governor::middleware::StateInformationMiddleware;
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{Quota, RateLimiter};
+use serde::{Deserialize, Serialize};
+
+//
+// RATE LIMITING
+//
+// One keyed limiter for every service (gateway events, chat-service
+// routes, auth-api logins), a thin layer over governor's GCRA limiter. A
+// `Limit` of `max` requests per `per` allows bursts of up to `max` and
+// refills one slot every `per / max`, so a client keeping to the average
+// rate is never refused. Refusals carry how long to wait, for
+// `Retry-After`.
+//
+// Keys are forgotten once their bucket has refilled (`retain_recent`);
+// services run that from a periodic job so per-connection or per-IP keys
+// do not accumulate.
+//
+
+/// At most `max` requests per `per`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub max: u32,
+    pub per: Duration,
+}
+
+impl Limit {
+    pub const fn new(max: u32, per: Duration) -> Self {
+        Self { max, per }
+    }
+
+    /// Parses "20/10s", "5/1m", "1000/1h" or "3/500ms"; a bare number is
+    /// seconds ("20/10").
+    pub fn parse(s: &str) -> Option<Self> {
+        let (max, per) = s.trim().split_once('/')?;
+        let max: u32 = max.trim().parse().ok()?;
+        let per = per.trim();
+        let split = per.find(|c: char| !c.is_ascii_digit()).unwrap_or(per.len());
+        let (n, unit) = per.split_at(split);
+        let n: u64 = n.parse().ok()?;
+        let per = match unit {
+            "ms" => Duration::from_millis(n),
+            "" | "s" => Duration::from_secs(n),
+            "m" => Duration::from_secs(n * 60),
+            "h" => Duration::from_secs(n * 3600),
+            _ => return None,
+        };
+        (max > 0 && !per.is_zero()).then_some(Self { max, per })
+    }
+
+    /// `name` parsed with `parse`, or `default` when unset or malformed.
+    pub fn from_env(name: &str, default: Limit) -> Self {
+        match std::env::var(name) {
+            Ok(value) => Self::parse(&value).unwrap_or_else(|| {
+                println!("ratelimit: ignoring malformed {}={:?}, using {}", name, value, default);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    fn quota(self) -> Quota {
+        let max = NonZeroU32::new(self.max.max(1)).unwrap();
+        Quota::with_period(self.per / max.get()).unwrap_or_else(|| Quota::per_second(max)).allow_burst(max)
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.per.subsec_millis() {
+            0 => write!(f, "{}/{}s", self.max, self.per.as_secs()),
+            _ => write!(f, "{}/{}ms", self.max, self.per.as_millis()),
+        }
+    }
+}
+
+/// A limiter's configuration and size, for status endpoints and metrics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimiterInfo {
+    pub name: String,
+    pub max: u32,
+    pub per_secs: u64,
+    /// Keys currently tracked.
+    pub keys: usize,
+}
+
+type Inner<K> = RateLimiter<K, DefaultKeyedStateStore<K>, DefaultClock, StateInformationMiddleware>;
+
+/// One `Limit` applied separately to each key (user, IP, connection...).
+pub struct KeyedLimiter<K: Hash + Eq + Clone> {
+    name: String,
+    limit: Limit,
+    inner: Inner<K>,
+}
+
+impl<K: Hash + Eq + Clone> KeyedLimiter<K> {
+    pub fn new(name: impl Into<String>, limit: Limit) -> Self {
+        let inner = RateLimiter::keyed(limit.quota()).with_middleware::<StateInformationMiddleware>();
+        Self { name: name.into(), limit, inner }
+    }
+
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    /// Takes one slot for `key`: how many are left, or how long until one
+    /// frees up.
+    pub fn check(&self, key: &K) -> Result<u32, Duration> {
+        self.inner
+            .check_key(key)
+            .map(|snapshot| snapshot.remaining_burst_capacity())
+            .map_err(|not_until| not_until.wait_time_from(DefaultClock::default().now()))
+    }
+
+    pub fn info(&self) -> LimiterInfo {
+        LimiterInfo {
+            name: self.name.clone(),
+            max: self.limit.max,
+            per_secs: self.limit.per.as_secs(),
+            keys: self.inner.len(),
+        }
+    }
+
+    /// Forgets keys whose bucket is full again; run periodically so idle
+    /// keys do not pile up.
+    pub fn retain_recent(&self) {
+        self.inner.retain_recent();
+        self.inner.shrink_to_fit();
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(Limit::parse("20/10s"), Some(Limit::new(20, Duration::from_secs(10))));
+        assert_eq!(Limit::parse("5/1m"), Some(Limit::new(5, Duration::from_secs(60))));
+        assert_eq!(Limit::parse("3/500ms"), Some(Limit::new(3, Duration::from_millis(500))));
+        assert_eq!(Limit::parse("20/10"), Some(Limit::new(20, Duration::from_secs(10))));
+        assert_eq!(Limit::parse("0/1s"), None);
+        assert_eq!(Limit::parse("5/1d"), None);
+        assert_eq!(Limit::parse("5"), None);
+    }
+
+    #[test]
+    fn refuses_past_the_burst_per_key() {
+        let limiter = KeyedLimiter::new("test", Limit::new(2, Duration::from_secs(60)));
+        assert_eq!(limiter.check(&"a"), Ok(1));
+        assert_eq!(limiter.check(&"a"), Ok(0));
+        let wait = limiter.check(&"a").unwrap_err();
+        assert!(wait > Duration::from_secs(20) && wait <= Duration::from_secs(30), "{:?}", wait);
+        assert!(limiter.check(&"b").is_ok());
+        assert_eq!(limiter.info().keys, 2);
+    }
+}
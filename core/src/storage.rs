@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+//
+// MEDIA STORAGE
+//
+// Uploaded files (gateway media and thumbnails, chat-service custom emoji)
+// are written through `Storage`, which returns the URL clients fetch them
+// from. `LocalStorage` keeps them on disk and the gateway serves them under
+// /media; an object-store backend only needs `put`, `get` and `delete`.
+//
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `bytes` under `key` and returns the object's public URL.
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<String>;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Removes `key`; a missing object is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Keys are generated by the services, but /media passes client input here
+/// too, so never let one leave the storage root.
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with('/')
+        && key.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+}
+
+/// Files under `UPLOAD_DIR` (default `uploads`), published as
+/// `MEDIA_BASE_URL/<key>` (default `/media`).
+pub struct LocalStorage {
+    root: PathBuf,
+    base_url: String,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>, base_url: &str) -> Self {
+        Self { root: root.into(), base_url: base_url.trim_end_matches('/').to_string() }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("UPLOAD_DIR").unwrap_or_else(|_| "uploads".into()),
+            &std::env::var("MEDIA_BASE_URL").unwrap_or_else(|_| "/media".into()),
+        )
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<String> {
+        if !valid_key(key) {
+            bail!("invalid storage key {:?}", key);
+        }
+        let path = self.root.join(key);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, bytes).await?;
+        Ok(format!("{}/{}", self.base_url, key))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if !valid_key(key) {
+            return Ok(None);
+        }
+        match tokio::fs::read(self.root.join(key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        if !valid_key(key) {
+            bail!("invalid storage key {:?}", key);
+        }
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use uchat_proto::events::{ClientEvent, ServerEvent};
+
+use crate::handlers::EventHandler;
+use crate::state::{AppState, Session};
+
+//
+// MESSAGE ACKS AND DELIVERY RECEIPTS
+//
+// A SendMessage with client_msg_id is answered with MessageAck once it is
+// published, naming the room and seq it went out as. Clients on flaky
+// links resend whatever has no ack after a reconnect: a resend of an id
+// the same user sent within GATEWAY_ACK_WINDOW_SECS (default 300) is acked
+// again with the original seq instead of being posted twice. Ids are per
+// user (per connection before login) and at most 64 characters. Slash
+// commands are not posted, so they are not acked; CommandResult (or an
+// Error) answers.
+//
+// With receipts set by a logged-in sender, the envelope carries
+// `receipts: true` and recipients answer Delivered { room, seq }. The first
+// Delivered from each logged-in user in the room, within the window, is
+// relayed to the sender's connections as MessageDelivered. Only senders
+// connected to this instance get them; with a backplane, a receipt for a
+// sender on another instance is dropped.
+//
+//   gateway_message_acks_total             acks sent for new messages
+//   gateway_message_ack_duplicates_total   resends acked without posting
+//   gateway_delivery_receipts_total        receipts relayed to senders
+//
+
+pub const MAX_CLIENT_MSG_ID: usize = 64;
+
+struct Sent {
+    room: String,
+    seq: u64,
+    at: Instant,
+}
+
+struct Awaiting {
+    sender: String,
+    at: Instant,
+    confirmed: HashSet<String>,
+}
+
+pub struct Acks {
+    window: Duration,
+    /// Keyed by (owner, client_msg_id); see `owner`.
+    sent: Mutex<HashMap<(String, String), Sent>>,
+    /// Messages sent with receipts, keyed by (room, seq).
+    awaiting: Mutex<HashMap<(String, u64), Awaiting>>,
+    acked: AtomicU64,
+    duplicates: AtomicU64,
+    relayed: AtomicU64,
+}
+
+impl Acks {
+    pub fn from_env() -> Self {
+        let secs = std::env::var("GATEWAY_ACK_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300);
+        Self {
+            window: Duration::from_secs(secs),
+            sent: Mutex::new(HashMap::new()),
+            awaiting: Mutex::new(HashMap::new()),
+            acked: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            relayed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whose ids a client_msg_id is unique among: the user once logged in,
+    /// the connection before.
+    fn owner(state: &AppState, session: &Session) -> String {
+        if logged_in(state, session) {
+            session.username.clone()
+        } else {
+            format!("#{}", session.id)
+        }
+    }
+
+    /// The ack to repeat when `id` was already posted, counting the resend.
+    pub fn resent(&self, state: &AppState, session: &Session, id: &str) -> Option<ServerEvent> {
+        let sent = self.sent.lock().unwrap();
+        let earlier = sent.get(&(Self::owner(state, session), id.to_string()))?;
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+        Some(ServerEvent::MessageAck { client_msg_id: id.to_string(), room: earlier.room.clone(), seq: earlier.seq })
+    }
+
+    /// Records that `id` went out as `seq` in `room`; returns the ack.
+    pub fn posted(&self, state: &AppState, session: &Session, id: String, room: &str, seq: u64) -> ServerEvent {
+        let sent = Sent { room: room.to_string(), seq, at: Instant::now() };
+        self.sent.lock().unwrap().insert((Self::owner(state, session), id.clone()), sent);
+        self.acked.fetch_add(1, Ordering::Relaxed);
+        ServerEvent::MessageAck { client_msg_id: id, room: room.to_string(), seq }
+    }
+
+    /// Starts collecting receipts for `sender`'s message `seq` in `room`.
+    pub fn await_receipts(&self, room: &str, seq: u64, sender: &str) {
+        let awaiting = Awaiting { sender: sender.to_string(), at: Instant::now(), confirmed: HashSet::new() };
+        self.awaiting.lock().unwrap().insert((room.to_string(), seq), awaiting);
+    }
+
+    /// The sender to tell, on `user`'s first receipt for a message that
+    /// asked for them.
+    fn delivered(&self, room: &str, seq: u64, user: &str) -> Option<String> {
+        let mut awaiting = self.awaiting.lock().unwrap();
+        let message = awaiting.get_mut(&(room.to_string(), seq))?;
+        if message.sender == user || !message.confirmed.insert(user.to_string()) {
+            return None;
+        }
+        Some(message.sender.clone())
+    }
+
+    /// Forgets acks and receipt requests older than the window; runs every
+    /// 30 seconds.
+    pub fn expire(&self) {
+        let window = self.window;
+        self.sent.lock().unwrap().retain(|_, s| s.at.elapsed() < window);
+        self.awaiting.lock().unwrap().retain(|_, a| a.at.elapsed() < window);
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, counter) in [
+            ("gateway_message_acks_total", &self.acked),
+            ("gateway_message_ack_duplicates_total", &self.duplicates),
+            ("gateway_delivery_receipts_total", &self.relayed),
+        ] {
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+pub fn logged_in(state: &AppState, session: &Session) -> bool {
+    state.connections.get(session.id).is_some_and(|c| c.username.is_some())
+}
+
+pub struct DeliveredHandler;
+
+#[async_trait]
+impl EventHandler for DeliveredHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let ClientEvent::Delivered { room, seq } = event else { return Ok(()) };
+        // receipts are best effort: anything that does not count is dropped
+        if !session.in_room(&room) || !logged_in(state, session) {
+            return Ok(());
+        }
+        if let Some(sender) = state.acks.delivered(&room, seq, &session.username) {
+            let to = session.username.clone();
+            if state.connections.send_to(&sender, &ServerEvent::MessageDelivered { room, seq, to }) > 0 {
+                state.acks.relayed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tungstenite::protocol::frame::coding::CloseCode;
+
+use uchat_proto::acl::RoomKind;
+use uchat_proto::events::ServerEvent;
+
+use crate::connections::ConnectionInfo;
+use crate::state::AppState;
+
+//
+// CONNECTION ADMINISTRATION
+//
+// What this instance holds, with real usernames, for operators dealing
+// with abuse (GET /debug/state redacts them):
+//
+//   GET  /admin/connections            open connections, oldest first;
+//                                      ?user= and ?room= narrow the list
+//   GET  /admin/rooms                  per room: its seq and who receives it
+//   POST /admin/connections/{id}/kick  closes one connection; body optional,
+//                                      {"reason": "..."} for the audit log
+//
+// Admins only. A kicked socket is closed with 1008 (policy violation),
+// "kicked by an admin"; a kicked long-poll session is ended and its next
+// poll answered 410. Nothing here stops the user from connecting again.
+// Kicks are in the audit log as connection.kick. Each instance answers for
+// its own connections only.
+//
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ConnectionsQuery {
+    user: Option<String>,
+    room: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Connections {
+    pub total: usize,
+    pub connections: Vec<ConnectionInfo>,
+}
+
+#[derive(Serialize)]
+pub struct RoomLoad {
+    pub room: String,
+    pub kind: RoomKind,
+    pub seq: u64,
+    /// Connections receiving the room.
+    pub connections: usize,
+    /// Distinct logged-in users among them.
+    pub users: usize,
+}
+
+#[derive(Serialize)]
+pub struct Rooms {
+    pub rooms: Vec<RoomLoad>,
+}
+
+#[derive(Deserialize)]
+pub struct KickRequest {
+    reason: Option<String>,
+}
+
+// GET /admin/connections
+pub async fn connections(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(q): Query<ConnectionsQuery>,
+) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+    let mut connections: Vec<ConnectionInfo> = state
+        .connections
+        .snapshot(true)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| q.user.as_ref().is_none_or(|user| c.username.as_ref() == Some(user)))
+        .filter(|c| q.room.as_ref().is_none_or(|room| c.rooms.contains(room)))
+        .collect();
+    connections.sort_by_key(|c| c.id);
+    Json(Connections { total: connections.len(), connections }).into_response()
+}
+
+// GET /admin/rooms
+pub async fn rooms(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+    // rooms with nothing published yet can still have listeners
+    let mut listeners: BTreeMap<String, (usize, BTreeSet<String>)> =
+        state.room_seqs(true).unwrap_or_default().into_keys().map(|room| (room, Default::default())).collect();
+    for conn in state.connections.snapshot(true).unwrap_or_default() {
+        for room in conn.rooms {
+            let (count, users) = listeners.entry(room).or_default();
+            *count += 1;
+            users.extend(conn.username.clone());
+        }
+    }
+    let rooms = listeners
+        .into_iter()
+        .map(|(room, (connections, users))| RoomLoad {
+            kind: state.rooms.kind(&room),
+            seq: state.current_seq(&room),
+            room,
+            connections,
+            users: users.len(),
+        })
+        .collect();
+    Json(Rooms { rooms }).into_response()
+}
+
+// POST /admin/connections/:id/kick
+pub async fn kick(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    body: Option<Json<KickRequest>>,
+) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    let Some(conn) = state.connections.get(id) else {
+        return error(StatusCode::NOT_FOUND, "no such connection");
+    };
+    let closed = match conn.transport {
+        "poll" => state.poll_sessions.close(&state, id),
+        _ => state.connections.close(id, CloseCode::Policy, "kicked by an admin"),
+    };
+    if !closed {
+        return error(StatusCode::NOT_FOUND, "no such connection");
+    }
+
+    let user = conn.username.clone().unwrap_or_default();
+    println!("GATEWAY: {} kicked connection {} ({})", admin, id, user);
+    let reason = body.and_then(|Json(b)| b.reason).unwrap_or_default();
+    let detail = if reason.is_empty() { user } else { format!("{}: {}", user, reason) };
+    state.audit.record("connection.kick", &admin, &id.to_string(), detail);
+    Json(conn).into_response()
+}
@@ -0,0 +1,53 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use serde::Serialize;
+
+//
+// AUDIT TRAIL
+//
+// Security-relevant actions are printed as one JSON line each (prefixed
+// `AUDIT`) for log shipping, and the most recent ones are kept in memory
+// for the admin API.
+//
+
+const KEEP: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub ts: String,
+    pub action: String,
+    pub actor: String,
+    pub target: String,
+    pub detail: String,
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+    recent: Mutex<VecDeque<AuditEvent>>,
+}
+
+impl AuditLog {
+    pub fn record(&self, action: &str, actor: &str, target: &str, detail: impl Into<String>) {
+        let event = AuditEvent {
+            ts: Utc::now().to_rfc3339(),
+            action: action.into(),
+            actor: actor.into(),
+            target: target.into(),
+            detail: detail.into(),
+        };
+        println!("AUDIT {}", serde_json::to_string(&event).unwrap());
+
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(event);
+        while recent.len() > KEEP {
+            recent.pop_front();
+        }
+    }
+
+    /// The newest `limit` events, newest first.
+    pub fn recent(&self, limit: usize) -> Vec<AuditEvent> {
+        self.recent.lock().unwrap().iter().rev().take(limit).cloned().collect()
+    }
+}
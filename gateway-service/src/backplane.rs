@@ -0,0 +1,263 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use uchat_proto::envelope::Envelope;
+
+#[cfg(feature = "redis")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "redis")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "redis")]
+use redis::aio::MultiplexedConnection;
+
+#[cfg(feature = "redis")]
+use crate::state::AppState;
+
+//
+// CROSS-INSTANCE BACKPLANE
+//
+// Each instance fans room events out through its own broadcast channel,
+// which only reaches its own connections. With several replicas behind the
+// load balancer, a Redis backplane (built with `--features redis`) carries
+// every room event to every instance:
+//
+//   GATEWAY_BACKPLANE_URL     redis://host:6379/0; unset keeps events local
+//   GATEWAY_BACKPLANE_PREFIX  key and channel prefix (default "uchat")
+//
+// A publish runs one script on Redis that takes the room's next seq
+// (<prefix>:seq:<room>, never below what this instance's journal has seen)
+// and PUBLISHes the envelope on <prefix>:rooms. Redis runs scripts one at a
+// time, so every instance receives a room's events in seq order. Each
+// instance, the publishing one included, subscribes to the channel and
+// hands what arrives to its broadcast channel; the publishing instance
+// still writes the journal, which instances share (see journal.rs).
+// Publishes share one multiplexed connection and wait for Redis only
+// within the publishing room's turn, so a slow Redis holds up that room
+// and no other.
+//
+// If Redis cannot be reached, events are numbered and delivered locally as
+// without a backplane, and other instances' clients see the gap in seq and
+// resume. While this instance's subscription is down, its own events are
+// delivered locally and the others' are missed the same way.
+//
+//   gateway_backplane_connected        gauge, 1 while subscribed
+//   gateway_backplane_published_total  counter
+//   gateway_backplane_received_total   counter
+//   gateway_backplane_failures_total   counter, publishes delivered locally instead
+//
+
+#[cfg(feature = "redis")]
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Between connection attempts, so an outage does not stall every publish.
+#[cfg(feature = "redis")]
+const RETRY: Duration = Duration::from_secs(5);
+
+/// KEYS[1] seq counter; ARGV[1] the publisher's last seq, ARGV[2] channel,
+/// ARGV[3] envelope. Publishes "<seq> <envelope>" and returns the seq.
+#[cfg(feature = "redis")]
+const PUBLISH_SCRIPT: &str = r"
+local seq = redis.call('INCR', KEYS[1])
+local floor = tonumber(ARGV[1])
+if seq <= floor then
+    seq = floor + 1
+    redis.call('SET', KEYS[1], seq)
+end
+redis.call('PUBLISH', ARGV[2], seq .. ' ' .. ARGV[3])
+return seq
+";
+
+#[cfg(feature = "redis")]
+struct Redis {
+    client: redis::Client,
+    prefix: String,
+    script: redis::Script,
+    /// Shared by publishes in every room; None until connected, and
+    /// after a failure.
+    conn: Mutex<Option<MultiplexedConnection>>,
+    retry_at: Mutex<Option<Instant>>,
+}
+
+pub struct Backplane {
+    url: Option<String>,
+    #[cfg(feature = "redis")]
+    redis: Option<Redis>,
+    connected: AtomicBool,
+    published: AtomicU64,
+    received: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl Backplane {
+    pub fn from_env() -> Self {
+        let url = std::env::var("GATEWAY_BACKPLANE_URL").ok().filter(|u| !u.is_empty());
+        #[cfg(feature = "redis")]
+        let redis = url.as_deref().and_then(|url| match redis::Client::open(url) {
+            Ok(client) => Some(Redis {
+                client,
+                prefix: std::env::var("GATEWAY_BACKPLANE_PREFIX").unwrap_or_else(|_| "uchat".into()),
+                script: redis::Script::new(PUBLISH_SCRIPT),
+                conn: Mutex::new(None),
+                retry_at: Mutex::new(None),
+            }),
+            Err(e) => {
+                println!("GATEWAY: ignoring GATEWAY_BACKPLANE_URL: {}", e);
+                None
+            }
+        });
+        Self {
+            url,
+            #[cfg(feature = "redis")]
+            redis,
+            connected: AtomicBool::new(false),
+            published: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    /// A backplane URL was given; whether it can be used depends on the build.
+    pub fn configured(&self) -> bool {
+        self.url.is_some()
+    }
+
+    /// Whether this instance's subscription is up, so its own events come
+    /// back through it.
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Numbers `envelope` cluster-wide and sends it to every instance;
+    /// false when there is no backplane or Redis failed, and the caller
+    /// numbers and delivers it locally. `last_seq` is the room's last seq
+    /// on this instance.
+    #[cfg(feature = "redis")]
+    pub async fn publish(&self, envelope: &mut Envelope, last_seq: u64) -> bool {
+        let Some(redis) = &self.redis else { return false };
+        let Some(mut conn) = self.connection(redis).await else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            return false;
+        };
+
+        let json = serde_json::to_string(envelope).unwrap();
+        let mut invocation = redis.script.key(format!("{}:seq:{}", redis.prefix, envelope.room));
+        invocation.arg(last_seq).arg(redis.channel()).arg(json);
+        let result = match tokio::time::timeout(TIMEOUT, invocation.invoke_async::<_, u64>(&mut conn)).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err("timed out".to_string()),
+        };
+        match result {
+            Ok(seq) => {
+                envelope.seq = seq;
+                self.published.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(e) => {
+                println!("GATEWAY: backplane publish failed, delivering locally: {}", e);
+                *redis.conn.lock().unwrap() = None;
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// The publish connection, connected if need be; None while Redis is
+    /// unreachable, retrying every RETRY.
+    #[cfg(feature = "redis")]
+    async fn connection(&self, redis: &Redis) -> Option<MultiplexedConnection> {
+        if let Some(conn) = redis.conn.lock().unwrap().clone() {
+            return Some(conn);
+        }
+        if redis.retry_at.lock().unwrap().is_some_and(|at| Instant::now() < at) {
+            return None;
+        }
+        let connected = match tokio::time::timeout(TIMEOUT, redis.client.get_multiplexed_tokio_connection()).await {
+            Ok(connected) => connected.map_err(|e| e.to_string()),
+            Err(_) => Err("timed out".to_string()),
+        };
+        match connected {
+            Ok(conn) => {
+                *redis.conn.lock().unwrap() = Some(conn.clone());
+                Some(conn)
+            }
+            Err(e) => {
+                println!("GATEWAY: backplane unreachable, delivering locally: {}", e);
+                *redis.retry_at.lock().unwrap() = Some(Instant::now() + RETRY);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "redis"))]
+    pub async fn publish(&self, _envelope: &mut Envelope, _last_seq: u64) -> bool {
+        false
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE gateway_backplane_connected gauge");
+        let _ = writeln!(out, "gateway_backplane_connected {}", self.connected() as u8);
+        let counters = [("published", &self.published), ("received", &self.received), ("failures", &self.failures)];
+        for (name, counter) in counters {
+            let _ = writeln!(out, "# TYPE gateway_backplane_{}_total counter", name);
+            let _ = writeln!(out, "gateway_backplane_{}_total {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Redis {
+    fn channel(&self) -> String {
+        format!("{}:rooms", self.prefix)
+    }
+}
+
+/// Subscribes to the backplane and delivers what arrives to this
+/// instance's connections, resubscribing after failures. Returns at once
+/// without a backplane.
+#[cfg(feature = "redis")]
+pub async fn run(state: Arc<AppState>) {
+    use futures_util::StreamExt;
+
+    let Some(redis) = &state.backplane.redis else { return };
+    loop {
+        let subscribed = async {
+            let mut pubsub = redis.client.get_async_connection().await?.into_pubsub();
+            pubsub.subscribe(redis.channel()).await?;
+            println!("GATEWAY: backplane subscribed to {}", redis.channel());
+            state.backplane.connected.store(true, Ordering::Relaxed);
+            let mut messages = pubsub.on_message();
+            while let Some(message) = messages.next().await {
+                let payload: String = message.get_payload()?;
+                deliver(&state, &payload);
+            }
+            redis::RedisResult::Ok(())
+        }
+        .await;
+        state.backplane.connected.store(false, Ordering::Relaxed);
+        match subscribed {
+            Ok(()) => println!("GATEWAY: backplane subscription closed, resubscribing"),
+            Err(e) => println!("GATEWAY: backplane subscription failed: {}", e),
+        }
+        tokio::time::sleep(RETRY).await;
+    }
+}
+
+/// Hands one "<seq> <envelope>" message to the broadcast channel.
+#[cfg(feature = "redis")]
+fn deliver(state: &AppState, payload: &str) {
+    let parsed = payload
+        .split_once(' ')
+        .and_then(|(seq, json)| Some((seq.parse().ok()?, serde_json::from_str::<Envelope>(json).ok()?)));
+    let Some((seq, mut envelope)) = parsed else {
+        println!("GATEWAY: ignoring malformed backplane message");
+        return;
+    };
+    envelope.seq = seq;
+    state.backplane.received.fetch_add(1, Ordering::Relaxed);
+    state.observe_seq(&envelope.room, seq);
+    state.replay.push(&envelope);
+    let _ = state.tx.send(envelope);
+}
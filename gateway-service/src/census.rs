@@ -0,0 +1,422 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use uchat_proto::events::{InstanceInfo, ServerEvent};
+
+use crate::connections::ConnectionInfo;
+use crate::journal;
+use crate::state::AppState;
+
+//
+// CONNECTION CENSUS
+//
+// With several gateway instances behind the load balancer, "where is user
+// X connected" needs a cluster view. Each instance keeps its logged-in
+// connections in a `census` table in the journal's SQLite file, shared
+// like the journal itself (see journal.rs), and names itself in
+// `census_instances`. Logins and disconnects are written as they happen;
+// a heartbeat rewrites the instance's rows and pushes their expiry out,
+// so the rows of an instance that dies lapse on their own.
+//
+//   GATEWAY_CENSUS_HEARTBEAT_SECS  how often the rows are refreshed (default 10)
+//   GATEWAY_CENSUS_TTL_SECS        rows not refreshed for this long are
+//                                  ignored, then pruned (default 30)
+//   GATEWAY_ADVERTISE_URL          this instance's HTTP address as other
+//                                  services reach it (default
+//                                  http://<instance id>:7000)
+//   GATEWAY_SERVICE_ACCOUNTS       token subjects of the services allowed
+//                                  the API below, "chat-service,moderation"
+//   GATEWAY_PUBLIC_URL             this instance's WebSocket URL as clients
+//                                  reach it, for redirect hints while
+//                                  another instance drains (see drain.rs)
+//
+//   GET  /census/users/:user         the user's connections cluster-wide,
+//                                    with the instance holding each
+//   GET  /census/instances           live instances and their connection counts
+//   POST /census/users/:user/events  delivers a ServerEvent to the user's
+//                                    connections on this instance
+//
+// Admins and service accounts only. A service routes an event to a user
+// by looking them up and posting it to the `url` of each instance listed.
+//
+//   gateway_census_connections           gauge, this instance's rows
+//   gateway_census_write_failures_total  counter
+//
+
+pub struct Census {
+    conn: Mutex<Connection>,
+    instance: InstanceInfo,
+    url: String,
+    public_url: Option<String>,
+    /// Mirrors the drain state into this instance's census entry.
+    draining: AtomicBool,
+    pub heartbeat: Duration,
+    ttl: Duration,
+    service_accounts: Vec<String>,
+    rows: AtomicU64,
+    write_failures: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Located {
+    pub instance: String,
+    pub region: Option<String>,
+    pub url: String,
+    pub connection: u64,
+    pub class: String,
+    pub transport: String,
+    pub connected_at: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserCensus {
+    pub user: String,
+    pub online: bool,
+    pub connections: Vec<Located>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveInstance {
+    pub instance: String,
+    pub region: Option<String>,
+    pub url: String,
+    pub public_url: Option<String>,
+    pub draining: bool,
+    pub started_at: String,
+    pub connections: u64,
+}
+
+#[derive(Serialize)]
+pub struct Delivered {
+    delivered: usize,
+}
+
+fn env_secs(name: &str, default: u64) -> Duration {
+    Duration::from_secs(std::env::var(name).ok().and_then(|v| v.parse().ok()).filter(|s| *s > 0).unwrap_or(default))
+}
+
+impl Census {
+    pub fn from_env(instance: &InstanceInfo) -> rusqlite::Result<Self> {
+        let url = std::env::var("GATEWAY_ADVERTISE_URL")
+            .ok()
+            .filter(|u| !u.trim().is_empty())
+            .unwrap_or_else(|| format!("http://{}:7000", instance.instance_id));
+        let service_accounts = std::env::var("GATEWAY_SERVICE_ACCOUNTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+        let public_url = std::env::var("GATEWAY_PUBLIC_URL").ok().filter(|u| !u.trim().is_empty());
+        Self::open(
+            &journal::path_from_env(),
+            instance,
+            url.trim_end_matches('/'),
+            public_url,
+            env_secs("GATEWAY_CENSUS_HEARTBEAT_SECS", 10),
+            env_secs("GATEWAY_CENSUS_TTL_SECS", 30),
+            service_accounts,
+        )
+    }
+
+    pub fn open(
+        path: &str,
+        instance: &InstanceInfo,
+        url: &str,
+        public_url: Option<String>,
+        heartbeat: Duration,
+        ttl: Duration,
+        service_accounts: Vec<String>,
+    ) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS census (
+                instance     TEXT NOT NULL,
+                connection   INTEGER NOT NULL,
+                username     TEXT NOT NULL,
+                class        TEXT NOT NULL,
+                transport    TEXT NOT NULL,
+                connected_at TEXT NOT NULL,
+                expires_at   INTEGER NOT NULL,
+                PRIMARY KEY (instance, connection)
+            );
+            CREATE INDEX IF NOT EXISTS census_username ON census (username);
+            CREATE TABLE IF NOT EXISTS census_instances (
+                instance   TEXT PRIMARY KEY,
+                region     TEXT,
+                url        TEXT NOT NULL,
+                started_at TEXT NOT NULL,
+                expires_at INTEGER NOT NULL,
+                public_url TEXT,
+                draining   INTEGER NOT NULL DEFAULT 0
+            );",
+        )?;
+        // files created before the drain columns; older instances sharing
+        // the file keep writing the columns they know
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('census_instances')")?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (column, ddl) in [("public_url", "public_url TEXT"), ("draining", "draining INTEGER NOT NULL DEFAULT 0")] {
+            if !columns.iter().any(|c| c == column) {
+                conn.execute_batch(&format!("ALTER TABLE census_instances ADD COLUMN {}", ddl))?;
+            }
+        }
+        // rows left by an earlier process under the same instance id
+        conn.execute("DELETE FROM census WHERE instance = ?1", [&instance.instance_id])?;
+        let census = Self {
+            conn: Mutex::new(conn),
+            instance: instance.clone(),
+            url: url.to_string(),
+            public_url,
+            draining: AtomicBool::new(false),
+            heartbeat,
+            ttl,
+            service_accounts,
+            rows: AtomicU64::new(0),
+            write_failures: AtomicU64::new(0),
+        };
+        census.heartbeat(&[]).map(|_| census)
+    }
+
+    fn expires_at(&self) -> i64 {
+        Utc::now().timestamp() + self.ttl.as_secs() as i64
+    }
+
+    fn failed(&self, what: &str, e: rusqlite::Error) {
+        self.write_failures.fetch_add(1, Ordering::Relaxed);
+        println!("GATEWAY: census {} failed: {}", what, e);
+    }
+
+    fn insert(&self, conn: &Connection, info: &ConnectionInfo) -> rusqlite::Result<()> {
+        let Some(username) = &info.username else { return Ok(()) };
+        conn.execute(
+            "INSERT OR REPLACE INTO census
+                 (instance, connection, username, class, transport, connected_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                self.instance.instance_id,
+                info.id as i64,
+                username,
+                info.class.as_str(),
+                info.transport,
+                info.connected_at.to_rfc3339(),
+                self.expires_at()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records connection `id` under the name it just logged in as.
+    pub fn logged_in(&self, state: &AppState, id: u64) {
+        let Some(info) = state.connections.get(id) else { return };
+        if let Err(e) = self.insert(&self.conn.lock().unwrap(), &info) {
+            self.failed("login", e);
+        }
+    }
+
+    pub fn closed(&self, id: u64) {
+        let res = self.conn.lock().unwrap().execute(
+            "DELETE FROM census WHERE instance = ?1 AND connection = ?2",
+            params![self.instance.instance_id, id as i64],
+        );
+        if let Err(e) = res {
+            self.failed("disconnect", e);
+        }
+    }
+
+    /// Replaces this instance's rows with `conns`, renews its own entry and
+    /// prunes whatever lapsed, this instance's or another's.
+    pub fn heartbeat(&self, conns: &[ConnectionInfo]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM census WHERE instance = ?1", [&self.instance.instance_id])?;
+        for info in conns {
+            self.insert(&tx, info)?;
+        }
+        tx.execute(
+            "INSERT INTO census_instances (instance, region, url, started_at, expires_at, public_url, draining)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (instance) DO UPDATE
+                 SET region = ?2, url = ?3, expires_at = ?5, public_url = ?6, draining = ?7",
+            params![
+                self.instance.instance_id,
+                self.instance.region,
+                self.url,
+                Utc::now().to_rfc3339(),
+                self.expires_at(),
+                self.public_url,
+                self.draining.load(Ordering::Relaxed)
+            ],
+        )?;
+        let now = Utc::now().timestamp();
+        tx.execute("DELETE FROM census WHERE expires_at <= ?1", [now])?;
+        tx.execute("DELETE FROM census_instances WHERE expires_at <= ?1", [now])?;
+        tx.commit()?;
+        self.rows.store(conns.iter().filter(|c| c.username.is_some()).count() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Where `user` is connected, across live instances.
+    pub fn locate(&self, user: &str) -> rusqlite::Result<Vec<Located>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT c.instance, i.region, i.url, c.connection, c.class, c.transport, c.connected_at
+             FROM census c JOIN census_instances i ON i.instance = c.instance
+             WHERE c.username = ?1 AND c.expires_at > ?2 AND i.expires_at > ?2
+             ORDER BY c.instance, c.connection",
+        )?;
+        let rows = stmt.query_map(params![user, Utc::now().timestamp()], |r| {
+            Ok(Located {
+                instance: r.get(0)?,
+                region: r.get(1)?,
+                url: r.get(2)?,
+                connection: r.get::<_, i64>(3)? as u64,
+                class: r.get(4)?,
+                transport: r.get(5)?,
+                connected_at: r.get(6)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Marks this instance draining, or not, in its census entry.
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+        let res = self.conn.lock().unwrap().execute(
+            "UPDATE census_instances SET draining = ?2 WHERE instance = ?1",
+            params![self.instance.instance_id, draining],
+        );
+        if let Err(e) = res {
+            self.failed("drain update", e);
+        }
+    }
+
+    /// Public URLs of the other live instances that are not draining.
+    pub fn alternates(&self) -> rusqlite::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT public_url FROM census_instances
+             WHERE instance != ?1 AND expires_at > ?2 AND NOT draining AND public_url IS NOT NULL
+             ORDER BY instance",
+        )?;
+        let rows = stmt.query_map(params![self.instance.instance_id, Utc::now().timestamp()], |r| r.get(0))?;
+        rows.collect()
+    }
+
+    pub fn instances(&self) -> rusqlite::Result<Vec<LiveInstance>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT i.instance, i.region, i.url, i.public_url, i.draining, i.started_at,
+                    (SELECT COUNT(*) FROM census c WHERE c.instance = i.instance AND c.expires_at > ?1)
+             FROM census_instances i WHERE i.expires_at > ?1 ORDER BY i.instance",
+        )?;
+        let rows = stmt.query_map([Utc::now().timestamp()], |r| {
+            Ok(LiveInstance {
+                instance: r.get(0)?,
+                region: r.get(1)?,
+                url: r.get(2)?,
+                public_url: r.get(3)?,
+                draining: r.get(4)?,
+                started_at: r.get(5)?,
+                connections: r.get::<_, i64>(6)? as u64,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# TYPE gateway_census_connections gauge\ngateway_census_connections {}",
+            self.rows.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE gateway_census_write_failures_total counter\ngateway_census_write_failures_total {}",
+            self.write_failures.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+pub async fn heartbeat(state: Arc<AppState>) -> anyhow::Result<()> {
+    let conns = state.connections.snapshot(true).unwrap_or_default();
+    if let Err(e) = state.census.heartbeat(&conns) {
+        state.census.write_failures.fetch_add(1, Ordering::Relaxed);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+/// Admins and service accounts.
+fn internal(state: &AppState, headers: &HeaderMap) -> Result<String, (StatusCode, &'static str)> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    match state.bearer_claims(auth) {
+        None => Err((StatusCode::UNAUTHORIZED, "missing or invalid token")),
+        Some(claims) if state.is_admin(&claims.sub) || state.census.service_accounts.contains(&claims.sub) => {
+            Ok(claims.sub)
+        }
+        Some(_) => Err((StatusCode::FORBIDDEN, "admins and service accounts only")),
+    }
+}
+
+fn storage_failed(e: rusqlite::Error) -> Response {
+    println!("GATEWAY: census read failed: {}", e);
+    error(StatusCode::INTERNAL_SERVER_ERROR, "census storage failed")
+}
+
+// GET /census/users/:user
+pub async fn locate(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(user): Path<String>) -> Response {
+    if let Err((status, details)) = internal(&state, &headers) {
+        return error(status, details);
+    }
+    match state.census.locate(&user) {
+        Ok(connections) => Json(UserCensus { user, online: !connections.is_empty(), connections }).into_response(),
+        Err(e) => storage_failed(e),
+    }
+}
+
+// GET /census/instances
+pub async fn instances(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err((status, details)) = internal(&state, &headers) {
+        return error(status, details);
+    }
+    match state.census.instances() {
+        Ok(instances) => Json(instances).into_response(),
+        Err(e) => storage_failed(e),
+    }
+}
+
+// POST /census/users/:user/events
+pub async fn deliver(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+    Json(event): Json<ServerEvent>,
+) -> Response {
+    if let Err((status, details)) = internal(&state, &headers) {
+        return error(status, details);
+    }
+    let delivered = state.connections.send_to(&user, &event);
+    Json(Delivered { delivered }).into_response()
+}
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use uchat_proto::events::{ClientEvent, ClientSettings, ServerEvent};
+
+use crate::connections::{ConnectionInfo, ProtocolState};
+use crate::handlers::EventHandler;
+use crate::profiles::ClientClass;
+use crate::state::{AppState, Session};
+
+//
+// CLIENT CONFIGURATION PUSH
+//
+// Operators tune clients, the ESP32 fleet above all, without a firmware
+// release: settings set through the admin API reach connections as
+// ConfigUpdate, and clients answer ConfigAck with the version they applied.
+//
+// Settings come in layers; for a connection, later layers override
+// earlier ones field by field (features name by name):
+//
+//   default          every connection
+//   class:<class>    connections of a client class (see profiles.rs)
+//   user:<name>      connections logged in as a user or device
+//
+//   GET    /client-config          the layers and the current version
+//   PUT    /client-config/:target  sets a layer (body: ClientSettings)
+//   DELETE /client-config/:target  removes it
+//   GET    /client-config/status   per connection, versions sent and acked
+//
+// All admins only. Every change bumps the version and pushes the merged
+// settings to the connections it affects; a connection gets its settings
+// after Hello and again after login. Layers live in memory, like device
+// command queues.
+//
+
+/// Accepted `telemetry_level` values.
+const TELEMETRY_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug"];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Default,
+    Class(ClientClass),
+    User(String),
+}
+
+impl Target {
+    fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "default" => Some(Target::Default),
+            Some(("class", class)) => ClientClass::parse(class).map(Target::Class),
+            Some(("user", user)) if !user.is_empty() => Some(Target::User(user.to_string())),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Target::Default => "default".into(),
+            Target::Class(class) => format!("class:{}", class.as_str()),
+            Target::User(user) => format!("user:{}", user),
+        }
+    }
+
+    fn covers(&self, conn: &ConnectionInfo) -> bool {
+        match self {
+            Target::Default => true,
+            Target::Class(class) => conn.class == *class,
+            Target::User(user) => conn.username.as_ref() == Some(user),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Layers {
+    version: u64,
+    /// In merge order: default, classes, users.
+    layers: BTreeMap<Target, ClientSettings>,
+}
+
+impl Layers {
+    fn merged(&self, conn: &ConnectionInfo) -> ClientSettings {
+        let mut out = ClientSettings::default();
+        for settings in self.layers.iter().filter(|(t, _)| t.covers(conn)).map(|(_, s)| s) {
+            if settings.heartbeat_interval_secs.is_some() {
+                out.heartbeat_interval_secs = settings.heartbeat_interval_secs;
+            }
+            if settings.telemetry_level.is_some() {
+                out.telemetry_level = settings.telemetry_level.clone();
+            }
+            out.features.extend(settings.features.iter().map(|(k, v)| (k.clone(), *v)));
+        }
+        out
+    }
+
+    /// The ConfigUpdate for `conn`, recording it as sent; None when no
+    /// layer has anything for it and it was never sent settings before.
+    fn update_for(&self, conn: &mut ConnectionInfo) -> Option<ServerEvent> {
+        let settings = self.merged(conn);
+        if settings == ClientSettings::default() && conn.config_sent.is_none() {
+            return None;
+        }
+        conn.config_sent = Some(self.version);
+        Some(ServerEvent::ConfigUpdate { version: self.version, settings })
+    }
+}
+
+#[derive(Default)]
+pub struct ClientConfigs {
+    layers: Mutex<Layers>,
+}
+
+impl ClientConfigs {
+    /// Sends connection `id` its settings, if it has any.
+    pub fn push_to(&self, state: &AppState, id: u64) {
+        let layers = self.layers.lock().unwrap();
+        state.connections.send_each(|conn| if conn.id == id { layers.update_for(conn) } else { None });
+    }
+
+    /// Replaces (or with None removes) a layer and pushes the result to
+    /// the connections it covers; returns the new version and how many
+    /// connections were sent it.
+    fn set(&self, state: &AppState, target: Target, settings: Option<ClientSettings>) -> (u64, usize) {
+        let mut layers = self.layers.lock().unwrap();
+        layers.version += 1;
+        match settings {
+            Some(settings) => layers.layers.insert(target.clone(), settings),
+            None => layers.layers.remove(&target),
+        };
+        let pushed = state.connections.send_each(|conn| {
+            let covered = conn.state == ProtocolState::Ready && target.covers(conn);
+            if covered { layers.update_for(conn) } else { None }
+        });
+        (layers.version, pushed)
+    }
+}
+
+pub struct ConfigAckHandler;
+
+#[async_trait]
+impl EventHandler for ConfigAckHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let ClientEvent::ConfigAck { version } = event else { return Ok(()) };
+
+        let mut known = false;
+        state.connections.update(session.id, |conn| {
+            known = conn.config_sent.is_some_and(|sent| version <= sent);
+            if known {
+                conn.config_acked = Some(version);
+            }
+        });
+        if !known {
+            session.reply(&ServerEvent::Error { details: format!("no config version {} was sent", version), code: None });
+        }
+        Ok(())
+    }
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+#[derive(Serialize)]
+pub struct ConfigLayers {
+    version: u64,
+    layers: BTreeMap<String, ClientSettings>,
+}
+
+#[derive(Serialize)]
+pub struct Pushed {
+    version: u64,
+    /// Connections sent the new settings.
+    pushed: usize,
+}
+
+#[derive(Serialize)]
+pub struct ConfigStatus {
+    connection: u64,
+    username: Option<String>,
+    class: ClientClass,
+    sent: Option<u64>,
+    acked: Option<u64>,
+    connected_at: DateTime<Utc>,
+}
+
+// GET /client-config
+pub async fn list(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+    let layers = state.client_config.layers.lock().unwrap();
+    Json(ConfigLayers {
+        version: layers.version,
+        layers: layers.layers.iter().map(|(t, s)| (t.name(), s.clone())).collect(),
+    })
+    .into_response()
+}
+
+// PUT /client-config/:target
+pub async fn put_layer(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+    Json(settings): Json<ClientSettings>,
+) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    let Some(parsed) = Target::parse(&target) else {
+        return error(StatusCode::BAD_REQUEST, "target must be default, class:<class> or user:<name>");
+    };
+    if settings.heartbeat_interval_secs == Some(0) {
+        return error(StatusCode::BAD_REQUEST, "heartbeat_interval_secs must be positive");
+    }
+    if settings.telemetry_level.as_deref().is_some_and(|l| !TELEMETRY_LEVELS.contains(&l)) {
+        return error(StatusCode::BAD_REQUEST, "telemetry_level must be off, error, warn, info or debug");
+    }
+    let detail = serde_json::to_string(&settings).unwrap();
+    let (version, pushed) = state.client_config.set(&state, parsed, Some(settings));
+    state.audit.record("client_config.set", &admin, &target, &detail);
+    Json(Pushed { version, pushed }).into_response()
+}
+
+// DELETE /client-config/:target
+pub async fn delete_layer(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    let Some(parsed) = Target::parse(&target) else {
+        return error(StatusCode::BAD_REQUEST, "target must be default, class:<class> or user:<name>");
+    };
+    if !state.client_config.layers.lock().unwrap().layers.contains_key(&parsed) {
+        return error(StatusCode::NOT_FOUND, "no such layer");
+    }
+    let (version, pushed) = state.client_config.set(&state, parsed, None);
+    state.audit.record("client_config.delete", &admin, &target, "");
+    Json(Pushed { version, pushed }).into_response()
+}
+
+// GET /client-config/status
+pub async fn status(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+    let mut conns = state.connections.snapshot(true).unwrap_or_default();
+    conns.sort_by_key(|c| c.id);
+    let status: Vec<ConfigStatus> = conns
+        .into_iter()
+        .map(|c| ConfigStatus {
+            connection: c.id,
+            username: c.username,
+            class: c.class,
+            sent: c.config_sent,
+            acked: c.config_acked,
+            connected_at: c.connected_at,
+        })
+        .collect();
+    Json(status).into_response()
+}
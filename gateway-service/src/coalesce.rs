@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use uchat_proto::events::Batching;
+
+//
+// ROOM EVENT COALESCING
+//
+// Telemetry rooms publish hundreds of small events a second, and sending
+// each as its own frame costs more in framing and syscalls than the events
+// themselves. For client classes with `batch_ms` set (see profiles.rs) the
+// delivery task collects a connection's room events here and sends them
+// together as one Batch frame:
+//
+//   {"Batch":{"events":[{"room":"telemetry","seq":41,...},{...}]}}
+//
+// A batch goes out `batch_ms` after its first event, or as soon as the
+// next event would take it past `batch_bytes`, whichever comes first. A
+// batch of one is sent as the plain envelope. Events keep their delivery
+// order, so per-room seq order holds within and across batches.
+//
+
+/// `{"Batch":{"events":[` and `]}}`.
+const FRAME_OVERHEAD: usize = 23;
+
+pub struct Coalescer {
+    delay: Duration,
+    max_bytes: usize,
+    /// Serialized envelopes, in delivery order.
+    events: Vec<String>,
+    bytes: usize,
+    deadline: Option<Instant>,
+}
+
+impl Coalescer {
+    pub fn new(batching: &Batching) -> Self {
+        Self {
+            delay: Duration::from_millis(batching.max_delay_ms),
+            max_bytes: batching.max_bytes,
+            events: Vec::new(),
+            bytes: FRAME_OVERHEAD,
+            deadline: None,
+        }
+    }
+
+    /// When the pending batch is due, if anything is pending.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Adds a serialized envelope; returns the frame it pushed out, if the
+    /// batch filled up.
+    pub fn push(&mut self, json: String) -> Option<(String, usize)> {
+        let full = !self.events.is_empty() && self.bytes + 1 + json.len() > self.max_bytes;
+        let flushed = if full { self.take() } else { None };
+        if self.events.is_empty() {
+            self.deadline = Some(Instant::now() + self.delay);
+        } else {
+            self.bytes += 1;
+        }
+        self.bytes += json.len();
+        self.events.push(json);
+        flushed
+    }
+
+    /// The pending events as one frame, with how many it holds.
+    pub fn take(&mut self) -> Option<(String, usize)> {
+        self.deadline = None;
+        self.bytes = FRAME_OVERHEAD;
+        match self.events.len() {
+            0 => None,
+            1 => self.events.pop().map(|json| (json, 1)),
+            n => {
+                // the envelopes are JSON already; splice rather than re-encode
+                let frame = format!("{{\"Batch\":{{\"events\":[{}]}}}}", self.events.join(","));
+                self.events.clear();
+                Some((frame, n))
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use uchat_core::i18n::codes;
+use uchat_core::ratelimit::{KeyedLimiter, Limit, LimiterInfo};
+use uchat_proto::events::ServerEvent;
+
+use crate::policy::PolicyAction;
+use crate::state::{admins_from_env, AppState, Session};
+
+//
+// SLASH COMMANDS
+//
+// "/name arg1 arg2" typed into chat is routed here instead of being
+// broadcast. Handlers are either local functions or HTTP callouts to bots.
+// A room-visible reply is posted like a chat message, so it needs the same
+// rights (authorize_post, policy); calling a bot needs them up front.
+//
+
+const CALLOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Ephemeral,
+    Room,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub visibility: Visibility,
+    pub text: String,
+}
+
+impl CommandResponse {
+    pub fn ephemeral(text: impl Into<String>) -> Self {
+        Self { visibility: Visibility::Ephemeral, text: text.into() }
+    }
+
+    pub fn room(text: impl Into<String>) -> Self {
+        Self { visibility: Visibility::Room, text: text.into() }
+    }
+}
+
+/// What a handler gets to see about the invocation.
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandContext {
+    pub command: String,
+    pub args: Vec<String>,
+    pub user: String,
+    /// Where the command was typed, and where a room reply goes.
+    pub room: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Everyone,
+    Admin,
+}
+
+pub enum CommandHandler {
+    Help,
+    Local(fn(&CommandContext) -> CommandResponse),
+    Http { url: String },
+}
+
+pub struct Command {
+    pub description: String,
+    pub handler: CommandHandler,
+    pub permission: Permission,
+    /// Invocations per user.
+    pub rate_limit: Limit,
+}
+
+pub struct CommandRegistry {
+    commands: HashMap<String, Command>,
+    admins: Vec<String>,
+    /// Each command's `rate_limit`, keyed by user.
+    limiters: HashMap<String, KeyedLimiter<String>>,
+    http: reqwest::Client,
+}
+
+pub fn parse(content: &str) -> Option<(String, Vec<String>)> {
+    let rest = content.strip_prefix('/')?;
+    let mut parts = rest.split_whitespace();
+    let name = parts.next()?.to_lowercase();
+    Some((name, parts.map(str::to_string).collect()))
+}
+
+impl CommandRegistry {
+    pub fn new(admins: Vec<String>) -> Self {
+        Self {
+            commands: HashMap::new(),
+            admins,
+            limiters: HashMap::new(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Built-in commands plus bot callouts from
+    /// `GATEWAY_BOT_COMMANDS="giphy=http://host/giphy,remind=http://host/remind"`.
+    pub fn from_env() -> Self {
+        let mut registry = Self::new(admins_from_env());
+        registry.register_builtins();
+
+        if let Ok(spec) = std::env::var("GATEWAY_BOT_COMMANDS") {
+            for entry in spec.split(',').filter(|e| !e.trim().is_empty()) {
+                match entry.split_once('=') {
+                    Some((name, url)) => registry.register(name.trim(), Command {
+                        description: format!("bot command ({})", url.trim()),
+                        handler: CommandHandler::Http { url: url.trim().to_string() },
+                        permission: Permission::Everyone,
+                        rate_limit: Limit::new(5, Duration::from_secs(60)),
+                    }),
+                    None => println!("GATEWAY: ignoring malformed bot command entry {:?}", entry),
+                }
+            }
+        }
+
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, command: Command) {
+        let name = name.to_lowercase();
+        self.limiters.insert(name.clone(), KeyedLimiter::new(format!("/{}", name), command.rate_limit));
+        self.commands.insert(name, command);
+    }
+
+    fn register_builtins(&mut self) {
+        self.register("help", Command {
+            description: "list available commands".into(),
+            handler: CommandHandler::Help,
+            permission: Permission::Everyone,
+            rate_limit: Limit::new(10, Duration::from_secs(60)),
+        });
+
+        self.register("me", Command {
+            description: "send an action message".into(),
+            handler: CommandHandler::Local(|ctx| {
+                CommandResponse::room(format!("* {} {}", ctx.user, ctx.args.join(" ")))
+            }),
+            permission: Permission::Everyone,
+            rate_limit: Limit::new(10, Duration::from_secs(10)),
+        });
+
+        self.register("shrug", Command {
+            description: "append a shrug".into(),
+            handler: CommandHandler::Local(|ctx| {
+                CommandResponse::room(format!("{} ¯\\_(ツ)_/¯", ctx.args.join(" ")).trim().to_string())
+            }),
+            permission: Permission::Everyone,
+            rate_limit: Limit::new(10, Duration::from_secs(10)),
+        });
+
+        self.register("announce", Command {
+            description: "post an announcement (admins only)".into(),
+            handler: CommandHandler::Local(|ctx| {
+                CommandResponse::room(format!("[announcement] {}", ctx.args.join(" ")))
+            }),
+            permission: Permission::Admin,
+            rate_limit: Limit::new(3, Duration::from_secs(60)),
+        });
+    }
+
+    fn help_text(&self) -> String {
+        let mut names: Vec<_> = self.commands.iter().collect();
+        names.sort_by(|a, b| a.0.cmp(b.0));
+        names
+            .into_iter()
+            .map(|(name, cmd)| format!("/{} — {}", name, cmd.description))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Forgets users whose command limits have fully recovered.
+    pub fn evict_rate_limits(&self) {
+        for limiter in self.limiters.values() {
+            limiter.retain_recent();
+        }
+    }
+
+    /// Size of each command's limiter, for /debug/state.
+    pub fn limiter_info(&self) -> Vec<LimiterInfo> {
+        self.limiters.values().map(|l| l.info()).collect()
+    }
+
+    /// Runs a command typed in `ctx.room` and delivers the reply: to the
+    /// invoking connection, or into the room if the user may post there.
+    /// Refusals go back as catalog errors.
+    pub async fn dispatch(&self, state: &AppState, session: &Session, ctx: CommandContext) {
+        let command = ctx.command.as_str();
+        let Some(cmd) = self.commands.get(command) else {
+            session.error(codes::COMMAND_UNKNOWN, &[("command", command)]);
+            return;
+        };
+
+        if cmd.permission == Permission::Admin && !self.admins.contains(&ctx.user) {
+            session.error(codes::COMMAND_FORBIDDEN, &[("command", command)]);
+            return;
+        }
+
+        if let Err(wait) = self.limiters[command].check(&ctx.user) {
+            let secs = (wait.as_secs() + 1).to_string();
+            session.error(codes::COMMAND_RATE_LIMITED, &[("command", command), ("secs", &secs)]);
+            return;
+        }
+
+        let (reply, authorized) = match &cmd.handler {
+            CommandHandler::Help => (CommandResponse::ephemeral(self.help_text()), false),
+            CommandHandler::Local(f) => (f(&ctx), false),
+            CommandHandler::Http { url } => {
+                // a bot sees what was typed in the room and may answer into
+                // it, so only someone who may post there can call one
+                if !may_post(state, session, &ctx.room).await {
+                    return;
+                }
+                match self.callout(url, &ctx).await {
+                    Ok(reply) => (reply, true),
+                    Err(code) => {
+                        session.error(code, &[("command", command)]);
+                        return;
+                    }
+                }
+            }
+        };
+
+        match reply.visibility {
+            Visibility::Room => {
+                if authorized || may_post(state, session, &ctx.room).await {
+                    state.broadcast_message(&ctx.room, &session.username, reply.text, false, None, None).await;
+                }
+            }
+            Visibility::Ephemeral => {
+                session.reply(&ServerEvent::CommandResult { command: ctx.command, content: reply.text });
+            }
+        }
+    }
+
+    async fn callout(&self, url: &str, ctx: &CommandContext) -> Result<CommandResponse, &'static str> {
+        let resp = self.http.post(url).timeout(CALLOUT_TIMEOUT).json(ctx).send().await.map_err(|e| {
+            println!("GATEWAY: command /{} callout failed: {}", ctx.command, e);
+            codes::COMMAND_UNAVAILABLE
+        })?;
+        resp.json().await.map_err(|_| codes::COMMAND_INVALID_REPLY)
+    }
+}
+
+/// Whether `session` may post in `room`, checked as for a chat message.
+async fn may_post(state: &AppState, session: &Session, room: &str) -> bool {
+    state.authorize_post(session, room) && state.policy.authorize(state, session, PolicyAction::SendMessage, room).await
+}
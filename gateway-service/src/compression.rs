@@ -0,0 +1,140 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use flate2::{Compress, FlushCompress, Status};
+
+//
+// FRAME COMPRESSION
+//
+// Chat traffic is repetitive JSON, so busy rooms and IoT links gain a lot
+// from compressing what the gateway sends. tungstenite refuses frames with
+// the RSV1 bit set, which permessage-deflate (RFC 7692) would let browsers
+// send as soon as it is negotiated, so the gateway does not offer the
+// extension; connections opt in on the upgrade instead:
+//
+//   GET /ws?compress=deflate
+//
+// and the upgrade answers `X-Uchat-Compression: deflate` when granted. From
+// then on every outgoing text frame of at least MIN_BYTES is sent as a
+// binary frame holding the permessage-deflate payload of that text: raw
+// DEFLATE, sync-flushed, without the trailing 00 00 ff ff, and sharing one
+// window across the connection (context takeover). Shorter frames stay
+// text; the gateway sends no other binary frames (CBOR connections, see
+// encoding.rs, are never compressed). What clients send is never
+// compressed.
+//
+// Each compressing connection holds a deflate context (about 300 KiB), so
+// their number is capped; past the cap connections are served uncompressed.
+//
+//   GATEWAY_COMPRESSION_MAX_CONTEXTS  compressing connections at once
+//                                     (default 1000); 0 turns compression off
+//
+//   gateway_compression_contexts
+//   gateway_compression_bytes_total{stage="before|after"}
+//
+
+pub const HEADER: &str = "x-uchat-compression";
+const DEFAULT_MAX_CONTEXTS: usize = 1000;
+/// Smaller frames would hardly shrink.
+const MIN_BYTES: usize = 128;
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+#[derive(Default)]
+struct Counters {
+    contexts: AtomicUsize,
+    before: AtomicU64,
+    after: AtomicU64,
+}
+
+pub struct FrameCompression {
+    max_contexts: usize,
+    counters: Arc<Counters>,
+}
+
+impl FrameCompression {
+    pub fn from_env() -> Self {
+        let max_contexts = std::env::var("GATEWAY_COMPRESSION_MAX_CONTEXTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_CONTEXTS);
+        Self::new(max_contexts)
+    }
+
+    pub fn new(max_contexts: usize) -> Self {
+        Self { max_contexts, counters: Default::default() }
+    }
+
+    /// A deflate context for a connection whose upgrade `query` asked for
+    /// one; None if it did not, or all are taken.
+    pub fn negotiate(&self, query: Option<&str>) -> Option<Deflater> {
+        if !query.is_some_and(|q| q.split('&').any(|p| p == "compress=deflate")) {
+            return None;
+        }
+        let taken = self.counters.contexts.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+            (n < self.max_contexts).then_some(n + 1)
+        });
+        if taken.is_err() {
+            return None;
+        }
+        Some(Deflater {
+            compress: Compress::new(flate2::Compression::default(), false),
+            counters: self.counters.clone(),
+        })
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let c = &self.counters;
+        let mut out = String::from("# TYPE gateway_compression_contexts gauge\n");
+        let _ = writeln!(out, "gateway_compression_contexts {}", c.contexts.load(Ordering::Relaxed));
+        out.push_str("# TYPE gateway_compression_bytes_total counter\n");
+        for (stage, counter) in [("before", &c.before), ("after", &c.after)] {
+            let bytes = counter.load(Ordering::Relaxed);
+            let _ = writeln!(out, "gateway_compression_bytes_total{{stage=\"{}\"}} {}", stage, bytes);
+        }
+        out
+    }
+}
+
+/// One connection's deflate context; gives its slot back when dropped.
+pub struct Deflater {
+    compress: Compress,
+    counters: Arc<Counters>,
+}
+
+impl Deflater {
+    /// The compressed payload of `text`, or None if it is too short to be
+    /// worth it (it is then sent as is).
+    pub fn compress(&mut self, text: &str) -> Option<Vec<u8>> {
+        if text.len() < MIN_BYTES {
+            return None;
+        }
+        let input = text.as_bytes();
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if out.len() == out.capacity() {
+                out.reserve(input.len() / 2 + 64);
+            }
+            let status = self.compress.compress_vec(&input[consumed..], &mut out, FlushCompress::Sync).ok()?;
+            let done = (self.compress.total_in() - start) as usize == input.len();
+            // a sync flush is complete once it left spare room in `out`
+            if done && out.len() < out.capacity() && matches!(status, Status::Ok | Status::BufError) {
+                break;
+            }
+        }
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        self.counters.before.fetch_add(input.len() as u64, Ordering::Relaxed);
+        self.counters.after.fetch_add(out.len() as u64, Ordering::Relaxed);
+        Some(out)
+    }
+}
+
+impl Drop for Deflater {
+    fn drop(&mut self) {
+        self.counters.contexts.fetch_sub(1, Ordering::Relaxed);
+    }
+}
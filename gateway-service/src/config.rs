@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use uchat_core::ratelimit::Limit;
+use uchat_proto::events::InstanceInfo;
+
+use crate::listener::SocketTuning;
+use crate::profiles::Profiles;
+
+//
+// GATEWAY CONFIG
+//
+// Protocol limits the gateway enforces and advertises to clients in the
+// Welcome event, so small devices can size buffers instead of guessing.
+//
+//   GATEWAY_MAX_MESSAGE_BYTES  largest inbound message, text or binary (default
+//                              65536); a larger one is answered message.too_large
+//                              and the connection closed with 1009
+//   GATEWAY_HEARTBEAT_SECS     server ping interval (default 30)
+//   GATEWAY_HELLO_TIMEOUT_SECS connections must send Hello within this (default 10)
+//   GATEWAY_RATE_LIMITS        per-event overrides, "send_message=20/10,login=5/1m"
+//                              meaning max events / period (seconds unless
+//                              suffixed ms, s, m or h); "=off" disables
+//   GATEWAY_ROOM_RATE_LIMIT, GATEWAY_ROOM_RATE_LIMITS
+//                              posts per room, whoever sends them (see room_limits.rs)
+//   GATEWAY_POLICY_URL, GATEWAY_POLICY_*
+//                              external authorization engine (see policy.rs)
+//   GATEWAY_COMPRESSION_MAX_CONTEXTS
+//                              connections whose frames are compressed at once
+//                              (default 1000; see compression.rs)
+//   GATEWAY_MESSAGE_MAX_TTL_SECS
+//                              longest lifetime of a disappearing message
+//                              (default 604800; see expiry.rs)
+//   GATEWAY_SLOW_CONSUMER_*    when connections that keep falling behind are
+//                              closed (see slow_consumers.rs)
+//   GATEWAY_E2EE_ROOMS         rooms that only accept end-to-end encrypted
+//                              messages, "board,legal" (see rooms.rs)
+//   GATEWAY_IDLE_TIMEOUT_SECS  WebSockets that send nothing, not even pongs, for
+//                              this long are closed (default 300; see idle.rs)
+//   GATEWAY_REVOCATIONS_URL, GATEWAY_REVOCATIONS_*
+//                              auth-api's revocation feed; connections whose
+//                              token is revoked are closed (see revocations.rs)
+//   GATEWAY_THUMBNAIL_SIZES    bounding boxes for image thumbnails (default "128,512")
+//   GATEWAY_RESUME_TTL_SECS    lifetime of resume tokens (default 43200)
+//   GATEWAY_RESUME_MAX_EVENTS  most events replayed per room on resume (default 500)
+//   GATEWAY_JOURNAL_RETENTION_SECS  how long room events stay resumable (default 3600)
+//   GATEWAY_MODERATION_RELOAD_SECS  how often moderation policies are re-read (default 5)
+//   GATEWAY_ALLOWED_ORIGINS    browser origins allowed to connect, by WebSocket
+//                              or long-polling (POST /poll/connect),
+//                              "https://chat.example.com,..."; empty allows
+//                              any (requests without Origin are not browsers
+//                              and always allowed). Web session cookies (see
+//                              auth-api's sessions.rs) are only taken from
+//                              origins listed here
+//   GATEWAY_BEHIND_TLS_PROXY   "1" when TLS is terminated in front of the gateway
+//   GATEWAY_PRODUCTION         "1" to refuse to start on unsafe settings (see validate.rs)
+//   GATEWAY_INSTANCE_ID, GATEWAY_REGION, GATEWAY_AFFINITY_BUCKETS
+//                              instance identity and routing hints (see routing.rs)
+//   GATEWAY_CENSUS_*, GATEWAY_ADVERTISE_URL, GATEWAY_SERVICE_ACCOUNTS, GATEWAY_PUBLIC_URL
+//                              cluster connection census (see census.rs)
+//   GATEWAY_DRAIN_WINDOW_SECS  how long draining clients get to move (see drain.rs)
+//   GATEWAY_BIND_ADDR, GATEWAY_TCP_*, ...
+//                              listener and socket options (see listener.rs)
+//   GATEWAY_PROFILE_BROWSER, GATEWAY_PROFILE_DESKTOP, GATEWAY_PROFILE_IOT
+//                              per client class overrides of the limits above
+//                              (see profiles.rs)
+//
+
+pub struct GatewayConfig {
+    pub max_message_bytes: usize,
+    pub heartbeat: Duration,
+    pub hello_timeout: Duration,
+    pub rate_limits: Vec<(String, Option<Limit>)>,
+    pub thumbnail_sizes: Vec<u32>,
+    pub resume_ttl: Duration,
+    pub resume_max_events: usize,
+    pub journal_retention: Duration,
+    pub moderation_reload: Duration,
+    pub allowed_origins: Vec<String>,
+    pub behind_tls_proxy: bool,
+    pub production: bool,
+    pub instance: InstanceInfo,
+    pub affinity_buckets: Option<u32>,
+    pub socket: SocketTuning,
+    pub profiles: Profiles,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+impl GatewayConfig {
+    pub fn from_env() -> Self {
+        let mut rate_limits = Vec::new();
+        if let Ok(spec) = std::env::var("GATEWAY_RATE_LIMITS") {
+            for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                match parse_rate_limit(entry) {
+                    Some(limit) => rate_limits.push(limit),
+                    None => println!("GATEWAY: ignoring malformed rate limit {:?}", entry),
+                }
+            }
+        }
+
+        let max_message_bytes = env_or("GATEWAY_MAX_MESSAGE_BYTES", 64 * 1024);
+        let heartbeat = Duration::from_secs(env_or("GATEWAY_HEARTBEAT_SECS", 30));
+        let resume_max_events = env_or("GATEWAY_RESUME_MAX_EVENTS", 500);
+
+        Self {
+            max_message_bytes,
+            heartbeat,
+            hello_timeout: Duration::from_secs(env_or("GATEWAY_HELLO_TIMEOUT_SECS", 10)),
+            rate_limits,
+            thumbnail_sizes: std::env::var("GATEWAY_THUMBNAIL_SIZES")
+                .unwrap_or_else(|_| "128,512".into())
+                .split(',')
+                .filter_map(|s| s.trim().parse().ok())
+                .filter(|s| *s > 0)
+                .collect(),
+            resume_ttl: Duration::from_secs(env_or("GATEWAY_RESUME_TTL_SECS", 12 * 3600)),
+            resume_max_events,
+            journal_retention: Duration::from_secs(env_or("GATEWAY_JOURNAL_RETENTION_SECS", 3600)),
+            moderation_reload: Duration::from_secs(env_or("GATEWAY_MODERATION_RELOAD_SECS", 5).max(1)),
+            allowed_origins: std::env::var("GATEWAY_ALLOWED_ORIGINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|o| o.trim().trim_end_matches('/').to_string())
+                .filter(|o| !o.is_empty())
+                .collect(),
+            behind_tls_proxy: std::env::var("GATEWAY_BEHIND_TLS_PROXY").is_ok_and(|v| v == "1"),
+            production: std::env::var("GATEWAY_PRODUCTION").is_ok_and(|v| v == "1"),
+            instance: crate::routing::instance_from_env(),
+            affinity_buckets: std::env::var("GATEWAY_AFFINITY_BUCKETS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n| *n > 0),
+            socket: SocketTuning::from_env(),
+            profiles: Profiles::from_env(max_message_bytes, heartbeat, resume_max_events),
+        }
+    }
+}
+
+impl GatewayConfig {
+    /// Whether a connection (WebSocket upgrade or long-poll connect) with
+    /// this Origin header may proceed.
+    pub fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) if !self.allowed_origins.is_empty() => {
+                self.allowed_origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether a connection from this Origin may sign in with the web
+    /// session cookie: only from an origin listed explicitly, since the
+    /// browser sends the cookie whichever page opens the connection.
+    pub fn cookie_origin_allowed(&self, origin: Option<&str>) -> bool {
+        origin.is_some_and(|origin| self.allowed_origins.iter().any(|o| o.eq_ignore_ascii_case(origin)))
+    }
+}
+
+/// One `GATEWAY_RATE_LIMITS` entry, "event=max/period" or "event=off".
+pub fn parse_rate_limit(entry: &str) -> Option<(String, Option<Limit>)> {
+    let (event, limit) = entry.split_once('=')?;
+    if limit == "off" {
+        return Some((event.to_string(), None));
+    }
+    Some((event.to_string(), Some(Limit::parse(limit)?)))
+}
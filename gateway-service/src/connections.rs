@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{mpsc, Notify};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Message};
+
+use uchat_proto::events::ServerEvent;
+use uchat_proto::jwt::Claims;
+
+use crate::profiles::ClientClass;
+use crate::state::Session;
+
+//
+// CONNECTION REGISTRY
+//
+// Every open connection (WebSocket, long-poll or QUIC) by connection id, with
+// what the gateway knows about it. Logged-in connections can be addressed
+// by username, so the gateway can reach a user or device directly instead
+// of the whole room.
+//
+// Each connection runs a small protocol state machine:
+//
+//   AwaitingHello --Hello--> Ready --close--> Closing
+//
+// Until the client's Hello only Hello is accepted, and a connection that
+// does not send one within GATEWAY_HELLO_TIMEOUT_SECS is closed. Every
+// transition is recorded in ConnectionInfo for debugging, as is the last
+// time anything was received from the client (see idle.rs).
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolState {
+    AwaitingHello,
+    Ready,
+    Closing,
+}
+
+impl ProtocolState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProtocolState::AwaitingHello => "awaiting_hello",
+            ProtocolState::Ready => "ready",
+            ProtocolState::Closing => "closing",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub state: ProtocolState,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// "ws", "poll" or "quic".
+    pub transport: &'static str,
+    /// Set once the connection logs in.
+    pub username: Option<String>,
+    pub class: ClientClass,
+    /// When the token the connection signed in with, or last refreshed
+    /// to, expires (see refresh.rs).
+    pub token_expires_at: Option<DateTime<Utc>>,
+    /// Which token that is, to tell whether it was revoked (see
+    /// revocations.rs).
+    #[serde(skip)]
+    pub token: Option<TokenRef>,
+    /// As negotiated on the upgrade, then as the client's Hello asked.
+    pub locale: &'static str,
+    pub state: ProtocolState,
+    /// Rooms the connection receives (see subscriptions.rs).
+    pub rooms: BTreeSet<String>,
+    pub connected_at: DateTime<Utc>,
+    /// Last frame received, pongs included.
+    pub last_seen: DateTime<Utc>,
+    pub transitions: Vec<Transition>,
+    /// Last ConfigUpdate version sent, and the last one the client acked
+    /// (see client_config.rs).
+    pub config_sent: Option<u64>,
+    pub config_acked: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenRef {
+    pub iat: usize,
+    pub jti: Option<String>,
+}
+
+impl ConnectionInfo {
+    /// Whether nothing was received from the client for `timeout`.
+    pub fn is_idle(&self, now: DateTime<Utc>, timeout: Duration) -> bool {
+        (now - self.last_seen).to_std().is_ok_and(|idle| idle >= timeout)
+    }
+}
+
+struct Connection {
+    info: ConnectionInfo,
+    out: mpsc::UnboundedSender<Message>,
+    reaped: Arc<Notify>,
+}
+
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    conns: Mutex<HashMap<u64, Connection>>,
+}
+
+impl ConnectionRegistry {
+    /// Adds `session`; the transport should stop reading once the returned
+    /// Notify fires, as the connection was reaped (see idle.rs).
+    pub fn register(&self, session: &Session, transport: &'static str) -> Arc<Notify> {
+        let now = Utc::now();
+        let info = ConnectionInfo {
+            id: session.id,
+            transport,
+            username: None,
+            class: session.class,
+            token_expires_at: None,
+            token: None,
+            locale: session.locale,
+            state: session.protocol,
+            rooms: session.rooms(),
+            connected_at: now,
+            last_seen: now,
+            transitions: vec![Transition { state: session.protocol, at: now }],
+            config_sent: None,
+            config_acked: None,
+        };
+        let reaped = Arc::new(Notify::new());
+        let conn = Connection { info, out: session.out.clone(), reaped: reaped.clone() };
+        self.conns.lock().unwrap().insert(session.id, conn);
+        reaped
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.conns.lock().unwrap().remove(&id);
+    }
+
+    /// Records a login; re-login on the same connection moves it to the new name.
+    pub fn set_username(&self, id: u64, username: &str) {
+        if let Some(conn) = self.conns.lock().unwrap().get_mut(&id) {
+            conn.info.username = Some(username.to_string());
+        }
+    }
+
+    /// Records the token the connection is signed in with.
+    pub fn set_token(&self, id: u64, claims: &Claims) {
+        if let Some(conn) = self.conns.lock().unwrap().get_mut(&id) {
+            conn.info.token_expires_at = DateTime::from_timestamp(claims.exp as i64, 0);
+            conn.info.token = Some(TokenRef { iat: claims.iat, jti: claims.jti.clone() });
+        }
+    }
+
+    /// Mirrors `session`'s rooms after a join or leave.
+    pub fn sync_rooms(&self, session: &Session) {
+        if let Some(conn) = self.conns.lock().unwrap().get_mut(&session.id) {
+            conn.info.rooms = session.rooms();
+        }
+    }
+
+    /// Moves `session` to `to`, recording the transition.
+    pub fn transition(&self, session: &mut Session, to: ProtocolState) {
+        if session.protocol == to {
+            return;
+        }
+        session.protocol = to;
+        if let Some(conn) = self.conns.lock().unwrap().get_mut(&session.id) {
+            conn.info.state = to;
+            conn.info.locale = session.locale;
+            conn.info.transitions.push(Transition { state: to, at: Utc::now() });
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<ConnectionInfo> {
+        self.conns.lock().unwrap().get(&id).map(|c| c.info.clone())
+    }
+
+    /// Every open connection; with `wait` false, None while the registry
+    /// is locked.
+    pub fn snapshot(&self, wait: bool) -> Option<Vec<ConnectionInfo>> {
+        let conns = crate::debug::read(&self.conns, wait)?;
+        Some(conns.values().map(|c| c.info.clone()).collect())
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.conns
+            .lock()
+            .unwrap()
+            .values()
+            .any(|c| c.info.username.as_deref() == Some(username))
+    }
+
+    /// Sends to every connection of `username`; returns how many got it.
+    pub fn send_to(&self, username: &str, event: &ServerEvent) -> usize {
+        let json = serde_json::to_string(event).unwrap();
+        self.conns
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|c| c.info.username.as_deref() == Some(username))
+            .filter(|c| c.out.send(Message::Text(json.clone())).is_ok())
+            .count()
+    }
+
+    /// Offers every connection to `pick`, which may update its info and
+    /// returns the event to send it, if any; returns how many got one.
+    pub fn send_each(&self, mut pick: impl FnMut(&mut ConnectionInfo) -> Option<ServerEvent>) -> usize {
+        let mut sent = 0;
+        for conn in self.conns.lock().unwrap().values_mut() {
+            let Some(event) = pick(&mut conn.info) else { continue };
+            if conn.out.send(Message::Text(serde_json::to_string(&event).unwrap())).is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    /// Asks a connection's writer to close it; false if it is gone.
+    pub fn close(&self, id: u64, code: CloseCode, reason: &'static str) -> bool {
+        let conns = self.conns.lock().unwrap();
+        let Some(conn) = conns.get(&id) else { return false };
+        conn.out.send(Message::Close(Some(CloseFrame { code, reason: reason.into() }))).is_ok()
+    }
+
+    /// Records that something was received from the client.
+    pub fn touch(&self, id: u64) {
+        if let Some(conn) = self.conns.lock().unwrap().get_mut(&id) {
+            conn.info.last_seen = Utc::now();
+        }
+    }
+
+    /// Closes a connection without waiting for its client (which stopped
+    /// answering, or is no longer let in): its writer sends the close frame
+    /// and its transport stops waiting for the reply.
+    pub fn reap(&self, id: u64, code: CloseCode, reason: &'static str) -> bool {
+        let conns = self.conns.lock().unwrap();
+        let Some(conn) = conns.get(&id) else { return false };
+        let _ = conn.out.send(Message::Close(Some(CloseFrame { code, reason: reason.into() })));
+        conn.reaped.notify_one();
+        true
+    }
+
+    /// Sends `msg` as is, e.g. a ping; false if the connection is gone.
+    pub fn send_raw(&self, id: u64, msg: Message) -> bool {
+        self.conns.lock().unwrap().get(&id).is_some_and(|c| c.out.send(msg).is_ok())
+    }
+
+    /// Updates one connection's info; false if it is gone.
+    pub fn update(&self, id: u64, f: impl FnOnce(&mut ConnectionInfo)) -> bool {
+        self.conns.lock().unwrap().get_mut(&id).map(|c| f(&mut c.info)).is_some()
+    }
+
+    /// Prometheus gauge of open connections per protocol state.
+    pub fn render_metrics(&self) -> String {
+        let mut per_state = BTreeMap::new();
+        for state in [ProtocolState::AwaitingHello, ProtocolState::Ready, ProtocolState::Closing] {
+            per_state.insert(state.as_str(), 0);
+        }
+        for conn in self.conns.lock().unwrap().values() {
+            *per_state.entry(conn.info.state.as_str()).or_default() += 1;
+        }
+
+        let mut out = String::from("# TYPE gateway_connections gauge\n");
+        for (state, count) in per_state {
+            out.push_str(&format!("gateway_connections{{state=\"{}\"}} {}\n", state, count));
+        }
+        out
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, TryLockError, Weak};
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use uchat_core::ratelimit::LimiterInfo;
+use uchat_proto::acl::RoomKind;
+use uchat_proto::events::{InstanceInfo, ServerEvent};
+
+use crate::connections::ProtocolState;
+use crate::state::AppState;
+
+//
+// DEBUG STATE SNAPSHOTS
+//
+// GET /debug/state (GATEWAY_ADMINS only) returns what the gateway holds in
+// memory: rooms and their sequence numbers, open connections, rate limiter
+// sizes and the state of the room event channel. Usernames are replaced by
+// a keyed hash, stable for the lifetime of the secret, so one user's
+// connections can still be correlated across snapshots.
+//
+//   GATEWAY_PANIC_SNAPSHOT_DIR   write the same snapshot there when a task
+//                                panics (at most once a minute)
+//
+// The panic path must not wait on a lock the panicking thread may hold, so
+// it skips (and names in `unavailable`) any section it cannot read.
+//
+
+const PANIC_SNAPSHOT_INTERVAL_SECS: i64 = 60;
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub generated_at: DateTime<Utc>,
+    pub instance: InstanceInfo,
+    /// "request" or "panic".
+    pub trigger: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub panic: Option<String>,
+    pub rooms: Vec<RoomSnapshot>,
+    pub connections: Option<ConnectionsSnapshot>,
+    pub rate_limiters: Vec<LimiterInfo>,
+    pub backplane: Backplane,
+    /// Lock-free counters (events, signed messages).
+    pub metrics: String,
+    /// Sections skipped because their lock was held.
+    pub unavailable: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct RoomSnapshot {
+    pub room: String,
+    pub kind: RoomKind,
+    pub seq: u64,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionsSnapshot {
+    pub total: usize,
+    pub by_state: BTreeMap<&'static str, usize>,
+    pub by_transport: BTreeMap<&'static str, usize>,
+    pub connections: Vec<ConnectionSummary>,
+}
+
+#[derive(Serialize)]
+pub struct ConnectionSummary {
+    pub id: u64,
+    pub transport: &'static str,
+    /// Redacted username.
+    pub user: Option<String>,
+    pub state: ProtocolState,
+    pub connected_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub transitions: usize,
+    /// Rooms joined.
+    pub rooms: usize,
+}
+
+/// The room event channel; a single-node broadcast until there is a
+/// cross-node backplane.
+#[derive(Serialize)]
+pub struct Backplane {
+    pub kind: &'static str,
+    pub subscribers: usize,
+    pub queued: usize,
+}
+
+/// Locks `m`, or with `wait` false gives up if it is held. A poisoned lock
+/// is still read: a snapshot is most wanted right after a panic.
+pub fn read<T>(m: &Mutex<T>, wait: bool) -> Option<MutexGuard<'_, T>> {
+    if wait {
+        return Some(m.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+    match m.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+fn redact(secret: &str, username: &str) -> String {
+    let digest = Sha256::new().chain_update(secret).chain_update(b":").chain_update(username).finalize();
+    let hex: String = digest.iter().take(6).map(|b| format!("{:02x}", b)).collect();
+    format!("user-{}", hex)
+}
+
+pub fn snapshot(state: &AppState, trigger: &'static str, wait: bool) -> Snapshot {
+    let mut unavailable = Vec::new();
+
+    let rooms = match state.room_seqs(wait) {
+        Some(seqs) => {
+            let mut rooms: Vec<RoomSnapshot> = seqs
+                .into_iter()
+                .map(|(room, seq)| RoomSnapshot { kind: state.rooms.kind(&room), room, seq })
+                .collect();
+            rooms.sort_by(|a, b| a.room.cmp(&b.room));
+            rooms
+        }
+        None => {
+            unavailable.push("rooms");
+            Vec::new()
+        }
+    };
+
+    let connections = match state.connections.snapshot(wait) {
+        Some(infos) => {
+            let mut by_state = BTreeMap::new();
+            let mut by_transport = BTreeMap::new();
+            for info in &infos {
+                *by_state.entry(info.state.as_str()).or_default() += 1;
+                *by_transport.entry(info.transport).or_default() += 1;
+            }
+            let mut connections: Vec<ConnectionSummary> = infos
+                .into_iter()
+                .map(|info| ConnectionSummary {
+                    id: info.id,
+                    transport: info.transport,
+                    user: info.username.map(|u| redact(&state.secret, &u)),
+                    state: info.state,
+                    connected_at: info.connected_at,
+                    last_seen: info.last_seen,
+                    transitions: info.transitions.len(),
+                    rooms: info.rooms.len(),
+                })
+                .collect();
+            connections.sort_by_key(|c| c.id);
+            Some(ConnectionsSnapshot { total: connections.len(), by_state, by_transport, connections })
+        }
+        None => {
+            unavailable.push("connections");
+            None
+        }
+    };
+
+    let mut rate_limiters = state.handlers.limiter_info();
+    rate_limiters.extend(state.room_limits.limiter_info());
+    rate_limiters.extend(state.commands.limiter_info());
+
+    let mut metrics = state.handlers.render_metrics();
+    metrics.push_str(&state.signed.render_metrics());
+
+    Snapshot {
+        generated_at: Utc::now(),
+        instance: state.config.instance.clone(),
+        trigger,
+        panic: None,
+        rooms,
+        connections,
+        rate_limiters,
+        backplane: Backplane { kind: "local", subscribers: state.tx.receiver_count(), queued: state.tx.len() },
+        metrics,
+        unavailable,
+    }
+}
+
+// GET /debug/state
+pub async fn state_dump(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(admin) => admin,
+        Err((status, details)) => {
+            let err = ServerEvent::Error { details: details.into(), code: None };
+            return (status, Json(err)).into_response();
+        }
+    };
+    state.audit.record("debug.state", &admin, "gateway", "");
+    Json(snapshot(&state, "request", true)).into_response()
+}
+
+static PANIC_STATE: OnceLock<(Weak<AppState>, PathBuf)> = OnceLock::new();
+static LAST_PANIC_SNAPSHOT: AtomicI64 = AtomicI64::new(i64::MIN);
+
+/// With GATEWAY_PANIC_SNAPSHOT_DIR set, chains a panic hook that writes a
+/// snapshot next to the usual panic message.
+pub fn install_panic_hook(state: &Arc<AppState>) {
+    let Some(dir) = std::env::var_os("GATEWAY_PANIC_SNAPSHOT_DIR").map(PathBuf::from) else { return };
+    if PANIC_STATE.set((Arc::downgrade(state), dir)).is_err() {
+        return;
+    }
+
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        write_panic_snapshot(&info.to_string());
+    }));
+}
+
+fn write_panic_snapshot(message: &str) {
+    let Some((state, dir)) = PANIC_STATE.get() else { return };
+    let Some(state) = state.upgrade() else { return };
+
+    let now = Utc::now();
+    let last = LAST_PANIC_SNAPSHOT.load(Ordering::Relaxed);
+    if now.timestamp() - last < PANIC_SNAPSHOT_INTERVAL_SECS
+        || LAST_PANIC_SNAPSHOT
+            .compare_exchange(last, now.timestamp(), Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+
+    let mut snap = snapshot(&state, "panic", false);
+    snap.panic = Some(message.to_string());
+    let Ok(json) = serde_json::to_vec_pretty(&snap) else { return };
+
+    // write then rename, so a crash mid-write never leaves half a file
+    let path = dir.join(format!("gateway-state-{}.json", now.timestamp_millis()));
+    let tmp = path.with_extension("json.tmp");
+    let written = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&tmp, json))
+        .and_then(|()| std::fs::rename(&tmp, &path));
+    match written {
+        Ok(()) => eprintln!("GATEWAY: state snapshot written to {}", path.display()),
+        Err(e) => eprintln!("GATEWAY: could not write state snapshot to {}: {}", path.display(), e),
+    }
+}
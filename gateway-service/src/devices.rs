@@ -0,0 +1,203 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use uchat_proto::events::{ClientEvent, ServerEvent};
+
+use crate::handlers::EventHandler;
+use crate::state::{AppState, Session};
+
+//
+// HOLD-AND-FORWARD DEVICE COMMANDS
+//
+// Commands for a device (a connection logged in under the device id) are
+// queued rather than fire-and-forget. A queued command is pushed to every
+// connection of the device, now if it is online and again on each login,
+// until the device answers with CommandAck or the command's TTL runs out.
+// Sleeping devices therefore pick up everything on wake.
+//
+
+const MAX_QUEUED: usize = 100;
+const DEFAULT_TTL_SECS: u64 = 24 * 3600;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedCommand {
+    pub id: String,
+    pub device: String,
+    pub command: String,
+    pub payload: Value,
+    pub queued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Times it was pushed to a connection without an ack yet.
+    pub attempts: u32,
+    pub last_sent_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+pub struct DeviceQueues {
+    queues: Mutex<HashMap<String, VecDeque<QueuedCommand>>>,
+}
+
+impl DeviceQueues {
+    /// Pushes every unexpired pending command to the device's connections.
+    pub fn deliver_pending(&self, state: &AppState, device: &str) {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(device) else { return };
+
+        let now = Utc::now();
+        queue.retain(|c| c.expires_at > now);
+        for cmd in queue.iter_mut() {
+            let event = ServerEvent::DeviceCommand {
+                id: cmd.id.clone(),
+                command: cmd.command.clone(),
+                payload: cmd.payload.clone(),
+            };
+            if state.connections.send_to(device, &event) > 0 {
+                cmd.attempts += 1;
+                cmd.last_sent_at = Some(now);
+            }
+        }
+    }
+
+    fn ack(&self, device: &str, id: &str) -> bool {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(device) else { return false };
+        let before = queue.len();
+        queue.retain(|c| c.id != id);
+        before != queue.len()
+    }
+}
+
+/// Drops expired commands; runs every 30 seconds.
+pub async fn expire_commands(state: Arc<AppState>) -> anyhow::Result<()> {
+    let now = Utc::now();
+    let mut queues = state.devices.queues.lock().unwrap();
+    for (device, queue) in queues.iter_mut() {
+        queue.retain(|c| {
+            let alive = c.expires_at > now;
+            if !alive {
+                println!("GATEWAY: command {} for {} expired after {} attempts", c.id, device, c.attempts);
+            }
+            alive
+        });
+    }
+    queues.retain(|_, q| !q.is_empty());
+    Ok(())
+}
+
+pub struct AckHandler;
+
+#[async_trait]
+impl EventHandler for AckHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let ClientEvent::CommandAck { id } = event else { return Ok(()) };
+
+        if !state.devices.ack(&session.username, &id) {
+            session.reply(&ServerEvent::Error { details: format!("no pending command {}", id), code: None });
+        }
+        Ok(())
+    }
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct EnqueueBody {
+    command: String,
+    #[serde(default)]
+    payload: Value,
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct Enqueued {
+    command: QueuedCommand,
+    /// Whether the device was online and got it immediately.
+    delivered: bool,
+}
+
+// POST /devices/:device/commands
+pub async fn enqueue(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(device): Path<String>,
+    Json(body): Json<EnqueueBody>,
+) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+
+    let now = Utc::now();
+    let ttl = chrono::Duration::seconds(body.ttl_secs.unwrap_or(DEFAULT_TTL_SECS) as i64);
+    let cmd = QueuedCommand {
+        id: uuid::Uuid::new_v4().to_string(),
+        device: device.clone(),
+        command: body.command,
+        payload: body.payload,
+        queued_at: now,
+        expires_at: now + ttl,
+        attempts: 0,
+        last_sent_at: None,
+    };
+    let id = cmd.id.clone();
+
+    {
+        let mut queues = state.devices.queues.lock().unwrap();
+        let queue = queues.entry(device.clone()).or_default();
+        if queue.len() >= MAX_QUEUED {
+            return error(StatusCode::TOO_MANY_REQUESTS, "device queue is full");
+        }
+        queue.push_back(cmd);
+    }
+
+    let delivered = state.connections.is_online(&device);
+    if delivered {
+        state.devices.deliver_pending(&state, &device);
+    }
+
+    let queues = state.devices.queues.lock().unwrap();
+    let command = queues[&device].iter().find(|c| c.id == id).cloned().unwrap();
+    (StatusCode::ACCEPTED, Json(Enqueued { command, delivered })).into_response()
+}
+
+// GET /devices/:device/commands
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(device): Path<String>,
+) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+    let queues = state.devices.queues.lock().unwrap();
+    let pending: Vec<&QueuedCommand> = queues.get(&device).map(|q| q.iter().collect()).unwrap_or_default();
+    Json(pending).into_response()
+}
+
+// DELETE /devices/:device/commands/:id
+pub async fn cancel(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path((device, id)): Path<(String, String)>,
+) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+    if state.devices.ack(&device, &id) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        error(StatusCode::NOT_FOUND, "no such pending command")
+    }
+}
@@ -0,0 +1,276 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+use uchat_proto::events::ServerEvent;
+
+use crate::state::AppState;
+
+//
+// CONNECTION DRAINING
+//
+// Blue/green rollouts take an instance out of service without dropping
+// anyone at once:
+//
+//   POST   /admin/drain  starts draining; body optional, {"window_secs": 30}
+//   GET    /admin/drain  progress
+//   DELETE /admin/drain  cancels; connections already told keep their notice
+//   GET    /ready        200, or 503 while draining (for the load balancer)
+//
+// The admin routes are admins only. While draining, WebSocket upgrades
+// and long-poll connects that still arrive get 503 with Retry-After and,
+// in X-Uchat-Alternates, the public URLs of the other live instances that
+// are not draining (GATEWAY_PUBLIC_URL, shared through the census); QUIC
+// handshakes are refused. Every open connection gets ReconnectSoon with
+// its own deadline, picked at random within the window so clients do not
+// all come back at once, and is closed with 1012 (service restart) if it
+// is still open then. Long-poll sessions cannot be closed from here; they
+// expire once their client has moved.
+//
+//   GATEWAY_DRAIN_WINDOW_SECS  default window (default 60)
+//
+//   gateway_draining                     gauge, 1 while draining
+//   gateway_drain_connections_remaining  gauge, open connections while draining
+//   gateway_drain_notified_total         counter, ReconnectSoon sent
+//   gateway_drain_closed_total           counter, closed at their deadline
+//   gateway_drain_refused_total          counter, new connections refused
+//
+
+pub const ALTERNATES_HEADER: &str = "x-uchat-alternates";
+
+/// Retry-After on refused connections; by then the load balancer should
+/// have seen /ready fail.
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+pub struct Drain {
+    window: Duration,
+    draining: AtomicBool,
+    since: Mutex<Option<(DateTime<Utc>, Duration)>>,
+    /// Closes connections at their deadlines; aborted on cancel.
+    closer: Mutex<Option<JoinHandle<()>>>,
+    notified: AtomicU64,
+    closed: AtomicU64,
+    refused: AtomicU64,
+}
+
+#[derive(Deserialize)]
+pub struct DrainRequest {
+    window_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DrainStatus {
+    draining: bool,
+    since: Option<DateTime<Utc>>,
+    window_secs: Option<u64>,
+    /// Open connections on this instance.
+    connections: usize,
+    notified: u64,
+    closed: u64,
+    refused: u64,
+}
+
+impl Drain {
+    pub fn from_env() -> Self {
+        let secs = std::env::var("GATEWAY_DRAIN_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).filter(|s| *s > 0);
+        Self {
+            window: Duration::from_secs(secs.unwrap_or(60)),
+            draining: AtomicBool::new(false),
+            since: Mutex::new(None),
+            closer: Mutex::new(None),
+            notified: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Counts a refused connection and returns where it could go instead.
+    pub fn refuse(&self, state: &AppState) -> Vec<String> {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        state.census.alternates().unwrap_or_else(|e| {
+            println!("GATEWAY: census read failed: {}", e);
+            Vec::new()
+        })
+    }
+
+    /// Tells every connection to reconnect within `window` and schedules
+    /// the closes; false if already draining.
+    fn start(&self, state: &Arc<AppState>, window: Duration) -> bool {
+        if self.draining.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        *self.since.lock().unwrap() = Some((Utc::now(), window));
+        state.census.set_draining(true);
+        let alternates = state.census.alternates().unwrap_or_default();
+
+        let started = tokio::time::Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut deadlines = Vec::new();
+        let notified = state.connections.send_each(|conn| {
+            let within = rng.gen_range(1..=window.as_secs().max(1));
+            if conn.transport != "poll" {
+                deadlines.push((within, conn.id));
+            }
+            Some(ServerEvent::ReconnectSoon { within_secs: within, alternates: alternates.clone() })
+        });
+        self.notified.fetch_add(notified as u64, Ordering::Relaxed);
+        deadlines.sort_unstable();
+
+        let closer = tokio::spawn({
+            let state = state.clone();
+            async move {
+                for (within, id) in deadlines {
+                    tokio::time::sleep_until(started + Duration::from_secs(within)).await;
+                    if state.connections.close(id, CloseCode::Restart, "draining") {
+                        state.drain.closed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+        *self.closer.lock().unwrap() = Some(closer);
+        true
+    }
+
+    /// Refuses new connections for good, for shutdown (see shutdown.rs),
+    /// which closes the open ones itself.
+    pub fn stop_accepting(&self, state: &AppState) {
+        self.draining.store(true, Ordering::SeqCst);
+        if let Some(closer) = self.closer.lock().unwrap().take() {
+            closer.abort();
+        }
+        state.census.set_draining(true);
+    }
+
+    /// Back in service; false if not draining.
+    fn cancel(&self, state: &AppState) -> bool {
+        if !self.draining.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        *self.since.lock().unwrap() = None;
+        if let Some(closer) = self.closer.lock().unwrap().take() {
+            closer.abort();
+        }
+        state.census.set_draining(false);
+        true
+    }
+
+    fn status(&self, state: &AppState) -> DrainStatus {
+        let since = *self.since.lock().unwrap();
+        DrainStatus {
+            draining: self.is_draining(),
+            since: since.map(|(at, _)| at),
+            window_secs: since.map(|(_, window)| window.as_secs()),
+            connections: state.connections.snapshot(true).map_or(0, |c| c.len()),
+            notified: self.notified.load(Ordering::Relaxed),
+            closed: self.closed.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn render_metrics(&self, state: &AppState) -> String {
+        let draining = self.is_draining();
+        let remaining = if draining { state.connections.snapshot(true).map_or(0, |c| c.len()) } else { 0 };
+        let mut out = String::new();
+        out.push_str(&format!("# TYPE gateway_draining gauge\ngateway_draining {}\n", draining as u8));
+        out.push_str(&format!(
+            "# TYPE gateway_drain_connections_remaining gauge\ngateway_drain_connections_remaining {}\n",
+            remaining
+        ));
+        for (name, counter) in [("notified", &self.notified), ("closed", &self.closed), ("refused", &self.refused)] {
+            out.push_str(&format!(
+                "# TYPE gateway_drain_{name}_total counter\ngateway_drain_{name}_total {}\n",
+                counter.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+/// The 503 for a connection attempt while draining.
+pub fn refused(state: &AppState) -> Response {
+    let alternates = state.drain.refuse(state);
+    let mut resp = error(StatusCode::SERVICE_UNAVAILABLE, "draining; reconnect to another instance");
+    let headers = resp.headers_mut();
+    headers.insert(header::RETRY_AFTER, RETRY_AFTER_SECS.into());
+    if let Ok(value) = HeaderValue::from_str(&alternates.join(", ")) {
+        if !alternates.is_empty() {
+            headers.insert(ALTERNATES_HEADER, value);
+        }
+    }
+    resp
+}
+
+// GET /ready
+pub async fn ready(State(state): State<Arc<AppState>>) -> Response {
+    if state.drain.is_draining() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "draining").into_response();
+    }
+    (StatusCode::OK, "ready").into_response()
+}
+
+// POST /admin/drain
+pub async fn start(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Option<Json<DrainRequest>>,
+) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    let window = match body.and_then(|Json(b)| b.window_secs) {
+        Some(0) => return error(StatusCode::BAD_REQUEST, "window_secs must be positive"),
+        Some(secs) => Duration::from_secs(secs),
+        None => state.drain.window,
+    };
+    // already draining: report progress, so deploy scripts can retry
+    if !state.drain.start(&state, window) {
+        return Json(state.drain.status(&state)).into_response();
+    }
+    let status = state.drain.status(&state);
+    println!("GATEWAY: draining {} connections over {}s", status.connections, window.as_secs());
+    state.audit.record("drain.start", &admin, &state.config.instance.instance_id, format!("{}s", window.as_secs()));
+    (StatusCode::ACCEPTED, Json(status)).into_response()
+}
+
+// GET /admin/drain
+pub async fn status(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err((status, details)) = state.require_admin(&headers) {
+        return error(status, details);
+    }
+    Json(state.drain.status(&state)).into_response()
+}
+
+// DELETE /admin/drain
+pub async fn cancel(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    if !state.drain.cancel(&state) {
+        return error(StatusCode::CONFLICT, "not draining");
+    }
+    println!("GATEWAY: drain cancelled");
+    state.audit.record("drain.cancel", &admin, &state.config.instance.instance_id, "");
+    Json(state.drain.status(&state)).into_response()
+}
@@ -0,0 +1,75 @@
+use serde::de::Error as _;
+use tungstenite::protocol::Message;
+
+use uchat_proto::events::ClientEvent;
+
+//
+// WIRE ENCODING
+//
+// Events travel as JSON text frames unless the client offers the
+// "unhidra-cbor" WebSocket subprotocol. The gateway then selects it and
+// every event, both ways, is the same uchat-proto event encoded as CBOR
+// (RFC 8949) in a binary frame, which saves small devices both bytes and
+// a JSON parser. JSON text frames from such a client are still accepted.
+//
+// Only one subprotocol can be selected, so a client offering a class as
+// well ("uchat.iot, unhidra-cbor") is answered "unhidra-cbor" and still
+// gets the class's profile (see profiles.rs).
+//
+// Frame compression (see compression.rs) also uses binary frames and is
+// not granted to CBOR connections.
+//
+
+pub const CBOR_SUBPROTOCOL: &str = "unhidra-cbor";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    /// The encoding asked for in a `Sec-WebSocket-Protocol` header.
+    pub fn from_subprotocols(header: &str) -> Self {
+        if header.split(',').any(|p| p.trim() == CBOR_SUBPROTOCOL) {
+            Encoding::Cbor
+        } else {
+            Encoding::Json
+        }
+    }
+
+    /// An outgoing frame in this encoding; events are produced as JSON
+    /// text everywhere, so CBOR connections get them transcoded here.
+    pub fn outgoing(self, msg: Message) -> Message {
+        match (self, msg) {
+            (Encoding::Cbor, Message::Text(text)) => {
+                let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) else { return Message::Text(text) };
+                let mut data = Vec::with_capacity(text.len());
+                match ciborium::ser::into_writer(&value, &mut data) {
+                    Ok(()) => Message::Binary(data),
+                    Err(_) => Message::Text(text),
+                }
+            }
+            (_, msg) => msg,
+        }
+    }
+
+    /// The event in an incoming data frame; None for frames that carry no
+    /// events in this encoding (binary frames on JSON connections).
+    pub fn decode(self, msg: &Message) -> Option<serde_json::Result<ClientEvent>> {
+        match (self, msg) {
+            (_, Message::Text(text)) => Some(serde_json::from_str(text)),
+            (Encoding::Cbor, Message::Binary(data)) => Some(decode_cbor(data)),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes through a JSON value, so CBOR and JSON clients get the same
+/// errors for well-formed frames that are not events.
+fn decode_cbor(data: &[u8]) -> serde_json::Result<ClientEvent> {
+    let value: serde_json::Value =
+        ciborium::de::from_reader(data).map_err(|_| serde_json::Error::custom("malformed CBOR"))?;
+    serde_json::from_value(value)
+}
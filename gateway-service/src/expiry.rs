@@ -0,0 +1,118 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+
+use uchat_proto::envelope::Envelope;
+use uchat_proto::events::ServerEvent;
+
+use crate::state::AppState;
+
+//
+// DISAPPEARING MESSAGES
+//
+// A SendMessage with expires_in_secs is published with `expires_at` set on
+// its envelope. From then on no client may receive its content after that
+// time, however slow or late it is: every delivery path (socket fan-out,
+// long-poll sessions, resume replay from the buffer or the journal) passes
+// envelopes through `MessageExpiry::deliver`, which swaps an expired
+// message for a MessageExpired tombstone under the same room and seq. The
+// client's numbering stays contiguous, and one that showed the message
+// before it expired learns to drop it.
+//
+// Stored copies go too: a job rewrites expired messages in the journal and
+// the replay buffer as tombstones, and drops them from the recent-message
+// buffer that reports quote from (see reports.rs).
+//
+//   GATEWAY_MESSAGE_MAX_TTL_SECS  longest expires_in_secs accepted
+//                                 (default 604800, a week)
+//
+//   gateway_messages_expired_total{path="fanout|poll|resume"}  tombstones
+//                                                              delivered
+//
+
+const DEFAULT_MAX_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy)]
+pub enum DeliveryPath {
+    Fanout,
+    Poll,
+    Resume,
+}
+
+impl DeliveryPath {
+    const ALL: [DeliveryPath; 3] = [DeliveryPath::Fanout, DeliveryPath::Poll, DeliveryPath::Resume];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            DeliveryPath::Fanout => "fanout",
+            DeliveryPath::Poll => "poll",
+            DeliveryPath::Resume => "resume",
+        }
+    }
+}
+
+pub struct MessageExpiry {
+    pub max_ttl_secs: u64,
+    delivered: [AtomicU64; 3],
+}
+
+impl MessageExpiry {
+    pub fn from_env() -> Self {
+        let max_ttl_secs = std::env::var("GATEWAY_MESSAGE_MAX_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_TTL_SECS);
+        Self::new(max_ttl_secs)
+    }
+
+    pub fn new(max_ttl_secs: u64) -> Self {
+        Self { max_ttl_secs, delivered: Default::default() }
+    }
+
+    /// Whether a message may ask to disappear after `secs`.
+    pub fn accepts(&self, secs: u64) -> bool {
+        (1..=self.max_ttl_secs).contains(&secs)
+    }
+
+    /// `envelope` as it may be delivered now.
+    pub fn deliver(&self, mut envelope: Envelope, path: DeliveryPath) -> Envelope {
+        expire(&mut envelope, Utc::now().timestamp_millis());
+        if matches!(envelope.event, ServerEvent::MessageExpired {}) {
+            self.delivered[path as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        envelope
+    }
+
+    /// Scrubs expired messages from everything the gateway keeps; returns
+    /// how many journaled ones were rewritten.
+    pub fn sweep(&self, state: &AppState) -> usize {
+        let now = Utc::now().timestamp_millis();
+        state.recent.lock().unwrap().retain(|m| m.expires_at.is_none_or(|at| at > now));
+        state.replay.expire(now);
+        state.journal.expire(now)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE gateway_messages_expired_total counter\n");
+        for path in DeliveryPath::ALL {
+            let count = self.delivered[path as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "gateway_messages_expired_total{{path=\"{}\"}} {}", path.as_str(), count);
+        }
+        out
+    }
+}
+
+/// Unix milliseconds `secs` from now.
+pub fn expires_at(secs: u64) -> i64 {
+    Utc::now().timestamp_millis().saturating_add(secs.saturating_mul(1000) as i64)
+}
+
+/// Turns `envelope` into its tombstone if it expired by `now`.
+pub fn expire(envelope: &mut Envelope, now: i64) {
+    if envelope.expires_at.is_some_and(|at| at <= now) {
+        envelope.event = ServerEvent::MessageExpired {};
+        // nothing left to confirm delivery of
+        envelope.receipts = false;
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use uchat_proto::events::ServerEvent;
+use uchat_proto::jwt::create_token;
+
+use crate::journal;
+use crate::state::AppState;
+use crate::subscriptions::valid_room;
+
+//
+// ROOM FREEZES
+//
+// Incident containment and moderation escalations need a room to go
+// quiet without closing it:
+//
+//   PUT    /rooms/:room/freeze  admins; body optional, {"reason": "..."}
+//   DELETE /rooms/:room/freeze  admins
+//
+// In a frozen room every SendMessage, room command reply and SendMedia is
+// refused with room.frozen, admins' included. Events the gateway publishes
+// itself are still delivered, starting with RoomFreezeChanged, which tells
+// the room it was frozen or thawed. GET /rooms/:room shows "frozen": true.
+//
+// Freezes are kept in the journal's SQLite file (GATEWAY_JOURNAL_PATH) and
+// reloaded as often as moderation policies (GATEWAY_MODERATION_RELOAD_SECS),
+// so every instance sharing it refuses sends. The same change is made in
+// chat-service (PUT/DELETE /rooms/{id}/freeze on CHAT_SERVICE_URL, as the
+// admin) so its HTTP sends are refused too; `propagated` in the answer is
+// false when that failed, e.g. because the admin is not in CHAT_ADMINS,
+// and the call can simply be repeated.
+//
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomFreeze {
+    pub reason: Option<String>,
+    pub frozen_by: String,
+    pub frozen_at: String,
+}
+
+pub struct RoomFreezes {
+    conn: Mutex<Connection>,
+    cache: RwLock<HashMap<String, RoomFreeze>>,
+    chat_url: String,
+    http: reqwest::Client,
+}
+
+impl RoomFreezes {
+    pub fn from_env() -> rusqlite::Result<Self> {
+        let chat_url = std::env::var("CHAT_SERVICE_URL").unwrap_or_else(|_| "http://127.0.0.1:9301".into());
+        Self::open(&journal::path_from_env(), chat_url.trim_end_matches('/'))
+    }
+
+    pub fn open(path: &str, chat_url: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS room_freezes (
+                room      TEXT PRIMARY KEY,
+                reason    TEXT,
+                frozen_by TEXT NOT NULL,
+                frozen_at TEXT NOT NULL
+            );",
+        )?;
+        let freezes = Self {
+            conn: Mutex::new(conn),
+            cache: RwLock::new(HashMap::new()),
+            chat_url: chat_url.to_string(),
+            http: reqwest::Client::new(),
+        };
+        freezes.reload()?;
+        Ok(freezes)
+    }
+
+    /// Replaces the cache with what is in the file.
+    pub fn reload(&self) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let freezes = {
+            let mut stmt = conn.prepare("SELECT room, reason, frozen_by, frozen_at FROM room_freezes")?;
+            let rows = stmt.query_map([], |r| {
+                let freeze = RoomFreeze { reason: r.get(1)?, frozen_by: r.get(2)?, frozen_at: r.get(3)? };
+                Ok((r.get(0)?, freeze))
+            })?;
+            rows.filter_map(Result::ok).collect()
+        };
+        // under the connection lock, like moderation policies
+        *self.cache.write().unwrap() = freezes;
+        Ok(())
+    }
+
+    pub fn is_frozen(&self, room: &str) -> bool {
+        self.cache.read().unwrap().contains_key(room)
+    }
+
+    fn freeze(&self, room: &str, reason: Option<String>, by: &str) -> rusqlite::Result<RoomFreeze> {
+        let freeze = RoomFreeze { reason, frozen_by: by.to_string(), frozen_at: Utc::now().to_rfc3339() };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO room_freezes (room, reason, frozen_by, frozen_at) VALUES (?1, ?2, ?3, ?4)",
+            params![room, freeze.reason, freeze.frozen_by, freeze.frozen_at],
+        )?;
+        self.cache.write().unwrap().insert(room.to_string(), freeze.clone());
+        Ok(freeze)
+    }
+
+    /// False if `room` was not frozen.
+    fn thaw(&self, room: &str) -> rusqlite::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM room_freezes WHERE room = ?1", [room])? > 0;
+        self.cache.write().unwrap().remove(room);
+        Ok(removed)
+    }
+
+    /// Makes the same change in chat-service, as `admin`; false if it was
+    /// not made there.
+    async fn propagate(&self, state: &AppState, room: &str, admin: &str, freeze: Option<&RoomFreeze>) -> bool {
+        let url = format!("{}/rooms/{}/freeze", self.chat_url, room);
+        let request = match freeze {
+            Some(freeze) => self.http.put(url).json(&json!({ "reason": freeze.reason })),
+            None => self.http.delete(url),
+        };
+        let request = request.timeout(Duration::from_secs(5)).bearer_auth(create_token(&state.secret, admin));
+        match request.send().await {
+            Ok(resp) if resp.status().is_success() => true,
+            Ok(resp) => {
+                println!("GATEWAY: chat-service refused the freeze of {}: {}", room, resp.status());
+                false
+            }
+            Err(e) => {
+                println!("GATEWAY: cannot reach chat-service to freeze {}: {}", room, e);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FreezeRequest {
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FreezeStatus {
+    pub room: String,
+    pub frozen: bool,
+    #[serde(flatten)]
+    pub freeze: Option<RoomFreeze>,
+    /// Whether chat-service made the same change.
+    pub propagated: bool,
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+fn storage_error(e: rusqlite::Error) -> Response {
+    println!("GATEWAY: room freeze storage failed: {}", e);
+    error(StatusCode::INTERNAL_SERVER_ERROR, "room freeze storage failed")
+}
+
+// PUT /rooms/:room/freeze
+pub async fn freeze(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    body: Option<Json<FreezeRequest>>,
+) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    if !valid_room(&room) {
+        return error(StatusCode::BAD_REQUEST, "invalid room name");
+    }
+    let reason = body.and_then(|Json(b)| b.reason).filter(|r| !r.trim().is_empty());
+
+    let freeze = match state.freezes.freeze(&room, reason.clone(), &admin) {
+        Ok(freeze) => freeze,
+        Err(e) => return storage_error(e),
+    };
+    state.publish(&room, None, ServerEvent::RoomFreezeChanged { frozen: true, reason: reason.clone() }).await;
+    println!("GATEWAY: {} froze {}", admin, room);
+    state.audit.record("room.freeze", &admin, &format!("room:{}", room), reason.unwrap_or_default());
+
+    let propagated = state.freezes.propagate(&state, &room, &admin, Some(&freeze)).await;
+    Json(FreezeStatus { room, frozen: true, freeze: Some(freeze), propagated }).into_response()
+}
+
+// DELETE /rooms/:room/freeze
+pub async fn thaw(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    if !valid_room(&room) {
+        return error(StatusCode::BAD_REQUEST, "invalid room name");
+    }
+
+    match state.freezes.thaw(&room) {
+        Ok(true) => {
+            state.publish(&room, None, ServerEvent::RoomFreezeChanged { frozen: false, reason: None }).await;
+            println!("GATEWAY: {} thawed {}", admin, room);
+            state.audit.record("room.thaw", &admin, &format!("room:{}", room), "");
+        }
+        // thawed here already; chat-service may still need telling
+        Ok(false) => {}
+        Err(e) => return storage_error(e),
+    }
+    let propagated = state.freezes.propagate(&state, &room, &admin, None).await;
+    Json(FreezeStatus { room, frozen: false, freeze: None, propagated }).into_response()
+}
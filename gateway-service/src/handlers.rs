@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use uchat_core::i18n::{self, codes};
+use uchat_core::ratelimit::{KeyedLimiter, Limit, LimiterInfo};
+use uchat_proto::events::{ClientEvent, RateLimit, ServerEvent};
+
+use crate::acks::{self, DeliveredHandler};
+use crate::client_config::ConfigAckHandler;
+use crate::commands::{self, CommandContext};
+use crate::connections::ProtocolState;
+use crate::devices::AckHandler;
+use crate::expiry;
+use crate::mutes::MuteHandler;
+use crate::policy::PolicyAction;
+use crate::presence::PresenceHandler;
+use crate::profiles::ClientClass;
+use crate::receipts::ReceiptHandler;
+use crate::refresh::RefreshHandler;
+use crate::resume;
+use crate::signed::SignedHandler;
+use crate::subscriptions::{self, RoomHandler};
+use crate::state::{AppState, Session, DEFAULT_ROOM};
+
+//
+// EVENT HANDLER REGISTRY
+//
+// Each ClientEvent variant is routed to the handler registered under its
+// event type. Deployments add their own handlers (or replace built-ins) by
+// calling `register` before the listener starts.
+//
+
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> Result<()>;
+}
+
+#[derive(Default)]
+pub struct HandlerStats {
+    pub handled: AtomicU64,
+    pub failed: AtomicU64,
+    pub rate_limited: AtomicU64,
+}
+
+impl HandlerStats {
+    fn get(&self, outcome: &str) -> u64 {
+        match outcome {
+            "handled" => self.handled.load(Ordering::Relaxed),
+            "failed" => self.failed.load(Ordering::Relaxed),
+            _ => self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Entry {
+    handler: Box<dyn EventHandler>,
+    /// Per connection, keyed by session id.
+    rate_limit: Option<KeyedLimiter<u64>>,
+    /// Client class profiles that override `rate_limit`; None turns it off.
+    class_limits: HashMap<ClientClass, Option<KeyedLimiter<u64>>>,
+    stats: HandlerStats,
+}
+
+impl Entry {
+    fn limiter(&self, class: ClientClass) -> Option<&KeyedLimiter<u64>> {
+        match self.class_limits.get(&class) {
+            Some(limiter) => limiter.as_ref(),
+            None => self.rate_limit.as_ref(),
+        }
+    }
+
+    fn limiters(&self) -> impl Iterator<Item = &KeyedLimiter<u64>> {
+        self.rate_limit.iter().chain(self.class_limits.values().flatten())
+    }
+}
+
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: HashMap<&'static str, Entry>,
+    /// Frames that were not a ClientEvent at all.
+    invalid: AtomicU64,
+    /// Frames over the connection's max_message_bytes.
+    oversized: AtomicU64,
+}
+
+pub fn event_type(event: &ClientEvent) -> &'static str {
+    match event {
+        ClientEvent::Login { .. } => "login",
+        ClientEvent::SendMessage { .. } => "send_message",
+        ClientEvent::SendMedia { .. } => "send_media",
+        ClientEvent::Hello { .. } => "hello",
+        ClientEvent::Block { .. } => "block",
+        ClientEvent::Unblock { .. } => "unblock",
+        ClientEvent::MuteRoom { .. } => "mute_room",
+        ClientEvent::UnmuteRoom { .. } => "unmute_room",
+        ClientEvent::JoinRoom { .. } => "join_room",
+        ClientEvent::LeaveRoom { .. } => "leave_room",
+        ClientEvent::CommandAck { .. } => "command_ack",
+        ClientEvent::Receipt { .. } => "receipt",
+        ClientEvent::Signed { .. } => "signed",
+        ClientEvent::ConfigAck { .. } => "config_ack",
+        ClientEvent::SetPresence { .. } => "set_presence",
+        ClientEvent::Delivered { .. } => "delivered",
+        ClientEvent::RefreshToken { .. } => "refresh_token",
+    }
+}
+
+impl HandlerRegistry {
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        let per = |max, secs| Some(Limit::new(max, Duration::from_secs(secs)));
+        registry.register("login", LoginHandler, per(5, 60));
+        registry.register("send_message", ChatHandler, per(20, 10));
+        registry.register("send_media", MediaHandler, per(5, 10));
+        registry.register("hello", HelloHandler, None);
+        for kind in ["block", "unblock", "mute_room", "unmute_room"] {
+            registry.register(kind, MuteHandler, per(30, 60));
+        }
+        for kind in ["join_room", "leave_room"] {
+            registry.register(kind, RoomHandler, per(30, 60));
+        }
+        registry.register("command_ack", AckHandler, None);
+        registry.register("config_ack", ConfigAckHandler, None);
+        registry.register("receipt", ReceiptHandler, per(100, 10));
+        registry.register("signed", SignedHandler, per(20, 10));
+        registry.register("set_presence", PresenceHandler, per(10, 60));
+        registry.register("delivered", DeliveredHandler, per(100, 10));
+        registry.register("refresh_token", RefreshHandler, per(5, 60));
+        registry
+    }
+
+    pub fn register(
+        &mut self,
+        event_type: &'static str,
+        handler: impl EventHandler + 'static,
+        rate_limit: Option<Limit>,
+    ) {
+        self.handlers.insert(event_type, Entry {
+            handler: Box::new(handler),
+            rate_limit: rate_limit.map(|limit| KeyedLimiter::new(event_type, limit)),
+            class_limits: HashMap::new(),
+            stats: HandlerStats::default(),
+        });
+    }
+
+    /// Applies `GATEWAY_RATE_LIMITS` overrides to registered handlers.
+    pub fn apply_rate_limits(&mut self, overrides: &[(String, Option<Limit>)]) {
+        for (kind, limit) in overrides {
+            match self.handlers.get_mut(kind.as_str()) {
+                Some(entry) => entry.rate_limit = limit.map(|limit| KeyedLimiter::new(kind.clone(), limit)),
+                None => println!("GATEWAY: rate limit for unknown event {}", kind),
+            }
+        }
+    }
+
+    /// Applies a client class profile's overrides (see profiles.rs).
+    pub fn apply_class_rate_limits(&mut self, class: ClientClass, overrides: &[(String, Option<Limit>)]) {
+        for (kind, limit) in overrides {
+            let name = format!("{}@{}", kind, class.as_str());
+            match self.handlers.get_mut(kind.as_str()) {
+                Some(entry) => {
+                    entry.class_limits.insert(class, limit.map(|limit| KeyedLimiter::new(name, limit)));
+                }
+                None => println!("GATEWAY: rate limit for unknown event {}", name),
+            }
+        }
+    }
+
+    /// Current limits for `class`, as advertised in the Welcome event.
+    pub fn rate_limits(&self, class: ClientClass) -> BTreeMap<String, RateLimit> {
+        self.handlers
+            .iter()
+            .filter_map(|(kind, entry)| {
+                let limit = entry.limiter(class)?.limit();
+                Some((kind.to_string(), RateLimit { max: limit.max, per_secs: limit.per.as_secs() }))
+            })
+            .collect()
+    }
+
+    /// Size of each handler's limiter, for /debug/state.
+    pub fn limiter_info(&self) -> Vec<LimiterInfo> {
+        let mut info: Vec<LimiterInfo> =
+            self.handlers.values().flat_map(Entry::limiters).map(|l| l.info()).collect();
+        info.sort_by(|a, b| a.name.cmp(&b.name));
+        info
+    }
+
+    /// The event a transport decoded, or None after answering the client
+    /// with protocol.invalid_event and what was wrong with the frame.
+    pub fn decode(&self, session: &Session, parsed: serde_json::Result<ClientEvent>) -> Option<ClientEvent> {
+        let e = match parsed {
+            Ok(event) => return Some(event),
+            Err(e) => e,
+        };
+        self.invalid.fetch_add(1, Ordering::Relaxed);
+        let reason = match e.classify() {
+            serde_json::error::Category::Data => {
+                // an unknown variant lists every event; its name is enough
+                let message = e.to_string();
+                message.split(", expected one of").next().unwrap_or_default().to_string()
+            }
+            _ => "malformed JSON".to_string(),
+        };
+        session.error(codes::PROTOCOL_INVALID_EVENT, &[("reason", &reason)]);
+        None
+    }
+
+    /// Answers a frame over `max` bytes with message.too_large; the
+    /// transport then closes the connection.
+    pub fn refuse_oversized(&self, session: &Session, max: usize) {
+        self.oversized.fetch_add(1, Ordering::Relaxed);
+        session.error(codes::MESSAGE_TOO_LARGE, &[("max", &max.to_string())]);
+    }
+
+    pub async fn dispatch(&self, state: &AppState, session: &mut Session, event: ClientEvent) {
+        let kind = event_type(&event);
+
+        let allowed = match session.protocol {
+            ProtocolState::AwaitingHello => kind == "hello",
+            ProtocolState::Ready => kind != "hello",
+            ProtocolState::Closing => false,
+        };
+        if !allowed {
+            let code = match session.protocol {
+                ProtocolState::AwaitingHello => codes::PROTOCOL_HELLO_REQUIRED,
+                _ => codes::PROTOCOL_WRONG_STATE,
+            };
+            session.error(code, &[("event", kind), ("state", session.protocol.as_str())]);
+            return;
+        }
+
+        let Some(entry) = self.handlers.get(kind) else {
+            session.error(codes::PROTOCOL_UNSUPPORTED_EVENT, &[("event", kind)]);
+            return;
+        };
+
+        if let Some(limiter) = entry.limiter(session.class) {
+            if limiter.check(&session.id).is_err() {
+                entry.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+                session.error(codes::PROTOCOL_RATE_LIMITED, &[("event", kind)]);
+                return;
+            }
+        }
+        crate::pipeline::mark("validate");
+
+        match entry.handler.handle(state, session, event).await {
+            Ok(()) => {
+                entry.stats.handled.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                entry.stats.failed.fetch_add(1, Ordering::Relaxed);
+                println!("GATEWAY: {} handler failed: {}", kind, e);
+                session.error(codes::PROTOCOL_HANDLER_FAILED, &[("event", kind)]);
+            }
+        }
+    }
+
+    /// Forgets connections whose limits have fully recovered, including
+    /// closed ones.
+    pub fn evict_rate_limits(&self) {
+        for limiter in self.handlers.values().flat_map(Entry::limiters) {
+            limiter.retain_recent();
+        }
+    }
+
+    /// Prometheus text exposition of per-handler counters.
+    pub fn render_metrics(&self) -> String {
+        let mut kinds: Vec<_> = self.handlers.iter().collect();
+        kinds.sort_by_key(|(kind, _)| *kind);
+
+        let mut out = String::new();
+        for outcome in ["handled", "failed", "rate_limited"] {
+            let _ = writeln!(out, "# TYPE gateway_events_{}_total counter", outcome);
+            for (kind, entry) in &kinds {
+                let _ = writeln!(
+                    out,
+                    "gateway_events_{}_total{{event=\"{}\"}} {}",
+                    outcome, kind, entry.stats.get(outcome)
+                );
+            }
+        }
+        let _ = writeln!(out, "# TYPE gateway_events_invalid_total counter");
+        let _ = writeln!(out, "gateway_events_invalid_total {}", self.invalid.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# TYPE gateway_messages_oversized_total counter");
+        let _ = writeln!(out, "gateway_messages_oversized_total {}", self.oversized.load(Ordering::Relaxed));
+        out
+    }
+}
+
+//
+// BUILT-IN HANDLERS
+//
+
+/// The gateway checks no passwords and so signs no tokens: a connection
+/// signs in with a token from auth-api on the upgrade (or a resume token
+/// it was given then), and Login is refused.
+pub struct LoginHandler;
+
+#[async_trait]
+impl EventHandler for LoginHandler {
+    async fn handle(&self, _state: &AppState, session: &mut Session, _event: ClientEvent) -> Result<()> {
+        session.error(codes::AUTH_LOGIN_UNSUPPORTED, &[]);
+        Ok(())
+    }
+}
+
+pub struct ChatHandler;
+
+#[async_trait]
+impl EventHandler for ChatHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> Result<()> {
+        let ClientEvent::SendMessage { content, room, client_msg_id, receipts, expires_in_secs, encryption } = event
+        else {
+            return Ok(());
+        };
+        let room = room.as_deref().unwrap_or(DEFAULT_ROOM);
+        if let Some(id) = &client_msg_id {
+            if id.is_empty() || id.len() > acks::MAX_CLIENT_MSG_ID {
+                session.error(codes::MESSAGE_CLIENT_ID_INVALID, &[("max", &acks::MAX_CLIENT_MSG_ID.to_string())]);
+                return Ok(());
+            }
+            if let Some(ack) = state.acks.resent(state, session, id) {
+                session.reply(&ack);
+                return Ok(());
+            }
+        }
+        if expires_in_secs.is_some_and(|secs| !state.expiry.accepts(secs)) {
+            session.error(codes::MESSAGE_TTL_INVALID, &[("max", &state.expiry.max_ttl_secs.to_string())]);
+            return Ok(());
+        }
+        if !subscriptions::require_joined(session, room) {
+            return Ok(());
+        }
+        let encryption = encryption.filter(|e| !e.alg.is_empty() && !e.key_id.is_empty());
+        if encryption.is_none() && state.rooms.e2ee_required(room) {
+            session.error(codes::MESSAGE_E2EE_REQUIRED, &[("room", room)]);
+            return Ok(());
+        }
+
+        // ciphertext is never a command, whatever it starts with
+        if let Some((command, args)) = commands::parse(&content).filter(|_| encryption.is_none()) {
+            let ctx = CommandContext { command, args, user: session.username.clone(), room: room.to_string() };
+            state.commands.dispatch(state, session, ctx).await;
+            return Ok(());
+        }
+
+        if !state.authorize_post(session, room)
+            || !state.policy.authorize(state, session, PolicyAction::SendMessage, room).await
+        {
+            return Ok(());
+        }
+        // receipts are relayed by username, so anonymous senders get none
+        let receipts = receipts && acks::logged_in(state, session);
+        let expires_at = expires_in_secs.map(expiry::expires_at);
+        let seq = state.broadcast_message(room, &session.username, content, receipts, expires_at, encryption).await;
+        if receipts {
+            state.acks.await_receipts(room, seq, &session.username);
+        }
+        if let Some(id) = client_msg_id {
+            session.reply(&state.acks.posted(state, session, id, room, seq));
+        }
+        Ok(())
+    }
+}
+
+pub struct MediaHandler;
+
+#[async_trait]
+impl EventHandler for MediaHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> Result<()> {
+        let ClientEvent::SendMedia { kind, url } = event else { return Ok(()) };
+        // the URL and thumbnails are plaintext
+        if state.rooms.e2ee_required(DEFAULT_ROOM) {
+            session.error(codes::MESSAGE_E2EE_REQUIRED, &[("room", DEFAULT_ROOM)]);
+            return Ok(());
+        }
+
+        if !state.authorize_post(session, DEFAULT_ROOM)
+            || !state.policy.authorize(state, session, PolicyAction::SendMedia, DEFAULT_ROOM).await
+        {
+            return Ok(());
+        }
+
+        let thumbnails = state.media.thumbnails(&url);
+        state.publish(DEFAULT_ROOM, Some(&session.username), ServerEvent::MediaBroadcast {
+            from: session.username.clone(),
+            kind,
+            url,
+            thumbnails,
+        }).await;
+        Ok(())
+    }
+}
+
+pub struct HelloHandler;
+
+#[async_trait]
+impl EventHandler for HelloHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> Result<()> {
+        let ClientEvent::Hello { locale, resume } = event else { return Ok(()) };
+
+        // an unknown locale is reported but does not fail the handshake
+        if !locale.is_empty() {
+            match i18n::catalog().resolve(&locale) {
+                Some(resolved) => session.locale = resolved,
+                None => session.error(codes::PROTOCOL_UNKNOWN_LOCALE, &[("locale", &locale)]),
+            }
+        }
+        state.connections.transition(session, ProtocolState::Ready);
+
+        let floor = resume.and_then(|r| resume::resume(state, session, r));
+        // signed in on the upgrade: the first resume token, and what was
+        // held for the device while it was away
+        if floor.is_none() && state.connections.get(session.id).is_some_and(|c| c.username.is_some()) {
+            session.reply(&ServerEvent::ResumeToken { token: resume::issue(state, session) });
+            state.devices.deliver_pending(state, &session.username);
+        }
+        session.start_delivery(floor.unwrap_or_default());
+        state.client_config.push_to(state, session.id);
+        Ok(())
+    }
+}
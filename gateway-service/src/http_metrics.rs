@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+
+use crate::state::AppState;
+
+//
+// HTTP METRICS
+//
+// Every request to the axum router is counted and timed per route
+// template (`/reports/:id/claim`, not the concrete path), method and
+// status:
+//
+//   gateway_http_requests_total{method,route,status}
+//   gateway_http_request_duration_seconds{method,route,status}  histogram
+//
+// With exemplars on, each histogram bucket remembers the trace id of the
+// last request that landed in it, taken from the W3C `traceparent` header
+// the tracing proxy in front sets, so a latency spike links to its trace.
+// Exemplars only exist in OpenMetrics, so they are rendered when the
+// scrape asks for `application/openmetrics-text`:
+//
+//   GATEWAY_METRICS_EXEMPLARS     "1" to enable; on by default when
+//                                 OTEL_EXPORTER_OTLP_ENDPOINT is set
+//
+
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub const OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    ts: f64,
+}
+
+#[derive(Default)]
+struct RouteStats {
+    count: u64,
+    sum: f64,
+    /// Per bucket, not cumulative; the last slot is +Inf.
+    buckets: [u64; BUCKETS.len() + 1],
+    exemplars: [Option<Exemplar>; BUCKETS.len() + 1],
+}
+
+type RouteKey = (String, String, u16);
+
+pub struct HttpMetrics {
+    exemplars: bool,
+    routes: Mutex<BTreeMap<RouteKey, RouteStats>>,
+}
+
+/// The trace id of a `traceparent: 00-<trace id>-<span id>-<flags>` header.
+fn trace_id(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("traceparent")?.to_str().ok()?;
+    let trace_id = value.split('-').nth(1)?;
+    let valid = trace_id.len() == 32
+        && trace_id.bytes().all(|b| b.is_ascii_hexdigit())
+        && trace_id.bytes().any(|b| b != b'0');
+    valid.then(|| trace_id.to_ascii_lowercase())
+}
+
+impl HttpMetrics {
+    pub fn from_env() -> Self {
+        let exemplars = match std::env::var("GATEWAY_METRICS_EXEMPLARS") {
+            Ok(v) => v == "1",
+            Err(_) => std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok_and(|v| !v.is_empty()),
+        };
+        Self { exemplars, routes: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn observe(&self, key: RouteKey, secs: f64, trace_id: Option<String>) {
+        let slot = BUCKETS.iter().position(|le| secs <= *le).unwrap_or(BUCKETS.len());
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry(key).or_default();
+        stats.count += 1;
+        stats.sum += secs;
+        stats.buckets[slot] += 1;
+        if let Some(trace_id) = trace_id {
+            let ts = Utc::now().timestamp_millis() as f64 / 1000.0;
+            stats.exemplars[slot] = Some(Exemplar { trace_id, value: secs, ts });
+        }
+    }
+
+    /// Whether a scrape with these headers gets OpenMetrics with exemplars.
+    pub fn wants_openmetrics(&self, headers: &HeaderMap) -> bool {
+        self.exemplars
+            && headers
+                .get("accept")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.contains("application/openmetrics-text"))
+    }
+
+    pub fn render_metrics(&self, with_exemplars: bool) -> String {
+        let routes = self.routes.lock().unwrap();
+        let mut out = String::from("# TYPE gateway_http_requests_total counter\n");
+        for ((method, route, status), stats) in routes.iter() {
+            let _ = writeln!(
+                out,
+                "gateway_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method, route, status, stats.count
+            );
+        }
+
+        out.push_str("# TYPE gateway_http_request_duration_seconds histogram\n");
+        for ((method, route, status), stats) in routes.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\",status=\"{}\"", method, route, status);
+            let mut cumulative = 0;
+            for (slot, count) in stats.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = BUCKETS.get(slot).map_or("+Inf".to_string(), |le| le.to_string());
+                let _ = write!(out, "gateway_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative);
+                if let (true, Some(e)) = (with_exemplars, &stats.exemplars[slot]) {
+                    let _ = write!(out, " # {{trace_id=\"{}\"}} {} {:.3}", e.trace_id, e.value, e.ts);
+                }
+                out.push('\n');
+            }
+            let _ = writeln!(out, "gateway_http_request_duration_seconds_sum{{{}}} {}", labels, stats.sum);
+            let _ = writeln!(out, "gateway_http_request_duration_seconds_count{{{}}} {}", labels, stats.count);
+        }
+        out
+    }
+}
+
+/// Router middleware; requests no route matched are counted as "unmatched"
+/// so scanners cannot blow up the label space.
+pub async fn track(State(state): State<Arc<AppState>>, matched: Option<MatchedPath>, req: Request, next: Next) -> Response {
+    let method = match req.method().as_str() {
+        m @ ("GET" | "HEAD" | "POST" | "PUT" | "PATCH" | "DELETE" | "OPTIONS") => m.to_string(),
+        _ => "other".to_string(),
+    };
+    let route = matched.map_or_else(|| "unmatched".to_string(), |m| m.as_str().to_string());
+    let trace_id = if state.http_metrics.exemplars { trace_id(req.headers()) } else { None };
+
+    let started = Instant::now();
+    let resp = next.run(req).await;
+    let secs = started.elapsed().as_secs_f64();
+
+    state.http_metrics.observe((method, route, resp.status().as_u16()), secs, trace_id);
+    resp
+}
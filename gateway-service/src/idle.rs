@@ -0,0 +1,96 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::Message;
+
+use crate::state::AppState;
+
+//
+// IDLE CONNECTIONS
+//
+// A WebSocket whose peer vanished (a phone that lost coverage, a NAT that
+// dropped the mapping) stays open on the gateway until TCP notices, which
+// without keepalives is never: its delivery task, rate limiter slots and
+// registry entry stay behind. Every frame received, pongs included, counts
+// as activity (see ConnectionInfo::last_seen), and the idle-reaper job
+// checks every open WebSocket:
+//
+//   - idle for half of GATEWAY_IDLE_TIMEOUT_SECS: pinged, on top of the
+//     regular heartbeat, so a live client answers in time
+//   - idle for all of it: closed with 1001 ("idle timeout") without
+//     waiting for a reply that will not come; its cleanup runs as for any
+//     other close, and the rate limiters forget what they kept for it
+//
+// QUIC connections have their own idle timeout in the transport (see
+// quic.rs) and long-poll sessions expire on their own (see longpoll.rs).
+//
+//   GATEWAY_IDLE_TIMEOUT_SECS  how long a WebSocket may go without sending
+//                              anything (default 300; 0 never reaps)
+//
+//   gateway_idle_pings_total
+//   gateway_idle_reaped_total
+//
+
+const DEFAULT_TIMEOUT_SECS: u64 = 300;
+
+pub struct IdleReaper {
+    /// None when idle connections are never reaped.
+    pub timeout: Option<Duration>,
+    pings: AtomicU64,
+    reaped: AtomicU64,
+}
+
+impl IdleReaper {
+    pub fn from_env() -> Self {
+        let secs = std::env::var("GATEWAY_IDLE_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TIMEOUT_SECS);
+        Self::new((secs > 0).then(|| Duration::from_secs(secs)))
+    }
+
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self { timeout, pings: AtomicU64::new(0), reaped: AtomicU64::new(0) }
+    }
+
+    /// How often the job looks; a connection is reaped at most a quarter
+    /// of the timeout late.
+    pub fn interval(&self) -> Duration {
+        self.timeout.map_or(Duration::from_secs(60), |t| (t / 4).max(Duration::from_secs(1)))
+    }
+
+    /// Pings or reaps idle WebSockets; returns how many were reaped.
+    pub fn sweep(&self, state: &AppState) -> usize {
+        let Some(timeout) = self.timeout else { return 0 };
+        let Some(conns) = state.connections.snapshot(true) else { return 0 };
+        let now = Utc::now();
+        let mut reaped = 0;
+        for conn in conns.iter().filter(|c| c.transport == "ws") {
+            if conn.is_idle(now, timeout) {
+                if state.connections.reap(conn.id, CloseCode::Away, "idle timeout") {
+                    let user = conn.username.as_deref().unwrap_or("anonymous");
+                    println!("GATEWAY: connection {} ({}) idle since {}, closing it", conn.id, user, conn.last_seen);
+                    reaped += 1;
+                }
+            } else if conn.is_idle(now, timeout / 2) && state.connections.send_raw(conn.id, Message::Ping(Vec::new())) {
+                self.pings.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if reaped > 0 {
+            self.reaped.fetch_add(reaped as u64, Ordering::Relaxed);
+            state.handlers.evict_rate_limits();
+        }
+        reaped
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE gateway_idle_pings_total counter\n");
+        let _ = writeln!(out, "gateway_idle_pings_total {}", self.pings.load(Ordering::Relaxed));
+        out.push_str("# TYPE gateway_idle_reaped_total counter\n");
+        let _ = writeln!(out, "gateway_idle_reaped_total {}", self.reaped.load(Ordering::Relaxed));
+        out
+    }
+}
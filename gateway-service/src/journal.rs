@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+
+use uchat_proto::envelope::Envelope;
+
+use crate::expiry;
+
+//
+// ROOM JOURNAL
+//
+// Every room broadcast is appended here before it is fanned out, so the
+// room's `seq` numbering and recent events outlive the process. After a
+// deploy, a client resuming on the fresh instance gets its gap replayed
+// from the journal (see resume.rs).
+//
+// The journal is a SQLite file (GATEWAY_JOURNAL_PATH, default
+// `gateway-journal.db`); events older than GATEWAY_JOURNAL_RETENTION_SECS
+// are pruned by a background job. Instances that should resume each
+// other's clients must share the file. Disappearing messages are rewritten
+// as tombstones once they expire (see expiry.rs).
+//
+
+/// GATEWAY_JOURNAL_PATH; other gateway tables shared between instances
+/// live in the same file.
+pub fn path_from_env() -> String {
+    std::env::var("GATEWAY_JOURNAL_PATH").unwrap_or_else(|_| "gateway-journal.db".into())
+}
+
+pub struct RoomJournal {
+    conn: Mutex<Connection>,
+}
+
+/// Events replayed for one room.
+pub struct Gap {
+    pub events: Vec<Envelope>,
+    /// False when events right after the requested `seq` were pruned or
+    /// cut by the replay limit.
+    pub complete: bool,
+}
+
+impl RoomJournal {
+    pub fn from_env() -> rusqlite::Result<Self> {
+        Self::open(&path_from_env())
+    }
+
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS room_events (
+                room     TEXT NOT NULL,
+                seq      INTEGER NOT NULL,
+                ts       TEXT NOT NULL,
+                envelope TEXT NOT NULL,
+                PRIMARY KEY (room, seq)
+            );
+            CREATE INDEX IF NOT EXISTS room_events_ts ON room_events (ts);",
+        )?;
+        // files created before disappearing messages
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('room_events')")?
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        if !columns.iter().any(|c| c == "expires_at") {
+            conn.execute_batch("ALTER TABLE room_events ADD COLUMN expires_at INTEGER")?;
+        }
+        conn.execute_batch("CREATE INDEX IF NOT EXISTS room_events_expires_at ON room_events (expires_at)")?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    pub fn append(&self, envelope: &Envelope) {
+        let json = serde_json::to_string(envelope).unwrap();
+        let res = self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO room_events (room, seq, ts, envelope, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![envelope.room, envelope.seq as i64, Utc::now().to_rfc3339(), json, envelope.expires_at],
+        );
+        if let Err(e) = res {
+            println!("GATEWAY: journal append failed for {}#{}: {}", envelope.room, envelope.seq, e);
+        }
+    }
+
+    /// Highest journaled `seq` per room, where numbering resumes on startup.
+    pub fn last_seqs(&self) -> HashMap<String, u64> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT room, MAX(seq) FROM room_events GROUP BY room").unwrap();
+        stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)? as u64)))
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect()
+    }
+
+    /// Events in `room` after `after` up to the room's `current` seq, at
+    /// most `limit` of them (the most recent ones when the gap is larger).
+    pub fn since(&self, room: &str, after: u64, current: u64, limit: usize) -> Gap {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT seq, envelope FROM room_events WHERE room = ?1 AND seq > ?2 AND seq <= ?3
+                 ORDER BY seq DESC LIMIT ?4",
+            )
+            .unwrap();
+        let mut rows: Vec<(u64, Envelope)> = stmt
+            .query_map(params![room, after as i64, current as i64, limit as i64], |r| {
+                Ok((r.get::<_, i64>(0)? as u64, r.get::<_, String>(1)?))
+            })
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter_map(|(seq, json)| Some((seq, serde_json::from_str(&json).ok()?)))
+            .collect();
+        rows.reverse();
+
+        let complete = match rows.first() {
+            Some((seq, _)) => *seq == after + 1,
+            None => after >= current,
+        };
+        Gap { events: rows.into_iter().map(|(_, e)| e).collect(), complete }
+    }
+
+    /// Rewrites messages that expired by `now` (unix ms) as tombstones;
+    /// returns how many.
+    pub fn expire(&self, now: i64) -> usize {
+        let mut conn = self.conn.lock().unwrap();
+        let Ok(tx) = conn.transaction() else { return 0 };
+        let due: Vec<(String, i64, String)> = {
+            let mut stmt = tx
+                .prepare("SELECT room, seq, envelope FROM room_events WHERE expires_at <= ?1")
+                .unwrap();
+            stmt.query_map([now], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+                .unwrap()
+                .filter_map(Result::ok)
+                .collect()
+        };
+        let mut expired = 0;
+        for (room, seq, json) in due {
+            let Ok(mut envelope) = serde_json::from_str::<Envelope>(&json) else { continue };
+            expiry::expire(&mut envelope, now);
+            let json = serde_json::to_string(&envelope).unwrap();
+            let res = tx.execute(
+                "UPDATE room_events SET envelope = ?3, expires_at = NULL WHERE room = ?1 AND seq = ?2",
+                params![room, seq, json],
+            );
+            if res.is_ok() {
+                expired += 1;
+            }
+        }
+        if let Err(e) = tx.commit() {
+            println!("GATEWAY: journal expiry failed: {}", e);
+            return 0;
+        }
+        expired
+    }
+
+    /// Drops events journaled before `cutoff`; returns how many.
+    pub fn prune(&self, cutoff: DateTime<Utc>) -> usize {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM room_events WHERE ts < ?1", [cutoff.to_rfc3339()])
+            .unwrap_or(0)
+    }
+}
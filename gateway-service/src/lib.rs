@@ -0,0 +1,58 @@
+//
+// GATEWAY SERVICE
+//
+// The binary (main.rs) reads the environment and binds the public ports;
+// everything else lives here so tests can run a gateway in-process on
+// ephemeral ports (see tests/).
+//
+
+pub mod acks;
+pub mod admin;
+pub mod audit;
+pub mod backplane;
+pub mod census;
+pub mod client_config;
+pub mod coalesce;
+pub mod commands;
+pub mod compression;
+pub mod config;
+pub mod connections;
+pub mod debug;
+pub mod devices;
+pub mod drain;
+pub mod encoding;
+pub mod expiry;
+pub mod freeze;
+pub mod handlers;
+pub mod http_metrics;
+pub mod idle;
+pub mod journal;
+pub mod listener;
+pub mod longpoll;
+pub mod media;
+pub mod moderation;
+pub mod mutes;
+pub mod pipeline;
+pub mod policy;
+pub mod presence;
+pub mod profiles;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod receipts;
+pub mod refresh;
+pub mod replay;
+pub mod reports;
+pub mod resume;
+pub mod revocations;
+pub mod room_limits;
+pub mod rooms;
+pub mod routing;
+pub mod scan;
+pub mod server;
+pub mod shutdown;
+pub mod signed;
+pub mod slow_consumers;
+pub mod state;
+pub mod subscriptions;
+pub mod supervisor;
+pub mod validate;
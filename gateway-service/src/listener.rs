@@ -0,0 +1,108 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+//
+// LISTENER TUNING
+//
+// How the WebSocket (9000) and HTTP (7000) ports are bound, and socket
+// options for the connections they accept. The defaults suit a LAN; on
+// lossy links (the ESP32 fleet) keepalive timers and buffer sizes are
+// worth setting.
+//
+//   GATEWAY_BIND_ADDR          address to bind (default 0.0.0.0); "::" binds
+//                              IPv6 and, dual-stack, takes IPv4 as well
+//   GATEWAY_IPV6_ONLY          "1" to keep a "::" listener off IPv4
+//   GATEWAY_LISTEN_BACKLOG     pending connections queued by the kernel
+//                              (default 1024)
+//   GATEWAY_TCP_NODELAY        "0" to let the kernel coalesce small frames
+//                              (Nagle); by default each frame goes out at once
+//   GATEWAY_TCP_KEEPALIVE_SECS idle time before keepalive probes; unset
+//                              leaves keepalive off
+//   GATEWAY_TCP_KEEPALIVE_INTERVAL_SECS  between probes (OS default if unset)
+//   GATEWAY_TCP_KEEPALIVE_RETRIES        unanswered probes before the
+//                              connection is dropped (OS default if unset)
+//   GATEWAY_SOCKET_RECV_BUFFER, GATEWAY_SOCKET_SEND_BUFFER
+//                              per-connection kernel buffers in bytes (OS
+//                              default if unset)
+//
+// Buffers and backlog are set on the listening socket, which accepted
+// connections inherit; nodelay and keepalive are set on each WebSocket
+// connection as it is accepted. The HTTP port only takes nodelay.
+//
+
+#[derive(Debug, Clone)]
+pub struct SocketTuning {
+    pub bind: IpAddr,
+    pub ipv6_only: bool,
+    pub backlog: i32,
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_retries: Option<u32>,
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        println!("GATEWAY: ignoring malformed {}={:?}", name, value);
+    }
+    parsed
+}
+
+impl SocketTuning {
+    pub fn from_env() -> Self {
+        Self {
+            bind: env_parse("GATEWAY_BIND_ADDR").unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ipv6_only: std::env::var("GATEWAY_IPV6_ONLY").is_ok_and(|v| v == "1"),
+            backlog: env_parse("GATEWAY_LISTEN_BACKLOG").unwrap_or(1024),
+            nodelay: !std::env::var("GATEWAY_TCP_NODELAY").is_ok_and(|v| v == "0"),
+            keepalive: env_parse("GATEWAY_TCP_KEEPALIVE_SECS").map(Duration::from_secs),
+            keepalive_interval: env_parse("GATEWAY_TCP_KEEPALIVE_INTERVAL_SECS").map(Duration::from_secs),
+            keepalive_retries: env_parse("GATEWAY_TCP_KEEPALIVE_RETRIES"),
+            recv_buffer: env_parse("GATEWAY_SOCKET_RECV_BUFFER"),
+            send_buffer: env_parse("GATEWAY_SOCKET_SEND_BUFFER"),
+        }
+    }
+
+    /// Binds `port` on the configured address.
+    pub fn bind(&self, port: u16) -> io::Result<TcpListener> {
+        let addr = SocketAddr::new(self.bind, port);
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(self.ipv6_only)?;
+        }
+        socket.set_reuse_address(true)?;
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Per-connection options for an accepted WebSocket connection.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let Some(idle) = self.keepalive else { return Ok(()) };
+        let mut keepalive = TcpKeepalive::new().with_time(idle);
+        if let Some(interval) = self.keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+        if let Some(retries) = self.keepalive_retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tungstenite::protocol::Message;
+
+use uchat_core::i18n::codes;
+
+use crate::connections::ProtocolState;
+use crate::expiry::DeliveryPath;
+use crate::profiles::ClientClass;
+use crate::{drain, handlers};
+use crate::state::{localized_error, negotiate_locale, AppState, DeliveryFloor, Session};
+
+//
+// LONG-POLLING TRANSPORT
+//
+// Fallback for networks that block WebSockets. A poll session is a normal
+// gateway Session whose outbound frames land in a cursor-indexed buffer
+// instead of a socket; clients page through it with GET /poll/events.
+// POST /poll/connect is checked like a WebSocket upgrade: the Origin, and
+// the Bearer token or session cookie the session signs in with.
+//
+
+const BUFFER_CAP: usize = 500;
+const POLL_WAIT: Duration = Duration::from_secs(25);
+const IDLE_EXPIRY: Duration = Duration::from_secs(60);
+
+struct Buffer {
+    events: VecDeque<(u64, Value)>,
+    next_cursor: u64,
+}
+
+pub struct PollSession {
+    conn_id: u64,
+    created: Instant,
+    session: tokio::sync::Mutex<Session>,
+    buffer: Mutex<Buffer>,
+    notify: Notify,
+    last_seen: Mutex<Instant>,
+    pump: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl PollSession {
+    fn push(&self, event: Value) {
+        let mut buf = self.buffer.lock().unwrap();
+        buf.next_cursor += 1;
+        let cursor = buf.next_cursor;
+        buf.events.push_back((cursor, event));
+        while buf.events.len() > BUFFER_CAP {
+            buf.events.pop_front();
+        }
+        drop(buf);
+        self.notify.notify_waiters();
+    }
+
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    /// Events after `cursor`, and whether older ones were already evicted.
+    fn since(&self, cursor: u64) -> (Vec<Value>, u64, bool) {
+        let buf = self.buffer.lock().unwrap();
+        let missed = buf.events.front().is_some_and(|(first, _)| *first > cursor + 1);
+        let events = buf
+            .events
+            .iter()
+            .filter(|(c, _)| *c > cursor)
+            .map(|(_, e)| e.clone())
+            .collect();
+        (events, buf.next_cursor, missed)
+    }
+}
+
+#[derive(Default)]
+pub struct PollSessions {
+    sessions: Mutex<HashMap<String, Arc<PollSession>>>,
+}
+
+impl PollSessions {
+    fn get(&self, id: &str) -> Option<Arc<PollSession>> {
+        let session = self.sessions.lock().unwrap().get(id).cloned()?;
+        session.touch();
+        Some(session)
+    }
+
+    /// Ends the session of connection `conn_id`, as if it had expired;
+    /// false if there is none.
+    pub fn close(&self, state: &AppState, conn_id: u64) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(id) = sessions.iter().find(|(_, s)| s.conn_id == conn_id).map(|(id, _)| id.clone()) else {
+            return false;
+        };
+        let poll = sessions.remove(&id).unwrap();
+        end(state, &poll);
+        println!("GATEWAY: poll session {} closed", id);
+        true
+    }
+}
+
+/// Stops a removed session's delivery and forgets its connection.
+fn end(state: &AppState, poll: &PollSession) {
+    if let Some(pump) = poll.pump.lock().unwrap().take() {
+        pump.abort();
+    }
+    state.connections.unregister(poll.conn_id);
+    state.census.closed(poll.conn_id);
+    state.presence.closed(state, poll.conn_id);
+}
+
+/// Drops sessions nobody has polled for `IDLE_EXPIRY`, and sessions that
+/// did not send Hello within the gateway's hello timeout.
+pub async fn expire_sessions(state: Arc<AppState>) -> anyhow::Result<()> {
+    let mut sessions = state.poll_sessions.sessions.lock().unwrap();
+    sessions.retain(|id, s| {
+        // a session busy in a handler is past the handshake or about to be
+        let greeted = s.session.try_lock().map_or(true, |sess| sess.protocol != ProtocolState::AwaitingHello);
+        let alive = s.last_seen.lock().unwrap().elapsed() < IDLE_EXPIRY
+            && (greeted || s.created.elapsed() < state.config.hello_timeout);
+        if !alive {
+            end(&state, s);
+            println!("GATEWAY: poll session {} expired", id);
+        }
+        alive
+    });
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Connected {
+    session: String,
+    cursor: u64,
+}
+
+// POST /poll/connect
+pub async fn connect(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if state.drain.is_draining() {
+        return drain::refused(&state);
+    }
+    let claims = match state.connect_claims(|name| headers.get(name).map(|v| v.to_str().unwrap_or_default())) {
+        Ok(claims) => claims,
+        Err(refusal) => {
+            let err = localized_error(accept_language(&headers), refusal.code(), &[]);
+            return (StatusCode::from_u16(refusal.status()).unwrap(), Json(err)).into_response();
+        }
+    };
+    let id = uuid::Uuid::new_v4().to_string();
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Message>();
+
+    let mut session = Session::new(out_tx, accept_language(&headers));
+    // as on a WebSocket, a token provisioned for a class sets it
+    if let Some(class) = claims.as_ref().and_then(|c| c.client_class.as_deref()).and_then(ClientClass::parse) {
+        session.class = class;
+    }
+    if let Some(claims) = &claims {
+        session.set_username(claims.sub.clone());
+        session.scope = claims.scope.clone();
+        session.groups = claims.groups.clone();
+    }
+    state.connections.register(&session, "poll");
+    if let Some(claims) = &claims {
+        state.connections.set_username(session.id, &session.username);
+        state.connections.set_token(session.id, claims);
+        state.census.logged_in(&state, session.id);
+        state.presence.logged_in(&state, session.id);
+    }
+    session.reply(&state.welcome(session.class));
+    let identity = session.identity();
+    let rooms = session.subscriptions();
+    let mut delivery = session.delivery();
+
+    let poll = Arc::new(PollSession {
+        conn_id: session.id,
+        created: Instant::now(),
+        session: tokio::sync::Mutex::new(session),
+        buffer: Mutex::new(Buffer { events: VecDeque::new(), next_cursor: 0 }),
+        notify: Notify::new(),
+        last_seen: Mutex::new(Instant::now()),
+        pump: Mutex::new(None),
+    });
+
+    // same fan-out as a socket: room broadcasts plus direct replies
+    let mut rx = state.tx.subscribe();
+    let target = poll.clone();
+    let delivery_state = state.clone();
+    let pump = tokio::spawn(async move {
+        // room events wait for Hello; direct replies go first so a resume
+        // replay is buffered before any live event
+        let mut floor: Option<DeliveryFloor> = None;
+        loop {
+            let event = tokio::select! {
+                biased;
+                Some(Message::Text(text)) = out_rx.recv() => match serde_json::from_str(&text) {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                },
+                Ok(()) = delivery.changed(), if floor.is_none() => {
+                    floor = delivery.borrow().clone();
+                    continue;
+                }
+                Ok(envelope) = rx.recv(), if floor.is_some() => {
+                    let skip = floor.as_ref().and_then(|f| f.get(&envelope.room)).is_some_and(|seq| envelope.seq <= *seq);
+                    let skip = skip || !rooms.borrow().contains(&envelope.room);
+                    if skip || !delivery_state.mutes.allows(&identity.borrow(), &envelope) {
+                        continue;
+                    }
+                    serde_json::to_value(delivery_state.expiry.deliver(envelope, DeliveryPath::Poll)).unwrap()
+                }
+                else => break,
+            };
+            target.push(event);
+        }
+    });
+    *poll.pump.lock().unwrap() = Some(pump);
+
+    state.poll_sessions.sessions.lock().unwrap().insert(id.clone(), poll);
+
+    // the cookie doubles as a stickiness key for load balancers
+    let cookie = format!("uchat_poll={}; Path=/poll; HttpOnly; SameSite=Lax", id);
+    (
+        [(header::SET_COOKIE, cookie)],
+        Json(Connected { session: id, cursor: 0 }),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct SendBody {
+    session: String,
+    /// Decoded once the session is known, so a bad event is answered on it.
+    event: Value,
+}
+
+// POST /poll/send
+pub async fn send(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<SendBody>,
+) -> Response {
+    // the body is decoded by now; "receive" covers the wait for the session
+    let received = Instant::now();
+    let Some(poll) = state.poll_sessions.get(&body.session) else {
+        return unknown_session(&headers);
+    };
+
+    let mut session = poll.session.lock().await;
+    let Some(event) = state.handlers.decode(&session, serde_json::from_value(body.event)) else {
+        return StatusCode::ACCEPTED.into_response();
+    };
+    let kind = handlers::event_type(&event);
+    state.pipeline.traced(kind, received, state.handlers.dispatch(&state, &mut session, event)).await;
+    StatusCode::ACCEPTED.into_response()
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    session: String,
+    #[serde(default)]
+    cursor: u64,
+}
+
+#[derive(Serialize)]
+pub struct EventsPage {
+    cursor: u64,
+    missed: bool,
+    events: Vec<Value>,
+}
+
+// GET /poll/events?session=..&cursor=..
+pub async fn events(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(q): Query<EventsQuery>,
+) -> Response {
+    let Some(poll) = state.poll_sessions.get(&q.session) else {
+        return unknown_session(&headers);
+    };
+
+    let deadline = tokio::time::Instant::now() + POLL_WAIT;
+    loop {
+        let notified = poll.notify.notified();
+        let (events, cursor, missed) = poll.since(q.cursor);
+        if !events.is_empty() || missed {
+            return Json(EventsPage { cursor, missed, events }).into_response();
+        }
+        if tokio::time::timeout_at(deadline, notified).await.is_err() {
+            return Json(EventsPage { cursor, missed: false, events }).into_response();
+        }
+        poll.touch();
+    }
+}
+
+fn accept_language(headers: &HeaderMap) -> &'static str {
+    negotiate_locale(headers.get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()))
+}
+
+fn unknown_session(headers: &HeaderMap) -> Response {
+    let err = localized_error(accept_language(headers), codes::POLL_SESSION_UNKNOWN, &[]);
+    (StatusCode::GONE, Json(err)).into_response()
+}
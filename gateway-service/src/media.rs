@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Multipart, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use image::codecs::jpeg::JpegEncoder;
+use image::{ImageReader, Limits};
+use serde::Serialize;
+
+use uchat_core::i18n::{self, codes};
+use uchat_proto::events::{ServerEvent, Thumbnail};
+
+use crate::reports::caller;
+use crate::scan::Outcome;
+use crate::state::{negotiate_locale, AppState};
+
+//
+// MEDIA UPLOADS AND THUMBNAILS
+//
+// Uploads go to `Storage`. Images also get a JPEG thumbnail per configured
+// size (GATEWAY_THUMBNAIL_SIZES), rendered off the async runtime. The
+// gateway remembers each upload's thumbnails by URL, so a later SendMedia
+// with that URL broadcasts them in the MediaBroadcast and clients can show
+// previews without downloading the original.
+//
+// Files are scanned first when a scanner is configured (see scan.rs).
+//
+
+/// Decoding limits; a small file can still claim enormous dimensions.
+const MAX_DIMENSION: u32 = 8192;
+const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 80;
+
+/// Storage prefix for flagged uploads; never served.
+const QUARANTINE: &str = "quarantine";
+
+#[derive(Default)]
+pub struct MediaIndex {
+    thumbnails: Mutex<HashMap<String, Vec<Thumbnail>>>,
+}
+
+impl MediaIndex {
+    /// Thumbnails generated for an uploaded file, if it was an image.
+    pub fn thumbnails(&self, url: &str) -> Vec<Thumbnail> {
+        self.thumbnails.lock().unwrap().get(url).cloned().unwrap_or_default()
+    }
+}
+
+/// Renders one JPEG per size that is smaller than the image itself, keeping
+/// the aspect ratio. `None` if `bytes` is not a decodable image.
+fn render_thumbnails(bytes: &[u8], sizes: &[u32]) -> Option<Vec<(u32, u32, Vec<u8>)>> {
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?;
+    reader.format()?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    reader.limits(limits);
+
+    let img = reader.decode().ok()?;
+    let longest = img.width().max(img.height());
+
+    let mut out = Vec::new();
+    for &size in sizes.iter().filter(|s| **s < longest) {
+        let thumb = img.thumbnail(size, size).to_rgb8();
+        let mut jpeg = Vec::new();
+        thumb
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY))
+            .ok()?;
+        out.push((thumb.width(), thumb.height(), jpeg));
+    }
+    Some(out)
+}
+
+fn extension(filename: Option<&str>) -> String {
+    filename
+        .and_then(|f| f.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .filter(|ext| !ext.is_empty() && ext.len() <= 8 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or_else(|| "bin".into())
+}
+
+#[derive(Serialize)]
+pub struct Uploaded {
+    pub url: String,
+    pub size: usize,
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// A scanner refusal, with a catalog code so clients can tell it apart.
+fn refused(headers: &HeaderMap, status: StatusCode, code: &str, params: &[(&str, &str)]) -> Response {
+    let locale = negotiate_locale(headers.get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()));
+    let details = i18n::catalog().render(locale, code, params);
+    (status, Json(ServerEvent::Error { details, code: Some(code.into()) })).into_response()
+}
+
+// POST /upload (multipart; every field is a file)
+//
+// Every file is read and scanned before any is stored, so a flagged file
+// rejects the whole request.
+pub async fn upload(State(state): State<Arc<AppState>>, headers: HeaderMap, mut multipart: Multipart) -> Response {
+    let mut files = Vec::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let name = field.file_name().unwrap_or("upload").to_string();
+        let ext = extension(field.file_name());
+        let Ok(data) = field.bytes().await else {
+            return (StatusCode::BAD_REQUEST, "malformed multipart body").into_response();
+        };
+        files.push((name, ext, data));
+    }
+
+    for (name, ext, data) in &files {
+        match state.scanning.check(data).await {
+            Outcome::Clean | Outcome::Unscanned => {}
+            Outcome::Unavailable => {
+                return refused(&headers, StatusCode::SERVICE_UNAVAILABLE, codes::UPLOAD_SCAN_UNAVAILABLE, &[]);
+            }
+            Outcome::Infected(signature) => {
+                let key = format!("{}/{}.{}", QUARANTINE, uuid::Uuid::new_v4(), ext);
+                let actor = caller(&state, &headers).map(|c| c.sub).unwrap_or_else(|| "anonymous".into());
+                let detail = format!("file={:?} size={} signature={}", name, data.len(), signature);
+                if let Err(e) = state.storage.put(&key, data.to_vec()).await {
+                    println!("GATEWAY: quarantining {} failed: {}", key, e);
+                }
+                state.audit.record("upload.quarantined", &actor, &key, detail);
+                let params = [("file", name.as_str()), ("signature", signature.as_str())];
+                return refused(&headers, StatusCode::UNPROCESSABLE_ENTITY, codes::UPLOAD_REJECTED, &params);
+            }
+        }
+    }
+
+    let mut uploaded = Vec::new();
+    for (_, ext, data) in files {
+        let id = uuid::Uuid::new_v4();
+        let url = match state.storage.put(&format!("uploads/{}.{}", id, ext), data.to_vec()).await {
+            Ok(url) => url,
+            Err(e) => {
+                println!("GATEWAY: upload failed: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "could not store upload").into_response();
+            }
+        };
+
+        let sizes = state.config.thumbnail_sizes.clone();
+        let bytes = data.clone();
+        let rendered = tokio::task::spawn_blocking(move || render_thumbnails(&bytes, &sizes))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+
+        let mut thumbnails = Vec::new();
+        for (width, height, jpeg) in rendered {
+            let key = format!("thumbs/{}_{}x{}.jpg", id, width, height);
+            match state.storage.put(&key, jpeg).await {
+                Ok(thumb_url) => thumbnails.push(Thumbnail { url: thumb_url, width, height }),
+                Err(e) => println!("GATEWAY: thumbnail {} failed: {}", key, e),
+            }
+        }
+
+        state.media.thumbnails.lock().unwrap().insert(url.clone(), thumbnails.clone());
+        uploaded.push(Uploaded { url, size: data.len(), thumbnails });
+    }
+
+    Json(uploaded).into_response()
+}
+
+fn content_type(key: &str) -> &'static str {
+    match key.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+// GET /media/*key
+pub async fn serve(State(state): State<Arc<AppState>>, Path(key): Path<String>) -> Response {
+    if key.starts_with(&format!("{}/", QUARANTINE)) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match state.storage.get(&key).await {
+        Ok(Some(bytes)) => (
+            [
+                (header::CONTENT_TYPE, content_type(&key)),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            ],
+            bytes,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("GATEWAY: reading {} failed: {}", key, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::journal;
+use crate::reports::{error, moderator};
+use crate::state::AppState;
+
+//
+// AUTO-MODERATION POLICY
+//
+// Per-room settings for acting on classifier scores: a threshold per
+// category (0 < t <= 1), what to do when a category crosses it, and groups
+// whose members are never auto-moderated. Rooms without a policy have no
+// thresholds, i.e. nothing is acted on automatically.
+//
+//   GET    /rooms/:room/moderation          moderators
+//   PUT    /rooms/:room/moderation          admins
+//   DELETE /rooms/:room/moderation          admins
+//   GET    /rooms/:room/moderation/history  moderators
+//   GET    /moderation/policies             moderators
+//
+// Policies and their history are kept in the journal's SQLite file
+// (GATEWAY_JOURNAL_PATH) so instances sharing it share them. Readers go
+// through an in-memory cache that is updated on every change here and
+// refreshed from the file every GATEWAY_MODERATION_RELOAD_SECS (default
+// 5), so a change made on another instance applies without a restart.
+// Every change is also written to the audit trail.
+//
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Toxicity,
+    SevereToxicity,
+    Insult,
+    Threat,
+    IdentityAttack,
+    Sexual,
+    Spam,
+}
+
+impl Category {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Toxicity => "toxicity",
+            Category::SevereToxicity => "severe_toxicity",
+            Category::Insult => "insult",
+            Category::Threat => "threat",
+            Category::IdentityAttack => "identity_attack",
+            Category::Sexual => "sexual",
+            Category::Spam => "spam",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Open a report for moderators; the message is delivered.
+    Flag,
+    /// Deliver only to the sender until a moderator approves it.
+    Hide,
+    Delete,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModerationPolicy {
+    #[serde(default)]
+    pub thresholds: BTreeMap<Category, f32>,
+    /// Categories with a threshold but no action are flagged.
+    #[serde(default)]
+    pub actions: BTreeMap<Category, Action>,
+    #[serde(default)]
+    pub exempt_roles: BTreeSet<String>,
+}
+
+impl ModerationPolicy {
+    fn validate(&self) -> Result<(), String> {
+        if let Some((category, t)) = self.thresholds.iter().find(|(_, t)| !(**t > 0.0 && **t <= 1.0)) {
+            return Err(format!("threshold for {} must be in (0, 1], got {}", category.as_str(), t));
+        }
+        if let Some(category) = self.actions.keys().find(|c| !self.thresholds.contains_key(c)) {
+            return Err(format!("action for {} has no threshold", category.as_str()));
+        }
+        if self.exempt_roles.iter().any(|r| r.trim().is_empty()) {
+            return Err("exempt roles must not be empty".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredPolicy {
+    pub room: String,
+    /// 0 for a room that never had a policy; bumped on every change.
+    pub version: u64,
+    #[serde(flatten)]
+    pub policy: ModerationPolicy,
+    pub updated_by: Option<String>,
+    pub updated_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyChange {
+    pub version: u64,
+    /// None when the policy was removed.
+    pub policy: Option<ModerationPolicy>,
+    pub changed_by: String,
+    pub changed_at: String,
+}
+
+pub struct ModerationPolicies {
+    conn: Mutex<Connection>,
+    cache: RwLock<HashMap<String, StoredPolicy>>,
+}
+
+impl ModerationPolicies {
+    pub fn from_env() -> rusqlite::Result<Self> {
+        Self::open(&journal::path_from_env())
+    }
+
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS moderation_policies (
+                room       TEXT PRIMARY KEY,
+                version    INTEGER NOT NULL,
+                policy     TEXT NOT NULL,
+                updated_by TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS moderation_policy_history (
+                room       TEXT NOT NULL,
+                version    INTEGER NOT NULL,
+                policy     TEXT,
+                changed_by TEXT NOT NULL,
+                changed_at TEXT NOT NULL,
+                PRIMARY KEY (room, version)
+            );",
+        )?;
+        let store = Self { conn: Mutex::new(conn), cache: RwLock::new(HashMap::new()) };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Replaces the cache with what is in the file.
+    pub fn reload(&self) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let policies: HashMap<String, StoredPolicy> = {
+            let mut stmt = conn.prepare("SELECT room, version, policy, updated_by, updated_at FROM moderation_policies")?;
+            let rows = stmt.query_map([], |r| {
+                Ok((r.get::<_, String>(0)?, r.get::<_, i64>(1)?, r.get::<_, String>(2)?, r.get(3)?, r.get(4)?))
+            })?;
+            rows.filter_map(Result::ok)
+                .filter_map(|(room, version, json, updated_by, updated_at)| {
+                    let policy = serde_json::from_str(&json).ok()?;
+                    let stored =
+                        StoredPolicy { room: room.clone(), version: version as u64, policy, updated_by, updated_at };
+                    Some((room, stored))
+                })
+                .collect()
+        };
+        // still under the connection lock, so a concurrent `set` is never
+        // overwritten with older rows
+        *self.cache.write().unwrap() = policies;
+        Ok(())
+    }
+
+    /// The policy in force for `room`.
+    pub fn get(&self, room: &str) -> StoredPolicy {
+        self.cache.read().unwrap().get(room).cloned().unwrap_or_else(|| StoredPolicy {
+            room: room.to_string(),
+            version: 0,
+            policy: ModerationPolicy::default(),
+            updated_by: None,
+            updated_at: None,
+        })
+    }
+
+    pub fn list(&self) -> Vec<StoredPolicy> {
+        let mut list: Vec<StoredPolicy> = self.cache.read().unwrap().values().cloned().collect();
+        list.sort_by(|a, b| a.room.cmp(&b.room));
+        list
+    }
+
+    /// Sets (or with None removes) `room`'s policy; returns the new
+    /// version, or None when there was nothing to remove.
+    fn set(&self, room: &str, policy: Option<&ModerationPolicy>, by: &str) -> rusqlite::Result<Option<u64>> {
+        let now = Utc::now().to_rfc3339();
+        let json = policy.map(|p| serde_json::to_string(p).unwrap());
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let exists = tx
+            .query_row("SELECT 1 FROM moderation_policies WHERE room = ?1", [room], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if json.is_none() && !exists {
+            return Ok(None);
+        }
+        // versions keep counting across removals, so history stays ordered
+        let version: i64 = tx.query_row(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM moderation_policy_history WHERE room = ?1",
+            [room],
+            |r| r.get(0),
+        )?;
+        match &json {
+            Some(json) => tx.execute(
+                "INSERT OR REPLACE INTO moderation_policies (room, version, policy, updated_by, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![room, version, json, by, now],
+            )?,
+            None => tx.execute("DELETE FROM moderation_policies WHERE room = ?1", [room])?,
+        };
+        tx.execute(
+            "INSERT INTO moderation_policy_history (room, version, policy, changed_by, changed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![room, version, json, by, now],
+        )?;
+        tx.commit()?;
+        self.apply(room, policy, version as u64, by, &now);
+        Ok(Some(version as u64))
+    }
+
+    fn apply(&self, room: &str, policy: Option<&ModerationPolicy>, version: u64, by: &str, at: &str) {
+        let mut cache = self.cache.write().unwrap();
+        match policy {
+            Some(policy) => {
+                cache.insert(room.to_string(), StoredPolicy {
+                    room: room.to_string(),
+                    version,
+                    policy: policy.clone(),
+                    updated_by: Some(by.to_string()),
+                    updated_at: Some(at.to_string()),
+                });
+            }
+            None => {
+                cache.remove(room);
+            }
+        }
+    }
+
+    /// Every change to `room`'s policy, newest first.
+    pub fn history(&self, room: &str) -> rusqlite::Result<Vec<PolicyChange>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT version, policy, changed_by, changed_at FROM moderation_policy_history
+             WHERE room = ?1 ORDER BY version DESC",
+        )?;
+        let rows = stmt.query_map([room], |r| {
+            Ok(PolicyChange {
+                version: r.get::<_, i64>(0)? as u64,
+                policy: r.get::<_, Option<String>>(1)?.and_then(|json| serde_json::from_str(&json).ok()),
+                changed_by: r.get(2)?,
+                changed_at: r.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+fn storage_error(e: rusqlite::Error) -> Response {
+    println!("GATEWAY: moderation policy storage failed: {}", e);
+    error(StatusCode::INTERNAL_SERVER_ERROR, "moderation policy storage failed")
+}
+
+// GET /moderation/policies
+pub async fn list(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Err((status, details)) = moderator(&state, &headers) {
+        return error(status, details);
+    }
+    Json(state.moderation.list()).into_response()
+}
+
+// GET /rooms/:room/moderation
+pub async fn get_policy(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    if let Err((status, details)) = moderator(&state, &headers) {
+        return error(status, details);
+    }
+    Json(state.moderation.get(&room)).into_response()
+}
+
+// PUT /rooms/:room/moderation
+pub async fn put_policy(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+    Json(policy): Json<ModerationPolicy>,
+) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    if let Err(details) = policy.validate() {
+        return error(StatusCode::BAD_REQUEST, &details);
+    }
+
+    match state.moderation.set(&room, Some(&policy), &admin) {
+        Ok(version) => {
+            let detail = format!("version={} {}", version.unwrap_or(0), serde_json::to_string(&policy).unwrap());
+            state.audit.record("moderation.policy_updated", &admin, &format!("room:{}", room), detail);
+            Json(state.moderation.get(&room)).into_response()
+        }
+        Err(e) => storage_error(e),
+    }
+}
+
+// DELETE /rooms/:room/moderation
+pub async fn delete_policy(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    match state.moderation.set(&room, None, &admin) {
+        Ok(Some(version)) => {
+            state.audit.record("moderation.policy_removed", &admin, &format!("room:{}", room), format!("version={}", version));
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(None) => error(StatusCode::NOT_FOUND, "no moderation policy for this room"),
+        Err(e) => storage_error(e),
+    }
+}
+
+// GET /rooms/:room/moderation/history
+pub async fn history(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    if let Err((status, details)) = moderator(&state, &headers) {
+        return error(status, details);
+    }
+    match state.moderation.history(&room) {
+        Ok(changes) => Json(changes).into_response(),
+        Err(e) => storage_error(e),
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use uchat_proto::envelope::Envelope;
+use uchat_proto::events::{ClientEvent, ServerEvent};
+
+use crate::handlers::EventHandler;
+use crate::state::{AppState, Session};
+
+//
+// MUTE / BLOCK LISTS
+//
+// Per-user lists enforced at delivery: a connection never receives room
+// events from a room its user muted, or messages sent by a user they
+// blocked. Users edit their own lists with Block/Unblock/MuteRoom/
+// UnmuteRoom events; the profile service keeps them in sync through
+// PUT /users/:user/mutes.
+//
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MuteList {
+    #[serde(default)]
+    pub blocked_users: BTreeSet<String>,
+    #[serde(default)]
+    pub muted_rooms: BTreeSet<String>,
+}
+
+#[derive(Default)]
+pub struct MuteLists {
+    lists: RwLock<HashMap<String, MuteList>>,
+}
+
+/// Who a room event came from, for events that have a sender.
+fn sender(event: &ServerEvent) -> Option<&str> {
+    match event {
+        ServerEvent::MessageBroadcast { from, .. } | ServerEvent::MediaBroadcast { from, .. } => Some(from),
+        _ => None,
+    }
+}
+
+impl MuteLists {
+    /// Whether `envelope` should be delivered to `user`'s connections.
+    pub fn allows(&self, user: &str, envelope: &Envelope) -> bool {
+        let lists = self.lists.read().unwrap();
+        let Some(list) = lists.get(user) else { return true };
+
+        if list.muted_rooms.contains(&envelope.room) {
+            return false;
+        }
+        sender(&envelope.event).is_none_or(|from| !list.blocked_users.contains(from))
+    }
+
+    pub fn get(&self, user: &str) -> MuteList {
+        self.lists.read().unwrap().get(user).cloned().unwrap_or_default()
+    }
+
+    pub fn replace(&self, user: &str, list: MuteList) {
+        self.lists.write().unwrap().insert(user.to_string(), list);
+    }
+
+    fn update(&self, user: &str, f: impl FnOnce(&mut MuteList)) {
+        f(self.lists.write().unwrap().entry(user.to_string()).or_default());
+    }
+}
+
+pub struct MuteHandler;
+
+#[async_trait]
+impl EventHandler for MuteHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        state.mutes.update(&session.username, |list| match event {
+            ClientEvent::Block { user } => {
+                list.blocked_users.insert(user);
+            }
+            ClientEvent::Unblock { user } => {
+                list.blocked_users.remove(&user);
+            }
+            ClientEvent::MuteRoom { room } => {
+                list.muted_rooms.insert(room);
+            }
+            ClientEvent::UnmuteRoom { room } => {
+                list.muted_rooms.remove(&room);
+            }
+            _ => {}
+        });
+        Ok(())
+    }
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+/// The bearer token must belong to `user` or an admin.
+fn authorize(state: &AppState, headers: &HeaderMap, user: &str) -> Option<Response> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    match state.bearer_claims(auth) {
+        None => Some(error(StatusCode::UNAUTHORIZED, "missing or invalid token")),
+        Some(claims) if claims.sub != user && !state.is_admin(&claims.sub) => {
+            Some(error(StatusCode::FORBIDDEN, "not your mute list"))
+        }
+        Some(_) => None,
+    }
+}
+
+// GET /users/:user/mutes
+pub async fn get_list(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+) -> Response {
+    if let Some(denied) = authorize(&state, &headers, &user) {
+        return denied;
+    }
+    Json(state.mutes.get(&user)).into_response()
+}
+
+// PUT /users/:user/mutes — replaces the whole list
+pub async fn put_list(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(user): Path<String>,
+    Json(list): Json<MuteList>,
+) -> Response {
+    if let Some(denied) = authorize(&state, &headers, &user) {
+        return denied;
+    }
+    state.mutes.replace(&user, list.clone());
+    Json(list).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use uchat_proto::envelope::Envelope;
+    use uchat_proto::events::{PresenceStatus, ServerEvent};
+
+    use super::{MuteList, MuteLists};
+
+    fn envelope(room: &str, event: ServerEvent) -> Envelope {
+        Envelope {
+            room: room.into(),
+            seq: 1,
+            origin: None,
+            sender: None,
+            ts: None,
+            receipts: false,
+            expires_at: None,
+            encryption: None,
+            event,
+        }
+    }
+
+    fn message(room: &str, from: &str) -> Envelope {
+        envelope(room, ServerEvent::MessageBroadcast { from: from.into(), content: "hi".into() })
+    }
+
+    #[test]
+    fn blocked_senders_and_muted_rooms_are_not_delivered() {
+        let mutes = MuteLists::default();
+        mutes.update("ann", |list| {
+            list.blocked_users.insert("eve".into());
+            list.muted_rooms.insert("random".into());
+        });
+
+        assert!(!mutes.allows("ann", &message("lobby", "eve")));
+        let media = ServerEvent::MediaBroadcast {
+            from: "eve".into(),
+            kind: "image".into(),
+            url: "/media/1".into(),
+            thumbnails: Vec::new(),
+        };
+        assert!(!mutes.allows("ann", &envelope("lobby", media)));
+        assert!(mutes.allows("ann", &message("lobby", "bob")));
+        assert!(!mutes.allows("ann", &message("random", "bob")));
+        // events without a sender still go out, except in muted rooms
+        let presence = |room: &str| {
+            let status = PresenceStatus::Online;
+            envelope(room, ServerEvent::PresenceChanged { room: room.into(), user: "eve".into(), status })
+        };
+        assert!(mutes.allows("ann", &presence("lobby")));
+        assert!(!mutes.allows("ann", &presence("random")));
+
+        // one user's list is theirs alone
+        assert!(mutes.allows("bob", &message("random", "eve")));
+    }
+
+    #[test]
+    fn lists_are_edited_in_place_or_replaced_whole() {
+        let mutes = MuteLists::default();
+        mutes.update("ann", |list| {
+            list.blocked_users.insert("eve".into());
+        });
+        mutes.update("ann", |list| {
+            list.muted_rooms.insert("random".into());
+        });
+        let list = mutes.get("ann");
+        assert_eq!((list.blocked_users.len(), list.muted_rooms.len()), (1, 1));
+
+        mutes.update("ann", |list| {
+            list.blocked_users.remove("eve");
+        });
+        assert!(mutes.allows("ann", &message("lobby", "eve")));
+
+        // a PUT body may leave either set out
+        let list: MuteList = serde_json::from_str(r#"{"blocked_users": ["mallory"]}"#).unwrap();
+        mutes.replace("ann", list);
+        assert!(mutes.allows("ann", &message("random", "eve")));
+        assert!(!mutes.allows("ann", &message("lobby", "mallory")));
+        assert!(mutes.get("nobody").blocked_users.is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::State,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use uchat_proto::events::ServerEvent;
+
+use crate::state::AppState;
+
+//
+// MESSAGE PIPELINE TIMING
+//
+// Each client event is timed through the stages it passes, so a latency
+// regression can be pinned on one of them:
+//
+//   receive    frame read -> event decoded
+//   validate   protocol state, handler lookup, rate limit
+//   authorize  room posting rules and token scope
+//   fanout     numbering and hand-off to the room channel
+//   persist    journal write, after the hand-off
+//
+// Stages are marked from wherever the work happens (dispatch, AppState)
+// through a task-local trace the transports open around each event, so
+// handlers need no timing plumbing. Events stop at the stage that refused
+// them, and a stage that was not reached is not recorded.
+//
+//   gateway_pipeline_stage_seconds{event,stage}  histogram
+//
+// A sample of events also keeps its whole breakdown, for GET
+// /debug/pipeline (admins):
+//
+//   GATEWAY_PIPELINE_SAMPLE_RATE   fraction of events kept (default 0.01)
+//
+
+const BUCKETS: &[f64] = &[0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Sampled traces kept for /debug/pipeline.
+const SAMPLES_KEPT: usize = 100;
+
+struct Trace {
+    last: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+tokio::task_local! {
+    static TRACE: RefCell<Trace>;
+}
+
+/// Closes the stage running since the previous mark; a no-op outside a
+/// traced event.
+pub fn mark(stage: &'static str) {
+    let _ = TRACE.try_with(|trace| {
+        let mut trace = trace.borrow_mut();
+        let now = Instant::now();
+        let elapsed = now - trace.last;
+        trace.last = now;
+        trace.stages.push((stage, elapsed));
+    });
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SampledTrace {
+    pub at: DateTime<Utc>,
+    pub event: &'static str,
+    /// Microseconds per stage, in pipeline order.
+    pub stages: Vec<(&'static str, u64)>,
+    pub total_us: u64,
+}
+
+#[derive(Default)]
+struct StageStats {
+    count: u64,
+    sum: f64,
+    /// Per bucket, not cumulative; the last slot is +Inf.
+    buckets: [u64; BUCKETS.len() + 1],
+}
+
+pub struct PipelineTiming {
+    sample_every: u64,
+    seen: AtomicU64,
+    stages: Mutex<BTreeMap<(&'static str, &'static str), StageStats>>,
+    samples: Mutex<VecDeque<SampledTrace>>,
+}
+
+impl PipelineTiming {
+    pub fn from_env() -> Self {
+        let rate: f64 = std::env::var("GATEWAY_PIPELINE_SAMPLE_RATE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0.01);
+        Self {
+            // every n-th event rather than a coin flip: cheap and steady
+            sample_every: if rate > 0.0 { (1.0 / rate.min(1.0)).round() as u64 } else { 0 },
+            seen: AtomicU64::new(0),
+            stages: Mutex::new(BTreeMap::new()),
+            samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Runs `handling` as one traced `event`, received at `received`, and
+    /// records the stages it marked.
+    pub async fn traced<F: Future<Output = ()>>(&self, event: &'static str, received: Instant, handling: F) {
+        let trace = RefCell::new(Trace { last: received, stages: Vec::new() });
+        let stages = TRACE
+            .scope(trace, async move {
+                mark("receive");
+                handling.await;
+                TRACE.with(|t| std::mem::take(&mut t.borrow_mut().stages))
+            })
+            .await;
+        self.record(event, stages);
+    }
+
+    fn record(&self, event: &'static str, stages: Vec<(&'static str, Duration)>) {
+        {
+            let mut stats = self.stages.lock().unwrap();
+            for (stage, elapsed) in &stages {
+                let secs = elapsed.as_secs_f64();
+                let slot = BUCKETS.iter().position(|le| secs <= *le).unwrap_or(BUCKETS.len());
+                let s = stats.entry((event, *stage)).or_default();
+                s.count += 1;
+                s.sum += secs;
+                s.buckets[slot] += 1;
+            }
+        }
+
+        let n = self.seen.fetch_add(1, Ordering::Relaxed);
+        if self.sample_every == 0 || !n.is_multiple_of(self.sample_every) {
+            return;
+        }
+        let stages: Vec<_> = stages.iter().map(|(s, d)| (*s, d.as_micros() as u64)).collect();
+        let sample = SampledTrace {
+            at: Utc::now(),
+            event,
+            total_us: stages.iter().map(|(_, us)| us).sum(),
+            stages,
+        };
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back(sample);
+        while samples.len() > SAMPLES_KEPT {
+            samples.pop_front();
+        }
+    }
+
+    /// Most recent sampled traces, newest first.
+    pub fn samples(&self) -> Vec<SampledTrace> {
+        self.samples.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let stats = self.stages.lock().unwrap();
+        let mut out = String::from("# TYPE gateway_pipeline_stage_seconds histogram\n");
+        for ((event, stage), s) in stats.iter() {
+            let labels = format!("event=\"{}\",stage=\"{}\"", event, stage);
+            let mut cumulative = 0;
+            for (slot, count) in s.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = BUCKETS.get(slot).map_or("+Inf".to_string(), |le| le.to_string());
+                let _ = writeln!(out, "gateway_pipeline_stage_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative);
+            }
+            let _ = writeln!(out, "gateway_pipeline_stage_seconds_sum{{{}}} {}", labels, s.sum);
+            let _ = writeln!(out, "gateway_pipeline_stage_seconds_count{{{}}} {}", labels, s.count);
+        }
+        out
+    }
+}
+
+// GET /debug/pipeline
+pub async fn samples_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(admin) => admin,
+        Err((status, details)) => {
+            let err = ServerEvent::Error { details: details.into(), code: None };
+            return (status, Json(err)).into_response();
+        }
+    };
+    state.audit.record("debug.pipeline", &admin, "gateway", "");
+    Json(state.pipeline.samples()).into_response()
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use uchat_core::i18n::codes;
+use uchat_proto::events::ServerEvent;
+
+use crate::state::{AppState, Session};
+
+//
+// AUTHORIZATION POLICY
+//
+// The built-in checks (token scopes, room ACLs, freezes, rate limits) are
+// the same for every deployment. Rules of its own, e.g. which groups may
+// send media in which rooms, go in an external policy engine that the
+// gateway asks after its own checks passed:
+//
+//   join_room     JoinRoom
+//   send_message  SendMessage and room command replies
+//   send_media    SendMedia
+//
+// The engine is queried like an OPA sidecar: POST GATEWAY_POLICY_URL with
+// {"input": {"user", "action", "room", "groups", "admin", "class",
+// "delegated"}}; the answer's "result" is the decision, either a boolean
+// or {"allow": bool}. An undefined result denies.
+//
+//   GATEWAY_POLICY_URL        e.g. http://127.0.0.1:8181/v1/data/uchat/allow;
+//                             unset: no policy hook
+//   GATEWAY_POLICY_CACHE_SECS how long a decision is reused for the same
+//                             user, groups, action and room (default 30;
+//                             0 asks every time)
+//   GATEWAY_POLICY_FAIL_OPEN  "1" to allow when the engine cannot be asked
+//                             (default: refused)
+//
+//   POST /admin/policy/reload  admins; forgets cached decisions, e.g. after
+//                              the engine's policies changed
+//
+// A refusal is answered with auth.policy_denied. Every decision the engine
+// makes is in the audit trail as policy.allow or policy.deny (target the
+// room, detail the action); a failed query is recorded as policy.error.
+// Decisions reused from the cache are only counted.
+//
+//   gateway_policy_decisions_total{result="allow|deny|error"}
+//   gateway_policy_cache_hits_total
+//
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PolicyAction {
+    JoinRoom,
+    SendMessage,
+    SendMedia,
+}
+
+impl PolicyAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PolicyAction::JoinRoom => "join_room",
+            PolicyAction::SendMessage => "send_message",
+            PolicyAction::SendMedia => "send_media",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DecisionKey {
+    user: String,
+    groups: Vec<String>,
+    action: PolicyAction,
+    room: String,
+}
+
+#[derive(Default)]
+struct Counters {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    errors: AtomicU64,
+    cache_hits: AtomicU64,
+}
+
+pub struct PolicyHook {
+    url: Option<String>,
+    cache_ttl: Duration,
+    fail_open: bool,
+    http: reqwest::Client,
+    cache: Mutex<HashMap<DecisionKey, (bool, Instant)>>,
+    counters: Counters,
+}
+
+impl PolicyHook {
+    pub fn from_env() -> Self {
+        let url = std::env::var("GATEWAY_POLICY_URL").ok().filter(|u| !u.trim().is_empty());
+        let cache_ttl = std::env::var("GATEWAY_POLICY_CACHE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map_or(DEFAULT_CACHE_TTL, Duration::from_secs);
+        let fail_open = std::env::var("GATEWAY_POLICY_FAIL_OPEN").is_ok_and(|v| v == "1");
+        Self::new(url, cache_ttl, fail_open)
+    }
+
+    pub fn new(url: Option<String>, cache_ttl: Duration, fail_open: bool) -> Self {
+        if let Some(url) = &url {
+            println!("GATEWAY: authorization policy from {}", url);
+        }
+        Self {
+            url,
+            cache_ttl,
+            fail_open,
+            http: reqwest::Client::new(),
+            cache: Mutex::new(HashMap::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Asks the engine whether `session` may do `action` in `room`; replies
+    /// with auth.policy_denied and returns false if not. Always true without
+    /// GATEWAY_POLICY_URL.
+    pub async fn authorize(&self, state: &AppState, session: &Session, action: PolicyAction, room: &str) -> bool {
+        let Some(url) = &self.url else { return true };
+        let key = DecisionKey {
+            user: session.username.clone(),
+            groups: session.groups.clone(),
+            action,
+            room: room.to_string(),
+        };
+        let allowed = match self.cached(&key) {
+            Some(allowed) => allowed,
+            None => self.evaluate(state, session, url, key).await,
+        };
+        if !allowed {
+            session.error(codes::AUTH_POLICY_DENIED, &[("action", action.as_str()), ("room", room)]);
+        }
+        allowed
+    }
+
+    fn cached(&self, key: &DecisionKey) -> Option<bool> {
+        let cache = self.cache.lock().unwrap();
+        let (allowed, at) = cache.get(key)?;
+        if at.elapsed() >= self.cache_ttl {
+            return None;
+        }
+        self.counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+        Some(*allowed)
+    }
+
+    async fn evaluate(&self, state: &AppState, session: &Session, url: &str, key: DecisionKey) -> bool {
+        let input = json!({
+            "input": {
+                "user": key.user,
+                "action": key.action.as_str(),
+                "room": key.room,
+                "groups": key.groups,
+                "admin": state.is_admin(&key.user),
+                "class": session.class.as_str(),
+                "delegated": session.scope.is_some(),
+            }
+        });
+        let target = format!("room:{}", key.room);
+        let decision = match self.query(url, &input).await {
+            Ok(allowed) => allowed,
+            Err(e) => {
+                println!("GATEWAY: policy query for {} failed: {}", key.user, e);
+                self.counters.errors.fetch_add(1, Ordering::Relaxed);
+                state.audit.record("policy.error", &key.user, &target, format!("{}: {}", key.action.as_str(), e));
+                // not cached, so the next attempt asks again
+                return self.fail_open;
+            }
+        };
+
+        let (counter, action) = if decision {
+            (&self.counters.allowed, "policy.allow")
+        } else {
+            (&self.counters.denied, "policy.deny")
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        state.audit.record(action, &key.user, &target, key.action.as_str());
+
+        if !self.cache_ttl.is_zero() {
+            self.cache.lock().unwrap().insert(key, (decision, Instant::now()));
+        }
+        decision
+    }
+
+    async fn query(&self, url: &str, input: &Value) -> anyhow::Result<bool> {
+        let resp = self.http.post(url).timeout(QUERY_TIMEOUT).json(input).send().await?;
+        if !resp.status().is_success() {
+            anyhow::bail!("policy engine answered {}", resp.status());
+        }
+        let body: Value = resp.json().await?;
+        Ok(match body.get("result") {
+            Some(Value::Bool(allowed)) => *allowed,
+            Some(result) => result.get("allow").and_then(Value::as_bool).unwrap_or(false),
+            None => false,
+        })
+    }
+
+    /// Forgets every cached decision; returns how many there were.
+    pub fn reload(&self) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        let cached = cache.len();
+        cache.clear();
+        cached
+    }
+
+    /// Drops expired decisions.
+    pub fn evict(&self) {
+        let ttl = self.cache_ttl;
+        self.cache.lock().unwrap().retain(|_, (_, at)| at.elapsed() < ttl);
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let c = &self.counters;
+        let mut out = String::from("# TYPE gateway_policy_decisions_total counter\n");
+        for (result, counter) in [("allow", &c.allowed), ("deny", &c.denied), ("error", &c.errors)] {
+            let count = counter.load(Ordering::Relaxed);
+            let _ = writeln!(out, "gateway_policy_decisions_total{{result=\"{}\"}} {}", result, count);
+        }
+        out.push_str("# TYPE gateway_policy_cache_hits_total counter\n");
+        let _ = writeln!(out, "gateway_policy_cache_hits_total {}", c.cache_hits.load(Ordering::Relaxed));
+        out
+    }
+}
+
+#[derive(Serialize)]
+pub struct PolicyReload {
+    /// Cached decisions that were forgotten.
+    pub forgotten: usize,
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+// POST /admin/policy/reload
+pub async fn reload(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let admin = match state.require_admin(&headers) {
+        Ok(a) => a,
+        Err((status, details)) => return error(status, details),
+    };
+    let forgotten = state.policy.reload();
+    println!("GATEWAY: {} reloaded the authorization policy ({} cached decisions dropped)", admin, forgotten);
+    state.audit.record("policy.reload", &admin, "policy", format!("{} cached decisions dropped", forgotten));
+    Json(PolicyReload { forgotten }).into_response()
+}
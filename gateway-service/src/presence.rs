@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use uchat_core::i18n::codes;
+use uchat_proto::events::{ClientEvent, PresenceStatus, ServerEvent};
+use uchat_proto::jwt::ScopeAction;
+
+use crate::connections::ProtocolState;
+use crate::handlers::EventHandler;
+use crate::state::{AppState, Session, DEFAULT_ROOM};
+
+//
+// PRESENCE
+//
+// Who is around, per user across their connections: online while any of
+// them is, away while all of them set SetPresence away, offline once the
+// last one closes. A user still connected to another gateway (per the
+// census) stays online when their last connection here closes.
+//
+// Changes go to the room's logged-in connections as PresenceChanged. They
+// are state, not history: no seq, no journal, nothing replayed on resume,
+// so a client that was away rereads the list:
+//
+//   GET /rooms/:room/presence   the room's members on this instance
+//
+// Any token that may read the room. Presence is kept for DEFAULT_ROOM
+// only, where every connection starts; connections that leave it stop
+// hearing changes. Other rooms have no members yet.
+//
+
+#[derive(Default)]
+pub struct PresenceTracker {
+    /// Logged-in connections by id: whose they are and what they set.
+    conns: Mutex<HashMap<u64, (String, PresenceStatus)>>,
+    /// Last status announced per user; offline users are dropped.
+    announced: Mutex<HashMap<String, PresenceStatus>>,
+}
+
+#[derive(Serialize)]
+pub struct Member {
+    user: String,
+    status: PresenceStatus,
+    connections: usize,
+}
+
+impl PresenceTracker {
+    /// Records a login on connection `id`; a re-login moves the connection
+    /// from the user it was logged in as.
+    pub fn logged_in(&self, state: &AppState, id: u64) {
+        let Some(user) = state.connections.get(id).and_then(|c| c.username) else { return };
+        let previous = self.conns.lock().unwrap().insert(id, (user.clone(), PresenceStatus::Online));
+        if let Some((previous, _)) = previous.filter(|(previous, _)| *previous != user) {
+            self.refresh(state, &previous);
+        }
+        self.refresh(state, &user);
+    }
+
+    pub fn closed(&self, state: &AppState, id: u64) {
+        let removed = self.conns.lock().unwrap().remove(&id);
+        if let Some((user, _)) = removed {
+            self.refresh(state, &user);
+        }
+    }
+
+    /// Sets connection `id`'s own status; false if it is not logged in.
+    fn set(&self, state: &AppState, id: u64, status: PresenceStatus) -> bool {
+        let user = match self.conns.lock().unwrap().get_mut(&id) {
+            Some((user, set)) => {
+                *set = status;
+                user.clone()
+            }
+            None => return false,
+        };
+        self.refresh(state, &user);
+        true
+    }
+
+    fn status(&self, state: &AppState, user: &str) -> PresenceStatus {
+        let conns = self.conns.lock().unwrap();
+        let local = conns.values().filter(|(u, _)| u == user).map(|(_, status)| *status).min();
+        drop(conns);
+        local.unwrap_or_else(|| {
+            // the census does not know about away; elsewhere counts as online
+            let here = &state.config.instance.instance_id;
+            let located = state.census.locate(user);
+            if located.is_ok_and(|conns| conns.iter().any(|c| c.instance != *here)) {
+                PresenceStatus::Online
+            } else {
+                PresenceStatus::Offline
+            }
+        })
+    }
+
+    /// Announces `user`'s status to the room if it changed. The lock is
+    /// held across the send so announcements go out in the order made.
+    fn refresh(&self, state: &AppState, user: &str) {
+        let status = self.status(state, user);
+        let mut announced = self.announced.lock().unwrap();
+        if announced.get(user).copied().unwrap_or(PresenceStatus::Offline) == status {
+            return;
+        }
+        if status == PresenceStatus::Offline {
+            announced.remove(user);
+        } else {
+            announced.insert(user.to_string(), status);
+        }
+
+        let event = ServerEvent::PresenceChanged { room: DEFAULT_ROOM.into(), user: user.into(), status };
+        state.connections.send_each(|conn| {
+            let ready = conn.username.is_some() && conn.state == ProtocolState::Ready;
+            let member = ready && conn.rooms.contains(DEFAULT_ROOM);
+            member.then(|| event.clone())
+        });
+    }
+
+    /// Members logged in on this instance, by name.
+    fn members(&self) -> Vec<Member> {
+        let mut members: BTreeMap<&str, Member> = BTreeMap::new();
+        let conns = self.conns.lock().unwrap();
+        for (user, status) in conns.values() {
+            let member = members.entry(user).or_insert_with(|| Member {
+                user: user.clone(),
+                status: *status,
+                connections: 0,
+            });
+            member.status = member.status.min(*status);
+            member.connections += 1;
+        }
+        members.into_values().collect()
+    }
+
+    /// Prometheus gauge of users per announced status.
+    pub fn render_metrics(&self) -> String {
+        let announced = self.announced.lock().unwrap();
+        let away = announced.values().filter(|s| **s == PresenceStatus::Away).count();
+        let mut out = String::from("# TYPE gateway_presence_users gauge\n");
+        out.push_str(&format!("gateway_presence_users{{status=\"online\"}} {}\n", announced.len() - away));
+        out.push_str(&format!("gateway_presence_users{{status=\"away\"}} {}\n", away));
+        out
+    }
+}
+
+pub struct PresenceHandler;
+
+#[async_trait]
+impl EventHandler for PresenceHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let ClientEvent::SetPresence { status } = event else { return Ok(()) };
+        if status == PresenceStatus::Offline {
+            session.reply(&ServerEvent::Error { details: "status must be online or away".into(), code: None });
+            return Ok(());
+        }
+        if !state.presence.set(state, session.id, status) {
+            session.error(codes::AUTH_FORBIDDEN, &[]);
+        }
+        Ok(())
+    }
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+// GET /rooms/:room/presence
+pub async fn room_presence(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+) -> Response {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let Some(claims) = state.token_claims(auth) else {
+        return error(StatusCode::UNAUTHORIZED, "missing or invalid token");
+    };
+    if !claims.scope.as_ref().is_none_or(|s| s.allows(&room, ScopeAction::Read)) {
+        return error(StatusCode::FORBIDDEN, "token may not read this room");
+    }
+    if room != DEFAULT_ROOM {
+        return error(StatusCode::NOT_FOUND, "no such room");
+    }
+    Json(state.presence.members()).into_response()
+}
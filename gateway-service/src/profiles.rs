@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use uchat_core::ratelimit::Limit;
+use uchat_proto::events::Batching;
+
+//
+// CLIENT CLASS PROFILES
+//
+// Connections belong to a client class, each with its own limits, so a
+// constrained device (the ESP32 fleet) is not sent payloads it cannot
+// buffer or asked to answer pings more often than it wakes up:
+//
+//   browser   the default
+//   desktop
+//   iot
+//
+// The class comes from the `client_class` claim of the token a connection
+// signs in with on the upgrade, else from a WebSocket subprotocol
+// "uchat.<class>" the client offers (echoed back in the handshake, unless
+// the CBOR encoding is selected instead; see encoding.rs). Other
+// transports use the default class.
+//
+// Each profile starts from the global settings (config.rs), iot with
+// smaller defaults, and can be overridden per class:
+//
+//   GATEWAY_PROFILE_BROWSER, GATEWAY_PROFILE_DESKTOP, GATEWAY_PROFILE_IOT
+//       "max_message_bytes=4096,heartbeat_secs=60,resume_max_events=50,send_message=5/10"
+//       any other key is a per-event rate limit, as in GATEWAY_RATE_LIMITS
+//
+//   max_message_bytes  largest message either way: bigger inbound messages
+//                      close the connection, bigger room events are not
+//                      delivered (the client sees the gap in seq)
+//   heartbeat_secs     server ping interval
+//   resume_max_events  most events replayed per room on resume
+//   batch_ms           coalesce room events into Batch frames sent at most
+//                      this long after their first event (default 0, off)
+//   batch_bytes        ...or once they reach this size (default 16384,
+//                      capped at max_message_bytes)
+//
+// iot defaults: 4096 bytes, 60s heartbeat, 50 events replayed.
+//
+//   gateway_profile_events_dropped_total{class}  counter
+//   gateway_profile_batches_total{class}         counter, Batch frames sent
+//   gateway_profile_batched_events_total{class}  counter, events in them
+//
+
+/// Subprotocols are "uchat.<class>".
+const SUBPROTOCOL_PREFIX: &str = "uchat.";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientClass {
+    #[default]
+    Browser,
+    Desktop,
+    Iot,
+}
+
+impl ClientClass {
+    pub const ALL: [ClientClass; 3] = [ClientClass::Browser, ClientClass::Desktop, ClientClass::Iot];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ClientClass::Browser => "browser",
+            ClientClass::Desktop => "desktop",
+            ClientClass::Iot => "iot",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.as_str().eq_ignore_ascii_case(s.trim()))
+    }
+
+    /// The first class offered in a `Sec-WebSocket-Protocol` header.
+    pub fn from_subprotocols(header: &str) -> Option<Self> {
+        header.split(',').find_map(|p| Self::parse(p.trim().strip_prefix(SUBPROTOCOL_PREFIX)?))
+    }
+
+    pub fn subprotocol(self) -> String {
+        format!("{}{}", SUBPROTOCOL_PREFIX, self.as_str())
+    }
+}
+
+pub struct Profile {
+    pub max_message_bytes: usize,
+    pub heartbeat: Duration,
+    pub resume_max_events: usize,
+    /// Per-event overrides of the global rate limits.
+    pub rate_limits: Vec<(String, Option<Limit>)>,
+    /// Zero leaves room events uncoalesced.
+    pub batch_delay: Duration,
+    pub batch_bytes: usize,
+    /// Room events not delivered for being over `max_message_bytes`.
+    dropped: AtomicU64,
+    batches: AtomicU64,
+    batched: AtomicU64,
+}
+
+impl Profile {
+    pub fn new(max_message_bytes: usize, heartbeat: Duration, resume_max_events: usize) -> Self {
+        Self {
+            max_message_bytes,
+            heartbeat,
+            resume_max_events,
+            rate_limits: Vec::new(),
+            batch_delay: Duration::ZERO,
+            batch_bytes: 16 * 1024,
+            dropped: AtomicU64::new(0),
+            batches: AtomicU64::new(0),
+            batched: AtomicU64::new(0),
+        }
+    }
+
+    /// How room events are coalesced for this class, if they are.
+    pub fn batching(&self) -> Option<Batching> {
+        (!self.batch_delay.is_zero()).then(|| Batching {
+            max_delay_ms: self.batch_delay.as_millis() as u64,
+            max_bytes: self.batch_bytes.min(self.max_message_bytes),
+        })
+    }
+
+    /// Counts a Batch frame sent with `events` in it.
+    pub fn count_batch(&self, events: usize) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.batched.fetch_add(events as u64, Ordering::Relaxed);
+    }
+
+    /// Whether an outbound frame fits; counts the ones that do not.
+    pub fn fits(&self, frame: &str) -> bool {
+        let fits = frame.len() <= self.max_message_bytes;
+        if !fits {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        fits
+    }
+
+    fn apply(&mut self, class: ClientClass, spec: &str) {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let applied = match entry.split_once('=') {
+                Some(("max_message_bytes", v)) => v.parse().map(|v| self.max_message_bytes = v).is_ok(),
+                Some(("heartbeat_secs", v)) => {
+                    v.parse().ok().filter(|s| *s > 0).map(|s| self.heartbeat = Duration::from_secs(s)).is_some()
+                }
+                Some(("resume_max_events", v)) => v.parse().map(|v| self.resume_max_events = v).is_ok(),
+                Some(("batch_ms", v)) => v.parse().map(|ms| self.batch_delay = Duration::from_millis(ms)).is_ok(),
+                Some(("batch_bytes", v)) => v.parse().map(|v| self.batch_bytes = v).is_ok(),
+                _ => crate::config::parse_rate_limit(entry).map(|limit| self.rate_limits.push(limit)).is_some(),
+            };
+            if !applied {
+                println!("GATEWAY: ignoring malformed {} profile setting {:?}", class.as_str(), entry);
+            }
+        }
+    }
+}
+
+pub struct Profiles {
+    profiles: BTreeMap<ClientClass, Profile>,
+}
+
+impl Profiles {
+    /// Profiles from GATEWAY_PROFILE_*, starting from the global settings.
+    pub fn from_env(max_message_bytes: usize, heartbeat: Duration, resume_max_events: usize) -> Self {
+        let profiles = ClientClass::ALL
+            .into_iter()
+            .map(|class| {
+                let mut profile = match class {
+                    ClientClass::Iot => Profile::new(
+                        max_message_bytes.min(4096),
+                        heartbeat.max(Duration::from_secs(60)),
+                        resume_max_events.min(50),
+                    ),
+                    _ => Profile::new(max_message_bytes, heartbeat, resume_max_events),
+                };
+                let var = format!("GATEWAY_PROFILE_{}", class.as_str().to_uppercase());
+                if let Ok(spec) = std::env::var(&var) {
+                    profile.apply(class, &spec);
+                }
+                (class, profile)
+            })
+            .collect();
+        Self { profiles }
+    }
+
+    pub fn get(&self, class: ClientClass) -> &Profile {
+        &self.profiles[&class]
+    }
+
+    /// What the transport admits before the class is known.
+    pub fn largest_message(&self) -> usize {
+        self.profiles.values().map(|p| p.max_message_bytes).max().unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ClientClass, &Profile)> {
+        self.profiles.iter().map(|(class, profile)| (*class, profile))
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let counters = |p: &Profile| [&p.dropped, &p.batches, &p.batched].map(|c| c.load(Ordering::Relaxed));
+        for (i, name) in ["events_dropped", "batches", "batched_events"].into_iter().enumerate() {
+            let _ = writeln!(out, "# TYPE gateway_profile_{}_total counter", name);
+            for (class, profile) in self.iter() {
+                let value = counters(profile)[i];
+                let _ = writeln!(out, "gateway_profile_{}_total{{class=\"{}\"}} {}", name, class.as_str(), value);
+            }
+        }
+        out
+    }
+}
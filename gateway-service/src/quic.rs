@@ -0,0 +1,188 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+use tungstenite::protocol::Message;
+
+use uchat_core::i18n::{self, codes};
+
+use crate::connections::ProtocolState;
+use crate::handlers;
+use crate::state::{AppState, Session};
+use crate::supervisor::{supervise, Cleanup};
+
+//
+// QUIC LISTENER (experimental, `--features quic`)
+//
+// A QUIC endpoint next to the WebSocket one, on the same session and room
+// layer, to measure latency and connection migration for mobile clients:
+//
+//   GATEWAY_QUIC_ADDR   UDP address to listen on (default 0.0.0.0:9443)
+//   GATEWAY_QUIC_CERT   PEM certificate chain
+//   GATEWAY_QUIC_KEY    PEM private key; without both a self-signed
+//                       certificate for localhost is generated (testing only)
+//
+// The ALPN is "uchat/1". The client opens one bidirectional stream by
+// writing its Hello, and both sides write newline-delimited JSON: exactly
+// the WebSocket text frames, one per line. Heartbeats are QUIC keep-alives
+// instead of pings. There is no upgrade to carry a token and Login is
+// refused (see handlers.rs), so a QUIC connection is anonymous unless its
+// Hello resumes a session signed in elsewhere (see resume.rs).
+//
+// This is raw QUIC, not yet WebTransport: browsers additionally need the
+// HTTP/3 session layer, which would carry the same stream.
+//
+
+const ALPN: &[u8] = b"uchat/1";
+
+pub async fn serve(state: Arc<AppState>) -> anyhow::Result<()> {
+    let addr: SocketAddr = std::env::var("GATEWAY_QUIC_ADDR")
+        .unwrap_or_else(|_| "0.0.0.0:9443".into())
+        .parse()
+        .context("GATEWAY_QUIC_ADDR")?;
+
+    let endpoint = quinn::Endpoint::server(server_config(&state)?, addr)?;
+    println!("GATEWAY: experimental QUIC listener on udp://{} (ALPN uchat/1)", addr);
+
+    while let Some(incoming) = endpoint.accept().await {
+        if state.drain.is_draining() {
+            state.drain.refuse(&state);
+            incoming.refuse();
+            continue;
+        }
+        let state = state.clone();
+        tokio::spawn(supervise(state.clone(), "quic", async move {
+            let conn = match incoming.await {
+                Ok(conn) => conn,
+                Err(e) => return println!("GATEWAY: QUIC handshake failed: {}", e),
+            };
+            if let Err(e) = handle_connection(&conn, state).await {
+                println!("GATEWAY: QUIC connection from {} ended: {}", conn.remote_address(), e);
+            }
+            conn.close(0u32.into(), b"bye");
+        }));
+    }
+    Ok(())
+}
+
+fn certificate() -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    if let (Ok(cert), Ok(key)) = (std::env::var("GATEWAY_QUIC_CERT"), std::env::var("GATEWAY_QUIC_KEY")) {
+        let chain = CertificateDer::pem_file_iter(&cert)
+            .with_context(|| format!("reading {}", cert))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(&key).with_context(|| format!("reading {}", key))?;
+        return Ok((chain, key));
+    }
+
+    println!("GATEWAY: QUIC using a self-signed certificate for localhost; set GATEWAY_QUIC_CERT/KEY");
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+    Ok((vec![generated.cert.der().clone()], key.into()))
+}
+
+fn server_config(state: &AppState) -> anyhow::Result<quinn::ServerConfig> {
+    let (chain, key) = certificate()?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(state.config.heartbeat));
+    transport.max_idle_timeout(Some((state.config.heartbeat * 3).try_into()?));
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+async fn handle_connection(conn: &quinn::Connection, state: Arc<AppState>) -> anyhow::Result<()> {
+    // the stream only reaches us with the client's first bytes (its Hello)
+    let (mut send, recv) = tokio::time::timeout(state.config.hello_timeout, conn.accept_bi())
+        .await
+        .context("no stream opened before the hello timeout")??;
+
+    let (msg_tx, mut msg_rx) = mpsc::unbounded_channel::<Message>();
+    let (room_tx, mut room_rx) = mpsc::channel::<Message>(state.slow_consumers.queue);
+    let writer = tokio::spawn(async move {
+        loop {
+            // direct replies first, as on WebSockets (see server.rs)
+            let msg = tokio::select! {
+                biased;
+                Some(msg) = msg_rx.recv() => msg,
+                Some(msg) = room_rx.recv() => msg,
+                else => break,
+            };
+            let text = match msg {
+                Message::Text(text) => text,
+                Message::Close(_) => {
+                    let _ = send.finish();
+                    break;
+                }
+                _ => continue,
+            };
+            if send.write_all(text.as_bytes()).await.is_err() || send.write_all(b"\n").await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new(msg_tx.clone(), i18n::DEFAULT_LOCALE);
+    state.connections.register(&session, "quic");
+    let mut cleanup = Cleanup::new(&state, &session, writer.abort_handle());
+    session.reply(&state.welcome(session.class));
+    let forwarder = crate::server::spawn_delivery(&state, &session, room_tx);
+    cleanup.forwarder(forwarder.abort_handle());
+
+    let max = state.config.profiles.get(session.class).max_message_bytes;
+    let mut reader = BufReader::new(recv);
+    let hello_deadline = tokio::time::Instant::now() + state.config.hello_timeout;
+    let result = loop {
+        let mut line = Vec::new();
+        let mut limited = (&mut reader).take(max as u64 + 1);
+        let read = limited.read_until(b'\n', &mut line);
+        let read = if session.protocol == ProtocolState::AwaitingHello {
+            tokio::select! {
+                read = read => read,
+                _ = tokio::time::sleep_until(hello_deadline) => {
+                    let secs = state.config.hello_timeout.as_secs().to_string();
+                    session.error(codes::PROTOCOL_HELLO_TIMEOUT, &[("secs", &secs)]);
+                    state.connections.transition(&mut session, ProtocolState::Closing);
+                    let _ = session.out.send(Message::Close(None));
+                    break Ok(());
+                }
+            }
+        } else {
+            read.await
+        };
+
+        match read {
+            Ok(0) => break Ok(()),
+            Ok(_) if line.last() != Some(&b'\n') && line.len() > max => {
+                state.handlers.refuse_oversized(&session, max);
+                break Err(anyhow::anyhow!("message over {} bytes", max));
+            }
+            Ok(_) => {
+                let received = Instant::now();
+                if let Some(event) = state.handlers.decode(&session, serde_json::from_slice(line.trim_ascii())) {
+                    let kind = handlers::event_type(&event);
+                    state.pipeline.traced(kind, received, state.handlers.dispatch(&state, &mut session, event)).await;
+                }
+            }
+            Err(e) => break Err(e.into()),
+        }
+    };
+
+    drop(cleanup);
+    // let the writer flush what is queued (an error, the stream finish)
+    drop(session);
+    let _ = tokio::time::timeout(Duration::from_secs(1), writer).await;
+    result
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_json::json;
+
+use uchat_proto::events::ClientEvent;
+use uchat_proto::jwt::create_token;
+
+use crate::handlers::EventHandler;
+use crate::state::{AppState, Session};
+
+//
+// DELIVERY RECEIPTS
+//
+// Receipt events are relayed to chat-service (POST /receipts), which keeps
+// them for compliance rooms and ignores the rest. The call carries a token
+// for the session's user, so chat-service applies its own room access
+// rules. CHAT_SERVICE_URL (default http://127.0.0.1:9301) points at it.
+//
+// Relaying is fire-and-forget: a receipt is never worth holding up the
+// connection, and clients re-send them on reconnect anyway.
+//
+
+pub struct ReceiptForwarder {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl ReceiptForwarder {
+    pub fn from_env() -> Self {
+        let base = std::env::var("CHAT_SERVICE_URL").unwrap_or_else(|_| "http://127.0.0.1:9301".into());
+        Self { url: format!("{}/receipts", base.trim_end_matches('/')), http: reqwest::Client::new() }
+    }
+}
+
+pub struct ReceiptHandler;
+
+#[async_trait]
+impl EventHandler for ReceiptHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let ClientEvent::Receipt { room, message_id, kind } = event else { return Ok(()) };
+
+        let request = state
+            .receipts
+            .http
+            .post(&state.receipts.url)
+            .timeout(Duration::from_secs(5))
+            .bearer_auth(create_token(&state.secret, &session.username))
+            .json(&json!({ "room": room, "message_id": message_id, "kind": kind }));
+        let username = session.username.clone();
+
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(resp) if !resp.status().is_success() => {
+                    println!("GATEWAY: receipt from {} for {}#{} refused: {}", username, room, message_id, resp.status());
+                }
+                Ok(_) => {}
+                Err(e) => println!("GATEWAY: receipt relay failed: {}", e),
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use async_trait::async_trait;
+
+use uchat_core::i18n::codes;
+use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::jwt::ScopeAction;
+
+use crate::handlers::EventHandler;
+use crate::resume;
+use crate::state::{AppState, Session, DEFAULT_ROOM};
+
+//
+// TOKEN REFRESH
+//
+// A connection outlives the token it signed in with. Rather than
+// reconnecting before it expires, a client sends RefreshToken with a new
+// one: the gateway verifies it like a token on the upgrade, and if it is
+// for the same user the connection carries on under it, answered with
+// TokenRefreshed. A token for someone else is refused (auth.forbidden), as
+// is a refresh on a connection that never signed in; an invalid or
+// expired one, or one revoked (see revocations.rs), gets auth.invalid_token
+// and leaves the connection as it was.
+//
+// The new token's groups and scope replace the old one's, and rooms it
+// would not let the connection join are left (RoomLeft), followed by a
+// fresh resume token; the lobby, which every connection starts in, is
+// kept. The new expiry is recorded in ConnectionInfo.
+//
+
+pub struct RefreshHandler;
+
+#[async_trait]
+impl EventHandler for RefreshHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let ClientEvent::RefreshToken { token } = event else { return Ok(()) };
+        let Some(claims) = state.verified_claims(&token) else {
+            session.error(codes::AUTH_INVALID_TOKEN, &[]);
+            return Ok(());
+        };
+        let signed_in = state.connections.get(session.id).and_then(|c| c.username);
+        if signed_in.as_deref() != Some(claims.sub.as_str()) {
+            session.error(codes::AUTH_FORBIDDEN, &[]);
+            return Ok(());
+        }
+
+        state.connections.set_token(session.id, &claims);
+        session.scope = claims.scope;
+        session.groups = claims.groups;
+        session.reply(&ServerEvent::TokenRefreshed { exp: claims.exp as u64 });
+
+        let mut left = false;
+        for room in session.rooms() {
+            let admitted = session.may(&room, ScopeAction::Read) && state.rooms.may_join(&session.groups, &room);
+            if room == DEFAULT_ROOM || admitted {
+                continue;
+            }
+            session.leave(&room);
+            session.reply(&ServerEvent::RoomLeft { room });
+            left = true;
+        }
+        if left {
+            state.connections.sync_rooms(session);
+            session.reply(&ServerEvent::ResumeToken { token: resume::issue(state, session) });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use uchat_proto::envelope::Envelope;
+
+use crate::expiry;
+use crate::journal::Gap;
+
+//
+// REPLAY BUFFER
+//
+// The last GATEWAY_REPLAY_BUFFER events of each room (default 256, 0 turns
+// the buffer off) are kept in memory as well as in the journal. A
+// resuming client's gap is served from here when the buffer holds all of
+// it, which covers the usual case of a phone switching networks, and
+// spares the journal a read per room when a whole fleet reconnects at
+// once. Longer gaps, and gaps from before this process started, come from
+// the journal as before (see resume.rs).
+//
+//   gateway_replay_gaps_total{source="buffer"|"journal"}
+//
+
+pub struct ReplayBuffer {
+    capacity: usize,
+    /// Per room, in seq order.
+    rooms: Mutex<HashMap<String, VecDeque<Envelope>>>,
+    from_buffer: AtomicU64,
+    from_journal: AtomicU64,
+}
+
+impl ReplayBuffer {
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("GATEWAY_REPLAY_BUFFER").ok().and_then(|v| v.parse().ok()).unwrap_or(256);
+        Self::new(capacity)
+    }
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            rooms: Mutex::new(HashMap::new()),
+            from_buffer: AtomicU64::new(0),
+            from_journal: AtomicU64::new(0),
+        }
+    }
+
+    /// Keeps `envelope`. Events from other instances can arrive slightly
+    /// out of order (see backplane.rs), so it is placed by seq.
+    pub fn push(&self, envelope: &Envelope) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut rooms = self.rooms.lock().unwrap();
+        let events = rooms.entry(envelope.room.clone()).or_default();
+        let at = events.iter().rposition(|e| e.seq < envelope.seq).map_or(0, |i| i + 1);
+        if events.get(at).is_some_and(|e| e.seq == envelope.seq) {
+            return;
+        }
+        events.insert(at, envelope.clone());
+        if events.len() > self.capacity {
+            events.pop_front();
+        }
+    }
+
+    /// Like `RoomJournal::since`, if the buffer holds every event it
+    /// would return; None sends the caller to the journal.
+    pub fn since(&self, room: &str, after: u64, current: u64, limit: usize) -> Option<Gap> {
+        let first = (after + 1).max((current + 1).saturating_sub(limit as u64));
+        let wanted = current.saturating_sub(first - 1) as usize;
+        let rooms = self.rooms.lock().unwrap();
+        let events: Vec<Envelope> = match rooms.get(room) {
+            _ if wanted == 0 => Vec::new(),
+            None => return self.miss(),
+            Some(buffered) => buffered.iter().filter(|e| e.seq >= first && e.seq <= current).cloned().collect(),
+        };
+        // seqs are unique and ordered, so a full count means no holes
+        if events.len() != wanted {
+            return self.miss();
+        }
+        self.from_buffer.fetch_add(1, Ordering::Relaxed);
+        Some(Gap { events, complete: first == after + 1 })
+    }
+
+    /// Replaces messages that expired by `now` (unix ms) with tombstones.
+    pub fn expire(&self, now: i64) {
+        for events in self.rooms.lock().unwrap().values_mut() {
+            for envelope in events.iter_mut() {
+                expiry::expire(envelope, now);
+            }
+        }
+    }
+
+    fn miss(&self) -> Option<Gap> {
+        self.from_journal.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE gateway_replay_gaps_total counter\n");
+        for (source, count) in [("buffer", &self.from_buffer), ("journal", &self.from_journal)] {
+            let count = count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "gateway_replay_gaps_total{{source=\"{}\"}} {}", source, count);
+        }
+        out
+    }
+}
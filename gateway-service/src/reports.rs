@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use uchat_proto::events::ServerEvent;
+use uchat_proto::jwt::Claims;
+
+use crate::state::{AppState, RecentMessage, DEFAULT_ROOM};
+
+//
+// ABUSE REPORTS
+//
+// Users flag a message or a user; moderators (GATEWAY_ADMINS or members of
+// the `moderators` group) work the queue: list, claim, resolve. Every
+// transition is written to the audit trail.
+//
+
+/// Group whose members may review reports.
+pub const MODERATOR_GROUP: &str = "moderators";
+
+/// Messages captured on each side of a reported message.
+const CONTEXT_WINDOW: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    Claimed,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub id: u64,
+    pub room: String,
+    pub reporter: String,
+    pub message_id: Option<u64>,
+    pub reported_user: Option<String>,
+    pub reason: String,
+    pub context: Vec<RecentMessage>,
+    pub status: ReportStatus,
+    pub claimed_by: Option<String>,
+    pub resolution: Option<String>,
+    pub note: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Default)]
+pub struct ReportQueue {
+    reports: Mutex<BTreeMap<u64, Report>>,
+    next_id: AtomicU64,
+    per_room: Mutex<HashMap<String, u64>>,
+}
+
+impl ReportQueue {
+    /// Prometheus lines for report volume, one series per room.
+    pub fn render_metrics(&self) -> String {
+        let per_room = self.per_room.lock().unwrap();
+        let mut rooms: Vec<_> = per_room.iter().collect();
+        rooms.sort();
+
+        let mut out = String::from("# TYPE gateway_reports_total counter\n");
+        for (room, count) in rooms {
+            out.push_str(&format!("gateway_reports_total{{room=\"{}\"}} {}\n", room, count));
+        }
+        out
+    }
+}
+
+pub fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+pub fn caller(state: &AppState, headers: &HeaderMap) -> Option<Claims> {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    state.bearer_claims(auth)
+}
+
+/// The caller's username if they may review reports.
+pub fn moderator(state: &AppState, headers: &HeaderMap) -> Result<String, (StatusCode, &'static str)> {
+    let Some(claims) = caller(state, headers) else {
+        return Err((StatusCode::UNAUTHORIZED, "missing or invalid token"));
+    };
+    if state.is_admin(&claims.sub) || claims.groups.iter().any(|g| g == MODERATOR_GROUP) {
+        Ok(claims.sub)
+    } else {
+        Err((StatusCode::FORBIDDEN, "moderators only"))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateReport {
+    #[serde(default = "default_room")]
+    room: String,
+    message_id: Option<u64>,
+    reported_user: Option<String>,
+    reason: String,
+    #[serde(default = "default_true")]
+    include_context: bool,
+}
+
+fn default_room() -> String {
+    DEFAULT_ROOM.into()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// POST /reports
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<CreateReport>,
+) -> Response {
+    let Some(claims) = caller(&state, &headers) else {
+        return error(StatusCode::UNAUTHORIZED, "missing or invalid token");
+    };
+    if body.reason.trim().is_empty() {
+        return error(StatusCode::BAD_REQUEST, "reason is required");
+    }
+    if body.message_id.is_none() && body.reported_user.is_none() {
+        return error(StatusCode::BAD_REQUEST, "message_id or reported_user is required");
+    }
+
+    let mut reported_user = body.reported_user;
+    let mut context = Vec::new();
+
+    {
+        let recent = state.recent.lock().unwrap();
+        let room: Vec<&RecentMessage> = recent.iter().filter(|m| m.room == body.room).collect();
+        if let Some(message_id) = body.message_id {
+            let Some(pos) = room.iter().position(|m| m.id == message_id) else {
+                return error(StatusCode::NOT_FOUND, "message no longer in the room buffer");
+            };
+            reported_user.get_or_insert_with(|| room[pos].from.clone());
+
+            if body.include_context {
+                let start = pos.saturating_sub(CONTEXT_WINDOW);
+                let end = (pos + CONTEXT_WINDOW + 1).min(room.len());
+                context = room[start..end].iter().map(|&m| m.clone()).collect();
+            }
+        } else if body.include_context {
+            let skip = room.len().saturating_sub(CONTEXT_WINDOW * 2);
+            context = room.iter().skip(skip).map(|&m| m.clone()).collect();
+        }
+    }
+
+    let id = state.reports.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let report = Report {
+        id,
+        room: body.room.clone(),
+        reporter: claims.sub.clone(),
+        message_id: body.message_id,
+        reported_user,
+        reason: body.reason,
+        context,
+        status: ReportStatus::Open,
+        claimed_by: None,
+        resolution: None,
+        note: None,
+        created_at: Utc::now().to_rfc3339(),
+    };
+
+    state.audit.record(
+        "report.created",
+        &claims.sub,
+        &format!("report:{}", id),
+        format!("room={} user={}", report.room, report.reported_user.as_deref().unwrap_or("-")),
+    );
+    *state.reports.per_room.lock().unwrap().entry(body.room).or_default() += 1;
+    state.reports.reports.lock().unwrap().insert(id, report.clone());
+
+    (StatusCode::CREATED, Json(report)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    status: Option<ReportStatus>,
+    room: Option<String>,
+}
+
+// GET /reports?status=open&room=lobby
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(q): Query<ListQuery>,
+) -> Response {
+    if let Err((status, details)) = moderator(&state, &headers) {
+        return error(status, details);
+    }
+
+    let reports = state.reports.reports.lock().unwrap();
+    let list: Vec<&Report> = reports
+        .values()
+        .filter(|r| q.status.is_none_or(|s| r.status == s))
+        .filter(|r| q.room.as_ref().is_none_or(|room| &r.room == room))
+        .collect();
+    Json(list).into_response()
+}
+
+// POST /reports/:id/claim
+pub async fn claim(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Response {
+    let moderator = match moderator(&state, &headers) {
+        Ok(m) => m,
+        Err((status, details)) => return error(status, details),
+    };
+
+    let mut reports = state.reports.reports.lock().unwrap();
+    let Some(report) = reports.get_mut(&id) else {
+        return error(StatusCode::NOT_FOUND, "no such report");
+    };
+    match report.status {
+        ReportStatus::Resolved => return error(StatusCode::CONFLICT, "report already resolved"),
+        ReportStatus::Claimed if report.claimed_by.as_deref() != Some(&moderator) => {
+            return error(StatusCode::CONFLICT, "report claimed by another moderator");
+        }
+        _ => {}
+    }
+
+    report.status = ReportStatus::Claimed;
+    report.claimed_by = Some(moderator.clone());
+    state.audit.record("report.claimed", &moderator, &format!("report:{}", id), "");
+    Json(report.clone()).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ResolveBody {
+    resolution: String,
+    note: Option<String>,
+}
+
+// POST /reports/:id/resolve
+pub async fn resolve(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(body): Json<ResolveBody>,
+) -> Response {
+    let moderator = match moderator(&state, &headers) {
+        Ok(m) => m,
+        Err((status, details)) => return error(status, details),
+    };
+
+    let mut reports = state.reports.reports.lock().unwrap();
+    let Some(report) = reports.get_mut(&id) else {
+        return error(StatusCode::NOT_FOUND, "no such report");
+    };
+    if report.status == ReportStatus::Resolved {
+        return error(StatusCode::CONFLICT, "report already resolved");
+    }
+    if report.claimed_by.as_deref().is_some_and(|m| m != moderator) {
+        return error(StatusCode::CONFLICT, "report claimed by another moderator");
+    }
+
+    report.status = ReportStatus::Resolved;
+    report.claimed_by = Some(moderator.clone());
+    report.resolution = Some(body.resolution.clone());
+    report.note = body.note;
+    state.audit.record("report.resolved", &moderator, &format!("report:{}", id), body.resolution);
+    Json(report.clone()).into_response()
+}
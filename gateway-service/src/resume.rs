@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::Message;
+
+use uchat_core::i18n::codes;
+use uchat_proto::events::{Resume, ServerEvent};
+use uchat_proto::jwt::{Scope, ScopeAction};
+
+use crate::connections::TokenRef;
+use crate::expiry::DeliveryPath;
+use crate::state::{AppState, DeliveryFloor, Session};
+
+//
+// SESSION RESUMPTION
+//
+// After Hello a signed-in connection gets a ResumeToken: a signed record of who it
+// is, the rooms it receives and each room's seq at issue time (a new one
+// follows every join and leave; see subscriptions.rs). A new token keeps
+// the previous one's seq for rooms the connection already had, since
+// events published after it may still be on their way to the client when
+// it drops; only newly joined rooms start at their current seq. Resuming
+// needs nothing but the token, so any gateway instance sharing the
+// signing secret and the room journal can honour it, including one
+// started after a deploy.
+//
+// A client resumes by sending the token in its Hello, with the last seq it
+// actually saw per room. The gateway replays the gap from the replay
+// buffer when it holds all of it, otherwise from the journal (see
+// replay.rs), then answers Resumed and a fresh token; live delivery
+// starts only after the replay, so the connection still sees every room
+// in seq order. Disappearing messages that expired in the meantime are
+// replayed as tombstones (see expiry.rs).
+//
+// The token also names the connection it was issued to. If that
+// connection is still open on this instance, typically a phone's socket
+// on the network it just left, resuming closes it (1001, "resumed
+// elsewhere") instead of leaving it to the heartbeat timeout. Being in the
+// signed token, the id cannot be used to close anyone else's connection.
+//
+// Tokens are signed with a key derived from JWT_SECRET, so a resume token
+// can never pass as an access token. It carries the limits of the token
+// the session signed in with: a delegated session resumes with the same
+// scope, only in rooms that scope lets it read, and not once that token
+// is revoked (see revocations.rs). Revocation goes by that token's issue
+// time and id, not the resume token's, which are renewed on every join.
+//
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeClaims {
+    sub: String,
+    /// Room -> seq at issue time; replay never reaches further back.
+    rooms: BTreeMap<String, u64>,
+    /// The connection it was issued to; 0 in tokens from before this.
+    #[serde(default)]
+    conn: u64,
+    iat: usize,
+    exp: usize,
+    /// Scope of the delegated token the session signed in with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scope: Option<Scope>,
+    /// Id of the token the session signed in with, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+    /// Issue time of that token; 0 in tokens from before this, which any
+    /// revocation of the account then covers.
+    #[serde(default)]
+    token_iat: usize,
+}
+
+fn signing_key(secret: &str) -> Vec<u8> {
+    format!("{}:resume", secret).into_bytes()
+}
+
+/// A token resuming `session` from the current position in its rooms.
+pub fn issue(state: &AppState, session: &Session) -> String {
+    let now = Utc::now();
+    let token = state.connections.get(session.id).and_then(|c| c.token);
+    let claims = ResumeClaims {
+        sub: session.username.clone(),
+        rooms: session.resume_floors(|room| state.current_seq(room)),
+        conn: session.id,
+        iat: now.timestamp() as usize,
+        exp: (now + state.config.resume_ttl).timestamp() as usize,
+        scope: session.scope.clone(),
+        jti: token.as_ref().and_then(|t| t.jti.clone()),
+        token_iat: token.map_or(0, |t| t.iat),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(&signing_key(&state.secret))).unwrap()
+}
+
+fn verify(secret: &str, token: &str) -> Option<ResumeClaims> {
+    decode::<ResumeClaims>(token, &DecodingKey::from_secret(&signing_key(secret)), &Validation::new(Algorithm::HS256))
+        .ok()
+        .map(|d| d.claims)
+}
+
+/// Restores the session named by `resume` and replays its gap. Returns the
+/// floor live delivery must start above, or `None` (after telling the
+/// client) if the token is not valid and the session starts fresh.
+pub fn resume(state: &AppState, session: &mut Session, resume: Resume) -> Option<DeliveryFloor> {
+    // a delegated session may only resume itself, and no one past the
+    // revocation of the token they signed in with
+    let claims = verify(&state.secret, &resume.token)
+        .filter(|c| session.scope.is_none() || c.sub == session.username)
+        .filter(|c| !state.revocations.is_revoked(&c.sub, c.token_iat, c.jti.as_deref()));
+    let Some(mut claims) = claims else {
+        session.error(codes::PROTOCOL_RESUME_INVALID, &[]);
+        return None;
+    };
+    // a scope of this connection's own is kept, else the resumed one
+    // applies; restricted rooms go by the groups of this connection's token
+    session.scope = session.scope.take().or(claims.scope.take());
+    claims.rooms.retain(|room, _| session.may(room, ScopeAction::Read) && state.rooms.may_join(&session.groups, room));
+
+    let stale = claims.conn != session.id
+        && state.connections.get(claims.conn).is_some_and(|c| c.username.as_ref() == Some(&claims.sub));
+    if stale && state.connections.close(claims.conn, CloseCode::Away, "resumed elsewhere") {
+        println!("GATEWAY: {} resumed, closing their previous connection {}", claims.sub, claims.conn);
+    }
+
+    session.set_username(claims.sub);
+    session.set_rooms(claims.rooms.keys().cloned().collect());
+    state.connections.set_username(session.id, &session.username);
+    // the connection now stands on the token the session signed in with
+    let token = TokenRef { iat: claims.token_iat, jti: claims.jti.clone() };
+    state.connections.update(session.id, |c| c.token = Some(token));
+    state.connections.sync_rooms(session);
+    state.census.logged_in(state, session.id);
+    state.presence.logged_in(state, session.id);
+
+    let profile = state.config.profiles.get(session.class);
+    let mut floor = HashMap::new();
+    let mut resumed_from = BTreeMap::new();
+    let mut replayed = 0;
+    let mut complete = true;
+    for (room, issued_at) in claims.rooms {
+        // the client may have seen more than the token records, never less
+        let after = resume.last_seq.get(&room).copied().unwrap_or(issued_at).max(issued_at);
+        resumed_from.insert(room.clone(), after);
+        let current = state.current_seq(&room);
+
+        let limit = profile.resume_max_events;
+        let gap = match state.replay.since(&room, after, current, limit) {
+            Some(gap) => gap,
+            None => state.journal.since(&room, after, current, limit),
+        };
+        complete &= gap.complete;
+        for envelope in gap.events {
+            if !session.may(&room, ScopeAction::Read) || !state.mutes.allows(&session.username, &envelope) {
+                continue;
+            }
+            // the buffer and journal are only scrubbed every few seconds
+            let envelope = state.expiry.deliver(envelope, DeliveryPath::Resume);
+            let json = serde_json::to_string(&envelope).unwrap();
+            if profile.fits(&json) {
+                let _ = session.out.send(Message::Text(json));
+                replayed += 1;
+            }
+        }
+        floor.insert(room, current);
+    }
+
+    println!("GATEWAY: {} resumed, {} events replayed", session.username, replayed);
+    session.reply(&ServerEvent::Resumed { username: session.username.clone(), replayed, complete });
+    // what was just replayed may be in flight too
+    session.set_resume_floors(resumed_from);
+    session.reply(&ServerEvent::ResumeToken { token: issue(state, session) });
+    state.devices.deliver_pending(state, &session.username);
+    Some(floor)
+}
@@ -0,0 +1,259 @@
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::Message;
+
+use uchat_core::i18n::codes;
+use uchat_proto::api::{Revocation, RevocationFeed};
+use uchat_proto::jwt::{Claims, RevocationList};
+
+use crate::state::{localized_error, AppState};
+
+//
+// TOKEN REVOCATION
+//
+// auth-api refuses a revoked token at once, but a WebSocket opened with it
+// would stay open until the client left. The gateway keeps a revocation
+// list of its own, fed from auth-api's revocation feed (GET /revocations,
+// see auth-api's revocations.rs). A token on it is refused wherever the
+// gateway checks one: on the upgrade (401), by the HTTP APIs and in
+// RefreshToken (see refresh.rs).
+//
+// The revocation-sync job polls the feed, then closes every open connection
+// whose token was revoked since: the client is told auth.token_revoked and
+// the connection is closed with 1008 ("token revoked"); a long-poll
+// session is ended, as if it had expired. An entry revokes every token of
+// a user issued before a time (the account was revoked), or one token by
+// its jti (a delegated token or a web session).
+//
+// With the Redis backplane configured (see backplane.rs), entries are also
+// kept in Redis, in the hashes <prefix>:revoked:users and
+// <prefix>:revoked:tokens, so every instance learns them even when its own
+// poll fails, and they outlive restarts of the gateway and of auth-api.
+//
+//   GATEWAY_REVOCATIONS_URL            auth-api's feed, e.g.
+//                                      http://auth-api:9200/revocations;
+//                                      unset: revocations are not followed
+//   GATEWAY_REVOCATIONS_TOKEN          auth-api's REVOCATIONS_FEED_TOKEN
+//   GATEWAY_REVOCATIONS_INTERVAL_SECS  how often to poll (default 15)
+//
+//   gateway_revocations{kind="user|token"}  entries held
+//   gateway_revocation_sync_failures_total
+//   gateway_revoked_connections_total
+//
+
+const DEFAULT_INTERVAL_SECS: u64 = 15;
+const FEED_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Feed {
+    url: String,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+pub struct Revocations {
+    feed: Option<Feed>,
+    pub interval: Duration,
+    list: Mutex<RevocationList>,
+    #[cfg(feature = "redis")]
+    redis: Option<mirror::Mirror>,
+    failures: AtomicU64,
+    closed: AtomicU64,
+}
+
+impl Revocations {
+    pub fn from_env() -> Self {
+        let url = std::env::var("GATEWAY_REVOCATIONS_URL").ok().filter(|u| !u.trim().is_empty());
+        let token = std::env::var("GATEWAY_REVOCATIONS_TOKEN").ok().filter(|t| !t.is_empty());
+        let secs = std::env::var("GATEWAY_REVOCATIONS_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_INTERVAL_SECS);
+        Self::new(url, token, Duration::from_secs(secs.max(1)))
+    }
+
+    pub fn new(url: Option<String>, token: Option<String>, interval: Duration) -> Self {
+        if let Some(url) = &url {
+            println!("GATEWAY: following token revocations from {}", url);
+        }
+        Self {
+            feed: url.map(|url| Feed { url, token, http: reqwest::Client::new() }),
+            interval,
+            list: Mutex::new(RevocationList::default()),
+            #[cfg(feature = "redis")]
+            redis: mirror::Mirror::from_env(),
+            failures: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a token of `sub` issued at `iat`, with id `jti`, is revoked.
+    pub fn is_revoked(&self, sub: &str, iat: usize, jti: Option<&str>) -> bool {
+        self.list.lock().unwrap().is_revoked(sub, iat, jti)
+    }
+
+    pub fn revokes(&self, claims: &Claims) -> bool {
+        self.is_revoked(&claims.sub, claims.iat, claims.jti.as_deref())
+    }
+
+    /// Adds entries, e.g. from the feed.
+    pub fn apply(&self, revocations: &[Revocation]) {
+        let mut list = self.list.lock().unwrap();
+        for revocation in revocations {
+            list.apply(revocation);
+        }
+    }
+
+    /// Reads the feed past the last entry applied.
+    async fn poll(&self) -> anyhow::Result<Vec<Revocation>> {
+        let Some(feed) = &self.feed else { return Ok(Vec::new()) };
+        let after = self.list.lock().unwrap().cursor;
+        let mut req = feed.http.get(&feed.url).query(&[("after", after)]).timeout(FEED_TIMEOUT);
+        if let Some(token) = &feed.token {
+            req = req.bearer_auth(token);
+        }
+        let page: RevocationFeed = req.send().await?.error_for_status()?.json().await?;
+        Ok(page.revocations)
+    }
+
+    /// Polls the feed and exchanges entries with Redis, then forgets
+    /// revoked tokens that have expired anyway.
+    pub async fn sync(&self) {
+        let fresh = match self.poll().await {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                println!("GATEWAY: cannot read token revocations: {}", e);
+                Vec::new()
+            }
+        };
+        self.apply(&fresh);
+
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            match redis.exchange(&fresh).await {
+                Ok((users, tokens)) => {
+                    let mut list = self.list.lock().unwrap();
+                    for (sub, before) in users {
+                        list.apply(&Revocation::User { id: 0, sub, before });
+                    }
+                    for (jti, exp) in tokens {
+                        list.apply(&Revocation::Token { id: 0, jti, exp });
+                    }
+                }
+                Err(e) => {
+                    self.failures.fetch_add(1, Ordering::Relaxed);
+                    println!("GATEWAY: cannot share token revocations through Redis: {}", e);
+                }
+            }
+        }
+
+        let now = Utc::now().timestamp().max(0) as usize;
+        self.list.lock().unwrap().forget_expired(now);
+    }
+
+    /// Closes every connection whose token is revoked; returns how many.
+    pub fn sweep(&self, state: &AppState) -> usize {
+        let Some(conns) = state.connections.snapshot(true) else { return 0 };
+        let mut closed = 0;
+        for conn in conns {
+            let (Some(user), Some(token)) = (&conn.username, &conn.token) else { continue };
+            if !self.is_revoked(user, token.iat, token.jti.as_deref()) {
+                continue;
+            }
+            let notice = localized_error(conn.locale, codes::AUTH_TOKEN_REVOKED, &[]);
+            state.connections.send_raw(conn.id, Message::Text(serde_json::to_string(&notice).unwrap()));
+            let ended = match conn.transport {
+                "poll" => state.poll_sessions.close(state, conn.id),
+                _ => state.connections.reap(conn.id, CloseCode::Policy, "token revoked"),
+            };
+            if ended {
+                println!("GATEWAY: connection {} ({}) had its token revoked, closing it", conn.id, user);
+                closed += 1;
+            }
+        }
+        self.closed.fetch_add(closed as u64, Ordering::Relaxed);
+        closed
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let (users, tokens) = self.list.lock().unwrap().counts();
+        let mut out = String::from("# TYPE gateway_revocations gauge\n");
+        let _ = writeln!(out, "gateway_revocations{{kind=\"user\"}} {}", users);
+        let _ = writeln!(out, "gateway_revocations{{kind=\"token\"}} {}", tokens);
+        out.push_str("# TYPE gateway_revocation_sync_failures_total counter\n");
+        let _ = writeln!(out, "gateway_revocation_sync_failures_total {}", self.failures.load(Ordering::Relaxed));
+        out.push_str("# TYPE gateway_revoked_connections_total counter\n");
+        let _ = writeln!(out, "gateway_revoked_connections_total {}", self.closed.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// The revocation-sync job.
+pub async fn sync(state: Arc<AppState>) -> anyhow::Result<()> {
+    state.revocations.sync().await;
+    state.revocations.sweep(&state);
+    Ok(())
+}
+
+#[cfg(feature = "redis")]
+mod mirror {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use redis::AsyncCommands;
+
+    use uchat_proto::api::Revocation;
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// The revocation list shared through the backplane's Redis.
+    pub struct Mirror {
+        client: redis::Client,
+        users: String,
+        tokens: String,
+    }
+
+    impl Mirror {
+        pub fn from_env() -> Option<Self> {
+            let url = std::env::var("GATEWAY_BACKPLANE_URL").ok().filter(|u| !u.is_empty())?;
+            let prefix = std::env::var("GATEWAY_BACKPLANE_PREFIX").unwrap_or_else(|_| "uchat".into());
+            let client = redis::Client::open(url).ok()?;
+            let (users, tokens) = (format!("{}:revoked:users", prefix), format!("{}:revoked:tokens", prefix));
+            Some(Self { client, users, tokens })
+        }
+
+        /// Stores `fresh` and returns every entry held, users and tokens.
+        pub async fn exchange(
+            &self,
+            fresh: &[Revocation],
+        ) -> redis::RedisResult<(HashMap<String, usize>, HashMap<String, usize>)> {
+            let exchanged = async {
+                let mut conn = self.client.get_async_connection().await?;
+                for revocation in fresh {
+                    let (key, field, value) = match revocation {
+                        Revocation::User { sub, before, .. } => (&self.users, sub, before),
+                        Revocation::Token { jti, exp, .. } => (&self.tokens, jti, exp),
+                    };
+                    conn.hset::<_, _, _, ()>(key, field, value).await?;
+                }
+                let users: HashMap<String, usize> = conn.hgetall(&self.users).await?;
+                let tokens: HashMap<String, usize> = conn.hgetall(&self.tokens).await?;
+                let now = chrono::Utc::now().timestamp().max(0) as usize;
+                let expired: Vec<&String> = tokens.iter().filter(|(_, exp)| **exp <= now).map(|(jti, _)| jti).collect();
+                if !expired.is_empty() {
+                    conn.hdel::<_, _, ()>(&self.tokens, expired).await?;
+                }
+                Ok((users, tokens))
+            };
+            match tokio::time::timeout(TIMEOUT, exchanged).await {
+                Ok(result) => result,
+                Err(_) => Err((redis::ErrorKind::IoError, "timed out").into()),
+            }
+        }
+    }
+}
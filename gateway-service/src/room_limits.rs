@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use uchat_core::ratelimit::{KeyedLimiter, Limit, LimiterInfo};
+
+use crate::config::parse_rate_limit;
+
+//
+// PER-ROOM RATE LIMITS
+//
+// GATEWAY_RATE_LIMITS caps what each connection sends; these cap what a
+// whole room takes in, so one flooded room cannot fill the broadcast
+// channel every room shares. Every post counts: messages, room command
+// replies and media, whoever sends them, except admins'.
+//
+//   GATEWAY_ROOM_RATE_LIMIT   posts per room, as in GATEWAY_RATE_LIMITS
+//                             (default 100/1s); "off" disables
+//   GATEWAY_ROOM_RATE_LIMITS  per-room overrides, "lobby=500/1s,news=off"
+//
+// A refused post is answered with room.rate_limited and not published.
+//
+//   gateway_room_rate_limited_total{room="..."}
+//
+
+const DEFAULT_LIMIT: Limit = Limit::new(100, Duration::from_secs(1));
+
+pub struct RoomRateLimits {
+    /// For rooms without an override, keyed by room.
+    default: Option<KeyedLimiter<String>>,
+    /// A room's own limit; None turns it off.
+    overrides: HashMap<String, Option<KeyedLimiter<String>>>,
+    refused: Mutex<HashMap<String, u64>>,
+}
+
+impl RoomRateLimits {
+    pub fn from_env() -> Self {
+        let default = match std::env::var("GATEWAY_ROOM_RATE_LIMIT").as_deref() {
+            Ok("off") => None,
+            Ok(_) => Some(Limit::from_env("GATEWAY_ROOM_RATE_LIMIT", DEFAULT_LIMIT)),
+            Err(_) => Some(DEFAULT_LIMIT),
+        };
+        let overrides = std::env::var("GATEWAY_ROOM_RATE_LIMITS").unwrap_or_default();
+        let overrides: Vec<_> = overrides
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .filter_map(|entry| {
+                let limit = parse_rate_limit(entry);
+                if limit.is_none() {
+                    println!("GATEWAY: ignoring malformed room rate limit {:?}", entry);
+                }
+                limit
+            })
+            .collect();
+        Self::new(default, &overrides)
+    }
+
+    pub fn new(default: Option<Limit>, overrides: &[(String, Option<Limit>)]) -> Self {
+        Self {
+            default: default.map(|limit| KeyedLimiter::new("room", limit)),
+            overrides: overrides
+                .iter()
+                .map(|(room, limit)| {
+                    (room.clone(), limit.map(|limit| KeyedLimiter::new(format!("room@{}", room), limit)))
+                })
+                .collect(),
+            refused: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limiter(&self, room: &str) -> Option<&KeyedLimiter<String>> {
+        match self.overrides.get(room) {
+            Some(limiter) => limiter.as_ref(),
+            None => self.default.as_ref(),
+        }
+    }
+
+    fn limiters(&self) -> impl Iterator<Item = &KeyedLimiter<String>> {
+        self.default.iter().chain(self.overrides.values().flatten())
+    }
+
+    /// Takes one post from `room`'s quota; false (and counted) if it has
+    /// none left.
+    pub fn check(&self, room: &str) -> bool {
+        let Some(limiter) = self.limiter(room) else { return true };
+        if limiter.check(&room.to_string()).is_ok() {
+            return true;
+        }
+        *self.refused.lock().unwrap().entry(room.to_string()).or_default() += 1;
+        false
+    }
+
+    /// Forgets rooms whose quota has fully recovered.
+    pub fn evict(&self) {
+        for limiter in self.limiters() {
+            limiter.retain_recent();
+        }
+    }
+
+    /// Size of each limiter, for /debug/state.
+    pub fn limiter_info(&self) -> Vec<LimiterInfo> {
+        self.limiters().map(|l| l.info()).collect()
+    }
+
+    /// Prometheus lines for refused posts, one series per room that had any.
+    pub fn render_metrics(&self) -> String {
+        let refused = self.refused.lock().unwrap();
+        let mut rooms: Vec<_> = refused.iter().collect();
+        rooms.sort();
+
+        let mut out = String::from("# TYPE gateway_room_rate_limited_total counter\n");
+        for (room, count) in rooms {
+            let _ = writeln!(out, "gateway_room_rate_limited_total{{room=\"{}\"}} {}", room, count);
+        }
+        out
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{HashMap, HashSet};
+
+use uchat_proto::acl::{RoomAcl, RoomKind};
+
+//
+// ROOM POSTING POLICY
+//
+// The gateway side of chat-service's room kinds: in an announcement room
+// only admins (GATEWAY_ADMINS, who moderate here) and bots (GATEWAY_BOTS)
+// may post. Kinds come from GATEWAY_ROOM_KINDS="news=announcement,..."; an
+// unlisted room is public.
+//
+// Joining a room restricted by ROOM_ACL (the same variable chat-service
+// reads) needs one of its groups in the connection's token. Private rooms
+// created through chat-service's API are only enforced there.
+//
+// Rooms listed in GATEWAY_E2EE_ROOMS="board,legal" are e2ee_required: a
+// SendMessage there without encryption metadata is refused with
+// message.e2ee_required, and so is anything the gateway would post there
+// in plaintext on a client's behalf (media shares, slash command replies).
+// Joining such a room says so in RoomJoined, so clients know to encrypt
+// before their first message bounces. The gateway cannot check that
+// content is really ciphertext; the flag keeps well-behaved clients from
+// leaking plaintext by mistake.
+//
+
+pub struct RoomPolicy {
+    kinds: HashMap<String, RoomKind>,
+    e2ee: HashSet<String>,
+    bots: Vec<String>,
+    acl: RoomAcl,
+}
+
+fn list(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl RoomPolicy {
+    pub fn from_env() -> Self {
+        let mut kinds = HashMap::new();
+        for entry in list("GATEWAY_ROOM_KINDS") {
+            match entry.split_once('=').and_then(|(room, kind)| Some((room, RoomKind::parse(kind)?))) {
+                Some((room, kind)) => {
+                    kinds.insert(room.to_string(), kind);
+                }
+                None => println!("GATEWAY: ignoring malformed room kind {:?}", entry),
+            }
+        }
+        let e2ee = list("GATEWAY_E2EE_ROOMS").into_iter().collect();
+        Self { kinds, e2ee, bots: list("GATEWAY_BOTS"), acl: RoomAcl::from_env() }
+    }
+
+    pub fn kind(&self, room: &str) -> RoomKind {
+        self.kinds.get(room).copied().unwrap_or_default()
+    }
+
+    /// Whether messages in `room` must be end-to-end encrypted.
+    pub fn e2ee_required(&self, room: &str) -> bool {
+        self.e2ee.contains(room)
+    }
+
+    /// Whether `user` may post in `room`; `is_admin` comes from AppState.
+    pub fn may_post(&self, user: &str, is_admin: bool, room: &str) -> bool {
+        match self.kind(room) {
+            RoomKind::Announcement => is_admin || self.bots.iter().any(|b| b == user),
+            RoomKind::Public | RoomKind::Private => true,
+        }
+    }
+
+    /// Whether a connection whose token carries `groups` may join `room`.
+    pub fn may_join(&self, groups: &[String], room: &str) -> bool {
+        self.acl.allows(room, groups)
+    }
+}
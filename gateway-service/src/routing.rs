@@ -0,0 +1,123 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use uchat_proto::events::InstanceInfo;
+
+use crate::reports::error;
+use crate::state::AppState;
+
+//
+// INSTANCE IDENTITY AND ROUTING HINTS
+//
+// Each gateway names itself in Welcome, in the `origin` of the room
+// envelopes it publishes, in /debug/state, and in an X-Gateway-Instance
+// header on every HTTP response and WebSocket upgrade:
+//
+//   GATEWAY_INSTANCE_ID       default $HOSTNAME, else a random "gw-..." id
+//   GATEWAY_REGION            optional
+//   GATEWAY_AFFINITY_BUCKETS  enables room affinity hints (e.g. 64)
+//
+// With affinity on, a room maps to one of N buckets by jump consistent
+// hash, the same on every instance, and changing N moves as few rooms as
+// possible. Clients get the bucket from GET /routing/affinity?room=...,
+// or in X-Uchat-Affinity on an upgrade to /ws?room=..., and send it back
+// as an X-Uchat-Affinity request header. The load balancer hashes on that
+// header (HAProxy `balance hdr(X-Uchat-Affinity)`, nginx `hash
+// $http_x_uchat_affinity consistent`), so one room's clients share an
+// instance.
+//
+
+pub const INSTANCE_HEADER: &str = "x-gateway-instance";
+pub const AFFINITY_HEADER: &str = "x-uchat-affinity";
+
+pub fn instance_from_env() -> InstanceInfo {
+    let instance_id = std::env::var("GATEWAY_INSTANCE_ID")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .filter(|id| !id.trim().is_empty())
+        .unwrap_or_else(|| format!("gw-{}", &uuid::Uuid::new_v4().simple().to_string()[..8]));
+    let region = std::env::var("GATEWAY_REGION").ok().filter(|r| !r.trim().is_empty());
+    InstanceInfo { instance_id, region }
+}
+
+/// Lamping & Veach's jump consistent hash.
+fn jump_hash(mut key: u64, buckets: u32) -> u32 {
+    let (mut b, mut j) = (-1i64, 0i64);
+    while j < i64::from(buckets) {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as u32
+}
+
+/// The affinity bucket for `room`, when hints are on.
+pub fn affinity(state: &AppState, room: &str) -> Option<u32> {
+    let buckets = state.config.affinity_buckets?;
+    let digest = Sha256::digest(room.as_bytes());
+    let key = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    Some(jump_hash(key, buckets))
+}
+
+/// Router middleware adding X-Gateway-Instance.
+pub async fn instance_header(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let mut resp = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&state.config.instance.instance_id) {
+        resp.headers_mut().insert(HeaderName::from_static(INSTANCE_HEADER), value);
+    }
+    resp
+}
+
+#[derive(Deserialize)]
+pub struct AffinityQuery {
+    room: String,
+}
+
+#[derive(Serialize)]
+pub struct Affinity {
+    room: String,
+    bucket: u32,
+    buckets: u32,
+    header: &'static str,
+}
+
+// GET /routing/affinity?room=lobby
+pub async fn get_affinity(State(state): State<Arc<AppState>>, Query(q): Query<AffinityQuery>) -> Response {
+    match (affinity(&state, &q.room), state.config.affinity_buckets) {
+        (Some(bucket), Some(buckets)) => {
+            Json(Affinity { room: q.room, bucket, buckets, header: "X-Uchat-Affinity" }).into_response()
+        }
+        _ => error(StatusCode::NOT_FOUND, "room affinity hints are off"),
+    }
+}
+
+/// The `room` query parameter of a WebSocket upgrade URI.
+pub fn upgrade_room(query: Option<&str>) -> Option<String> {
+    let query = query?;
+    url_decode(query.split('&').find_map(|pair| pair.strip_prefix("room="))?)
+}
+
+fn url_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
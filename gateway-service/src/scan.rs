@@ -0,0 +1,207 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+//
+// UPLOAD SCANNING
+//
+// Uploaded files can be handed to a malware scanner before they are
+// stored:
+//
+//   GATEWAY_UPLOAD_SCANNER            clamd://host:port (clamd over TCP),
+//                                     clamd:///path/to/clamd.ctl (Unix socket),
+//                                     or an http(s):// scanning service;
+//                                     unset disables scanning
+//   GATEWAY_UPLOAD_SCAN_FAIL_OPEN     "1" to store uploads unscanned while the
+//                                     scanner is down (default: refuse them)
+//   GATEWAY_UPLOAD_SCAN_TIMEOUT_SECS  per file (default 30)
+//
+// An HTTP scanner gets the file as the POST body and answers
+// {"clean": true} or {"clean": false, "signature": "..."}.
+//
+// Flagged files are kept under quarantine/ in storage, which /media never
+// serves, and leave an "upload.quarantined" audit event (see media.rs).
+//
+
+/// clamd INSTREAM chunk size.
+const CHUNK: usize = 64 * 1024;
+
+pub enum Verdict {
+    Clean,
+    /// Flagged, with the scanner's signature name.
+    Infected(String),
+}
+
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    async fn scan(&self, bytes: &[u8]) -> Result<Verdict>;
+}
+
+/// ClamAV's daemon, via the INSTREAM command.
+pub struct Clamd {
+    target: String,
+}
+
+impl Clamd {
+    async fn instream<S: AsyncRead + AsyncWrite + Unpin>(mut conn: S, bytes: &[u8]) -> Result<Verdict> {
+        conn.write_all(b"zINSTREAM\0").await?;
+        for chunk in bytes.chunks(CHUNK) {
+            conn.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            conn.write_all(chunk).await?;
+        }
+        conn.write_all(&[0; 4]).await?;
+
+        // the "z" prefix makes clamd end its reply with a NUL
+        let mut reply = Vec::new();
+        BufReader::new(conn).read_until(0, &mut reply).await?;
+        let reply = String::from_utf8_lossy(&reply);
+        let reply = reply.trim_end_matches(['\0', '\n']).trim();
+        // "stream: OK", "stream: Eicar-Signature FOUND" or "... ERROR"
+        let result = reply.strip_prefix("stream:").map(str::trim).unwrap_or(reply);
+        if result == "OK" {
+            Ok(Verdict::Clean)
+        } else if let Some(signature) = result.strip_suffix("FOUND") {
+            Ok(Verdict::Infected(signature.trim().to_string()))
+        } else {
+            bail!("clamd: {}", reply)
+        }
+    }
+}
+
+#[async_trait]
+impl Scanner for Clamd {
+    async fn scan(&self, bytes: &[u8]) -> Result<Verdict> {
+        if self.target.starts_with('/') {
+            #[cfg(unix)]
+            return Self::instream(tokio::net::UnixStream::connect(&self.target).await?, bytes).await;
+            #[cfg(not(unix))]
+            bail!("clamd Unix sockets are not supported on this platform");
+        }
+        Self::instream(tokio::net::TcpStream::connect(&self.target).await?, bytes).await
+    }
+}
+
+/// A scanning service reached over HTTP.
+pub struct HttpScanner {
+    url: String,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct HttpVerdict {
+    clean: bool,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+#[async_trait]
+impl Scanner for HttpScanner {
+    async fn scan(&self, bytes: &[u8]) -> Result<Verdict> {
+        let resp = self
+            .http
+            .post(&self.url)
+            .header("content-type", "application/octet-stream")
+            .body(bytes.to_vec())
+            .send()
+            .await?
+            .error_for_status()?;
+        let verdict: HttpVerdict = resp.json().await?;
+        Ok(match verdict.clean {
+            true => Verdict::Clean,
+            false => Verdict::Infected(verdict.signature.unwrap_or_else(|| "unknown".into())),
+        })
+    }
+}
+
+pub enum Outcome {
+    Clean,
+    Infected(String),
+    /// The scanner failed and uploads fail closed.
+    Unavailable,
+    /// No scanner configured, or it failed and uploads fail open.
+    Unscanned,
+}
+
+#[derive(Default)]
+struct Counters {
+    clean: AtomicU64,
+    infected: AtomicU64,
+    failed: AtomicU64,
+}
+
+pub struct UploadScanning {
+    scanner: Option<Box<dyn Scanner>>,
+    fail_open: bool,
+    timeout: Duration,
+    counters: Counters,
+}
+
+impl UploadScanning {
+    pub fn from_env() -> Self {
+        let scanner: Option<Box<dyn Scanner>> = match std::env::var("GATEWAY_UPLOAD_SCANNER") {
+            Ok(spec) if spec.starts_with("clamd://") => {
+                Some(Box::new(Clamd { target: spec.trim_start_matches("clamd://").to_string() }))
+            }
+            Ok(spec) if spec.starts_with("http://") || spec.starts_with("https://") => {
+                Some(Box::new(HttpScanner { url: spec, http: reqwest::Client::new() }))
+            }
+            Ok(spec) if !spec.is_empty() => {
+                println!("GATEWAY: ignoring unknown GATEWAY_UPLOAD_SCANNER {:?}", spec);
+                None
+            }
+            _ => None,
+        };
+        Self {
+            scanner,
+            fail_open: std::env::var("GATEWAY_UPLOAD_SCAN_FAIL_OPEN").is_ok_and(|v| v == "1"),
+            timeout: Duration::from_secs(
+                std::env::var("GATEWAY_UPLOAD_SCAN_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30),
+            ),
+            counters: Counters::default(),
+        }
+    }
+
+    pub async fn check(&self, bytes: &[u8]) -> Outcome {
+        let Some(scanner) = &self.scanner else { return Outcome::Unscanned };
+        let error = match tokio::time::timeout(self.timeout, scanner.scan(bytes)).await {
+            Ok(Ok(Verdict::Clean)) => {
+                self.counters.clean.fetch_add(1, Ordering::Relaxed);
+                return Outcome::Clean;
+            }
+            Ok(Ok(Verdict::Infected(signature))) => {
+                self.counters.infected.fetch_add(1, Ordering::Relaxed);
+                return Outcome::Infected(signature);
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("no verdict within {}s", self.timeout.as_secs()),
+        };
+        self.counters.failed.fetch_add(1, Ordering::Relaxed);
+        match self.fail_open {
+            true => {
+                println!("GATEWAY: upload scan failed, storing unscanned: {}", error);
+                Outcome::Unscanned
+            }
+            false => {
+                println!("GATEWAY: upload scan failed, refusing upload: {}", error);
+                Outcome::Unavailable
+            }
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let c = &self.counters;
+        format!(
+            "# TYPE gateway_upload_scans_total counter\n\
+             gateway_upload_scans_total{{result=\"clean\"}} {}\n\
+             gateway_upload_scans_total{{result=\"infected\"}} {}\n\
+             gateway_upload_scans_total{{result=\"failed\"}} {}\n",
+            c.clean.load(Ordering::Relaxed),
+            c.infected.load(Ordering::Relaxed),
+            c.failed.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,540 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::error::CapacityError;
+use tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use futures_util::{SinkExt, StreamExt};
+use tungstenite::protocol::Message;
+
+use axum::{
+    routing::{delete, get, post, put},
+    Router,
+    extract::State,
+    http::{header, HeaderMap},
+    middleware,
+    response::IntoResponse,
+};
+
+use anyhow::Result;
+
+use uchat_core::i18n::{self, codes};
+use uchat_core::jobs::Job;
+use uchat_proto::jwt::ScopeAction;
+
+use crate::coalesce::Coalescer;
+use crate::connections::ProtocolState;
+use crate::encoding::{Encoding, CBOR_SUBPROTOCOL};
+use crate::expiry::DeliveryPath;
+use crate::profiles::ClientClass;
+use crate::slow_consumers::{Lag, LagTracker};
+use crate::state::{negotiate_locale, AppState, Session};
+use crate::supervisor::{supervise, Cleanup};
+use crate::{
+    admin, census, client_config, compression, debug, devices, drain, freeze, handlers, http_metrics, longpoll, media,
+    moderation, mutes, pipeline, policy, presence, reports, revocations, routing,
+    subscriptions,
+};
+
+//
+// BACKGROUND JOBS
+//
+pub fn spawn_jobs(state: &Arc<AppState>) {
+    state.jobs.spawn(Job::every("poll-session-expiry", Duration::from_secs(10)), {
+        let state = state.clone();
+        move || longpoll::expire_sessions(state.clone())
+    });
+    state.jobs.spawn(Job::every("device-command-expiry", Duration::from_secs(30)), {
+        let state = state.clone();
+        move || devices::expire_commands(state.clone())
+    });
+    state.jobs.spawn(Job::every("journal-prune", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                let cutoff = chrono::Utc::now() - state.config.journal_retention;
+                state.journal.prune(cutoff);
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("message-expiry", Duration::from_secs(5)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.expiry.sweep(&state);
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("signed-nonce-expiry", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.signed.expire();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("moderation-policy-reload", state.config.moderation_reload), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.moderation.reload()?;
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("room-freeze-reload", state.config.moderation_reload), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.freezes.reload()?;
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("census-heartbeat", state.census.heartbeat), {
+        let state = state.clone();
+        move || census::heartbeat(state.clone())
+    });
+    state.jobs.spawn(Job::every("rate-limit-evict", Duration::from_secs(60)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.handlers.evict_rate_limits();
+                state.room_limits.evict();
+                state.commands.evict_rate_limits();
+                state.policy.evict();
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("idle-reaper", state.idle.interval()), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.idle.sweep(&state);
+                Ok(())
+            }
+        }
+    });
+    state.jobs.spawn(Job::every("revocation-sync", state.revocations.interval), {
+        let state = state.clone();
+        move || revocations::sync(state.clone())
+    });
+    state.jobs.spawn(Job::every("ack-expiry", Duration::from_secs(30)), {
+        let state = state.clone();
+        move || {
+            let state = state.clone();
+            async move {
+                state.acks.expire();
+                Ok(())
+            }
+        }
+    });
+    #[cfg(feature = "redis")]
+    tokio::spawn(crate::backplane::run(state.clone()));
+}
+
+//
+// HTTP ROUTES
+//
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/upload", post(media::upload))
+        .route("/media/*key", get(media::serve))
+        .route("/metrics", get(metrics_handler))
+        .route("/ready", get(drain::ready))
+        .route("/admin/drain", post(drain::start).get(drain::status).delete(drain::cancel))
+        .route("/admin/connections", get(admin::connections))
+        .route("/admin/connections/:id/kick", post(admin::kick))
+        .route("/admin/rooms", get(admin::rooms))
+        .route("/admin/policy/reload", post(policy::reload))
+        .route("/debug/state", get(debug::state_dump))
+        .route("/debug/pipeline", get(pipeline::samples_handler))
+        .route("/routing/affinity", get(routing::get_affinity))
+        .route("/poll/connect", post(longpoll::connect))
+        .route("/poll/send", post(longpoll::send))
+        .route("/poll/events", get(longpoll::events))
+        .route("/users/:user/mutes", get(mutes::get_list).put(mutes::put_list))
+        .route("/reports", post(reports::create).get(reports::list))
+        .route("/reports/:id/claim", post(reports::claim))
+        .route("/reports/:id/resolve", post(reports::resolve))
+        .route("/moderation/policies", get(moderation::list))
+        .route(
+            "/rooms/:room/moderation",
+            get(moderation::get_policy).put(moderation::put_policy).delete(moderation::delete_policy),
+        )
+        .route("/rooms/:room/moderation/history", get(moderation::history))
+        .route("/devices/:device/commands", post(devices::enqueue).get(devices::list))
+        .route("/devices/:device/commands/:id", delete(devices::cancel))
+        .route("/client-config", get(client_config::list))
+        .route("/client-config/status", get(client_config::status))
+        .route("/client-config/:target", put(client_config::put_layer).delete(client_config::delete_layer))
+        .route("/census/instances", get(census::instances))
+        .route("/census/users/:user", get(census::locate))
+        .route("/census/users/:user/events", post(census::deliver))
+        .route("/rooms/:room", get(subscriptions::room_info))
+        .route("/rooms/:room/presence", get(presence::room_presence))
+        .route("/rooms/:room/freeze", put(freeze::freeze).delete(freeze::thaw))
+        .layer(middleware::from_fn_with_state(state.clone(), routing::instance_header))
+        .layer(middleware::from_fn_with_state(state.clone(), http_metrics::track))
+        .with_state(state)
+}
+
+//
+// WS HANDLER
+//
+/// Accepts WebSocket connections until the listener fails.
+pub async fn serve_ws(listener: TcpListener, state: Arc<AppState>) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        if let Err(e) = state.config.socket.apply(&stream) {
+            println!("GATEWAY: could not set socket options: {}", e);
+        }
+        let state = state.clone();
+
+        tokio::spawn(supervise(state.clone(), "ws", async move {
+            let _ = handle_ws(stream, state).await;
+        }));
+    }
+}
+
+// the handshake callback's error type is fixed by tungstenite
+#[allow(clippy::result_large_err)]
+async fn handle_ws(stream: TcpStream, state: Arc<AppState>) -> Result<()> {
+    // Accept-Language on the upgrade request picks the initial locale; a
+    // Hello event can change it later
+    let mut locale = i18n::DEFAULT_LOCALE;
+    let mut claims = None;
+    let mut subprotocol_class = None;
+    let mut encoding = Encoding::Json;
+    let mut deflater = None;
+    // the class, and with it the real limit, is only known after the
+    // handshake; see the check on each message below
+    let largest = state.config.profiles.largest_message();
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(largest),
+        max_frame_size: Some(largest),
+        ..Default::default()
+    };
+    let ws = accept_hdr_async_with_config(stream, |req: &Request, mut resp: Response| {
+        if state.drain.is_draining() {
+            let alternates = state.drain.refuse(&state);
+            let mut refused = ErrorResponse::new(Some("draining; reconnect to another instance".into()));
+            *refused.status_mut() = tungstenite::http::StatusCode::SERVICE_UNAVAILABLE;
+            refused.headers_mut().insert("retry-after", drain::RETRY_AFTER_SECS.into());
+            if let Ok(value) = alternates.join(", ").parse() {
+                if !alternates.is_empty() {
+                    refused.headers_mut().insert(drain::ALTERNATES_HEADER, value);
+                }
+            }
+            return Err(refused);
+        }
+        let header = req.headers().get("accept-language").and_then(|v| v.to_str().ok());
+        locale = negotiate_locale(header);
+        // a header that is not text still counts as sent
+        claims = match state.connect_claims(|name| req.headers().get(name).map(|v| v.to_str().unwrap_or_default())) {
+            Ok(claims) => claims,
+            Err(refusal) => {
+                let mut refused = ErrorResponse::new(Some(i18n::catalog().render(locale, refusal.code(), &[])));
+                *refused.status_mut() = tungstenite::http::StatusCode::from_u16(refusal.status()).unwrap();
+                return Err(refused);
+            }
+        };
+        let offered = req.headers().get("sec-websocket-protocol").and_then(|v| v.to_str().ok());
+        subprotocol_class = offered.and_then(ClientClass::from_subprotocols);
+        encoding = offered.map(Encoding::from_subprotocols).unwrap_or_default();
+        let headers = resp.headers_mut();
+        if encoding == Encoding::Cbor {
+            headers.insert("sec-websocket-protocol", CBOR_SUBPROTOCOL.parse().unwrap());
+        } else if let Some(class) = subprotocol_class {
+            headers.insert("sec-websocket-protocol", class.subprotocol().parse().unwrap());
+        }
+        if let Ok(value) = state.config.instance.instance_id.parse() {
+            headers.insert(routing::INSTANCE_HEADER, value);
+        }
+        // CBOR frames are binary already
+        if encoding == Encoding::Json {
+            deflater = state.compression.negotiate(req.uri().query());
+        }
+        if deflater.is_some() {
+            headers.insert(compression::HEADER, "deflate".parse().unwrap());
+        }
+        let bucket = routing::upgrade_room(req.uri().query()).and_then(|room| routing::affinity(&state, &room));
+        if let Some(bucket) = bucket {
+            headers.insert(routing::AFFINITY_HEADER, bucket.into());
+        }
+        Ok(resp)
+    }, Some(ws_config))
+    .await?;
+    let (mut ws_write, mut ws_read) = ws.split();
+
+    // a token provisioned for a class outranks what the client offers
+    let claimed_class = claims.as_ref().and_then(|c| c.client_class.as_deref()).and_then(ClientClass::parse);
+    let class = claimed_class.or(subprotocol_class).unwrap_or_default();
+    let profile = state.config.profiles.get(class);
+
+    let (msg_tx, mut msg_rx) = mpsc::unbounded_channel::<Message>();
+    let (room_tx, mut room_rx) = mpsc::channel::<Message>(state.slow_consumers.queue);
+    let mut heartbeat = tokio::time::interval(profile.heartbeat);
+    heartbeat.reset();
+    let writer = tokio::spawn(async move {
+        loop {
+            // direct replies first: a resume replay is queued there before
+            // any live room event
+            let msg = tokio::select! {
+                biased;
+                Some(msg) = msg_rx.recv() => msg,
+                Some(msg) = room_rx.recv() => msg,
+                _ = heartbeat.tick() => Message::Ping(Vec::new()),
+                else => break,
+            };
+            let closing = matches!(msg, Message::Close(_));
+            let msg = match (encoding.outgoing(msg), deflater.as_mut()) {
+                (Message::Text(text), Some(deflater)) => match deflater.compress(&text) {
+                    Some(data) => Message::Binary(data),
+                    None => Message::Text(text),
+                },
+                (msg, _) => msg,
+            };
+            let _ = ws_write.send(msg).await;
+            if closing {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new(msg_tx.clone(), locale);
+    session.class = class;
+    if let Some(claims) = &claims {
+        session.set_username(claims.sub.clone());
+        session.scope = claims.scope.clone();
+        session.groups = claims.groups.clone();
+    }
+    let reaped = state.connections.register(&session, "ws");
+    let writer_abort = writer.abort_handle();
+    let mut cleanup = Cleanup::new(&state, &session, writer_abort.clone());
+    if let Some(claims) = &claims {
+        state.connections.set_username(session.id, &session.username);
+        state.connections.set_token(session.id, claims);
+        state.census.logged_in(&state, session.id);
+        state.presence.logged_in(&state, session.id);
+    }
+    session.reply(&state.welcome(class));
+
+    let forwarder = spawn_delivery(&state, &session, room_tx);
+    cleanup.forwarder(forwarder.abort_handle());
+
+    let hello_deadline = tokio::time::Instant::now() + state.config.hello_timeout;
+    loop {
+        let msg = if session.protocol == ProtocolState::AwaitingHello {
+            tokio::select! {
+                msg = ws_read.next() => msg,
+                _ = tokio::time::sleep_until(hello_deadline) => {
+                    let secs = state.config.hello_timeout.as_secs().to_string();
+                    session.error(codes::PROTOCOL_HELLO_TIMEOUT, &[("secs", &secs)]);
+                    state.connections.transition(&mut session, ProtocolState::Closing);
+                    let _ = session.out.send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "hello timeout".into(),
+                    })));
+                    // let the writer flush the error and the close frame
+                    let _ = tokio::time::timeout(Duration::from_secs(1), writer).await;
+                    break;
+                }
+            }
+        } else {
+            tokio::select! {
+                msg = ws_read.next() => msg,
+                // the peer is likely gone and will not answer the close frame
+                _ = reaped.notified() => {
+                    let _ = tokio::time::timeout(Duration::from_secs(1), writer).await;
+                    break;
+                }
+            }
+        };
+        if matches!(msg, Some(Ok(_))) {
+            state.connections.touch(session.id);
+        }
+
+        // text or binary; the transport refuses what is over the largest
+        // class's limit, this the rest
+        let size = match &msg {
+            Some(Ok(Message::Text(text))) => text.len(),
+            Some(Ok(Message::Binary(data))) => data.len(),
+            Some(Err(tungstenite::Error::Capacity(CapacityError::MessageTooLong { size, .. }))) => *size,
+            _ => 0,
+        };
+        match msg {
+            _ if size > profile.max_message_bytes => {
+                state.handlers.refuse_oversized(&session, profile.max_message_bytes);
+                state.connections.transition(&mut session, ProtocolState::Closing);
+                let _ = session.out.send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Size,
+                    reason: "message too big".into(),
+                })));
+                let _ = tokio::time::timeout(Duration::from_secs(1), writer).await;
+                break;
+            }
+            Some(Ok(Message::Close(_))) => {
+                state.connections.transition(&mut session, ProtocolState::Closing);
+            }
+            Some(Ok(msg)) => {
+                let received = Instant::now();
+                let Some(parsed) = encoding.decode(&msg) else { continue };
+                if let Some(event) = state.handlers.decode(&session, parsed) {
+                    let kind = handlers::event_type(&event);
+                    state.pipeline.traced(kind, received, state.handlers.dispatch(&state, &mut session, event)).await;
+                }
+            }
+            Some(Err(_)) | None => break,
+        }
+    }
+
+    drop(cleanup);
+    writer_abort.abort();
+    Ok(())
+}
+
+/// Forwards room events to a connection once its delivery starts (after
+/// Hello and any resume replay), skipping muted ones, those at or below
+/// the delivery floor and those too big for its client class, into its
+/// writer's queue `out`; closes the connection if it keeps falling behind
+/// (see slow_consumers.rs). Shared by the socket transports.
+pub fn spawn_delivery(state: &Arc<AppState>, session: &Session, out: mpsc::Sender<Message>) -> JoinHandle<()> {
+    let mut rx = state.tx.subscribe();
+    let identity = session.identity();
+    let rooms = session.subscriptions();
+    let mut delivery = session.delivery();
+    let scope = session.scope.clone();
+    let class = session.class;
+    let mut lags = state.slow_consumers.tracker(session);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let Ok(floor) = delivery.wait_for(Option::is_some).await.map(|f| f.clone().unwrap_or_default()) else {
+            return;
+        };
+        let profile = state.config.profiles.get(class);
+        let mut batch = profile.batching().map(|b| Coalescer::new(&b));
+        // true once the connection fell behind too often and was closed
+        let send = |(frame, events): (String, usize), lags: &mut LagTracker| {
+            if events > 1 {
+                profile.count_batch(events);
+            }
+            match out.try_send(Message::Text(frame)) {
+                Err(TrySendError::Full(_)) => state.slow_consumers.fell_behind(&state, lags, Lag::Queue, events as u64),
+                _ => false,
+            }
+        };
+        loop {
+            let deadline = batch.as_ref().and_then(Coalescer::deadline);
+            let due = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now));
+            let received = tokio::select! {
+                received = rx.recv() => received,
+                _ = due, if deadline.is_some() => {
+                    if let Some(frame) = batch.as_mut().and_then(Coalescer::take) {
+                        if send(frame, &mut lags) {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
+            match received {
+                Ok(envelope) => {
+                    if floor.get(&envelope.room).is_some_and(|seq| envelope.seq <= *seq) {
+                        continue;
+                    }
+                    if !rooms.borrow().contains(&envelope.room) {
+                        continue;
+                    }
+                    if scope.as_ref().is_some_and(|s| !s.allows(&envelope.room, ScopeAction::Read)) {
+                        continue;
+                    }
+                    if !state.mutes.allows(&identity.borrow(), &envelope) {
+                        continue;
+                    }
+                    // a lagging receiver may get it well after it was published
+                    let envelope = state.expiry.deliver(envelope, DeliveryPath::Fanout);
+                    let json = serde_json::to_string(&envelope).unwrap();
+                    if !profile.fits(&json) {
+                        continue;
+                    }
+                    let frame = match batch.as_mut() {
+                        Some(batch) => batch.push(json),
+                        None => Some((json, 1)),
+                    };
+                    if let Some(frame) = frame {
+                        if send(frame, &mut lags) {
+                            break;
+                        }
+                    }
+                }
+                // the client sees the jump in seq and can catch up, unless
+                // it keeps falling behind
+                Err(RecvError::Lagged(missed)) => {
+                    if state.slow_consumers.fell_behind(&state, &mut lags, Lag::Channel, missed) {
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => {
+                    if let Some(frame) = batch.as_mut().and_then(Coalescer::take) {
+                        send(frame, &mut lags);
+                    }
+                    break;
+                }
+            }
+        }
+    })
+}
+
+//
+// METRICS
+//
+async fn metrics_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
+    let openmetrics = state.http_metrics.wants_openmetrics(&headers);
+    let mut out = state.handlers.render_metrics();
+    out.push_str(&state.reports.render_metrics());
+    out.push_str(&state.room_limits.render_metrics());
+    out.push_str(&state.policy.render_metrics());
+    out.push_str(&state.compression.render_metrics());
+    out.push_str(&state.expiry.render_metrics());
+    out.push_str(&state.slow_consumers.render_metrics());
+    out.push_str(&state.idle.render_metrics());
+    out.push_str(&state.revocations.render_metrics());
+    out.push_str(&state.connections.render_metrics());
+    out.push_str(&state.signed.render_metrics());
+    out.push_str(&state.scanning.render_metrics());
+    out.push_str(&state.pipeline.render_metrics());
+    out.push_str(&state.config.profiles.render_metrics());
+    out.push_str(&state.census.render_metrics());
+    out.push_str(&state.presence.render_metrics());
+    out.push_str(&state.drain.render_metrics(&state));
+    out.push_str(&state.backplane.render_metrics());
+    out.push_str(&state.acks.render_metrics());
+    out.push_str(&state.replay.render_metrics());
+    out.push_str(&state.supervisor.render_metrics());
+    out.push_str(&state.jobs.render_metrics("gateway"));
+    out.push_str(&state.http_metrics.render_metrics(openmetrics));
+    if openmetrics {
+        out.push_str("# EOF\n");
+        return ([(header::CONTENT_TYPE, http_metrics::OPENMETRICS)], out);
+    }
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], out)
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+use crate::state::AppState;
+
+//
+// GRACEFUL SHUTDOWN
+//
+// On SIGTERM (or Ctrl-C) the gateway closes its connections before it
+// exits instead of dropping every socket mid-frame:
+//
+//   1. new WebSocket upgrades, long-poll connects and QUIC handshakes are
+//      refused and /ready fails, as while draining (see drain.rs);
+//   2. room events already published get up to a second to reach every
+//      delivery task, plus the longest batch_ms so batching classes send
+//      their last batch;
+//   3. every socket connection is closed with 1012 (service restart),
+//      "server restarting", after what is queued for it;
+//   4. the process exits once they have closed, or after
+//      GATEWAY_SHUTDOWN_TIMEOUT_SECS (default 10) with whatever is left.
+//
+// Nobody is told to reconnect ahead of time, so every client reconnects
+// at once; a rollout that can afford it drains first (POST /admin/drain)
+// and lets SIGTERM close what is left. Long-poll sessions are not closed;
+// their next poll fails and their client reconnects elsewhere.
+//
+
+/// Longest wait for published events to reach the delivery tasks.
+const FLUSH: Duration = Duration::from_secs(1);
+
+const POLL: Duration = Duration::from_millis(20);
+
+/// Resolves on SIGTERM or Ctrl-C.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate()).expect("GATEWAY: cannot listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Closes every socket connection, returning once they are gone or the
+/// shutdown timeout is up.
+pub async fn run(state: &AppState) {
+    let secs = std::env::var("GATEWAY_SHUTDOWN_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(10);
+    let deadline = Instant::now() + Duration::from_secs(secs);
+    state.drain.stop_accepting(state);
+
+    let flushed = Instant::now() + FLUSH;
+    while !state.tx.is_empty() && Instant::now() < flushed.min(deadline) {
+        tokio::time::sleep(POLL).await;
+    }
+    let batching = state.config.profiles.iter().map(|(_, p)| p.batch_delay).max().unwrap_or_default();
+    tokio::time::sleep_until((Instant::now() + batching).min(deadline)).await;
+
+    let open: Vec<u64> = state
+        .connections
+        .snapshot(true)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| c.transport != "poll")
+        .map(|c| c.id)
+        .collect();
+    println!("GATEWAY: shutting down, closing {} connections", open.len());
+    for id in &open {
+        state.connections.close(*id, CloseCode::Restart, "server restarting");
+    }
+
+    let remaining = || open.iter().filter(|id| state.connections.get(**id).is_some()).count();
+    while remaining() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(POLL).await;
+    }
+    match remaining() {
+        0 => println!("GATEWAY: all connections closed"),
+        left => println!("GATEWAY: {} connections still open after {}s, exiting anyway", left, secs),
+    }
+}
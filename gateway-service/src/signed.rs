@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use uchat_core::i18n::codes;
+use uchat_proto::events::ClientEvent;
+
+use crate::handlers::EventHandler;
+use crate::state::{AppState, Session};
+
+//
+// SIGNED DEVICE MESSAGES
+//
+// Devices may wrap any event in Signed, authenticated with a per-device
+// key (see ClientEvent::Signed for the format), so a middlebox or a
+// buggy proxy cannot forge or replay device traffic:
+//
+//   GATEWAY_DEVICE_KEYS          "thermo-1=key1,thermo-2=key2"; the device
+//                                id is the username it logs in with
+//   GATEWAY_REPLAY_WINDOW_SECS   accepted clock skew either way (default 300)
+//
+// A message is rejected when its signature does not verify, its timestamp
+// is outside the window, or its nonce was already seen from that device.
+// Nonces are remembered only as long as their timestamp stays inside the
+// window, since anything older is refused as stale anyway; the "signed"
+// rate limit bounds how many that can be per device. Rejections are
+// counted in /metrics and audit-logged.
+//
+
+type HmacSha256 = Hmac<Sha256>;
+
+const REASONS: &[&str] = &["bad_signature", "stale", "duplicate", "unknown_device", "invalid_body"];
+
+pub struct SignedMessages {
+    keys: HashMap<String, Vec<u8>>,
+    window_secs: i64,
+    /// Device -> nonce -> its timestamp.
+    seen: Mutex<HashMap<String, HashMap<String, i64>>>,
+    accepted: AtomicU64,
+    rejected: HashMap<&'static str, AtomicU64>,
+}
+
+impl SignedMessages {
+    pub fn from_env() -> Self {
+        let mut keys = HashMap::new();
+        for entry in std::env::var("GATEWAY_DEVICE_KEYS").unwrap_or_default().split(',') {
+            match entry.split_once('=') {
+                Some((device, key)) if !key.is_empty() => {
+                    keys.insert(device.trim().to_string(), key.trim().as_bytes().to_vec());
+                }
+                _ if entry.trim().is_empty() => {}
+                _ => println!("GATEWAY: ignoring malformed device key entry for {:?}", entry.split('=').next()),
+            }
+        }
+
+        Self {
+            keys,
+            window_secs: std::env::var("GATEWAY_REPLAY_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300),
+            seen: Mutex::new(HashMap::new()),
+            accepted: AtomicU64::new(0),
+            rejected: REASONS.iter().map(|r| (*r, AtomicU64::new(0))).collect(),
+        }
+    }
+
+    fn verify(&self, device: &str, body: &str, nonce: &str, ts: i64, sig: &str) -> Result<(), &'static str> {
+        let Some(key) = self.keys.get(device) else { return Err("unknown_device") };
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(format!("{}.{}.{}", nonce, ts, body).as_bytes());
+        let sig = B64.decode(sig).map_err(|_| "bad_signature")?;
+        mac.verify_slice(&sig).map_err(|_| "bad_signature")
+    }
+
+    /// Accepts each (device, nonce) once while `ts` is inside the window.
+    fn check_fresh(&self, device: &str, nonce: &str, ts: i64, now: i64) -> Result<(), &'static str> {
+        if (now - ts).abs() > self.window_secs {
+            return Err("stale");
+        }
+        let mut seen = self.seen.lock().unwrap();
+        let nonces = seen.entry(device.to_string()).or_default();
+        nonces.retain(|_, t| now - *t <= self.window_secs);
+        if nonces.contains_key(nonce) {
+            return Err("duplicate");
+        }
+        nonces.insert(nonce.to_string(), ts);
+        Ok(())
+    }
+
+    /// Forgets nonces that aged out of the window, and idle devices.
+    pub fn expire(&self) {
+        let now = Utc::now().timestamp();
+        self.seen.lock().unwrap().retain(|_, nonces| {
+            nonces.retain(|_, t| now - *t <= self.window_secs);
+            !nonces.is_empty()
+        });
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE gateway_signed_messages_total counter\n");
+        let _ = writeln!(
+            out,
+            "gateway_signed_messages_total{{outcome=\"accepted\"}} {}",
+            self.accepted.load(Ordering::Relaxed)
+        );
+        for reason in REASONS {
+            let _ = writeln!(
+                out,
+                "gateway_signed_messages_total{{outcome=\"{}\"}} {}",
+                reason,
+                self.rejected[reason].load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+pub struct SignedHandler;
+
+fn reject(state: &AppState, session: &Session, nonce: &str, reason: &'static str) {
+    state.signed.rejected[reason].fetch_add(1, Ordering::Relaxed);
+    state.audit.record("device.signed_rejected", &session.username, nonce, reason);
+    session.error(codes::PROTOCOL_SIGNED_REJECTED, &[("reason", reason)]);
+}
+
+#[async_trait]
+impl EventHandler for SignedHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let ClientEvent::Signed { body, nonce, ts, sig } = event else { return Ok(()) };
+        let signed = &state.signed;
+
+        let checked = signed
+            .verify(&session.username, &body, &nonce, ts, &sig)
+            .and_then(|()| signed.check_fresh(&session.username, &nonce, ts, Utc::now().timestamp()));
+        if let Err(reason) = checked {
+            reject(state, session, &nonce, reason);
+            return Ok(());
+        }
+
+        // handshake and nested envelopes stay outside signed traffic
+        let inner = match serde_json::from_str::<ClientEvent>(&body) {
+            Ok(ClientEvent::Signed { .. } | ClientEvent::Hello { .. } | ClientEvent::Login { .. }) | Err(_) => {
+                reject(state, session, &nonce, "invalid_body");
+                return Ok(());
+            }
+            Ok(inner) => inner,
+        };
+        signed.accepted.fetch_add(1, Ordering::Relaxed);
+        state.handlers.dispatch(state, session, inner).await;
+        Ok(())
+    }
+}
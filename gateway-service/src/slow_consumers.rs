@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::Message;
+
+use uchat_core::i18n::{self, codes};
+
+use crate::state::{localized_error, AppState, Session};
+
+//
+// SLOW CONSUMERS
+//
+// A connection's delivery task (see server.rs) takes room events off the
+// broadcast channel, which holds the last 1024, and queues them for the
+// socket writer, which holds at most GATEWAY_SLOW_CONSUMER_QUEUE frames. A
+// client that reads slower than its rooms are written falls behind one or
+// the other: the channel moves on without its delivery task
+// (RecvError::Lagged), or its queue is full and the frame is dropped.
+//
+// Falling behind once is survivable: the client sees the jump in seq and
+// can resume. A client that keeps falling behind would keep missing events
+// instead, so after GATEWAY_SLOW_CONSUMER_LAGS times within
+// GATEWAY_SLOW_CONSUMER_WINDOW_SECS the gateway tells it protocol.too_slow
+// and closes the connection (1013, "too slow"). Reconnecting with its
+// resume token replays what it missed (see resume.rs).
+//
+//   GATEWAY_SLOW_CONSUMER_QUEUE        frames queued per connection (default 256)
+//   GATEWAY_SLOW_CONSUMER_LAGS         times a connection may fall behind
+//                                      (default 3; 0 never closes it)
+//   GATEWAY_SLOW_CONSUMER_WINDOW_SECS  within this long (default 60)
+//
+//   gateway_delivery_lags_total{cause="channel|queue"}
+//   gateway_delivery_missed_events_total
+//   gateway_slow_consumer_evictions_total
+//
+
+/// Where a connection fell behind.
+#[derive(Debug, Clone, Copy)]
+pub enum Lag {
+    /// The broadcast channel overwrote events its delivery task had not read.
+    Channel,
+    /// Its writer's queue was full.
+    Queue,
+}
+
+#[derive(Default)]
+struct Counters {
+    channel: AtomicU64,
+    queue: AtomicU64,
+    missed: AtomicU64,
+    evictions: AtomicU64,
+}
+
+pub struct SlowConsumers {
+    /// Frames a connection's writer may have queued.
+    pub queue: usize,
+    max_lags: usize,
+    window: Duration,
+    counters: Counters,
+}
+
+/// One connection's recent lags, kept by its delivery task.
+pub struct LagTracker {
+    conn: u64,
+    replies: mpsc::UnboundedSender<Message>,
+    lags: VecDeque<Instant>,
+    missed: u64,
+}
+
+impl SlowConsumers {
+    pub fn from_env() -> Self {
+        let env = |name: &str, default: u64| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        Self::new(
+            env("GATEWAY_SLOW_CONSUMER_QUEUE", 256) as usize,
+            env("GATEWAY_SLOW_CONSUMER_LAGS", 3) as usize,
+            Duration::from_secs(env("GATEWAY_SLOW_CONSUMER_WINDOW_SECS", 60)),
+        )
+    }
+
+    pub fn new(queue: usize, max_lags: usize, window: Duration) -> Self {
+        Self { queue: queue.max(1), max_lags, window, counters: Counters::default() }
+    }
+
+    pub fn tracker(&self, session: &Session) -> LagTracker {
+        LagTracker { conn: session.id, replies: session.out.clone(), lags: VecDeque::new(), missed: 0 }
+    }
+
+    /// Records that the connection missed `missed` events; once it fell
+    /// behind too often, tells it so, closes it and returns true.
+    pub fn fell_behind(&self, state: &AppState, tracker: &mut LagTracker, lag: Lag, missed: u64) -> bool {
+        let counter = match lag {
+            Lag::Channel => &self.counters.channel,
+            Lag::Queue => &self.counters.queue,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.counters.missed.fetch_add(missed, Ordering::Relaxed);
+
+        let now = Instant::now();
+        tracker.missed += missed;
+        tracker.lags.push_back(now);
+        while tracker.lags.front().is_some_and(|at| now.duration_since(*at) > self.window) {
+            tracker.lags.pop_front();
+        }
+        if self.max_lags == 0 || tracker.lags.len() < self.max_lags {
+            return false;
+        }
+
+        let info = state.connections.get(tracker.conn);
+        let locale = info.as_ref().map_or(i18n::DEFAULT_LOCALE, |c| c.locale);
+        let user = info.and_then(|c| c.username).unwrap_or_else(|| "anonymous".into());
+        println!(
+            "GATEWAY: connection {} ({}) fell behind {} times, closing it ({} events missed)",
+            tracker.conn,
+            user,
+            tracker.lags.len(),
+            tracker.missed
+        );
+        let notice = localized_error(locale, codes::PROTOCOL_TOO_SLOW, &[("missed", &tracker.missed.to_string())]);
+        let _ = tracker.replies.send(Message::Text(serde_json::to_string(&notice).unwrap()));
+        state.connections.close(tracker.conn, CloseCode::Again, "too slow");
+        self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let c = &self.counters;
+        let mut out = String::from("# TYPE gateway_delivery_lags_total counter\n");
+        for (cause, counter) in [("channel", &c.channel), ("queue", &c.queue)] {
+            let count = counter.load(Ordering::Relaxed);
+            let _ = writeln!(out, "gateway_delivery_lags_total{{cause=\"{}\"}} {}", cause, count);
+        }
+        out.push_str("# TYPE gateway_delivery_missed_events_total counter\n");
+        let _ = writeln!(out, "gateway_delivery_missed_events_total {}", c.missed.load(Ordering::Relaxed));
+        out.push_str("# TYPE gateway_slow_consumer_evictions_total counter\n");
+        let _ = writeln!(out, "gateway_slow_consumer_evictions_total {}", c.evictions.load(Ordering::Relaxed));
+        out
+    }
+}
@@ -0,0 +1,604 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::http::{header, HeaderMap, StatusCode};
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, watch};
+use tungstenite::protocol::Message;
+
+use uchat_core::i18n;
+use uchat_core::jobs::Scheduler;
+use uchat_core::storage::{LocalStorage, Storage};
+use uchat_proto::envelope::{Encryption, Envelope};
+use uchat_proto::events::{Limits, ServerEvent};
+use uchat_proto::jwt::{
+    secret_from_env, session_cookie, verify_claims, verify_session_claims, Claims, Scope, ScopeAction,
+};
+
+use crate::acks::Acks;
+use crate::audit::AuditLog;
+use crate::backplane::Backplane;
+use crate::census::Census;
+use crate::client_config::ClientConfigs;
+use crate::commands::CommandRegistry;
+use crate::config::GatewayConfig;
+use crate::connections::{ConnectionRegistry, ProtocolState};
+use crate::devices::DeviceQueues;
+use crate::drain::Drain;
+use crate::expiry::MessageExpiry;
+use crate::freeze::RoomFreezes;
+use crate::handlers::HandlerRegistry;
+use crate::http_metrics::HttpMetrics;
+use crate::idle::IdleReaper;
+use crate::journal::RoomJournal;
+use crate::revocations::Revocations;
+use crate::longpoll::PollSessions;
+use crate::media::MediaIndex;
+use crate::moderation::ModerationPolicies;
+use crate::mutes::MuteLists;
+use crate::pipeline::{self, PipelineTiming};
+use crate::presence::PresenceTracker;
+use crate::profiles::ClientClass;
+use crate::receipts::ReceiptForwarder;
+use crate::replay::ReplayBuffer;
+use crate::reports::ReportQueue;
+use crate::compression::FrameCompression;
+use crate::policy::PolicyHook;
+use crate::room_limits::RoomRateLimits;
+use crate::rooms::RoomPolicy;
+use crate::scan::UploadScanning;
+use crate::signed::SignedMessages;
+use crate::slow_consumers::SlowConsumers;
+use crate::supervisor::Supervisor;
+
+/// The room every connection starts in, and where messages without a
+/// room go.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+const RECENT_CAP: usize = 200;
+
+/// Optional protocol features, advertised in the Welcome event.
+const FEATURES: &[&str] = &[
+    "room_seq",
+    "slash_commands",
+    "mute_lists",
+    "locale",
+    "long_poll",
+    "device_queue",
+    "hello_handshake",
+    "resume",
+    "receipts",
+    "signed_messages",
+    "instance_origin",
+    "client_config",
+    "presence",
+    "multi_room",
+    "disappearing_messages",
+    "e2ee_rooms",
+    "token_refresh",
+];
+
+/// Per-room seq a connection's live delivery starts above (see resume.rs).
+pub type DeliveryFloor = HashMap<String, u64>;
+
+/// A broadcast chat message as remembered by the room ring buffer.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentMessage {
+    pub id: u64,
+    pub room: String,
+    pub from: String,
+    pub content: String,
+    pub ts: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+/// Process-wide gateway state shared by every connection.
+pub struct AppState {
+    pub secret: String,
+    pub config: GatewayConfig,
+    pub admins: Vec<String>,
+    pub rooms: RoomPolicy,
+    pub room_limits: RoomRateLimits,
+    pub tx: broadcast::Sender<Envelope>,
+    pub commands: CommandRegistry,
+    pub handlers: HandlerRegistry,
+    pub poll_sessions: PollSessions,
+    pub reports: ReportQueue,
+    pub audit: AuditLog,
+    pub mutes: MuteLists,
+    pub connections: ConnectionRegistry,
+    pub devices: DeviceQueues,
+    pub client_config: ClientConfigs,
+    pub storage: Box<dyn Storage>,
+    pub media: MediaIndex,
+    pub scanning: UploadScanning,
+    pub jobs: Scheduler,
+    pub journal: Arc<RoomJournal>,
+    pub moderation: ModerationPolicies,
+    pub freezes: RoomFreezes,
+    /// External authorization engine, asked after the built-in checks.
+    pub policy: PolicyHook,
+    pub census: Census,
+    pub presence: PresenceTracker,
+    pub drain: Drain,
+    pub backplane: Backplane,
+    pub compression: FrameCompression,
+    pub expiry: MessageExpiry,
+    pub slow_consumers: SlowConsumers,
+    pub idle: IdleReaper,
+    pub revocations: Revocations,
+    pub receipts: ReceiptForwarder,
+    pub acks: Acks,
+    pub replay: ReplayBuffer,
+    pub supervisor: Supervisor,
+    pub signed: SignedMessages,
+    pub http_metrics: HttpMetrics,
+    pub pipeline: PipelineTiming,
+    pub recent: Mutex<VecDeque<RecentMessage>>,
+    next_message_id: AtomicU64,
+    /// Last sequence number handed out per room.
+    room_seq: Mutex<HashMap<String, u64>>,
+    /// Held while a room's next event is numbered and sent.
+    room_turns: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Comma-separated `GATEWAY_ADMINS`; admins also act as moderators.
+pub fn admins_from_env() -> Vec<String> {
+    std::env::var("GATEWAY_ADMINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl AppState {
+    pub fn from_env() -> Self {
+        let config = GatewayConfig::from_env();
+        let mut handlers = HandlerRegistry::with_builtins();
+        handlers.apply_rate_limits(&config.rate_limits);
+        for (class, profile) in config.profiles.iter() {
+            handlers.apply_class_rate_limits(class, &profile.rate_limits);
+        }
+        let journal = RoomJournal::from_env().expect("GATEWAY: cannot open room journal");
+        let census = Census::from_env(&config.instance).expect("GATEWAY: cannot open connection census");
+
+        Self {
+            secret: secret_from_env(),
+            config,
+            admins: admins_from_env(),
+            rooms: RoomPolicy::from_env(),
+            room_limits: RoomRateLimits::from_env(),
+            tx: broadcast::channel::<Envelope>(1024).0,
+            commands: CommandRegistry::from_env(),
+            handlers,
+            poll_sessions: Default::default(),
+            reports: Default::default(),
+            audit: Default::default(),
+            mutes: Default::default(),
+            connections: Default::default(),
+            devices: Default::default(),
+            client_config: Default::default(),
+            storage: Box::new(LocalStorage::from_env()),
+            media: Default::default(),
+            scanning: UploadScanning::from_env(),
+            jobs: Default::default(),
+            receipts: ReceiptForwarder::from_env(),
+            acks: Acks::from_env(),
+            replay: ReplayBuffer::from_env(),
+            supervisor: Supervisor::from_env(),
+            signed: SignedMessages::from_env(),
+            http_metrics: HttpMetrics::from_env(),
+            pipeline: PipelineTiming::from_env(),
+            recent: Mutex::new(VecDeque::new()),
+            next_message_id: AtomicU64::new(1),
+            // numbering continues where the journal left off
+            room_seq: Mutex::new(journal.last_seqs()),
+            room_turns: Mutex::new(HashMap::new()),
+            journal: Arc::new(journal),
+            moderation: ModerationPolicies::from_env().expect("GATEWAY: cannot open moderation policies"),
+            freezes: RoomFreezes::from_env().expect("GATEWAY: cannot open room freezes"),
+            policy: PolicyHook::from_env(),
+            census,
+            presence: Default::default(),
+            drain: Drain::from_env(),
+            backplane: Backplane::from_env(),
+            compression: FrameCompression::from_env(),
+            expiry: MessageExpiry::from_env(),
+            slow_consumers: SlowConsumers::from_env(),
+            idle: IdleReaper::from_env(),
+            revocations: Revocations::from_env(),
+        }
+    }
+
+    /// The Welcome event sent as the first frame of every connection, with
+    /// the limits of its client class.
+    pub fn welcome(&self, class: ClientClass) -> ServerEvent {
+        let profile = self.config.profiles.get(class);
+        ServerEvent::Welcome {
+            limits: Limits {
+                max_message_bytes: profile.max_message_bytes,
+                heartbeat_interval_secs: profile.heartbeat.as_secs(),
+                rate_limits: self.handlers.rate_limits(class),
+                features: FEATURES.iter().map(|f| f.to_string()).collect(),
+                batching: profile.batching(),
+            },
+            server: Some(self.config.instance.clone()),
+        }
+    }
+
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.admins.iter().any(|a| a == user)
+    }
+
+    /// The caller's username if their Bearer token is an admin's; for the
+    /// admin-only HTTP APIs. Otherwise the status and details to answer.
+    pub fn require_admin(&self, headers: &HeaderMap) -> Result<String, (StatusCode, &'static str)> {
+        let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+        match self.bearer_claims(auth) {
+            None => Err((StatusCode::UNAUTHORIZED, "missing or invalid token")),
+            Some(claims) if !self.is_admin(&claims.sub) => Err((StatusCode::FORBIDDEN, "admins only")),
+            Some(claims) => Ok(claims.sub),
+        }
+    }
+
+    /// Authorization hook for room posts; replies with an error and returns
+    /// false when `session` may not post in `room`, nobody may (see
+    /// freeze.rs) or the room is over its rate limit (see room_limits.rs).
+    pub fn authorize_post(&self, session: &Session, room: &str) -> bool {
+        if !session.may(room, ScopeAction::Send) {
+            session.error(i18n::codes::AUTH_SCOPE_DENIED, &[("action", ScopeAction::Send.as_str()), ("room", room)]);
+            return false;
+        }
+        if self.freezes.is_frozen(room) {
+            session.error(i18n::codes::ROOM_FROZEN, &[("room", room)]);
+            return false;
+        }
+        let user = &session.username;
+        let is_admin = self.is_admin(user);
+        if !self.rooms.may_post(user, is_admin, room) {
+            session.error(i18n::codes::ROOM_POST_FORBIDDEN, &[("room", room)]);
+            return false;
+        }
+        if !is_admin && !self.room_limits.check(room) {
+            session.error(i18n::codes::ROOM_RATE_LIMITED, &[("room", room)]);
+            return false;
+        }
+        pipeline::mark("authorize");
+        true
+    }
+
+    /// Resolves an `Authorization: Bearer` header value to its claims.
+    /// The HTTP APIs act on whole accounts, so delegated tokens, which only
+    /// cover sending and reading in some rooms, are refused here.
+    pub fn bearer_claims(&self, header: Option<&str>) -> Option<Claims> {
+        self.token_claims(header).filter(|c| !c.is_delegated())
+    }
+
+    /// Like `bearer_claims`, delegated tokens included.
+    pub fn token_claims(&self, header: Option<&str>) -> Option<Claims> {
+        self.verified_claims(header?.strip_prefix("Bearer ")?)
+    }
+
+    /// Verifies a token, refusing one that was revoked (see revocations.rs).
+    pub fn verified_claims(&self, token: &str) -> Option<Claims> {
+        verify_claims(&self.secret, token).filter(|c| !self.revocations.revokes(c))
+    }
+
+    /// Checks a connection being opened, a WebSocket upgrade or a long-poll
+    /// connect, by its request headers: the Origin (see config.rs), then
+    /// the token it signs in with. Bots bring a (delegated) Bearer token,
+    /// web clients their session cookie, which is only taken from a listed
+    /// origin. Ok(None) when it brings neither.
+    pub fn connect_claims<'h>(
+        &self,
+        header: impl Fn(&str) -> Option<&'h str>,
+    ) -> Result<Option<Claims>, ConnectRefused> {
+        let origin = header("origin");
+        if !self.config.origin_allowed(origin) {
+            println!("GATEWAY: refused connection from origin {:?}", origin.unwrap_or_default());
+            return Err(ConnectRefused::Origin);
+        }
+        if let Some(auth) = header("authorization") {
+            return self.token_claims(Some(auth)).map(Some).ok_or(ConnectRefused::InvalidToken);
+        }
+        let Some(token) = header("cookie").and_then(session_cookie) else {
+            return Ok(None);
+        };
+        if !self.config.cookie_origin_allowed(origin) {
+            println!("GATEWAY: refused session cookie from origin {:?}", origin.unwrap_or_default());
+            return Err(ConnectRefused::CookieOrigin);
+        }
+        verify_session_claims(&self.secret, token)
+            .filter(|c| !self.revocations.revokes(c))
+            .map(Some)
+            .ok_or(ConnectRefused::InvalidToken)
+    }
+
+    /// Fans a chat message out to `room` and remembers it in the ring
+    /// buffer (moderation context, reports); returns its seq. `receipts`
+    /// asks recipients to confirm delivery (see acks.rs); `expires_at`
+    /// makes it a disappearing message (see expiry.rs); `encryption` is
+    /// the sender's end-to-end encryption metadata, passed on as is.
+    pub async fn broadcast_message(
+        &self,
+        room: &str,
+        from: &str,
+        content: String,
+        receipts: bool,
+        expires_at: Option<i64>,
+        encryption: Option<Encryption>,
+    ) -> u64 {
+        let id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut recent = self.recent.lock().unwrap();
+            recent.push_back(RecentMessage {
+                id,
+                room: room.to_string(),
+                from: from.to_string(),
+                content: content.clone(),
+                ts: Utc::now().to_rfc3339(),
+                expires_at,
+            });
+            while recent.len() > RECENT_CAP {
+                recent.pop_front();
+            }
+        }
+
+        let event = ServerEvent::MessageBroadcast { from: from.to_string(), content };
+        self.publish_with(room, Some(from), event, receipts, expires_at, encryption).await
+    }
+
+    /// Sends a room event with the room's next sequence number. Publishes
+    /// in one room take turns, so channel order always matches `seq` order;
+    /// other rooms are not held up. The event is journaled after the turn,
+    /// before this returns. `sender` is the user whose action it is, None for the
+    /// gateway's own.
+    pub async fn publish(&self, room: &str, sender: Option<&str>, event: ServerEvent) -> u64 {
+        self.publish_with(room, sender, event, false, None, None).await
+    }
+
+    /// `publish`, with the envelope's `receipts` flag, `expires_at` and
+    /// `encryption`.
+    pub async fn publish_with(
+        &self,
+        room: &str,
+        sender: Option<&str>,
+        event: ServerEvent,
+        receipts: bool,
+        expires_at: Option<i64>,
+        encryption: Option<Encryption>,
+    ) -> u64 {
+        let turn = self.room_turns.lock().unwrap().entry(room.to_string()).or_default().clone();
+        let envelope = {
+            let _turn = turn.lock().await;
+            let last = self.current_seq(room);
+            let mut envelope = Envelope {
+                room: room.to_string(),
+                seq: last + 1,
+                origin: Some(self.config.instance.clone()),
+                sender: sender.map(str::to_string),
+                ts: Some(Utc::now().timestamp_millis()),
+                receipts,
+                expires_at,
+                encryption,
+                event,
+            };
+            // with a backplane the cluster numbers the event, and it comes
+            // back through this instance's subscription like everyone else's
+            let shared = self.backplane.publish(&mut envelope, last).await;
+            self.observe_seq(room, envelope.seq);
+            self.replay.push(&envelope);
+            if !shared || !self.backplane.connected() {
+                let _ = self.tx.send(envelope.clone());
+            }
+            pipeline::mark("fanout");
+            envelope
+        };
+
+        // resumes are served from the replay buffer until this lands
+        let seq = envelope.seq;
+        let journal = self.journal.clone();
+        let _ = tokio::task::spawn_blocking(move || journal.append(&envelope)).await;
+        pipeline::mark("persist");
+        seq
+    }
+
+    /// Records a seq another instance published in `room`.
+    pub fn observe_seq(&self, room: &str, seq: u64) {
+        let mut seqs = self.room_seq.lock().unwrap();
+        let last = seqs.entry(room.to_string()).or_insert(0);
+        *last = (*last).max(seq);
+    }
+
+    /// Last seq of every room; with `wait` false, None while the lock
+    /// is held.
+    pub fn room_seqs(&self, wait: bool) -> Option<HashMap<String, u64>> {
+        crate::debug::read(&self.room_seq, wait).map(|seqs| seqs.clone())
+    }
+
+    /// Last seq published in `room`.
+    pub fn current_seq(&self, room: &str) -> u64 {
+        self.room_seq.lock().unwrap().get(room).copied().unwrap_or(0)
+    }
+}
+
+/// Per-connection state handed to event handlers.
+pub struct Session {
+    /// Process-unique connection id (see `ConnectionRegistry`).
+    pub id: u64,
+    pub username: String,
+    /// Limits of the delegated token the connection signed in with, if any.
+    pub scope: Option<Scope>,
+    /// Groups in the token the connection signed in with; restricted
+    /// rooms admit their members only.
+    pub groups: Vec<String>,
+    /// Picks the limits the connection gets (see profiles.rs).
+    pub class: ClientClass,
+    /// Where the connection is in the handshake; change it through
+    /// `ConnectionRegistry::transition`.
+    pub protocol: ProtocolState,
+    /// Mirrors `username` for the delivery task, which filters by user.
+    identity: watch::Sender<String>,
+    /// Rooms whose events the connection receives; change them through
+    /// `join` and `leave`, which the delivery task follows.
+    rooms: watch::Sender<BTreeSet<String>>,
+    /// Unset until Hello; room events wait for it (see `start_delivery`).
+    delivery: watch::Sender<Option<DeliveryFloor>>,
+    /// Room -> floor in the last resume token issued (see resume.rs).
+    resume_floors: Mutex<BTreeMap<String, u64>>,
+    /// Catalog locale for server-generated text (Accept-Language or Hello).
+    pub locale: &'static str,
+    pub out: mpsc::UnboundedSender<Message>,
+}
+
+impl Session {
+    pub fn new(out: mpsc::UnboundedSender<Message>, locale: &'static str) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+        let username = String::from("user");
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            identity: watch::Sender::new(username.clone()),
+            rooms: watch::Sender::new(BTreeSet::from([DEFAULT_ROOM.to_string()])),
+            delivery: watch::Sender::new(None),
+            resume_floors: Mutex::new(BTreeMap::new()),
+            username,
+            scope: None,
+            groups: Vec::new(),
+            class: ClientClass::default(),
+            protocol: ProtocolState::AwaitingHello,
+            locale,
+            out,
+        }
+    }
+
+    pub fn set_username(&mut self, username: String) {
+        self.identity.send_replace(username.clone());
+        self.username = username;
+    }
+
+    /// Whether the session's token allows `action` in `room`.
+    pub fn may(&self, room: &str, action: ScopeAction) -> bool {
+        self.scope.as_ref().is_none_or(|s| s.allows(room, action))
+    }
+
+    /// Follows this session's username from another task.
+    pub fn identity(&self) -> watch::Receiver<String> {
+        self.identity.subscribe()
+    }
+
+    pub fn rooms(&self) -> BTreeSet<String> {
+        self.rooms.borrow().clone()
+    }
+
+    /// Floors for a new resume token: the last token's for rooms the
+    /// session already held, `current` for rooms joined since.
+    pub fn resume_floors(&self, current: impl Fn(&str) -> u64) -> BTreeMap<String, u64> {
+        let mut issued = self.resume_floors.lock().unwrap();
+        let floors: BTreeMap<_, _> = self
+            .rooms()
+            .into_iter()
+            .map(|room| {
+                let floor = issued.get(&room).copied().unwrap_or_else(|| current(&room));
+                (room, floor)
+            })
+            .collect();
+        *issued = floors.clone();
+        floors
+    }
+
+    /// Starts the next resume token from `floors` (after a resume).
+    pub fn set_resume_floors(&self, floors: BTreeMap<String, u64>) {
+        *self.resume_floors.lock().unwrap() = floors;
+    }
+
+    pub fn in_room(&self, room: &str) -> bool {
+        self.rooms.borrow().contains(room)
+    }
+
+    /// Adds `room`; false if the connection is already in it.
+    pub fn join(&self, room: &str) -> bool {
+        self.rooms.send_if_modified(|rooms| rooms.insert(room.to_string()))
+    }
+
+    /// Replaces the rooms, as a resumed session had them.
+    pub fn set_rooms(&self, rooms: BTreeSet<String>) {
+        self.rooms.send_replace(rooms);
+    }
+
+    /// Removes `room`; false if the connection was not in it.
+    pub fn leave(&self, room: &str) -> bool {
+        self.rooms.send_if_modified(|rooms| rooms.remove(room))
+    }
+
+    /// Follows the session's rooms from the delivery task.
+    pub fn subscriptions(&self) -> watch::Receiver<BTreeSet<String>> {
+        self.rooms.subscribe()
+    }
+
+    /// Lets room events through, skipping those at or below `floor`.
+    pub fn start_delivery(&self, floor: DeliveryFloor) {
+        self.delivery.send_replace(Some(floor));
+    }
+
+    /// Follows the delivery floor from the delivery task.
+    pub fn delivery(&self) -> watch::Receiver<Option<DeliveryFloor>> {
+        self.delivery.subscribe()
+    }
+
+    /// Sends an event to this connection only.
+    pub fn reply(&self, event: &ServerEvent) {
+        let json = serde_json::to_string(event).unwrap();
+        let _ = self.out.send(Message::Text(json));
+    }
+
+    /// Sends a catalog error, rendered in this session's locale.
+    pub fn error(&self, code: &str, params: &[(&str, &str)]) {
+        self.reply(&localized_error(self.locale, code, params));
+    }
+}
+
+/// Locale for an `Accept-Language` header value, if the client sent one.
+pub fn negotiate_locale(accept_language: Option<&str>) -> &'static str {
+    match accept_language {
+        Some(value) => i18n::catalog().negotiate(value),
+        None => i18n::DEFAULT_LOCALE,
+    }
+}
+
+/// Why a connection was refused before it opened; see `connect_claims`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectRefused {
+    Origin,
+    CookieOrigin,
+    InvalidToken,
+}
+
+impl ConnectRefused {
+    pub fn status(self) -> u16 {
+        match self {
+            Self::Origin | Self::CookieOrigin => 403,
+            Self::InvalidToken => 401,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::Origin => i18n::codes::AUTH_ORIGIN_REFUSED,
+            Self::CookieOrigin => i18n::codes::AUTH_COOKIE_ORIGIN_REFUSED,
+            Self::InvalidToken => i18n::codes::AUTH_INVALID_TOKEN,
+        }
+    }
+}
+
+pub fn localized_error(locale: &str, code: &str, params: &[(&str, &str)]) -> ServerEvent {
+    ServerEvent::Error {
+        details: i18n::catalog().render(locale, code, params),
+        code: Some(code.to_string()),
+    }
+}
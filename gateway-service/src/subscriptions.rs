@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use uchat_core::i18n::codes;
+use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::jwt::ScopeAction;
+
+use crate::handlers::EventHandler;
+use crate::policy::PolicyAction;
+use crate::resume;
+use crate::state::{AppState, Session};
+
+//
+// ROOM SUBSCRIPTIONS
+//
+// A connection receives the events of a set of rooms, starting with
+// DEFAULT_ROOM, and changes it with JoinRoom / LeaveRoom, answered with
+// RoomJoined (carrying the room's current seq; every later event is
+// delivered) and RoomLeft, or an error. Every room shares the one
+// broadcast channel; each connection's delivery task drops the envelopes
+// of rooms it is not in, as it does for muted rooms. Posting (SendMessage
+// with a room) needs the room joined.
+//
+// Joining is checked against the connection's token: a delegated token
+// must allow reading the room, and a room restricted by ROOM_ACL needs one
+// of its groups (see rooms.rs).
+//
+// The set travels in the resume token, so a resumed session is back in
+// the same rooms with their gaps replayed; a fresh token follows every
+// join and leave of a logged-in connection.
+//
+// Every event of a room carries the next number of its seq, which only
+// grows (across restarts through the journal, across instances through
+// the backplane), so a client spots a gap or a reordering by comparing
+// against the last one it saw. GET /rooms/:room answers the room's
+// current seq to anyone whose token could join it, e.g. to check a
+// connection is not behind without reconnecting.
+//
+
+/// Rooms one connection may be in at once.
+pub const MAX_ROOMS: usize = 50;
+
+/// Same rule as chat-service room ids: a lowercase slug.
+pub fn valid_room(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= 64
+        && room.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Replies with room.not_joined and returns false unless `session` is in `room`.
+pub fn require_joined(session: &Session, room: &str) -> bool {
+    if session.in_room(room) {
+        return true;
+    }
+    session.error(codes::ROOM_NOT_JOINED, &[("room", room)]);
+    false
+}
+
+/// Replies with the reason and returns false unless `session` may join `room`.
+fn may_join(state: &AppState, session: &Session, room: &str) -> bool {
+    if !valid_room(room) {
+        session.error(codes::ROOM_INVALID_NAME, &[("room", room)]);
+        return false;
+    }
+    if !session.may(room, ScopeAction::Read) {
+        session.error(codes::AUTH_SCOPE_DENIED, &[("action", ScopeAction::Read.as_str()), ("room", room)]);
+        return false;
+    }
+    if !state.rooms.may_join(&session.groups, room) {
+        session.error(codes::ROOM_JOIN_FORBIDDEN, &[("room", room)]);
+        return false;
+    }
+    if !session.in_room(room) && session.rooms().len() >= MAX_ROOMS {
+        session.error(codes::ROOM_LIMIT_REACHED, &[("max", &MAX_ROOMS.to_string())]);
+        return false;
+    }
+    true
+}
+
+pub struct RoomHandler;
+
+#[async_trait]
+impl EventHandler for RoomHandler {
+    async fn handle(&self, state: &AppState, session: &mut Session, event: ClientEvent) -> anyhow::Result<()> {
+        let changed = match event {
+            ClientEvent::JoinRoom { room } if may_join(state, session, &room) => {
+                if !state.policy.authorize(state, session, PolicyAction::JoinRoom, &room).await {
+                    return Ok(());
+                }
+                let changed = session.join(&room);
+                // read after joining, so nothing past it can be missed
+                let seq = state.current_seq(&room);
+                let e2ee_required = state.rooms.e2ee_required(&room);
+                session.reply(&ServerEvent::RoomJoined { room, seq, e2ee_required });
+                changed
+            }
+            ClientEvent::LeaveRoom { room } if require_joined(session, &room) => {
+                session.leave(&room);
+                session.reply(&ServerEvent::RoomLeft { room });
+                true
+            }
+            _ => false,
+        };
+        // record the new set and hand out a token that resumes it
+        if changed {
+            state.connections.sync_rooms(session);
+            if state.connections.get(session.id).is_some_and(|c| c.username.is_some()) {
+                session.reply(&ServerEvent::ResumeToken { token: resume::issue(state, session) });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct RoomInfo {
+    pub room: String,
+    /// Seq of the room's latest event; 0 before its first.
+    pub seq: u64,
+    /// Sends are refused (see freeze.rs); left out when false.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+}
+
+fn error(status: StatusCode, details: &str) -> Response {
+    (status, Json(ServerEvent::Error { details: details.into(), code: None })).into_response()
+}
+
+// GET /rooms/:room
+pub async fn room_info(State(state): State<Arc<AppState>>, headers: HeaderMap, Path(room): Path<String>) -> Response {
+    let auth = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok());
+    let Some(claims) = state.token_claims(auth) else {
+        return error(StatusCode::UNAUTHORIZED, "missing or invalid token");
+    };
+    if !valid_room(&room) {
+        return error(StatusCode::BAD_REQUEST, "invalid room name");
+    }
+    let readable = claims.scope.as_ref().is_none_or(|s| s.allows(&room, ScopeAction::Read));
+    if !readable || !state.rooms.may_join(&claims.groups, &room) {
+        return error(StatusCode::FORBIDDEN, "token may not join this room");
+    }
+    let seq = state.current_seq(&room);
+    let frozen = state.freezes.is_frozen(&room);
+    Json(RoomInfo { room, seq, frozen }).into_response()
+}
@@ -0,0 +1,112 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+
+use futures_util::FutureExt;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Message};
+
+use crate::state::{AppState, Session};
+
+//
+// CONNECTION SUPERVISION
+//
+// Every socket connection is its own task. A panic in a handler used to
+// end that task halfway: the connection stayed registered, counted in the
+// census and present in its rooms until the process restarted. Transports
+// now run each connection under `supervise`, which catches the panic, logs
+// it and counts it, and hold a `Cleanup` from registration on, whose Drop
+// does the teardown on every way out: a close, an error or a panic.
+//
+// After a panic the client is sent close code 1011 (internal error) so it
+// reconnects at once instead of waiting out its heartbeat;
+// GATEWAY_PANIC_CLOSE=0 drops the socket without a close frame. The
+// handlers' per-connection rate limit buckets cannot be removed one by one;
+// they go with the next limiter eviction, as after a normal close.
+//
+//   gateway_connection_panics_total{transport}
+//
+
+pub struct Supervisor {
+    close_on_panic: bool,
+    panics: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Supervisor {
+    pub fn from_env() -> Self {
+        Self {
+            close_on_panic: std::env::var("GATEWAY_PANIC_CLOSE").map_or(true, |v| v != "0"),
+            panics: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::from("# TYPE gateway_connection_panics_total counter\n");
+        for (transport, count) in self.panics.lock().unwrap().iter() {
+            let _ = writeln!(out, "gateway_connection_panics_total{{transport=\"{}\"}} {}", transport, count);
+        }
+        out
+    }
+}
+
+/// Runs one connection task of `transport`, catching a panic in it.
+pub async fn supervise(state: Arc<AppState>, transport: &'static str, connection: impl Future<Output = ()>) {
+    if let Err(panic) = AssertUnwindSafe(connection).catch_unwind().await {
+        println!("GATEWAY: {} connection task panicked: {}", transport, panic_message(&*panic));
+        *state.supervisor.panics.lock().unwrap().entry(transport).or_default() += 1;
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("(no message)", String::as_str),
+    }
+}
+
+/// Tears a registered connection down when dropped.
+pub struct Cleanup {
+    state: Arc<AppState>,
+    id: u64,
+    out: mpsc::UnboundedSender<Message>,
+    writer: AbortHandle,
+    forwarder: Option<AbortHandle>,
+}
+
+impl Cleanup {
+    /// For `session`, just registered, whose frames `writer` sends.
+    pub fn new(state: &Arc<AppState>, session: &Session, writer: AbortHandle) -> Self {
+        Self { state: state.clone(), id: session.id, out: session.out.clone(), writer, forwarder: None }
+    }
+
+    /// Stops the connection's room event forwarder as well.
+    pub fn forwarder(&mut self, forwarder: AbortHandle) {
+        self.forwarder = Some(forwarder);
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        let state = &self.state;
+        state.connections.unregister(self.id);
+        state.census.closed(self.id);
+        state.presence.closed(state, self.id);
+        if let Some(forwarder) = &self.forwarder {
+            forwarder.abort();
+        }
+        // on a normal way out the transport closes its writer itself
+        if !std::thread::panicking() {
+            return;
+        }
+        let close = CloseFrame { code: CloseCode::Error, reason: "internal error".into() };
+        // the writer stops after sending the close frame
+        if !state.supervisor.close_on_panic || self.out.send(Message::Close(Some(close))).is_err() {
+            self.writer.abort();
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use uchat_proto::jwt::DEFAULT_SECRET;
+
+use crate::state::AppState;
+
+//
+// STARTUP VALIDATION
+//
+// Defaults that are convenient on a laptop are unsafe in front of users.
+// Every start runs these checks and prints what fails; with
+// GATEWAY_PRODUCTION=1 any failure aborts startup instead:
+//
+//   - JWT_SECRET set, and at least 32 bytes
+//   - GATEWAY_ALLOWED_ORIGINS not empty (empty accepts any browser origin)
+//   - TLS: the WebSocket and HTTP listeners are plaintext, so TLS must be
+//     terminated in front (GATEWAY_BEHIND_TLS_PROXY=1); the QUIC listener
+//     needs a real certificate (GATEWAY_QUIC_CERT/KEY)
+//   - no event rate limit turned off in GATEWAY_RATE_LIMITS
+//
+
+const MIN_SECRET_BYTES: usize = 32;
+
+pub struct Check {
+    pub name: &'static str,
+    /// None when the check passed.
+    pub problem: Option<String>,
+}
+
+pub fn run(state: &AppState) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut check = |name, problem: Option<String>| checks.push(Check { name, problem });
+
+    check(
+        "jwt_secret",
+        if state.secret == DEFAULT_SECRET {
+            Some("JWT_SECRET is not set; tokens are signed with the built-in default".into())
+        } else if state.secret.len() < MIN_SECRET_BYTES {
+            Some(format!("JWT_SECRET is {} bytes; use at least {}", state.secret.len(), MIN_SECRET_BYTES))
+        } else {
+            None
+        },
+    );
+
+    check(
+        "allowed_origins",
+        state
+            .config
+            .allowed_origins
+            .is_empty()
+            .then(|| "GATEWAY_ALLOWED_ORIGINS is empty; WebSocket upgrades from any origin are accepted".into()),
+    );
+
+    let quic_cert = std::env::var_os("GATEWAY_QUIC_CERT").is_some() && std::env::var_os("GATEWAY_QUIC_KEY").is_some();
+    check(
+        "tls",
+        if !state.config.behind_tls_proxy {
+            Some("listeners are plaintext; terminate TLS in front and set GATEWAY_BEHIND_TLS_PROXY=1".into())
+        } else if cfg!(feature = "quic") && !quic_cert {
+            Some("QUIC would use a self-signed certificate; set GATEWAY_QUIC_CERT and GATEWAY_QUIC_KEY".into())
+        } else {
+            None
+        },
+    );
+
+    check(
+        "backplane",
+        (state.backplane.configured() && !cfg!(feature = "redis"))
+            .then(|| "GATEWAY_BACKPLANE_URL is set but this build has no Redis support; events stay local".into()),
+    );
+
+    let disabled: Vec<&str> =
+        state.config.rate_limits.iter().filter(|(_, limit)| limit.is_none()).map(|(kind, _)| kind.as_str()).collect();
+    check(
+        "rate_limits",
+        (!disabled.is_empty()).then(|| format!("rate limits are off for {}", disabled.join(", "))),
+    );
+
+    checks
+}
+
+/// Prints the report; Err with the number of failures when they should
+/// stop a production start.
+pub fn report(state: &AppState) -> Result<(), usize> {
+    let checks = run(state);
+    let failed = checks.iter().filter(|c| c.problem.is_some()).count();
+
+    if state.config.production {
+        println!("GATEWAY: production config check:");
+        for check in &checks {
+            match &check.problem {
+                None => println!("GATEWAY:   ok    {}", check.name),
+                Some(problem) => println!("GATEWAY:   FAIL  {}: {}", check.name, problem),
+            }
+        }
+        return if failed == 0 { Ok(()) } else { Err(failed) };
+    }
+
+    for check in &checks {
+        if let Some(problem) = &check.problem {
+            println!("GATEWAY: warning ({}): {}", check.name, problem);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1787 @@
+//
+// END-TO-END GATEWAY TESTS
+//
+// Each test boots its own gateway in-process (see support/mod.rs) and
+// talks to it over real sockets, so they run in CI with `cargo test` and
+// need no services started by hand.
+//
+
+mod support;
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::Message;
+
+use gateway_service::state::Session;
+use gateway_service::shutdown;
+use gateway_service::supervisor::{supervise, Cleanup};
+use uchat_core::i18n::{self, codes};
+use uchat_proto::api::Revocation;
+use uchat_proto::envelope::{Encryption, Envelope};
+use uchat_proto::events::{ClientEvent, PresenceStatus, ReceiptKind, Resume, ServerEvent};
+use uchat_proto::jwt::{
+    create_delegated_token, create_session_token, create_token, create_token_with_groups, Scope,
+    ScopeAction,
+};
+
+use support::{say, say_in, FakePolicy, FakeRevocations, Frame, Gateway, SECRET};
+
+#[tokio::test]
+async fn login_is_refused_and_tokens_sign_in_on_the_upgrade() {
+    let gw = Gateway::start().await;
+    let mut client = gw.connect().await;
+    client.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    client.send(&ClientEvent::Login { username: "root".into(), password: String::new() }).await;
+    let refused = client.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::AUTH_LOGIN_UNSUPPORTED));
+    client.send(&say("who am I")).await;
+    let Frame::Room(envelope) = client.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref from, .. } if from != "root"));
+
+    let mut alice = gw.sign_in("alice").await;
+    alice.send(&say("hi")).await;
+    let Frame::Room(envelope) = alice.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref from, .. } if from == "alice"));
+}
+
+#[tokio::test]
+async fn messages_reach_every_connection_in_seq_order() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    alice.send(&say("one")).await;
+    alice.send(&say("two")).await;
+
+    for client in [&mut alice, &mut bob] {
+        let mut seen = Vec::new();
+        while seen.len() < 2 {
+            if let Frame::Room(envelope) = client.expect(|f| matches!(f, Frame::Room(_))).await {
+                let ServerEvent::MessageBroadcast { from, content } = envelope.event else { continue };
+                assert_eq!(from, "alice");
+                assert_eq!(envelope.origin.as_ref().map(|o| o.instance_id.as_str()), Some("gw-test"));
+                assert_eq!(envelope.sender.as_deref(), Some("alice"));
+                assert!(envelope.ts.is_some());
+                seen.push((envelope.seq, content));
+            }
+        }
+        assert_eq!(seen, [(1, "one".to_string()), (2, "two".to_string())]);
+    }
+}
+
+/// Two instances on one Redis, each with a sender, publishing into the same
+/// room at once: both instances' clients see one numbering, gap-free.
+#[cfg(feature = "redis")]
+#[tokio::test]
+#[ignore = "needs a Redis at GATEWAY_TEST_REDIS_URL"]
+async fn two_instances_on_the_backplane_agree_on_room_order() {
+    let url = std::env::var("GATEWAY_TEST_REDIS_URL").expect("GATEWAY_TEST_REDIS_URL");
+    let prefix = format!("uchat-test-{}", std::process::id());
+    let a = Gateway::start_with(&[
+        ("GATEWAY_BACKPLANE_URL", &url),
+        ("GATEWAY_BACKPLANE_PREFIX", &prefix),
+        ("GATEWAY_INSTANCE_ID", "gw-a"),
+    ])
+    .await;
+    let b = Gateway::start_with(&[
+        ("GATEWAY_BACKPLANE_URL", &url),
+        ("GATEWAY_BACKPLANE_PREFIX", &prefix),
+        ("GATEWAY_INSTANCE_ID", "gw-b"),
+    ])
+    .await;
+    let mut alice = a.sign_in("alice").await;
+    let mut bob = b.sign_in("bob").await;
+
+    for i in 0..5 {
+        let (from_a, from_b) = (say(format!("a{}", i)), say(format!("b{}", i)));
+        tokio::join!(alice.send(&from_a), bob.send(&from_b));
+    }
+
+    let mut orders = Vec::new();
+    for client in [&mut alice, &mut bob] {
+        let mut seen = Vec::new();
+        while seen.len() < 10 {
+            if let Frame::Room(envelope) = client.expect(|f| matches!(f, Frame::Room(_))).await {
+                let ServerEvent::MessageBroadcast { content, .. } = envelope.event else { continue };
+                seen.push((envelope.seq, content));
+            }
+        }
+        let seqs: Vec<u64> = seen.iter().map(|(seq, _)| *seq).collect();
+        assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1), "gap or reorder in {:?}", seqs);
+        orders.push(seen);
+    }
+    assert_eq!(orders[0], orders[1]);
+}
+
+#[tokio::test]
+async fn events_over_the_rate_limit_are_refused() {
+    let gw = Gateway::start_with(&[("GATEWAY_RATE_LIMITS", "send_message=2/1m")]).await;
+    let mut alice = gw.sign_in("alice").await;
+
+    for n in 0..3 {
+        alice.send(&say(format!("m{}", n))).await;
+    }
+
+    let frame = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = frame else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::PROTOCOL_RATE_LIMITED));
+    assert_eq!(gw.state.room_seqs(true).unwrap().get("lobby"), Some(&2));
+}
+
+#[tokio::test]
+async fn a_busy_room_is_limited_across_connections() {
+    let gw = Gateway::start_with(&[
+        ("GATEWAY_ROOM_RATE_LIMIT", "2/1m"),
+        ("GATEWAY_ROOM_RATE_LIMITS", "random=off"),
+        ("GATEWAY_ADMINS", "root"),
+    ])
+    .await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    alice.send(&say("one")).await;
+    bob.send(&say("two")).await;
+    bob.send(&say("three")).await;
+    let frame = bob.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = frame else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::ROOM_RATE_LIMITED));
+
+    // admins are not counted, and a room with its own limit keeps to it
+    let mut root = gw.sign_in("root").await;
+    root.send(&say("announcement")).await;
+    root.expect(|f| matches!(f, Frame::Room(e) if e.room == "lobby" && e.seq == 3)).await;
+    alice.send(&ClientEvent::JoinRoom { room: "random".into() }).await;
+    alice.expect(|f| matches!(f, Frame::Event(ServerEvent::RoomJoined { .. }))).await;
+    for n in 0..3 {
+        alice.send(&say_in("random", format!("r{}", n))).await;
+    }
+    alice.expect(|f| matches!(f, Frame::Room(e) if e.room == "random" && e.seq == 3)).await;
+
+    let seqs = gw.state.room_seqs(true).unwrap();
+    assert_eq!((seqs.get("lobby"), seqs.get("random")), (Some(&3), Some(&3)));
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_room_rate_limited_total{room=\"lobby\"} 1"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn the_policy_engine_decides_after_the_built_in_checks() {
+    let policy = FakePolicy::start(|input| input["user"] == "mallory" && input["action"] == "send_message").await;
+    let gw = Gateway::start_with(&[("GATEWAY_POLICY_URL", policy.url.as_str()), ("GATEWAY_ADMINS", "root")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut mallory = gw.sign_in("mallory").await;
+
+    alice.send(&say("hi")).await;
+    alice.expect(|f| matches!(f, Frame::Room(e) if e.room == "lobby" && e.seq == 1)).await;
+    // the second refusal is the cached decision
+    for _ in 0..2 {
+        mallory.send(&say("spam")).await;
+        let frame = mallory.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+        let Frame::Event(ServerEvent::Error { code, .. }) = frame else { unreachable!() };
+        assert_eq!(code.as_deref(), Some(codes::AUTH_POLICY_DENIED));
+    }
+    let queries = policy.queries.lock().unwrap().clone();
+    assert_eq!(queries.len(), 2, "{:?}", queries);
+    assert_eq!((queries[1]["room"].as_str(), queries[1]["admin"].as_bool()), (Some("lobby"), Some(false)));
+
+    let audit = gw.state.audit.recent(10);
+    let deny = audit.iter().find(|e| e.action == "policy.deny").expect("the refusal is audited");
+    let deny = (deny.actor.as_str(), deny.target.as_str(), deny.detail.as_str());
+    assert_eq!(deny, ("mallory", "room:lobby", "send_message"));
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_policy_decisions_total{result=\"deny\"} 1"), "{}", metrics);
+    assert!(metrics.contains("gateway_policy_cache_hits_total 1"), "{}", metrics);
+
+    // a reload forgets the cached decisions, so the engine is asked again
+    let http = reqwest::Client::new();
+    let reload = |token: String| http.post(gw.url("/admin/policy/reload")).bearer_auth(token).send();
+    assert_eq!(reload(create_token(SECRET, "alice")).await.unwrap().status(), 403);
+    let body: serde_json::Value = reload(create_token(SECRET, "root")).await.unwrap().json().await.unwrap();
+    assert_eq!(body["forgotten"], 2);
+    mallory.send(&say("spam")).await;
+    mallory.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    assert_eq!(policy.queries.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn connections_without_hello_are_closed_with_a_policy_code() {
+    let gw = Gateway::start_with(&[("GATEWAY_HELLO_TIMEOUT_SECS", "1")]).await;
+    let mut client = gw.connect().await;
+
+    let Frame::Event(ServerEvent::Error { code, .. }) = client.recv().await else { panic!("expected an error") };
+    assert_eq!(code.as_deref(), Some(codes::PROTOCOL_HELLO_TIMEOUT));
+    match client.recv().await {
+        Frame::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Policy),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn upgrades_from_other_origins_are_refused() {
+    let gw = Gateway::start_with(&[("GATEWAY_ALLOWED_ORIGINS", "https://chat.example.com")]).await;
+    let upgrade = |origin: &'static str| {
+        let mut req = format!("ws://{}/ws", gw.ws).into_client_request().unwrap();
+        req.headers_mut().insert("origin", origin.parse().unwrap());
+        tokio_tungstenite::connect_async(req)
+    };
+
+    match upgrade("https://evil.example.com").await {
+        Err(tungstenite::Error::Http(resp)) => assert_eq!(resp.status(), 403),
+        other => panic!("expected 403, got {:?}", other.map(|(_, resp)| resp.status())),
+    }
+    assert!(upgrade("https://chat.example.com").await.is_ok());
+}
+
+#[tokio::test]
+async fn long_poll_connects_are_checked_like_upgrades() {
+    let gw = Gateway::start_with(&[
+        ("GATEWAY_ALLOWED_ORIGINS", "https://chat.example.com"),
+        ("GATEWAY_ADMINS", "root"),
+    ])
+    .await;
+    let http = reqwest::Client::new();
+    let connect = |gw: &Gateway, origin: &str| http.post(gw.url("/poll/connect")).header("origin", origin);
+    let status = |resp: reqwest::Result<reqwest::Response>| resp.unwrap().status().as_u16();
+    let listed = "https://chat.example.com";
+    let cookie = create_session_token(SECRET, "carol", Vec::new(), "s1", chrono::Duration::minutes(5));
+    let cookie = format!("uchat_session={}", cookie);
+
+    assert_eq!(status(connect(&gw, "https://evil.example.com").send().await), 403);
+    let forged = create_token("not-the-gateway-secret", "dave");
+    assert_eq!(status(connect(&gw, listed).bearer_auth(forged).send().await), 401);
+    assert_eq!(status(connect(&gw, listed).header("cookie", &cookie).send().await), 200);
+    // any page can make the browser send the cookie; only listed origins count
+    let open = Gateway::start().await;
+    assert_eq!(status(connect(&open, "https://evil.example.com").header("cookie", &cookie).send().await), 403);
+
+    // the session signs in with the token
+    assert_eq!(status(connect(&gw, listed).bearer_auth(gw.token("dave")).send().await), 200);
+    let admin = http.get(gw.url("/admin/connections")).bearer_auth(gw.token("root"));
+    let all: serde_json::Value = admin.send().await.unwrap().json().await.unwrap();
+    let mut users: Vec<_> = all["connections"].as_array().unwrap().iter().map(|c| c["username"].clone()).collect();
+    users.sort_by_key(|u| u.to_string());
+    assert_eq!(users, [serde_json::json!("carol"), serde_json::json!("dave")]);
+}
+
+#[tokio::test]
+async fn admin_api_checks_the_bearer_token() {
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let http = reqwest::Client::new();
+    let get = |token: Option<String>| {
+        let req = http.get(gw.url("/debug/state"));
+        match token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+        .send()
+    };
+
+    assert_eq!(get(None).await.unwrap().status(), 401);
+    assert_eq!(get(Some(create_token("not-the-gateway-secret", "root"))).await.unwrap().status(), 401);
+    assert_eq!(get(Some(gw.token("bob"))).await.unwrap().status(), 403);
+
+    let resp = get(Some(gw.token("root"))).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-gateway-instance"], "gw-test");
+    let snapshot: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(snapshot["instance"]["instance_id"], "gw-test");
+}
+
+#[tokio::test]
+async fn receipts_are_relayed_to_chat_service() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+    alice.send(&ClientEvent::Receipt { room: "lobby".into(), message_id: 7, kind: ReceiptKind::Read }).await;
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        if let Some(receipt) = gw.chat.receipts.lock().unwrap().first() {
+            assert_eq!(receipt["room"], "lobby");
+            assert_eq!(receipt["message_id"], 7);
+            assert_eq!(receipt["kind"], "read");
+            break;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "no receipt relayed within 5s");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn flagged_uploads_are_quarantined_and_refused() {
+    let scanner = axum::Router::new().route(
+        "/scan",
+        axum::routing::post(|body: axum::body::Bytes| async move {
+            match body.windows(5).any(|w| w == b"EICAR") {
+                true => axum::Json(serde_json::json!({ "clean": false, "signature": "Eicar-Test-Signature" })),
+                false => axum::Json(serde_json::json!({ "clean": true })),
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let scanner_url = format!("http://{}/scan", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, scanner).await });
+
+    let gw = Gateway::start_with(&[("GATEWAY_UPLOAD_SCANNER", &scanner_url)]).await;
+    let http = reqwest::Client::new();
+    let upload = |bytes: &[u8]| {
+        let mut body = b"--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"note.txt\"\r\n\r\n".to_vec();
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+        http.post(gw.url("/upload")).header("content-type", "multipart/form-data; boundary=b").body(body).send()
+    };
+
+    let resp = upload(b"hello").await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let stored: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(stored[0]["size"], 5);
+
+    let resp = upload(b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR").await.unwrap();
+    assert_eq!(resp.status(), 422);
+    let refused: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(refused["Error"]["code"], codes::UPLOAD_REJECTED);
+
+    let quarantined: Vec<_> = std::fs::read_dir(gw.dir.join("uploads/quarantine")).unwrap().collect();
+    assert_eq!(quarantined.len(), 1);
+    let key = format!("quarantine/{}", quarantined[0].as_ref().unwrap().file_name().to_str().unwrap());
+    assert_eq!(http.get(gw.url(&format!("/media/{}", key))).send().await.unwrap().status(), 404);
+    let audit = gw.state.audit.recent(10);
+    assert!(audit.iter().any(|e| e.action == "upload.quarantined" && e.target == key));
+}
+
+#[tokio::test]
+async fn delegated_tokens_stay_within_their_scope() {
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let scope = Scope { bot: "digest".into(), rooms: vec!["lobby".into()], actions: vec![ScopeAction::Read] };
+    let token = create_delegated_token(SECRET, "root", Vec::new(), "t1", scope, chrono::Duration::minutes(5));
+
+    let mut bot = gw.connect_with_token(&token).await;
+    bot.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    bot.send(&say("from the bot")).await;
+    let refused = bot.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::AUTH_SCOPE_DENIED));
+
+    let mut alice = gw.sign_in("alice").await;
+    alice.send(&say("hi bot")).await;
+    let Frame::Room(envelope) = bot.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref content, .. } if content == "hi bot"));
+
+    // the owner is an admin, but the admin API is not in the token's scope
+    let resp = reqwest::Client::new().get(gw.url("/debug/state")).bearer_auth(&token).send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+}
+
+#[tokio::test]
+async fn pipeline_stages_are_timed_and_sampled() {
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root"), ("GATEWAY_PIPELINE_SAMPLE_RATE", "1")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    alice.send(&say("hi")).await;
+    alice.expect(|f| matches!(f, Frame::Room(_))).await;
+
+    // the journal write, and with it the trace, finishes after the fan-out
+    let http = reqwest::Client::new();
+    let mut metrics = String::new();
+    for _ in 0..50 {
+        metrics = http.get(gw.url("/metrics")).send().await.unwrap().text().await.unwrap();
+        if metrics.contains("stage=\"persist\"} 1") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    for stage in ["receive", "validate", "authorize", "fanout", "persist"] {
+        let line = format!("gateway_pipeline_stage_seconds_count{{event=\"send_message\",stage=\"{}\"}} 1", stage);
+        assert!(metrics.contains(&line), "missing {}", line);
+    }
+
+    let resp = http.get(gw.url("/debug/pipeline")).bearer_auth(gw.token("root")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let samples: serde_json::Value = resp.json().await.unwrap();
+    let send = samples.as_array().unwrap().iter().find(|s| s["event"] == "send_message").unwrap();
+    let stages: Vec<_> = send["stages"].as_array().unwrap().iter().map(|s| s[0].as_str().unwrap()).collect();
+    assert_eq!(stages, ["receive", "validate", "authorize", "fanout", "persist"]);
+}
+
+#[tokio::test]
+async fn session_cookies_sign_in_from_allowed_origins_only() {
+    let gw = Gateway::start_with(&[("GATEWAY_ALLOWED_ORIGINS", "https://chat.example.com")]).await;
+    let cookie = create_session_token(SECRET, "carol", Vec::new(), "s1", chrono::Duration::minutes(5));
+    let upgrade = |origin: &'static str, header: &'static str, value: String| {
+        let mut req = format!("ws://{}/ws", gw.ws).into_client_request().unwrap();
+        req.headers_mut().insert("origin", origin.parse().unwrap());
+        req.headers_mut().insert(header, value.parse().unwrap());
+        tokio_tungstenite::connect_async(req)
+    };
+    let status = |result: Result<_, tungstenite::Error>| match result {
+        Err(tungstenite::Error::Http(resp)) => resp.status().as_u16(),
+        Err(e) => panic!("upgrade failed: {}", e),
+        Ok(_) => 101,
+    };
+
+    let cookie_header = || format!("theme=dark; uchat_session={}", cookie);
+    assert_eq!(status(upgrade("https://chat.example.com", "cookie", cookie_header()).await), 101);
+    // any page can make the browser send the cookie; only listed origins count
+    let open = Gateway::start().await;
+    let mut req = format!("ws://{}/ws", open.ws).into_client_request().unwrap();
+    req.headers_mut().insert("origin", "https://evil.example.com".parse().unwrap());
+    req.headers_mut().insert("cookie", cookie_header().parse().unwrap());
+    assert_eq!(status(tokio_tungstenite::connect_async(req).await), 403);
+    // a session token is no Bearer token
+    let bearer = format!("Bearer {}", cookie);
+    assert_eq!(status(upgrade("https://chat.example.com", "authorization", bearer).await), 401);
+    // nor does a plain token work as the cookie
+    let plain = format!("uchat_session={}", gw.token("carol"));
+    assert_eq!(status(upgrade("https://chat.example.com", "cookie", plain).await), 401);
+
+    let (ws, _) = upgrade("https://chat.example.com", "cookie", cookie_header()).await.unwrap();
+    let mut carol = support::Client { ws };
+    carol.expect(|f| matches!(f, Frame::Event(ServerEvent::Welcome { .. }))).await;
+    carol.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    carol.send(&say("signed in by cookie")).await;
+    let Frame::Room(envelope) = carol.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref from, .. } if from == "carol"));
+}
+
+#[tokio::test]
+async fn iot_clients_get_their_class_limits() {
+    let gw = Gateway::start_with(&[("GATEWAY_PROFILE_IOT", "max_message_bytes=300,send_message=1/1m")]).await;
+    let mut req = format!("ws://{}/ws", gw.ws).into_client_request().unwrap();
+    req.headers_mut().insert("sec-websocket-protocol", "chat.v1, uchat.iot".parse().unwrap());
+    req.headers_mut().insert("authorization", format!("Bearer {}", gw.token("sensor")).parse().unwrap());
+    let (ws, resp) = tokio_tungstenite::connect_async(req).await.unwrap();
+    assert_eq!(resp.headers()["sec-websocket-protocol"], "uchat.iot");
+    let mut device = support::Client { ws };
+    let Frame::Event(ServerEvent::Welcome { limits, .. }) = device.recv().await else { panic!("expected Welcome") };
+    assert_eq!((limits.max_message_bytes, limits.heartbeat_interval_secs), (300, 60));
+    assert_eq!(limits.rate_limits["send_message"].max, 1);
+    device.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    device.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+
+    // too big for the device: skipped, while browsers still get it
+    let mut bob = gw.sign_in("bob").await;
+    bob.send(&say("x".repeat(400))).await;
+    bob.send(&say("short")).await;
+    let Frame::Room(envelope) = device.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref content, .. } if content == "short"));
+    assert_eq!(envelope.seq, 2);
+    let Frame::Room(envelope) = bob.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert_eq!(envelope.seq, 1);
+
+    device.send(&say("21.5C")).await;
+    device.send(&say("21.6C")).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) =
+        device.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(code.as_deref(), Some(codes::PROTOCOL_RATE_LIMITED));
+    // bob's limits are the browser ones
+    bob.send(&say("again")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if e.seq == 4)).await;
+
+    device.ws.send(tungstenite::Message::Text("y".repeat(400))).await.unwrap();
+    let refused = device.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::MESSAGE_TOO_LARGE));
+    let closed = device.expect(|f| matches!(f, Frame::Close(_))).await;
+    assert!(matches!(closed, Frame::Close(Some(ref frame)) if frame.code == CloseCode::Size));
+}
+
+#[tokio::test]
+async fn oversized_binary_frames_are_refused_and_counted() {
+    let gw = Gateway::start_with(&[("GATEWAY_MAX_MESSAGE_BYTES", "1000")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    // under the limit a binary frame is ignored, over it the socket closes
+    alice.ws.send(tungstenite::Message::Binary(vec![0; 900])).await.unwrap();
+    alice.send(&say("still here")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if e.seq == 1)).await;
+    alice.ws.send(tungstenite::Message::Binary(vec![0; 1500])).await.unwrap();
+    let refused = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, details }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::MESSAGE_TOO_LARGE));
+    assert!(details.contains("1000"), "{}", details);
+    let closed = alice.expect(|f| matches!(f, Frame::Close(_))).await;
+    assert!(matches!(closed, Frame::Close(Some(ref frame)) if frame.code == CloseCode::Size));
+
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_messages_oversized_total 1"), "{}", metrics);
+    assert_eq!(gw.state.room_seqs(true).unwrap().get("lobby"), Some(&1));
+}
+
+#[tokio::test]
+async fn client_config_is_pushed_and_acked() {
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let http = reqwest::Client::new();
+    let put = |target: &str, settings: serde_json::Value, user: &str| {
+        http.put(gw.url(&format!("/client-config/{}", target))).bearer_auth(gw.token(user)).json(&settings).send()
+    };
+    let resp = put("class:iot", serde_json::json!({ "heartbeat_interval_secs": 120 }), "root").await.unwrap();
+    assert_eq!(resp.json::<serde_json::Value>().await.unwrap()["pushed"], 0);
+    assert_eq!(put("default", serde_json::json!({}), "bob").await.unwrap().status(), 403);
+    let bad = put("default", serde_json::json!({ "telemetry_level": "chatty" }), "root").await.unwrap();
+    assert_eq!(bad.status(), 400);
+
+    let mut req = format!("ws://{}/ws", gw.ws).into_client_request().unwrap();
+    req.headers_mut().insert("sec-websocket-protocol", "uchat.iot".parse().unwrap());
+    req.headers_mut().insert("authorization", format!("Bearer {}", gw.token("esp-1")).parse().unwrap());
+    let (ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    let mut device = support::Client { ws };
+    device.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    let update = |f: &Frame| matches!(f, Frame::Event(ServerEvent::ConfigUpdate { .. }));
+    let Frame::Event(ServerEvent::ConfigUpdate { version, settings }) = device.expect(update).await else {
+        unreachable!()
+    };
+    assert_eq!((version, settings.heartbeat_interval_secs), (1, Some(120)));
+
+    // a user layer adds to the class one, and reaches only that device
+    let _bob = gw.sign_in("bob").await;
+    let settings = serde_json::json!({ "telemetry_level": "warn", "features": { "ota": false } });
+    let resp = put("user:esp-1", settings, "root").await.unwrap();
+    assert_eq!(resp.json::<serde_json::Value>().await.unwrap()["pushed"], 1);
+    let Frame::Event(ServerEvent::ConfigUpdate { version, settings }) = device.expect(update).await else {
+        unreachable!()
+    };
+    assert_eq!(version, 2);
+    assert_eq!(settings.heartbeat_interval_secs, Some(120));
+    assert_eq!(settings.telemetry_level.as_deref(), Some("warn"));
+    assert!(!settings.features["ota"]);
+
+    device.send(&ClientEvent::ConfigAck { version: 2 }).await;
+    device.send(&ClientEvent::ConfigAck { version: 9 }).await;
+    device.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let status: serde_json::Value = http
+        .get(gw.url("/client-config/status"))
+        .bearer_auth(gw.token("root"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let row = |user: &str| status.as_array().unwrap().iter().find(|c| c["username"] == user).unwrap().clone();
+    assert_eq!((row("esp-1")["sent"].as_u64(), row("esp-1")["acked"].as_u64()), (Some(2), Some(2)));
+    assert_eq!(row("esp-1")["class"], "iot");
+    assert!(row("bob")["sent"].is_null());
+}
+
+#[tokio::test]
+async fn census_locates_users_across_instances() {
+    let a = Gateway::start_with(&[("GATEWAY_SERVICE_ACCOUNTS", "notifier")]).await;
+    let shared = a.dir.join("journal.db");
+    let b = Gateway::start_with(&[
+        ("GATEWAY_JOURNAL_PATH", shared.to_str().unwrap()),
+        ("GATEWAY_INSTANCE_ID", "gw-b"),
+        ("GATEWAY_ADVERTISE_URL", "http://gw-b.internal:7000/"),
+    ])
+    .await;
+    let http = reqwest::Client::new();
+    let get = |path: &str, user: &str| http.get(a.url(path)).bearer_auth(a.token(user)).send();
+
+    let _on_a = a.sign_in("alice").await;
+    let mut on_b = b.sign_in("alice").await;
+    let _bob = b.sign_in("bob").await;
+
+    assert_eq!(get("/census/users/alice", "bob").await.unwrap().status(), 403);
+    let census: serde_json::Value = get("/census/users/alice", "notifier").await.unwrap().json().await.unwrap();
+    assert_eq!(census["online"], true);
+    let where_: Vec<_> = census["connections"].as_array().unwrap().iter().map(|c| c["instance"].clone()).collect();
+    assert_eq!(where_, ["gw-b", "gw-test"]);
+    assert_eq!(census["connections"][0]["url"], "http://gw-b.internal:7000");
+
+    let instances: serde_json::Value = get("/census/instances", "notifier").await.unwrap().json().await.unwrap();
+    let counts: Vec<_> = instances.as_array().unwrap().iter().map(|i| i["connections"].as_u64().unwrap()).collect();
+    assert_eq!(counts, [2, 1]);
+
+    // service accounts are per instance; the one holding a connection delivers to it
+    let event = ServerEvent::Error { details: "ping from the notifier".into(), code: None };
+    let resp = http.post(b.url("/census/users/alice/events")).bearer_auth(b.token("notifier")).json(&event).send();
+    assert_eq!(resp.await.unwrap().status(), 403);
+    let resp = http.post(a.url("/census/users/alice/events")).bearer_auth(a.token("notifier")).json(&event).send();
+    assert_eq!(resp.await.unwrap().json::<serde_json::Value>().await.unwrap()["delivered"], 1);
+
+    on_b.ws.close(None).await.unwrap();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    loop {
+        let census: serde_json::Value = get("/census/users/alice", "notifier").await.unwrap().json().await.unwrap();
+        if census["connections"].as_array().unwrap().len() == 1 {
+            assert_eq!(census["connections"][0]["instance"], "gw-test");
+            break;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "closed connection still in the census after 5s");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn malformed_frames_get_a_structured_error() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+
+    async fn invalid(client: &mut support::Client, frame: &str) -> String {
+        client.ws.send(tungstenite::Message::Text(frame.into())).await.unwrap();
+        let frame = client.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+        let Frame::Event(ServerEvent::Error { code, details }) = frame else { unreachable!() };
+        assert_eq!(code.as_deref(), Some(codes::PROTOCOL_INVALID_EVENT));
+        details
+    }
+    assert_eq!(invalid(&mut alice, "{not json").await, "Invalid event: malformed JSON");
+    assert!(invalid(&mut alice, r#"{"Shout":{}}"#).await.starts_with("Invalid event: unknown variant `Shout`"));
+    assert!(invalid(&mut alice, r#"{"SendMessage":{}}"#).await.contains("missing field `content`"));
+
+    // the connection is still usable
+    alice.send(&say("still here")).await;
+    alice.expect(|f| matches!(f, Frame::Room(_))).await;
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_events_invalid_total 3"));
+}
+
+#[tokio::test]
+async fn connections_receive_the_rooms_they_joined() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    async fn error_code(client: &mut support::Client) -> Option<String> {
+        let frame = client.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+        let Frame::Event(ServerEvent::Error { code, .. }) = frame else { unreachable!() };
+        code
+    }
+
+    alice.send(&say_in("random", "too early")).await;
+    assert_eq!(error_code(&mut alice).await.as_deref(), Some(codes::ROOM_NOT_JOINED));
+    alice.send(&ClientEvent::JoinRoom { room: "Not A Room".into() }).await;
+    assert_eq!(error_code(&mut alice).await.as_deref(), Some(codes::ROOM_INVALID_NAME));
+
+    // joining is confirmed and hands out a token that resumes the new set
+    alice.send(&ClientEvent::JoinRoom { room: "random".into() }).await;
+    let joined = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::RoomJoined { .. }))).await;
+    let expected = ServerEvent::RoomJoined { room: "random".into(), seq: 0, e2ee_required: false };
+    assert!(matches!(joined, Frame::Event(ref e) if format!("{:?}", e) == format!("{:?}", expected)));
+    alice.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    alice.send(&say_in("random", "anyone?")).await;
+    let Frame::Room(envelope) = alice.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert_eq!((envelope.room.as_str(), envelope.seq), ("random", 1));
+
+    // bob is only in the lobby: the next room event he sees is from there
+    alice.send(&say_in("lobby", "hello lobby")).await;
+    let Frame::Room(envelope) = bob.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert_eq!(envelope.room, "lobby");
+
+    alice.send(&ClientEvent::LeaveRoom { room: "lobby".into() }).await;
+    alice.expect(|f| matches!(f, Frame::Event(ServerEvent::RoomLeft { room }) if room == "lobby")).await;
+    alice.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    bob.send(&say("still there?")).await;
+    alice.send(&say_in("random", "only here now")).await;
+    let Frame::Room(envelope) = alice.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert_eq!((envelope.room.as_str(), envelope.seq), ("random", 2));
+}
+
+#[tokio::test]
+async fn restricted_rooms_need_a_group_in_the_token() {
+    let gw = Gateway::start_with(&[("ROOM_ACL", "ops=sre,oncall")]).await;
+
+    async fn join(gw: &Gateway, token: &str) -> ServerEvent {
+        let mut client = gw.connect_with_token(token).await;
+        client.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+        client.send(&ClientEvent::JoinRoom { room: "ops".into() }).await;
+        let frame = client
+            .expect(|f| matches!(f, Frame::Event(ServerEvent::RoomJoined { .. } | ServerEvent::Error { .. })))
+            .await;
+        let Frame::Event(event) = frame else { unreachable!() };
+        event
+    }
+
+    let answer = join(&gw, &create_token_with_groups(SECRET, "alice", vec!["oncall".into()])).await;
+    assert!(matches!(answer, ServerEvent::RoomJoined { ref room, .. } if room == "ops"), "{:?}", answer);
+
+    let answer = join(&gw, &create_token_with_groups(SECRET, "bob", vec!["design".into()])).await;
+    let ServerEvent::Error { code, .. } = answer else { panic!("expected an error, got {:?}", answer) };
+    assert_eq!(code.as_deref(), Some(codes::ROOM_JOIN_FORBIDDEN));
+
+    // a delegated token also needs the room in its scope
+    let scope = Scope { bot: "digest".into(), rooms: vec!["lobby".into()], actions: vec![ScopeAction::Read] };
+    let token = create_delegated_token(SECRET, "carol", vec!["sre".into()], "t1", scope, chrono::Duration::minutes(5));
+    let ServerEvent::Error { code, .. } = join(&gw, &token).await else { panic!("expected an error") };
+    assert_eq!(code.as_deref(), Some(codes::AUTH_SCOPE_DENIED));
+}
+
+#[tokio::test]
+async fn room_events_are_coalesced_for_batching_classes() {
+    let gw = Gateway::start_with(&[("GATEWAY_PROFILE_BROWSER", "batch_ms=500")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    for n in 1..=3 {
+        alice.send(&say(format!("reading {}", n))).await;
+    }
+    let mut seqs = Vec::new();
+    let mut frames = 0;
+    while seqs.len() < 3 {
+        frames += 1;
+        match bob.expect(|f| matches!(f, Frame::Room(_) | Frame::Event(ServerEvent::Batch { .. }))).await {
+            Frame::Room(envelope) => seqs.push(envelope.seq),
+            Frame::Event(ServerEvent::Batch { events }) => seqs.extend(events.iter().map(|e| e.seq)),
+            _ => unreachable!(),
+        }
+    }
+    assert_eq!(seqs, [1, 2, 3]);
+    assert!(frames < 3, "nothing was coalesced");
+}
+
+#[tokio::test]
+async fn presence_follows_users_across_connections() {
+    let gw = Gateway::start().await;
+    let http = reqwest::Client::new();
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+    let mut bob_phone = gw.sign_in("bob").await;
+
+    async fn next_change(client: &mut support::Client) -> (String, PresenceStatus) {
+        let frame = client.expect(|f| matches!(f, Frame::Event(ServerEvent::PresenceChanged { .. }))).await;
+        let Frame::Event(ServerEvent::PresenceChanged { room, user, status }) = frame else { unreachable!() };
+        assert_eq!(room, "lobby");
+        (user, status)
+    }
+    // she signed in before her Hello, so she sees bob's only; his second
+    // connection changes nothing
+    assert_eq!(next_change(&mut alice).await, ("bob".into(), PresenceStatus::Online));
+
+    // away only once every connection is
+    bob.send(&ClientEvent::SetPresence { status: PresenceStatus::Away }).await;
+    bob_phone.send(&ClientEvent::SetPresence { status: PresenceStatus::Away }).await;
+    assert_eq!(next_change(&mut alice).await, ("bob".into(), PresenceStatus::Away));
+
+    let url = gw.url("/rooms/lobby/presence");
+    let members: serde_json::Value = http.get(&url).bearer_auth(gw.token("carol")).send().await.unwrap().json().await.unwrap();
+    assert_eq!(
+        members,
+        serde_json::json!([
+            { "user": "alice", "status": "online", "connections": 1 },
+            { "user": "bob", "status": "away", "connections": 2 },
+        ])
+    );
+    let resp = http.get(gw.url("/rooms/elsewhere/presence")).bearer_auth(gw.token("carol")).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(http.get(&url).send().await.unwrap().status(), 401);
+
+    bob.ws.close(None).await.unwrap();
+    bob_phone.ws.close(None).await.unwrap();
+    assert_eq!(next_change(&mut alice).await, ("bob".into(), PresenceStatus::Offline));
+}
+
+#[tokio::test]
+async fn draining_moves_clients_to_other_instances() {
+    let a = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let shared = a.dir.join("journal.db");
+    let b = Gateway::start_with(&[
+        ("GATEWAY_JOURNAL_PATH", shared.to_str().unwrap()),
+        ("GATEWAY_INSTANCE_ID", "gw-b"),
+        ("GATEWAY_PUBLIC_URL", "wss://gw-b.example.com/ws"),
+    ])
+    .await;
+    let http = reqwest::Client::new();
+    let mut alice = a.sign_in("alice").await;
+
+    let drain = http.post(a.url("/admin/drain")).bearer_auth(a.token("root"));
+    let resp = drain.json(&serde_json::json!({ "window_secs": 1 })).send().await.unwrap();
+    assert_eq!(resp.status(), 202);
+    let Frame::Event(ServerEvent::ReconnectSoon { within_secs, alternates }) =
+        alice.expect(|f| matches!(f, Frame::Event(ServerEvent::ReconnectSoon { .. }))).await
+    else {
+        unreachable!()
+    };
+    assert_eq!((within_secs, alternates), (1, vec!["wss://gw-b.example.com/ws".to_string()]));
+
+    assert_eq!(http.get(a.url("/ready")).send().await.unwrap().status(), 503);
+    assert_eq!(http.get(b.url("/ready")).send().await.unwrap().status(), 200);
+    let Err(tungstenite::Error::Http(refused)) = tokio_tungstenite::connect_async(format!("ws://{}/ws", a.ws)).await
+    else {
+        panic!("upgrade accepted while draining")
+    };
+    assert_eq!(refused.status(), 503);
+    assert_eq!(refused.headers()["x-uchat-alternates"], "wss://gw-b.example.com/ws");
+
+    // closed at its deadline if it did not leave by itself
+    let Frame::Close(Some(frame)) = alice.expect(|f| matches!(f, Frame::Close(_))).await else { unreachable!() };
+    assert_eq!(frame.code, CloseCode::Restart);
+
+    let status = http.get(a.url("/admin/drain")).bearer_auth(a.token("root")).send().await.unwrap();
+    let status: serde_json::Value = status.json().await.unwrap();
+    assert_eq!((&status["draining"], &status["notified"], &status["refused"]), (&true.into(), &1.into(), &1.into()));
+    let metrics = http.get(a.url("/metrics")).send().await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_draining 1"), "{}", metrics);
+
+    let cancel = http.delete(a.url("/admin/drain")).bearer_auth(a.token("root")).send().await.unwrap();
+    assert_eq!(cancel.status(), 200);
+    assert_eq!(http.get(a.url("/ready")).send().await.unwrap().status(), 200);
+    a.sign_in("bob").await;
+}
+
+#[tokio::test]
+async fn sdk_client_reconnects_and_resumes() {
+    use uchat_client::{Client, ConnectOptions, Event, Reconnect};
+
+    let gw = Gateway::start().await;
+    let reconnect = Reconnect { initial: Duration::from_millis(50), ..Reconnect::default() };
+    let options = ConnectOptions::new(format!("ws://{}/ws", gw.ws)).token(gw.token("alice"));
+    let (client, mut events) = Client::connect(options.reconnect(Some(reconnect))).await.unwrap();
+
+    let mut next = async || tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap().unwrap();
+    client.send_message("one").await.unwrap();
+    loop {
+        if let Event::Room(envelope) = next().await {
+            assert_eq!(envelope.seq, 1);
+            break;
+        }
+    }
+
+    // too big a message gets the socket closed; the client comes back and
+    // is replayed what it missed
+    let too_big = "x".repeat(client.limits().unwrap().max_message_bytes + 1);
+    client.send_message(too_big).await.unwrap();
+    loop {
+        if let Event::Disconnected { .. } = next().await {
+            break;
+        }
+    }
+    let mut bob = gw.sign_in("bob").await;
+    bob.send(&say("while you were away")).await;
+
+    let (mut resumed, mut missed) = (false, false);
+    while !(resumed && missed) {
+        match next().await {
+            Event::Reconnected { resumed: r, .. } => resumed = r,
+            Event::Room(envelope) => {
+                let ServerEvent::MessageBroadcast { from, content } = envelope.event else { continue };
+                assert_eq!((from.as_str(), content.as_str(), envelope.seq), ("bob", "while you were away", 2));
+                missed = true;
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    client.close();
+}
+
+#[tokio::test]
+async fn sends_are_acked_once_and_receipts_reach_the_sender() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    let send = |id: &str, content: &str| ClientEvent::SendMessage {
+        content: content.into(),
+        room: None,
+        client_msg_id: Some(id.into()),
+        receipts: true,
+        expires_in_secs: None,
+        encryption: None,
+    };
+    async fn ack(client: &mut support::Client) -> (String, u64) {
+        let frame = client.expect(|f| matches!(f, Frame::Event(ServerEvent::MessageAck { .. }))).await;
+        let Frame::Event(ServerEvent::MessageAck { client_msg_id, seq, .. }) = frame else { unreachable!() };
+        (client_msg_id, seq)
+    }
+    async fn message(client: &mut support::Client) -> (u64, bool, String) {
+        let frame = client
+            .expect(|f| matches!(f, Frame::Room(e) if matches!(e.event, ServerEvent::MessageBroadcast { .. })))
+            .await;
+        let Frame::Room(envelope) = frame else { unreachable!() };
+        let ServerEvent::MessageBroadcast { content, .. } = envelope.event else { unreachable!() };
+        (envelope.seq, envelope.receipts, content)
+    }
+
+    alice.send(&send("m-1", "hi")).await;
+    assert_eq!(ack(&mut alice).await, ("m-1".to_string(), 1));
+    assert_eq!(message(&mut bob).await, (1, true, "hi".to_string()));
+
+    // a resend is acked with the original seq and not posted again
+    alice.send(&send("m-1", "hi")).await;
+    assert_eq!(ack(&mut alice).await, ("m-1".to_string(), 1));
+    alice.send(&say("plain")).await;
+    assert_eq!(message(&mut bob).await, (2, false, "plain".to_string()));
+
+    // bob's first receipt is relayed, a repeat is not
+    bob.send(&ClientEvent::Delivered { room: "lobby".into(), seq: 1 }).await;
+    bob.send(&ClientEvent::Delivered { room: "lobby".into(), seq: 1 }).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::MessageDelivered { .. }))).await;
+    assert!(matches!(frame, Frame::Event(ServerEvent::MessageDelivered { ref room, seq: 1, ref to })
+        if room == "lobby" && to == "bob"));
+    alice.send(&send("m-2", "again")).await;
+    assert_eq!(ack(&mut alice).await, ("m-2".to_string(), 3));
+    bob.send(&ClientEvent::Delivered { room: "lobby".into(), seq: 3 }).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::MessageDelivered { .. }))).await;
+    assert!(matches!(frame, Frame::Event(ServerEvent::MessageDelivered { seq: 3, .. })));
+
+    alice.send(&send("", "no id")).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    assert!(matches!(frame, Frame::Event(ServerEvent::Error { code: Some(ref c), .. })
+        if c == codes::MESSAGE_CLIENT_ID_INVALID));
+}
+
+#[tokio::test]
+async fn resuming_replays_from_the_buffer_and_closes_the_stale_connection() {
+    let gw = Gateway::start().await;
+    let mut phone = gw.connect_with_token(&gw.token("alice")).await;
+    phone.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    let frame = phone.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    let Frame::Event(ServerEvent::ResumeToken { token }) = frame else { unreachable!() };
+
+    let mut bob = gw.sign_in("bob").await;
+    bob.send(&say("one")).await;
+    bob.send(&say("two")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if e.seq == 2)).await;
+
+    // the phone changed networks; its old socket is still open
+    let mut again = gw.connect().await;
+    let resume = Resume { token, last_seq: [("lobby".to_string(), 0)].into() };
+    again.send(&ClientEvent::Hello { locale: String::new(), resume: Some(resume) }).await;
+    for seq in [1, 2] {
+        let frame = again.expect(|f| matches!(f, Frame::Room(_))).await;
+        assert!(matches!(frame, Frame::Room(ref e) if e.seq == seq), "{:?}", frame);
+    }
+    let frame = again.expect(|f| matches!(f, Frame::Event(ServerEvent::Resumed { .. }))).await;
+    assert!(matches!(frame, Frame::Event(ServerEvent::Resumed { replayed: 2, complete: true, .. })));
+
+    loop {
+        match phone.recv().await {
+            Frame::Close(frame) => {
+                assert_eq!(frame.map(|f| f.code), Some(CloseCode::Away));
+                break;
+            }
+            _ => continue,
+        }
+    }
+
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_replay_gaps_total{source=\"buffer\"} 1"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn a_token_issued_on_join_still_replays_what_was_in_flight() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+    bob.send(&say("one")).await;
+    bob.send(&say("two")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if e.seq == 2)).await;
+
+    // alice joins and drops before her client has seen the lobby messages
+    alice.send(&ClientEvent::JoinRoom { room: "random".into() }).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    let Frame::Event(ServerEvent::ResumeToken { token }) = frame else { unreachable!() };
+
+    let mut again = gw.connect().await;
+    let resume = Resume { token, last_seq: Default::default() };
+    again.send(&ClientEvent::Hello { locale: String::new(), resume: Some(resume) }).await;
+    for seq in [1, 2] {
+        let frame = again.expect(|f| matches!(f, Frame::Room(_))).await;
+        assert!(matches!(frame, Frame::Room(ref e) if e.room == "lobby" && e.seq == seq), "{:?}", frame);
+    }
+    let frame = again.expect(|f| matches!(f, Frame::Event(ServerEvent::Resumed { .. }))).await;
+    assert!(matches!(frame, Frame::Event(ServerEvent::Resumed { replayed: 2, .. })));
+
+    // a token after the resume starts where the replay did
+    bob.send(&say("three")).await;
+    again.expect(|f| matches!(f, Frame::Room(e) if e.seq == 3)).await;
+    again.send(&ClientEvent::JoinRoom { room: "general".into() }).await;
+    let frame = again.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    let Frame::Event(ServerEvent::ResumeToken { token }) = frame else { unreachable!() };
+    let mut last = gw.connect().await;
+    let resume = Resume { token, last_seq: Default::default() };
+    last.send(&ClientEvent::Hello { locale: String::new(), resume: Some(resume) }).await;
+    let frame = last.expect(|f| matches!(f, Frame::Event(ServerEvent::Resumed { .. }))).await;
+    assert!(matches!(frame, Frame::Event(ServerEvent::Resumed { replayed: 3, .. })), "{:?}", frame);
+}
+
+#[tokio::test]
+async fn a_panicking_connection_is_cleaned_up_and_told_why() {
+    let gw = Gateway::start().await;
+    let (out, mut frames) = mpsc::unbounded_channel();
+    let session = Session::new(out, i18n::DEFAULT_LOCALE);
+    let id = session.id;
+    gw.state.connections.register(&session, "ws");
+    let writer = tokio::spawn(std::future::pending::<()>());
+
+    let state = gw.state.clone();
+    supervise(gw.state.clone(), "ws", async move {
+        let _cleanup = Cleanup::new(&state, &session, writer.abort_handle());
+        panic!("a handler bug");
+    })
+    .await;
+
+    assert!(gw.state.connections.get(id).is_none());
+    let Some(Message::Close(Some(frame))) = frames.recv().await else { panic!("no close frame") };
+    assert_eq!(frame.code, CloseCode::Error);
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_connection_panics_total{transport=\"ws\"} 1"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn room_info_reports_the_current_seq_to_those_who_may_join() {
+    let gw = Gateway::start_with(&[("ROOM_ACL", "ops=sre")]).await;
+    let mut bob = gw.sign_in("bob").await;
+    bob.send(&say("one")).await;
+    bob.send(&say("two")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if e.seq == 2)).await;
+
+    let http = reqwest::Client::new();
+    let info: serde_json::Value =
+        http.get(gw.url("/rooms/lobby")).bearer_auth(gw.token("carol")).send().await.unwrap().json().await.unwrap();
+    assert_eq!(info, serde_json::json!({ "room": "lobby", "seq": 2 }));
+
+    let status = |path: &'static str, token: Option<String>| {
+        let req = http.get(gw.url(path));
+        let req = match token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        };
+        async move { req.send().await.unwrap().status().as_u16() }
+    };
+    assert_eq!(status("/rooms/lobby", None).await, 401);
+    assert_eq!(status("/rooms/Not%20A%20Room", Some(gw.token("carol"))).await, 400);
+    assert_eq!(status("/rooms/ops", Some(gw.token("carol"))).await, 403);
+    let sre = create_token_with_groups(SECRET, "dave", vec!["sre".into()]);
+    let info: serde_json::Value =
+        http.get(gw.url("/rooms/ops")).bearer_auth(sre).send().await.unwrap().json().await.unwrap();
+    assert_eq!(info["seq"], 0);
+}
+
+#[tokio::test]
+async fn shutdown_flushes_events_then_closes_every_connection() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+    bob.send(&say("last words")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if e.seq == 1)).await;
+
+    let state = gw.state.clone();
+    let shutdown = tokio::spawn(async move { shutdown::run(&state).await });
+
+    alice.expect(|f| matches!(f, Frame::Room(e) if e.seq == 1)).await;
+    let Frame::Close(Some(frame)) = alice.expect(|f| matches!(f, Frame::Close(_))).await else { unreachable!() };
+    assert_eq!((frame.code, frame.reason.as_ref()), (CloseCode::Restart, "server restarting"));
+    drop((alice, bob));
+    tokio::time::timeout(Duration::from_secs(5), shutdown).await.expect("shutdown did not finish").unwrap();
+    assert!(gw.state.connections.snapshot(true).unwrap().is_empty());
+
+    assert_eq!(reqwest::get(gw.url("/ready")).await.unwrap().status(), 503);
+    let Err(tungstenite::Error::Http(refused)) = tokio_tungstenite::connect_async(format!("ws://{}/ws", gw.ws)).await
+    else {
+        panic!("upgrade accepted while shutting down")
+    };
+    assert_eq!(refused.status(), 503);
+}
+
+#[tokio::test]
+async fn admins_list_connections_and_kick_one() {
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let _alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+    let http = reqwest::Client::new();
+    let get = |path: &str, user: &str| http.get(gw.url(path)).bearer_auth(gw.token(user)).send();
+
+    assert_eq!(get("/admin/connections", "bob").await.unwrap().status(), 403);
+    let all: serde_json::Value = get("/admin/connections", "root").await.unwrap().json().await.unwrap();
+    assert_eq!(all["total"], 2);
+    let only_bob: serde_json::Value = get("/admin/connections?user=bob", "root").await.unwrap().json().await.unwrap();
+    assert_eq!(only_bob["total"], 1);
+    let conn = &only_bob["connections"][0];
+    assert_eq!((&conn["username"], &conn["transport"]), (&"bob".into(), &"ws".into()));
+
+    let rooms: serde_json::Value = get("/admin/rooms", "root").await.unwrap().json().await.unwrap();
+    let lobby = rooms["rooms"].as_array().unwrap().iter().find(|r| r["room"] == "lobby").unwrap();
+    assert_eq!((&lobby["connections"], &lobby["users"]), (&2.into(), &2.into()));
+
+    let kick = format!("/admin/connections/{}/kick", conn["id"]);
+    let reason = serde_json::json!({ "reason": "spam" });
+    let resp = http.post(gw.url(&kick)).bearer_auth(gw.token("root")).json(&reason).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let Frame::Close(Some(frame)) = bob.expect(|f| matches!(f, Frame::Close(_))).await else { unreachable!() };
+    assert_eq!((frame.code, frame.reason.as_ref()), (CloseCode::Policy, "kicked by an admin"));
+    drop(bob);
+    let again = http.post(gw.url(&kick)).bearer_auth(gw.token("root")).send().await.unwrap();
+    assert_eq!(again.status(), 404);
+
+    // a long-poll session is ended rather than sent a close frame
+    let poll: serde_json::Value = http.post(gw.url("/poll/connect")).send().await.unwrap().json().await.unwrap();
+    let all: serde_json::Value = get("/admin/connections", "root").await.unwrap().json().await.unwrap();
+    let polling = all["connections"].as_array().unwrap().iter().find(|c| c["transport"] == "poll").unwrap();
+    let kick = format!("/admin/connections/{}/kick", polling["id"]);
+    assert_eq!(http.post(gw.url(&kick)).bearer_auth(gw.token("root")).send().await.unwrap().status(), 200);
+    let events = gw.url(&format!("/poll/events?session={}", poll["session"].as_str().unwrap()));
+    assert_eq!(http.get(events).send().await.unwrap().status(), 410);
+}
+
+#[tokio::test]
+async fn a_frozen_room_refuses_sends_but_still_gets_system_events() {
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut root = gw.sign_in("root").await;
+    let http = reqwest::Client::new();
+    let freeze = |user: &str| http.put(gw.url("/rooms/lobby/freeze")).bearer_auth(gw.token(user));
+
+    assert_eq!(freeze("alice").send().await.unwrap().status(), 403);
+    let resp = freeze("root").json(&serde_json::json!({ "reason": "incident 42" })).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let status: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!((&status["frozen"], &status["propagated"]), (&true.into(), &true.into()));
+    assert_eq!(gw.chat.freezes.lock().unwrap().clone(), vec![("PUT".to_string(), "lobby".to_string())]);
+
+    let frozen = |f: &Frame| matches!(f, Frame::Room(e) if matches!(e.event, ServerEvent::RoomFreezeChanged { .. }));
+    let Frame::Room(event) = alice.expect(frozen).await else { unreachable!() };
+    assert_eq!(event.sender, None);
+    let ServerEvent::RoomFreezeChanged { frozen: true, reason } = event.event else { unreachable!() };
+    assert_eq!(reason.as_deref(), Some("incident 42"));
+
+    // admins included
+    for client in [&mut alice, &mut root] {
+        client.send(&say("anyone there?")).await;
+        let Frame::Event(ServerEvent::Error { code, .. }) =
+            client.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await
+        else {
+            unreachable!()
+        };
+        assert_eq!(code.as_deref(), Some(codes::ROOM_FROZEN));
+    }
+    let info: serde_json::Value =
+        http.get(gw.url("/rooms/lobby")).bearer_auth(gw.token("alice")).send().await.unwrap().json().await.unwrap();
+    assert_eq!(info["frozen"], true);
+
+    let thaw = http.delete(gw.url("/rooms/lobby/freeze")).bearer_auth(gw.token("root")).send().await.unwrap();
+    assert_eq!(thaw.status(), 200);
+    alice.expect(frozen).await;
+    alice.send(&say("back")).await;
+    alice.expect(|f| matches!(f, Frame::Room(e) if matches!(e.event, ServerEvent::MessageBroadcast { .. }))).await;
+    assert_eq!(gw.chat.freezes.lock().unwrap().last().unwrap().0, "DELETE");
+}
+
+#[tokio::test]
+async fn slash_commands_answer_with_catalog_codes_and_post_only_where_allowed() {
+    let bots = "down=http://127.0.0.1:9/down";
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root"), ("GATEWAY_BOT_COMMANDS", bots)]).await;
+    let mut alice = gw.sign_in("alice").await;
+    let http = reqwest::Client::new();
+    let freeze = http.put(gw.url("/rooms/lobby/freeze")).bearer_auth(gw.token("root"));
+    assert_eq!(freeze.json(&serde_json::json!({})).send().await.unwrap().status(), 200);
+
+    // a frozen room takes neither room replies nor bot callouts
+    let cases = [
+        ("/nosuch", codes::COMMAND_UNKNOWN),
+        ("/announce hello", codes::COMMAND_FORBIDDEN),
+        ("/me waves", codes::ROOM_FROZEN),
+        ("/down", codes::ROOM_FROZEN),
+    ];
+    for (typed, expected) in cases {
+        alice.send(&say(typed)).await;
+        let Frame::Event(ServerEvent::Error { code, .. }) =
+            alice.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await
+        else {
+            unreachable!()
+        };
+        assert_eq!(code.as_deref(), Some(expected), "{}", typed);
+    }
+    alice.send(&say("/help")).await;
+    alice.expect(|f| matches!(f, Frame::Event(ServerEvent::CommandResult { .. }))).await;
+
+    let thaw = http.delete(gw.url("/rooms/lobby/freeze")).bearer_auth(gw.token("root")).send().await.unwrap();
+    assert_eq!(thaw.status(), 200);
+    alice.send(&say("/down")).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) =
+        alice.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(code.as_deref(), Some(codes::COMMAND_UNAVAILABLE));
+    alice.send(&say("/me waves")).await;
+    let Frame::Room(event) =
+        alice.expect(|f| matches!(f, Frame::Room(e) if matches!(e.event, ServerEvent::MessageBroadcast { .. }))).await
+    else {
+        unreachable!()
+    };
+    assert_eq!(event.room, "lobby");
+    let ServerEvent::MessageBroadcast { content, .. } = event.event else { unreachable!() };
+    assert_eq!(content, "* alice waves");
+}
+
+/// The JSON in one binary frame of a compressed connection (see
+/// compression.rs); `stream` carries the window from frame to frame.
+fn inflate(stream: &mut flate2::Decompress, data: &[u8]) -> serde_json::Value {
+    let input = [data, &[0x00, 0x00, 0xff, 0xff]].concat();
+    let mut out = Vec::with_capacity(64 * 1024);
+    let before = stream.total_in();
+    stream.decompress_vec(&input, &mut out, flate2::FlushDecompress::Sync).unwrap();
+    assert_eq!(stream.total_in() - before, input.len() as u64);
+    serde_json::from_slice(&out).unwrap()
+}
+
+#[tokio::test]
+async fn connections_that_opt_in_get_compressed_frames() {
+    let gw = Gateway::start_with(&[("GATEWAY_COMPRESSION_MAX_CONTEXTS", "1")]).await;
+    let url = format!("ws://{}/ws?compress=deflate", gw.ws);
+    let (mut ws, resp) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    assert_eq!(resp.headers().get("x-uchat-compression").unwrap(), "deflate");
+    // the only context is taken, so the next connection is served as usual
+    let (plain, resp) = tokio_tungstenite::connect_async(&url).await.unwrap();
+    assert!(resp.headers().get("x-uchat-compression").is_none());
+    support::Client { ws: plain }.expect(|f| matches!(f, Frame::Event(ServerEvent::Welcome { .. }))).await;
+
+    let mut stream = flate2::Decompress::new(false);
+    let mut next = |msg: Option<Result<Message, tungstenite::Error>>| match msg {
+        Some(Ok(Message::Binary(data))) => (data.len(), inflate(&mut stream, &data)),
+        Some(Ok(Message::Text(text))) => (0, serde_json::from_str(&text).unwrap()),
+        other => panic!("unexpected frame {:?}", other),
+    };
+    let (_, welcome) = next(ws.next().await);
+    assert!(matches!(serde_json::from_value(welcome), Ok(ServerEvent::Welcome { .. })));
+
+    let say = |text: &str| Message::Text(serde_json::to_string(&support::say(text)).unwrap());
+    let hello = ClientEvent::Hello { locale: String::new(), resume: None };
+    ws.send(Message::Text(serde_json::to_string(&hello).unwrap())).await.unwrap();
+    let long = "the quick brown fox jumps over the lazy dog, ".repeat(8);
+    ws.send(say(&long)).await.unwrap();
+    ws.send(say(&long)).await.unwrap();
+    let mut sizes = Vec::new();
+    while sizes.len() < 2 {
+        let (size, value) = next(ws.next().await);
+        if value["room"] == "lobby" {
+            assert!(value.to_string().contains(&long), "{}", value);
+            sizes.push(size);
+        }
+    }
+    // the second copy is mostly a reference back into the shared window
+    assert!(sizes[0] > 0 && sizes[1] < sizes[0] / 2, "{:?}", sizes);
+
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_compression_contexts 1"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn cbor_clients_send_and_receive_binary_events() {
+    let gw = Gateway::start().await;
+    let mut req = format!("ws://{}/ws?compress=deflate", gw.ws).into_client_request().unwrap();
+    req.headers_mut().insert("sec-websocket-protocol", "uchat.iot, unhidra-cbor".parse().unwrap());
+    req.headers_mut().insert("authorization", format!("Bearer {}", gw.token("esp")).parse().unwrap());
+    let (mut ws, resp) = tokio_tungstenite::connect_async(req).await.unwrap();
+    assert_eq!(resp.headers().get("sec-websocket-protocol").unwrap(), "unhidra-cbor");
+    // binary frames are CBOR here, so they are not compressed as well
+    assert!(resp.headers().get("x-uchat-compression").is_none());
+
+    let send = |event: ClientEvent| {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(&event, &mut data).unwrap();
+        Message::Binary(data)
+    };
+    let Some(Ok(Message::Binary(data))) = ws.next().await else { panic!("expected a CBOR frame") };
+    let welcome: serde_json::Value = ciborium::de::from_reader(&data[..]).unwrap();
+    let Ok(ServerEvent::Welcome { limits, .. }) = serde_json::from_value(welcome) else { panic!("expected Welcome") };
+    // the class offered alongside still applies
+    assert_eq!(limits.max_message_bytes, 4096);
+
+    ws.send(send(ClientEvent::Hello { locale: String::new(), resume: None })).await.unwrap();
+    ws.send(send(say("from a small device"))).await.unwrap();
+    // a frame that is CBOR but no event is answered like malformed JSON
+    ws.send(Message::Binary(vec![0x01])).await.unwrap();
+    let mut saw = (false, false);
+    while saw != (true, true) {
+        let Some(Ok(Message::Binary(data))) = ws.next().await else { panic!("expected a CBOR frame") };
+        let value: serde_json::Value = ciborium::de::from_reader(&data[..]).unwrap();
+        if value["room"] == "lobby" {
+            let envelope: uchat_proto::envelope::Envelope = serde_json::from_value(value).unwrap();
+            assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref content, .. }
+                if content == "from a small device"));
+            saw.0 = true;
+        } else if let Ok(ServerEvent::Error { code, .. }) = serde_json::from_value(value) {
+            assert_eq!(code.as_deref(), Some(codes::PROTOCOL_INVALID_EVENT));
+            saw.1 = true;
+        }
+    }
+}
+
+fn say_expiring(content: &str, expires_in_secs: u64) -> ClientEvent {
+    ClientEvent::SendMessage {
+        content: content.into(),
+        room: None,
+        client_msg_id: None,
+        receipts: true,
+        expires_in_secs: Some(expires_in_secs),
+        encryption: None,
+    }
+}
+
+#[tokio::test]
+async fn expired_messages_are_fanned_out_as_tombstones() {
+    let gw = Gateway::start_with(&[("GATEWAY_MESSAGE_MAX_TTL_SECS", "60")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    for secs in [0, 61] {
+        bob.send(&say_expiring("too long", secs)).await;
+        let frame = bob.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+        let Frame::Event(ServerEvent::Error { code, .. }) = frame else { unreachable!() };
+        assert_eq!(code.as_deref(), Some(codes::MESSAGE_TTL_INVALID));
+    }
+
+    bob.send(&say_expiring("soon gone", 60)).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Room(_))).await;
+    let Frame::Room(envelope) = frame else { unreachable!() };
+    assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref content, .. } if content == "soon gone"));
+    assert!(envelope.expires_at.is_some_and(|at| at > chrono::Utc::now().timestamp_millis()));
+
+    // what a receiver far enough behind sees: the message expired before
+    // its delivery task got to it
+    let expired = Some(chrono::Utc::now().timestamp_millis() - 1);
+    gw.state.broadcast_message("lobby", "bob", "already gone".into(), true, expired, None).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Room(_))).await;
+    let Frame::Room(envelope) = frame else { unreachable!() };
+    assert_eq!(envelope.seq, 2);
+    assert!(matches!(envelope.event, ServerEvent::MessageExpired {}), "{:?}", envelope);
+    assert!(!envelope.receipts);
+
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_messages_expired_total{path=\"fanout\"} 2"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn resuming_after_expiry_replays_tombstones_in_place() {
+    // from the replay buffer, then from the journal
+    for buffer in ["256", "0"] {
+        let gw = Gateway::start_with(&[("GATEWAY_REPLAY_BUFFER", buffer)]).await;
+        let mut phone = gw.connect_with_token(&gw.token("alice")).await;
+        phone.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+        let frame = phone.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+        let Frame::Event(ServerEvent::ResumeToken { token }) = frame else { unreachable!() };
+        drop(phone);
+
+        let mut bob = gw.sign_in("bob").await;
+        bob.send(&say_expiring("soon gone", 1)).await;
+        bob.send(&say("stays")).await;
+        bob.expect(|f| matches!(f, Frame::Room(e) if e.seq == 2)).await;
+
+        // expired, but most likely not swept from the buffer or journal yet
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let mut again = gw.connect().await;
+        let resume = Resume { token, last_seq: [("lobby".to_string(), 0)].into() };
+        again.send(&ClientEvent::Hello { locale: String::new(), resume: Some(resume) }).await;
+
+        let frame = again.expect(|f| matches!(f, Frame::Room(_))).await;
+        let Frame::Room(envelope) = frame else { unreachable!() };
+        assert_eq!(envelope.seq, 1, "buffer {}", buffer);
+        assert!(matches!(envelope.event, ServerEvent::MessageExpired {}), "buffer {}: {:?}", buffer, envelope);
+        let frame = again.expect(|f| matches!(f, Frame::Room(_))).await;
+        let Frame::Room(envelope) = frame else { unreachable!() };
+        assert_eq!(envelope.seq, 2, "buffer {}", buffer);
+        assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref content, .. } if content == "stays"));
+        let frame = again.expect(|f| matches!(f, Frame::Event(ServerEvent::Resumed { .. }))).await;
+        assert!(matches!(frame, Frame::Event(ServerEvent::Resumed { replayed: 2, complete: true, .. })));
+
+        // once swept, nothing stored holds the content either
+        gw.state.expiry.sweep(&gw.state);
+        let gap = gw.state.journal.since("lobby", 0, 2, 10);
+        assert!(matches!(gap.events[0].event, ServerEvent::MessageExpired {}), "buffer {}", buffer);
+        assert!(gw.state.recent.lock().unwrap().iter().all(|m| m.content != "soon gone"));
+    }
+}
+
+#[tokio::test]
+async fn connections_that_keep_falling_behind_are_closed() {
+    let gw = Gateway::start_with(&[("GATEWAY_SLOW_CONSUMER_QUEUE", "4"), ("GATEWAY_SLOW_CONSUMER_LAGS", "2")]).await;
+    let mut slow = gw.sign_in("slow").await;
+
+    // far more than the socket buffers hold, while the client reads nothing
+    let content = "x".repeat(32 * 1024);
+    for seq in 1..=1000 {
+        let event = ServerEvent::MessageBroadcast { from: "bob".into(), content: content.clone() };
+        let envelope = Envelope {
+            room: "lobby".into(),
+            seq,
+            origin: None,
+            sender: None,
+            ts: None,
+            receipts: false,
+            expires_at: None,
+            encryption: None,
+            event,
+        };
+        let _ = gw.state.tx.send(envelope);
+        tokio::task::yield_now().await;
+    }
+
+    let mut told = false;
+    let close = loop {
+        match slow.recv().await {
+            Frame::Event(ServerEvent::Error { code, .. }) => {
+                assert_eq!(code.as_deref(), Some(codes::PROTOCOL_TOO_SLOW));
+                told = true;
+            }
+            Frame::Close(frame) => break frame,
+            _ => continue,
+        }
+    };
+    assert!(told, "closed without the notice");
+    assert_eq!(close.map(|f| f.code), Some(CloseCode::Again));
+
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_slow_consumer_evictions_total 1"), "{}", metrics);
+    assert!(!metrics.contains("gateway_delivery_missed_events_total 0"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn connections_that_stop_answering_are_reaped() {
+    let gw = Gateway::start_with(&[("GATEWAY_IDLE_TIMEOUT_SECS", "3"), ("GATEWAY_HEARTBEAT_SECS", "1")]).await;
+    let mut gone = gw.sign_in("gone").await;
+    let mut listening = gw.sign_in("listening").await;
+
+    // reading answers the pings; `gone` is never read, so it never does
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while let Ok(msg) = tokio::time::timeout_at(deadline, listening.ws.next()).await {
+        assert!(matches!(msg, Some(Ok(_))), "listening client dropped: {:?}", msg);
+    }
+
+    // the close frame itself may be lost: answering the pings queued ahead
+    // of it resets the closed socket
+    gone.expect(|f| matches!(f, Frame::Close(_))).await;
+    assert!(!gw.state.connections.is_online("gone"));
+    assert!(gw.state.connections.is_online("listening"));
+    listening.send(&say("still here")).await;
+    listening.expect(|f| matches!(f, Frame::Room(e) if matches!(&e.event, ServerEvent::MessageBroadcast { .. }))).await;
+
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_idle_reaped_total 1"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn e2ee_rooms_refuse_plaintext() {
+    let gw = Gateway::start_with(&[("GATEWAY_E2EE_ROOMS", "board")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    let mut bob = gw.sign_in("bob").await;
+    for client in [&mut alice, &mut bob] {
+        client.send(&ClientEvent::JoinRoom { room: "board".into() }).await;
+        let joined = client.expect(|f| matches!(f, Frame::Event(ServerEvent::RoomJoined { .. }))).await;
+        assert!(matches!(joined, Frame::Event(ServerEvent::RoomJoined { e2ee_required: true, .. })));
+    }
+
+    alice.send(&say_in("board", "the merger closes friday")).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, details }) = frame else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::MESSAGE_E2EE_REQUIRED));
+    assert!(details.contains("board"), "{}", details);
+
+    // passed on untouched, even when the ciphertext looks like a command
+    let encryption = Encryption { alg: "megolm.v1.aes-sha2".into(), key_id: "board-1".into(), nonce: None };
+    alice
+        .send(&ClientEvent::SendMessage {
+            content: "/bWVyZ2VyIGNsb3NlcyBmcmlkYXk=".into(),
+            room: Some("board".into()),
+            client_msg_id: None,
+            receipts: false,
+            expires_in_secs: None,
+            encryption: Some(encryption.clone()),
+        })
+        .await;
+    let Frame::Room(envelope) = bob.expect(|f| matches!(f, Frame::Room(e) if e.room == "board")).await else {
+        unreachable!()
+    };
+    assert_eq!(envelope.seq, 1, "the refused message was published");
+    assert_eq!(envelope.encryption, Some(encryption));
+    let ServerEvent::MessageBroadcast { content, .. } = envelope.event else { panic!("{:?}", envelope.event) };
+    assert_eq!(content, "/bWVyZ2VyIGNsb3NlcyBmcmlkYXk=");
+
+    // other rooms are unaffected
+    alice.send(&say("plain as day")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if e.room == "lobby" && e.encryption.is_none())).await;
+}
+
+#[tokio::test]
+async fn tokens_refresh_without_reconnecting() {
+    let gw = Gateway::start_with(&[("ROOM_ACL", "ops=sre")]).await;
+    let mut alice = gw.connect_with_token(&create_token_with_groups(SECRET, "alice", vec!["sre".into()])).await;
+    alice.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    alice.send(&ClientEvent::JoinRoom { room: "ops".into() }).await;
+    alice.expect(|f| matches!(f, Frame::Event(ServerEvent::RoomJoined { .. }))).await;
+
+    let error = |f: &Frame| matches!(f, Frame::Event(ServerEvent::Error { .. }));
+    let refused = [("not-a-token".to_string(), codes::AUTH_INVALID_TOKEN), (gw.token("bob"), codes::AUTH_FORBIDDEN)];
+    for (token, expected) in refused {
+        alice.send(&ClientEvent::RefreshToken { token }).await;
+        let Frame::Event(ServerEvent::Error { code, .. }) = alice.expect(error).await else { unreachable!() };
+        assert_eq!(code.as_deref(), Some(expected));
+    }
+
+    // the new token lacks the group ops needs, so the room is left
+    alice.send(&ClientEvent::RefreshToken { token: gw.token("alice") }).await;
+    let refreshed = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::TokenRefreshed { .. }))).await;
+    let Frame::Event(ServerEvent::TokenRefreshed { exp }) = refreshed else { unreachable!() };
+    assert!(exp as i64 > chrono::Utc::now().timestamp());
+    let left = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::RoomLeft { .. }))).await;
+    assert!(matches!(left, Frame::Event(ServerEvent::RoomLeft { ref room }) if room == "ops"), "{:?}", left);
+
+    // still signed in as alice, in the lobby
+    alice.send(&say("still here")).await;
+    let echoed = alice.expect(|f| matches!(f, Frame::Room(_))).await;
+    let Frame::Room(envelope) = echoed else { unreachable!() };
+    assert_eq!(envelope.room, "lobby");
+    assert!(matches!(envelope.event, ServerEvent::MessageBroadcast { ref from, .. } if from == "alice"));
+}
+
+#[tokio::test]
+async fn revoked_tokens_lose_their_connections() {
+    let feed = FakeRevocations::start().await;
+    let gw = Gateway::start_with(&[
+        ("GATEWAY_REVOCATIONS_URL", feed.url.as_str()),
+        ("GATEWAY_REVOCATIONS_TOKEN", "feed-token"),
+        ("GATEWAY_REVOCATIONS_INTERVAL_SECS", "1"),
+    ])
+    .await;
+    let token = gw.token("alice");
+    let mut alice = gw.connect_with_token(&token).await;
+    let scope = Scope { bot: "digest".into(), rooms: vec!["lobby".into()], actions: vec![ScopeAction::Read] };
+    let delegated = create_delegated_token(SECRET, "carol", Vec::new(), "t1", scope, chrono::Duration::minutes(5));
+    let mut digest = gw.connect_with_token(&delegated).await;
+    let mut bob = gw.sign_in("bob").await;
+
+    let now = chrono::Utc::now().timestamp() as usize;
+    feed.revocations.lock().unwrap().extend([
+        Revocation::User { id: 1, sub: "alice".into(), before: now + 1 },
+        Revocation::Token { id: 2, jti: "t1".into(), exp: now + 300 },
+    ]);
+    for client in [&mut alice, &mut digest] {
+        let error = client.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+        let Frame::Event(ServerEvent::Error { code, .. }) = error else { unreachable!() };
+        assert_eq!(code.as_deref(), Some(codes::AUTH_TOKEN_REVOKED));
+        let Frame::Close(Some(frame)) = client.expect(|f| matches!(f, Frame::Close(_))).await else { unreachable!() };
+        assert_eq!((frame.code, frame.reason.as_ref()), (CloseCode::Policy, "token revoked"));
+    }
+
+    // the old tokens no longer open a connection
+    for token in [token, delegated] {
+        let mut req = format!("ws://{}/ws", gw.ws).into_client_request().unwrap();
+        req.headers_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        let refused = tokio_tungstenite::connect_async(req).await;
+        assert!(matches!(refused, Err(tungstenite::Error::Http(ref resp)) if resp.status() == 401), "{:?}", refused);
+    }
+
+    bob.send(&say("still here")).await;
+    bob.expect(|f| matches!(f, Frame::Room(e) if matches!(&e.event, ServerEvent::MessageBroadcast { .. }))).await;
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_revoked_connections_total 2"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn delegated_sessions_resume_within_their_scope() {
+    let gw = Gateway::start().await;
+    let scope = Scope { bot: "digest".into(), rooms: vec!["lobby".into()], actions: vec![ScopeAction::Read] };
+    let token = create_delegated_token(SECRET, "root", Vec::new(), "t1", scope, chrono::Duration::minutes(5));
+    let mut bot = gw.connect_with_token(&token).await;
+    bot.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+    let frame = bot.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    let Frame::Event(ServerEvent::ResumeToken { token: resume_token }) = frame else { unreachable!() };
+    drop(bot);
+
+    // resumed on a socket without a token, the session is still the bot
+    let mut again = gw.connect().await;
+    let resume = Resume { token: resume_token, last_seq: Default::default() };
+    again.send(&ClientEvent::Hello { locale: String::new(), resume: Some(resume) }).await;
+    again.expect(|f| matches!(f, Frame::Event(ServerEvent::Resumed { .. }))).await;
+    let frame = again.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    let Frame::Event(ServerEvent::ResumeToken { token: resume_token }) = frame else { unreachable!() };
+    again.send(&say("as root")).await;
+    let refused = again.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::AUTH_SCOPE_DENIED));
+    again.send(&ClientEvent::JoinRoom { room: "ops".into() }).await;
+    let refused = again.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::AUTH_SCOPE_DENIED));
+
+    // and once the token is revoked, its resume tokens are worthless
+    let exp = chrono::Utc::now().timestamp() as usize + 300;
+    gw.state.revocations.apply(&[Revocation::Token { id: 1, jti: "t1".into(), exp }]);
+    let mut late = gw.connect().await;
+    let resume = Resume { token: resume_token, last_seq: Default::default() };
+    late.send(&ClientEvent::Hello { locale: String::new(), resume: Some(resume) }).await;
+    let refused = late.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::PROTOCOL_RESUME_INVALID));
+}
+
+#[tokio::test]
+async fn resume_tokens_do_not_outlive_a_revocation() {
+    let gw = Gateway::start().await;
+    let mut alice = gw.sign_in("alice").await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let before = chrono::Utc::now().timestamp() as usize;
+    gw.state.revocations.apply(&[Revocation::User { id: 1, sub: "alice".into(), before }]);
+
+    // still open until the next sweep; a join now hands out a fresh token
+    alice.send(&ClientEvent::JoinRoom { room: "ops".into() }).await;
+    let frame = alice.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+    let Frame::Event(ServerEvent::ResumeToken { token }) = frame else { unreachable!() };
+    let mut again = gw.connect().await;
+    let resume = Resume { token, last_seq: Default::default() };
+    again.send(&ClientEvent::Hello { locale: String::new(), resume: Some(resume) }).await;
+    let refused = again.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    let Frame::Event(ServerEvent::Error { code, .. }) = refused else { unreachable!() };
+    assert_eq!(code.as_deref(), Some(codes::PROTOCOL_RESUME_INVALID));
+
+    // the open connection goes by the token it signed in with
+    assert_eq!(gw.state.revocations.sweep(&gw.state), 1);
+}
+
+#[tokio::test]
+async fn signed_device_messages_are_accepted_once() {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64;
+    use base64::Engine;
+    use hmac::{Hmac, Mac};
+
+    let gw = Gateway::start_with(&[("GATEWAY_DEVICE_KEYS", "thermo-1=key1")]).await;
+    let mut device = gw.sign_in("thermo-1").await;
+    let mut bob = gw.sign_in("bob").await;
+
+    let signed = |key: &str, nonce: &str, ts: i64, content: &str| {
+        let body = serde_json::to_string(&say(content)).unwrap();
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(key.as_bytes()).unwrap();
+        mac.update(format!("{}.{}.{}", nonce, ts, body).as_bytes());
+        let sig = B64.encode(mac.finalize().into_bytes());
+        ClientEvent::Signed { body, nonce: nonce.into(), ts, sig }
+    };
+    async fn rejection(client: &mut support::Client) -> String {
+        let frame = client.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+        let Frame::Event(ServerEvent::Error { code, details }) = frame else { unreachable!() };
+        assert_eq!(code.as_deref(), Some(codes::PROTOCOL_SIGNED_REJECTED));
+        details
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    device.send(&signed("key1", "n1", now, "21.5C")).await;
+    let Frame::Room(envelope) = bob.expect(|f| matches!(f, Frame::Room(_))).await else { unreachable!() };
+    assert_eq!((envelope.sender.as_deref(), envelope.seq), (Some("thermo-1"), 1));
+
+    // a replay, a stale timestamp and a wrong key are all refused
+    device.send(&signed("key1", "n1", now, "21.5C")).await;
+    assert!(rejection(&mut device).await.ends_with("duplicate"));
+    device.send(&signed("key1", "n2", now - 600, "21.5C")).await;
+    assert!(rejection(&mut device).await.ends_with("stale"));
+    device.send(&signed("key2", "n3", now, "99.9C")).await;
+    assert!(rejection(&mut device).await.ends_with("bad_signature"));
+
+    // only devices with a key may sign
+    bob.send(&signed("key1", "n4", now, "hi")).await;
+    assert!(rejection(&mut bob).await.ends_with("unknown_device"));
+
+    assert_eq!(gw.state.room_seqs(true).unwrap().get("lobby"), Some(&1));
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_signed_messages_total{outcome=\"accepted\"} 1"));
+    assert!(metrics.contains("gateway_signed_messages_total{outcome=\"duplicate\"} 1"));
+}
+
+#[tokio::test]
+async fn device_commands_are_held_until_acknowledged() {
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let http = reqwest::Client::new();
+    let commands = gw.url("/devices/thermo-1/commands");
+    let enqueue = |token: String, command: &str| {
+        let body = serde_json::json!({ "command": command, "payload": { "target": 19 } });
+        http.post(&commands).bearer_auth(token).json(&body).send()
+    };
+    let pending = || async {
+        let resp = http.get(&commands).bearer_auth(gw.token("root")).send().await.unwrap();
+        resp.json::<Vec<serde_json::Value>>().await.unwrap()
+    };
+    async fn command(client: &mut support::Client) -> (String, String) {
+        let frame = client.expect(|f| matches!(f, Frame::Event(ServerEvent::DeviceCommand { .. }))).await;
+        let Frame::Event(ServerEvent::DeviceCommand { id, command, .. }) = frame else { unreachable!() };
+        (id, command)
+    }
+
+    assert_eq!(enqueue(gw.token("alice"), "set_target").await.unwrap().status().as_u16(), 403);
+
+    // queued while the device sleeps, and pushed on every wake until acked
+    let resp = enqueue(gw.token("root"), "set_target").await.unwrap();
+    assert_eq!(resp.status().as_u16(), 202);
+    let queued: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(queued["delivered"], false);
+    let id = queued["command"]["id"].as_str().unwrap().to_string();
+
+    let mut device = gw.sign_in("thermo-1").await;
+    assert_eq!(command(&mut device).await, (id.clone(), "set_target".to_string()));
+    drop(device);
+    let mut device = gw.sign_in("thermo-1").await;
+    assert_eq!(command(&mut device).await.0, id);
+    assert_eq!(pending().await[0]["attempts"], 2);
+
+    device.send(&ClientEvent::CommandAck { id: id.clone() }).await;
+    device.send(&ClientEvent::CommandAck { id }).await;
+    device.expect(|f| matches!(f, Frame::Event(ServerEvent::Error { .. }))).await;
+    assert!(pending().await.is_empty());
+
+    // an online device gets it at once; an admin may also take it back
+    let queued: serde_json::Value = enqueue(gw.token("root"), "reboot").await.unwrap().json().await.unwrap();
+    assert_eq!(queued["delivered"], true);
+    let (id, _) = command(&mut device).await;
+    let cancel = || http.delete(format!("{}/{}", commands, id)).bearer_auth(gw.token("root")).send();
+    assert_eq!(cancel().await.unwrap().status().as_u16(), 204);
+    assert_eq!(cancel().await.unwrap().status().as_u16(), 404);
+    assert!(pending().await.is_empty());
+}
+
+#[tokio::test]
+async fn reports_are_claimed_and_resolved_by_one_moderator() {
+    use serde_json::{json, Value};
+
+    let gw = Gateway::start_with(&[("GATEWAY_ADMINS", "root")]).await;
+    let mut alice = gw.sign_in("alice").await;
+    for n in 1..=7 {
+        alice.send(&say(format!("m{}", n))).await;
+    }
+    alice.expect(|f| matches!(f, Frame::Room(e) if e.seq == 7)).await;
+
+    let http = reqwest::Client::new();
+    let moderator = create_token_with_groups(SECRET, "mod", vec!["moderators".into()]);
+    let report = |token: String, body: Value| http.post(gw.url("/reports")).bearer_auth(token).json(&body).send();
+    let status = |resp: reqwest::Result<reqwest::Response>| resp.unwrap().status().as_u16();
+
+    assert_eq!(status(http.post(gw.url("/reports")).json(&json!({ "reason": "x" })).send().await), 401);
+    assert_eq!(status(report(gw.token("bob"), json!({ "message_id": 4, "reason": " " })).await), 400);
+    assert_eq!(status(report(gw.token("bob"), json!({ "reason": "spam" })).await), 400);
+    assert_eq!(status(report(gw.token("bob"), json!({ "message_id": 99, "reason": "spam" })).await), 404);
+
+    // a reported message names its sender and keeps what surrounded it
+    let resp = report(gw.token("bob"), json!({ "message_id": 4, "reason": "spam" })).await.unwrap();
+    assert_eq!(resp.status().as_u16(), 201);
+    let created: Value = resp.json().await.unwrap();
+    assert_eq!((created["reported_user"].as_str(), created["status"].as_str()), (Some("alice"), Some("open")));
+    let context = created["context"].as_array().unwrap();
+    let context: Vec<&str> = context.iter().map(|m| m["content"].as_str().unwrap()).collect();
+    assert_eq!(context, ["m1", "m2", "m3", "m4", "m5", "m6", "m7"]);
+    let id = created["id"].as_u64().unwrap();
+
+    // only moderators see the queue, and a claim keeps others off it
+    let list = |token: String| http.get(gw.url("/reports?status=open")).bearer_auth(token).send();
+    assert_eq!(status(list(gw.token("bob")).await), 403);
+    let open: Vec<Value> = list(moderator.clone()).await.unwrap().json().await.unwrap();
+    assert_eq!(open.len(), 1);
+    let claim = |token: String| http.post(gw.url(&format!("/reports/{}/claim", id))).bearer_auth(token).send();
+    let resolve = |token: String| {
+        let body = json!({ "resolution": "warned", "note": "first offence" });
+        http.post(gw.url(&format!("/reports/{}/resolve", id))).bearer_auth(token).json(&body).send()
+    };
+    assert_eq!(status(claim(moderator.clone()).await), 200);
+    assert_eq!(status(claim(gw.token("root")).await), 409);
+    assert_eq!(status(resolve(gw.token("root")).await), 409);
+    let resolved: Value = resolve(moderator.clone()).await.unwrap().json().await.unwrap();
+    assert_eq!((resolved["status"].as_str(), resolved["resolution"].as_str()), (Some("resolved"), Some("warned")));
+    assert_eq!(status(resolve(moderator.clone()).await), 409);
+    assert!(list(moderator).await.unwrap().json::<Vec<Value>>().await.unwrap().is_empty());
+
+    let audit = gw.state.audit.recent(10);
+    let target = format!("report:{}", id);
+    let actions: Vec<&str> = audit.iter().rev().filter(|e| e.target == target).map(|e| e.action.as_str()).collect();
+    assert_eq!(actions, ["report.created", "report.claimed", "report.resolved"]);
+    let metrics = reqwest::get(gw.url("/metrics")).await.unwrap().text().await.unwrap();
+    assert!(metrics.contains("gateway_reports_total{room=\"lobby\"} 1"));
+}
@@ -0,0 +1,326 @@
+//
+// IN-PROCESS GATEWAY HARNESS
+//
+// `Gateway::start` builds an AppState from the environment, like the
+// binary, with a test JWT secret, a throwaway directory for the journal
+// and uploads, and a fake chat-service, then serves WebSocket and HTTP on
+// ephemeral localhost ports inside the test's runtime. Extra settings are passed as
+// environment overrides (`("GATEWAY_RATE_LIMITS", "send_message=2/1m")`).
+//
+// Config is read from the process environment, so construction is
+// serialized: each gateway sees only its own overrides.
+//
+
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::routing::{any, get, post};
+use axum::{Json, Router};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::protocol::frame::CloseFrame;
+use tungstenite::protocol::Message;
+
+use gateway_service::server;
+use gateway_service::state::AppState;
+use uchat_proto::api::{Revocation, RevocationFeed};
+use uchat_proto::envelope::Envelope;
+use uchat_proto::events::{ClientEvent, ServerEvent};
+use uchat_proto::jwt::create_token;
+
+pub const SECRET: &str = "integration-test-secret-0123456789abcdef";
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+static ENV: Mutex<()> = Mutex::new(());
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Revocation feed entries, oldest first.
+type Feed = Arc<Mutex<Vec<Revocation>>>;
+
+/// Freeze calls, as (method, room).
+type Calls = Arc<Mutex<Vec<(String, String)>>>;
+
+/// Stands in for chat-service: records what the gateway relays to it.
+pub struct FakeChat {
+    pub url: String,
+    pub receipts: Arc<Mutex<Vec<Value>>>,
+    pub freezes: Calls,
+}
+
+impl FakeChat {
+    async fn start() -> Self {
+        let receipts = Arc::new(Mutex::new(Vec::new()));
+        let freezes = Arc::new(Mutex::new(Vec::new()));
+        let freeze = Router::new()
+            .route(
+                "/rooms/:room/freeze",
+                any(|State(seen): State<Calls>, method: Method, Path(room): Path<String>| async move {
+                    seen.lock().unwrap().push((method.to_string(), room));
+                    "{}"
+                }),
+            )
+            .with_state(freezes.clone());
+        let app = Router::new()
+            .route(
+                "/receipts",
+                post(|State(seen): State<Arc<Mutex<Vec<Value>>>>, Json(body): Json<Value>| async move {
+                    seen.lock().unwrap().push(body);
+                    "ok"
+                }),
+            )
+            .with_state(receipts.clone())
+            .merge(freeze);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { url, receipts, freezes }
+    }
+}
+
+/// Stands in for an OPA sidecar: records the input of every query and
+/// allows all of them but those `deny` matches.
+pub struct FakePolicy {
+    pub url: String,
+    pub queries: Arc<Mutex<Vec<Value>>>,
+}
+
+impl FakePolicy {
+    pub async fn start(deny: fn(&Value) -> bool) -> Self {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route(
+                "/v1/data/uchat/allow",
+                post(move |State(seen): State<Arc<Mutex<Vec<Value>>>>, Json(body): Json<Value>| async move {
+                    let input = body["input"].clone();
+                    let allow = !deny(&input);
+                    seen.lock().unwrap().push(input);
+                    Json(json!({ "result": allow }))
+                }),
+            )
+            .with_state(queries.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/data/uchat/allow", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { url, queries }
+    }
+}
+
+/// Stands in for auth-api's revocation feed: serves what is pushed onto
+/// `revocations`, to callers with the bearer token "feed-token".
+pub struct FakeRevocations {
+    pub url: String,
+    pub revocations: Feed,
+}
+
+impl FakeRevocations {
+    pub async fn start() -> Self {
+        let revocations = Feed::default();
+        let app = Router::new()
+            .route(
+                "/revocations",
+                get(|State(list): State<Feed>, headers: HeaderMap, Query(q): Query<Value>| async move {
+                    if headers.get("authorization").and_then(|v| v.to_str().ok()) != Some("Bearer feed-token") {
+                        return Err(StatusCode::UNAUTHORIZED);
+                    }
+                    let after: u64 = q["after"].as_str().and_then(|a| a.parse().ok()).unwrap_or(0);
+                    let revocations = list.lock().unwrap().iter().filter(|r| r.id() > after).cloned().collect();
+                    Ok(Json(RevocationFeed { revocations }))
+                }),
+            )
+            .with_state(revocations.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/revocations", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Self { url, revocations }
+    }
+}
+
+pub struct Gateway {
+    pub state: Arc<AppState>,
+    pub ws: SocketAddr,
+    pub http: SocketAddr,
+    pub chat: FakeChat,
+    pub dir: PathBuf,
+}
+
+impl Gateway {
+    pub async fn start() -> Self {
+        Self::start_with(&[]).await
+    }
+
+    pub async fn start_with(overrides: &[(&str, &str)]) -> Self {
+        let chat = FakeChat::start().await;
+        let dir = std::env::temp_dir().join(format!(
+            "gateway-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let journal = dir.join("journal.db");
+        let uploads = dir.join("uploads");
+
+        let state = {
+            let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let mut vars = vec![
+                ("JWT_SECRET", SECRET),
+                ("GATEWAY_JOURNAL_PATH", journal.to_str().unwrap()),
+                ("UPLOAD_DIR", uploads.to_str().unwrap()),
+                ("CHAT_SERVICE_URL", chat.url.as_str()),
+                ("GATEWAY_INSTANCE_ID", "gw-test"),
+            ];
+            vars.extend_from_slice(overrides);
+            for (name, value) in &vars {
+                std::env::set_var(name, value);
+            }
+            let state = Arc::new(AppState::from_env());
+            for (name, _) in &vars {
+                std::env::remove_var(name);
+            }
+            state
+        };
+        server::spawn_jobs(&state);
+
+        let ws_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws = ws_listener.local_addr().unwrap();
+        tokio::spawn(server::serve_ws(ws_listener, state.clone()));
+
+        let http_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = http_listener.local_addr().unwrap();
+        let app = server::router(state.clone());
+        tokio::spawn(async move { axum::serve(http_listener, app).await });
+
+        Self { state, ws, http, chat, dir }
+    }
+
+    /// A token for `user` signed with the test secret.
+    pub fn token(&self, user: &str) -> String {
+        create_token(SECRET, user)
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.http, path)
+    }
+
+    /// Opens a WebSocket and reads the Welcome frame.
+    pub async fn connect(&self) -> Client {
+        self.connect_request(format!("ws://{}/ws", self.ws).into_client_request().unwrap()).await
+    }
+
+    /// Like `connect`, signed in with `token` on the upgrade.
+    pub async fn connect_with_token(&self, token: &str) -> Client {
+        let mut req = format!("ws://{}/ws", self.ws).into_client_request().unwrap();
+        req.headers_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        self.connect_request(req).await
+    }
+
+    async fn connect_request(&self, req: tungstenite::handshake::client::Request) -> Client {
+        let (ws, _) = connect_async(req).await.unwrap();
+        let mut client = Client { ws };
+        match client.recv().await {
+            Frame::Event(ServerEvent::Welcome { .. }) => client,
+            other => panic!("expected Welcome, got {:?}", other),
+        }
+    }
+
+    /// Connects signed in as `user`, sends Hello and reads the resume
+    /// token that answers it.
+    pub async fn sign_in(&self, user: &str) -> Client {
+        let mut client = self.connect_with_token(&self.token(user)).await;
+        client.send(&ClientEvent::Hello { locale: String::new(), resume: None }).await;
+        client.expect(|f| matches!(f, Frame::Event(ServerEvent::ResumeToken { .. }))).await;
+        client
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Frame {
+    Event(ServerEvent),
+    Room(Envelope),
+    Close(Option<CloseFrame<'static>>),
+}
+
+pub struct Client {
+    pub ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+/// A lobby message, asking for no ack or receipts.
+pub fn say(content: impl Into<String>) -> ClientEvent {
+    ClientEvent::SendMessage {
+        content: content.into(),
+        room: None,
+        client_msg_id: None,
+        receipts: false,
+        expires_in_secs: None,
+        encryption: None,
+    }
+}
+
+/// Like `say`, in `room`.
+pub fn say_in(room: &str, content: impl Into<String>) -> ClientEvent {
+    ClientEvent::SendMessage {
+        content: content.into(),
+        room: Some(room.into()),
+        client_msg_id: None,
+        receipts: false,
+        expires_in_secs: None,
+        encryption: None,
+    }
+}
+
+impl Client {
+    pub async fn send(&mut self, event: &ClientEvent) {
+        self.ws.send(Message::Text(serde_json::to_string(event).unwrap())).await.unwrap();
+    }
+
+    /// The next frame; pings are skipped, and a dropped connection reads
+    /// as a Close without a frame.
+    pub async fn recv(&mut self) -> Frame {
+        loop {
+            let msg = tokio::time::timeout(RECV_TIMEOUT, self.ws.next()).await.expect("no frame within 5s");
+            match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let value: Value = serde_json::from_str(&text).unwrap();
+                    return if value.get("room").is_some() && value.get("seq").is_some() {
+                        Frame::Room(serde_json::from_value(value).unwrap())
+                    } else {
+                        Frame::Event(serde_json::from_value(value).unwrap())
+                    };
+                }
+                Some(Ok(Message::Close(frame))) => return Frame::Close(frame.map(CloseFrame::into_owned)),
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return Frame::Close(None),
+            }
+        }
+    }
+
+    /// Reads frames until one matches.
+    pub async fn expect(&mut self, matches: impl Fn(&Frame) -> bool) -> Frame {
+        loop {
+            let frame = self.recv().await;
+            if matches(&frame) {
+                return frame;
+            }
+            if let Frame::Close(_) = frame {
+                panic!("connection closed while waiting: {:?}", frame);
+            }
+        }
+    }
+}
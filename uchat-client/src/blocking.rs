@@ -0,0 +1,56 @@
+//! A blocking wrapper around `Client`, for tools without an async runtime.
+//! It runs the client on a small runtime of its own.
+
+use std::time::Duration;
+
+use tokio::runtime::Runtime;
+
+use crate::{Client, ClientEvent, ConnectOptions, Event, Events, Result};
+
+pub struct BlockingClient {
+    rt: Runtime,
+    client: Client,
+    events: Events,
+}
+
+impl BlockingClient {
+    pub fn connect(options: ConnectOptions) -> Result<Self> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .expect("failed to start the client runtime");
+        let (client, events) = rt.block_on(Client::connect(options))?;
+        Ok(Self { rt, client, events })
+    }
+
+    /// The async client, e.g. to hand to code that has a runtime after all.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn refresh_token(&self, token: &str) -> Result<u64> {
+        self.rt.block_on(self.client.refresh_token(token))
+    }
+
+    pub fn send(&self, event: ClientEvent) -> Result<()> {
+        self.rt.block_on(self.client.send(event))
+    }
+
+    pub fn send_message(&self, content: impl Into<String>) -> Result<()> {
+        self.rt.block_on(self.client.send_message(content))
+    }
+
+    /// The next event, waiting at most `timeout`; None on timeout or once
+    /// the client is closed.
+    pub fn next_event(&mut self, timeout: Duration) -> Option<Event> {
+        let events = &mut self.events;
+        self.rt.block_on(async { tokio::time::timeout(timeout, events.next()).await.ok().flatten() })
+    }
+
+    pub fn close(self) {
+        self.client.close();
+        // let the driver send the close frame before the runtime goes
+        self.rt.shutdown_timeout(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use uchat_proto::envelope::Envelope;
+use uchat_proto::events::{ClientEvent, InstanceInfo, Limits, Resume, ServerEvent};
+
+use crate::{ConnectOptions, Error, Event, Result};
+
+/// Error code of a resume the gateway would not take (core's i18n catalog).
+const RESUME_INVALID: &str = "protocol.resume_invalid";
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct Pending {
+    pub matches: Box<dyn Fn(&ServerEvent) -> bool + Send>,
+    pub reply: oneshot::Sender<Result<ServerEvent>>,
+}
+
+pub enum Command {
+    Send(ClientEvent, Option<Pending>),
+    Close,
+}
+
+/// What the driver learns that handles can read.
+#[derive(Default)]
+pub struct Shared {
+    pub limits: Option<Limits>,
+    pub server: Option<InstanceInfo>,
+}
+
+struct Driver {
+    options: ConnectOptions,
+    shared: Arc<Mutex<Shared>>,
+    events: mpsc::UnboundedSender<Event>,
+    /// Requests waiting for their reply, oldest first.
+    pending: VecDeque<Pending>,
+    /// Signs in the next upgrade: the latest refreshed token, else the one
+    /// connected with.
+    bearer: Option<String>,
+    /// Token of a RefreshToken sent and not answered yet.
+    refreshing: Option<String>,
+    resume: Option<String>,
+    last_seq: BTreeMap<String, u64>,
+    /// A resuming Hello went out and its answer has not come yet.
+    resuming: bool,
+    heartbeat: Duration,
+}
+
+/// Opens the first connection and leaves it to a driver task.
+pub async fn start(
+    options: ConnectOptions,
+    shared: Arc<Mutex<Shared>>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    events: mpsc::UnboundedSender<Event>,
+) -> Result<()> {
+    let mut driver = Driver {
+        bearer: options.token.clone(),
+        refreshing: None,
+        options,
+        shared,
+        events,
+        pending: VecDeque::new(),
+        resume: None,
+        last_seq: BTreeMap::new(),
+        resuming: false,
+        heartbeat: Duration::from_secs(30),
+    };
+    let ws = driver.open().await?;
+    tokio::spawn(driver.run(ws, commands));
+    Ok(())
+}
+
+async fn send(ws: &mut Socket, event: &ClientEvent) -> std::result::Result<(), String> {
+    ws.send(Message::Text(serde_json::to_string(event).unwrap())).await.map_err(|e| e.to_string())
+}
+
+impl Driver {
+    fn emit(&self, event: Event) {
+        let _ = self.events.send(event);
+    }
+
+    fn fail_pending(&mut self, error: fn() -> Error) {
+        for pending in self.pending.drain(..) {
+            let _ = pending.reply.send(Err(error()));
+        }
+    }
+
+    /// Notes what `event`, about to go out, waits for.
+    fn sending(&mut self, event: &ClientEvent, pending: Option<Pending>) {
+        self.pending.extend(pending);
+        if let ClientEvent::RefreshToken { token } = event {
+            self.refreshing = Some(token.clone());
+        }
+    }
+
+    /// Connects, reads Welcome and sends Hello, resuming if there is a token.
+    async fn open(&mut self) -> Result<Socket> {
+        let mut req = self.options.url.as_str().into_client_request()?;
+        let invalid = |e: header::InvalidHeaderValue| Error::from(tokio_tungstenite::tungstenite::Error::from(e));
+        if let Some(token) = &self.bearer {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(invalid)?;
+            req.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+        if let Some(class) = &self.options.client_class {
+            let value = HeaderValue::from_str(&format!("uchat.{}", class)).map_err(invalid)?;
+            req.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, value);
+        }
+        let (mut ws, _) = connect_async(req).await?;
+
+        let first = tokio::time::timeout(self.options.request_timeout, ws.next()).await;
+        let Ok(Some(Ok(Message::Text(text)))) = first else { return Err(Error::NoWelcome) };
+        let Ok(ServerEvent::Welcome { limits, server }) = serde_json::from_str(&text) else {
+            return Err(Error::NoWelcome);
+        };
+        self.heartbeat = Duration::from_secs(limits.heartbeat_interval_secs.max(1));
+        *self.shared.lock().unwrap() = Shared { limits: Some(limits), server };
+
+        let resume = self.resume.clone().map(|token| Resume { token, last_seq: self.last_seq.clone() });
+        self.resuming = resume.is_some();
+        let hello = ClientEvent::Hello { locale: self.options.locale.clone(), resume };
+        ws.send(Message::Text(serde_json::to_string(&hello).unwrap())).await?;
+        Ok(ws)
+    }
+
+    async fn run(mut self, mut ws: Socket, mut commands: mpsc::UnboundedReceiver<Command>) {
+        loop {
+            let Some(reason) = self.serve(&mut ws, &mut commands).await else { return };
+            self.fail_pending(|| Error::Disconnected);
+            self.emit(Event::Disconnected { reason });
+            match self.reconnect(&mut commands).await {
+                Some(reopened) => ws = reopened,
+                None => return,
+            }
+        }
+    }
+
+    /// Serves a connection until it drops, with the reason, or the client
+    /// closes it (None).
+    async fn serve(&mut self, ws: &mut Socket, commands: &mut mpsc::UnboundedReceiver<Command>) -> Option<String> {
+        // the gateway pings every heartbeat; much longer silence is a dead link
+        let silence = self.heartbeat * 2 + Duration::from_secs(5);
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    None | Some(Command::Close) => {
+                        let _ = ws.close(None).await;
+                        self.fail_pending(|| Error::Closed);
+                        return None;
+                    }
+                    Some(Command::Send(event, pending)) => {
+                        self.sending(&event, pending);
+                        if let Err(e) = send(ws, &event).await {
+                            return Some(e);
+                        }
+                    }
+                },
+                frame = tokio::time::timeout(silence, ws.next()) => match frame {
+                    Err(_) => return Some(format!("nothing from the gateway for {:?}", silence)),
+                    Ok(Some(Ok(Message::Text(text)))) => {
+                        if let Err(e) = self.incoming(ws, &text).await {
+                            return Some(e);
+                        }
+                    }
+                    Ok(Some(Ok(Message::Close(frame)))) => {
+                        let reason = frame.map(|f| f.reason.into_owned()).filter(|r| !r.is_empty());
+                        return Some(reason.unwrap_or_else(|| "closed by the gateway".into()));
+                    }
+                    Ok(Some(Ok(_))) => {}
+                    Ok(Some(Err(e))) => return Some(e.to_string()),
+                    Ok(None) => return Some("connection closed".into()),
+                },
+            }
+        }
+    }
+
+    async fn incoming(&mut self, ws: &mut Socket, text: &str) -> std::result::Result<(), String> {
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(text) else { return Ok(()) };
+        if value.get("room").is_some() && value.get("seq").is_some() {
+            if let Ok(envelope) = serde_json::from_value::<Envelope>(value) {
+                self.room(envelope);
+            }
+            return Ok(());
+        }
+        let Ok(event) = serde_json::from_value::<ServerEvent>(value) else { return Ok(()) };
+
+        match event {
+            ServerEvent::Batch { events } => events.into_iter().for_each(|envelope| self.room(envelope)),
+            ServerEvent::ResumeToken { token } => self.resume = Some(token),
+            ServerEvent::Resumed { replayed, complete, .. } => {
+                self.resuming = false;
+                self.emit(Event::Reconnected { resumed: true, replayed, complete });
+            }
+            ServerEvent::Error { code: Some(code), .. } if self.resuming && code == RESUME_INVALID => {
+                // the session starts over; gaps show up as Gap events
+                self.resuming = false;
+                self.resume = None;
+                self.emit(Event::Reconnected { resumed: false, replayed: 0, complete: false });
+            }
+            event => {
+                if let ServerEvent::TokenRefreshed { .. } = &event {
+                    self.bearer = self.refreshing.take().or(self.bearer.take());
+                }
+                if let ServerEvent::ConfigUpdate { version, .. } = &event {
+                    if self.options.auto_ack_config {
+                        send(ws, &ClientEvent::ConfigAck { version: *version }).await?;
+                    }
+                }
+                self.pending.retain(|p| !p.reply.is_closed());
+                if let ServerEvent::Error { code, details } = &event {
+                    if let Some(pending) = self.pending.pop_front() {
+                        let error = Error::Server { code: code.clone(), details: details.clone() };
+                        let _ = pending.reply.send(Err(error));
+                        return Ok(());
+                    }
+                } else if let Some(i) = self.pending.iter().position(|p| (p.matches)(&event)) {
+                    let _ = self.pending.remove(i).unwrap().reply.send(Ok(event));
+                    return Ok(());
+                }
+                self.emit(Event::Direct(event));
+            }
+        }
+        Ok(())
+    }
+
+    fn room(&mut self, envelope: Envelope) {
+        let last = self.last_seq.insert(envelope.room.clone(), envelope.seq);
+        if let Some(last) = last.filter(|last| envelope.seq > last + 1) {
+            self.emit(Event::Gap { room: envelope.room.clone(), expected: last + 1, got: envelope.seq });
+        }
+        self.emit(Event::Room(envelope));
+    }
+
+    /// Reopens the connection with backoff; None when the client closes or
+    /// the attempts run out. Events sent meanwhile go out once it is back.
+    async fn reconnect(&mut self, commands: &mut mpsc::UnboundedReceiver<Command>) -> Option<Socket> {
+        let policy = self.options.reconnect.clone()?;
+        let mut queued: Vec<(ClientEvent, Option<Pending>)> = Vec::new();
+        let mut delay = policy.initial;
+        let mut attempt = 0;
+        loop {
+            if policy.attempts.is_some_and(|max| attempt >= max) {
+                return None;
+            }
+            attempt += 1;
+            let wake = tokio::time::sleep(delay);
+            tokio::pin!(wake);
+            loop {
+                tokio::select! {
+                    _ = &mut wake => break,
+                    command = commands.recv() => match command {
+                        None | Some(Command::Close) => {
+                            for pending in queued.into_iter().filter_map(|(_, p)| p) {
+                                let _ = pending.reply.send(Err(Error::Closed));
+                            }
+                            return None;
+                        }
+                        Some(Command::Send(event, pending)) => queued.push((event, pending)),
+                    },
+                }
+            }
+
+            match self.open().await {
+                Ok(mut ws) => {
+                    if !self.resuming {
+                        self.emit(Event::Reconnected { resumed: false, replayed: 0, complete: false });
+                    }
+                    for (event, pending) in queued {
+                        self.sending(&event, pending);
+                        let _ = send(&mut ws, &event).await;
+                    }
+                    return Some(ws);
+                }
+                Err(_) => delay = (delay * 2).min(policy.max),
+            }
+        }
+    }
+}
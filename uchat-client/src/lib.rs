@@ -0,0 +1,326 @@
+//! Typed async client for the gateway's WebSocket protocol.
+//!
+//! `Client::connect` opens the socket, reads Welcome and sends Hello; a
+//! driver task then owns the connection. Requests with a reply
+//! (`request`) resolve from the server's answer, room events arrive on
+//! `Events` in `seq` order with gaps reported, and a dropped connection is
+//! reopened with the connection's token and resumed where it left off (see
+//! `uchat_proto::envelope` for the ordering rules this relies on).
+//!
+//! With the `blocking` feature, `blocking::BlockingClient` wraps the same
+//! client for tools that do not run an async runtime.
+//!
+//! ```no_run
+//! # async fn run() -> uchat_client::Result<()> {
+//! use uchat_client::{Client, ConnectOptions, Event};
+//!
+//! // a token from auth-api's POST /login
+//! # let token = String::new();
+//! let options = ConnectOptions::new("ws://127.0.0.1:9000/ws").token(token);
+//! let (client, mut events) = Client::connect(options).await?;
+//! client.send_message("hello").await?;
+//! while let Some(event) = events.next().await {
+//!     if let Event::Room(envelope) = event {
+//!         println!("{}#{}: {:?}", envelope.room, envelope.seq, envelope.event);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod driver;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+pub use uchat_proto::envelope::Envelope;
+pub use uchat_proto::events::{
+    ClientEvent, ClientSettings, InstanceInfo, Limits, PresenceStatus, ReceiptKind, ServerEvent,
+};
+
+use driver::{Command, Pending, Shared};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("connection failed: {0}")]
+    Connect(Box<tokio_tungstenite::tungstenite::Error>),
+
+    #[error("gateway did not send Welcome")]
+    NoWelcome,
+
+    #[error("gateway error: {details}")]
+    Server { code: Option<String>, details: String },
+
+    #[error("no reply within {0:?}")]
+    Timeout(Duration),
+
+    /// The connection dropped before the reply came; the request may or
+    /// may not have been handled.
+    #[error("connection lost")]
+    Disconnected,
+
+    #[error("client closed")]
+    Closed,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::Connect(Box::new(e))
+    }
+}
+
+/// Reconnection backoff: `initial`, doubling up to `max`, for at most
+/// `attempts` tries in a row (None: forever).
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    pub initial: Duration,
+    pub max: Duration,
+    pub attempts: Option<u32>,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self { initial: Duration::from_millis(500), max: Duration::from_secs(30), attempts: None }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    pub url: String,
+    /// Signs in on the upgrade; a token from auth-api. Without one the
+    /// connection stays anonymous.
+    pub token: Option<String>,
+    /// Offered as the "uchat.<class>" subprotocol (browser, desktop, iot).
+    pub client_class: Option<String>,
+    pub locale: String,
+    /// None gives up when the connection drops.
+    pub reconnect: Option<Reconnect>,
+    pub request_timeout: Duration,
+    /// Answer ConfigUpdate with ConfigAck as it arrives.
+    pub auto_ack_config: bool,
+}
+
+impl ConnectOptions {
+    /// `url` is the gateway's WebSocket endpoint, e.g. `ws://127.0.0.1:9000/ws`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            token: None,
+            client_class: None,
+            locale: String::new(),
+            reconnect: Some(Reconnect::default()),
+            request_timeout: Duration::from_secs(10),
+            auto_ack_config: true,
+        }
+    }
+
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn client_class(mut self, class: impl Into<String>) -> Self {
+        self.client_class = Some(class.into());
+        self
+    }
+
+    pub fn locale(mut self, locale: impl Into<String>) -> Self {
+        self.locale = locale.into();
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: Option<Reconnect>) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+}
+
+// room events are most of what arrives; boxing them would cost an
+// allocation each
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A room event, in `seq` order per room; Batch frames arrive unpacked.
+    Room(Envelope),
+    /// A direct event no request was waiting for.
+    Direct(ServerEvent),
+    /// Room events between `expected` and `got` were never delivered;
+    /// refetch history to fill them in.
+    Gap { room: String, expected: u64, got: u64 },
+    Disconnected { reason: String },
+    /// The connection is back. `resumed` is false when the gateway could
+    /// not resume the session and it started over; `complete` is false
+    /// when part of the gap was no longer retained.
+    Reconnected { resumed: bool, replayed: u64, complete: bool },
+}
+
+/// What the client receives; ends when the client is closed or gives up
+/// reconnecting.
+pub struct Events {
+    rx: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Events {
+    pub async fn next(&mut self) -> Option<Event> {
+        self.rx.recv().await
+    }
+}
+
+/// A handle to the connection; clones share it.
+#[derive(Clone)]
+pub struct Client {
+    commands: mpsc::UnboundedSender<Command>,
+    shared: Arc<Mutex<Shared>>,
+    request_timeout: Duration,
+}
+
+impl Client {
+    /// Connects, reads Welcome and sends Hello.
+    pub async fn connect(options: ConnectOptions) -> Result<(Client, Events)> {
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (event_tx, rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let request_timeout = options.request_timeout;
+        driver::start(options, shared.clone(), command_rx, event_tx).await?;
+        Ok((Client { commands, shared, request_timeout }, Events { rx }))
+    }
+
+    /// Limits from the latest Welcome.
+    pub fn limits(&self) -> Option<Limits> {
+        self.shared.lock().unwrap().limits.clone()
+    }
+
+    /// The gateway instance the connection is on.
+    pub fn server(&self) -> Option<InstanceInfo> {
+        self.shared.lock().unwrap().server.clone()
+    }
+
+    /// Sends an event without waiting for anything back.
+    pub async fn send(&self, event: ClientEvent) -> Result<()> {
+        self.commands.send(Command::Send(event, None)).map_err(|_| Error::Closed)
+    }
+
+    /// Sends `event` and waits for the first direct event `reply` accepts.
+    /// An Error event while waiting fails the oldest waiting request: the
+    /// gateway answers in order and does not say what an error refers to.
+    pub async fn request(
+        &self,
+        event: ClientEvent,
+        reply: impl Fn(&ServerEvent) -> bool + Send + 'static,
+    ) -> Result<ServerEvent> {
+        let (tx, rx) = oneshot::channel();
+        let pending = Pending { matches: Box::new(reply), reply: tx };
+        self.commands.send(Command::Send(event, Some(pending))).map_err(|_| Error::Closed)?;
+        match tokio::time::timeout(self.request_timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Error::Closed),
+            Err(_) => Err(Error::Timeout(self.request_timeout)),
+        }
+    }
+
+    /// Moves the connection to a new token for the same user, e.g. before
+    /// the current one expires; returns its expiry (unix seconds). Later
+    /// reconnects sign in with it.
+    pub async fn refresh_token(&self, token: &str) -> Result<u64> {
+        let event = ClientEvent::RefreshToken { token: token.into() };
+        match self.request(event, |e| matches!(e, ServerEvent::TokenRefreshed { .. })).await? {
+            ServerEvent::TokenRefreshed { exp } => Ok(exp),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Posts to the lobby.
+    pub async fn send_message(&self, content: impl Into<String>) -> Result<()> {
+        self.send(post(None, content.into(), None)).await
+    }
+
+    /// Posts to `room`, which the connection must have joined.
+    pub async fn send_to(&self, room: &str, content: impl Into<String>) -> Result<()> {
+        self.send(post(Some(room), content.into(), None)).await
+    }
+
+    /// Posts a disappearing message to `room`: after `expires_in_secs` the
+    /// gateway only delivers it as `ServerEvent::MessageExpired`, and
+    /// receivers should drop it at the envelope's `expires_at`.
+    pub async fn send_expiring(&self, room: &str, content: impl Into<String>, expires_in_secs: u64) -> Result<()> {
+        self.send(post(Some(room), content.into(), Some(expires_in_secs))).await
+    }
+
+    /// Posts to `room` and waits for the gateway's `MessageAck`; returns the
+    /// message's seq. After a timeout, retry with the same `client_msg_id`:
+    /// the gateway acks a message it already has instead of posting it
+    /// again. With `receipts`, recipients' confirmations arrive as
+    /// `ServerEvent::MessageDelivered`.
+    pub async fn send_acked(
+        &self,
+        room: &str,
+        content: impl Into<String>,
+        client_msg_id: &str,
+        receipts: bool,
+    ) -> Result<u64> {
+        let event = ClientEvent::SendMessage {
+            content: content.into(),
+            room: Some(room.into()),
+            client_msg_id: Some(client_msg_id.into()),
+            receipts,
+            expires_in_secs: None,
+            encryption: None,
+        };
+        let id = client_msg_id.to_string();
+        let acked =
+            move |e: &ServerEvent| matches!(e, ServerEvent::MessageAck { client_msg_id, .. } if *client_msg_id == id);
+        match self.request(event, acked).await? {
+            ServerEvent::MessageAck { seq, .. } => Ok(seq),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Confirms a room event whose envelope asked for `receipts` arrived.
+    pub async fn confirm_delivery(&self, room: &str, seq: u64) -> Result<()> {
+        self.send(ClientEvent::Delivered { room: room.into(), seq }).await
+    }
+
+    /// Starts receiving `room`'s events, alongside the rooms already joined.
+    /// Answered with `ServerEvent::RoomJoined`, or an error if the token
+    /// does not admit the connection to the room.
+    pub async fn join_room(&self, room: &str) -> Result<()> {
+        self.send(ClientEvent::JoinRoom { room: room.into() }).await
+    }
+
+    pub async fn leave_room(&self, room: &str) -> Result<()> {
+        self.send(ClientEvent::LeaveRoom { room: room.into() }).await
+    }
+
+    /// Confirms a stored message reached the user, or was read.
+    pub async fn receipt(&self, room: &str, message_id: i64, kind: ReceiptKind) -> Result<()> {
+        self.send(ClientEvent::Receipt { room: room.into(), message_id, kind }).await
+    }
+
+    /// Confirms a DeviceCommand; until then it is redelivered each time the device connects.
+    pub async fn ack_command(&self, id: &str) -> Result<()> {
+        self.send(ClientEvent::CommandAck { id: id.into() }).await
+    }
+
+    /// Closes the connection; `Events` ends once it is closed.
+    pub fn close(&self) {
+        let _ = self.commands.send(Command::Close);
+    }
+}
+
+/// A SendMessage asking for no ack or receipts.
+fn post(room: Option<&str>, content: String, expires_in_secs: Option<u64>) -> ClientEvent {
+    let room = room.map(Into::into);
+    ClientEvent::SendMessage { content, room, client_msg_id: None, receipts: false, expires_in_secs, encryption: None }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Who may post in a room. Reading is governed by the room's groups
+/// (private rooms, `ROOM_ACL`), not by the kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum RoomKind {
+    /// Anyone may read and post.
+    #[default]
+    Public,
+    /// Only members of the room's groups may read and post.
+    Private,
+    /// Everyone reads; only moderators and bots post.
+    Announcement,
+}
+
+impl RoomKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "public" => Some(Self::Public),
+            "private" => Some(Self::Private),
+            "announcement" => Some(Self::Announcement),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Private => "private",
+            Self::Announcement => "announcement",
+        }
+    }
+}
+
+/// Groups whose members may post in announcement rooms.
+pub const ANNOUNCER_GROUPS: &[&str] = &["moderators", "bots"];
+
+/// Room -> groups allowed in it. Rooms without an entry are open to everyone.
+#[derive(Debug, Clone, Default)]
+pub struct RoomAcl {
+    rooms: HashMap<String, Vec<String>>,
+}
+
+impl RoomAcl {
+    /// Parses `ROOM_ACL="ops=sre,oncall;finance=fin"`.
+    pub fn from_env() -> Self {
+        Self::parse(&std::env::var("ROOM_ACL").unwrap_or_default())
+    }
+
+    pub fn parse(spec: &str) -> Self {
+        let mut rooms = HashMap::new();
+        for entry in spec.split(';').filter(|e| !e.trim().is_empty()) {
+            if let Some((room, groups)) = entry.split_once('=') {
+                let groups = groups
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|g| !g.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                rooms.insert(room.trim().to_string(), groups);
+            }
+        }
+        Self { rooms }
+    }
+
+    pub fn is_restricted(&self, room: &str) -> bool {
+        self.rooms.contains_key(room)
+    }
+
+    /// Groups allowed in `room`, if it is restricted.
+    pub fn groups(&self, room: &str) -> Option<&[String]> {
+        self.rooms.get(room).map(Vec::as_slice)
+    }
+
+    pub fn allows(&self, room: &str, groups: &[String]) -> bool {
+        match self.rooms.get(room) {
+            Some(allowed) => allowed.iter().any(|g| groups.contains(g)),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+
+use crate::jwt::{Scope, ScopeAction};
+
+//
+// AUTH-API HTTP DTOs
+//
+// Shared by auth-api and unhidra-client so the two cannot drift; with the
+// `openapi` feature they also carry the schemas behind /openapi.json.
+//
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+/// Wire shape of `ServerEvent::LoginOk`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LoginResponse {
+    #[serde(rename = "LoginOk")]
+    pub login_ok: TokenBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TokenBody {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct IntrospectRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct IntrospectResponse {
+    pub active: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Set for delegated tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Scope>,
+}
+
+/// POST /tokens/delegate: a token for a bot, limited to `rooms` and
+/// `actions`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct DelegateRequest {
+    pub bot: String,
+    pub rooms: Vec<String>,
+    pub actions: Vec<ScopeAction>,
+    /// Lifetime in seconds; defaults to an hour, capped by the server.
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+}
+
+/// A delegated token; `token` is only returned when it is minted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DelegatedToken {
+    pub id: String,
+    pub bot: String,
+    pub rooms: Vec<String>,
+    pub actions: Vec<ScopeAction>,
+    pub created_at: String,
+    pub expires_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// A web session; the token itself stays in the HttpOnly cookie.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SessionInfo {
+    pub user: String,
+    /// Echo in the X-CSRF-Token header on requests that change state.
+    pub csrf_token: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct CreateGroupRequest {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AddMemberRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Group {
+    pub id: String,
+    pub owner: String,
+    /// Empty unless the caller owns the group or is a member.
+    pub members: Vec<String>,
+}
+
+/// Reply to DELETE /account: when the account will be purged unless the
+/// user logs in again first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AccountDeletion {
+    pub username: String,
+    pub purge_at: String,
+}
+
+/// Wire shape of `ServerEvent::Error`, used by every non-2xx JSON reply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ErrorResponse {
+    #[serde(rename = "Error")]
+    pub error: ErrorBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ErrorBody {
+    pub details: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Set on 429s from the sign-in routes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<Throttle>,
+}
+
+/// When a throttled sign-in may be tried again, for clients to count down
+/// instead of guessing; the same values go in the response headers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Throttle {
+    /// As in the Retry-After header.
+    pub retry_after_secs: u64,
+    /// Failed sign-ins the address may still make before it is locked out
+    /// (X-Login-Attempts-Remaining); left out where failures do not count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_attempts: Option<u32>,
+    /// RFC 3339 end of the address's lockout (X-Lockout-Until), if it is
+    /// locked out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockout_until: Option<String>,
+}
+
+//
+// POLICY ACCEPTANCE
+//
+
+/// A published terms-of-service or privacy policy version.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PolicyVersion {
+    /// "tos" or "privacy".
+    pub kind: String,
+    pub version: String,
+    pub url: String,
+    pub published_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Policies {
+    pub policies: Vec<PolicyVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct PublishPolicyRequest {
+    pub kind: String,
+    pub version: String,
+    pub url: String,
+}
+
+/// Login reply while current policies are not yet accepted; the ticket
+/// completes the login through POST /policies/accept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PolicyAcceptanceRequired {
+    #[serde(rename = "PolicyAcceptanceRequired")]
+    pub required: PendingPolicies,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PendingPolicies {
+    pub ticket: String,
+    pub policies: Vec<PolicyVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AcceptedPolicy {
+    pub kind: String,
+    pub version: String,
+}
+
+/// Accepts policy versions, authenticated by a login ticket or a bearer
+/// token; answered with a fresh `LoginResponse`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct AcceptPoliciesRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticket: Option<String>,
+    pub accept: Vec<AcceptedPolicy>,
+}
+
+/// A user's acceptance of one policy, as recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PolicyAcceptance {
+    pub kind: String,
+    pub version: String,
+    pub accepted_at: String,
+    pub ip: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AcceptedPolicies {
+    pub policies: Vec<PolicyAcceptance>,
+}
+
+/// What POST /login answers with 200.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(untagged)]
+pub enum LoginOutcome {
+    LoggedIn(LoginResponse),
+    PolicyAcceptanceRequired(PolicyAcceptanceRequired),
+}
+
+//
+// REVOCATION FEED
+//
+
+/// An entry of auth-api's GET /revocations, for services that accepted a
+/// token before it was revoked (the gateway's WebSockets) to stop honouring
+/// it. `id`s only grow; poll with the last one seen.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Revocation {
+    /// Every token of `sub` issued (`iat`) before `before`, unix seconds.
+    User { id: u64, sub: String, before: usize },
+    /// The token with this `jti`, a delegated token or a web session,
+    /// which would have expired at `exp` anyway.
+    Token { id: u64, jti: String, exp: usize },
+}
+
+impl Revocation {
+    pub fn id(&self) -> u64 {
+        match self {
+            Revocation::User { id, .. } | Revocation::Token { id, .. } => *id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct RevocationFeed {
+    pub revocations: Vec<Revocation>,
+}
@@ -0,0 +1,78 @@
+use alloc::string::String;
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::{InstanceInfo, ServerEvent};
+
+//
+// ROOM ENVELOPE
+//
+// Every room broadcast goes out wrapped with its room and a per-room
+// sequence number, flattened next to the event tag:
+//
+//   {"room":"lobby","seq":42,"sender":"bob","ts":1767225600000,
+//    "MessageBroadcast":{"from":"bob","content":"hi"}}
+//
+// `room` and `seq` together identify the event. `sender` is the user
+// whose action produced it, absent for the gateway's own events; `ts` is
+// when the gateway published it, in unix milliseconds. Both are absent
+// from events journaled before they existed. `receipts` is true when the
+// sender asked for delivery receipts: answer with ClientEvent::Delivered.
+// `expires_at` (unix milliseconds) marks a disappearing message; clients
+// should discard it at that time. The gateway never delivers it later:
+// live, replayed or resumed, it then arrives as a MessageExpired tombstone
+// under the same `room` and `seq`.
+//
+// `encryption` is set on end-to-end encrypted messages: the event's
+// content is then ciphertext, and `alg`, `key_id` and `nonce` are what the
+// sender's client attached for recipients to decrypt it. The gateway
+// neither reads nor checks them beyond requiring them in rooms that are
+// marked e2ee_required (see RoomJoined).
+//
+// Ordering guarantees clients can rely on:
+// - `seq` starts at 1 for each room and increases by exactly 1 per
+//   broadcast in that room, and frames are delivered in `seq` order.
+// - If a client sees `seq > last + 1` it has missed events (slow consumer,
+//   reconnect) and should refetch history or resume.
+// - `seq <= last` never happens on one connection. Numbering survives
+//   gateway restarts (it continues from the room journal), so a resuming
+//   client can ask for exactly the events after its last `seq`. Only if
+//   the journal is wiped does numbering restart at 1, meaning "start over".
+// - Direct replies (LoginOk, Error, CommandResult) are not room events and
+//   carry no envelope.
+//
+// `origin`, when present, names the gateway instance that published the
+// event. It is for debugging multi-instance deployments only; clients
+// must not depend on it.
+//
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub room: String,
+    pub seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<InstanceInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<i64>,
+    #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+    pub receipts: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    #[serde(flatten)]
+    pub event: ServerEvent,
+}
+
+/// End-to-end encryption metadata, opaque to the gateway.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Scheme the content was encrypted with, e.g. "megolm.v1.aes-sha2".
+    pub alg: String,
+    /// Which of the room's keys to decrypt with.
+    pub key_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
@@ -1,5 +1,11 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use serde::{Deserialize, Serialize};
 
+use crate::envelope::{Encryption, Envelope};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientEvent {
     Login {
@@ -7,25 +13,139 @@ pub enum ClientEvent {
         password: String,
     },
 
+    // Posts to room, which the connection must have joined; the lobby
+    // when left out. With client_msg_id the gateway answers MessageAck,
+    // and a resend of the same id is acked again rather than posted twice.
+    // receipts asks recipients to confirm delivery (see Delivered).
+    // expires_in_secs makes it a disappearing message: once that many
+    // seconds have passed it is only ever delivered as MessageExpired.
+    // encryption marks content as end-to-end encrypted and is passed on in
+    // the envelope; rooms marked e2ee_required refuse messages without it
     SendMessage {
         content: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+        #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+        receipts: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_in_secs: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encryption: Option<Encryption>,
     },
 
     // NEW — send image/video/file
     SendMedia {
         kind: String,   // "image" / "video" / "file"
         url: String,
-    }
+    },
+
+    // Client hello: must be the first event on a connection. Optionally
+    // names the preferred locale for server-generated text, and resumes a
+    // previous session (possibly on another gateway instance)
+    Hello {
+        #[serde(default)]
+        locale: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume: Option<Resume>,
+    },
+
+    // Server-side mute/block lists; filtered before delivery
+    Block {
+        user: String,
+    },
+
+    Unblock {
+        user: String,
+    },
+
+    MuteRoom {
+        room: String,
+    },
+
+    UnmuteRoom {
+        room: String,
+    },
+
+    // Start or stop receiving a room's events on this connection. Every
+    // connection starts in the lobby
+    JoinRoom {
+        room: String,
+    },
+
+    LeaveRoom {
+        room: String,
+    },
+
+    // Device confirms a DeviceCommand; until then it stays queued
+    CommandAck {
+        id: String,
+    },
+
+    // A stored chat-service message reached the client or was read;
+    // kept as an audit trail in compliance rooms
+    Receipt {
+        room: String,
+        message_id: i64,
+        kind: ReceiptKind,
+    },
+
+    // Device event authenticated with the device's key: sig is the
+    // base64url HMAC-SHA256 of "{nonce}.{ts}.{body}", where body is the
+    // inner ClientEvent as JSON and ts is unix seconds. Each nonce is
+    // accepted once
+    Signed {
+        body: String,
+        nonce: String,
+        ts: i64,
+        sig: String,
+    },
+
+    // Client applied the ConfigUpdate with this version
+    ConfigAck {
+        version: u64,
+    },
+
+    // Marks this connection online or away; a user is away only while all
+    // their connections are
+    SetPresence {
+        status: PresenceStatus,
+    },
+
+    // A room event whose envelope asked for receipts reached this client
+    Delivered {
+        room: String,
+        seq: u64,
+    },
+
+    // A new token for the user the connection is signed in as, e.g. before
+    // the current one expires; answered with TokenRefreshed. Its groups and
+    // scope replace the old token's, and rooms it does not admit are left
+    RefreshToken {
+        token: String,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerEvent {
+    // First frame on every connection: what this gateway allows
+    Welcome {
+        limits: Limits,
+        // which gateway answered, for debugging multi-instance deployments
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server: Option<InstanceInfo>,
+    },
+
     LoginOk {
         token: String,
     },
 
     Error {
         details: String,
+        // stable catalog code (see core::i18n::codes); details is localized
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
     },
 
     MessageBroadcast {
@@ -38,5 +158,247 @@ pub enum ServerEvent {
         from: String,
         kind: String,
         url: String,
-    }
+        // previews generated at upload, smallest first
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        thumbnails: Vec<Thumbnail>,
+    },
+
+    // Slash-command reply only the invoking connection sees
+    CommandResult {
+        command: String,
+        content: String,
+    },
+
+    // poll.updated — live tallies, one count per option
+    PollUpdated {
+        poll_id: String,
+        room: String,
+        counts: Vec<u32>,
+        closed: bool,
+    },
+
+    // Link preview fetched after the message was sent; attach to message_id
+    MessageUnfurled {
+        message_id: i64,
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        image: Option<String>,
+    },
+
+    // Operator command for a device; answer with ClientEvent::CommandAck.
+    // Redelivered on every login until acked, so handlers must be idempotent
+    DeviceCommand {
+        id: String,
+        command: String,
+        payload: serde_json::Value,
+    },
+
+    // Room events coalesced into one frame, each as it would have been
+    // sent alone, in delivery order. Only sent to connections whose
+    // Welcome limits carry batching
+    Batch {
+        events: Vec<Envelope>,
+    },
+
+    // Answer to JoinRoom: the connection now receives the room's events
+    // after seq, its latest; history up to it is in chat-service.
+    // e2ee_required: messages posted there must be end-to-end encrypted
+    RoomJoined {
+        room: String,
+        seq: u64,
+        #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+        e2ee_required: bool,
+    },
+
+    // Answer to LeaveRoom: no more events from the room
+    RoomLeft {
+        room: String,
+    },
+
+    // Answer to a SendMessage with client_msg_id: the message went out as
+    // room event seq, which with room is its server-side id
+    MessageAck {
+        client_msg_id: String,
+        room: String,
+        seq: u64,
+    },
+
+    // A recipient confirmed a message sent with receipts; one per user
+    MessageDelivered {
+        room: String,
+        seq: u64,
+        to: String,
+    },
+
+    // Signed token for Hello { resume }; replaces any earlier one
+    ResumeToken {
+        token: String,
+    },
+
+    // Answer to a resuming Hello, sent after the replayed room events.
+    // complete is false when part of the gap was no longer retained
+    Resumed {
+        username: String,
+        replayed: u64,
+        complete: bool,
+    },
+
+    // Someone reacted to a stored message; emoji is Unicode or a custom
+    // emoji as ":name:" (resolve it with chat-service GET /emoji)
+    ReactionAdded {
+        room: String,
+        message_id: i64,
+        from: String,
+        emoji: String,
+    },
+
+    // The author replaced a stored message's text. revision is 1 as sent
+    // and one more per edit; apply an edit only over a lower revision
+    MessageEdited {
+        room: String,
+        message_id: i64,
+        from: String,
+        content: String,
+        revision: u64,
+    },
+
+    // Operator-set client settings, complete: fields left out go back to
+    // the client's own defaults. Answer with ClientEvent::ConfigAck
+    ConfigUpdate {
+        version: u64,
+        settings: ClientSettings,
+    },
+
+    // A room member's status changed. Not a room event: it has no seq and
+    // is not replayed on resume, so reread GET /rooms/{room}/presence then
+    PresenceChanged {
+        room: String,
+        user: String,
+        status: PresenceStatus,
+    },
+
+    // The gateway is draining: reconnect within this many seconds, after
+    // which it closes the connection (1012). alternates are other
+    // instances' WebSocket URLs, when the operator published them
+    ReconnectSoon {
+        within_secs: u64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        alternates: Vec<String>,
+    },
+
+    // Room event from the gateway itself: an admin froze the room, and
+    // sends to it are refused (room.frozen) until it is thawed (frozen
+    // false). Events the gateway publishes still arrive
+    RoomFreezeChanged {
+        frozen: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+
+    // Room event standing in for a disappearing message delivered after
+    // its expires_at: the envelope keeps the message's room and seq, so
+    // drop any copy of that message and carry on with the next seq
+    MessageExpired {},
+
+    // Answer to RefreshToken: the connection now runs on the new token,
+    // which expires at exp (unix seconds)
+    TokenRefreshed {
+        exp: u64,
+    },
+}
+
+/// Resumption request carried in `ClientEvent::Hello`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resume {
+    /// The last `ServerEvent::ResumeToken` received.
+    pub token: String,
+    /// Last `seq` seen per room; rooms left out resume from the token.
+    #[serde(default)]
+    pub last_seq: BTreeMap<String, u64>,
+}
+
+/// What a `ClientEvent::Receipt` confirms; a read implies delivery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+/// A user's status in `ClientEvent::SetPresence` and
+/// `ServerEvent::PresenceChanged`; clients set only online and away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+/// Settings pushed in `ServerEvent::ConfigUpdate`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientSettings {
+    /// How often the client should check in (keepalive, telemetry report).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// "off", "error", "warn", "info" or "debug".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telemetry_level: Option<String>,
+    /// Client features switched on or off by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, bool>,
+}
+
+/// A downscaled preview of an uploaded image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A gateway instance, named in Welcome and in the room envelopes it
+/// publishes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceInfo {
+    pub instance_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+/// Protocol limits advertised in `ServerEvent::Welcome`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    /// Largest message the gateway accepts; bigger frames close the socket.
+    pub max_message_bytes: usize,
+    /// The server pings this often; treat a longer silence as a dead link.
+    pub heartbeat_interval_secs: u64,
+    /// Per-connection limits keyed by event type ("send_message", ...).
+    pub rate_limits: BTreeMap<String, RateLimit>,
+    /// Optional protocol features this gateway supports.
+    pub features: Vec<String>,
+    /// Set when room events may arrive coalesced in `ServerEvent::Batch`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batching: Option<Batching>,
+}
+
+/// How the gateway coalesces room events for this connection: a batch
+/// goes out at most `max_delay_ms` after its first event, or once it
+/// reaches about `max_bytes`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Batching {
+    pub max_delay_ms: u64,
+    pub max_bytes: usize,
+}
+
+/// At most `max` events per `per_secs` window.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub max: u32,
+    pub per_secs: u64,
 }
@@ -1,18 +1,113 @@
+use std::collections::HashMap;
+
 use chrono::{Utc, Duration};
 use jsonwebtoken::{encode, decode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::api::Revocation;
+
+pub const DEFAULT_SECRET: &str = "MY_SECRET_KEY";
+
+/// `aud` of web session tokens; tokens with any audience are refused by
+/// `verify_claims`, so a session cookie never works as a Bearer token.
+pub const SESSION_AUDIENCE: &str = "uchat-web-session";
+/// Cookie holding a web session token.
+pub const SESSION_COOKIE: &str = "uchat_session";
+/// Header carrying the session's CSRF token on state-changing requests.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    /// Issue time; tokens minted before an account's revocation are dead.
+    #[serde(default)]
+    pub iat: usize,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Token id; set on delegated tokens so they can be revoked one by one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Set on delegated tokens, which act for `sub` only within it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Scope>,
+    /// SESSION_AUDIENCE on web session tokens, whose `jti` is the session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Client class ("browser", "desktop", "iot") the token was provisioned
+    /// for; picks the gateway's limits for the connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_class: Option<String>,
+}
+
+/// What a delegated token may do, minted by auth-api's /tokens/delegate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Scope {
+    /// Label of the bot the owner handed the token to.
+    pub bot: String,
+    pub rooms: Vec<String>,
+    pub actions: Vec<ScopeAction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ScopeAction {
+    /// Post messages and media.
+    Send,
+    /// Receive room events and read history.
+    Read,
+}
+
+impl ScopeAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScopeAction::Send => "send",
+            ScopeAction::Read => "read",
+        }
+    }
+}
+
+impl Scope {
+    pub fn allows(&self, room: &str, action: ScopeAction) -> bool {
+        self.actions.contains(&action) && self.rooms.iter().any(|r| r == room)
+    }
+}
+
+impl Claims {
+    pub fn is_delegated(&self) -> bool {
+        self.scope.is_some()
+    }
+
+    /// Whether the token may do `action` in `room`; unscoped tokens carry
+    /// their owner's full rights.
+    pub fn allows(&self, room: &str, action: ScopeAction) -> bool {
+        self.scope.as_ref().is_none_or(|s| s.allows(room, action))
+    }
+}
+
+/// Shared signing secret, `JWT_SECRET` if set.
+pub fn secret_from_env() -> String {
+    std::env::var("JWT_SECRET").unwrap_or_else(|_| DEFAULT_SECRET.to_string())
 }
 
 pub fn create_token(secret: &str, username: &str) -> String {
-    let expiration = Utc::now() + Duration::hours(12);
+    create_token_with_groups(secret, username, Vec::new())
+}
+
+pub fn create_token_with_groups(secret: &str, username: &str, groups: Vec<String>) -> String {
+    let now = Utc::now();
+    let expiration = now + Duration::hours(12);
     let claims = Claims {
         sub: username.to_string(),
         exp: expiration.timestamp() as usize,
+        iat: now.timestamp() as usize,
+        groups,
+        jti: None,
+        scope: None,
+        aud: None,
+        client_class: None,
     };
 
     encode(
@@ -22,7 +117,53 @@ pub fn create_token(secret: &str, username: &str) -> String {
     ).unwrap()
 }
 
+/// A token acting for `username` only within `scope`, valid for `ttl`.
+pub fn create_delegated_token(
+    secret: &str,
+    username: &str,
+    groups: Vec<String>,
+    jti: &str,
+    scope: Scope,
+    ttl: Duration,
+) -> String {
+    let now = Utc::now();
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: (now + ttl).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        groups,
+        jti: Some(jti.to_string()),
+        scope: Some(scope),
+        aud: None,
+        client_class: None,
+    };
+
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+}
+
+/// A web session token for session `sid`, valid for `ttl`; see
+/// auth-api's sessions.rs.
+pub fn create_session_token(secret: &str, username: &str, groups: Vec<String>, sid: &str, ttl: Duration) -> String {
+    let now = Utc::now();
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: (now + ttl).timestamp() as usize,
+        iat: now.timestamp() as usize,
+        groups,
+        jti: Some(sid.to_string()),
+        scope: None,
+        aud: Some(SESSION_AUDIENCE.to_string()),
+        client_class: None,
+    };
+
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_bytes())).unwrap()
+}
+
 pub fn verify_token(secret: &str, token: &str) -> Option<String> {
+    verify_claims(secret, token).map(|c| c.sub)
+}
+
+pub fn verify_claims(secret: &str, token: &str) -> Option<Claims> {
     let validation = Validation::new(Algorithm::HS256);
     let decoded = decode::<Claims>(
         token,
@@ -30,5 +171,70 @@ pub fn verify_token(secret: &str, token: &str) -> Option<String> {
         &validation,
     ).ok()?;
 
-    Some(decoded.claims.sub)
+    Some(decoded.claims)
+}
+
+/// Claims of a web session token; plain and delegated tokens are refused.
+pub fn verify_session_claims(secret: &str, token: &str) -> Option<Claims> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[SESSION_AUDIENCE]);
+    validation.set_required_spec_claims(&["exp", "aud"]);
+    let decoded = decode::<Claims>(token, &DecodingKey::from_secret(secret.as_bytes()), &validation).ok()?;
+    Some(decoded.claims)
+}
+
+/// The session token in a `Cookie` header value, if any.
+pub fn session_cookie(header: &str) -> Option<&str> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// Revoked tokens as learned from auth-api's revocation feed
+/// (`api::RevocationFeed`); services that verify tokens themselves check
+/// them against it.
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    /// Tokens of these users issued before the time (unix seconds) are revoked.
+    users: HashMap<String, usize>,
+    /// Revoked jti, with when the token expires anyway.
+    tokens: HashMap<String, usize>,
+    /// Last feed entry applied.
+    pub cursor: u64,
+}
+
+impl RevocationList {
+    pub fn apply(&mut self, revocation: &Revocation) {
+        match revocation {
+            Revocation::User { sub, before, .. } => {
+                let cutoff = self.users.entry(sub.clone()).or_default();
+                *cutoff = (*cutoff).max(*before);
+            }
+            Revocation::Token { jti, exp, .. } => {
+                self.tokens.insert(jti.clone(), *exp);
+            }
+        }
+        self.cursor = self.cursor.max(revocation.id());
+    }
+
+    /// Whether a token of `sub` issued at `iat`, with id `jti`, is revoked.
+    pub fn is_revoked(&self, sub: &str, iat: usize, jti: Option<&str>) -> bool {
+        self.users.get(sub).is_some_and(|before| iat < *before) || jti.is_some_and(|id| self.tokens.contains_key(id))
+    }
+
+    pub fn revokes(&self, claims: &Claims) -> bool {
+        self.is_revoked(&claims.sub, claims.iat, claims.jti.as_deref())
+    }
+
+    /// Forgets revoked tokens that expire by `now` anyway.
+    pub fn forget_expired(&mut self, now: usize) {
+        self.tokens.retain(|_, exp| *exp > now);
+    }
+
+    /// Entries held: users, tokens.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.users.len(), self.tokens.len())
+    }
 }
@@ -1,3 +1,15 @@
+// Without `std` this is the wire types alone, for no_std targets with an
+// allocator (see Cargo.toml).
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod jwt;
 pub mod events;
 pub mod errors;
+#[cfg(feature = "std")]
+pub mod acl;
+#[cfg(feature = "std")]
+pub mod api;
+pub mod envelope;
@@ -0,0 +1,87 @@
+//
+// BACKWARD COMPATIBILITY FIXTURES
+//
+// tests/fixtures/<version>/ holds frozen JSON samples of each protocol
+// version clients were built against:
+//
+//   v1  the original protocol (login, messages, media)
+//   v2  the Hello handshake and everything added with it
+//
+// Every sample must still parse as the variant its file name starts with
+// (`Hello.minimal.json` is a `Hello`). Samples are never edited once a
+// version ships; protocol changes get new files, or a new version
+// directory when clients must change.
+//
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use uchat_proto::envelope::Envelope;
+use uchat_proto::events::{ClientEvent, ServerEvent};
+
+fn fixtures(kind: &str) -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut files = Vec::new();
+    for version in fs::read_dir(&root).unwrap() {
+        let dir = version.unwrap().path().join(kind);
+        if !dir.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(dir).unwrap() {
+            files.push(file.unwrap().path());
+        }
+    }
+    files.sort();
+    assert!(!files.is_empty(), "no {} fixtures under {}", kind, root.display());
+    files
+}
+
+/// The variant a fixture file is a sample of.
+fn expected_tag(path: &Path) -> &str {
+    let name = path.file_name().unwrap().to_str().unwrap();
+    name.split('.').next().unwrap()
+}
+
+/// Parses the fixture, and checks that what it parsed to serializes back
+/// to something that parses again.
+fn parse<T: Serialize + DeserializeOwned>(path: &Path) -> Value {
+    let text = fs::read_to_string(path).unwrap();
+    let event: T = serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let value = serde_json::to_value(&event).unwrap();
+    serde_json::from_value::<T>(value.clone()).unwrap_or_else(|e| panic!("{} re-encoded: {}", path.display(), e));
+    value
+}
+
+fn tag(value: &Value) -> &str {
+    let envelope_fields = ["room", "seq", "origin", "sender", "ts", "receipts", "expires_at", "encryption"];
+    value.as_object().unwrap().keys().find(|k| !envelope_fields.contains(&k.as_str())).unwrap()
+}
+
+#[test]
+fn client_fixtures_parse() {
+    for path in fixtures("client") {
+        let value = parse::<ClientEvent>(&path);
+        assert_eq!(tag(&value), expected_tag(&path), "{}", path.display());
+    }
+}
+
+#[test]
+fn server_fixtures_parse() {
+    for path in fixtures("server") {
+        let value = parse::<ServerEvent>(&path);
+        assert_eq!(tag(&value), expected_tag(&path), "{}", path.display());
+    }
+}
+
+#[test]
+fn envelope_fixtures_parse() {
+    for path in fixtures("envelope") {
+        let value = parse::<Envelope>(&path);
+        assert_eq!(tag(&value), expected_tag(&path), "{}", path.display());
+        assert!(value["seq"].as_u64().unwrap() >= 1, "{}", path.display());
+    }
+}
@@ -0,0 +1,273 @@
+//
+// ROUND-TRIP PROPERTIES
+//
+// Any event the code can build must survive encode -> decode unchanged,
+// for every variant. `variant` matches exhaustively, so adding a variant
+// does not compile until it has a strategy here too.
+//
+
+use std::collections::BTreeMap;
+
+use proptest::collection::{btree_map, vec};
+use proptest::option;
+use proptest::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use uchat_proto::envelope::{Encryption, Envelope};
+use uchat_proto::events::{
+    Batching, ClientEvent, ClientSettings, InstanceInfo, Limits, PresenceStatus, RateLimit, ReceiptKind, Resume,
+    ServerEvent, Thumbnail,
+};
+
+fn text() -> impl Strategy<Value = String> {
+    any::<String>()
+}
+
+fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        text().prop_map(Value::from),
+    ];
+    leaf.prop_recursive(3, 16, 4, |inner| {
+        prop_oneof![
+            vec(inner.clone(), 0..4).prop_map(Value::from),
+            btree_map(text(), inner, 0..4).prop_map(|m| Value::Object(m.into_iter().collect())),
+        ]
+    })
+}
+
+fn resume() -> impl Strategy<Value = Resume> {
+    (text(), btree_map(text(), any::<u64>(), 0..4)).prop_map(|(token, last_seq)| Resume { token, last_seq })
+}
+
+fn receipt_kind() -> impl Strategy<Value = ReceiptKind> {
+    prop_oneof![Just(ReceiptKind::Delivered), Just(ReceiptKind::Read)]
+}
+
+fn presence_status() -> impl Strategy<Value = PresenceStatus> {
+    prop_oneof![Just(PresenceStatus::Online), Just(PresenceStatus::Away), Just(PresenceStatus::Offline)]
+}
+
+fn encryption() -> impl Strategy<Value = Encryption> {
+    (text(), text(), option::of(text())).prop_map(|(alg, key_id, nonce)| Encryption { alg, key_id, nonce })
+}
+
+fn client_event() -> impl Strategy<Value = ClientEvent> {
+    prop_oneof![
+        (text(), text()).prop_map(|(username, password)| ClientEvent::Login { username, password }),
+        (
+            text(),
+            option::of(text()),
+            option::of(text()),
+            any::<bool>(),
+            option::of(any::<u64>()),
+            option::of(encryption()),
+        )
+            .prop_map(|(content, room, client_msg_id, receipts, expires_in_secs, encryption)| {
+                ClientEvent::SendMessage { content, room, client_msg_id, receipts, expires_in_secs, encryption }
+            }),
+        (text(), text()).prop_map(|(kind, url)| ClientEvent::SendMedia { kind, url }),
+        (text(), option::of(resume())).prop_map(|(locale, resume)| ClientEvent::Hello { locale, resume }),
+        text().prop_map(|user| ClientEvent::Block { user }),
+        text().prop_map(|user| ClientEvent::Unblock { user }),
+        text().prop_map(|room| ClientEvent::MuteRoom { room }),
+        text().prop_map(|room| ClientEvent::UnmuteRoom { room }),
+        text().prop_map(|room| ClientEvent::JoinRoom { room }),
+        text().prop_map(|room| ClientEvent::LeaveRoom { room }),
+        text().prop_map(|id| ClientEvent::CommandAck { id }),
+        (text(), any::<i64>(), receipt_kind())
+            .prop_map(|(room, message_id, kind)| ClientEvent::Receipt { room, message_id, kind }),
+        (text(), text(), any::<i64>(), text())
+            .prop_map(|(body, nonce, ts, sig)| ClientEvent::Signed { body, nonce, ts, sig }),
+        any::<u64>().prop_map(|version| ClientEvent::ConfigAck { version }),
+        presence_status().prop_map(|status| ClientEvent::SetPresence { status }),
+        (text(), any::<u64>()).prop_map(|(room, seq)| ClientEvent::Delivered { room, seq }),
+        text().prop_map(|token| ClientEvent::RefreshToken { token }),
+    ]
+}
+
+fn limits() -> impl Strategy<Value = Limits> {
+    let rate_limits = btree_map(text(), (any::<u32>(), any::<u64>()), 0..4)
+        .prop_map(|m| m.into_iter().map(|(k, (max, per_secs))| (k, RateLimit { max, per_secs })).collect());
+    let batching = option::of(
+        (any::<u64>(), any::<usize>()).prop_map(|(max_delay_ms, max_bytes)| Batching { max_delay_ms, max_bytes }),
+    );
+    (any::<usize>(), any::<u64>(), rate_limits, vec(text(), 0..4), batching).prop_map(
+        |(max_message_bytes, heartbeat_interval_secs, rate_limits, features, batching): (_, _, BTreeMap<_, _>, _, _)| {
+            Limits { max_message_bytes, heartbeat_interval_secs, rate_limits, features, batching }
+        },
+    )
+}
+
+fn instance() -> impl Strategy<Value = InstanceInfo> {
+    (text(), option::of(text())).prop_map(|(instance_id, region)| InstanceInfo { instance_id, region })
+}
+
+fn client_settings() -> impl Strategy<Value = ClientSettings> {
+    (option::of(any::<u64>()), option::of(text()), btree_map(text(), any::<bool>(), 0..4)).prop_map(
+        |(heartbeat_interval_secs, telemetry_level, features)| ClientSettings {
+            heartbeat_interval_secs,
+            telemetry_level,
+            features,
+        },
+    )
+}
+
+fn thumbnail() -> impl Strategy<Value = Thumbnail> {
+    (text(), any::<u32>(), any::<u32>()).prop_map(|(url, width, height)| Thumbnail { url, width, height })
+}
+
+fn server_event() -> impl Strategy<Value = ServerEvent> {
+    prop_oneof![
+        (limits(), option::of(instance())).prop_map(|(limits, server)| ServerEvent::Welcome { limits, server }),
+        text().prop_map(|token| ServerEvent::LoginOk { token }),
+        (text(), option::of(text())).prop_map(|(details, code)| ServerEvent::Error { details, code }),
+        (text(), text()).prop_map(|(from, content)| ServerEvent::MessageBroadcast { from, content }),
+        (text(), text(), text(), vec(thumbnail(), 0..3))
+            .prop_map(|(from, kind, url, thumbnails)| ServerEvent::MediaBroadcast { from, kind, url, thumbnails }),
+        (text(), text()).prop_map(|(command, content)| ServerEvent::CommandResult { command, content }),
+        (text(), text(), vec(any::<u32>(), 0..6), any::<bool>())
+            .prop_map(|(poll_id, room, counts, closed)| ServerEvent::PollUpdated { poll_id, room, counts, closed }),
+        (any::<i64>(), text(), option::of(text()), option::of(text()), option::of(text())).prop_map(
+            |(message_id, url, title, description, image)| ServerEvent::MessageUnfurled {
+                message_id,
+                url,
+                title,
+                description,
+                image,
+            }
+        ),
+        (text(), text(), json_value())
+            .prop_map(|(id, command, payload)| ServerEvent::DeviceCommand { id, command, payload }),
+        (text(), any::<u64>(), any::<bool>())
+            .prop_map(|(room, seq, e2ee_required)| ServerEvent::RoomJoined { room, seq, e2ee_required }),
+        text().prop_map(|room| ServerEvent::RoomLeft { room }),
+        (text(), text(), any::<u64>())
+            .prop_map(|(client_msg_id, room, seq)| ServerEvent::MessageAck { client_msg_id, room, seq }),
+        (text(), any::<u64>(), text()).prop_map(|(room, seq, to)| ServerEvent::MessageDelivered { room, seq, to }),
+        text().prop_map(|token| ServerEvent::ResumeToken { token }),
+        (text(), any::<u64>(), any::<bool>())
+            .prop_map(|(username, replayed, complete)| ServerEvent::Resumed { username, replayed, complete }),
+        (text(), any::<i64>(), text(), text())
+            .prop_map(|(room, message_id, from, emoji)| ServerEvent::ReactionAdded { room, message_id, from, emoji }),
+        (text(), any::<i64>(), text(), text(), any::<u64>()).prop_map(|(room, message_id, from, content, revision)| {
+            ServerEvent::MessageEdited { room, message_id, from, content, revision }
+        }),
+        (any::<u64>(), client_settings()).prop_map(|(version, settings)| ServerEvent::ConfigUpdate { version, settings }),
+        (text(), text(), presence_status())
+            .prop_map(|(room, user, status)| ServerEvent::PresenceChanged { room, user, status }),
+        (any::<u64>(), vec(text(), 0..3))
+            .prop_map(|(within_secs, alternates)| ServerEvent::ReconnectSoon { within_secs, alternates }),
+        (any::<bool>(), option::of(text()))
+            .prop_map(|(frozen, reason)| ServerEvent::RoomFreezeChanged { frozen, reason }),
+        Just(ServerEvent::MessageExpired {}),
+        any::<u64>().prop_map(|exp| ServerEvent::TokenRefreshed { exp }),
+    ]
+}
+
+fn client_variant(event: &ClientEvent) -> &'static str {
+    match event {
+        ClientEvent::Login { .. } => "Login",
+        ClientEvent::SendMessage { .. } => "SendMessage",
+        ClientEvent::SendMedia { .. } => "SendMedia",
+        ClientEvent::Hello { .. } => "Hello",
+        ClientEvent::Block { .. } => "Block",
+        ClientEvent::Unblock { .. } => "Unblock",
+        ClientEvent::MuteRoom { .. } => "MuteRoom",
+        ClientEvent::JoinRoom { .. } => "JoinRoom",
+        ClientEvent::LeaveRoom { .. } => "LeaveRoom",
+        ClientEvent::UnmuteRoom { .. } => "UnmuteRoom",
+        ClientEvent::CommandAck { .. } => "CommandAck",
+        ClientEvent::Receipt { .. } => "Receipt",
+        ClientEvent::Signed { .. } => "Signed",
+        ClientEvent::ConfigAck { .. } => "ConfigAck",
+        ClientEvent::SetPresence { .. } => "SetPresence",
+        ClientEvent::Delivered { .. } => "Delivered",
+        ClientEvent::RefreshToken { .. } => "RefreshToken",
+    }
+}
+
+fn server_variant(event: &ServerEvent) -> &'static str {
+    match event {
+        ServerEvent::Welcome { .. } => "Welcome",
+        ServerEvent::LoginOk { .. } => "LoginOk",
+        ServerEvent::Error { .. } => "Error",
+        ServerEvent::MessageBroadcast { .. } => "MessageBroadcast",
+        ServerEvent::MediaBroadcast { .. } => "MediaBroadcast",
+        ServerEvent::CommandResult { .. } => "CommandResult",
+        ServerEvent::PollUpdated { .. } => "PollUpdated",
+        ServerEvent::MessageUnfurled { .. } => "MessageUnfurled",
+        ServerEvent::DeviceCommand { .. } => "DeviceCommand",
+        ServerEvent::Batch { .. } => "Batch",
+        ServerEvent::RoomJoined { .. } => "RoomJoined",
+        ServerEvent::RoomLeft { .. } => "RoomLeft",
+        ServerEvent::MessageAck { .. } => "MessageAck",
+        ServerEvent::MessageDelivered { .. } => "MessageDelivered",
+        ServerEvent::ResumeToken { .. } => "ResumeToken",
+        ServerEvent::Resumed { .. } => "Resumed",
+        ServerEvent::ReactionAdded { .. } => "ReactionAdded",
+        ServerEvent::MessageEdited { .. } => "MessageEdited",
+        ServerEvent::ConfigUpdate { .. } => "ConfigUpdate",
+        ServerEvent::PresenceChanged { .. } => "PresenceChanged",
+        ServerEvent::ReconnectSoon { .. } => "ReconnectSoon",
+        ServerEvent::RoomFreezeChanged { .. } => "RoomFreezeChanged",
+        ServerEvent::MessageExpired {} => "MessageExpired",
+        ServerEvent::TokenRefreshed { .. } => "TokenRefreshed",
+    }
+}
+
+/// Encodes, decodes and encodes again; both encodings must agree.
+fn round_trip<T: Serialize + DeserializeOwned>(event: &T) -> Result<Value, TestCaseError> {
+    let text = serde_json::to_string(event).unwrap();
+    let decoded: T = serde_json::from_str(&text).map_err(|e| TestCaseError::fail(format!("{}: {}", e, text)))?;
+    let first = serde_json::to_value(event).unwrap();
+    prop_assert_eq!(&first, &serde_json::to_value(&decoded).unwrap());
+    Ok(first)
+}
+
+proptest! {
+    #[test]
+    fn client_events_round_trip(event in client_event()) {
+        let value = round_trip(&event)?;
+        prop_assert!(value.get(client_variant(&event)).is_some(), "tag missing: {}", value);
+    }
+
+    #[test]
+    fn server_events_round_trip(event in server_event()) {
+        let value = round_trip(&event)?;
+        prop_assert!(value.get(server_variant(&event)).is_some(), "tag missing: {}", value);
+    }
+
+    #[test]
+    fn envelopes_round_trip(
+        room in text(),
+        seq in any::<u64>(),
+        origin in option::of(instance()),
+        sender in option::of(text()),
+        ts in option::of(any::<i64>()),
+        receipts in any::<bool>(),
+        expires_at in option::of(any::<i64>()),
+        encryption in option::of(encryption()),
+        event in server_event(),
+    ) {
+        let value = round_trip(&Envelope { room, seq, origin, sender, ts, receipts, expires_at, encryption, event })?;
+        prop_assert!(value["seq"].is_u64());
+    }
+
+    #[test]
+    fn batches_round_trip(events in vec((text(), any::<u64>(), server_event()), 0..4)) {
+        let events = events
+            .into_iter()
+            .map(|(room, seq, event)| {
+                let (origin, sender, ts, expires_at, encryption) = (None, None, None, None, None);
+                Envelope { room, seq, origin, sender, ts, receipts: false, expires_at, encryption, event }
+            })
+            .collect();
+        let value = round_trip(&ServerEvent::Batch { events })?;
+        prop_assert!(value["Batch"]["events"].is_array());
+    }
+}
@@ -0,0 +1,158 @@
+//! Typed client for auth-api.
+//!
+//! Request and response types come from `uchat_proto::api`, the same DTOs
+//! auth-api deserializes and documents in its /openapi.json, so a change
+//! to either side shows up as a compile error here instead of a runtime
+//! parse failure in the GUI or tools.
+
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+
+pub use uchat_proto::api::{
+    AcceptPoliciesRequest, AcceptedPolicies, AcceptedPolicy, AccountDeletion, AddMemberRequest, CreateGroupRequest,
+    ErrorResponse, Group, IntrospectRequest, IntrospectResponse, LoginOutcome, LoginRequest, LoginResponse,
+    PendingPolicies, Policies, PolicyVersion,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("transport error: {0}")]
+    Transport(#[from] reqwest::Error),
+
+    #[error("auth-api returned {status}: {details}")]
+    Api { status: StatusCode, details: String },
+
+    #[error("this call needs a token; use `with_token`")]
+    MissingToken,
+
+    /// Credentials were fine, but new policy versions must be accepted
+    /// first; pass the ticket to `accept_policies`.
+    #[error("{} policy version(s) must be accepted before login", .0.policies.len())]
+    PolicyAcceptanceRequired(PendingPolicies),
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+#[derive(Clone)]
+pub struct AuthClient {
+    base: String,
+    http: reqwest::Client,
+    token: Option<String>,
+}
+
+impl AuthClient {
+    /// `base` is the auth-api root, e.g. `http://127.0.0.1:9200`.
+    pub fn new(base: impl Into<String>) -> Self {
+        Self {
+            base: base.into().trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+            token: None,
+        }
+    }
+
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http.request(method, format!("{}{}", self.base, path))
+    }
+
+    fn authed(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        let token = self.token.as_ref().ok_or(ClientError::MissingToken)?;
+        Ok(self.request(method, path).bearer_auth(token))
+    }
+
+    async fn send<T: DeserializeOwned>(req: RequestBuilder) -> Result<T> {
+        let resp = req.send().await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp.json().await?);
+        }
+
+        let body = resp.text().await.unwrap_or_default();
+        let details = serde_json::from_str::<ErrorResponse>(&body)
+            .map(|e| e.error.details)
+            .unwrap_or(body);
+        Err(ClientError::Api { status, details })
+    }
+
+    /// Logs in and returns a client carrying the issued token.
+    pub async fn login(&self, username: &str, password: &str) -> Result<AuthClient> {
+        let body = LoginRequest { username: username.into(), password: password.into() };
+        match Self::send(self.request(Method::POST, "/login").json(&body)).await? {
+            LoginOutcome::LoggedIn(resp) => Ok(self.clone().with_token(resp.login_ok.token)),
+            LoginOutcome::PolicyAcceptanceRequired(p) => Err(ClientError::PolicyAcceptanceRequired(p.required)),
+        }
+    }
+
+    pub async fn policies(&self) -> Result<Policies> {
+        Self::send(self.request(Method::GET, "/policies")).await
+    }
+
+    /// Accepts `policies`, finishing a login with its `ticket`, or for the
+    /// current token when `ticket` is `None`. Returns a client carrying the
+    /// newly issued token.
+    pub async fn accept_policies(&self, ticket: Option<&str>, policies: &[PolicyVersion]) -> Result<AuthClient> {
+        let body = AcceptPoliciesRequest {
+            ticket: ticket.map(Into::into),
+            accept: policies
+                .iter()
+                .map(|p| AcceptedPolicy { kind: p.kind.clone(), version: p.version.clone() })
+                .collect(),
+        };
+        let req = match ticket {
+            Some(_) => self.request(Method::POST, "/policies/accept"),
+            None => self.authed(Method::POST, "/policies/accept")?,
+        };
+        let resp: LoginResponse = Self::send(req.json(&body)).await?;
+        Ok(self.clone().with_token(resp.login_ok.token))
+    }
+
+    pub async fn accepted_policies(&self) -> Result<AcceptedPolicies> {
+        Self::send(self.authed(Method::GET, "/policies/accepted")?).await
+    }
+
+    pub async fn introspect(&self, token: &str) -> Result<IntrospectResponse> {
+        let body = IntrospectRequest { token: token.into() };
+        Self::send(self.request(Method::POST, "/introspect").json(&body)).await
+    }
+
+    /// Schedules deletion of the caller's account; logging in again before
+    /// `purge_at` cancels it.
+    pub async fn delete_account(&self) -> Result<AccountDeletion> {
+        Self::send(self.authed(Method::DELETE, "/account")?).await
+    }
+
+    pub async fn list_groups(&self) -> Result<Vec<Group>> {
+        Self::send(self.authed(Method::GET, "/groups")?).await
+    }
+
+    pub async fn get_group(&self, id: &str) -> Result<Group> {
+        Self::send(self.authed(Method::GET, &format!("/groups/{}", id))?).await
+    }
+
+    pub async fn create_group(&self, id: &str) -> Result<Group> {
+        let body = CreateGroupRequest { id: id.into() };
+        Self::send(self.authed(Method::POST, "/groups")?.json(&body)).await
+    }
+
+    pub async fn delete_group(&self, id: &str) -> Result<()> {
+        let _: serde_json::Value = Self::send(self.authed(Method::DELETE, &format!("/groups/{}", id))?).await?;
+        Ok(())
+    }
+
+    pub async fn add_member(&self, group: &str, username: &str) -> Result<Group> {
+        let body = AddMemberRequest { username: username.into() };
+        Self::send(self.authed(Method::POST, &format!("/groups/{}/members", group))?.json(&body)).await
+    }
+
+    pub async fn remove_member(&self, group: &str, username: &str) -> Result<Group> {
+        Self::send(self.authed(Method::DELETE, &format!("/groups/{}/members/{}", group, username))?).await
+    }
+}